@@ -0,0 +1,186 @@
+use std::fmt;
+use std::time::Instant;
+use termcolor::Color;
+use crate::build::
+{
+    build,
+    BuildError,
+    BuildParams,
+};
+use crate::printer::Printer;
+use crate::system::System;
+
+/*  Wall times of the up-to-date builds, in microseconds, in the order they ran. */
+#[derive(Debug, PartialEq)]
+pub struct BenchReport
+{
+    pub run_micros : Vec<u64>,
+}
+
+impl BenchReport
+{
+    fn sorted(&self) -> Vec<u64>
+    {
+        let mut sorted = self.run_micros.clone();
+        sorted.sort();
+        sorted
+    }
+
+    pub fn min(&self) -> u64
+    {
+        match self.sorted().first()
+        {
+            Some(micros) => *micros,
+            None => 0,
+        }
+    }
+
+    pub fn max(&self) -> u64
+    {
+        match self.sorted().last()
+        {
+            Some(micros) => *micros,
+            None => 0,
+        }
+    }
+
+    /*  With an even number of runs, the lower of the two middle ones. */
+    pub fn median(&self) -> u64
+    {
+        let sorted = self.sorted();
+        match sorted.len()
+        {
+            0 => 0,
+            n => sorted[(n - 1) / 2],
+        }
+    }
+}
+
+pub enum BenchError
+{
+    BuildError(BuildError),
+}
+
+impl fmt::Display for BenchError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            BenchError::BuildError(error) =>
+                write!(formatter, "{}", error),
+        }
+    }
+}
+
+fn format_millis(micros : u64) -> String
+{
+    format!("{}.{:03}ms", micros / 1000, micros % 1000)
+}
+
+/*  This is the function that runs when you type "ruler bench" at the command-line.  Builds once to
+    bring everything up-to-date, then builds again the given number of times, timing each.  Those
+    later builds find nothing to do, so what gets measured is the overhead of reading rules,
+    checking files and consulting history.  Prints the min, median and max. */
+pub fn bench
+<
+    SystemType : System + 'static,
+    PrinterType : Printer,
+>
+(
+    system : SystemType,
+    printer : &mut PrinterType,
+    params : BuildParams,
+    runs : usize
+)
+-> Result<BenchReport, BenchError>
+{
+    match build(system.clone(), printer, None, params.clone())
+    {
+        Ok(()) => {},
+        Err(error) => return Err(BenchError::BuildError(error)),
+    }
+
+    let mut run_micros = Vec::new();
+    for _ in 0..runs
+    {
+        let start = Instant::now();
+        match build(system.clone(), printer, None, params.clone())
+        {
+            Ok(()) => {},
+            Err(error) => return Err(BenchError::BuildError(error)),
+        }
+        run_micros.push(start.elapsed().as_micros() as u64);
+    }
+
+    let report = BenchReport
+    {
+        run_micros : run_micros,
+    };
+
+    printer.print_single_banner_line("     Bench", Color::Cyan,
+        &format!("{} up-to-date runs: min {} median {} max {}",
+            report.run_micros.len(),
+            format_millis(report.min()),
+            format_millis(report.median()),
+            format_millis(report.max())));
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::bench::
+    {
+        bench,
+        BenchReport,
+    };
+    use crate::build::
+    {
+        BuildParams,
+    };
+    use crate::printer::EmptyPrinter;
+    use crate::system::fake::FakeSystem;
+    use crate::system::util::write_str_to_file;
+
+    /*  The first build runs the command, and the timed builds after it find everything up-to-date,
+        so the command log holds exactly one command while the report holds one time per run. */
+    #[test]
+    fn bench_runs_requested_number_of_builds()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "\
+poem.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+poem.txt
+:
+").unwrap();
+
+        let report = bench(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            BuildParams::for_test(),
+            5).ok().unwrap();
+
+        assert_eq!(report.run_micros.len(), 5);
+        assert_eq!(system.get_command_log_joined(), vec!["mycat verse1.txt poem.txt".to_string()]);
+    }
+
+    #[test]
+    fn bench_report_statistics()
+    {
+        let report = BenchReport
+        {
+            run_micros : vec![40, 10, 30, 20],
+        };
+        assert_eq!(report.min(), 10);
+        assert_eq!(report.median(), 20);
+        assert_eq!(report.max(), 40);
+    }
+}
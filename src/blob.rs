@@ -12,10 +12,13 @@ use crate::cache::
     DownloadResult,
 };
 use crate::system::util::get_timestamp;
+use crate::printer::PrinterHandle;
+use termcolor::Color;
 use crate::ticket::
 {
     TicketFactory,
     Ticket,
+    get_neither_file_nor_directory_error,
 };
 use serde::
 {
@@ -23,20 +26,108 @@ use serde::
     Deserialize,
 };
 use std::fmt;
+use std::sync::Arc;
 use std::time::
 {
     SystemTimeError
 };
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum FileResolution
 {
     AlreadyCorrect,
-    Recovered,
+
+    /*  Restored from a cache, the string being that cache's tier. */
+    Recovered(String),
     Downloaded,
     NeedsRebuild,
 }
 
+/*  A way of bringing back a target's content from its ticket.  A rule's resolvers get asked in order:
+    the cache first, then any the build was given (say one that reconstructs content from a database),
+    then the download urls.  One that can put content with the ticket at path does so and returns how
+    to report it.  One that can't returns None. */
+pub trait Resolver<SystemType : System> : Send + Sync
+{
+    fn try_resolve(&self, ticket : &Ticket, path : &str, system : &mut SystemType)
+        -> Result<Option<FileResolution>, ResolutionError>;
+
+    /*  Whether content this resolver puts in place is sure to have the ticket and the remembered
+        executable bit.  Otherwise, the content gets checked against the ticket and the bit gets set. */
+    fn is_trusted(&self) -> bool
+    {
+        false
+    }
+}
+
+/*  Restores content from a cache, reporting the cache's tier. */
+pub struct CacheResolver<SystemType : System>
+{
+    cache : SysCache<SystemType>,
+}
+
+impl<SystemType : System> CacheResolver<SystemType>
+{
+    pub fn new(cache : SysCache<SystemType>) -> CacheResolver<SystemType>
+    {
+        CacheResolver
+        {
+            cache : cache,
+        }
+    }
+}
+
+impl<SystemType : System> Resolver<SystemType> for CacheResolver<SystemType>
+{
+    fn try_resolve(&self, ticket : &Ticket, path : &str, _system : &mut SystemType)
+        -> Result<Option<FileResolution>, ResolutionError>
+    {
+        let mut cache = self.cache.clone();
+        match cache.restore_file(ticket, path)
+        {
+            RestoreResult::Done => Ok(Some(FileResolution::Recovered(cache.get_tier().to_string()))),
+            RestoreResult::NotThere => Ok(None),
+            RestoreResult::CacheDirectoryMissing => Err(ResolutionError::CacheDirectoryMissing),
+            RestoreResult::SystemError(error) => Err(ResolutionError::CacheMalfunction(error)),
+        }
+    }
+
+    fn is_trusted(&self) -> bool
+    {
+        true
+    }
+}
+
+/*  Downloads content from the download urls. */
+pub struct DownloadResolver
+{
+    downloader_cache : DownloaderCache,
+}
+
+impl DownloadResolver
+{
+    pub fn new(downloader_cache : DownloaderCache) -> DownloadResolver
+    {
+        DownloadResolver
+        {
+            downloader_cache : downloader_cache,
+        }
+    }
+}
+
+impl<SystemType : System> Resolver<SystemType> for DownloadResolver
+{
+    fn try_resolve(&self, ticket : &Ticket, path : &str, system : &mut SystemType)
+        -> Result<Option<FileResolution>, ResolutionError>
+    {
+        match self.downloader_cache.restore_file(ticket, system, path)
+        {
+            DownloadResult::Done => Ok(Some(FileResolution::Downloaded)),
+            DownloadResult::NotThere => Ok(None),
+        }
+    }
+}
+
 /*  The data in FileState are things which would follow the file if it were renamed/moved.  There's a ticket
     representing the file's contents, a timestamp (modifed date), and a bool for whether the file is executable.
     Those things would follow the file in a rename/move operation. */
@@ -92,6 +183,9 @@ pub struct FileInfo
 {
     pub path : String,
     pub file_state : FileState,
+
+    /*  When true, backing up the file before a rebuild copies it instead of moving it. */
+    pub precious : bool,
 }
 
 #[derive(Debug)]
@@ -163,6 +257,62 @@ impl Blob
         );
     }
 
+    /*  The full state of each file as it is in the system right now: ticket, timestamp and executable
+        bit.  Uses the timestamp optimization for the ticket, but leaves the blob alone. */
+    pub fn get_current_file_states<SystemType: System>
+    (
+        self : &Self,
+        system : &SystemType,
+    )
+    -> Result<Vec<FileState>, GetFileStateError>
+    {
+        let mut file_states = vec![];
+        for target_info in self.file_infos.iter()
+        {
+            let ticket =
+            match get_file_ticket(system, &target_info.path, &target_info.file_state)
+            {
+                Ok(Some(ticket)) => ticket,
+                Ok(None) => return Err(GetFileStateError::FileNotFound(target_info.path.clone())),
+                Err(error) => return Err(GetFileStateError::ReadWriteError(target_info.path.clone(), error)),
+            };
+
+            let timestamp =
+            match system.get_modified(&target_info.path)
+            {
+                Ok(system_time) => match get_timestamp(system_time)
+                {
+                    Ok(timestamp) => timestamp,
+                    Err(_) => 0,
+                },
+                Err(_) => return Err(GetFileStateError::FileNotFound(target_info.path.clone())),
+            };
+
+            let executable =
+            match system.is_executable(&target_info.path)
+            {
+                Ok(executable) => executable,
+                Err(_) => false,
+            };
+
+            file_states.push(
+                FileState
+                {
+                    ticket : ticket,
+                    timestamp : timestamp,
+                    executable : executable,
+                });
+        }
+
+        Ok(file_states)
+    }
+
+    /*  The file states the blob has recorded, in order. */
+    pub fn get_file_states(self : &Self) -> Vec<FileState>
+    {
+        self.file_infos.iter().map(|info| info.file_state.clone()).collect()
+    }
+
     /*  Takes a system, and updates the file contents in the blob to reflect the files in the system.
         Returns a vector of FileStates which is current according to the file system. */
     pub fn update_to_match_system_file_state<SystemType: System>
@@ -221,18 +371,84 @@ impl Blob
                 {
                     file_state : get_state(&path),
                     path : path,
+                    precious : false,
                 }
             }
         ).collect()}
     }
 
+    /*  Replaces the FileState of each file in the blob, in order, with the ones given. */
+    pub fn set_file_states(self : &mut Self, file_states : Vec<FileState>)
+    {
+        for (info, file_state) in self.file_infos.iter_mut().zip(file_states.into_iter())
+        {
+            info.file_state = file_state;
+        }
+    }
+
+    /*  Marks every file in the blob precious, or not. */
+    pub fn set_precious(self : &mut Self, precious : bool)
+    {
+        for info in self.file_infos.iter_mut()
+        {
+            info.precious = precious;
+        }
+    }
+
+    /*  True when every target's recorded state has the ticket remembered for it, and the file on disk
+        still has the recorded timestamp, so nothing needs resolving.  Only looks at timestamps, never
+        reads file content. */
+    pub fn matches_unmodified<SystemType : System>
+    (
+        self : &Self,
+        system : &SystemType,
+        remembered_file_state_vec : &FileStateVec,
+    )
+    -> bool
+    {
+        if self.file_infos.len() != remembered_file_state_vec.infos.len()
+        {
+            return false;
+        }
+
+        for (i, info) in self.file_infos.iter().enumerate()
+        {
+            if info.file_state.ticket != remembered_file_state_vec.infos[i].ticket
+            {
+                return false;
+            }
+
+            match system.get_modified(&info.path)
+            {
+                Ok(system_time) =>
+                {
+                    match get_timestamp(system_time)
+                    {
+                        Ok(timestamp) =>
+                        {
+                            if timestamp != info.file_state.timestamp
+                            {
+                                return false;
+                            }
+                        },
+                        Err(_) => return false,
+                    }
+                },
+                Err(_) => return false,
+            }
+        }
+
+        true
+    }
+
     pub fn resolve_remembered_file_state_vec<SystemType : System>
     (
         self : &Self,
         system : &mut SystemType,
         cache : &mut SysCache<SystemType>,
-        downloader_cache_opt : &Option<DownloaderCache>,
+        resolvers : &Vec<Arc<dyn Resolver<SystemType>>>,
         remembered_tickets : &FileStateVec,
+        printer_handle : &PrinterHandle,
     )
     ->
     Result<Vec<FileResolution>, ResolutionError>
@@ -243,7 +459,41 @@ impl Blob
             match resolve_single_target(
                 system,
                 cache,
-                downloader_cache_opt,
+                resolvers,
+                &remembered_tickets.get_info(i),
+                info,
+                printer_handle)
+            {
+                Ok(resolution) => resolutions.push(resolution),
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(resolutions)
+    }
+
+    /*  What resolve_remembered_file_state_vec would do for each target, without doing any of it:
+        nothing gets backed up, restored or downloaded.  A target the local cache lacks counts as
+        Downloaded if downloads are available at all, since whether the remote really has it can't be
+        known without downloading it. */
+    pub fn predict_remembered_file_state_vec<SystemType : System>
+    (
+        self : &Self,
+        system : &SystemType,
+        cache : &SysCache<SystemType>,
+        downloads_available : bool,
+        remembered_tickets : &FileStateVec,
+    )
+    ->
+    Result<Vec<FileResolution>, ResolutionError>
+    {
+        let mut resolutions = vec![];
+        for (i, info) in self.file_infos.iter().enumerate()
+        {
+            match predict_single_target(
+                system,
+                cache,
+                downloads_available,
                 &remembered_tickets.get_info(i),
                 info)
             {
@@ -260,6 +510,7 @@ impl Blob
         self : &Blob,
         system : &mut SystemType,
         cache : &mut SysCache<SystemType>,
+        printer_handle : &PrinterHandle
     )
     ->
     Result<Vec<FileResolution>, ResolutionError>
@@ -271,9 +522,7 @@ impl Blob
             {
                 Ok(Some(current_target_ticket)) =>
                 {
-                    match cache.back_up_file_with_ticket(
-                        &current_target_ticket,
-                        &file_info.path)
+                    match back_up_target(cache, &current_target_ticket, file_info, printer_handle)
                     {
                         Ok(_) =>
                         {
@@ -410,6 +659,11 @@ impl FileStateVec
         self.infos[sub_index].ticket.clone()
     }
 
+    pub fn get_tickets(&self) -> Vec<Ticket>
+    {
+        self.infos.iter().map(|info| info.ticket.clone()).collect()
+    }
+
     /*  Currently used by a display function, hence the formatting. */
     pub fn human_readable(&self)
     -> String
@@ -436,6 +690,8 @@ impl FileStateVec
 
     If the file exists, returns a ticket.
     If the file does not exist, returns Ok, but with no Ticket inside
+    If something is there but it's neither a file nor a directory, like a named pipe, returns an
+    error without opening it.
     If the file exists but does not open or some other error occurs when generating
     the ticket, returns an error. */
 fn get_file_ticket_from_path<SystemType: System>
@@ -463,7 +719,11 @@ fn get_file_ticket_from_path<SystemType: System>
     }
     else
     {
-        Ok(None)
+        match get_neither_file_nor_directory_error(system, path)
+        {
+            ReadWriteError::SystemError(SystemError::NotFound) => Ok(None),
+            error => Err(error),
+        }
     }
 }
 
@@ -637,60 +897,87 @@ impl fmt::Display for ResolutionError
 fn restore_or_download<SystemType : System>
 (
     system : &mut SystemType,
-    cache : &mut SysCache<SystemType>,
-    downloader_cache_opt : &Option<DownloaderCache>,
+    resolvers : &Vec<Arc<dyn Resolver<SystemType>>>,
     remembered_target_content_info : &FileState,
-    target_info : &FileInfo
+    target_info : &FileInfo,
+    printer_handle : &PrinterHandle
 )
 -> Result<FileResolution, ResolutionError>
 {
-    match cache.restore_file(
-        &remembered_target_content_info.ticket,
-        &target_info.path)
+    for resolver in resolvers.iter()
     {
-        RestoreResult::Done =>
-            return Ok(FileResolution::Recovered),
-
-        RestoreResult::NotThere => {},
-
-        RestoreResult::CacheDirectoryMissing =>
-            return Err(ResolutionError::CacheDirectoryMissing),
+        let resolution =
+        match resolver.try_resolve(&remembered_target_content_info.ticket, &target_info.path, system)?
+        {
+            Some(resolution) => resolution,
+            None => continue,
+        };
 
-        RestoreResult::SystemError(error) =>
-            return Err(ResolutionError::CacheMalfunction(error)),
-    }
+        if resolver.is_trusted()
+        {
+            return Ok(resolution);
+        }
 
-    match downloader_cache_opt
-    {
-        Some(downloader_cache) =>
+        match TicketFactory::from_file(system, &target_info.path).map(|mut factory| factory.result())
         {
-            match downloader_cache.restore_file(
-                &remembered_target_content_info.ticket,
-                system,
-                &target_info.path)
+            Ok(ticket) if ticket == remembered_target_content_info.ticket => {},
+            _ =>
             {
-                DownloadResult::Done => {}
-                DownloadResult::NotThere =>
-                    return Ok(FileResolution::NeedsRebuild),
-            }
+                printer_handle.print_single_banner_line("   Warning", Color::Yellow,
+                    &format!("resolved content does not match the ticket: {}", target_info.path));
+                continue;
+            },
+        }
 
-            return match system.set_is_executable(&target_info.path, remembered_target_content_info.executable)
-            {
-                Err(_) =>
-                {
-                    println!("Warning: failed to set executable");
-                    Ok(FileResolution::Downloaded)
-                },
-                Ok(_) => Ok(FileResolution::Downloaded)
-            };
-        },
+        match system.set_is_executable(&target_info.path, remembered_target_content_info.executable)
+        {
+            Ok(_) => {},
+            Err(_) => printer_handle.print_single_banner_line("   Warning", Color::Yellow,
+                &format!("failed to set executable: {}", target_info.path)),
+        }
 
-        None => {}
+        return Ok(resolution);
     }
 
     Ok(FileResolution::NeedsRebuild)
 }
 
+/*  Backs up the target's current content into the cache ahead of a rebuild or a restore: moves it
+    there, or copies it if the target is precious.  Caching the old content is only ever an
+    optimization, so when the cache's disk is full, that's a warning, not an error, and the old content
+    gets written over like any other build tool would. */
+fn back_up_target<SystemType : System>
+(
+    cache : &mut SysCache<SystemType>,
+    current_target_ticket : &Ticket,
+    target_info : &FileInfo,
+    printer_handle : &PrinterHandle
+)
+->
+Result<(), ReadWriteError>
+{
+    let result =
+    if target_info.precious
+    {
+        cache.copy_file_with_ticket(current_target_ticket, &target_info.path)
+    }
+    else
+    {
+        cache.back_up_file_with_ticket(current_target_ticket, &target_info.path)
+    };
+
+    match result
+    {
+        Err(ReadWriteError::SystemError(SystemError::OutOfSpace)) =>
+        {
+            printer_handle.print_single_banner_line("   Warning", Color::Yellow,
+                &format!("could not cache previous copy of {}: disk full", target_info.path));
+            Ok(())
+        },
+        result => result,
+    }
+}
+
 /*  Given a target-info and a remembered ticket for that target file, check the current
     ticket, and if it matches, return AlreadyCorrect.  If it doesn't match, back up the current
     file, and then attempt to restore the remembered file from cache, if the cache doesn't have it,
@@ -699,9 +986,10 @@ pub fn resolve_single_target<SystemType : System>
 (
     system : &mut SystemType,
     cache : &mut SysCache<SystemType>,
-    downloader_cache_opt : &Option<DownloaderCache>,
+    resolvers : &Vec<Arc<dyn Resolver<SystemType>>>,
     remembered_target_content_info : &FileState,
-    target_info : &FileInfo
+    target_info : &FileInfo,
+    printer_handle : &PrinterHandle
 )
 ->
 Result<FileResolution, ResolutionError>
@@ -715,9 +1003,7 @@ Result<FileResolution, ResolutionError>
                 return Ok(FileResolution::AlreadyCorrect);
             }
 
-            match cache.back_up_file_with_ticket(
-                &current_target_ticket,
-                &target_info.path)
+            match back_up_target(cache, &current_target_ticket, target_info, printer_handle)
             {
                 Ok(_) => {},
                 Err(error) =>
@@ -729,10 +1015,10 @@ Result<FileResolution, ResolutionError>
 
             restore_or_download(
                 system,
-                cache,
-                downloader_cache_opt,
+                resolvers,
                 remembered_target_content_info,
-                target_info)
+                target_info,
+                printer_handle)
         },
 
         // None means the file is not there, in which case, we just try to restore/download, and go home.
@@ -740,10 +1026,10 @@ Result<FileResolution, ResolutionError>
         {
             restore_or_download(
                 system,
-                cache,
-                downloader_cache_opt,
+                resolvers,
                 remembered_target_content_info,
-                target_info)
+                target_info,
+                printer_handle)
         },
 
         Err(error) =>
@@ -753,6 +1039,47 @@ Result<FileResolution, ResolutionError>
     }
 }
 
+/*  Like resolve_single_target, but only predicts: compares the target's current ticket with the
+    remembered one, and failing that, looks for the remembered one in the cache, backing up, restoring
+    and downloading nothing. */
+pub fn predict_single_target<SystemType : System>
+(
+    system : &SystemType,
+    cache : &SysCache<SystemType>,
+    downloads_available : bool,
+    remembered_target_content_info : &FileState,
+    target_info : &FileInfo
+)
+->
+Result<FileResolution, ResolutionError>
+{
+    match get_file_ticket(system, &target_info.path, &target_info.file_state)
+    {
+        Ok(Some(current_target_ticket)) =>
+        {
+            if remembered_target_content_info.ticket == current_target_ticket
+            {
+                return Ok(FileResolution::AlreadyCorrect);
+            }
+        },
+        Ok(None) => {},
+        Err(error) => return Err(ResolutionError::TicketAlignmentError(error)),
+    }
+
+    if cache.contains(&remembered_target_content_info.ticket)
+    {
+        Ok(FileResolution::Recovered(cache.get_tier().to_string()))
+    }
+    else if downloads_available
+    {
+        Ok(FileResolution::Downloaded)
+    }
+    else
+    {
+        Ok(FileResolution::NeedsRebuild)
+    }
+}
+
 #[cfg(test)]
 mod test
 {
@@ -769,8 +1096,16 @@ mod test
         get_file_ticket,
         get_file_ticket_from_path,
         get_actual_file_state,
+        resolve_single_target,
+        predict_single_target,
+        Blob,
+        FileResolution,
         GetCurrentFileInfoError,
+        Resolver,
+        CacheResolver,
     };
+    use crate::cache::SysCache;
+    use crate::printer::PrinterHandle;
     use crate::system::
     {
         fake::FakeSystem,
@@ -779,7 +1114,9 @@ mod test
     use crate::system::util::
     {
         write_str_to_file,
+        read_file_to_string,
     };
+    use std::sync::Arc;
 
     /*  Create a file, and make FileInfo that matches the reality of that file.
         Call get_actual_file_state and check that the returned data matches. */
@@ -1184,4 +1521,74 @@ mod test
         assert_eq!(file_state_vec, FileStateVec::from_download_string(
             &file_state_vec.download_string()).unwrap());
     }
+
+    /*  Set up two cache tiers, with the poem only in the second.  Resolving against the first finds
+        nothing, so the target needs a rebuild.  Resolving against the second recovers it, and the
+        resolution names that tier. */
+    #[test]
+    fn resolve_single_target_reports_tier()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir(".ruler-cache").unwrap();
+        system.create_dir("shared-cache").unwrap();
+        let mut local_cache = SysCache::new(system.clone(), ".ruler-cache");
+        let mut shared_cache = SysCache::with_tier(system.clone(), "shared-cache", "shared");
+
+        write_str_to_file(&mut system, "poem.txt", "Roses are red.\n").unwrap();
+        shared_cache.back_up_file("poem.txt").unwrap();
+
+        let remembered = FileState::new_with_ticket(TicketFactory::from_str("Roses are red.\n").result());
+        let blob = Blob::from_paths(vec!["poem.txt".to_string()], |_path| FileState::empty());
+        let target_info = &blob.get_file_infos()[0];
+
+        let local_resolvers : Vec<Arc<dyn Resolver<FakeSystem>>> = vec![Arc::new(CacheResolver::new(local_cache.clone()))];
+        match resolve_single_target(&mut system, &mut local_cache, &local_resolvers, &remembered, target_info, &PrinterHandle::discard())
+        {
+            Ok(FileResolution::NeedsRebuild) => {},
+            other => panic!("Expected NeedsRebuild from the local tier, got: {:?}", other.ok()),
+        }
+
+        let shared_resolvers : Vec<Arc<dyn Resolver<FakeSystem>>> = vec![Arc::new(CacheResolver::new(shared_cache.clone()))];
+        match resolve_single_target(&mut system, &mut shared_cache, &shared_resolvers, &remembered, target_info, &PrinterHandle::discard())
+        {
+            Ok(FileResolution::Recovered(tier)) => assert_eq!(tier, "shared"),
+            other => panic!("Expected recovery from the shared tier, got: {:?}", other.ok()),
+        }
+
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\n");
+    }
+
+    /*  The cache has the poem, and a different poem is in its place.  Predicting says the poem would
+        come back from the cache, and says so again, since neither the cache nor the stale poem moved.
+        With no cache entry, it's a download if downloads are available, otherwise a rebuild. */
+    #[test]
+    fn predict_single_target_touches_nothing()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir(".ruler-cache").unwrap();
+        let mut cache = SysCache::new(system.clone(), ".ruler-cache");
+
+        write_str_to_file(&mut system, "poem.txt", "Roses are red.\n").unwrap();
+        cache.back_up_file("poem.txt").unwrap();
+        write_str_to_file(&mut system, "poem.txt", "Roses are plaid.\n").unwrap();
+
+        let remembered = FileState::new_with_ticket(TicketFactory::from_str("Roses are red.\n").result());
+        let blob = Blob::from_paths(vec!["poem.txt".to_string()], |_path| FileState::empty());
+        let target_info = &blob.get_file_infos()[0];
+
+        for _ in 0..2
+        {
+            match predict_single_target(&system, &cache, false, &remembered, target_info)
+            {
+                Ok(FileResolution::Recovered(tier)) => assert_eq!(tier, "local"),
+                other => panic!("Expected recovery from the cache, got: {:?}", other.ok()),
+            }
+        }
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are plaid.\n");
+        assert!(cache.contains(&remembered.ticket));
+
+        let missing = FileState::new_with_ticket(TicketFactory::from_str("Roses are blue.\n").result());
+        assert_eq!(predict_single_target(&system, &cache, true, &missing, target_info).unwrap(), FileResolution::Downloaded);
+        assert_eq!(predict_single_target(&system, &cache, false, &missing, target_info).unwrap(), FileResolution::NeedsRebuild);
+    }
 }
@@ -1,4 +1,5 @@
 use std::thread;
+use std::sync::Arc;
 use std::sync::mpsc::
 {
     self,
@@ -6,6 +7,12 @@ use std::sync::mpsc::
     Receiver,
     SendError,
     RecvError,
+    RecvTimeoutError,
+};
+use std::time::
+{
+    Duration,
+    SystemTime,
 };
 use std::str::from_utf8;
 use std::fmt;
@@ -13,25 +20,35 @@ use std::io::
 {
     self,
     Read,
+    Write,
+};
+use serde::
+{
+    Serialize,
+    Deserialize,
 };
-use serde::Deserialize;
 use crate::directory::
 {
     self,
+    Elements,
     InitDirectoryError
 };
 use crate::rule::
 {
-    parse_all,
+    parse_all_with_global_sources,
+    dedupe_identical_rules,
+    normalize_line_endings,
     ParseError,
+    Provenance,
 };
 use crate::sort::
 {
     Node,
     NodePack,
     SourceIndex,
-    topological_sort,
-    topological_sort_all,
+    to_path_key,
+    topological_sort_all_with_path_case,
+    topological_sort_with_path_case,
     TopologicalSortError,
 };
 use crate::ticket::
@@ -47,6 +64,12 @@ use crate::packet::
 use crate::blob::
 {
     FileResolution,
+    FileState,
+    FileStateVec,
+    Resolver,
+    CacheResolver,
+    DownloadResolver,
+    get_file_ticket,
 };
 use crate::work::
 {
@@ -55,24 +78,91 @@ use crate::work::
     WorkError,
     HandleNodeInfo,
     RuleExt,
+    CommandGate,
+    ContradictionPolicy,
     handle_rule_node,
     handle_source_only_node,
+    handle_trusted_source_only_node,
     clean_targets,
 };
 use crate::cache::
 {
+    self,
     DownloaderCache,
+    DownloadResult,
+    LOCAL_CACHE_TIER,
+};
+use crate::downloader::
+{
+    Downloader,
+    DownloadError,
+    HttpDownloader,
+};
+use std::collections::
+{
+    HashMap,
+    HashSet,
+};
+use rand::seq::SliceRandom;
+use crate::timing::
+{
+    self,
+    BuildTimings,
+    NodeTiming,
+};
+use crate::hash::
+{
+    self,
+    TargetMismatch,
+};
+use crate::observer::
+{
+    BuildObserver,
+    EmptyObserver,
+};
+use crate::orphans;
+use crate::pool::Executor;
+use crate::private;
+use crate::progress::
+{
+    get_error_code,
+    get_status,
+    ProgressEvent,
+    ProgressStream,
+};
+use crate::ledger::
+{
+    self,
+    LedgerRecord,
+    DEFAULT_LEDGER_MAX_RECORDS,
 };
 use crate::history::
 {
     HistoryError,
     DownloaderHistory,
+    RuleHistory,
 };
 use crate::current::
 {
-    CurrentFileStatesError
+    CurrentFileStatesError,
+    UNTRUSTED_TIMESTAMP,
+};
+use crate::plan::
+{
+    BuildPlan,
+    PlanEdge,
+    PlannedNode,
+    PredictedAction,
+    RebuildReason,
+    RecoverFrom,
+};
+use crate::printer::
+{
+    print_messages,
+    Printer,
+    PrinterHandle,
+    EmptyPrinter,
 };
-use crate::printer::Printer;
 use termcolor::
 {
     Color,
@@ -81,11 +171,15 @@ use crate::system::
 {
     System,
     SystemError,
+    CommandEnvironment,
+    CommandLineOutput,
     to_command_script
 };
 use crate::system::util::
 {
+    get_timestamp,
     read_file_to_string,
+    write_str_to_file,
     ReadFileToStringError,
 };
 
@@ -94,11 +188,14 @@ use crate::system::util::
     to rules, references between them, and sorted structure.  But a NodePack does not know about how _this_ module
     will dispatch the work of building onto threads, so the first step when receiving a NodePack is to
     process it and turn it into one of these ChannelPacks which has channel sender/receiver according to the
-    dependencies in the NodePack. */
+    dependencies in the NodePack.
+
+    Each sender goes with the name of the target on the other end, and each receiver with the name of
+    the source, so packets can be traced. */
 struct ChannelPack
 {
-    leaves: Vec<(String, Vec<Sender<Packet>>)>,
-    nodes: Vec<(Node, Vec<(usize, Sender<Packet>)>, Vec<Receiver<Packet>>)>,
+    leaves: Vec<(String, Vec<(String, Sender<Packet>)>)>,
+    nodes: Vec<(Node, Vec<(usize, String, Sender<Packet>)>, Vec<(String, Receiver<Packet>)>)>,
 }
 
 impl ChannelPack
@@ -106,24 +203,42 @@ impl ChannelPack
     /*  Consumes a NodePack, returns the same leaves and nodes in a ChannelPack */
     fn new(node_pack : NodePack) -> Self
     {
-        let mut leaves : Vec<(String, Vec<Sender<Packet>>)> =
+        let mut leaves : Vec<(String, Vec<(String, Sender<Packet>)>)> =
             node_pack.leaves.into_iter().map(|leaf| {(leaf, vec![])}).collect();
 
-        let mut nodes : Vec<(Node, Vec<(usize, Sender<Packet>)>, Vec<Receiver<Packet>>)> =
+        let mut nodes : Vec<(Node, Vec<(usize, String, Sender<Packet>)>, Vec<(String, Receiver<Packet>)>)> =
             node_pack.nodes.into_iter().map(|node| {(node, vec![], vec![])}).collect();
 
         for node_index in 0..nodes.len()
         {
+            let target = nodes[node_index].0.targets[0].clone();
             for source_indicies_index in 0..nodes[node_index].0.source_indices.len()
             {
                 let (sender, receiver) : (Sender<Packet>, Receiver<Packet>) = mpsc::channel();
+                let source =
                 match nodes[node_index].0.source_indices[source_indicies_index]
                 {
-                    SourceIndex::Leaf(i) => leaves[i].1.push(sender),
-                    SourceIndex::Pair(i, sub_index) => nodes[i].1.push((sub_index, sender)),
-                }
+                    SourceIndex::Leaf(i) =>
+                    {
+                        leaves[i].1.push((target.clone(), sender));
+                        leaves[i].0.clone()
+                    },
+                    SourceIndex::Pair(i, sub_index) =>
+                    {
+                        nodes[i].1.push((sub_index, target.clone(), sender));
+                        nodes[i].0.targets[sub_index].clone()
+                    },
+                };
 
-                nodes[node_index].2.push(receiver);
+                nodes[node_index].2.push((source, receiver));
+            }
+
+            /*  Global sources come last, so they fold into the sources ticket after the rule's own. */
+            for i in node_pack.global_source_indices.iter()
+            {
+                let (sender, receiver) : (Sender<Packet>, Receiver<Packet>) = mpsc::channel();
+                leaves[*i].1.push((target.clone(), sender));
+                nodes[node_index].2.push((leaves[*i].0.clone(), receiver));
             }
         }
 
@@ -135,6 +250,33 @@ impl ChannelPack
     }
 }
 
+/*  For each node in a NodePack, the tasks it has to wait for when run on a WorkerPool: leaves are
+    tasks 0 through leaves.len() - 1, and node i is task leaves.len() + i. */
+fn get_task_dependencies(node_pack : &NodePack) -> Vec<Vec<usize>>
+{
+    let leaf_count = node_pack.leaves.len();
+    node_pack.nodes.iter().map(
+        |node|
+        {
+            let mut dependencies : Vec<usize> = node.source_indices.iter().map(
+                |source_index| match source_index
+                {
+                    SourceIndex::Leaf(i) => *i,
+                    SourceIndex::Pair(i, _sub_index) => leaf_count + *i,
+                }).chain(node_pack.global_source_indices.iter().cloned()).collect();
+            dependencies.sort();
+            dependencies.dedup();
+            dependencies
+        }).collect()
+}
+
+/*  The most workers the last build on this thread had running at once, if it used a WorkerPool. */
+#[cfg(test)]
+thread_local!
+{
+    static LAST_PEAK_WORKERS : std::cell::Cell<Option<usize>> = std::cell::Cell::new(None);
+}
+
 #[derive(Debug)]
 pub enum BuildError
 {
@@ -145,13 +287,17 @@ pub enum BuildError
     RuleFileNotUTF8,
     RuleFileFailedToRead(String, io::Error),
     RuleFileFailedToOpen(String, SystemError),
-    WorkErrors(Vec<WorkError>),
+    RuleFileFailedToDownload(String, DownloadError),
+    WorkErrors(Vec<(Option<Provenance>, WorkError)>),
     RuleFileFailedToParse(ParseError),
     TopologicalSortFailed(TopologicalSortError),
-    DirectoryMalfunction,
+    DirectoryInitFailed(InitDirectoryError),
     HistoryError(HistoryError),
     DownloadUrlsError(DownloadUrlsError),
     WorkError(WorkError),
+    NoRules(Option<String>),
+    RulesRequired(Vec<String>),
+    ChangedListIncomplete(Vec<String>),
     Weird,
 }
 
@@ -188,18 +334,25 @@ impl fmt::Display for BuildError
             BuildError::RuleFileFailedToOpen(path, error) =>
                 write!(formatter, "Rules file {} failed to open with error: {}", path, error),
 
+            BuildError::RuleFileFailedToDownload(url, error) =>
+                write!(formatter, "Rules file {} failed to download with error: {}", url, error),
+
             BuildError::WorkErrors(work_errors) =>
             {
                 let mut error_text = String::new();
-                for work_error in work_errors.iter()
+                for (provenance_opt, work_error) in work_errors.iter()
                 {
-                    error_text.push_str(&format!("{}\n", work_error));
+                    match provenance_opt
+                    {
+                        Some(provenance) => error_text.push_str(&format!("{}: {}\n", provenance, work_error)),
+                        None => error_text.push_str(&format!("{}\n", work_error)),
+                    }
                 }
                 write!(formatter, "{}", error_text)
             },
 
-            BuildError::DirectoryMalfunction =>
-                write!(formatter, "Error while managing ruler directory."),
+            BuildError::DirectoryInitFailed(error) =>
+                write!(formatter, "{}", error),
 
             BuildError::HistoryError(error) =>
                 write!(formatter, "Rule history error: {}", error),
@@ -210,6 +363,21 @@ impl fmt::Display for BuildError
             BuildError::WorkError(error) =>
                 write!(formatter, "{}", error),
 
+            BuildError::NoRules(None) =>
+                write!(formatter, "Rules file(s) parsed but contain no rules"),
+
+            BuildError::NoRules(Some(target)) =>
+                write!(formatter, "Target {} not found, and that's because the rules file(s) parsed \
+                    contain no rules at all.  Is a rules file empty or malformed?", target),
+
+            BuildError::RulesRequired(rulefile_paths) =>
+                write!(formatter, "Rules required, but no rules found in: {}", rulefile_paths.join(", ")),
+
+            BuildError::ChangedListIncomplete(paths) =>
+                write!(formatter, "Sources changed but not in the list of changed files: {}.  Building anyway \
+                    would use what was recorded for them, so nothing was built.  Fix the list, or build without it.",
+                    paths.join(", ")),
+
             BuildError::Weird =>
                 write!(formatter, "Weird! How did you do that!"),
         }
@@ -237,43 +405,123 @@ impl fmt::Display for RunError
     }
 }
 
-fn read_all_rules_files_to_strings<SystemType : System>
+/*  Rules files given as http(s) urls get downloaded rather than opened. */
+fn is_url(rulefile_path : &str) -> bool
+{
+    rulefile_path.starts_with("http://") || rulefile_path.starts_with("https://")
+}
+
+/*  The variable rules can mention to keep each variant's targets apart, say in a directory per variant. */
+const VARIANT_VARIABLE : &str = "$(VARIANT)";
+
+/*  Rules file text with every mention of VARIANT_VARIABLE replaced by the variant's name.  This happens
+    before parsing, so the name ends up in the targets, sources and command alike, and so in the rule's
+    ticket. */
+fn substitute_variant(content : &str, variant : &str) -> String
+{
+    content.replace(VARIANT_VARIABLE, variant)
+}
+
+/*  Reads each rules file, from the filesystem or, if the path is a url, through the downloader.  A url
+    listed more than once is only fetched once.  Along with the contents come the paths of the files
+    that had a byte-order-mark or \r\n line endings taken out, for the caller to mention. */
+pub fn read_all_rules_files_to_strings
+<
+    SystemType : System,
+    DownloaderType : Downloader,
+>
 (
     system : &SystemType,
+    downloader : &DownloaderType,
     mut rulefile_paths : Vec<String>
 )
--> Result<Vec<(String, String)>, BuildError>
+-> Result<(Vec<(String, String)>, Vec<String>), BuildError>
 {
     let mut result : Vec<(String, String)> = vec![];
+    let mut normalized_paths : Vec<String> = vec![];
+    let mut fetched : HashMap<String, Vec<u8>> = HashMap::new();
     for rulefile_path in rulefile_paths.drain(..)
     {
-        match system.open(&rulefile_path)
+        let rule_content =
+        if is_url(&rulefile_path)
         {
-            Ok(mut file) =>
+            match fetched.get(&rulefile_path)
             {
-                let mut rule_content = Vec::new();
-                match file.read_to_end(&mut rule_content)
+                Some(rule_content) => rule_content.clone(),
+                None =>
                 {
-                    Ok(_size) => match from_utf8(&rule_content)
+                    match downloader.download_bytes(&rulefile_path)
                     {
-                        Ok(rule_text) => result.push((rulefile_path, rule_text.to_string())),
-                        Err(_) => return Err(BuildError::RuleFileNotUTF8),
-                    },
-                    Err(error) => return Err(
-                        BuildError::RuleFileFailedToRead(
-                            rulefile_path.to_string(), error)),
+                        Ok(rule_content) =>
+                        {
+                            fetched.insert(rulefile_path.clone(), rule_content.clone());
+                            rule_content
+                        },
+                        Err(error) => return Err(
+                            BuildError::RuleFileFailedToDownload(
+                                rulefile_path.to_string(), error)),
+                    }
+                }
+            }
+        }
+        else
+        {
+            match system.open(&rulefile_path)
+            {
+                Ok(mut file) =>
+                {
+                    let mut rule_content = Vec::new();
+                    match file.read_to_end(&mut rule_content)
+                    {
+                        Ok(_size) => rule_content,
+                        Err(error) => return Err(
+                            BuildError::RuleFileFailedToRead(
+                                rulefile_path.to_string(), error)),
+                    }
+                },
+                Err(error) => return Err(
+                    BuildError::RuleFileFailedToOpen(
+                        rulefile_path.to_string(), error)),
+            }
+        };
+
+        match from_utf8(&rule_content)
+        {
+            Ok(rule_text) =>
+            {
+                let (rule_text, normalized) = normalize_line_endings(rule_text);
+                if normalized
+                {
+                    normalized_paths.push(rulefile_path.clone());
                 }
+                result.push((rulefile_path, rule_text))
             },
-            Err(error) => return Err(
-                BuildError::RuleFileFailedToOpen(
-                    rulefile_path.to_string(), error)),
+            Err(_) => return Err(BuildError::RuleFileNotUTF8),
         }
     }
 
-    Ok(result)
+    Ok((result, normalized_paths))
+}
+
+/*  Prints a warning for each rules file read as if its line endings were plain. */
+pub fn print_normalized_rules_warnings
+<
+    PrinterType : Printer,
+>
+(
+    printer : &mut PrinterType,
+    normalized_paths : &Vec<String>
+)
+{
+    for path in normalized_paths.iter()
+    {
+        printer.print_single_banner_line("   Warning", Color::Yellow,
+            &format!("{} has a byte-order-mark or \\r\\n line endings, read as if it had plain \\n", path));
+    }
 }
 
-/*  Open the rulefile(s), parse, and return the vector of Nodes. */
+/*  Open the rulefile(s), parse, and return the vector of Nodes.  If dedupe_rules is true, rules that
+    are exact duplicates of an earlier rule are dropped rather than reported as conflicting. */
 pub fn get_nodes
 <
     SystemType : System,
@@ -281,40 +529,310 @@ pub fn get_nodes
 (
     system : &SystemType,
     rulefile_paths : Vec<String>,
-    goal_target_opt: Option<String>
+    goal_target_opt: Option<String>,
+    dedupe_rules : bool,
+    case_insensitive_paths : bool,
+    variant_opt : Option<&str>
+)
+-> Result<NodePack, BuildError>
+{
+    get_nodes_with_downloader(system, &HttpDownloader::new(), rulefile_paths, goal_target_opt, dedupe_rules, case_insensitive_paths, variant_opt)
+}
+
+/*  Like get_nodes, but rules files given as urls are fetched through the given downloader. */
+pub fn get_nodes_with_downloader
+<
+    SystemType : System,
+    DownloaderType : Downloader,
+>
+(
+    system : &SystemType,
+    downloader : &DownloaderType,
+    rulefile_paths : Vec<String>,
+    goal_target_opt: Option<String>,
+    dedupe_rules : bool,
+    case_insensitive_paths : bool,
+    variant_opt : Option<&str>
 )
 -> Result<NodePack, BuildError>
 {
-    let all_rule_text = read_all_rules_files_to_strings(system, rulefile_paths)?;
+    let (all_rule_text, _normalized_paths) = read_rules_with_variant(system, downloader, rulefile_paths, variant_opt)?;
+    get_nodes_from_rule_text(all_rule_text, goal_target_opt, dedupe_rules, case_insensitive_paths)
+}
+
+/*  The path and content of each rules file, with the variant, if any, substituted in, and the paths
+    of those whose line endings got normalized. */
+fn read_rules_with_variant
+<
+    SystemType : System,
+    DownloaderType : Downloader,
+>
+(
+    system : &SystemType,
+    downloader : &DownloaderType,
+    rulefile_paths : Vec<String>,
+    variant_opt : Option<&str>
+)
+-> Result<(Vec<(String, String)>, Vec<String>), BuildError>
+{
+    let (mut all_rule_text, normalized_paths) = read_all_rules_files_to_strings(system, downloader, rulefile_paths)?;
+    match variant_opt
+    {
+        Some(variant) =>
+        {
+            for (_path, content) in all_rule_text.iter_mut()
+            {
+                *content = substitute_variant(content, variant);
+            }
+        },
+        None => {},
+    }
+    Ok((all_rule_text, normalized_paths))
+}
 
-    let rules =
-    match parse_all(all_rule_text)
+/*  Parses and sorts the rules already read, the rest of get_nodes. */
+fn get_nodes_from_rule_text
+(
+    all_rule_text : Vec<(String, String)>,
+    goal_target_opt: Option<String>,
+    dedupe_rules : bool,
+    case_insensitive_paths : bool
+)
+-> Result<NodePack, BuildError>
+{
+    let (mut rules, global_sources) =
+    match parse_all_with_global_sources(all_rule_text)
     {
-        Ok(rules) => rules,
+        Ok(parsed) => parsed,
         Err(error) => return Err(BuildError::RuleFileFailedToParse(error)),
     };
 
-    Ok(
-        match goal_target_opt
+    /*  With no rules at all, any goal target is bound to be missing, but saying so would suggest a typo
+        in the target when the likelier story is an empty or broken rules file. */
+    match (&goal_target_opt, rules.is_empty())
+    {
+        (Some(goal_target), true) => return Err(BuildError::NoRules(Some(goal_target.clone()))),
+        _ => {},
+    }
+
+    if dedupe_rules
+    {
+        rules = dedupe_identical_rules(rules);
+    }
+
+    let mut node_pack =
+    match goal_target_opt
+    {
+        Some(goal_target) =>
+        {
+            match topological_sort_with_path_case(rules, &goal_target, case_insensitive_paths)
+            {
+                Ok(pack) => pack,
+                Err(error) => return Err(BuildError::TopologicalSortFailed(error)),
+            }
+        },
+        None =>
+        {
+            match topological_sort_all_with_path_case(rules, case_insensitive_paths)
+            {
+                Ok(pack) => pack,
+                Err(error) => return Err(BuildError::TopologicalSortFailed(error)),
+            }
+        }
+    };
+
+    match node_pack.add_global_sources(global_sources)
+    {
+        Ok(()) => Ok(node_pack),
+        Err(error) => Err(BuildError::TopologicalSortFailed(error)),
+    }
+}
+
+/*  The NodePack from the last build, with a ticket for everything that went into it. */
+#[derive(Serialize, Deserialize)]
+struct NodePackCache
+{
+    key : Ticket,
+    node_pack : NodePack,
+}
+
+/*  The path of the file in the ruler directory keeping the rules as last parsed and sorted. */
+pub fn get_node_pack_cache_path(directory_path : &str) -> String
+{
+    format!("{}/nodes", directory_path)
+}
+
+/*  A ticket for the rules files and everything else that decides what get_nodes makes of them, the
+    version of ruler included, since that decides the form they're kept in. */
+fn get_node_pack_key
+(
+    all_rule_text : &Vec<(String, String)>,
+    goal_target_opt : &Option<String>,
+    dedupe_rules : bool,
+    case_insensitive_paths : bool
+)
+-> Ticket
+{
+    let mut factory = TicketFactory::from_str(env!("CARGO_PKG_VERSION"));
+    for (path, content) in all_rule_text.iter()
+    {
+        factory.input_ticket(TicketFactory::from_str(path).result());
+        factory.input_ticket(TicketFactory::from_str(content).result());
+    }
+
+    match goal_target_opt
+    {
+        Some(goal_target) => factory.input_ticket(TicketFactory::from_str(goal_target).result()),
+        None => factory.input_str("*"),
+    }
+    factory.input_str(&format!("{} {}", dedupe_rules, case_insensitive_paths));
+    factory.result()
+}
+
+/*  Like get_nodes_with_downloader for the rules files, goal target and options in params, but when
+    those are just as they were the last time, the NodePack comes from the ruler directory instead of parsing and sorting again.  Otherwise it
+    gets made as usual, counted in sort_count, and kept for next time.  Trouble reading or writing the
+    kept NodePack only means parsing and sorting.  A process doing several builds can keep the last
+    NodePack in memory too, in memo_opt, and then that gets checked first and left up-to-date. */
+fn get_nodes_cached
+<
+    SystemType : System,
+    DownloaderType : Downloader,
+>
+(
+    system : &mut SystemType,
+    downloader : &DownloaderType,
+    params : &BuildParams,
+    sort_count : &mut usize,
+    normalized_paths : &mut Vec<String>,
+    memo_opt : &mut Option<(Ticket, NodePack)>
+)
+-> Result<NodePack, BuildError>
+{
+    let (all_rule_text, paths) = read_rules_with_variant(system, downloader, params.rulefile_paths.clone(),
+        params.variant_opt.as_deref())?;
+    *normalized_paths = paths;
+    let key = get_node_pack_key(&all_rule_text, &params.goal_target_opt, params.dedupe_rules, params.case_insensitive_paths);
+    match memo_opt
+    {
+        Some((memo_key, node_pack)) if *memo_key == key => return Ok(node_pack.clone()),
+        _ => {},
+    }
+
+    let cache_path = get_node_pack_cache_path(&params.directory_path);
+
+    let mut content = vec![];
+    match system.open(&cache_path)
+    {
+        Ok(mut file) => match file.read_to_end(&mut content)
         {
-            Some(goal_target) =>
+            Ok(_size) => match bincode::deserialize::<NodePackCache>(&content)
             {
-                match topological_sort(rules, &goal_target)
+                Ok(cached) if cached.key == key =>
                 {
-                    Ok(pack) => pack,
-                    Err(error) => return Err(BuildError::TopologicalSortFailed(error)),
-                }
+                    *memo_opt = Some((key, cached.node_pack.clone()));
+                    return Ok(cached.node_pack);
+                },
+                _ => {},
             },
-            None =>
+            Err(_error) => {},
+        },
+        Err(_error) => {},
+    }
+
+    let node_pack = get_nodes_from_rule_text(all_rule_text, params.goal_target_opt.clone(), params.dedupe_rules,
+        params.case_insensitive_paths)?;
+    *sort_count += 1;
+
+    let cached = NodePackCache{key : key, node_pack : node_pack};
+    match bincode::serialize(&cached)
+    {
+        Ok(content) => match system.create_file(&cache_path)
+        {
+            Ok(mut file) => match file.write_all(&content)
             {
-                match topological_sort_all(rules)
-                {
-                    Ok(pack) => pack,
-                    Err(error) => return Err(BuildError::TopologicalSortFailed(error)),
-                }
-            }
+                Ok(_) => {},
+                Err(_error) => {},
+            },
+            Err(_error) => {},
+        },
+        Err(_error) => {},
+    }
+
+    *memo_opt = Some((cached.key, cached.node_pack.clone()));
+    Ok(cached.node_pack)
+}
+
+/*  This is the function that runs when you type "ruler print-command" at the command-line.  Finds
+    the rule with the given target and returns its command as the lines that would be executed, without
+    building anything. */
+pub fn get_command_lines
+<
+    SystemType : System,
+>
+(
+    system : &SystemType,
+    rulefile_paths : Vec<String>,
+    target : String,
+    dedupe_rules : bool,
+    case_insensitive_paths : bool
+)
+-> Result<Vec<String>, BuildError>
+{
+    let mut node_pack = get_nodes(system, rulefile_paths, Some(target.clone()), dedupe_rules, case_insensitive_paths, None)?;
+    let target_key = to_path_key(&target, case_insensitive_paths);
+    for node in node_pack.nodes.drain(..)
+    {
+        if node.targets.iter().any(|node_target| to_path_key(node_target, case_insensitive_paths) == target_key)
+        {
+            return Ok(to_command_script(node.command).lines);
         }
-    )
+    }
+
+    Err(BuildError::Weird)
+}
+
+/*  This is the function that runs when you type "ruler print-leaves" at the command-line.  Returns
+    every leaf of the whole graph, that is, every source no rule produces, sorted. */
+pub fn get_leaves
+<
+    SystemType : System,
+>
+(
+    system : &SystemType,
+    rulefile_paths : Vec<String>,
+    dedupe_rules : bool,
+    case_insensitive_paths : bool
+)
+-> Result<Vec<String>, BuildError>
+{
+    let mut leaves = get_nodes(system, rulefile_paths, None, dedupe_rules, case_insensitive_paths, None)?.leaves;
+    leaves.sort();
+    Ok(leaves)
+}
+
+/*  Called after a build with --compare-to.  Compares each target of the rules the build covered (the
+    goal target's, and the rules it depends on, if there is one) against its golden copy under
+    golden_directory, and returns the ones that differ. */
+pub fn compare_to_golden
+<
+    SystemType : System,
+>
+(
+    system : &SystemType,
+    params : BuildParams,
+    golden_directory : &str
+)
+-> Result<Vec<TargetMismatch>, BuildError>
+{
+    let node_pack = get_nodes(system, params.rulefile_paths, params.goal_target_opt,
+        params.dedupe_rules, params.case_insensitive_paths, params.variant_opt.as_deref())?;
+    let targets : Vec<String> = node_pack.nodes.into_iter().flat_map(|node| node.targets).collect();
+    match hash::compare_to_golden(system, &targets, golden_directory)
+    {
+        Ok(mismatches) => Ok(mismatches),
+        Err(error) => Err(BuildError::WorkError(error)),
+    }
 }
 
 #[derive(Deserialize, PartialEq, Debug)]
@@ -356,57 +874,258 @@ impl fmt::Display for DownloadUrlsError
     }
 }
 
-/*  From the given urls file, read the config file and parse as toml to obtain a DownloadUrlsList */
-fn read_download_urls<SystemType : System>
+/*  The urls file as written, before each url gets checked.  Entries stay as toml values so one that
+    isn't even a string doesn't stop the others from being read. */
+#[derive(Deserialize)]
+struct DownloadUrlsFile
+{
+    urls: Vec<toml::Value>
+}
+
+/*  Whether url looks like something a download could be attempted from: http or https, a host, and no
+    whitespace or control characters anywhere. */
+fn is_valid_download_url(url : &str) -> bool
+{
+    let rest =
+    match url.strip_prefix("http://").or_else(|| url.strip_prefix("https://"))
+    {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let host = rest.split(|c| c == '/' || c == '?' || c == '#').next().unwrap_or("");
+    host != ""
+        && ! host.starts_with(':')
+        && ! url.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/*  From the given urls file, read the config file and parse as toml to obtain a DownloadUrlsList.  Only
+    a file that can't be read or parsed is an error.  Entries that aren't valid urls get skipped, each
+    with a warning, and the rest get used. */
+fn read_download_urls<SystemType : System, PrinterType : Printer>
 (
     system : &SystemType,
+    printer : &mut PrinterType,
     path_str : &str
 )
 ->
 Result<DownloadUrls, DownloadUrlsError>
 {
+    let content_string =
     match read_file_to_string(system, path_str)
     {
-        Ok(content_string) =>
-        {
-            return match toml::from_str(&content_string)
-            {
-                Ok(config) => Ok(config),
-                Err(error) => Err(DownloadUrlsError::TomlDeError(error)),
-            }
-        },
+        Ok(content_string) => content_string,
         Err(error) => return Err(DownloadUrlsError::FailedToReadFile(error)),
+    };
+
+    let (content_string, _normalized) = normalize_line_endings(&content_string);
+    let file : DownloadUrlsFile =
+    match toml::from_str(&content_string)
+    {
+        Ok(file) => file,
+        Err(error) => return Err(DownloadUrlsError::TomlDeError(error)),
+    };
+
+    let mut download_urls = DownloadUrls::new();
+    for value in file.urls
+    {
+        match value.as_str()
+        {
+            Some(url) if is_valid_download_url(url) => download_urls.urls.push(url.to_string()),
+            _ => printer.print_single_banner_line("   Warning", Color::Yellow,
+                &format!("Skipping malformed download url in {}: {}", path_str, value)),
+        }
     }
+
+    Ok(download_urls)
 }
 
-/*  Takes a vector of receivers, and waits for them all to receive, so it can
-    hash together all their results into one Ticket obejct.  Returns an error
-    if the receivers error or if the packet produces an error when it tries to
-    get the ticket from it. */
-fn wait_for_sources_ticket(receiver_vec : Vec<Receiver<Packet>>) -> Result<Ticket, BuildError>
+/*  Where node threads report each packet they send or receive when the build traces packets.  The
+    main thread prints what arrives.  When not tracing there's no channel, and nothing gets formatted. */
+#[derive(Clone)]
+struct PacketTracer
 {
-    let mut tickets = vec![];
-    let mut canceled = false;
+    sender_opt : Option<Sender<String>>,
+    start : u64,
+}
 
-    /*  It is tempting to have this loop exit early if one source cancels, but
+impl PacketTracer
+{
+    fn trace(&self, verb : &str, source : &str, target : &str, packet : &Packet)
+    {
+        match &self.sender_opt
+        {
+            Some(sender) =>
+            {
+                match sender.send(format!("packet {} {} -> {}: {} at +{}us",
+                    verb, source, target, packet.describe(), timing::now().saturating_sub(self.start)))
+                {
+                    Ok(_) => {},
+                    Err(_error) => {},
+                }
+            },
+            None => {},
+        }
+    }
+
+    /*  Sends the packet from the source to the target, tracing it on the way. */
+    fn send(&self, source : &str, target : &str, sender : &Sender<Packet>, packet : Packet) -> Result<(), BuildError>
+    {
+        self.trace("sent", source, target, &packet);
+        match sender.send(packet)
+        {
+            Ok(_) => Ok(()),
+            Err(error) => Err(BuildError::SenderError(error)),
+        }
+    }
+}
+
+/*  How long a rule waits on one of its sources before saying so, when the build shows waiting. */
+const WAITING_REPORT_THRESHOLD : Duration = Duration::from_secs(10);
+
+/*  How often a waiting rule looks at the clock. */
+const WAITING_POLL_INTERVAL : Duration = Duration::from_millis(100);
+
+/*  Where node threads report having waited a long time on a source, when the build shows waiting.
+    Waiting goes by the System's clock.  Without a channel, receiving just blocks. */
+#[derive(Clone)]
+struct WaitingReporter
+{
+    sender_opt : Option<Sender<String>>,
+}
+
+impl WaitingReporter
+{
+    fn report(&self, sender : &Sender<String>, line : String)
+    {
+        match sender.send(line)
+        {
+            Ok(_) => {},
+            Err(_error) => {},
+        }
+    }
+
+    /*  Receives the next packet from the source.  The first time target has waited longer than
+        WAITING_REPORT_THRESHOLD since wait_start, reports the source it's blocked on and sets
+        reported, so a rule says it's waiting once however many sources it waits on.  If the source
+        goes away without sending, says that too. */
+    fn receive<SystemType : System>
+    (
+        &self,
+        system : &SystemType,
+        receiver : &Receiver<Packet>,
+        source : &str,
+        target : &str,
+        wait_start : SystemTime,
+        reported : &mut bool
+    )
+    -> Result<Packet, BuildError>
+    {
+        let sender =
+        match &self.sender_opt
+        {
+            Some(sender) => sender,
+            None =>
+            {
+                return match receiver.recv()
+                {
+                    Ok(packet) => Ok(packet),
+                    Err(error) => Err(BuildError::ReceiverError(error)),
+                }
+            },
+        };
+
+        loop
+        {
+            match receiver.recv_timeout(WAITING_POLL_INTERVAL)
+            {
+                Ok(packet) => return Ok(packet),
+                Err(RecvTimeoutError::Timeout) =>
+                {
+                    let waited =
+                    match system.now().duration_since(wait_start)
+                    {
+                        Ok(waited) => waited,
+                        Err(_error) => Duration::ZERO,
+                    };
+
+                    if !*reported && waited > WAITING_REPORT_THRESHOLD
+                    {
+                        self.report(sender, format!("Waiting: {} on {}", target, source));
+                        *reported = true;
+                    }
+                },
+                Err(RecvTimeoutError::Disconnected) =>
+                {
+                    self.report(sender, format!("Stopped waiting: {} on {}, which finished without sending", target, source));
+                    return Err(BuildError::ReceiverError(RecvError));
+                },
+            }
+        }
+    }
+}
+
+/*  Prints whatever packet traces and waiting reports have arrived so far, without waiting for more. */
+fn print_packet_traces<PrinterType : Printer>
+(
+    printer : &mut PrinterType,
+    trace_receiver_opt : &Option<Receiver<String>>
+)
+{
+    match trace_receiver_opt
+    {
+        Some(trace_receiver) =>
+        {
+            for line in trace_receiver.try_iter()
+            {
+                printer.print(&line);
+            }
+        },
+        None => {},
+    }
+}
+
+/*  Takes a vector of receivers, and waits for them all to receive, so it can
+    hash together all their results into one Ticket obejct.  Also returns the
+    FileState each source arrived with, by source path.  Returns an error if the
+    receivers error or if the packet produces an error when it tries to get
+    the file state from it.  Each receiver comes with the name of its source, and the
+    target is the node's own, for tracing and for saying what's waiting on what. */
+fn wait_for_sources<SystemType : System>
+(
+    system : &SystemType,
+    receiver_vec : Vec<(String, Receiver<Packet>)>,
+    target : &str,
+    tracer : &PacketTracer,
+    waiting_reporter : &WaitingReporter
+)
+-> Result<(Ticket, HashMap<String, FileState>), BuildError>
+{
+    let mut file_states = vec![];
+    let mut canceled = false;
+    let wait_start = system.now();
+    let mut reported = false;
+
+    /*  It is tempting to have this loop exit early if one source cancels, but
         that makes possible the following race:
 
         Suppose two sources A and B.  A cancels quickly, then this loop bails early,
         the thread exist, the receiving channel closes.  Later B tries to send a
         source ticket and fails with "sending on a closed channel" */
-    for receiver in receiver_vec.iter()
+    for (source, receiver) in receiver_vec.iter()
     {
-        match receiver.recv()
+        match waiting_reporter.receive(system, receiver, source, target, wait_start, &mut reported)
         {
             Ok(packet) =>
             {
-                match packet.get_ticket()
+                tracer.trace("received", source, target, &packet);
+                match packet.get_file_state()
                 {
-                    Ok(ticket) => tickets.push(ticket),
+                    Ok(file_state) => file_states.push((source.clone(), file_state)),
                     Err(PacketError::Cancel) => canceled = true,
                 }
             },
-            Err(error) => return Err(BuildError::ReceiverError(error)),
+            Err(error) => return Err(error),
         }
     }
 
@@ -416,601 +1135,4682 @@ fn wait_for_sources_ticket(receiver_vec : Vec<Receiver<Packet>>) -> Result<Ticke
     }
 
     let mut factory = TicketFactory::new();
-    for ticket in tickets
+    for (_source, file_state) in file_states.iter()
     {
-        factory.input_ticket(ticket);
+        factory.input_ticket(file_state.ticket.clone());
     }
-    Ok(factory.result())
+    Ok((factory.result(), file_states.into_iter().collect()))
 }
 
-pub struct BuildParams
+/*  With command provenance, the ticket a target counts for as a source: its content ticket and the
+    ticket of the rule that built it, hashed together. */
+fn get_provenance_ticket(ticket : &Ticket, rule_ticket : &Ticket) -> Ticket
 {
-    directory_path : String,
-    rulefile_paths : Vec<String>,
-    urlfile_path_opt : Option<String>,
-    goal_target_opt: Option<String>,
+    let mut factory = TicketFactory::new();
+    factory.input_ticket(ticket.clone());
+    factory.input_ticket(rule_ticket.clone());
+    factory.result()
 }
 
-impl BuildParams
+/*  Folds the recursive content ticket of each directory source into a node's sources ticket, after all
+    its other sources.  Ignored files don't count, as always with directory tickets.  A directory source
+    that isn't there is as much an error as a missing source file.  A node with no directory sources
+    keeps the ticket it had. */
+fn add_directory_tickets<SystemType : System>
+(
+    system : &SystemType,
+    sources_ticket : Ticket,
+    directories : &Vec<String>
+)
+-> Result<Ticket, WorkError>
 {
-    pub fn from_all(
-        directory_path : String,
-        rulefile_paths : Vec<String>,
-        urlfile_path_opt : Option<String>,
-        goal_target_opt : Option<String>,
-    ) -> Self
+    if directories.is_empty()
     {
-        BuildParams
+        return Ok(sources_ticket);
+    }
+
+    let mut factory = TicketFactory::new();
+    factory.input_ticket(sources_ticket);
+    for directory in directories.iter()
+    {
+        if ! system.is_dir(directory)
         {
-            directory_path : directory_path,
-            rulefile_paths : rulefile_paths,
-            urlfile_path_opt : urlfile_path_opt,
-            goal_target_opt : goal_target_opt,
+            return Err(WorkError::FileNotFound(directory.clone()));
+        }
+
+        match TicketFactory::from_directory(system, directory)
+        {
+            Ok(mut directory_factory) => factory.input_ticket(directory_factory.result()),
+            Err(error) => return Err(WorkError::ReadWriteError(directory.clone(), error)),
         }
     }
+    Ok(factory.result())
 }
 
-/*  This is the function that runs when you type "ruler build" at the commandline.
-    It opens the rulefile, parses it, and then either updates all targets in all rules
-    or, if goal_target_opt is Some, only the targets that are ancestors of goal_target_opt
-    in the dependence graph. */
-pub fn build
-<
-    SystemType : System + 'static,
-    PrinterType : Printer,
->
-(
-    mut system : SystemType,
-    printer : &mut PrinterType,
-    params : BuildParams
-)
--> Result<(), BuildError>
+/*  What a successful build did, counted by target.  Pinned targets count as up-to-date. */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BuildSummary
 {
-    let mut elements =
-    match directory::init(&mut system, &params.directory_path)
+    pub built : usize,
+    pub recovered : usize,
+    pub downloaded : usize,
+    pub up_to_date : usize,
+}
+
+impl BuildSummary
+{
+    pub fn new() -> BuildSummary
     {
-        Ok(elements) => elements,
-        Err(error) =>
+        BuildSummary
         {
-            return match error
-            {
-                InitDirectoryError::FailedToReadCurrentFileStates(current_file_states_error) =>
-                    Err(BuildError::FailedToReadCurrentFileStates(current_file_states_error)),
-                _ => Err(BuildError::DirectoryMalfunction),
-            }
+            built : 0,
+            recovered : 0,
+            downloaded : 0,
+            up_to_date : 0,
         }
-    };
+    }
 
-    let download_urls =
-    match params.urlfile_path_opt
+    /*  True when every target was up-to-date already, so the build changed nothing. */
+    pub fn is_nothing_to_do(&self) -> bool
     {
-        None => DownloadUrls::new(),
-        Some(path_string) =>
+        self.built == 0 && self.recovered == 0 && self.downloaded == 0
+    }
+
+    /*  The line build prints last, saying in one go whether there was anything to do. */
+    pub fn to_line(&self, seconds : f64) -> String
+    {
+        if self.is_nothing_to_do()
         {
-            match read_download_urls(&system, &path_string)
-            {
-                Ok(download_urls) => download_urls,
-                Err(error) => return Err(BuildError::DownloadUrlsError(error)),
-            }
+            format!("Nothing to do: {} targets up to date", self.up_to_date)
         }
-    };
+        else
+        {
+            format!("Build succeeded: {} built, {} recovered, {} downloaded, {} up-to-date in {:.2} seconds",
+                self.built, self.recovered, self.downloaded, self.up_to_date, seconds)
+        }
+    }
+}
 
-    let mut channel_pack = ChannelPack::new(get_nodes(&system, params.rulefile_paths, params.goal_target_opt)?);
-    let mut handles = Vec::new();
+/*  The file in the ruler directory recording how the last successful build went: "nothing-to-do" or
+    "built" on the first line, so scripts can tell the two apart, then the summary line. */
+pub fn get_last_build_path(directory_path : &str) -> String
+{
+    format!("{}/last-build", directory_path)
+}
 
-    for (leaf, sender_vec) in channel_pack.leaves.drain(..)
+/*  Seconds from start_time until the system's now, or 0 if the clock went backwards. */
+fn seconds_since<SystemType : System>(system : &SystemType, start_time : SystemTime) -> f64
+{
+    match system.now().duration_since(start_time)
     {
-        let blob = elements.current_file_states.take_blob(vec![leaf.clone()]);
-        let system_clone = system.clone();
-        handles.push(
-            (
-                None,
-                thread::spawn(
-                    move || -> Result<WorkResult, BuildError>
-                    {
-                        match handle_source_only_node(system_clone, blob)
-                        {
-                            Ok(result) =>
-                            {
-                                for sender in sender_vec
-                                {
-                                    match sender.send(Packet::from_ticket(result.file_state_vec.get_ticket(0)))
-                                    {
-                                        Ok(_) => {},
-                                        Err(error) => return Err(BuildError::SenderError(error)),
-                                    }
-                                }
-                                Ok(result)
-                            },
-                            Err(error) =>
-                            {
-                                for sender in sender_vec
-                                {
-                                    match sender.send(Packet::cancel())
-                                    {
-                                        Ok(_) => {},
-                                        Err(error) => return Err(BuildError::SenderError(error)),
-                                    }
-                                }
-                                Err(BuildError::WorkError(error))
-                            },
-                        }
-                    }
-                )
-            )
-        )
+        Ok(duration) => duration.as_secs_f64(),
+        Err(_) => 0.0,
     }
+}
 
-    for (mut node, sender_vec, receiver_vec) in channel_pack.nodes.drain(..)
-    {
-        let temp_targets = node.targets;
-        node.targets = vec![];
-        let blob = elements.current_file_states.take_blob(temp_targets);
+/*  How often the heartbeat updates while the sources get checked. */
+const HEARTBEAT_INTERVAL : Duration = Duration::from_millis(100);
 
-        let mut downloader_cache_urls = Vec::new();
-        let mut downloader_history_urls = Vec::new();
+/*  Printed after command output that had to be shown with replacement characters. */
+const NOT_UTF8_NOTE : &str = "(output above contained bytes that aren't valid UTF-8, shown as \u{FFFD})";
 
-        for url in &download_urls.urls
+/*  Which rules a build runs the commands of whatever their histories say.  Goal forces only the
+    rule for the goal target, Deep that rule and every rule it depends on, All every rule.  Rules that
+    depend on a forced rule aren't forced themselves (except under Deep and All): they rebuild only
+    if the forced rule's targets come out different, as usual. */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Force
+{
+    Off,
+    Goal,
+    Deep,
+    All,
+}
+
+impl Force
+{
+    /*  Whether a rule with the given targets gets forced in a build toward goal_target_opt.  A build
+        toward a goal only has the goal's rule and the rules it depends on, so under Deep, every rule
+        there is gets forced. */
+    fn forces_rule(&self, targets : &Vec<String>, goal_target_opt : &Option<String>, case_insensitive_paths : bool) -> bool
+    {
+        match self
         {
-            downloader_cache_urls.push(format!("{}/files", url));
-            downloader_history_urls.push(format!("{}/rules", url));
+            Force::Off => false,
+            Force::Goal =>
+            {
+                match goal_target_opt
+                {
+                    Some(goal_target) => targets.iter().any(
+                        |target| to_path_key(target, case_insensitive_paths) == to_path_key(goal_target, case_insensitive_paths)),
+                    None => false,
+                }
+            },
+            Force::Deep | Force::All => true,
         }
+    }
+}
 
-        let downloader_cache = DownloaderCache::new(downloader_cache_urls);
-        let downloader_history = DownloaderHistory::new(downloader_history_urls);
-        let system_clone = system.clone();
+#[derive(Clone)]
+pub struct BuildParams
+{
+    pub directory_path : String,
+    pub rulefile_paths : Vec<String>,
+    pub urlfile_path_opt : Option<String>,
+    pub goal_target_opt: Option<String>,
+    pub dedupe_rules : bool,
+    pub contradiction_policy : ContradictionPolicy,
+    pub report_unused_sources : bool,
 
-        let rule_history = match elements.history.read_rule_history(&node.rule_ticket)
-        {
-            Ok(rule_history) => rule_history,
-            Err(history_error) => return Err(BuildError::HistoryError(history_error)),
-        };
+    /*  When Some, commands run with an empty environment except for PATH and the variables listed here
+        (plus any the rule itself allows).  None means commands inherit ruler's environment. */
+    pub hermetic_env_opt : Option<Vec<String>>,
 
-        let cache_clone = elements.cache.clone();
-        let downloader_cache_clone = downloader_cache.clone();
-        let downloader_rule_history = downloader_history.get_rule_history(&node.rule_ticket);
+    /*  When true, a command writing output that isn't valid UTF-8 fails its rule instead of getting
+        printed with replacement characters. */
+    pub strict_utf8_output : bool,
 
-        handles.push(
-            (
-                Some(node.rule_ticket.clone()),
-                thread::spawn(
-                    move || -> Result<WorkResult, BuildError>
-                    {
-                        let mut info = HandleNodeInfo::new(system_clone);
-                        info.blob = blob;
+    /*  When true, rule histories get written gzipped. */
+    pub compress_history : bool,
 
-                        let sources_ticket = match wait_for_sources_ticket(receiver_vec)
-                        {
-                            Ok(sources_ticket) => sources_ticket,
-                            Err(error) =>
-                            {
-                                for (_sub_index, sender) in sender_vec
-                                {
-                                    match sender.send(Packet::cancel())
-                                    {
-                                        Ok(_) => {},
-                                        Err(error) => return Err(BuildError::SenderError(error)),
-                                    }
-                                }
-                                return Err(error);
-                            }
-                        };
+    /*  When true, building everything from rules files with no rules in them quietly succeeds.
+        Otherwise that's an error, since it usually means a rules file came out empty by mistake. */
+    pub allow_empty : bool,
 
-                        match handle_rule_node(info, RuleExt
-                            {
-                                sources_ticket : sources_ticket,
-                                command : node.command,
-                                rule_history : rule_history,
-                                cache : cache_clone,
-                                downloader_cache_opt : Some(downloader_cache_clone),
-                                downloader_rule_history_opt : Some(downloader_rule_history),
-                            })
-                        {
-                            Ok(result) =>
-                            {
-                                for (sub_index, sender) in sender_vec
-                                {
-                                    match sender.send(Packet::from_ticket(result.file_state_vec.get_ticket(sub_index)))
-                                    {
-                                        Ok(_) => {},
-                                        Err(error) => return Err(BuildError::SenderError(error)),
-                                    }
-                                }
-                                Ok(result)
-                            },
-                            Err(error) =>
-                            {
-                                for (_sub_index, sender) in sender_vec
-                                {
-                                    match sender.send(Packet::cancel())
-                                    {
-                                        Ok(_) => {},
-                                        Err(error) => return Err(BuildError::SenderError(error)),
-                                    }
-                                }
-                                Err(BuildError::WorkError(error))
-                            },
-                        }
-                    }
-                )
-            )
-        )
-    }
+    /*  Where to keep the cache, when not inside the ruler directory. */
+    pub cache_directory_opt : Option<String>,
 
-    let mut work_errors = Vec::new();
+    /*  When true, a command's stdout gets printed only if the command fails. */
+    pub output_on_failure : bool,
 
-    for (node_ticket, handle) in handles
-    {
-        match handle.join()
-        {
+    /*  The most records the ledger keeps before dropping the oldest. */
+    pub ledger_max_records : usize,
+
+    /*  When true, rules files with no rules in them are an error whatever else is asked for, even
+        with allow_empty. */
+    pub require_rules : bool,
+
+    /*  When true, sources match targets whatever the case of their paths. */
+    pub case_insensitive_paths : bool,
+
+    /*  How many cache entries to check against their tickets once the build is done.  0 checks none. */
+    pub verify_sample_size : usize,
+
+    /*  When true, the banner for each target is left out, and a successful build prints only its
+        summary line (plus any command output and warnings). */
+    pub quiet : bool,
+
+    /*  When true, every packet a node sends or receives gets printed, naming the target it came from,
+        the target it went to, and what it carried.  For working out why a build hangs. */
+    pub trace_packets : bool,
+
+    /*  When true, a rule that's waited on a target it depends on for longer than
+        WAITING_REPORT_THRESHOLD says which target, once. */
+    pub show_waiting : bool,
+
+    /*  When Some, the build keeps its own current file states under this name, and rules get the name
+        wherever they say $(VARIANT). */
+    pub variant_opt : Option<String>,
+
+    /*  When true, modified times get trusted even when the clock reads earlier than at the last build.
+        Otherwise such a build hashes every file. */
+    pub trust_clock : bool,
+
+    /*  When Some, the only sources that might have changed since the last build.  Sources not listed
+        are taken to be as recorded in current file states without looking at them, and listed ones
+        get hashed whatever their modified times say. */
+    pub changed_paths_opt : Option<Vec<String>>,
+
+    /*  With a list of changed sources, how many of the unlisted ones to check anyway before
+        building, failing the build if any turn out to have changed. */
+    pub changed_verify_sample : usize,
+
+    /*  Which rules run their commands whatever their histories say. */
+    pub force : Force,
+
+    /*  When true, a target counts as a source by its content and the ticket of the rule that built it
+        together, so changing a rule's command reruns everything downstream, even when the target
+        comes out the same. */
+    pub command_provenance : bool,
+
+    /*  When Some, the most commands that run at once. */
+    pub jobs_opt : Option<usize>,
+
+    /*  How many commands of each named group run at once.  Groups not listed run one at a time. */
+    pub group_jobs : Vec<(String, usize)>,
+
+    /*  When Some, where to write progress events as they happen, one JSON object per line. */
+    pub progress_path_opt : Option<String>,
+
+    /*  When true, rule histories are neither read nor written, for a ruler directory that gets thrown
+        away after one build.  Targets then get recovered only by what the current file states and the
+        cache already say. */
+    pub no_history : bool,
+}
+
+impl BuildParams
+{
+    /*  Params for building everything from the given rules files in the ruler directory at
+        directory_path, with every option off.  Callers set whichever fields they need after. */
+    pub fn new(directory_path : String, rulefile_paths : Vec<String>) -> Self
+    {
+        BuildParams
+        {
+            directory_path : directory_path,
+            rulefile_paths : rulefile_paths,
+            urlfile_path_opt : None,
+            goal_target_opt : None,
+            dedupe_rules : false,
+            contradiction_policy : ContradictionPolicy::Error,
+            report_unused_sources : false,
+            hermetic_env_opt : None,
+            strict_utf8_output : false,
+            compress_history : false,
+            allow_empty : false,
+            cache_directory_opt : None,
+            output_on_failure : false,
+            ledger_max_records : DEFAULT_LEDGER_MAX_RECORDS,
+            require_rules : false,
+            case_insensitive_paths : false,
+            verify_sample_size : 0,
+            quiet : false,
+            trace_packets : false,
+            show_waiting : false,
+            variant_opt : None,
+            trust_clock : false,
+            changed_paths_opt : None,
+            changed_verify_sample : 0,
+            force : Force::Off,
+            command_provenance : false,
+            jobs_opt : None,
+            group_jobs : vec![],
+            progress_path_opt : None,
+            no_history : false,
+        }
+    }
+
+    /*  Params for building everything from build.rules in .ruler, the layout the tests use. */
+    #[cfg(test)]
+    pub fn for_test() -> BuildParams
+    {
+        BuildParams::new(".ruler".to_string(), vec!["build.rules".to_string()])
+    }
+
+    /*  The same params, but building toward goal_target_opt, or everything if it's None. */
+    pub fn with_goal_target(&self, goal_target_opt : Option<String>) -> BuildParams
+    {
+        let mut params = self.clone();
+        params.goal_target_opt = goal_target_opt;
+        params
+    }
+
+    /*  One "name = value" line per setting, in the order they're declared, for --dump-config.  Unset
+        options read "none". */
+    pub fn to_config_lines(&self) -> Vec<String>
+    {
+        fn opt_to_string(value_opt : &Option<String>) -> String
+        {
+            match value_opt
+            {
+                Some(value) => value.clone(),
+                None => "none".to_string(),
+            }
+        }
+
+        vec![
+            format!("directory = {}", self.directory_path),
+            format!("rules = {}", self.rulefile_paths.join(", ")),
+            format!("urls = {}", opt_to_string(&self.urlfile_path_opt)),
+            format!("target = {}", opt_to_string(&self.goal_target_opt)),
+            format!("dedupe_identical_rules = {}", self.dedupe_rules),
+            format!("fail_on_contradiction = {}",
+                match self.contradiction_policy
+                {
+                    ContradictionPolicy::Error => "error",
+                    ContradictionPolicy::Warn => "warn",
+                    ContradictionPolicy::Overwrite => "overwrite",
+                }),
+            format!("report_unused_sources = {}", self.report_unused_sources),
+            format!("hermetic_env = {}",
+                match &self.hermetic_env_opt
+                {
+                    Some(env_allow) => format!("allowing [{}]", env_allow.join(", ")),
+                    None => "false".to_string(),
+                }),
+            format!("strict_utf8_output = {}", self.strict_utf8_output),
+            format!("compress_history = {}", self.compress_history),
+            format!("allow_empty = {}", self.allow_empty),
+            format!("cache_dir = {}", opt_to_string(&self.cache_directory_opt)),
+            format!("output_on_failure = {}", self.output_on_failure),
+            format!("ledger_max_records = {}", self.ledger_max_records),
+            format!("require_rules = {}", self.require_rules),
+            format!("case_insensitive_paths = {}", self.case_insensitive_paths),
+            format!("verify_sample = {}", self.verify_sample_size),
+            format!("quiet = {}", self.quiet),
+            format!("trace_packets = {}", self.trace_packets),
+            format!("show_waiting = {}", self.show_waiting),
+            format!("variant = {}", opt_to_string(&self.variant_opt)),
+            format!("trust_clock = {}", self.trust_clock),
+            format!("changed = {}",
+                match &self.changed_paths_opt
+                {
+                    Some(changed_paths) => changed_paths.join(", "),
+                    None => "none".to_string(),
+                }),
+            format!("changed_verify_sample = {}", self.changed_verify_sample),
+            format!("force = {}",
+                match self.force
+                {
+                    Force::Off => "off",
+                    Force::Goal => "goal",
+                    Force::Deep => "deep",
+                    Force::All => "all",
+                }),
+            format!("command_provenance = {}", self.command_provenance),
+            format!("jobs = {}",
+                match self.jobs_opt
+                {
+                    Some(jobs) => jobs.to_string(),
+                    None => "none".to_string(),
+                }),
+            format!("group_jobs = {}",
+                if self.group_jobs.len() == 0
+                {
+                    "none".to_string()
+                }
+                else
+                {
+                    self.group_jobs.iter().map(|(name, jobs)| format!("{}={}", name, jobs)).collect::<Vec<String>>().join(", ")
+                }),
+            format!("progress_file = {}", opt_to_string(&self.progress_path_opt)),
+            format!("no_history = {}", self.no_history),
+        ]
+    }
+}
+
+/*  Hashes sample_size of the sources, picked at random, whatever their modified times, and returns
+    the paths of those that don't match what's recorded for them, missing ones included. */
+fn find_changed_sources<SystemType : System>
+(
+    system : &SystemType,
+    recorded : &Vec<(String, FileState)>,
+    sample_size : usize
+)
+-> Vec<String>
+{
+    let mut changed = vec![];
+    for (path, file_state) in recorded.choose_multiple(&mut rand::thread_rng(), sample_size)
+    {
+        let untrusted_state = FileState
+        {
+            ticket : file_state.ticket.clone(),
+            timestamp : UNTRUSTED_TIMESTAMP,
+            executable : file_state.executable,
+        };
+
+        match get_file_ticket(system, path, &untrusted_state)
+        {
+            Ok(Some(ticket)) if ticket == file_state.ticket => {},
+            _ => changed.push(path.clone()),
+        }
+    }
+    changed.sort();
+    changed
+}
+
+/*  Prints what a command wrote: stdout (if print_out), stderr, and the exit code if it failed. */
+fn print_command_output<PrinterType : Printer>
+(
+    printer : &mut PrinterType,
+    output : &CommandLineOutput,
+    print_out : bool
+)
+{
+    if print_out && output.out != ""
+    {
+        printer.print(&output.out);
+        if !output.out_is_utf8()
+        {
+            printer.print(NOT_UTF8_NOTE);
+        }
+    }
+
+    if output.err != ""
+    {
+        printer.error(&output.err);
+        if !output.err_is_utf8()
+        {
+            printer.error(NOT_UTF8_NOTE);
+        }
+    }
+
+    if !output.success
+    {
+        printer.error(
+            &format!("RESULT: {}",
+                match output.code
+                {
+                    Some(code) => format!("{}", code),
+                    None => "None".to_string(),
+                }
+            )
+        );
+    }
+}
+
+/*  The environment a node's command runs with: everything, or when hermetic, PATH plus the global
+    allowlist plus the rule's own. */
+fn get_command_environment(hermetic_env_opt : &Option<Vec<String>>, rule_env_allow : &Vec<String>) -> CommandEnvironment
+{
+    match hermetic_env_opt
+    {
+        None => CommandEnvironment::Inherit,
+        Some(global_env_allow) =>
+        {
+            let mut names = vec!["PATH".to_string()];
+            for name in global_env_allow.iter().chain(rule_env_allow.iter())
+            {
+                if ! names.contains(name)
+                {
+                    names.push(name.clone());
+                }
+            }
+            CommandEnvironment::Only(names)
+        },
+    }
+}
+
+/*  Tells the observer about each rule that's said it started since last time. */
+fn notify_started<ObserverType : BuildObserver + ?Sized>
+(
+    observer : &mut ObserverType,
+    started_receiver : &Receiver<Vec<String>>
+)
+{
+    for targets in started_receiver.try_iter()
+    {
+        observer.node_started(&targets);
+    }
+}
+
+/*  Passes what a rule's command printed on to the observer a line at a time, stdout then stderr. */
+fn notify_command_output<ObserverType : BuildObserver + ?Sized>
+(
+    observer : &mut ObserverType,
+    targets : &[String],
+    output : &CommandLineOutput
+)
+{
+    for line in output.out.lines().chain(output.err.lines())
+    {
+        observer.command_output(targets, line);
+    }
+}
+
+/*  This is the function that runs when you type "ruler build" at the commandline.
+    It opens the rulefile, parses it, and then either updates all targets in all rules
+    or, if goal_target_opt is Some, only the targets that are ancestors of goal_target_opt
+    in the dependence graph.  If there's an observer, it gets told of each rule as it starts and
+    finishes, each line its command prints and each failure, as well as when the build starts and
+    finishes. */
+pub fn build
+<
+    SystemType : System + 'static,
+    PrinterType : Printer,
+>
+(
+    system : SystemType,
+    printer : &mut PrinterType,
+    observer_opt : Option<&mut dyn BuildObserver>,
+    params : BuildParams
+)
+-> Result<(), BuildError>
+{
+    match observer_opt
+    {
+        Some(observer) =>
+            build_with_downloader(system, &HttpDownloader::new(), printer, observer, params, vec![]),
+        None =>
+            build_with_downloader(system, &HttpDownloader::new(), printer, &mut EmptyObserver::new(), params, vec![]),
+    }
+}
+
+/*  Like build, but rules files given as urls are fetched through the given downloader, targets
+    can be brought back by the given resolvers, and the observer hears about it all. */
+pub fn build_with_downloader
+<
+    SystemType : System + 'static,
+    DownloaderType : Downloader,
+    PrinterType : Printer,
+    ObserverType : BuildObserver + ?Sized,
+>
+(
+    system : SystemType,
+    downloader : &DownloaderType,
+    printer : &mut PrinterType,
+    observer : &mut ObserverType,
+    params : BuildParams,
+    resolvers : Vec<Arc<dyn Resolver<SystemType>>>
+)
+-> Result<(), BuildError>
+{
+    build_with_memo(system, downloader, printer, observer, params, resolvers, &mut BuildMemo::new())
+}
+
+/*  What a build loads before it gets going, kept from one build to the next by a process that does
+    several in a row, like "ruler daemon", so each build after the first skips reading the ruler
+    directory, and skips parsing and sorting the rules when they haven't changed.  The ruler directory
+    still gets written at the end of every build, as usual.  Whatever a build changes on disk, it
+    changes here to match, so the two only disagree if something else changes the ruler directory in
+    the meantime. */
+pub struct BuildMemo<SystemType : System>
+{
+    /*  The ruler directory as the last build left it, with the directory, cache directory and variant
+        it was read for, in case a build asks for others. */
+    elements_opt : Option<(String, Elements<SystemType>)>,
+
+    /*  The rules as the last build parsed and sorted them, under the key get_node_pack_key gave. */
+    node_pack_opt : Option<(Ticket, NodePack)>,
+
+    /*  How many times a build had to load the rules, by parsing and sorting them or reading them back
+        from the ruler directory, rather than finding them here. */
+    pub load_count : usize,
+}
+
+impl<SystemType : System> BuildMemo<SystemType>
+{
+    pub fn new() -> BuildMemo<SystemType>
+    {
+        BuildMemo
+        {
+            elements_opt : None,
+            node_pack_opt : None,
+            load_count : 0,
+        }
+    }
+
+    /*  Whether the last build left the rules parsed and sorted here. */
+    pub fn has_rules(&self) -> bool
+    {
+        self.node_pack_opt.is_some()
+    }
+}
+
+fn get_elements_key(params : &BuildParams) -> String
+{
+    format!("{}\n{:?}\n{:?}", params.directory_path, params.cache_directory_opt, params.variant_opt)
+}
+
+/*  Like build_with_downloader, but starting from whatever the memo has from the last build, and
+    leaving what this one loads in the memo for the next. */
+pub fn build_with_memo
+<
+    SystemType : System + 'static,
+    DownloaderType : Downloader,
+    PrinterType : Printer,
+    ObserverType : BuildObserver + ?Sized,
+>
+(
+    mut system : SystemType,
+    downloader : &DownloaderType,
+    printer : &mut PrinterType,
+    observer : &mut ObserverType,
+    params : BuildParams,
+    resolvers : Vec<Arc<dyn Resolver<SystemType>>>,
+    memo : &mut BuildMemo<SystemType>
+)
+-> Result<(), BuildError>
+{
+    let start_time = system.now();
+    let elements_key = get_elements_key(&params);
+    let mut elements =
+    match memo.elements_opt.take()
+    {
+        Some((key, elements)) if key == elements_key => elements,
+        _ =>
+        {
+            printer.heartbeat("reading current file states");
+            match directory::init(&mut system, &params.directory_path, params.cache_directory_opt.as_deref(), params.variant_opt.as_deref(), params.compress_history)
+            {
+                Ok(elements) => elements,
+                Err(error) =>
+                {
+                    printer.clear_heartbeat();
+                    return match error
+                    {
+                        InitDirectoryError::FailedToReadCurrentFileStates(current_file_states_error) =>
+                            Err(BuildError::FailedToReadCurrentFileStates(current_file_states_error)),
+                        _ => Err(BuildError::DirectoryInitFailed(error)),
+                    }
+                }
+            }
+        },
+    };
+    printer.clear_heartbeat();
+
+    /*  A clock set back can give an edited file the very modified time recorded before the edit, and
+        then the timestamp shortcut would miss the edit.  So when the clock reads earlier than at the last
+        build, this build hashes everything. */
+    let build_time = get_timestamp(start_time).unwrap_or(0);
+    match elements.current_file_states.get_last_build_time()
+    {
+        Some(last_build_time) if build_time < last_build_time && ! params.trust_clock =>
+        {
+            printer.print_single_banner_line("   Warning", Color::Red,
+                &format!("The clock reads {:.0} seconds earlier than when the last build started.  \
+                    Hashing every file this build instead of trusting modified times (--trust-clock skips this).",
+                    (last_build_time - build_time) as f64 / 1_000_000.0));
+            elements.current_file_states.distrust_timestamps();
+        },
+        _ => {},
+    }
+    elements.current_file_states.set_last_build_time(build_time);
+
+    let download_urls =
+    match &params.urlfile_path_opt
+    {
+        None => DownloadUrls::new(),
+        Some(path_string) =>
+        {
+            match read_download_urls(&system, printer, path_string)
+            {
+                Ok(download_urls) => download_urls,
+                Err(error) => return Err(BuildError::DownloadUrlsError(error)),
+            }
+        }
+    };
+
+    let building_everything = params.goal_target_opt.is_none();
+    let rulefile_paths = params.rulefile_paths.clone();
+    let mut sort_count = 0;
+    let mut normalized_paths = vec![];
+    let memo_key_opt = memo.node_pack_opt.as_ref().map(|(key, _node_pack)| key.clone());
+    let node_pack_result = get_nodes_cached(&mut system, downloader, &params, &mut sort_count, &mut normalized_paths,
+        &mut memo.node_pack_opt);
+    print_normalized_rules_warnings(printer, &normalized_paths);
+    let node_pack =
+    match node_pack_result
+    {
+        Ok(node_pack) => node_pack,
+        Err(BuildError::NoRules(_)) if params.require_rules => return Err(BuildError::RulesRequired(rulefile_paths)),
+        Err(error) => return Err(error),
+    };
+
+    /*  The memo only keeps a NodePack it didn't have when it had to load one. */
+    if memo_key_opt.is_none() || memo.node_pack_opt.as_ref().map(|(key, _node_pack)| key.clone()) != memo_key_opt
+    {
+        memo.load_count += 1;
+    }
+
+    if params.require_rules && node_pack.nodes.is_empty()
+    {
+        return Err(BuildError::RulesRequired(rulefile_paths));
+    }
+
+    if building_everything && node_pack.nodes.is_empty() && ! params.allow_empty
+    {
+        printer.print_single_banner_line("   Warning", Color::Yellow,
+            "rules file(s) parsed but contain no rules (pass --allow-empty if that's intended)");
+        return Err(BuildError::NoRules(None));
+    }
+
+    /*  Say up front what commands can see, so a build that differs from someone else's can be
+        traced to the environment. */
+    match get_command_environment(&params.hermetic_env_opt, &vec![])
+    {
+        CommandEnvironment::Only(names) =>
+            printer.print_single_banner_line("  Hermetic", Color::Cyan,
+                &format!("commands see only {}, plus what rules allow with @env-allow", names.join(" "))),
+        CommandEnvironment::Inherit => {},
+    }
+
+    let report_unused_sources = params.report_unused_sources;
+    let output_on_failure = params.output_on_failure;
+    let mut traced_sources_vec : Vec<Option<Vec<String>>> = (0..node_pack.nodes.len()).map(
+        |node_index|
+        {
+            if report_unused_sources
+            {
+                Some(node_pack.get_source_paths(node_index))
+            }
+            else
+            {
+                None
+            }
+        }
+    ).collect();
+
+    let mut fingerprint_factory = TicketFactory::new();
+    for node in node_pack.nodes.iter()
+    {
+        fingerprint_factory.input_ticket(node.rule_ticket.clone());
+    }
+    let build_fingerprint = fingerprint_factory.result();
+
+    let rule_paths_opt = if building_everything { Some(orphans::get_rule_paths(&node_pack)) } else { None };
+    let mut dependencies_vec = get_task_dependencies(&node_pack);
+    let mut executor = Executor::for_task_count(node_pack.leaves.len() + node_pack.nodes.len());
+    let mut channel_pack = ChannelPack::new(node_pack);
+    let mut handles = Vec::new();
+    let (timing_sender, timing_receiver) : (Sender<(Ticket, NodeTiming)>, Receiver<(Ticket, NodeTiming)>) = mpsc::channel();
+    let command_gate = CommandGate::with_limits(params.jobs_opt, &params.group_jobs);
+
+    /*  Rules print through this, so everything reaches the terminal from this thread alone. */
+    let (printer_handle, printer_receiver) = PrinterHandle::new();
+    let progress = ProgressStream::start(&mut system, &params.progress_path_opt, printer_handle.clone());
+    progress.get_sender().send(ProgressEvent::BuildStarted{total_nodes : channel_pack.nodes.len()});
+    observer.build_started(channel_pack.nodes.len());
+
+    /*  Rules say here when they start, for the observer to hear about from this thread. */
+    let (started_sender, started_receiver) : (Sender<Vec<String>>, Receiver<Vec<String>>) = mpsc::channel();
+    let mut private_rule_tickets = Vec::new();
+    let leaf_count = channel_pack.leaves.len();
+    let (hashed_sender, hashed_receiver) : (Sender<()>, Receiver<()>) = mpsc::channel();
+    let (trace_sender_opt, trace_receiver_opt) =
+    if params.trace_packets || params.show_waiting
+    {
+        let (trace_sender, trace_receiver) : (Sender<String>, Receiver<String>) = mpsc::channel();
+        (Some(trace_sender), Some(trace_receiver))
+    }
+    else
+    {
+        (None, None)
+    };
+    let tracer = PacketTracer{sender_opt : if params.trace_packets { trace_sender_opt.clone() } else { None }, start : timing::now()};
+    let waiting_reporter = WaitingReporter{sender_opt : if params.show_waiting { trace_sender_opt.clone() } else { None }};
+    drop(trace_sender_opt);
+
+    /*  Given a list of changed sources, the ones not on it get taken to be as recorded, without
+        looking at them, and the ones on it get hashed.  Sources with nothing recorded get handled as
+        usual either way. */
+    let mut trusted_leaves = HashSet::new();
+    match &params.changed_paths_opt
+    {
+        Some(changed_paths) =>
+        {
+            let changed_set : HashSet<&String> = changed_paths.iter().collect();
+            let mut trusted = vec![];
+            for (leaf, _sender_vec) in channel_pack.leaves.iter()
+            {
+                if changed_set.contains(leaf)
+                {
+                    elements.current_file_states.distrust_timestamp(leaf);
+                    continue;
+                }
+
+                match elements.current_file_states.get_file_state(leaf)
+                {
+                    Some(file_state) if *file_state != FileState::empty() =>
+                        trusted.push((leaf.clone(), file_state.clone())),
+                    _ => {},
+                }
+            }
+
+            let lies = find_changed_sources(&system, &trusted, params.changed_verify_sample);
+            if lies.len() > 0
+            {
+                return Err(BuildError::ChangedListIncomplete(lies));
+            }
+
+            trusted_leaves = trusted.into_iter().map(|(path, _file_state)| path).collect();
+        },
+        None => {},
+    }
+
+    for (leaf, sender_vec) in channel_pack.leaves.drain(..)
+    {
+        /*  Only a build told what changed goes by what's recorded for a source.  Otherwise every
+            source gets hashed, since an edit within one tick of the clock leaves the modified time as
+            it was. */
+        let mut blob = elements.current_file_states.take_blob(vec![leaf.clone()]);
+        if params.changed_paths_opt.is_none()
+        {
+            blob.set_file_states(vec![FileState::empty()]);
+        }
+        let trusted = trusted_leaves.contains(&leaf);
+        let system_clone = system.clone();
+        let hashed_sender_clone = hashed_sender.clone();
+        let tracer_clone = tracer.clone();
+        handles.push(
+            (
+                None,
+                None,
+                vec![leaf.clone()],
+                executor.spawn(
+                    &[],
+                    move || -> Result<WorkResult, BuildError>
+                    {
+                        let result =
+                        if trusted
+                        {
+                            Ok(handle_trusted_source_only_node(blob))
+                        }
+                        else
+                        {
+                            handle_source_only_node(system_clone, blob)
+                        };
+                        match hashed_sender_clone.send(())
+                        {
+                            Ok(_) => {},
+                            Err(_error) => {},
+                        }
+
+                        match result
+                        {
+                            Ok(result) =>
+                            {
+                                for (target, sender) in sender_vec
+                                {
+                                    match tracer_clone.send(&leaf, &target, &sender,
+                                        Packet::from_file_state(result.target_file_states[0].clone()))
+                                    {
+                                        Ok(_) => {},
+                                        Err(error) => return Err(error),
+                                    }
+                                }
+                                Ok(result)
+                            },
+                            Err(error) =>
+                            {
+                                for (target, sender) in sender_vec
+                                {
+                                    match tracer_clone.send(&leaf, &target, &sender, Packet::cancel())
+                                    {
+                                        Ok(_) => {},
+                                        Err(error) => return Err(error),
+                                    }
+                                }
+                                Err(BuildError::WorkError(error))
+                            },
+                        }
+                    }
+                )
+            )
+        )
+    }
+
+    for (((mut node, sender_vec, receiver_vec), traced_sources_opt), dependencies) in
+        channel_pack.nodes.drain(..).zip(traced_sources_vec.drain(..)).zip(dependencies_vec.drain(..))
+    {
+        let temp_targets = node.targets;
+        node.targets = vec![];
+        let temp_targets_names = temp_targets.clone();
+        let pinned_tickets : Vec<Option<Ticket>> = temp_targets.iter().map(
+            |target| elements.pins.get(target).cloned()).collect();
+        let mut blob = elements.current_file_states.take_blob(temp_targets);
+        blob.set_precious(node.precious);
+
+        let mut downloader_cache_urls = Vec::new();
+        let mut downloader_history_urls = Vec::new();
+
+        for url in &download_urls.urls
+        {
+            downloader_cache_urls.push(format!("{}/files", url));
+            downloader_history_urls.push(format!("{}/rules", url));
+        }
+
+        let downloader_cache = DownloaderCache::new(downloader_cache_urls);
+        let downloader_history = DownloaderHistory::new(downloader_history_urls);
+        let system_clone = system.clone();
+
+        let rule_history =
+        if params.no_history
+        {
+            RuleHistory::new()
+        }
+        else
+        {
+            match elements.history.read_rule_history(&node.rule_ticket)
+            {
+                Ok(rule_history) => rule_history,
+                Err(history_error) => return Err(BuildError::HistoryError(history_error)),
+            }
+        };
+
+        let cache_clone = elements.cache.clone();
+        let downloader_rule_history = downloader_history.get_rule_history(&node.rule_ticket);
+        let rule_ticket = node.rule_ticket.clone();
+        let producing_rule_ticket = node.rule_ticket.clone();
+        let command_provenance = params.command_provenance;
+        let timing_sender_clone = timing_sender.clone();
+        let command_gate_clone = command_gate.clone();
+        let printer_handle_clone = printer_handle.clone();
+        let tracer_clone = tracer.clone();
+        let waiting_reporter_clone = waiting_reporter.clone();
+        let progress_sender = progress.get_sender();
+        let started_sender_clone = started_sender.clone();
+        let node_targets = temp_targets_names.clone();
+        let contradiction_policy = if node.install { ContradictionPolicy::Overwrite } else { params.contradiction_policy };
+        let strict_utf8_output = params.strict_utf8_output;
+        let force = params.force.forces_rule(&temp_targets_names, &params.goal_target_opt, params.case_insensitive_paths);
+        let environment = get_command_environment(&params.hermetic_env_opt, &node.env_allow);
+        let private = node.private;
+        let mut rule_resolvers : Vec<Arc<dyn Resolver<SystemType>>> =
+            vec![Arc::new(CacheResolver::new(elements.cache.clone()))];
+        rule_resolvers.extend(resolvers.iter().cloned());
+        if private
+        {
+            private_rule_tickets.push(node.rule_ticket.clone());
+        }
+        else
+        {
+            rule_resolvers.push(Arc::new(DownloadResolver::new(downloader_cache)));
+        }
+
+        handles.push(
+            (
+                Some(node.rule_ticket.clone()),
+                Some(node.provenance.clone()),
+                node_targets.clone(),
+                executor.spawn(
+                    &dependencies,
+                    move || -> Result<WorkResult, BuildError>
+                    {
+                        let mut info = HandleNodeInfo::new(system_clone);
+                        info.blob = blob;
+
+                        let wait_start = timing::now();
+                        let (sources_ticket, source_file_states) = match wait_for_sources(
+                            &info.system, receiver_vec, &node_targets[0], &tracer_clone, &waiting_reporter_clone).and_then(
+                            |(sources_ticket, source_file_states)|
+                                add_directory_tickets(&info.system, sources_ticket, &node.directory_sources)
+                                    .map(|sources_ticket| (sources_ticket, source_file_states))
+                                    .map_err(BuildError::WorkError))
+                        {
+                            Ok(sources) => sources,
+                            Err(error) =>
+                            {
+                                for (sub_index, target, sender) in sender_vec
+                                {
+                                    match tracer_clone.send(&node_targets[sub_index], &target, &sender, Packet::cancel())
+                                    {
+                                        Ok(_) => {},
+                                        Err(error) => return Err(error),
+                                    }
+                                }
+                                return Err(error);
+                            }
+                        };
+
+                        let wait_end = timing::now();
+                        progress_sender.send(ProgressEvent::NodeStarted{targets : node_targets.clone()});
+                        match started_sender_clone.send(node_targets.clone())
+                        {
+                            Ok(_) => {},
+                            Err(_error) => {},
+                        }
+                        let work_result = handle_rule_node(info, RuleExt
+                            {
+                                sources_ticket : sources_ticket,
+                                source_file_states : source_file_states,
+                                command : node.command,
+                                output_constraints : node.output_constraints,
+                                provenance : node.provenance,
+                                contradiction_policy : contradiction_policy,
+                                traced_sources_opt : traced_sources_opt,
+                                environment : environment,
+                                strict_utf8_output : strict_utf8_output,
+                                success_criteria : node.success_criteria,
+                                rule_history : rule_history,
+                                cache : cache_clone,
+                                downloader_rule_history_opt : if private { None } else { Some(downloader_rule_history) },
+                                resolvers : rule_resolvers,
+                                pinned_tickets : pinned_tickets,
+                                no_cache : node.no_cache,
+                                force : force,
+                                serial : node.serial,
+                                group_opt : node.group_opt,
+                                command_gate : command_gate_clone,
+                                printer_handle : printer_handle_clone,
+                            });
+
+                        let work_end = timing::now();
+                        match timing_sender_clone.send(
+                            (rule_ticket, NodeTiming
+                            {
+                                wait_start : wait_start,
+                                wait_end : wait_end,
+                                work_end : work_end,
+                            }))
+                        {
+                            Ok(_) => {},
+                            Err(_error) => {},
+                        }
+
+                        progress_sender.send(
+                            match &work_result
+                            {
+                                Ok(result) => ProgressEvent::NodeFinished
+                                {
+                                    targets : node_targets.clone(),
+                                    status : get_status(&result.work_option).to_string(),
+                                    duration : work_end.saturating_sub(wait_end) as f64 / 1_000_000.0,
+                                },
+                                Err(error) => ProgressEvent::NodeFailed
+                                {
+                                    targets : node_targets.clone(),
+                                    error_code : get_error_code(error),
+                                    message : error.to_string(),
+                                },
+                            });
+
+                        match work_result
+                        {
+                            Ok(result) =>
+                            {
+                                for (sub_index, target, sender) in sender_vec
+                                {
+                                    let mut file_state = result.target_file_states[sub_index].clone();
+                                    if command_provenance
+                                    {
+                                        file_state.ticket = get_provenance_ticket(&file_state.ticket, &producing_rule_ticket);
+                                    }
+
+                                    match tracer_clone.send(&node_targets[sub_index], &target, &sender,
+                                        Packet::from_file_state(file_state))
+                                    {
+                                        Ok(_) => {},
+                                        Err(error) => return Err(error),
+                                    }
+                                }
+                                Ok(result)
+                            },
+                            Err(error) =>
+                            {
+                                for (sub_index, target, sender) in sender_vec
+                                {
+                                    match tracer_clone.send(&node_targets[sub_index], &target, &sender, Packet::cancel())
+                                    {
+                                        Ok(_) => {},
+                                        Err(error) => return Err(error),
+                                    }
+                                }
+                                Err(BuildError::WorkError(error))
+                            },
+                        }
+                    }
+                )
+            )
+        )
+    }
+
+    /*  Comparing every source with the filesystem can take a while on a cold start, with nothing
+        else to show for it, so keep a heartbeat going until the last source is done. */
+    drop(hashed_sender);
+    let mut hashed_count = 0;
+    loop
+    {
+        print_packet_traces(printer, &trace_receiver_opt);
+        print_messages(printer, &printer_receiver);
+        printer.heartbeat(&format!("checking source files: {} of {}", hashed_count, leaf_count));
+        match hashed_receiver.recv_timeout(HEARTBEAT_INTERVAL)
+        {
+            Ok(()) => hashed_count += 1,
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    printer.clear_heartbeat();
+
+    let mut work_errors = Vec::new();
+    let mut ledger_records = Vec::new();
+    let mut summary = BuildSummary::new();
+    let quiet = params.quiet;
+
+    drop(started_sender);
+    for (node_ticket, provenance_opt, node_targets, handle) in handles
+    {
+        print_packet_traces(printer, &trace_receiver_opt);
+        print_messages(printer, &printer_receiver);
+        notify_started(observer, &started_receiver);
+        let join_result = handle.join();
+        notify_started(observer, &started_receiver);
+        match join_result
+        {
             Ok(work_result_result) =>
             {
-                match work_result_result
-                {
-                    Ok(work_result) =>
-                    {
-                        match work_result.work_option
-                        {
-                            WorkOption::SourceOnly =>
-                            {
-                            },
+                match work_result_result
+                {
+                    Ok(work_result) =>
+                    {
+                        match &work_result.work_option
+                        {
+                            WorkOption::SourceOnly => {},
+                            WorkOption::CommandExecuted(output) =>
+                            {
+                                notify_command_output(observer, &node_targets, output);
+                                observer.node_finished(&node_targets, &work_result.work_option);
+                            },
+                            work_option => observer.node_finished(&node_targets, work_option),
+                        }
+
+                        match work_result.work_option
+                        {
+                            WorkOption::SourceOnly =>
+                            {
+                            },
+
+                            WorkOption::Resolutions(resolutions) =>
+                            {
+                                for (i, path) in work_result.blob.get_paths().iter().enumerate()
+                                {
+                                    match &resolutions[i]
+                                    {
+                                        FileResolution::Recovered(_tier) => summary.recovered += 1,
+                                        FileResolution::Downloaded => summary.downloaded += 1,
+                                        FileResolution::AlreadyCorrect => summary.up_to_date += 1,
+                                        FileResolution::NeedsRebuild => {},
+                                    }
+
+                                    if quiet
+                                    {
+                                        continue;
+                                    }
+
+                                    let (banner_text, banner_color) =
+                                        match &resolutions[i]
+                                        {
+                                            FileResolution::Recovered(_tier) =>
+                                                (" Recovered", Color::Green),
+
+                                            FileResolution::Downloaded =>
+                                                ("Downloaded", Color::Yellow),
+
+                                            FileResolution::AlreadyCorrect =>
+                                                ("Up-to-date", Color::Cyan),
+
+                                            FileResolution::NeedsRebuild =>
+                                                ("  Outdated", Color::Red),
+                                        };
+
+                                    /*  Name the cache a file came from only when it's not the usual local one. */
+                                    let path_text =
+                                        match &resolutions[i]
+                                        {
+                                            FileResolution::Recovered(tier) if tier != LOCAL_CACHE_TIER =>
+                                                format!("{} (from {} cache)", path, tier),
+                                            _ => path.to_string(),
+                                        };
+
+                                    printer.print_single_banner_line(banner_text, banner_color, &path_text);
+                                }
+                            },
+
+                            WorkOption::Aggregated =>
+                            {
+                                for path in work_result.blob.get_paths().iter()
+                                {
+                                    summary.up_to_date += 1;
+                                    if ! quiet
+                                    {
+                                        printer.print_single_banner_line("Up-to-date", Color::Cyan, &path);
+                                    }
+                                }
+                            },
+
+                            WorkOption::Pinned =>
+                            {
+                                for path in work_result.blob.get_paths().iter()
+                                {
+                                    summary.up_to_date += 1;
+                                    if ! quiet
+                                    {
+                                        printer.print_single_banner_line("    Pinned", Color::Cyan, &path);
+                                    }
+                                }
+                            },
+
+                            WorkOption::CommandExecuted(output) =>
+                            {
+                                for path in work_result.blob.get_paths().iter()
+                                {
+                                    summary.built += 1;
+                                    if ! quiet
+                                    {
+                                        printer.print_single_banner_line("     Built", Color::Magenta, &path);
+                                    }
+                                }
+
+                                print_command_output(printer, &output, !output_on_failure);
+
+                                match (&node_ticket, &work_result.sources_ticket_opt)
+                                {
+                                    (Some(rule_ticket), Some(sources_ticket)) =>
+                                    {
+                                        /*  The times get filled in from the timings, once they're all in. */
+                                        ledger_records.push(LedgerRecord
+                                        {
+                                            timestamp : 0,
+                                            duration_micros : 0,
+                                            rule_ticket : rule_ticket.clone(),
+                                            sources_ticket : sources_ticket.clone(),
+                                            target_tickets : work_result.target_file_states.iter().map(
+                                                |file_state| file_state.ticket.clone()).collect(),
+                                            first_target : match work_result.blob.get_paths().first()
+                                            {
+                                                Some(path) => path.clone(),
+                                                None => String::new(),
+                                            },
+                                        });
+                                    },
+                                    _ => {},
+                                }
+                            },
+                        }
+
+                        if work_result.contradicted_paths.len() > 0
+                        {
+                            let mut message = "WARNING: The following targets contradict an existing target history:\n".to_string();
+                            for path in work_result.contradicted_paths.iter()
+                            {
+                                message.push_str(path);
+                                message.push_str("\n");
+                            }
+                            match &provenance_opt
+                            {
+                                Some(provenance) =>
+                                    message.push_str(&format!("This might mean a real dependence is not reflected in the rule at {}.\n", provenance)),
+                                None => {},
+                            }
+                            message.push_str("The new result replaced the old one in history because contradictions are downgraded to warnings.\n");
+                            printer.error(&message);
+                        }
+
+                        for path in work_result.unused_sources.iter()
+                        {
+                            printer.print_single_banner_line("    Unused", Color::Yellow,
+                                &match &provenance_opt
+                                {
+                                    Some(provenance) => format!("{} (declared by rule at {}, never read by its command)", path, provenance),
+                                    None => format!("{} (never read by its command)", path),
+                                });
+                        }
+
+                        let produced_targets = node_ticket.is_some();
+                        match node_ticket
+                        {
+                            Some(ticket) =>
+                            {
+                                match work_result.rule_history
+                                {
+                                    Some(_history) if params.no_history => {},
+                                    Some(history) =>
+                                    {
+                                        match elements.history.write_rule_history(ticket, history)
+                                        {
+                                            Ok(()) => {},
+                                            Err(error) => panic!("Fatal Error: {}", error),
+                                        }
+                                    },
+                                    None => {},
+                                }
+                            }
+                            None => {},
+                        }
+
+                        if produced_targets
+                        {
+                            elements.current_file_states.insert_target_blob(work_result.blob);
+                        }
+                        else
+                        {
+                            elements.current_file_states.insert_blob(work_result.blob);
+                        }
+                    },
+                    Err(BuildError::WorkError(work_error)) =>
+                    {
+                        /*  Whatever the mode, a failing command's output is the first thing anyone
+                            will want to see. */
+                        match &work_error
+                        {
+                            WorkError::CommandExecutedButErrored(output) =>
+                            {
+                                print_command_output(printer, output, true);
+                                notify_command_output(observer, &node_targets, output);
+                            },
+                            _ => {},
+                        }
+                        observer.node_failed(&node_targets, &work_error);
+                        work_errors.push((provenance_opt, work_error));
+                    },
+                    Err(BuildError::Canceled) => {},
+                    Err(error) => panic!("Unexpected build error: {}", error),
+                }
+            },
+            Err(_error) => return Err(BuildError::Weird),
+        }
+    }
+
+    #[cfg(test)]
+    LAST_PEAK_WORKERS.with(|peak| peak.set(executor.get_peak_workers_opt()));
+    drop(executor);
+
+    print_packet_traces(printer, &trace_receiver_opt);
+    print_messages(printer, &printer_receiver);
+
+    /*  Only a build of everything has all the rules to hand, so only it can tell a file no rule
+        makes anymore. */
+    match &rule_paths_opt
+    {
+        Some(rule_paths) => orphans::warn_about_orphans(&system, printer,
+            &orphans::find_orphans(&elements.current_file_states, rule_paths)),
+        None => {},
+    }
+
+    match elements.current_file_states.to_file()
+    {
+        Ok(_) => {},
+        Err(_) => printer.error("Error writing history"),
+    }
+
+    drop(timing_sender);
+    let mut build_timings = BuildTimings::new();
+    for (rule_ticket, timing) in timing_receiver.try_iter()
+    {
+        build_timings.insert(rule_ticket, timing);
+    }
+
+    match timing::write_timings(&mut system, &timing::get_timings_path(&params.directory_path), &build_timings)
+    {
+        Ok(_) => {},
+        Err(error) => printer.error(&format!("{}", error)),
+    }
+
+    for record in ledger_records.iter_mut()
+    {
+        match build_timings.get(&record.rule_ticket)
+        {
+            Some(node_timing) =>
+            {
+                record.timestamp = node_timing.work_end;
+                record.duration_micros = node_timing.work_micros();
+            },
+            None => record.timestamp = timing::now(),
+        }
+    }
+
+    match ledger::append_ledger(&mut system, &ledger::get_ledger_path(&params.directory_path),
+        ledger_records, params.ledger_max_records)
+    {
+        Ok(_) => {},
+        Err(error) => printer.error(&format!("{}", error)),
+    }
+
+    match private::record_private_rules(&mut system, &params.directory_path, private_rule_tickets)
+    {
+        Ok(_) => {},
+        Err(error) => printer.error(&format!("{}", error)),
+    }
+
+    if params.verify_sample_size > 0
+    {
+        cache::verify_cache_sample(&mut system, printer, &mut elements.cache, &params.directory_path,
+            params.verify_sample_size, &build_fingerprint, timing::now());
+    }
+
+    memo.elements_opt = Some((elements_key, elements));
+
+    progress.finish(
+        ProgressEvent::BuildFinished
+        {
+            success : work_errors.len() == 0,
+            failed : work_errors.len(),
+            summary : summary.clone(),
+        });
+    observer.build_finished(work_errors.len() == 0, &summary);
+    print_messages(printer, &printer_receiver);
+
+    if work_errors.len() == 0
+    {
+        let summary_line = summary.to_line(seconds_since(&system, start_time));
+        let outcome = if summary.is_nothing_to_do() { "nothing-to-do" } else { "built" };
+        match write_str_to_file(&mut system, &get_last_build_path(&params.directory_path),
+            &format!("{}\n{}\n", outcome, summary_line))
+        {
+            Ok(()) => {},
+            Err(error) => printer.error(&format!("Error writing last build summary: {}", error)),
+        }
+
+        printer.print(&summary_line);
+        Ok(())
+    }
+    else
+    {
+        Err(BuildError::WorkErrors(work_errors))
+    }
+}
+
+/*  Called when you type "ruler run".  Appeals to build() function to do the build, with executable
+    as the goal target.  If there are no errors, executes the target file specified, passing it
+    extra_args. */
+pub fn run
+<
+    SystemType : System + 'static,
+    PrinterType : Printer,
+>
+(
+    mut system : SystemType,
+    printer : &mut PrinterType,
+    params : BuildParams,
+    executable : String,
+    mut extra_args : Vec<String>
+)
+-> Result<(), RunError>
+{
+    match build(system.clone(), printer, None, params.with_goal_target(Some(executable.clone())))
+    {
+        Err(error) => return Err(RunError::BuildError(error)),
+        Ok(()) => {},
+    }
+
+    let mut all = vec![format!("./{}", executable)];
+    all.append(&mut extra_args);
+
+    for result in system.execute_command(to_command_script(all))
+    {
+        match result
+        {
+            Ok(_command_line_output) => {},
+            Err(system_error) => return Err(RunError::ExecutionError(system_error)),
+        }
+    }
+
+    Ok(())
+}
+
+/*  This is the function that runs when you type "ruler clean" at the command-line.
+    It takes a rulefile, parses it and either removes all targets to the cache,
+    or, if goal_target_opt is Some, removes only those targets that are acnestors
+    of goal_target_opt in the depdnece-graph. */
+pub fn clean
+<
+    SystemType : System + 'static,
+    PrinterType : Printer,
+>
+(
+    mut system : SystemType,
+    printer : &mut PrinterType,
+    params : BuildParams
+)
+-> Result<(), BuildError>
+{
+    let start_time = system.now();
+    let variant_opt = params.variant_opt.as_deref();
+    let mut elements =
+    match directory::init(&mut system, &params.directory_path, params.cache_directory_opt.as_deref(), variant_opt, false)
+    {
+        Ok(elements) => elements,
+        Err(error) =>
+        {
+            return match error
+            {
+                InitDirectoryError::FailedToReadCurrentFileStates(current_file_states_error) =>
+                    Err(BuildError::FailedToReadCurrentFileStates(current_file_states_error)),
+                _ => Err(BuildError::DirectoryInitFailed(error)),
+            }
+        }
+    };
+
+    let mut node_pack = get_nodes(&mut system, params.rulefile_paths.clone(), params.goal_target_opt.clone(),
+        params.dedupe_rules, params.case_insensitive_paths, variant_opt)?;
+
+    let mut handles = Vec::new();
+    for node in node_pack.nodes.drain(..)
+    {
+        let blob = elements.current_file_states.take_blob(node.targets);
+        let mut system_clone = system.clone();
+        let mut local_cache_clone = elements.cache.clone();
+
+        handles.push(
+            (
+                node.provenance,
+                thread::spawn(
+                    move || -> Result<usize, WorkError>
+                    {
+                        clean_targets(
+                            blob,
+                            &mut system_clone,
+                            &mut local_cache_clone)
+                    }
+                )
+            )
+        );
+    }
+
+    let mut work_errors : Vec<(Option<Provenance>, WorkError)> = Vec::new();
+    let mut cleaned_count = 0;
+
+    for (provenance, handle) in handles
+    {
+        match handle.join()
+        {
+            Err(_error) => return Err(BuildError::Weird),
+            Ok(remove_result_result) =>
+            {
+                match remove_result_result
+                {
+                    Ok(count) => cleaned_count += count,
+                    Err(work_error) => work_errors.push((Some(provenance), work_error)),
+                }
+            }
+        }
+    }
+
+    if work_errors.len() == 0
+    {
+        if cleaned_count == 0
+        {
+            printer.print("Nothing to do: no targets to clean");
+        }
+        else
+        {
+            printer.print(&format!("Clean succeeded: {} targets moved to the cache in {:.2} seconds",
+                cleaned_count, seconds_since(&system, start_time)));
+        }
+        Ok(())
+    }
+    else
+    {
+        Err(BuildError::WorkErrors(work_errors))
+    }
+}
+
+/*  What "ruler prefetch" found for one rule. */
+#[derive(Debug, PartialEq)]
+pub enum PrefetchStatus
+{
+    /*  Target files or target tickets were downloaded, and a build can now recover the targets offline. */
+    Prefetched,
+
+    /*  The local history and cache already have everything a build needs for this rule. */
+    AlreadyLocal,
+
+    /*  No download url remembers targets for this rule with the current sources. */
+    NoRemoteEntry,
+
+    /*  Some source's ticket can't be known before building (it is missing, or it is the target of a rule
+        that could not be prefetched), so the sources a build would see differ from anything we can look up. */
+    SourcesDiffer,
+
+    /*  A download url remembers the targets, but not every target file could be downloaded. */
+    DownloadFailed,
+
+    /*  The rule is marked private, so download urls were not consulted, and the local history and
+        cache don't have everything. */
+    Private,
+}
+
+/*  This is the function that runs when you type "ruler prefetch" at the command-line.  Walks the rules
+    in dependence order computing the sources-ticket each rule would see in a build, looks each up in the
+    local history and then the download urls, and downloads any target files the cache does not already
+    have.  Remembered target tickets go in the local history, so a subsequent build can recover
+    everything without a network connection.  The working tree is never touched. */
+pub fn prefetch
+<
+    SystemType : System,
+    DownloaderType : Downloader,
+    PrinterType : Printer,
+>
+(
+    system : SystemType,
+    downloader : &DownloaderType,
+    printer : &mut PrinterType,
+    params : BuildParams
+)
+-> Result<Vec<(Vec<String>, PrefetchStatus)>, BuildError>
+{
+    let download_urls =
+    match &params.urlfile_path_opt
+    {
+        None => DownloadUrls::new(),
+        Some(path_string) =>
+        {
+            match read_download_urls(&system, printer, path_string)
+            {
+                Ok(download_urls) => download_urls,
+                Err(error) => return Err(BuildError::DownloadUrlsError(error)),
+            }
+        }
+    };
+
+    prefetch_from_urls(system, downloader, printer, params, download_urls.urls)
+}
+
+/*  Like prefetch, but looks things up at the given urls instead of those in the urls file. */
+pub fn prefetch_from_urls
+<
+    SystemType : System,
+    DownloaderType : Downloader,
+    PrinterType : Printer,
+>
+(
+    mut system : SystemType,
+    downloader : &DownloaderType,
+    printer : &mut PrinterType,
+    params : BuildParams,
+    urls : Vec<String>
+)
+-> Result<Vec<(Vec<String>, PrefetchStatus)>, BuildError>
+{
+    let mut elements =
+    match directory::init(&mut system, &params.directory_path, params.cache_directory_opt.as_deref(), params.variant_opt.as_deref(), params.compress_history)
+    {
+        Ok(elements) => elements,
+        Err(error) =>
+        {
+            return match error
+            {
+                InitDirectoryError::FailedToReadCurrentFileStates(current_file_states_error) =>
+                    Err(BuildError::FailedToReadCurrentFileStates(current_file_states_error)),
+                _ => Err(BuildError::DirectoryInitFailed(error)),
+            }
+        }
+    };
+
+    let mut downloader_cache_urls = Vec::new();
+    let mut downloader_history_urls = Vec::new();
+
+    for url in &urls
+    {
+        downloader_cache_urls.push(format!("{}/files", url));
+        downloader_history_urls.push(format!("{}/rules", url));
+    }
+
+    let remote = Remote
+    {
+        downloader : downloader,
+        downloader_cache : DownloaderCache::new(downloader_cache_urls),
+        downloader_history : DownloaderHistory::new(downloader_history_urls),
+    };
+    let (printer_handle, printer_receiver) = PrinterHandle::new();
+
+    let node_pack = get_nodes_with_downloader(&system, downloader, params.rulefile_paths, params.goal_target_opt, params.dedupe_rules, params.case_insensitive_paths, params.variant_opt.as_deref())?;
+
+    let mut leaf_tickets : Vec<Option<Ticket>> = Vec::new();
+    for leaf in node_pack.leaves
+    {
+        let blob = elements.current_file_states.take_blob(vec![leaf]);
+        leaf_tickets.push(
+            match blob.get_current_file_state_vec(&system)
+            {
+                Ok(file_state_vec) => Some(file_state_vec.get_ticket(0)),
+                Err(_error) => None,
+            }
+        );
+    }
+
+    /*  The nodes come sorted, so by the time a node refers to another node's targets, the
+        target tickets (if we could find them) are already in this vector. */
+    let mut node_file_state_vecs : Vec<Option<FileStateVec>> = Vec::new();
+    let mut report = Vec::new();
+
+    for node in node_pack.nodes
+    {
+        let mut factory = TicketFactory::new();
+        let mut sources_known = true;
+        for source_index in node.source_indices.iter()
+        {
+            let ticket_opt = match source_index
+            {
+                SourceIndex::Leaf(i) => leaf_tickets[*i].clone(),
+                SourceIndex::Pair(i, sub_index) =>
+                    node_file_state_vecs[*i].as_ref().map(|file_state_vec| file_state_vec.get_ticket(*sub_index)),
+            };
+
+            match ticket_opt
+            {
+                Some(ticket) => factory.input_ticket(ticket),
+                None => sources_known = false,
+            }
+        }
+
+        let sources_ticket_opt =
+        match add_directory_tickets(&system, factory.result(), &node.directory_sources)
+        {
+            Ok(sources_ticket) if sources_known => Some(sources_ticket),
+            _ => None,
+        };
+
+        let (file_state_vec_opt, status) =
+        if let Some(sources_ticket) = sources_ticket_opt
+        {
+            prefetch_rule(
+                &system,
+                &remote,
+                &mut elements,
+                &node,
+                sources_ticket,
+                &printer_handle)?
+        }
+        else
+        {
+            (None, PrefetchStatus::SourcesDiffer)
+        };
+        print_messages(printer, &printer_receiver);
+
+        let (banner_text, banner_color) =
+            match status
+            {
+                PrefetchStatus::Prefetched => ("Prefetched", Color::Yellow),
+                PrefetchStatus::AlreadyLocal => ("     Local", Color::Cyan),
+                PrefetchStatus::NoRemoteEntry => (" No remote", Color::Red),
+                PrefetchStatus::SourcesDiffer => ("   Differs", Color::Red),
+                PrefetchStatus::DownloadFailed => ("    Failed", Color::Red),
+                PrefetchStatus::Private => ("   Private", Color::White),
+            };
+
+        for path in node.targets.iter()
+        {
+            printer.print_single_banner_line(banner_text, banner_color, path);
+        }
+
+        node_file_state_vecs.push(file_state_vec_opt);
+        report.push((node.targets, status));
+    }
+
+    Ok(report)
+}
+
+/*  Where prefetch looks for what the ruler directory doesn't have: the downloader, and the download
+    urls for target files and for rule histories. */
+struct Remote<'a, DownloaderType : Downloader>
+{
+    downloader : &'a DownloaderType,
+    downloader_cache : DownloaderCache,
+    downloader_history : DownloaderHistory,
+}
+
+/*  Prefetches one rule whose sources-ticket is known.  Returns the target tickets a build would
+    end up with (if prefetch could make sure of them) along with the status to report. */
+fn prefetch_rule
+<
+    SystemType : System,
+    DownloaderType : Downloader,
+>
+(
+    system : &SystemType,
+    remote : &Remote<DownloaderType>,
+    elements : &mut directory::Elements<SystemType>,
+    node : &Node,
+    sources_ticket : Ticket,
+    printer_handle : &PrinterHandle
+)
+-> Result<(Option<FileStateVec>, PrefetchStatus), BuildError>
+{
+    let mut rule_history = match elements.history.read_rule_history(&node.rule_ticket)
+    {
+        Ok(rule_history) => rule_history,
+        Err(history_error) => return Err(BuildError::HistoryError(history_error)),
+    };
+
+    let (file_state_vec, remembered_locally) =
+    match rule_history.get_file_state_vec(&sources_ticket)
+    {
+        Some(file_state_vec) => (file_state_vec.clone(), true),
+        None if node.private => return Ok((None, PrefetchStatus::Private)),
+        None =>
+        {
+            match remote.downloader_history.get_rule_history(&node.rule_ticket)
+                .get_file_state_vec_from(remote.downloader, &sources_ticket, printer_handle)
+            {
+                Some(file_state_vec) => (file_state_vec, false),
+                None => return Ok((None, PrefetchStatus::NoRemoteEntry)),
+            }
+        }
+    };
+
+    let blob = elements.current_file_states.take_blob(node.targets.clone());
+    let mut downloaded = false;
+    for (i, info) in blob.get_file_infos().iter().enumerate()
+    {
+        let ticket = file_state_vec.get_ticket(i);
+
+        match get_file_ticket(system, &info.path, &info.file_state)
+        {
+            Ok(Some(current_ticket)) =>
+            {
+                if current_ticket == ticket
+                {
+                    continue;
+                }
+            },
+            _ => {},
+        }
+
+        if elements.cache.open(&ticket).is_ok()
+        {
+            continue;
+        }
+
+        if node.private
+        {
+            return Ok((None, PrefetchStatus::Private));
+        }
+
+        match remote.downloader_cache.download_to_cache(remote.downloader, &ticket, &mut elements.cache)
+        {
+            DownloadResult::Done => downloaded = true,
+            DownloadResult::NotThere => return Ok((None, PrefetchStatus::DownloadFailed)),
+        }
+    }
+
+    if remembered_locally
+    {
+        return Ok((Some(file_state_vec),
+            if downloaded { PrefetchStatus::Prefetched } else { PrefetchStatus::AlreadyLocal }));
+    }
+
+    match rule_history.insert(sources_ticket, file_state_vec.clone(), get_timestamp(system.now()).unwrap_or(0))
+    {
+        Ok(()) => {},
+        Err(_error) => return Err(BuildError::Weird),
+    }
+
+    match elements.history.write_rule_history(node.rule_ticket.clone(), rule_history)
+    {
+        Ok(()) => {},
+        Err(error) => return Err(BuildError::HistoryError(error)),
+    }
+
+    Ok((Some(file_state_vec), PrefetchStatus::Prefetched))
+}
+
+/*  Like plan_build, but rules files given as urls, and remote rule histories, are fetched through the
+    given downloader. */
+pub fn plan_build_with_downloader
+<
+    SystemType : System,
+    DownloaderType : Downloader,
+>
+(
+    system : &SystemType,
+    downloader : &DownloaderType,
+    params : BuildParams,
+    ticket_overrides : &HashMap<String, Ticket>
+)
+-> Result<BuildPlan, BuildError>
+{
+    let mut elements =
+    match directory::open_readonly(system, &params.directory_path, params.cache_directory_opt.as_deref(), params.variant_opt.as_deref())
+    {
+        Ok(elements) => elements,
+        Err(InitDirectoryError::FailedToReadCurrentFileStates(error)) =>
+            return Err(BuildError::FailedToReadCurrentFileStates(error)),
+        Err(error) => return Err(BuildError::DirectoryInitFailed(error)),
+    };
+
+    match (elements.current_file_states.get_last_build_time(), get_timestamp(system.now()))
+    {
+        (Some(last_build_time), Ok(now)) if now < last_build_time && ! params.trust_clock =>
+            elements.current_file_states.distrust_timestamps(),
+        _ => {},
+    }
+
+    let download_urls =
+    match params.urlfile_path_opt
+    {
+        None => DownloadUrls::new(),
+        Some(path_string) =>
+        {
+            match read_download_urls(system, &mut EmptyPrinter::new(), &path_string)
+            {
+                Ok(download_urls) => download_urls,
+                Err(error) => return Err(BuildError::DownloadUrlsError(error)),
+            }
+        }
+    };
+
+    let downloader_history = DownloaderHistory::new(
+        download_urls.urls.iter().map(|url| format!("{}/rules", url)).collect());
+
+    let command_provenance = params.command_provenance;
+    let node_pack = get_nodes_with_downloader(system, downloader, params.rulefile_paths, params.goal_target_opt,
+        params.dedupe_rules, params.case_insensitive_paths, params.variant_opt.as_deref())?;
+
+    let mut leaf_tickets : Vec<Option<Ticket>> = Vec::new();
+    for leaf in node_pack.leaves.iter()
+    {
+        let blob = elements.current_file_states.take_blob(vec![leaf.clone()]);
+        leaf_tickets.push(
+            match ticket_overrides.get(leaf)
+            {
+                Some(ticket) => Some(ticket.clone()),
+                None => match blob.get_current_file_state_vec(system)
+                {
+                    Ok(file_state_vec) => Some(file_state_vec.get_ticket(0)),
+                    Err(_error) => None,
+                },
+            }
+        );
+    }
+
+    /*  The target tickets each node would end up with, if they can be known without building.  The
+        nodes come sorted, so a node's sources are always in here already. */
+    let mut target_tickets : Vec<Option<Vec<Ticket>>> = Vec::new();
+    let mut plan = BuildPlan{nodes : vec![]};
+
+    for node in node_pack.nodes.iter()
+    {
+        let mut sources = vec![];
+        let mut factory = TicketFactory::new();
+        let mut missing = vec![];
+        let mut rebuilt = vec![];
+
+        let global_indices = node_pack.global_source_indices.iter().map(|i| SourceIndex::Leaf(*i));
+        for source_index in node.source_indices.iter().cloned().chain(global_indices)
+        {
+            let (path, from_node, ticket_opt) =
+            match source_index
+            {
+                SourceIndex::Leaf(i) => (node_pack.leaves[i].clone(), None, leaf_tickets[i].clone()),
+                SourceIndex::Pair(i, sub_index) => (node_pack.nodes[i].targets[sub_index].clone(), Some(i),
+                    target_tickets[i].as_ref().map(
+                        |tickets|
+                        if command_provenance
+                        {
+                            get_provenance_ticket(&tickets[sub_index], &node_pack.nodes[i].rule_ticket)
+                        }
+                        else
+                        {
+                            tickets[sub_index].clone()
+                        })),
+            };
+
+            match (ticket_opt, from_node)
+            {
+                (Some(ticket), _) => factory.input_ticket(ticket),
+                (None, None) => missing.push(path.clone()),
+                (None, Some(_)) => rebuilt.push(path.clone()),
+            }
+            sources.push(PlanEdge{path : path, from_node : from_node});
+        }
+
+        for directory in node.directory_sources.iter()
+        {
+            if ! system.is_dir(directory)
+            {
+                missing.push(directory.clone());
+            }
+        }
+
+        let sources_ticket_opt = if missing.len() == 0 && rebuilt.len() == 0
+        {
+            add_directory_tickets(system, factory.result(), &node.directory_sources).ok()
+        }
+        else
+        {
+            None
+        };
+        let blob = elements.current_file_states.take_blob(node.targets.clone());
+
+        let (action, tickets_opt) =
+        match sources_ticket_opt.as_ref()
+        {
+            None if missing.len() > 0 => (PredictedAction::Rebuild{reason : RebuildReason::SourcesMissing(missing)}, None),
+            None => (PredictedAction::Rebuild{reason : RebuildReason::SourcesRebuilt(rebuilt)}, None),
+            Some(_) if node.targets.iter().any(|target| elements.pins.get(target).is_some()) =>
+            {
+                let tickets : Vec<Option<Ticket>> = blob.get_file_infos().iter().map(
+                    |info| match elements.pins.get(&info.path)
+                    {
+                        Some(ticket) => Some(ticket.clone()),
+                        None => get_file_ticket(system, &info.path, &info.file_state).unwrap_or(None),
+                    }).collect();
+                (PredictedAction::UpToDate, tickets.into_iter().collect())
+            },
+            Some(sources_ticket) if node.command.is_empty() =>
+                (PredictedAction::UpToDate, Some(vec![sources_ticket.clone(); node.targets.len()])),
+            Some(_) if node.no_cache => (PredictedAction::Rebuild{reason : RebuildReason::NoCache}, None),
+            Some(sources_ticket) =>
+            {
+                let rule_history =
+                match elements.history.read_rule_history(&node.rule_ticket)
+                {
+                    Ok(rule_history) => rule_history,
+                    Err(error) => return Err(BuildError::HistoryError(error)),
+                };
+
+                let remembered_opt =
+                match rule_history.get_file_state_vec(sources_ticket)
+                {
+                    Some(file_state_vec) => Some(file_state_vec.clone()),
+                    None if node.private => None,
+                    /*  A plan prints nothing as it goes, so warnings about downloads get dropped. */
+                    None => downloader_history.get_rule_history(&node.rule_ticket)
+                        .get_file_state_vec_from(downloader, sources_ticket, &PrinterHandle::discard()),
+                };
+
+                match remembered_opt
+                {
+                    None if rule_history.get_all_file_state_vecs().len() == 0 =>
+                        (PredictedAction::Rebuild{reason : RebuildReason::NeverBuilt}, None),
+                    None => (PredictedAction::Rebuild{reason : RebuildReason::SourcesChanged}, None),
+                    Some(remembered) if blob.matches_unmodified(system, &remembered) =>
+                        (PredictedAction::UpToDate, Some(remembered.get_tickets())),
+                    Some(remembered) =>
+                    {
+                        let downloads_available = ! node.private && download_urls.urls.len() > 0;
+                        let resolutions =
+                        match blob.predict_remembered_file_state_vec(system, &elements.cache, downloads_available, &remembered)
+                        {
+                            Ok(resolutions) => resolutions,
+                            Err(error) => return Err(BuildError::WorkError(WorkError::ResolutionError(error))),
+                        };
+
+                        let unavailable : Vec<String> = blob.get_paths().into_iter().zip(resolutions.iter()).filter(
+                            |(_path, resolution)| **resolution == FileResolution::NeedsRebuild).map(|(path, _resolution)| path).collect();
+
+                        let action =
+                        if unavailable.len() > 0
+                        {
+                            PredictedAction::Rebuild{reason : RebuildReason::TargetsUnavailable(unavailable)}
+                        }
+                        else if resolutions.iter().all(|resolution| *resolution == FileResolution::AlreadyCorrect)
+                        {
+                            PredictedAction::UpToDate
+                        }
+                        else if resolutions.iter().any(|resolution| *resolution == FileResolution::Downloaded)
+                        {
+                            PredictedAction::Recover{from : RecoverFrom::Download}
+                        }
+                        else
+                        {
+                            PredictedAction::Recover{from : RecoverFrom::Cache}
+                        };
+
+                        match action
+                        {
+                            PredictedAction::Rebuild{..} => (action, None),
+                            _ => (action, Some(remembered.get_tickets())),
+                        }
+                    },
+                }
+            },
+        };
+
+        target_tickets.push(tickets_opt);
+        plan.nodes.push(
+            PlannedNode
+            {
+                targets : node.targets.clone(),
+                rule_ticket : node.rule_ticket.human_readable(),
+                action : action,
+                sources_ticket : sources_ticket_opt.map(|ticket| ticket.human_readable()),
+                sources : sources,
+            });
+    }
+
+    Ok(plan)
+}
+
+/*  This is the function that runs when you type "ruler build --dry-run" at the command-line.  Works
+    out what a build would do with each rule, in the order build would start them, without running
+    commands, and without changing the cache, the history, the current file states or the files in
+    the tree.  Targets that would come back from the cache stay where they are. */
+pub fn plan_build<SystemType : System>
+(
+    system : &SystemType,
+    params : BuildParams
+)
+-> Result<BuildPlan, BuildError>
+{
+    plan_build_with_downloader(system, &HttpDownloader::new(), params, &HashMap::new())
+}
+
+/*  Like plan_build, but with the sources in ticket_overrides taken to have the tickets given instead of
+    the ones they really have, for asking what would rebuild if they changed.  Like plan_build, nothing
+    gets run or recorded, and the sources themselves are left alone. */
+pub fn plan_build_what_if<SystemType : System>
+(
+    system : &SystemType,
+    params : BuildParams,
+    ticket_overrides : &HashMap<String, Ticket>
+)
+-> Result<BuildPlan, BuildError>
+{
+    plan_build_with_downloader(system, &HttpDownloader::new(), params, ticket_overrides)
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::time::
+    {
+        Duration,
+        Instant,
+        SystemTime,
+    };
+    use std::thread;
+    use std::sync::mpsc;
+    use std::sync::
+    {
+        Arc,
+        Mutex,
+    };
+    use std::sync::atomic::
+    {
+        AtomicUsize,
+        Ordering,
+    };
+    use crate::directory;
+    use crate::build::
+    {
+        build,
+        build_with_downloader,
+        prefetch,
+        get_nodes,
+        get_nodes_cached,
+        get_command_lines,
+        get_leaves,
+        clean,
+        compare_to_golden,
+        BuildParams,
+        Force,
+        BuildError,
+        PrefetchStatus,
+        wait_for_sources,
+        PacketTracer,
+        WaitingReporter,
+        NOT_UTF8_NOTE,
+        LAST_PEAK_WORKERS,
+    };
+    use crate::packet::Packet;
+    use crate::downloader::FakeDownloader;
+    use crate::hash::TargetMismatch;
+    use crate::timing::
+    {
+        read_timings,
+        get_timings_path,
+    };
+    use crate::system::
+    {
+        System,
+        CommandLineOutput,
+        ReadWriteError,
+        FileType,
+        fake::FakeSystem
+    };
+    use crate::work::
+    {
+        WorkError,
+        ContradictionPolicy,
+        handle_source_only_node,
+    };
+    use crate::rule::Provenance;
+    use crate::ticket::
+    {
+        Ticket,
+        TicketFactory,
+    };
+    use crate::blob::
+    {
+        FileResolution,
+        Resolver,
+        ResolutionError,
+    };
+    use crate::cache::
+    {
+        SysCache,
+        OpenError,
+    };
+    use crate::system::util::
+    {
+        get_timestamp,
+        write_str_to_file,
+        read_file,
+        read_file_to_string
+    };
+    use crate::printer::
+    {
+        EmptyPrinter,
+        RecordingPrinter,
+    };
+    use crate::observer::
+    {
+        EmptyObserver,
+        RecordingObserver,
+    };
+    use crate::blob::
+    {
+        Blob,
+        FileState,
+        FileStateVec,
+    };
+    use std::io::Write;
+
+    fn make_default_build_params() -> BuildParams
+    {
+        let mut params = BuildParams::for_test();
+        params.goal_target_opt = Some("poem.txt".to_string());
+        params
+    }
+
+    /*  Set up a filesystem and a .rules file with one poem depending on two verses
+        as source. Populate the verses with lines of the target poem.  Run the build
+        command and check that the file appears and has the correct contents. */
+    #[test]
+    fn build_basic()
+    {
+        let rules = "\
+poem.txt
+:
+verse1.txt
+verse2.txt
+:
+mycat
+verse1.txt
+verse2.txt
+poem.txt
+:
+";
+        let mut system = FakeSystem::new(10);
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+
+
+        build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_default_build_params()
+        ).unwrap();
+
+        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(), "Roses are red.\nViolets are violet.\n");
+    }
+
+    /*  Set up a filesystem and a .rules file with one poem depending on two verses
+        as source. Populate the verses with lines of the target poem, except, omit one
+        of the source files.  Run the build command and check that it errors sensibly. */
+    #[test]
+    fn build_one_source_file_missing()
+    {
+        let rules = "\
+poem.txt
+:
+verse1.txt
+verse2.txt
+:
+mycat
+verse1.txt
+verse2.txt
+poem.txt
+:
+";
+        let mut system = FakeSystem::new(10);
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        match build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_default_build_params())
+        {
+            Ok(_) => panic!("unexpected success"),
+            Err(BuildError::WorkErrors(errors)) =>
+            {
+                assert_eq!(errors.len(), 1);
+                match &errors[0].1
+                {
+                    WorkError::FileNotFound(path_str) => assert_eq!(path_str, "verse2.txt"),
+                    _ => panic!("Got work error but not the correct error: {}", errors[0].1),
+                }
+            },
+            Err(error) => panic!("Got error but not the correct error: {}", error),
+        }
+    }
+
+    /*  A source that's a named pipe fails the build straight away, without getting read, and the error
+        names the path and what sort of thing it is. */
+    #[test]
+    fn build_special_file_source()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        system.create_special_file("verse2.fifo").unwrap();
+        write_str_to_file(&mut system, "build.rules", "poem.txt\n:\nverse1.txt\nverse2.fifo\n:\nmycat\nverse1.txt\nverse2.fifo\npoem.txt\n:\n").unwrap();
+
+        match build(system.clone(), &mut EmptyPrinter::new(), None, make_default_build_params())
+        {
+            Ok(_) => panic!("Unexpected success with a special file for a source"),
+            Err(BuildError::WorkErrors(errors)) =>
+            {
+                assert_eq!(errors.len(), 1);
+                match &errors[0].1
+                {
+                    WorkError::ReadWriteError(path, ReadWriteError::NotRegularFile(_, FileType::Other)) =>
+                        assert_eq!(path, "verse2.fifo"),
+                    _ => panic!("Got work error but not the correct error: {}", errors[0].1),
+                }
+                assert_eq!(format!("{}", errors[0].1),
+                    "Error reading file: verse2.fifo: Not a regular file or directory: verse2.fifo is a special file");
+            },
+            Err(error) => panic!("Got error but not the correct error: {}", error),
+        }
+        assert_eq!(system.get_read_marker("verse2.fifo"), Some(0));
+        assert!(!system.is_file("poem.txt"));
+    }
+
+    #[test]
+    fn build_one_dependence()
+    {
+        let rules = "\
+stanza1.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+stanza1.txt
+:
+
+poem.txt
+:
+stanza1.txt
+:
+mycat
+stanza1.txt
+poem.txt
+:
+";
+        let mut system = FakeSystem::new(10);
+
+        write_str_to_file(&mut system, "verse1.txt", "I looked over Jordan, and what did I see?\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        
+
+        match build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_default_build_params()
+        )
+        {
+            Ok(_) =>
+            {
+                assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(),
+                    "I looked over Jordan, and what did I see?\n");
+            },
+            Err(error) => panic!("Unexpected error: {}", error),
+        }
+    }
+
+    #[test]
+    fn build_one_dependence_with_intermediate_already_present()
+    {
+        let rules = "\
+stanza1.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+stanza1.txt
+:
+
+poem.txt
+:
+stanza1.txt
+:
+mycat
+stanza1.txt
+poem.txt
+:
+";
+        let mut system = FakeSystem::new(10);
+
+        write_str_to_file(&mut system, "verse1.txt", "I looked over Jordan, and what did I see?\n").unwrap();
+        write_str_to_file(&mut system, "stanza1.txt", "Some wrong content\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+
+
+        match build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_default_build_params())
+        {
+            Ok(_) =>
+            {
+                assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(),
+                    "I looked over Jordan, and what did I see?\n");
+            },
+            Err(error) => panic!("Unexpected error: {}", error),
+        }
+    }
+
+    /*  Rules for a poem with two verses and a refrain.  Try building the poem three times, once with each source file omitted.
+        Check that the error matches the missing file. */
+    #[test]
+    fn build_poem_with_various_omitted_sources()
+    {
+        let rules = "\
+stanza1.txt
+:
+verse1.txt
+refrain.txt
+:
+mycat
+verse1.txt
+refrain.txt
+stanza1.txt
+:
+
+stanza2.txt
+:
+verse2.txt
+refrain.txt
+:
+mycat
+verse2.txt
+refrain.txt
+stanza2.txt
+:
+
+poem.txt
+:
+stanza1.txt
+stanza2.txt
+:
+mycat
+stanza1.txt
+stanza2.txt
+poem.txt
+:
+";
+        for omit_me in ["verse1.txt", "verse2.txt", "refrain.txt"]
+        {
+            let mut system = FakeSystem::new(10);
+
+            if omit_me != "verse1.txt"
+            {
+                write_str_to_file(&mut system, "verse1.txt", "I looked over Jordan, and what did I see?\n").unwrap();
+            }
+
+            if omit_me != "verse2.txt"
+            {
+                write_str_to_file(&mut system, "verse2.txt", "A band of angels comin' after me\n").unwrap();
+            }
+
+            if omit_me != "refrain.txt"
+            {
+                write_str_to_file(&mut system, "refrain.txt", "Comin' for to carry me home\n").unwrap();
+            }
+
+            write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+            match build(
+                system.clone(),
+                &mut EmptyPrinter::new(),
+                None,
+                make_default_build_params())
+            {
+                Ok(_) => panic!("unexpected success"),
+                Err(BuildError::WorkErrors(errors)) =>
+                {
+                    assert_eq!(errors.len(), 1);
+                    match &errors[0].1
+                    {
+                        WorkError::FileNotFound(path_str) => assert_eq!(path_str, omit_me),
+                        _ => panic!("When omitting {}, Got work error but not the correct error: {}", omit_me, errors[0].1),
+                    }
+                },
+                Err(error) => panic!("When omitting {}, Got error but not the correct error: {}", omit_me, error),
+            }
+        }
+    }
+
+    /*  Set up a filesystem and a .rules file with invalid UTF8 in it instead of rules.
+        Check that the build fails with a message about UTF8 */
+    #[test]
+    fn build_rulefile_not_utf8()
+    {
+        let mut system = FakeSystem::new(11);
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        system.create_file("build.rules").unwrap().write_all(&[0x80u8]).unwrap();
+
+        match build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_default_build_params())
+        {
+            Ok(_) => panic!("Unexpected success with invalid rules file."),
+            Err(BuildError::RuleFileNotUTF8) => {},
+            Err(error) => panic!("Got error but not the correct error: {}", error),
+        }
+    }
+
+    /*  Build everything from a rules file with nothing in it.  Check that's an error, with a warning
+        printed, rather than a quiet success. */
+    #[test]
+    fn build_empty_rules_file()
+    {
+        let mut system = FakeSystem::new(11);
+        write_str_to_file(&mut system, "build.rules", "\n\n").unwrap();
+
+        let mut printer = RecordingPrinter::new();
+        match build(system.clone(), &mut printer, None, BuildParams::for_test())
+        {
+            Ok(_) => panic!("Unexpected success with empty rules file"),
+            Err(BuildError::NoRules(None)) => {},
+            Err(error) => panic!("Got error but not the correct error: {}", error),
+        }
+
+        assert_eq!(printer.printed, vec![
+            "   Warning rules file(s) parsed but contain no rules (pass --allow-empty if that's intended)".to_string()]);
+    }
+
+    /*  Build a goal target from an empty rules file, and check the error says there were no rules
+        at all instead of just that the target is missing. */
+    #[test]
+    fn build_empty_rules_file_with_target()
+    {
+        let mut system = FakeSystem::new(11);
+        write_str_to_file(&mut system, "build.rules", "").unwrap();
+
+        match build(system.clone(), &mut EmptyPrinter::new(), None, make_default_build_params())
+        {
+            Ok(_) => panic!("Unexpected success with empty rules file"),
+            Err(BuildError::NoRules(Some(target))) => assert_eq!(target, "poem.txt"),
+            Err(error) => panic!("Got error but not the correct error: {}", error),
+        }
+    }
+
+    /*  With allow_empty, building everything from an empty rules file succeeds and prints nothing but
+        the summary. */
+    #[test]
+    fn build_empty_rules_file_allowed()
+    {
+        let mut system = FakeSystem::new(11);
+        write_str_to_file(&mut system, "build.rules", "").unwrap();
+
+        let mut params = BuildParams::for_test();
+        params.allow_empty = true;
+
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, params).unwrap();
+        assert_eq!(printer.printed, vec!["Nothing to do: 0 targets up to date".to_string()]);
+    }
+
+    /*  With require_rules, rules files with nothing but blank lines and comments are an error naming
+        every file checked, both building everything (even with allow_empty) and building a target.
+        Without it, allow_empty makes the same build succeed with nothing to do. */
+    #[test]
+    fn build_require_rules()
+    {
+        let mut system = FakeSystem::new(11);
+        write_str_to_file(&mut system, "a.rules", "\n# Nothing yet\n").unwrap();
+        write_str_to_file(&mut system, "b.rules", "").unwrap();
+
+        let mut params = BuildParams::for_test();
+        params.rulefile_paths = vec!["a.rules".to_string(), "b.rules".to_string()];
+        params.allow_empty = true;
+
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, params.clone()).unwrap();
+        assert_eq!(printer.printed, vec!["Nothing to do: 0 targets up to date".to_string()]);
+
+        params.require_rules = true;
+        match build(system.clone(), &mut EmptyPrinter::new(), None, params.clone())
+        {
+            Ok(_) => panic!("Unexpected success with rules required"),
+            Err(error) =>
+            {
+                assert_eq!(format!("{}", error), "Rules required, but no rules found in: a.rules, b.rules");
+                match error
+                {
+                    BuildError::RulesRequired(paths) => assert_eq!(paths, vec!["a.rules", "b.rules"]),
+                    _ => panic!("Got error but not the correct error: {}", error),
+                }
+            },
+        }
+
+        params.goal_target_opt = Some("poem.txt".to_string());
+        match build(system.clone(), &mut EmptyPrinter::new(), None, params)
+        {
+            Ok(_) => panic!("Unexpected success with rules required"),
+            Err(BuildError::RulesRequired(paths)) => assert_eq!(paths, vec!["a.rules", "b.rules"]),
+            Err(error) => panic!("Got error but not the correct error: {}", error),
+        }
+    }
+
+    /*  Set up a filesystem and a .rules file with one real dependence missing
+        from the rules.  Build once, make sure it goes as planned, then change
+        the contents of the omitted source file.  Check that Building again produces
+        a particular error: a contradiction. */
+    #[test]
+    fn build_with_missing_source()
+    {
+        let rules = "\
+poem.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+verse2.txt
+poem.txt
+:
+";
+        let mut system = FakeSystem::new(10);
+
+        system.create_dir(".ruler-cache").unwrap();
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are blue.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_default_build_params()
+        ).unwrap();
+
+        system.time_passes(1);
+
+        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(), "Roses are red.\nViolets are blue.\n");
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "poem.txt", "Wrong content forcing a rebuild").unwrap();
+
+        match build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_default_build_params())
+        {
+            Ok(()) => panic!("Unexpected silence when contradiction should arise"),
+            Err(error) =>
+            {
+                match error
+                {
+                    BuildError::WorkErrors(work_errors) =>
+                    {
+                        assert_eq!(work_errors.len(), 1);
+                        match &work_errors[0].1
+                        {
+                            WorkError::Contradiction(paths, _provenance) => assert_eq!(paths, &vec!["poem.txt".to_string()]),
+                            _ => panic!("Wrong type of WorkError"),
+                        }
+                    }
+                    _ => panic!("Wrong type of error"),
+                }
+            },
+        }
+
+        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(), "Roses are red.\nViolets are violet.\n");
+    }
+
+    /*  Set up filesystem to build a poem with two verses.  Invoke the build, and check the resulting poem. */
+    #[test]
+    fn build_change_build_check_cache()
+    {
+        let rules = "\
+poem.txt
+:
+verse1.txt
+verse2.txt
+:
+mycat
+verse1.txt
+verse2.txt
+poem.txt
+:
+";
+        let mut system = FakeSystem::new(10);
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are blue.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_default_build_params()
+        ).unwrap();
+
+        system.time_passes(1);
+
+        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(),
+            "Roses are red.\nViolets are blue.\n");
+
+        let ticket = TicketFactory::from_file(&system, "poem.txt").unwrap().result();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+
+        build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_default_build_params()
+        ).unwrap();
+
+        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(),
+            "Roses are red.\nViolets are violet.\n");
+
+        let mut cache = SysCache::new(system.clone(), ".ruler/cache");
+        cache.restore_file(&ticket, "temp-poem.txt");
+
+        assert_eq!(read_file_to_string(&mut system, "temp-poem.txt").unwrap(),
+            "Roses are red.\nViolets are blue.\n");
+
+        cache.back_up_file_with_ticket(&ticket, "temp-poem.txt").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are blue.\n").unwrap();
+
+        build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_default_build_params()
+        ).unwrap();
+
+        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(), "Roses are red.\nViolets are blue.\n");
+    }
+
+    /*  Set up filesystem to build a poem with incorrect rules, which say they generate a target, but actually do not. */
+    #[test]
+    fn build_command_fails_to_generate_target()
+    {
+        let rules = "\
+poem.txt
+:
+verse1.txt
+verse2.txt
+:
+mycat
+verse1.txt
+verse2.txt
+someotherpoem.txt
+:
+";
+        let mut system = FakeSystem::new(10);
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are blue.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        match build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_default_build_params()
+        )
+        {
+            Ok(_) => panic!("unexpected success"),
+            Err(BuildError::WorkErrors(errors)) =>
+            {
+                assert_eq!(errors.len(), 1);
+                match &errors[0].1
+                {
+                    WorkError::TargetFileNotGenerated(path_str) => assert_eq!(path_str, "poem.txt"),
+                    _ => panic!("Got work error but not the correct error: {}", errors[0].1),
+                }
+            },
+            Err(error) => panic!("Got error but not the correct error: {}", error),
+        }
+    }
+
+    /*  With rules split across two files, the rule that fails to generate its target is in the second
+        one, and the error says so, down to the line. */
+    #[test]
+    fn build_error_names_rule_file_of_failing_rule()
+    {
+        let mut system = FakeSystem::new(10);
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are blue.\n").unwrap();
+        write_str_to_file(&mut system, "stanza.rules", "\
+stanza.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+stanza.txt
+:
+").unwrap();
+        write_str_to_file(&mut system, "poem.rules", "\
+poem.txt
+:
+stanza.txt
+verse2.txt
+:
+mycat
+stanza.txt
+verse2.txt
+someotherpoem.txt
+:
+").unwrap();
+
+        let mut params = BuildParams::for_test();
+        params.rulefile_paths = vec!["stanza.rules".to_string(), "poem.rules".to_string()];
+        match build(system.clone(), &mut EmptyPrinter::new(), None, params)
+        {
+            Ok(_) => panic!("unexpected success"),
+            Err(BuildError::WorkErrors(errors)) =>
+            {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].0, Some(Provenance::new("poem.rules", 1)));
+                assert_eq!(format!("{}", BuildError::WorkErrors(errors)),
+                    "poem.rules:1: Target file missing after running build command: poem.txt\n");
+            },
+            Err(error) => panic!("Got error but not the correct error: {}", error),
+        }
+
+        assert_eq!(read_file_to_string(&system, "stanza.txt").unwrap(), "Roses are red.\n");
+    }
+
+    /*  In a file system, create source files and rules file for a poem.
+        Access the .ruler direcotry, and use the take() function to get the state of the poem.
+        Verify that it is uninitialized.  Then run the build.  Verify that the build imparted
+        the new FileState on the poem. */
+    #[test]
+    fn build_check_file_state()
+    {
+        let rules = "\
+poem.txt
+:
+verse1.txt
+verse2.txt
+:
+mycat
+verse1.txt
+verse2.txt
+poem.txt
+:
+";
+        let mut system = FakeSystem::new(17);
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        let expected_poem_blob_before = Blob::from_paths(vec!["poem.txt".to_string()], |_path|{FileState::empty()});
+        let expected_poem_blob_after = Blob::from_paths(vec!["poem.txt".to_string()], |_path|
+            {FileState::new(
+                TicketFactory::from_str("Roses are red.\nViolets are violet.\n").result(), 17)
+            });
+
+        {
+            let mut elements = directory::init(&mut system, ".ruler", None, None, false).unwrap();
+            assert_eq!(elements.current_file_states.take_blob(vec!["poem.txt".to_string()]), expected_poem_blob_before);
+        }
+
+        build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_default_build_params()
+            ).unwrap();
+
+        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(),
+            "Roses are red.\nViolets are violet.\n");
+
+        {
+            let mut elements = directory::init(&mut system, ".ruler", None, None, false).unwrap();
+            assert_eq!(elements.current_file_states.take_blob(vec!["poem.txt".to_string()]), expected_poem_blob_after);
+        }
+    }
+
+    /*  Rules for a poem and a stanza, where the poem rule starts on line 42 of its-file.rules.
+        Used to check that errors point at the responsible rule. */
+    fn rules_with_poem_on_line_42(poem_command : &str) -> String
+    {
+        format!("\
+stanza.txt
+:
+verse.txt
+:
+mycat
+verse.txt
+stanza.txt
+:
+{}poem.txt
+:
+stanza.txt
+:
+{}
+:
+", "\n".repeat(33), poem_command)
+    }
+
+    fn make_its_file_build_params() -> BuildParams
+    {
+        let mut params = BuildParams::new(".ruler".to_string(), vec!["its-file.rules".to_string()]);
+        params.goal_target_opt = Some("poem.txt".to_string());
+        params
+    }
+
+    /*  Give the poem a second rule on line 42, check that the sort error names that line. */
+    #[test]
+    fn build_sort_error_names_rule_file_and_line()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "its-file.rules", &format!("\
+poem.txt
+:
+verse.txt
+:
+mycat
+verse.txt
+poem.txt
+:
+{}poem.txt
+:
+verse.txt
+:
+mycat
+verse.txt
+poem.txt
+:
+", "\n".repeat(33))).unwrap();
+
+        match build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_its_file_build_params())
+        {
+            Ok(_) => panic!("Unexpected success with poem.txt in two rules"),
+            Err(error) =>
+            {
+                match &error
+                {
+                    BuildError::TopologicalSortFailed(_) => {},
+                    _ => panic!("Wrong kind of error: {}", error),
+                }
+                assert!(format!("{}", error).contains("its-file.rules:42"), "{}", error);
+            },
+        }
+    }
+
+    /*  Make the command for the poem rule on line 42 fail, check that the work error names that line. */
+    #[test]
+    fn build_work_error_names_rule_file_and_line()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "its-file.rules", &rules_with_poem_on_line_42("error")).unwrap();
+
+        match build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_its_file_build_params())
+        {
+            Ok(_) => panic!("Unexpected success when command errors"),
+            Err(error) =>
+            {
+                match &error
+                {
+                    BuildError::WorkErrors(work_errors) =>
+                    {
+                        assert_eq!(work_errors.len(), 1);
+                        assert_eq!(work_errors[0].0, Some(Provenance::new("its-file.rules", 42)));
+                    },
+                    _ => panic!("Wrong kind of error: {}", error),
+                }
+                assert!(format!("{}", error).contains("its-file.rules:42"), "{}", error);
+            },
+        }
+    }
+
+    #[test]
+    fn build_first_does_not_cache()
+    {
+        let rules = "\
+poem.txt
+:
+verse1.txt
+verse2.txt
+:
+mycat
+verse1.txt
+verse2.txt
+poem.txt
+:
+";
+        let mut system = FakeSystem::new(19);
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_default_build_params()
+        ).unwrap();
+
+        assert_eq!(
+            read_file_to_string(&mut system, "poem.txt").unwrap(),
+            "Roses are red.\nViolets are violet.\n");
+
+        let elements = directory::init(&mut system, "ruler-directory", None, None, false).unwrap();
+        match elements.cache.open(&TicketFactory::from_str("Roses are red.\nViolets are violet.\n").result())
+        {
+            Ok(_file) => panic!("Unexpected cache presence after first build"),
+            Err(OpenError::NotThere) => {},
+            Err(_) => panic!("Unexpected error trying to access cache after first build"),
+        }
+    }
+
+    fn make_prefetch_build_params() -> BuildParams
+    {
+        let mut params = BuildParams::for_test();
+        params.urlfile_path_opt = Some("download.urls".to_string());
+        params
+    }
+
+    /*  Serve target tickets and poem content from a fake remote, prefetch, then build with no download
+        urls at all.  The command errors if it runs, so the build succeeding means the poem came out of
+        what prefetch stored.  Also check prefetch left the working tree alone. */
+    #[test]
+    fn prefetch_then_build_offline()
+    {
+        let rules = "\
+poem.txt
+:
+verse1.txt
+verse2.txt
+:
+error
+:
+";
+        let mut system = FakeSystem::new(20);
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+        write_str_to_file(&mut system, "download.urls", "urls = [\"http://remote\"]").unwrap();
+
+        let rule_ticket = get_nodes(&system, vec!["build.rules".to_string()], None, false, false, None).unwrap().nodes[0].rule_ticket.clone();
+        let mut factory = TicketFactory::new();
+        factory.input_ticket(TicketFactory::from_str("Roses are red.\n").result());
+        factory.input_ticket(TicketFactory::from_str("Violets are violet.\n").result());
+        let sources_ticket = factory.result();
+        let poem_ticket = TicketFactory::from_str("Roses are red.\nViolets are violet.\n").result();
+
+        let mut downloader = FakeDownloader::new();
+        downloader.serve(
+            &format!("http://remote/rules/{}/{}", rule_ticket.human_readable(), sources_ticket.human_readable()),
+            FileStateVec::from_ticket_vec(vec![poem_ticket.clone()]).download_string().as_bytes());
+        downloader.serve(
+            &format!("http://remote/files/{}", poem_ticket.human_readable()),
+            "Roses are red.\nViolets are violet.\n".as_bytes());
+
+        assert_eq!(
+            prefetch(system.clone(), &downloader, &mut EmptyPrinter::new(), make_prefetch_build_params()).unwrap(),
+            vec![(vec!["poem.txt".to_string()], PrefetchStatus::Prefetched)]);
+
+        assert!(!system.is_file("poem.txt"));
+
+        build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_default_build_params()
+        ).unwrap();
+
+        assert_eq!(
+            read_file_to_string(&mut system, "poem.txt").unwrap(),
+            "Roses are red.\nViolets are violet.\n");
+
+        assert_eq!(
+            prefetch(system.clone(), &FakeDownloader::new(), &mut EmptyPrinter::new(), make_prefetch_build_params()).unwrap(),
+            vec![(vec!["poem.txt".to_string()], PrefetchStatus::AlreadyLocal)]);
+    }
+
+    /*  With nothing on the remote, the stanza rule has no remote entry, and since its target can't be
+        known, the poem depending on it reports that its sources differ. */
+    #[test]
+    fn prefetch_no_remote_entry_then_sources_differ()
+    {
+        let rules = "\
+stanza.txt
+:
+verse1.txt
+verse2.txt
+:
+mycat
+verse1.txt
+verse2.txt
+stanza.txt
+:
+
+poem.txt
+:
+stanza.txt
+:
+mycat
+stanza.txt
+poem.txt
+:
+";
+        let mut system = FakeSystem::new(21);
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+        write_str_to_file(&mut system, "download.urls", "urls = [\"http://remote\"]").unwrap();
+
+        assert_eq!(
+            prefetch(system.clone(), &FakeDownloader::new(), &mut EmptyPrinter::new(), make_prefetch_build_params()).unwrap(),
+            vec![
+                (vec!["stanza.txt".to_string()], PrefetchStatus::NoRemoteEntry),
+                (vec!["poem.txt".to_string()], PrefetchStatus::SourcesDiffer),
+            ]);
+    }
+
+    /*  The remote remembers the poem's ticket, but serves content that doesn't match it.  Prefetch
+        should refuse the content, and not remember the ticket locally either. */
+    #[test]
+    fn prefetch_rejects_content_not_matching_ticket()
+    {
+        let rules = "\
+poem.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+poem.txt
+:
+";
+        let mut system = FakeSystem::new(22);
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+        write_str_to_file(&mut system, "download.urls", "urls = [\"http://remote\"]").unwrap();
+
+        let rule_ticket = get_nodes(&system, vec!["build.rules".to_string()], None, false, false, None).unwrap().nodes[0].rule_ticket.clone();
+        let mut factory = TicketFactory::new();
+        factory.input_ticket(TicketFactory::from_str("Roses are red.\n").result());
+        let sources_ticket = factory.result();
+        let poem_ticket = TicketFactory::from_str("Roses are red.\n").result();
+
+        let mut downloader = FakeDownloader::new();
+        downloader.serve(
+            &format!("http://remote/rules/{}/{}", rule_ticket.human_readable(), sources_ticket.human_readable()),
+            FileStateVec::from_ticket_vec(vec![poem_ticket.clone()]).download_string().as_bytes());
+        downloader.serve(
+            &format!("http://remote/files/{}", poem_ticket.human_readable()),
+            "Roses are blue.\n".as_bytes());
+
+        assert_eq!(
+            prefetch(system.clone(), &downloader, &mut EmptyPrinter::new(), make_prefetch_build_params()).unwrap(),
+            vec![(vec!["poem.txt".to_string()], PrefetchStatus::DownloadFailed)]);
+
+        let elements = directory::init(&mut system, ".ruler", None, None, false).unwrap();
+        assert!(elements.cache.open(&poem_ticket).is_err());
+        assert_eq!(
+            elements.history.read_rule_history(&rule_ticket).unwrap().get_file_state_vec(&sources_ticket),
+            None);
+    }
+
+    /*  Write the poem rules with a byte-order-mark and CRLF line endings, the way Notepad might, and
+        check the poem builds by name, and the download urls file gets the same treatment. */
+    #[test]
+    fn build_rules_with_bom_and_crlf()
+    {
+        let rules = "\u{feff}poem.txt\r\n:\r\nverse1.txt\r\nverse2.txt\r\n:\r\nmycat\r\nverse1.txt\r\nverse2.txt\r\npoem.txt\r\n:\r\n";
+        let mut system = FakeSystem::new(23);
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+        write_str_to_file(&mut system, "download.urls", "\u{feff}urls = []\r\n").unwrap();
+
+        let node_pack = get_nodes(&system, vec!["build.rules".to_string()], Some("poem.txt".to_string()), false, false, None).unwrap();
+        assert_eq!(node_pack.nodes[0].targets, vec!["poem.txt".to_string()]);
+        assert_eq!(node_pack.leaves, vec!["verse1.txt".to_string(), "verse2.txt".to_string()]);
+
+        let mut params = BuildParams::for_test();
+        params.urlfile_path_opt = Some("download.urls".to_string());
+        params.goal_target_opt = Some("poem.txt".to_string());
+
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, params).unwrap();
+
+        assert_eq!(
+            read_file_to_string(&mut system, "poem.txt").unwrap(),
+            "Roses are red.\nViolets are violet.\n");
+        assert!(printer.printed.contains(
+            &"   Warning build.rules has a byte-order-mark or \\r\\n line endings, read as if it had plain \\n".to_string()));
+    }
+
+    /*  A generated rules file with the poem rule emitted twice.  With dedupe on, the duplicate goes
+        away quietly.  Change the second copy's command, and it's an error regardless. */
+    #[test]
+    fn get_nodes_dedupes_identical_rules_only()
+    {
+        let poem_rule = "poem.txt\n:\nverse1.txt\n:\nmycat\nverse1.txt\npoem.txt\n:\n";
+        let mut system = FakeSystem::new(24);
+
+        write_str_to_file(&mut system, "build.rules", &format!("{}\n{}", poem_rule, poem_rule)).unwrap();
+
+        match get_nodes(&system, vec!["build.rules".to_string()], None, false, false, None)
+        {
+            Err(BuildError::TopologicalSortFailed(_)) => {},
+            _ => panic!("Expected duplicate rules to be an error without dedupe"),
+        }
+
+        let node_pack = get_nodes(&system, vec!["build.rules".to_string()], None, true, false, None).unwrap();
+        assert_eq!(node_pack.nodes.len(), 1);
+        assert_eq!(node_pack.nodes[0].provenance, Provenance::new("build.rules", 1));
+
+        write_str_to_file(&mut system, "build.rules",
+            &format!("{}\n{}", poem_rule, poem_rule.replace("mycat", "error"))).unwrap();
+
+        match get_nodes(&system, vec!["build.rules".to_string()], None, true, false, None)
+        {
+            Err(BuildError::TopologicalSortFailed(_)) => {},
+            _ => panic!("Expected conflicting rules to be an error even with dedupe"),
+        }
+    }
+
+    /*  Two rules files.  Getting the nodes twice with nothing changed sorts once, the second time
+        coming out the same from the ruler directory.  Changing one of the files, or asking for a
+        particular target, sorts again. */
+    #[test]
+    fn get_nodes_cached_skips_sort_when_rules_unchanged()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir(".ruler").unwrap();
+        write_str_to_file(&mut system, "poem.rules", "poem.txt\n:\nstanza.txt\n:\nmycat\nstanza.txt\npoem.txt\n:\n").unwrap();
+        write_str_to_file(&mut system, "stanza.rules", "stanza.txt\n:\nverse.txt\n:\nmycat\nverse.txt\nstanza.txt\n:\n").unwrap();
+        let rulefile_paths = vec!["poem.rules".to_string(), "stanza.rules".to_string()];
+        let params = BuildParams::new(".ruler".to_string(), rulefile_paths.clone());
+
+        let mut sort_count = 0;
+        let first = get_nodes_cached(&mut system, &FakeDownloader::new(), &params, &mut sort_count, &mut vec![], &mut None).unwrap();
+        let second = get_nodes_cached(&mut system, &FakeDownloader::new(), &params, &mut sort_count, &mut vec![], &mut None).unwrap();
+        assert_eq!(sort_count, 1);
+        assert_eq!(first, second);
+        assert_eq!(second, get_nodes(&system, rulefile_paths.clone(), None, false, false, None).unwrap());
+
+        write_str_to_file(&mut system, "stanza.rules", "stanza.txt\n:\nverse.txt\n:\nmycat2\nverse.txt\nstanza.txt\n:\n").unwrap();
+        let third = get_nodes_cached(&mut system, &FakeDownloader::new(), &params, &mut sort_count, &mut vec![], &mut None).unwrap();
+        assert_eq!(sort_count, 2);
+        assert_ne!(third, second);
+        assert_eq!(third, get_nodes(&system, rulefile_paths.clone(), None, false, false, None).unwrap());
+
+        get_nodes_cached(&mut system, &FakeDownloader::new(), &params, &mut sort_count, &mut vec![], &mut None).unwrap();
+        assert_eq!(sort_count, 2);
+
+        get_nodes_cached(&mut system, &FakeDownloader::new(),
+            &params.with_goal_target(Some("stanza.txt".to_string())), &mut sort_count, &mut vec![], &mut None).unwrap();
+        assert_eq!(sort_count, 3);
+    }
+
+    /*  Build the poem and check the timings file in the ruler directory has an entry for its rule,
+        with the times in order. */
+    #[test]
+    fn build_records_timings()
+    {
+        let rules = "poem.txt\n:\nverse1.txt\nverse2.txt\n:\nmycat\nverse1.txt\nverse2.txt\npoem.txt\n:\n";
+        let mut system = FakeSystem::new(25);
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            make_default_build_params()
+        ).unwrap();
+
+        let rule_ticket = get_nodes(&system, vec!["build.rules".to_string()], None, false, false, None).unwrap().nodes[0].rule_ticket.clone();
+        let timings = read_timings(&system, &get_timings_path(".ruler")).unwrap();
+        let timing = timings.get(&rule_ticket).unwrap();
+        assert!(timing.wait_start <= timing.wait_end);
+        assert!(timing.wait_end <= timing.work_end);
+    }
+
+    /*  The poem comes from two stanzas, each from a verse and the shared chorus.  The leaves are
+        the chorus and the verses, sorted, with the stanzas left out since rules produce them. */
+    #[test]
+    fn get_leaves_poem()
+    {
+        let rules = "\
+poem.txt
+:
+stanza2.txt
+stanza1.txt
+:
+mycat
+stanza1.txt
+stanza2.txt
+poem.txt
+:
+
+stanza1.txt
+:
+verse1.txt
+chorus.txt
+:
+mycat
+verse1.txt
+chorus.txt
+stanza1.txt
+:
+
+stanza2.txt
+:
+verse2.txt
+chorus.txt
+:
+mycat
+verse2.txt
+chorus.txt
+stanza2.txt
+:
+";
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        assert_eq!(get_leaves(&system, vec!["build.rules".to_string()], false, false).unwrap(),
+            vec![
+                "chorus.txt".to_string(),
+                "verse1.txt".to_string(),
+                "verse2.txt".to_string(),
+            ]);
+    }
+
+    /*  Print the command for the poem, check it's the command section of the rule, and that nothing
+        got built. */
+    #[test]
+    fn get_command_lines_poem()
+    {
+        let rules = "\
+stanza.txt
+:
+verse1.txt
+verse2.txt
+:
+mycat
+verse1.txt
+verse2.txt
+stanza.txt
+:
+
+poem.txt
+:
+stanza.txt
+:
+mycat
+stanza.txt
+poem.txt
+;
+mycat
+poem.txt
+:
+";
+        let mut system = FakeSystem::new(26);
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        assert_eq!(
+            get_command_lines(&system, vec!["build.rules".to_string()], "poem.txt".to_string(), false, false).unwrap(),
+            vec!["mycat stanza.txt poem.txt".to_string(), "mycat poem.txt".to_string()]);
+
+        assert!(!system.is_file("poem.txt"));
+        assert!(!system.is_dir(".ruler"));
+    }
+
+    /*  A rules file with a target outside the project directory.  Clean refuses to touch it, and
+        says which target and rule.  With the directive, clean moves it into the cache like any other. */
+    #[test]
+    fn clean_refuses_external_target_without_directive()
+    {
+        let rules = "../shared/out.bin\n:\nverse1.txt\n:\nmycat\nverse1.txt\n../shared/out.bin\n:\n";
+        let mut system = FakeSystem::new(27);
+
+        system.create_dir("shared").unwrap();
+        system.create_dir("project").unwrap();
+        system.set_current_dir("project").unwrap();
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "../shared/out.bin", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        match clean(system.clone(), &mut EmptyPrinter::new(), BuildParams::for_test())
+        {
+            Err(BuildError::RuleFileFailedToParse(error)) =>
+            {
+                let message = format!("{}", error);
+                assert!(message.contains("../shared/out.bin"), "{}", message);
+                assert!(message.contains("build.rules:1"), "{}", message);
+            },
+            _ => panic!("Expected clean to refuse an external target"),
+        }
+
+        assert!(system.is_file("/shared/out.bin"));
+
+        write_str_to_file(&mut system, "build.rules",
+            &format!("@allow-external-targets ../shared\n{}", rules)).unwrap();
+
+        clean(system.clone(), &mut EmptyPrinter::new(), BuildParams::for_test()).ok().unwrap();
+        assert!(!system.is_file("/shared/out.bin"));
+    }
+
+    /*  Build the poem, scribble over it, and fill up the disk the cache is on.  The next build can't
+        keep the scribbled copy, says so, and rebuilds the poem anyway. */
+    #[test]
+    fn build_with_full_cache_warns_and_rebuilds()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "poem.txt\n:\nverse1.txt\n:\nmycat\nverse1.txt\npoem.txt\n:\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+        system.time_passes(1);
+        write_str_to_file(&mut system, "poem.txt", "Scribbles.\n").unwrap();
+        system.set_out_of_space(".ruler/cache");
+
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\n");
+        assert_eq!(system.get_command_log().len(), 2);
+        assert!(printer.printed.contains(
+            &"   Warning could not cache previous copy of poem.txt: disk full".to_string()), "{:?}", printer.printed);
+    }
+
+    /*  Clean is nothing but moving targets into the cache, so with the cache's disk full, clean still
+        fails, and the poem stays put. */
+    #[test]
+    fn clean_with_full_cache_fails()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "poem.txt\n:\nverse1.txt\n:\nmycat\nverse1.txt\npoem.txt\n:\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        system.set_out_of_space(".ruler/cache");
+
+        match clean(system.clone(), &mut EmptyPrinter::new(), BuildParams::for_test())
+        {
+            Err(BuildError::WorkErrors(work_errors)) =>
+            {
+                assert_eq!(work_errors.len(), 1);
+                match &work_errors[0].1
+                {
+                    WorkError::FileNotAvailableToCache(path, _error) => assert_eq!(path, "poem.txt"),
+                    error => panic!("Unexpected error: {}", error),
+                }
+            },
+            _ => panic!("Expected clean to fail with the cache full"),
+        }
+
+        assert!(system.is_file("poem.txt"));
+    }
+
+    /*  Build with no history.  The poem comes out right, and no rule history gets written. */
+    #[test]
+    fn build_with_no_history_writes_no_history()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "poem.txt\n:\nverse1.txt\nverse2.txt\n:\nmycat\nverse1.txt\nverse2.txt\npoem.txt\n:\n").unwrap();
+
+        let mut params = BuildParams::for_test();
+        params.no_history = true;
+        build(system.clone(), &mut EmptyPrinter::new(), None, params).unwrap();
+
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\nViolets are violet.\n");
+        assert_eq!(system.list_dir(".ruler/history").unwrap().len(), 0);
+    }
+
+    /*  Build and then clean with the cache kept outside the ruler directory.  The cleaned poem's
+        backup lands in that cache, while the rule's history stays in the ruler directory, which gets
+        no cache of its own. */
+    #[test]
+    fn build_and_clean_with_separate_cache_directory()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "poem.txt\n:\nverse1.txt\n:\nmycat\nverse1.txt\npoem.txt\n:\n").unwrap();
+
+        let mut params = BuildParams::for_test();
+        params.cache_directory_opt = Some("machine/cache".to_string());
+        build(system.clone(), &mut EmptyPrinter::new(), None, params.clone()).unwrap();
+
+        clean(system.clone(), &mut EmptyPrinter::new(), params).unwrap();
+
+        assert!(!system.is_file("poem.txt"));
+        assert!(system.is_file(&format!("machine/cache/{}",
+            TicketFactory::from_str("Roses are red.\n").result().human_readable())));
+        assert_eq!(system.list_dir(".ruler/history").unwrap().len(), 1);
+        assert!(!system.is_dir(".ruler/cache"));
+    }
+
+    /*  Serve the poem rules from a url, build with the url as the rules file, and check the poem
+        gets built.  Then try a url nothing is served at, and check for the download error. */
+    #[test]
+    fn build_rules_from_url()
+    {
+        let rules = "poem.txt\n:\nverse1.txt\nverse2.txt\n:\nmycat\nverse1.txt\nverse2.txt\npoem.txt\n:\n";
+        let mut system = FakeSystem::new(28);
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+
+        let mut downloader = FakeDownloader::new();
+        downloader.serve("https://rules.example/build.rules", rules.as_bytes());
+
+        build_with_downloader(
+            system.clone(),
+            &downloader,
+            &mut EmptyPrinter::new(),
+            &mut EmptyObserver::new(),
+            BuildParams::new(".ruler".to_string(), vec!["https://rules.example/build.rules".to_string()])
+                .with_goal_target(Some("poem.txt".to_string())),
+            vec![]).unwrap();
+
+        assert_eq!(
+            read_file_to_string(&mut system, "poem.txt").unwrap(),
+            "Roses are red.\nViolets are violet.\n");
+
+        match build_with_downloader(
+            system.clone(),
+            &downloader,
+            &mut EmptyPrinter::new(),
+            &mut EmptyObserver::new(),
+            BuildParams::new(".ruler".to_string(), vec!["https://rules.example/missing.rules".to_string()]),
+                vec![])
+        {
+            Err(BuildError::RuleFileFailedToDownload(url, _error)) =>
+                assert_eq!(url, "https://rules.example/missing.rules"),
+            _ => panic!("Expected a download error for a url with nothing there"),
+        }
+    }
+
+    /*  The urls file has a garbage url next to a good one.  Prefetch warns about the garbage, skips it,
+        and downloads the poem from the good one.  The build after warns the same way and proceeds.  The
+        command errors if it runs, so the build succeeding means the poem came from the remote. */
+    #[test]
+    fn build_skips_malformed_download_url()
+    {
+        let mut system = FakeSystem::new(20);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "poem.txt\n:\nverse1.txt\n:\nerror\n:\n").unwrap();
+        write_str_to_file(&mut system, "download.urls", "urls = [\"not a url\", \"http://remote\"]").unwrap();
+
+        let rule_ticket = get_nodes(&system, vec!["build.rules".to_string()], None, false, false, None).unwrap().nodes[0].rule_ticket.clone();
+        let mut factory = TicketFactory::new();
+        factory.input_ticket(TicketFactory::from_str("Roses are red.\n").result());
+        let sources_ticket = factory.result();
+        let poem_ticket = TicketFactory::from_str("Roses are red.\n").result();
+
+        let mut downloader = FakeDownloader::new();
+        downloader.serve(
+            &format!("http://remote/rules/{}/{}", rule_ticket.human_readable(), sources_ticket.human_readable()),
+            FileStateVec::from_ticket_vec(vec![poem_ticket.clone()]).download_string().as_bytes());
+        downloader.serve(
+            &format!("http://remote/files/{}", poem_ticket.human_readable()),
+            "Roses are red.\n".as_bytes());
+
+        let mut printer = RecordingPrinter::new();
+        assert_eq!(
+            prefetch(system.clone(), &downloader, &mut printer, make_prefetch_build_params()).unwrap(),
+            vec![(vec!["poem.txt".to_string()], PrefetchStatus::Prefetched)]);
+        assert!(printer.printed.contains(&"   Warning Skipping malformed download url in download.urls: \"not a url\"".to_string()));
+
+        let mut params = BuildParams::for_test();
+        params.urlfile_path_opt = Some("download.urls".to_string());
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, params).unwrap();
+        assert!(printer.printed.contains(&"   Warning Skipping malformed download url in download.urls: \"not a url\"".to_string()));
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\n");
+    }
+
+    /*  Same setup as build_with_missing_source, but with contradictions downgraded to warnings.
+        The contradicting build succeeds, and the history takes the new result: a third build (back
+        to treating contradictions as errors) with the poem scrambled recovers the new poem from
+        cache instead of reporting a contradiction. */
+    #[test]
+    fn build_with_missing_source_contradiction_warns()
+    {
+        let rules = "\
+poem.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+verse2.txt
+poem.txt
+:
+";
+        let mut system = FakeSystem::new(10);
+
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are blue.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, make_default_build_params()).unwrap();
+        system.time_passes(1);
+
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "poem.txt", "Wrong content forcing a rebuild").unwrap();
+
+        let mut params = make_default_build_params();
+        params.contradiction_policy = ContradictionPolicy::Warn;
+        build(system.clone(), &mut EmptyPrinter::new(), None, params).unwrap();
+        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(), "Roses are red.\nViolets are violet.\n");
+
+        system.time_passes(1);
+        write_str_to_file(&mut system, "poem.txt", "Wrong content again").unwrap();
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, make_default_build_params()).unwrap();
+        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(), "Roses are red.\nViolets are violet.\n");
+    }
+
+    /*  A private rule next to a public one, with a download url configured.  Prefetch asks the remote
+        about the public rule, but never makes a single request mentioning the private rule's ticket. */
+    #[test]
+    fn prefetch_skips_downloader_for_private_rule()
+    {
+        let rules = "\
+@private
+licensed.bin
+:
+sdk.txt
+:
+mycat
+sdk.txt
+licensed.bin
+:
+
+poem.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+poem.txt
+:
+";
+        let mut system = FakeSystem::new(24);
+
+        write_str_to_file(&mut system, "sdk.txt", "Licensed bits\n").unwrap();
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+        write_str_to_file(&mut system, "download.urls", "urls = [\"http://remote\"]").unwrap();
+
+        let node_pack = get_nodes(&system, vec!["build.rules".to_string()], None, false, false, None).unwrap();
+        let private_node = node_pack.nodes.iter().find(|node| node.private).unwrap();
+        let public_node = node_pack.nodes.iter().find(|node| !node.private).unwrap();
+
+        let downloader = FakeDownloader::new();
+        let report = prefetch(system.clone(), &downloader, &mut EmptyPrinter::new(), make_prefetch_build_params()).unwrap();
+        assert!(report.contains(&(vec!["licensed.bin".to_string()], PrefetchStatus::Private)));
+        assert!(report.contains(&(vec!["poem.txt".to_string()], PrefetchStatus::NoRemoteEntry)));
+
+        let requests = downloader.requests();
+        assert!(requests.iter().any(|url| url.contains(&public_node.rule_ticket.human_readable())));
+        assert!(!requests.iter().any(|url| url.contains(&private_node.rule_ticket.human_readable())));
+    }
+
+
+    const DIAMOND_RULES : &str = "\
+base.txt
+:
+leaf.txt
+:
+mycat
+leaf.txt
+base.txt
+:
+
+left.txt
+:
+base.txt
+:
+mycat
+base.txt
+left.txt
+:
+
+right.txt
+:
+base.txt
+:
+mycat
+base.txt
+right.txt
+:
+";
+
+    /*  Each history file in the ruler directory with its modified time. */
+    fn get_history_modified_times(system : &FakeSystem) -> Vec<(String, SystemTime)>
+    {
+        let mut result : Vec<(String, SystemTime)> = system.list_dir(".ruler/history").unwrap().into_iter().map(
+            |path|
+            {
+                let modified = system.get_modified(&path).unwrap();
+                (path, modified)
+            }
+        ).collect();
+        result.sort();
+        result
+    }
 
-                            WorkOption::Resolutions(resolutions) =>
-                            {
-                                for (i, path) in work_result.blob.get_paths().iter().enumerate()
-                                {
-                                    let (banner_text, banner_color) =
-                                        match resolutions[i]
-                                        {
-                                            FileResolution::Recovered =>
-                                                (" Recovered", Color::Green),
+    /*  Two sinks share an ancestor.  Building everything runs the ancestor's command once, not once
+        per sink.  Building again with nothing changed runs no commands and rewrites no history. */
+    #[test]
+    fn build_shared_ancestor_runs_once()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "leaf.txt", "Leaf\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", DIAMOND_RULES).unwrap();
 
-                                            FileResolution::Downloaded =>
-                                                ("Downloaded", Color::Yellow),
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
 
-                                            FileResolution::AlreadyCorrect =>
-                                                ("Up-to-date", Color::Cyan),
+        let command_log = system.get_command_log_joined();
+        assert_eq!(command_log.iter().filter(|command| command.as_str() == "mycat leaf.txt base.txt").count(), 1);
+        assert_eq!(command_log.len(), 3);
+        assert_eq!(read_file_to_string(&system, "left.txt").unwrap(), "Leaf\n");
+        assert_eq!(read_file_to_string(&system, "right.txt").unwrap(), "Leaf\n");
 
-                                            FileResolution::NeedsRebuild =>
-                                                ("  Outdated", Color::Red),
-                                        };
+        let history_times = get_history_modified_times(&system);
+        assert_eq!(history_times.len(), 3);
 
-                                    printer.print_single_banner_line(banner_text, banner_color, &path);
-                                }
-                            },
+        system.time_passes(5);
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
 
-                            WorkOption::CommandExecuted(output) =>
-                            {
-                                for path in work_result.blob.get_paths().iter()
-                                {
-                                    printer.print_single_banner_line("     Built", Color::Magenta, &path);
-                                }
+        assert_eq!(system.get_command_log_joined().len(), 3);
+        assert_eq!(get_history_modified_times(&system), history_times);
+    }
 
-                                if output.out != ""
-                                {
-                                    printer.print(&output.out);
-                                }
+    /*  Same diamond, but one sink's command fails.  The shared ancestor still runs once and gets its
+        history recorded, so building again runs only the failing command. */
+    #[test]
+    fn build_shared_ancestor_with_failing_sink()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "leaf.txt", "Leaf\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", &DIAMOND_RULES.replace("mycat\nbase.txt\nright.txt", "error")).unwrap();
 
-                                if output.err != ""
-                                {
-                                    printer.error(&output.err);
-                                }
+        match build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test())
+        {
+            Err(BuildError::WorkErrors(work_errors)) => assert_eq!(work_errors.len(), 1),
+            _ => panic!("Expected the failing sink to make the build fail"),
+        }
 
-                                if !output.success
-                                {
-                                    printer.error(
-                                        &format!("RESULT: {}",
-                                            match output.code
-                                            {
-                                                Some(code) => format!("{}", code),
-                                                None => "None".to_string(),
-                                            }
-                                        )
-                                    );
-                                }
+        let mut command_log = system.get_command_log_joined();
+        command_log.sort();
+        assert_eq!(command_log, vec!["error", "mycat base.txt left.txt", "mycat leaf.txt base.txt"]);
+        assert_eq!(get_history_modified_times(&system).len(), 2);
 
-                            },
-                        }
+        system.time_passes(5);
+        match build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test())
+        {
+            Err(BuildError::WorkErrors(work_errors)) => assert_eq!(work_errors.len(), 1),
+            _ => panic!("Expected the failing sink to make the build fail again"),
+        }
 
-                        match node_ticket
-                        {
-                            Some(ticket) =>
-                            {
-                                match work_result.rule_history
-                                {
-                                    Some(history) =>
-                                    {
-                                        match elements.history.write_rule_history(ticket, history)
-                                        {
-                                            Ok(()) => {},
-                                            Err(error) => panic!("Fatal Error: {}", error),
-                                        }
-                                    },
-                                    None => {},
-                                }
-                            }
-                            None => {},
-                        }
+        let command_log = system.get_command_log_joined();
+        assert_eq!(command_log.len(), 4);
+        assert_eq!(command_log[3], "error");
+    }
+
+    /*  A rule expects a poem of at least 100 bytes, and its command writes 15.  The build fails
+        saying so, and the poem doesn't get recorded: there's no history, and building again runs the
+        command again. */
+    #[test]
+    fn build_expect_size_too_small_fails()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules",
+            "@expect poem.txt size >= 100\npoem.txt\n:\nverse1.txt\n:\nmycat\nverse1.txt\npoem.txt\n:\n").unwrap();
 
-                        elements.current_file_states.insert_blob(work_result.blob);
+        match build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test())
+        {
+            Err(BuildError::WorkErrors(work_errors)) =>
+            {
+                assert_eq!(work_errors.len(), 1);
+                match &work_errors[0].1
+                {
+                    WorkError::OutputConstraintViolated(constraint, size) =>
+                    {
+                        assert_eq!(format!("{}", constraint), "poem.txt size >= 100");
+                        assert_eq!(*size, 15);
                     },
-                    Err(BuildError::WorkError(work_error)) => work_errors.push(work_error),
-                    Err(BuildError::Canceled) => {},
-                    Err(error) => panic!("Unexpected build error: {}", error),
+                    error => panic!("Unexpected error: {}", error),
                 }
             },
-            Err(_error) => return Err(BuildError::Weird),
+            _ => panic!("Expected the poem to fail its expectation"),
+        }
+
+        assert_eq!(system.list_dir(".ruler/history").unwrap().len(), 0);
+
+        system.time_passes(1);
+        match build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test())
+        {
+            Err(BuildError::WorkErrors(_)) => {},
+            _ => panic!("Expected the poem to fail its expectation again"),
         }
+        assert_eq!(system.get_command_log_joined().len(), 2);
+    }
+
+    /*  A poem that meets its expectation gets recorded like any other.  After the verse changes and
+        changes back, the old poem comes back from the cache without its command running, and without
+        getting checked again, since it passed when it was built. */
+    #[test]
+    fn build_expect_size_met_records_normally()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules",
+            "@expect poem.txt size >= 10\npoem.txt\n:\nverse1.txt\n:\nmycat\nverse1.txt\npoem.txt\n:\n").unwrap();
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\n");
+        assert_eq!(system.list_dir(".ruler/history").unwrap().len(), 1);
+
+        system.time_passes(1);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are violet.\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+        system.time_passes(1);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\n");
+        assert_eq!(system.get_command_log_joined().len(), 2);
+    }
+
+
+    /*  A source that's executable goes through the leaf's work and a packet to the node that depends
+        on it, and arrives with its executable bit and timestamp intact, along with the sources ticket
+        hashed from its content. */
+    #[test]
+    fn packet_carries_executable_bit_to_dependent()
+    {
+        let mut system = FakeSystem::new(17);
+        write_str_to_file(&mut system, "tool.sh", "echo hello\n").unwrap();
+        system.set_is_executable("tool.sh", true).unwrap();
+
+        let result = handle_source_only_node(system.clone(), Blob::from_paths(vec!["tool.sh".to_string()], |_path| FileState::empty())).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        sender.send(Packet::from_file_state(result.target_file_states[0].clone())).unwrap();
+
+        let (sources_ticket, source_file_states) = wait_for_sources(&system, vec![("tool.sh".to_string(), receiver)], "app",
+            &PacketTracer{sender_opt : None, start : 0}, &WaitingReporter{sender_opt : None}).unwrap();
+        assert_eq!(source_file_states.len(), 1);
+        assert!(source_file_states["tool.sh"].executable);
+        assert_eq!(source_file_states["tool.sh"].timestamp, 17);
+        assert_eq!(source_file_states["tool.sh"].ticket, TicketFactory::from_str("echo hello\n").result());
+
+        let mut factory = TicketFactory::new();
+        factory.input_ticket(TicketFactory::from_str("echo hello\n").result());
+        assert_eq!(sources_ticket, factory.result());
+    }
+
+    /*  A rule that copies an executable source gets the executable bit from the packet the source
+        arrived in, so the copy comes out executable without the source getting looked at again past
+        the leaf's own look. */
+    #[test]
+    fn build_copy_uses_executable_bit_from_packet()
+    {
+        let mut system = FakeSystem::new(17);
+        write_str_to_file(&mut system, "tool.sh", "echo hello\n").unwrap();
+        system.set_is_executable("tool.sh", true).unwrap();
+        write_str_to_file(&mut system, "build.rules",
+            "bin/tool.sh\n:\ntool.sh\n:\n@copy tool.sh bin/tool.sh\n:\n").unwrap();
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+        assert_eq!(read_file_to_string(&system, "bin/tool.sh").unwrap(), "echo hello\n");
+        assert!(system.is_executable("bin/tool.sh").unwrap());
+        assert_eq!(system.get_executable_count("tool.sh"), 1);
+    }
+
+
+    fn make_env_system(rules : &str) -> FakeSystem
+    {
+        let mut system = FakeSystem::new(10);
+        system.set_env_var("PATH", "/usr/bin");
+        system.set_env_var("CFLAGS", "-O3");
+        system.set_env_var("PYTHONPATH", "/home/me/lib");
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+        system
+    }
+
+    fn env(pairs : Vec<(&str, &str)>) -> Vec<(String, String)>
+    {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    /*  Without --hermetic-env a command sees everything.  With it, only PATH, and a rule marked
+        @env-allow gets exactly the variable it names on top of that. */
+    #[test]
+    fn build_hermetic_env()
+    {
+        let rules = "\
+poem.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+poem.txt
+:
+";
+        let system = make_env_system(rules);
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(system.get_environment_log(),
+            vec![env(vec![("CFLAGS", "-O3"), ("PATH", "/usr/bin"), ("PYTHONPATH", "/home/me/lib")])]);
+
+        let system = make_env_system(rules);
+        let mut params = BuildParams::for_test();
+        params.hermetic_env_opt = Some(vec![]);
+        build(system.clone(), &mut EmptyPrinter::new(), None, params).unwrap();
+        assert_eq!(system.get_environment_log(), vec![env(vec![("PATH", "/usr/bin")])]);
+
+        let system = make_env_system(&format!("@env-allow CFLAGS\n{}", rules));
+        let mut params = BuildParams::for_test();
+        params.hermetic_env_opt = Some(vec![]);
+        build(system.clone(), &mut EmptyPrinter::new(), None, params).unwrap();
+        assert_eq!(system.get_environment_log(), vec![env(vec![("CFLAGS", "-O3"), ("PATH", "/usr/bin")])]);
+    }
+
+    /*  A target whose directory doesn't exist yet, nor the directory above that.  The build makes both
+        before running the command, so the command succeeds. */
+    #[test]
+    fn build_creates_target_directories()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "\
+out/sub/poem.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+out/sub/poem.txt
+:
+").unwrap();
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+        assert!(system.is_dir("out"));
+        assert!(system.is_dir("out/sub"));
+        assert_eq!(read_file_to_string(&system, "out/sub/poem.txt").unwrap(), "Roses are red.\n");
+    }
+
+    /*  A comment line in a command section isn't a comment to the parser: it reaches the command
+        as written, for the shell to make sense of. */
+    #[test]
+    fn build_keeps_comments_in_commands()
+    {
+        let mut system = FakeSystem::new(10);
+        system.register_command("record", Box::new(
+            |_system : &mut FakeSystem, _args : &[String]| CommandLineOutput::new()));
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "\
+# comments here get dropped
+poem.txt
+:
+# and here
+verse1.txt
+:
+mycat
+verse1.txt
+poem.txt
+;
+record
+# kept
+:
+").unwrap();
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\n");
+        assert!(system.get_command_log().contains(&vec!["record".to_string(), "# kept".to_string()]));
+    }
+
+    /*  A line starting with # right after the colon that opens a command is the first line of the
+        command, not a comment, so the command run is the one that starts with it.  Building again
+        runs nothing, since the rule's ticket, comment line and all, is as it was. */
+    #[test]
+    fn build_runs_command_starting_with_comment_line()
+    {
+        let mut system = FakeSystem::new(10);
+        system.register_command("#stamp", Box::new(
+            |system : &mut FakeSystem, args : &[String]|
+            {
+                match write_str_to_file(system, &args[0], "stamped\n")
+                {
+                    Ok(()) => CommandLineOutput::new(),
+                    Err(_) => CommandLineOutput::error("#stamp: write failed".to_string()),
+                }
+            }));
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "\
+stamp.txt
+:
+verse1.txt
+:
+#stamp
+stamp.txt
+:
+").unwrap();
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(system.get_command_log_joined(), vec!["#stamp stamp.txt".to_string()]);
+        assert_eq!(read_file_to_string(&system, "stamp.txt").unwrap(), "stamped\n");
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(system.get_command_log_joined().len(), 1);
+    }
+
+    /*  Build the poem, then again two hours later.  Set the clock back to when the poem was built and
+        overwrite it, so it gets the very modified time recorded for it.  Returns the system and whether
+        the next build warned. */
+    fn build_after_clock_went_back(trust_clock : bool) -> (FakeSystem, bool)
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "poem.txt\n:\nverse1.txt\n:\nmycat\nverse1.txt\npoem.txt\n:\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+        system.time_passes(7_200_000_000);
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+        system.time_goes_back(7_200_000_000);
+        write_str_to_file(&mut system, "poem.txt", "Roses are plaid.\n").unwrap();
+
+        let mut params = BuildParams::for_test();
+        params.trust_clock = trust_clock;
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, params).unwrap();
+        let warned = printer.printed.contains(&"   Warning The clock reads 7200 seconds earlier than when the last build started.  \
+            Hashing every file this build instead of trusting modified times (--trust-clock skips this).".to_string());
+        (system, warned)
+    }
+
+    /*  With the clock set back, the overwritten poem has its recorded modified time.  Trusting the clock
+        misses the change.  Otherwise the build warns, hashes the poem and rebuilds it, and the build after
+        that, with the clock moving forward again, doesn't warn. */
+    #[test]
+    fn build_clock_went_back()
+    {
+        let (system, warned) = build_after_clock_went_back(true);
+        assert!(!warned);
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are plaid.\n");
+
+        let (mut system, warned) = build_after_clock_went_back(false);
+        assert!(warned);
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\n");
+
+        system.time_passes(1);
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
+        assert!(!printer.printed.iter().any(|line| line.starts_with("   Warning The clock")));
+    }
+
+    /*  Build a stanza from a verse and a poem from the stanza, then change the stanza's command to
+        one that makes the very same stanza.  Returns the commands the second build ran. */
+    fn build_after_upstream_command_change(command_provenance : bool) -> Vec<String>
+    {
+        let rules = "\
+stanza.txt
+:
+verse.txt
+:
+COPY
+verse.txt
+stanza.txt
+:
+
+poem.txt
+:
+stanza.txt
+:
+mycat
+stanza.txt
+poem.txt
+:
+";
+        let mut system = FakeSystem::new(10);
+        system.register_command("mycopy", Box::new(
+            |system : &mut FakeSystem, args : &[String]|
+            {
+                let content = read_file_to_string(system, &args[0]).unwrap();
+                write_str_to_file(system, &args[1], &content).unwrap();
+                CommandLineOutput::new()
+            }));
+        write_str_to_file(&mut system, "verse.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", &rules.replace("COPY", "mycat")).unwrap();
+
+        let mut params = BuildParams::for_test();
+        params.command_provenance = command_provenance;
+        build(system.clone(), &mut EmptyPrinter::new(), None, params.clone()).unwrap();
+
+        system.time_passes(1);
+        write_str_to_file(&mut system, "build.rules", &rules.replace("COPY", "mycopy")).unwrap();
+        let before = system.get_command_log().len();
+        build(system.clone(), &mut EmptyPrinter::new(), None, params).unwrap();
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\n");
+        system.get_command_log_joined()[before..].to_vec()
     }
 
-    match elements.current_file_states.to_file()
+    /*  Changing the stanza's command reruns the stanza's rule either way.  Since the stanza comes out
+        the same, the poem's rule only reruns with command provenance. */
+    #[test]
+    fn build_command_provenance_ripples_downstream()
     {
-        Ok(_) => {},
-        Err(_) => printer.error("Error writing history"),
+        assert_eq!(build_after_upstream_command_change(false),
+            vec!["mycopy verse.txt stanza.txt"]);
+        assert_eq!(build_after_upstream_command_change(true),
+            vec!["mycopy verse.txt stanza.txt", "mycat stanza.txt poem.txt"]);
     }
 
-    if work_errors.len() == 0
+    /*  A poem made from whatever is in the gen directory, listed before the rule that generates the
+        stanza in there.  Also an unrelated directory of notes.  Built once. */
+    fn make_generated_directory_system() -> FakeSystem
     {
-        Ok(())
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "title.txt", "Roses\n").unwrap();
+        system.create_dir_all("gen").unwrap();
+        system.create_dir_all("notes").unwrap();
+        write_str_to_file(&mut system, "notes/todo.txt", "Rhyme.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "\
+poem.txt\n:\ntitle.txt\ndir:gen/\n:\nmycat\ntitle.txt\npoem.txt\n:\n
+gen/stanza.txt\n:\nverse.txt\n:\nmycat\nverse.txt\ngen/stanza.txt\n:\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        system
     }
-    else
+
+    /*  The rule with a target in the gen directory runs before the one with the directory as a
+        source, even though it comes second in the file. */
+    #[test]
+    fn build_directory_source_orders_generator_first()
     {
-        Err(BuildError::WorkErrors(work_errors))
+        let system = make_generated_directory_system();
+        assert_eq!(system.get_command_log_joined(), vec![
+            "mycat verse.txt gen/stanza.txt",
+            "mycat title.txt poem.txt",
+        ]);
     }
-}
 
-/*  Called when you type "ruler run".  Appeals to build() function to do the build.
-    If there are no errors, executes the target file specified, passing it extra_args. */
-pub fn run
-<
-    SystemType : System + 'static,
-    PrinterType : Printer,
->
-(
-    mut system : SystemType,
-    directory_path : &str,
-    rulefile_paths : Vec<String>,
-    urlfile_path_opt : Option<String>,
-    executable : String,
-    mut extra_args : Vec<String>,
-    printer : &mut PrinterType
-)
--> Result<(), RunError>
-{
-    match build(
-        system.clone(),
-        printer,
-        BuildParams::from_all(
-            directory_path.to_string(),
-            rulefile_paths,
-            urlfile_path_opt,
-            Some(executable.clone()))
-    )
+    /*  A new file in the gen directory changes the poem's sources ticket, so the poem gets rebuilt, and
+        nothing else does. */
+    #[test]
+    fn build_directory_source_new_file_rebuilds()
     {
-        Err(error) => return Err(RunError::BuildError(error)),
-        Ok(()) => {},
-    }
+        let mut system = make_generated_directory_system();
+        system.time_passes(1);
+        write_str_to_file(&mut system, "gen/extra.txt", "Violets are blue.\n").unwrap();
 
-    let mut all = vec![format!("./{}", executable)];
-    all.append(&mut extra_args);
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(system.get_command_log_joined()[2..].to_vec(), vec!["mycat title.txt poem.txt"]);
+    }
 
-    for result in system.execute_command(to_command_script(all))
+    /*  A change in a directory no rule names as a source rebuilds nothing. */
+    #[test]
+    fn build_directory_source_unrelated_change_does_nothing()
     {
-        match result
-        {
-            Ok(_command_line_output) => {},
-            Err(system_error) => return Err(RunError::ExecutionError(system_error)),
-        }
-    }
+        let mut system = make_generated_directory_system();
+        system.time_passes(1);
+        write_str_to_file(&mut system, "notes/todo.txt", "Rhyme better.\n").unwrap();
+        write_str_to_file(&mut system, "notes/done.txt", "Title.\n").unwrap();
 
-    Ok(())
-}
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(system.get_command_log().len(), 2);
+    }
 
-/*  This is the function that runs when you type "ruler clean" at the command-line.
-    It takes a rulefile, parses it and either removes all targets to the cache,
-    or, if goal_target_opt is Some, removes only those targets that are acnestors
-    of goal_target_opt in the depdnece-graph. */
-pub fn clean<SystemType : System + 'static>
-(
-    mut system : SystemType,
-    directory_path : &str,
-    rulefile_paths: Vec<String>,
-    goal_target_opt: Option<String>
-)
--> Result<(), BuildError>
-{
-    let mut elements =
-    match directory::init(&mut system, directory_path)
+    /*  An aggregation rule, with no command, grouping the two stanzas.  Building it builds both, and
+        it's up-to-date without any file of its own.  Building again runs nothing, and when a verse
+        changes, only its stanza gets rebuilt. */
+    #[test]
+    fn build_aggregation_rule_with_empty_command()
     {
-        Ok(elements) => elements,
-        Err(error) =>
-        {
-            return match error
-            {
-                InitDirectoryError::FailedToReadCurrentFileStates(current_file_states_error) =>
-                    Err(BuildError::FailedToReadCurrentFileStates(current_file_states_error)),
-                _ => Err(BuildError::DirectoryMalfunction),
-            }
-        }
-    };
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "\
+stanzas\n:\nstanza1.txt\nstanza2.txt\n:\n:\n
+stanza1.txt\n:\nverse1.txt\n:\nmycat\nverse1.txt\nstanza1.txt\n:\n
+stanza2.txt\n:\nverse2.txt\n:\nmycat\nverse2.txt\nstanza2.txt\n:\n").unwrap();
 
-    let mut node_pack = get_nodes(&mut system, rulefile_paths, goal_target_opt)?;
+        let mut params = BuildParams::for_test();
+        params.goal_target_opt = Some("stanzas".to_string());
 
-    let mut handles = Vec::new();
-    for node in node_pack.nodes.drain(..)
-    {
-        let blob = elements.current_file_states.take_blob(node.targets);
-        let mut system_clone = system.clone();
-        let mut local_cache_clone = elements.cache.clone();
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, params.clone()).unwrap();
+        assert_eq!(system.get_command_log().len(), 2);
+        assert!(!system.is_file("stanzas"));
+        assert!(printer.printed.contains(&"Up-to-date stanzas".to_string()), "{:?}", printer.printed);
 
-        handles.push(
-            thread::spawn(
-                move || -> Result<(), WorkError>
-                {
-                    clean_targets(
-                        blob,
-                        &mut system_clone,
-                        &mut local_cache_clone)
-                }
-            )
-        );
+        build(system.clone(), &mut EmptyPrinter::new(), None, params.clone()).unwrap();
+        assert_eq!(system.get_command_log().len(), 2);
+
+        system.time_passes(1);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are blue.\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, params).unwrap();
+        assert_eq!(system.get_command_log_joined()[2..].to_vec(), vec!["mycat verse1.txt stanza1.txt"]);
     }
 
-    let mut work_errors : Vec<WorkError> = Vec::new();
+    /*  Build the stanzas and poem (or try to, with the given rule for the second stanza) with progress
+        going to a file, and read back the events, one per line. */
+    fn build_with_progress(stanza2_command : &str) -> (Result<(), BuildError>, Vec<serde_json::Value>)
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", &format!("\
+stanza1.txt\n:\nverse1.txt\n:\nmycat\nverse1.txt\nstanza1.txt\n:\n
+stanza2.txt\n:\nverse2.txt\n:\n{}\nverse2.txt\nstanza2.txt\n:\n
+poem.txt\n:\nstanza1.txt\nstanza2.txt\n:\nmycat\nstanza1.txt\nstanza2.txt\npoem.txt\n:\n", stanza2_command)).unwrap();
+
+        let mut params = BuildParams::for_test();
+        params.progress_path_opt = Some("progress.jsonl".to_string());
+        let result = build(system.clone(), &mut EmptyPrinter::new(), None, params);
+        (result, read_file_to_string(&system, "progress.jsonl").unwrap().lines().map(
+            |line| serde_json::from_str(line).unwrap()).collect())
+    }
 
-    for handle in handles
+    /*  A poem build starts by counting its three rules, and each rule starts then gets built, the poem
+        last.  The build finishes saying so, with the summary. */
+    #[test]
+    fn build_progress_events_for_poem()
     {
-        match handle.join()
+        let (result, events) = build_with_progress("mycat");
+        result.unwrap();
+
+        assert_eq!(events.len(), 8);
+        assert_eq!(events[0], serde_json::json!({"event" : "build_started", "total_nodes" : 3}));
+        for target in ["stanza1.txt", "stanza2.txt", "poem.txt"].iter()
         {
-            Err(_error) => return Err(BuildError::Weird),
-            Ok(remove_result_result) =>
-            {
-                match remove_result_result
-                {
-                    Ok(_) => {},
-                    Err(work_error) => work_errors.push(work_error),
-                }
-            }
+            let started = events.iter().position(
+                |event| event["event"] == "node_started" && event["targets"] == serde_json::json!([target])).unwrap();
+            let finished = events.iter().position(
+                |event| event["event"] == "node_finished" && event["targets"] == serde_json::json!([target])).unwrap();
+            assert!(started < finished);
+            assert_eq!(events[finished]["status"], "built");
+            assert!(events[finished]["duration"].is_number());
         }
+        assert_eq!(events[6]["targets"], serde_json::json!(["poem.txt"]));
+        assert_eq!(events[7], serde_json::json!({
+            "event" : "build_finished",
+            "success" : true,
+            "failed" : 0,
+            "summary" : {"built" : 3, "recovered" : 0, "downloaded" : 0, "up_to_date" : 0},
+        }));
     }
 
-    if work_errors.len() == 0
+    /*  When the second stanza's command fails, its rule says so, with the exit code, before the build
+        finishes saying it failed. */
+    #[test]
+    fn build_progress_events_for_failure()
     {
-        Ok(())
+        let (result, events) = build_with_progress("error");
+        assert!(result.is_err());
+
+        let failed = events.iter().position(|event| event["event"] == "node_failed").unwrap();
+        assert_eq!(events[failed]["targets"], serde_json::json!(["stanza2.txt"]));
+        assert_eq!(events[failed]["error_code"], 1);
+        assert_eq!(events.last().unwrap()["event"], "build_finished");
+        assert_eq!(events.last().unwrap()["success"], false);
+        assert_eq!(events.last().unwrap()["failed"], 1);
+        assert!(failed < events.len() - 1);
     }
-    else
+
+    /*  Params for building everything, told which sources changed and how many of the rest to check. */
+    fn make_changed_params(changed : Vec<&str>, changed_verify_sample : usize) -> BuildParams
     {
-        Err(BuildError::WorkErrors(work_errors))
+        let mut params = BuildParams::for_test();
+        params.changed_paths_opt = Some(changed.into_iter().map(|path| path.to_string()).collect());
+        params.changed_verify_sample = changed_verify_sample;
+        params
     }
-}
 
-#[cfg(test)]
-mod test
-{
-    use crate::directory;
-    use crate::build::
+    /*  Two stanzas, each from its own verse, make a poem. */
+    fn make_stanza_system() -> FakeSystem
     {
-        build,
-        BuildParams,
-        BuildError,
-    };
-    use crate::system::
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "\
+stanza1.txt\n:\nverse1.txt\n:\nmycat\nverse1.txt\nstanza1.txt\n:\n
+stanza2.txt\n:\nverse2.txt\n:\nmycat\nverse2.txt\nstanza2.txt\n:\n
+poem.txt\n:\nstanza1.txt\nstanza2.txt\n:\nmycat\nstanza1.txt\nstanza2.txt\npoem.txt\n:\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        system
+    }
+
+    /*  Change the first verse and build saying so.  The second verse never gets opened or even has
+        its modified time looked at, and the first stanza and the poem get rebuilt. */
+    #[test]
+    fn build_changed_list_skips_probing_unlisted_sources()
     {
-        System,
-        fake::FakeSystem
-    };
-    use crate::work::WorkError;
-    use crate::ticket::TicketFactory;
-    use crate::cache::
+        let mut system = make_stanza_system();
+        system.time_passes(1);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are blue.\n").unwrap();
+
+        let reads_before = system.get_read_marker("verse2.txt");
+        let modified_before = system.get_modified_count("verse2.txt");
+        build(system.clone(), &mut EmptyPrinter::new(), None, make_changed_params(vec!["verse1.txt"], 0)).unwrap();
+        assert_eq!(system.get_read_marker("verse2.txt"), reads_before);
+        assert_eq!(system.get_modified_count("verse2.txt"), modified_before);
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are blue.\nViolets are violet.\n");
+        assert_eq!(system.get_command_log().len(), 5);
+    }
+
+    /*  Overwrite the first verse before the clock moves, so it keeps the modified time recorded for
+        it.  Listing only the second verse as changed, the first gets trusted and the change is missed.
+        Listed as changed, the verse gets hashed anyway, and the change goes all the way up to the
+        poem. */
+    #[test]
+    fn build_changed_list_rehashes_listed_source()
     {
-        SysCache,
-        OpenError,
-    };
-    use crate::system::util::
+        let mut system = make_stanza_system();
+        write_str_to_file(&mut system, "verse1.txt", "Roses are plaid.\n").unwrap();
+        system.time_passes(1);
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, make_changed_params(vec!["verse2.txt"], 0)).unwrap();
+        assert_eq!(system.get_command_log().len(), 3);
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\nViolets are violet.\n");
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, make_changed_params(vec!["verse1.txt"], 0)).unwrap();
+        assert_eq!(system.get_command_log().len(), 5);
+        assert_eq!(read_file_to_string(&system, "stanza1.txt").unwrap(), "Roses are plaid.\n");
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are plaid.\nViolets are violet.\n");
+    }
+
+    /*  Change both verses but list only the first.  Checking the one unlisted source catches the
+        second verse, and the build fails without running anything.  With no check, the list gets
+        trusted and the poem comes out half stale. */
+    #[test]
+    fn build_changed_verify_sample_catches_omission()
     {
-        write_str_to_file,
-        read_file_to_string
-    };
-    use crate::printer::EmptyPrinter;
-    use crate::blob::
+        let mut system = make_stanza_system();
+        system.time_passes(1);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are blue.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are indigo.\n").unwrap();
+
+        match build(system.clone(), &mut EmptyPrinter::new(), None, make_changed_params(vec!["verse1.txt"], 1))
+        {
+            Err(BuildError::ChangedListIncomplete(paths)) => assert_eq!(paths, vec!["verse2.txt"]),
+            _ => panic!("Expected the changed list to be caught leaving out verse2.txt"),
+        }
+        assert_eq!(system.get_command_log().len(), 3);
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, make_changed_params(vec!["verse1.txt"], 0)).unwrap();
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are blue.\nViolets are violet.\n");
+    }
+
+    /*  Params for building everything as the given variant. */
+    fn make_variant_params(variant : &str) -> BuildParams
     {
-        Blob,
-        FileState
-    };
-    use std::io::Write;
+        let mut params = BuildParams::for_test();
+        params.variant_opt = Some(variant.to_string());
+        params
+    }
 
-    fn make_default_build_params() -> BuildParams
+    /*  One rule writes to a path of its own for each variant, the other to the same path either way,
+        each with the variant as a flag.  Build debug, then release, then debug again.  The second debug
+        build runs nothing: its own target is where it left it, and the shared one comes back from the
+        cache. */
+    #[test]
+    fn build_variants_switch_back_without_rebuilding()
     {
-        BuildParams
+        let mut system = FakeSystem::new(10);
+        system.register_command("compile", Box::new(
+            |system : &mut FakeSystem, args : &[String]|
+            {
+                let code = read_file_to_string(system, &args[1]).unwrap();
+                match write_str_to_file(system, &args[2], &format!("{} binary of {}", args[0], code))
+                {
+                    Ok(()) => CommandLineOutput::new(),
+                    Err(_) => CommandLineOutput::error("compile: write failed".to_string()),
+                }
+            }));
+        write_str_to_file(&mut system, "main.c", "version 1\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "\
+app.$(VARIANT)
+:
+main.c
+:
+compile
+--$(VARIANT)
+main.c
+app.$(VARIANT)
+:
+
+lib
+:
+main.c
+:
+compile
+--$(VARIANT)
+main.c
+lib
+:
+").unwrap();
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, make_variant_params("debug")).unwrap();
+        assert_eq!(read_file_to_string(&system, "app.debug").unwrap(), "--debug binary of version 1\n");
+        assert_eq!(read_file_to_string(&system, "lib").unwrap(), "--debug binary of version 1\n");
+
+        system.time_passes(1);
+        build(system.clone(), &mut EmptyPrinter::new(), None, make_variant_params("release")).unwrap();
+        assert_eq!(read_file_to_string(&system, "app.release").unwrap(), "--release binary of version 1\n");
+        assert_eq!(read_file_to_string(&system, "lib").unwrap(), "--release binary of version 1\n");
+        assert_eq!(system.get_command_log().len(), 4);
+        assert!(system.is_file(".ruler/current_file_states.debug"));
+        assert!(system.is_file(".ruler/current_file_states.release"));
+        assert!(!system.is_file(".ruler/current_file_states"));
+
+        system.time_passes(1);
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, make_variant_params("debug")).unwrap();
+        assert_eq!(system.get_command_log().len(), 4);
+        assert_eq!(read_file_to_string(&system, "app.debug").unwrap(), "--debug binary of version 1\n");
+        assert_eq!(read_file_to_string(&system, "lib").unwrap(), "--debug binary of version 1\n");
+        assert!(printer.printed.iter().any(|line| line.starts_with("Build succeeded: 0 built, 1 recovered, 0 downloaded, 1 up-to-date")));
+    }
+
+    /*  Build a precious target, break its source so the command fails, and check the old target is
+        still in place.  Then fix the source, rebuild, and check the history records the new target. */
+    #[test]
+    fn build_precious_target_survives_failure()
+    {
+        let mut system = FakeSystem::new(10);
+        system.register_command("compile", Box::new(
+            |system : &mut FakeSystem, args : &[String]|
+            {
+                let code = read_file_to_string(system, &args[0]).unwrap();
+                if code.contains("oops")
+                {
+                    return CommandLineOutput::error("compile: syntax error".to_string());
+                }
+
+                match write_str_to_file(system, &args[1], &format!("binary of {}", code))
+                {
+                    Ok(()) => CommandLineOutput::new(),
+                    Err(_) => CommandLineOutput::error("compile: write failed".to_string()),
+                }
+            }));
+        write_str_to_file(&mut system, "main.c", "version 1\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "@precious\napp\n:\nmain.c\n:\ncompile\nmain.c\napp\n:\n").unwrap();
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(read_file_to_string(&system, "app").unwrap(), "binary of version 1\n");
+
+        system.time_passes(1);
+        write_str_to_file(&mut system, "main.c", "oops\n").unwrap();
+        match build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test())
         {
-            directory_path : ".ruler".to_string(),
-            rulefile_paths : vec!["build.rules".to_string()],
-            urlfile_path_opt : None,
-            goal_target_opt : Some("poem.txt".to_string()),
+            Ok(_) => panic!("Unexpected success with a broken source"),
+            Err(BuildError::WorkErrors(_)) => {},
+            Err(error) => panic!("Got error but not the correct error: {}", error),
         }
+        assert_eq!(read_file_to_string(&system, "app").unwrap(), "binary of version 1\n");
+
+        system.time_passes(1);
+        write_str_to_file(&mut system, "main.c", "version 2\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(read_file_to_string(&system, "app").unwrap(), "binary of version 2\n");
+
+        let rule_ticket = get_nodes(&system, vec!["build.rules".to_string()], None, false, false, None).unwrap().nodes[0].rule_ticket.clone();
+        let mut factory = TicketFactory::new();
+        factory.input_ticket(TicketFactory::from_str("version 2\n").result());
+        let elements = directory::open_readonly(&system, ".ruler", None, None).unwrap();
+        let rule_history = elements.history.read_rule_history(&rule_ticket).unwrap();
+        assert_eq!(rule_history.get_file_state_vec(&factory.result()).unwrap().get_tickets(),
+            vec![TicketFactory::from_str("binary of version 2\n").result()]);
     }
 
-    /*  Set up a filesystem and a .rules file with one poem depending on two verses
-        as source. Populate the verses with lines of the target poem.  Run the build
-        command and check that the file appears and has the correct contents. */
+    /*  With output_on_failure, a successful command's stdout goes unprinted, but a failing
+        command's stdout and stderr both get printed, along with its exit code. */
     #[test]
-    fn build_basic()
+    fn build_output_on_failure()
     {
-        let rules = "\
+        let mut system = FakeSystem::new(10);
+        system.register_command("chatty", Box::new(
+            |system : &mut FakeSystem, args : &[String]|
+            {
+                match write_str_to_file(system, &args[0], "done\n")
+                {
+                    Ok(()) => CommandLineOutput::from_bytes(b"chatty says hi\n".to_vec(), vec![], Some(0), true),
+                    Err(_) => CommandLineOutput::error("chatty: write failed".to_string()),
+                }
+            }));
+        system.register_command("grumpy", Box::new(
+            |_system : &mut FakeSystem, _args : &[String]|
+            {
+                CommandLineOutput::from_bytes(b"grumpy got this far\n".to_vec(), b"grumpy gave up\n".to_vec(), Some(2), false)
+            }));
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "\
 poem.txt
 :
 verse1.txt
-verse2.txt
 :
-mycat
-verse1.txt
-verse2.txt
+chatty
 poem.txt
 :
-";
-        let mut system = FakeSystem::new(10);
-
-        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
-        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
-        write_str_to_file(&mut system, "build.rules", rules).unwrap();
 
+limerick.txt
+:
+verse1.txt
+:
+grumpy
+limerick.txt
+:
+").unwrap();
 
+        let mut params = BuildParams::for_test();
+        params.output_on_failure = true;
 
-        build(
-            system.clone(),
-            &mut EmptyPrinter::new(),
-            make_default_build_params()
-        ).unwrap();
+        let mut printer = RecordingPrinter::new();
+        match build(system.clone(), &mut printer, None, params)
+        {
+            Err(BuildError::WorkErrors(_)) => {},
+            _ => panic!("Expected grumpy to fail the build"),
+        }
 
-        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(), "Roses are red.\nViolets are violet.\n");
+        assert!(!printer.printed.contains(&"chatty says hi\n".to_string()));
+        assert!(printer.printed.contains(&"grumpy got this far\n".to_string()));
+        assert!(printer.errors.contains(&"grumpy gave up\n".to_string()));
+        assert!(printer.errors.contains(&"RESULT: 2".to_string()));
     }
 
-    /*  Set up a filesystem and a .rules file with one poem depending on two verses
-        as source. Populate the verses with lines of the target poem, except, omit one
-        of the source files.  Run the build command and check that it errors sensibly. */
+    /*  A command prints a progress bar with a byte that isn't UTF-8.  The build prints what it can of
+        it, followed by a note saying bytes were replaced. */
     #[test]
-    fn build_one_source_file_missing()
+    fn build_prints_note_for_output_not_utf8()
     {
-        let rules = "\
+        let mut system = FakeSystem::new(10);
+        system.register_command("progress", Box::new(
+            |system : &mut FakeSystem, args : &[String]|
+            {
+                match write_str_to_file(system, &args[0], "done\n")
+                {
+                    Ok(()) => CommandLineOutput::from_bytes(b"[==\xff==]".to_vec(), vec![], Some(0), true),
+                    Err(_) => CommandLineOutput::error("progress: write failed".to_string()),
+                }
+            }));
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "\
 poem.txt
 :
 verse1.txt
-verse2.txt
 :
-mycat
-verse1.txt
-verse2.txt
+progress
 poem.txt
 :
-";
-        let mut system = FakeSystem::new(10);
+").unwrap();
 
-        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
-        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
 
-        match build(
-            system.clone(),
-            &mut EmptyPrinter::new(),
-            make_default_build_params())
-        {
-            Ok(_) => panic!("unexpected success"),
-            Err(BuildError::WorkErrors(errors)) =>
+        let position = printer.printed.iter().position(|line| line == "[==\u{FFFD}==]").unwrap();
+        assert_eq!(printer.printed[position + 1], NOT_UTF8_NOTE);
+    }
+
+    /*  A command prints a 0xFF byte and writes one at the start of its target.  Cleaning moves the
+        target into the cache, and the next build restores it from there, without running the command,
+        with the 0xFF byte as written.  What the command prints isn't stored anywhere; the target is
+        what persists. */
+    #[test]
+    fn build_keeps_bytes_not_utf8_through_cache()
+    {
+        let mut system = FakeSystem::new(10);
+        system.register_command("stamp", Box::new(
+            |system : &mut FakeSystem, args : &[String]|
             {
-                assert_eq!(errors.len(), 1);
-                match &errors[0]
+                let mut content = b"\xff".to_vec();
+                content.extend(read_file_to_string(system, &args[0]).unwrap().into_bytes());
+                match system.create_file(&args[1])
                 {
-                    WorkError::FileNotFound(path_str) => assert_eq!(path_str, "verse2.txt"),
-                    _ => panic!("Got work error but not the correct error: {}", errors[0]),
+                    Ok(mut file) =>
+                    {
+                        file.write_all(&content).unwrap();
+                        CommandLineOutput::from_bytes(b"[==\xff==]".to_vec(), vec![], Some(0), true)
+                    },
+                    Err(_) => CommandLineOutput::error("stamp: write failed".to_string()),
                 }
-            },
-            Err(error) => panic!("Got error but not the correct error: {}", error),
-        }
+            }));
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "poem.txt\n:\nverse1.txt\n:\nstamp\nverse1.txt\npoem.txt\n:\n").unwrap();
+
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
+        assert!(printer.printed.contains(&NOT_UTF8_NOTE.to_string()));
+        assert_eq!(read_file(&system, "poem.txt").unwrap(), b"\xffRoses are red.\n".to_vec());
+
+        clean(system.clone(), &mut EmptyPrinter::new(), BuildParams::for_test()).unwrap();
+        assert!(!system.is_file("poem.txt"));
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(read_file(&system, "poem.txt").unwrap(), b"\xffRoses are red.\n".to_vec());
+        assert_eq!(system.get_command_log().len(), 1);
     }
 
-    #[test]
-    fn build_one_dependence()
-    {
-        let rules = "\
+    const GLOBAL_SOURCE_RULES : &str = "\
+@global-source config.txt
+
 stanza1.txt
 :
 verse1.txt
@@ -1020,39 +5820,112 @@ verse1.txt
 stanza1.txt
 :
 
-poem.txt
+stanza2.txt
 :
-stanza1.txt
+verse2.txt
 :
 mycat
-stanza1.txt
-poem.txt
+verse2.txt
+stanza2.txt
 :
 ";
+
+    fn make_global_source_system() -> FakeSystem
+    {
         let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "config.txt", "debug\n").unwrap();
+        write_str_to_file(&mut system, "notes.txt", "nothing to see\n").unwrap();
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", GLOBAL_SOURCE_RULES).unwrap();
+        system
+    }
 
-        write_str_to_file(&mut system, "verse1.txt", "I looked over Jordan, and what did I see?\n").unwrap();
-        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+    /*  Editing the global source rebuilds every rule, though no rule lists it. */
+    #[test]
+    fn build_global_source_edit_rebuilds_all()
+    {
+        let mut system = make_global_source_system();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(system.get_command_log().len(), 2);
 
-        
+        system.time_passes(1);
+        write_str_to_file(&mut system, "config.txt", "release\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(system.get_command_log().len(), 4);
+    }
 
-        match build(
-            system.clone(),
-            &mut EmptyPrinter::new(),
-            make_default_build_params()
-        )
+    /*  Editing a file that's neither a source nor a global source rebuilds nothing. */
+    #[test]
+    fn build_global_source_unrelated_edit()
+    {
+        let mut system = make_global_source_system();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(system.get_command_log().len(), 2);
+
+        system.time_passes(1);
+        write_str_to_file(&mut system, "notes.txt", "still nothing\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(system.get_command_log().len(), 2);
+    }
+
+    /*  A missing global source gets reported like any other missing source. */
+    #[test]
+    fn build_global_source_missing()
+    {
+        let mut system = make_global_source_system();
+        system.remove_file("config.txt").unwrap();
+        match build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test())
         {
-            Ok(_) =>
+            Err(BuildError::WorkErrors(work_errors)) =>
             {
-                assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(),
-                    "I looked over Jordan, and what did I see?\n");
+                assert!(work_errors.iter().any(
+                    |(_provenance, error)| match error
+                    {
+                        WorkError::FileNotFound(path) => path == "config.txt",
+                        _ => false,
+                    }));
             },
-            Err(error) => panic!("Unexpected error: {}", error),
+            _ => panic!("Expected the missing global source to be reported"),
         }
     }
 
+    /*  Build a tree of twenty sources feeding ten rules feeding one, with a recording printer.  The
+        heartbeat shows while the current file states load and the sources get checked, counting them,
+        and is cleared by the end.  None of it lands among the printed lines. */
     #[test]
-    fn build_one_dependence_with_intermediate_already_present()
+    fn build_heartbeat_while_checking_sources()
+    {
+        let mut system = FakeSystem::new(11);
+        let mut rules = String::new();
+        let mut parts = vec![];
+        for i in 0..10
+        {
+            write_str_to_file(&mut system, &format!("line{}a.txt", i), &format!("line {} a\n", i)).unwrap();
+            write_str_to_file(&mut system, &format!("line{}b.txt", i), &format!("line {} b\n", i)).unwrap();
+            rules.push_str(&format!("part{}.txt\n:\nline{}a.txt\nline{}b.txt\n:\nmycat\nline{}a.txt\nline{}b.txt\npart{}.txt\n:\n\n",
+                i, i, i, i, i, i));
+            parts.push(format!("part{}.txt", i));
+        }
+        rules.push_str(&format!("poem.txt\n:\n{}\n:\nmycat\n{}\npoem.txt\n:\n", parts.join("\n"), parts.join("\n")));
+        write_str_to_file(&mut system, "build.rules", &rules).unwrap();
+
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
+
+        assert_eq!(printer.heartbeats.first().unwrap(), "reading current file states");
+        assert!(printer.heartbeats.contains(&"checking source files: 0 of 20".to_string()));
+        assert!(!printer.heartbeat_showing);
+        assert_eq!(printer.printed.len(), 12);
+        assert!(printer.printed[..11].iter().all(|line| line.starts_with("     Built")));
+        assert_eq!(printer.printed[11], "Build succeeded: 11 built, 0 recovered, 0 downloaded, 0 up-to-date in 0.00 seconds");
+    }
+
+    /*  Build a poem from two stanzas, then again with nothing changed, then after editing one verse,
+        then quietly after changing it back.  Each build ends with one summary line counting what
+        happened, the last-build file records it, and quiet leaves only that line. */
+    #[test]
+    fn build_prints_summary()
     {
         let rules = "\
 stanza1.txt
@@ -1074,387 +5947,804 @@ poem.txt
 :
 ";
         let mut system = FakeSystem::new(10);
-
         write_str_to_file(&mut system, "verse1.txt", "I looked over Jordan, and what did I see?\n").unwrap();
-        write_str_to_file(&mut system, "stanza1.txt", "Some wrong content\n").unwrap();
         write_str_to_file(&mut system, "build.rules", rules).unwrap();
 
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
+        assert_eq!(printer.printed.last().unwrap(),
+            "Build succeeded: 2 built, 0 recovered, 0 downloaded, 0 up-to-date in 0.00 seconds");
+        assert_eq!(read_file_to_string(&system, ".ruler/last-build").unwrap(),
+            "built\nBuild succeeded: 2 built, 0 recovered, 0 downloaded, 0 up-to-date in 0.00 seconds\n");
 
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
+        assert_eq!(printer.printed.last().unwrap(), "Nothing to do: 2 targets up to date");
+        assert_eq!(read_file_to_string(&system, ".ruler/last-build").unwrap(),
+            "nothing-to-do\nNothing to do: 2 targets up to date\n");
 
-        match build(
-            system.clone(),
-            &mut EmptyPrinter::new(),
-            make_default_build_params())
-        {
-            Ok(_) =>
-            {
-                assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(),
-                    "I looked over Jordan, and what did I see?\n");
-            },
-            Err(error) => panic!("Unexpected error: {}", error),
-        }
+        system.time_passes(1);
+        write_str_to_file(&mut system, "verse1.txt", "A band of angels coming after me\n").unwrap();
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
+        assert_eq!(printer.printed.last().unwrap(),
+            "Build succeeded: 2 built, 0 recovered, 0 downloaded, 0 up-to-date in 0.00 seconds");
+
+        system.time_passes(1);
+        write_str_to_file(&mut system, "verse1.txt", "I looked over Jordan, and what did I see?\n").unwrap();
+        let mut params = BuildParams::for_test();
+        params.quiet = true;
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, params).unwrap();
+        assert_eq!(printer.printed,
+            vec!["Build succeeded: 1 built, 1 recovered, 0 downloaded, 0 up-to-date in 0.00 seconds".to_string()]);
     }
 
-    /*  Rules for a poem with two verses and a refrain.  Try building the poem three times, once with each source file omitted.
-        Check that the error matches the missing file. */
+    /*  Build a no-cache rule that stamps a counter into its target next to an ordinary rule, three
+        times over.  The no-cache rule runs every time, and nothing about it lands in the history,
+        while the ordinary rule stays up to date after the first build. */
     #[test]
-    fn build_poem_with_various_omitted_sources()
+    fn build_no_cache_rule_always_rebuilds()
     {
-        let rules = "\
-stanza1.txt
-:
-verse1.txt
-refrain.txt
-:
-mycat
-verse1.txt
-refrain.txt
-stanza1.txt
-:
-
-stanza2.txt
+        let mut system = FakeSystem::new(10);
+        let count = Arc::new(AtomicUsize::new(0));
+        let count_clone = count.clone();
+        system.register_command("stamp", Box::new(
+            move |system : &mut FakeSystem, args : &[String]|
+            {
+                let n = count_clone.fetch_add(1, Ordering::SeqCst) + 1;
+                let version = read_file_to_string(system, &args[0]).unwrap();
+                match write_str_to_file(system, &args[1], &format!("{}build {}\n", version, n))
+                {
+                    Ok(()) => CommandLineOutput::new(),
+                    Err(_) => CommandLineOutput::error("stamp: write failed".to_string()),
+                }
+            }));
+        write_str_to_file(&mut system, "version.txt", "version 1\n").unwrap();
+        write_str_to_file(&mut system, "verse.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "\
+@no-cache
+stamp.txt
 :
-verse2.txt
-refrain.txt
+version.txt
 :
-mycat
-verse2.txt
-refrain.txt
-stanza2.txt
+stamp
+version.txt
+stamp.txt
 :
 
 poem.txt
 :
-stanza1.txt
-stanza2.txt
+verse.txt
 :
 mycat
-stanza1.txt
-stanza2.txt
+verse.txt
 poem.txt
 :
-";
-        for omit_me in ["verse1.txt", "verse2.txt", "refrain.txt"]
+").unwrap();
+
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
+        assert_eq!(printer.printed.last().unwrap(),
+            "Build succeeded: 2 built, 0 recovered, 0 downloaded, 0 up-to-date in 0.00 seconds");
+        assert_eq!(read_file_to_string(&system, "stamp.txt").unwrap(), "version 1\nbuild 1\n");
+
+        for n in 2..4
         {
-            let mut system = FakeSystem::new(10);
+            let mut printer = RecordingPrinter::new();
+            build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
+            assert_eq!(printer.printed.last().unwrap(),
+                "Build succeeded: 1 built, 0 recovered, 0 downloaded, 1 up-to-date in 0.00 seconds");
+            assert_eq!(read_file_to_string(&system, "stamp.txt").unwrap(), format!("version 1\nbuild {}\n", n));
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 3);
 
-            if omit_me != "verse1.txt"
-            {
-                write_str_to_file(&mut system, "verse1.txt", "I looked over Jordan, and what did I see?\n").unwrap();
-            }
+        let nodes = get_nodes(&system, vec!["build.rules".to_string()], None, false, false, None).unwrap().nodes;
+        let elements = directory::open_readonly(&system, ".ruler", None, None).unwrap();
+        for node in nodes.iter().filter(|node| node.command.len() > 0)
+        {
+            let rule_history = elements.history.read_rule_history(&node.rule_ticket).unwrap();
+            let mut factory = TicketFactory::new();
+            factory.input_ticket(TicketFactory::from_str(
+                if node.no_cache { "version 1\n" } else { "Roses are red.\n" }).result());
+            assert_eq!(rule_history.get_file_state_vec(&factory.result()).is_some(), !node.no_cache);
+        }
+    }
 
-            if omit_me != "verse2.txt"
+    /*  Two ordinary rules whose commands wait for each other, a third that waits for both of those to
+        start, and a serial rule depending on the third.  The serial rule's command can only start
+        while the first two are running, so the gate is all that keeps them apart.  The log shows the
+        first two overlapping and the serial command running alone. */
+    #[test]
+    fn build_serial_rule_runs_alone()
+    {
+        let mut system = FakeSystem::new(10);
+        let log = Arc::new(Mutex::new(Vec::<String>::new()));
+        let pair_started = Arc::new(AtomicUsize::new(0));
+        let log_clone = log.clone();
+        system.register_command("work", Box::new(
+            move |system : &mut FakeSystem, args : &[String]|
             {
-                write_str_to_file(&mut system, "verse2.txt", "A band of angels comin' after me\n").unwrap();
-            }
+                let name = args[0].as_str();
+                log_clone.lock().unwrap().push(format!("start {}", name));
+                let wait_for_pair = |count : usize|
+                {
+                    let deadline = Instant::now() + Duration::from_secs(5);
+                    while pair_started.load(Ordering::SeqCst) < count && Instant::now() < deadline
+                    {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                };
 
-            if omit_me != "refrain.txt"
-            {
-                write_str_to_file(&mut system, "refrain.txt", "Comin' for to carry me home\n").unwrap();
-            }
+                match name
+                {
+                    "a" | "b" =>
+                    {
+                        pair_started.fetch_add(1, Ordering::SeqCst);
+                        wait_for_pair(2);
+                        thread::sleep(Duration::from_millis(50));
+                    },
+                    "x" => wait_for_pair(2),
+                    _ => {},
+                }
 
-            write_str_to_file(&mut system, "build.rules", rules).unwrap();
+                log_clone.lock().unwrap().push(format!("end {}", name));
+                match write_str_to_file(system, &args[1], &format!("{}\n", name))
+                {
+                    Ok(()) => CommandLineOutput::new(),
+                    Err(_) => CommandLineOutput::error("work: write failed".to_string()),
+                }
+            }));
 
-            match build(
-                system.clone(),
-                &mut EmptyPrinter::new(),
-                make_default_build_params())
+        let mut rules = String::new();
+        for name in ["a", "b", "x"]
+        {
+            write_str_to_file(&mut system, &format!("{}.in", name), name).unwrap();
+            rules.push_str(&format!("{}.txt\n:\n{}.in\n:\nwork\n{}\n{}.txt\n:\n\n", name, name, name, name));
+        }
+        rules.push_str("@serial\ns.txt\n:\nx.txt\n:\nwork\ns\ns.txt\n:\n");
+        write_str_to_file(&mut system, "build.rules", &rules).unwrap();
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+        let log = log.lock().unwrap().clone();
+        let position = |event : &str| log.iter().position(|entry| entry == event).unwrap();
+        assert_eq!(log.len(), 8);
+        assert!(position("start a") < position("end b"));
+        assert!(position("start b") < position("end a"));
+        assert_eq!(position("end s"), position("start s") + 1);
+        assert!(position("start s") > position("end a"));
+        assert!(position("start s") > position("end b"));
+    }
+
+    /*  Two independent rules in the same group, and a rule outside it which waits for a group command
+        to start, as the group's commands wait for it.  The log shows the group's commands one after the
+        other, and the outsider running alongside the first of them. */
+    #[test]
+    fn build_group_rules_never_overlap()
+    {
+        let mut system = FakeSystem::new(10);
+        let log = Arc::new(Mutex::new(Vec::<String>::new()));
+        let group_started = Arc::new(AtomicUsize::new(0));
+        let free_started = Arc::new(AtomicUsize::new(0));
+        let log_clone = log.clone();
+        system.register_command("work", Box::new(
+            move |system : &mut FakeSystem, args : &[String]|
             {
-                Ok(_) => panic!("unexpected success"),
-                Err(BuildError::WorkErrors(errors)) =>
+                let name = args[0].as_str();
+                log_clone.lock().unwrap().push(format!("start {}", name));
+                let wait_for = |counter : &AtomicUsize|
                 {
-                    assert_eq!(errors.len(), 1);
-                    match &errors[0]
+                    let deadline = Instant::now() + Duration::from_secs(5);
+                    while counter.load(Ordering::SeqCst) < 1 && Instant::now() < deadline
                     {
-                        WorkError::FileNotFound(path_str) => assert_eq!(path_str, omit_me),
-                        _ => panic!("When omitting {}, Got work error but not the correct error: {}", omit_me, errors[0]),
+                        thread::sleep(Duration::from_millis(1));
                     }
-                },
-                Err(error) => panic!("When omitting {}, Got error but not the correct error: {}", omit_me, error),
-            }
+                };
+
+                match name
+                {
+                    "f" =>
+                    {
+                        free_started.fetch_add(1, Ordering::SeqCst);
+                        wait_for(&group_started);
+                    },
+                    _ =>
+                    {
+                        group_started.fetch_add(1, Ordering::SeqCst);
+                        wait_for(&free_started);
+                    },
+                }
+                thread::sleep(Duration::from_millis(50));
+
+                log_clone.lock().unwrap().push(format!("end {}", name));
+                match write_str_to_file(system, &args[1], &format!("{}\n", name))
+                {
+                    Ok(()) => CommandLineOutput::new(),
+                    Err(_) => CommandLineOutput::error("work: write failed".to_string()),
+                }
+            }));
+
+        let mut rules = String::new();
+        for (name, directive) in [("g1", "@group gpu\n"), ("g2", "@group gpu\n"), ("f", "")]
+        {
+            write_str_to_file(&mut system, &format!("{}.in", name), name).unwrap();
+            rules.push_str(&format!("{}{}.txt\n:\n{}.in\n:\nwork\n{}\n{}.txt\n:\n\n", directive, name, name, name, name));
         }
+        write_str_to_file(&mut system, "build.rules", &rules).unwrap();
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+        let log = log.lock().unwrap().clone();
+        let position = |event : &str| log.iter().position(|entry| entry == event).unwrap();
+        assert_eq!(log.len(), 6);
+        let (first, second) = if position("start g1") < position("start g2") { ("g1", "g2") } else { ("g2", "g1") };
+        assert!(position(&format!("end {}", first)) < position(&format!("start {}", second)));
+        assert!(position("start f") < position(&format!("end {}", first)));
+        assert!(position(&format!("start {}", first)) < position("end f"));
     }
 
-    /*  Set up a filesystem and a .rules file with invalid UTF8 in it instead of rules.
-        Check that the build fails with a message about UTF8 */
+    /*  Six independent rules in a group allowed two jobs at once, with eight jobs allowed overall.
+        Counting the group's commands running at any moment, the count reaches two and never more. */
     #[test]
-    fn build_rulefile_not_utf8()
+    fn build_group_jobs_limit_overlap()
     {
-        let mut system = FakeSystem::new(11);
+        let mut system = FakeSystem::new(10);
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+        let running_clone = running.clone();
+        let most_running_clone = most_running.clone();
+        system.register_command("link", Box::new(
+            move |system : &mut FakeSystem, args : &[String]|
+            {
+                let now_running = running_clone.fetch_add(1, Ordering::SeqCst) + 1;
+                most_running_clone.fetch_max(now_running, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(50));
+                running_clone.fetch_sub(1, Ordering::SeqCst);
 
-        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
-        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
-        system.create_file("build.rules").unwrap().write_all(&[0x80u8]).unwrap();
+                match write_str_to_file(system, &args[1], &format!("{}\n", args[0]))
+                {
+                    Ok(()) => CommandLineOutput::new(),
+                    Err(_) => CommandLineOutput::error("link: write failed".to_string()),
+                }
+            }));
 
-        match build(
-            system.clone(),
-            &mut EmptyPrinter::new(),
-            make_default_build_params())
+        let mut rules = String::new();
+        for index in 0..6
         {
-            Ok(_) => panic!("Unexpected success with invalid rules file."),
-            Err(BuildError::RuleFileNotUTF8) => {},
-            Err(error) => panic!("Got error but not the correct error: {}", error),
+            write_str_to_file(&mut system, &format!("{}.o", index), "object").unwrap();
+            rules.push_str(&format!("@group linker\n{}.bin\n:\n{}.o\n:\nlink\n{}\n{}.bin\n:\n\n", index, index, index, index));
         }
+        write_str_to_file(&mut system, "build.rules", &rules).unwrap();
+
+        let mut params = BuildParams::for_test();
+        params.jobs_opt = Some(8);
+        params.group_jobs = vec![("linker".to_string(), 2)];
+        build(system.clone(), &mut EmptyPrinter::new(), None, params).unwrap();
+
+        assert_eq!(system.get_command_log().len(), 6);
+        assert_eq!(most_running.load(Ordering::SeqCst), 2);
     }
 
-    /*  Set up a filesystem and a .rules file with one real dependence missing
-        from the rules.  Build once, make sure it goes as planned, then change
-        the contents of the omitted source file.  Check that Building again produces
-        a particular error: a contradiction. */
+    /*  Build a poem from two stanzas from three verses, tracing packets.  Each of the five source edges
+        carries one packet, traced once sent and once received, naming both ends and the short ticket. */
     #[test]
-    fn build_with_missing_source()
+    fn build_trace_packets()
     {
-        let rules = "\
-poem.txt
-:
-verse1.txt
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "verse3.txt", "Sugar is sweet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "\
+stanza1.txt
 :
-mycat
 verse1.txt
 verse2.txt
-poem.txt
 :
-";
-        let mut system = FakeSystem::new(10);
-
-        system.create_dir(".ruler-cache").unwrap();
-        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
-        write_str_to_file(&mut system, "verse2.txt", "Violets are blue.\n").unwrap();
-        write_str_to_file(&mut system, "build.rules", rules).unwrap();
-
-        build(
-            system.clone(),
-            &mut EmptyPrinter::new(),
-            make_default_build_params()
-        ).unwrap();
-
-        system.time_passes(1);
-
-        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(), "Roses are red.\nViolets are blue.\n");
-        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
-        write_str_to_file(&mut system, "poem.txt", "Wrong content forcing a rebuild").unwrap();
-
-        match build(
-            system.clone(),
-            &mut EmptyPrinter::new(),
-            make_default_build_params())
-        {
-            Ok(()) => panic!("Unexpected silence when contradiction should arise"),
-            Err(error) =>
-            {
-                match error
-                {
-                    BuildError::WorkErrors(work_errors) =>
-                    {
-                        assert_eq!(work_errors.len(), 1);
-                        match &work_errors[0]
-                        {
-                            WorkError::Contradiction(paths) => assert_eq!(paths, &vec!["poem.txt".to_string()]),
-                            _ => panic!("Wrong type of WorkError"),
-                        }
-                    }
-                    _ => panic!("Wrong type of error"),
-                }
-            },
-        }
+mycat
+verse1.txt
+verse2.txt
+stanza1.txt
+:
 
-        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(), "Roses are red.\nViolets are violet.\n");
+stanza2.txt
+:
+verse3.txt
+:
+mycat
+verse3.txt
+stanza2.txt
+:
+
+poem.txt
+:
+stanza1.txt
+stanza2.txt
+:
+mycat
+stanza1.txt
+stanza2.txt
+poem.txt
+:
+").unwrap();
+
+        let mut params = BuildParams::for_test();
+        params.trace_packets = true;
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, params).unwrap();
+
+        let sent : Vec<&String> = printer.printed.iter().filter(|line| line.starts_with("packet sent ")).collect();
+        let received : Vec<&String> = printer.printed.iter().filter(|line| line.starts_with("packet received ")).collect();
+        assert_eq!(sent.len(), 5);
+        assert_eq!(received.len(), 5);
+
+        let verse1_short = TicketFactory::from_str("Roses are red.\n").result().short_human_readable();
+        assert!(sent.iter().any(|line| line.starts_with(
+            &format!("packet sent verse1.txt -> stanza1.txt: {} at +", verse1_short))));
+        assert!(received.iter().any(|line| line.starts_with(
+            &format!("packet received verse1.txt -> stanza1.txt: {} at +", verse1_short))));
+
+        let stanza2_short = TicketFactory::from_str("Sugar is sweet.\n").result().short_human_readable();
+        assert!(received.iter().any(|line| line.starts_with(
+            &format!("packet received stanza2.txt -> poem.txt: {} at +", stanza2_short))));
+
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
+        assert!(!printer.printed.iter().any(|line| line.starts_with("packet ")));
     }
 
-    /*  Set up filesystem to build a poem with two verses.  Invoke the build, and check the resulting poem. */
+    /*  Build a poem from a stanza made by a command that takes twenty seconds by the clock.  With
+        show_waiting, the poem's rule says once that it's waiting on the stanza.  The command sleeps
+        a little before moving the clock on, so the poem's rule is already waiting when it does.  The same build with a
+        quick command says nothing about waiting. */
     #[test]
-    fn build_change_build_check_cache()
+    fn build_show_waiting()
     {
         let rules = "\
+stanza.txt
+:
+verse.txt
+:
+slow
+verse.txt
+stanza.txt
+:
+
 poem.txt
 :
-verse1.txt
-verse2.txt
+stanza.txt
 :
 mycat
-verse1.txt
-verse2.txt
+stanza.txt
 poem.txt
 :
 ";
         let mut system = FakeSystem::new(10);
-
-        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
-        write_str_to_file(&mut system, "verse2.txt", "Violets are blue.\n").unwrap();
+        system.register_command("slow", Box::new(
+            |system : &mut FakeSystem, args : &[String]|
+            {
+                thread::sleep(Duration::from_millis(200));
+                system.time_passes(20_000_000);
+                thread::sleep(Duration::from_millis(300));
+                let content = read_file_to_string(system, &args[0]).unwrap();
+                write_str_to_file(system, &args[1], &content).unwrap();
+                CommandLineOutput::new()
+            }));
+        write_str_to_file(&mut system, "verse.txt", "Roses are red.\n").unwrap();
         write_str_to_file(&mut system, "build.rules", rules).unwrap();
 
-        build(
-            system.clone(),
-            &mut EmptyPrinter::new(),
-            make_default_build_params()
-        ).unwrap();
+        let mut params = BuildParams::for_test();
+        params.show_waiting = true;
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, params).unwrap();
 
-        system.time_passes(1);
+        let waiting : Vec<&String> = printer.printed.iter().filter(|line| line.starts_with("Waiting: ")).collect();
+        assert_eq!(waiting, vec!["Waiting: poem.txt on stanza.txt"]);
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\n");
 
-        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(),
-            "Roses are red.\nViolets are blue.\n");
+        let mut system = FakeSystem::new(10);
+        system.register_command("slow", Box::new(
+            |system : &mut FakeSystem, args : &[String]|
+            {
+                let content = read_file_to_string(system, &args[0]).unwrap();
+                write_str_to_file(system, &args[1], &content).unwrap();
+                CommandLineOutput::new()
+            }));
+        write_str_to_file(&mut system, "verse.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
 
-        let ticket = TicketFactory::from_file(&system, "poem.txt").unwrap().result();
-        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        let mut params = BuildParams::for_test();
+        params.show_waiting = true;
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, params).unwrap();
+        assert!(!printer.printed.iter().any(|line| line.starts_with("Waiting: ")));
+    }
 
-        build(
-            system.clone(),
-            &mut EmptyPrinter::new(),
-            make_default_build_params()
-        ).unwrap();
+    /*  A chain of ten thousand rules is far past the point where build switches to a worker pool.  The
+        build still gets the verse all the way to the end of the chain, and a chain only ever has one
+        rule ready at a time, so the pool never needs more than a couple of workers. */
+    #[test]
+    fn build_long_chain_on_worker_pool()
+    {
+        let mut rules = String::new();
+        for i in 0..10000
+        {
+            rules.push_str(&format!("link{}.txt\n:\nlink{}.txt\n:\nmycat\nlink{}.txt\nlink{}.txt\n:\n\n", i + 1, i, i, i + 1));
+        }
 
-        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(),
-            "Roses are red.\nViolets are violet.\n");
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "link0.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", &rules).unwrap();
 
-        let mut cache = SysCache::new(system.clone(), ".ruler/cache");
-        cache.restore_file(&ticket, "temp-poem.txt");
+        let mut printer = EmptyPrinter::new();
+        build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
 
-        assert_eq!(read_file_to_string(&mut system, "temp-poem.txt").unwrap(),
-            "Roses are red.\nViolets are blue.\n");
+        assert_eq!(read_file_to_string(&system, "link10000.txt").unwrap(), "Roses are red.\n");
+        assert_eq!(system.get_command_log().len(), 10000);
+        match LAST_PEAK_WORKERS.with(|peak| peak.get())
+        {
+            Some(peak) => assert!(peak <= 2, "peak workers: {}", peak),
+            None => panic!("Expected the build to use a worker pool"),
+        }
+    }
 
-        cache.back_up_file_with_ticket(&ticket, "temp-poem.txt").unwrap();
-        write_str_to_file(&mut system, "verse2.txt", "Violets are blue.\n").unwrap();
+    /*  A command that writes an empty target has still generated it.  The build succeeds, the history
+        remembers the ticket of empty content, and the next build finds the empty target up to date. */
+    #[test]
+    fn build_empty_target()
+    {
+        let mut system = FakeSystem::new(10);
+        system.register_command("clear", Box::new(
+            |system : &mut FakeSystem, args : &[String]|
+            {
+                match system.create_file(&args[1])
+                {
+                    Ok(_file) => CommandLineOutput::new(),
+                    Err(_) => CommandLineOutput::error("clear: create failed".to_string()),
+                }
+            }));
+        write_str_to_file(&mut system, "notes.txt", "Nothing to see.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "empty.txt\n:\nnotes.txt\n:\nclear\nnotes.txt\nempty.txt\n:\n").unwrap();
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert!(system.is_file("empty.txt"));
+        assert_eq!(read_file_to_string(&system, "empty.txt").unwrap(), "");
+
+        let rule_ticket = get_nodes(&system, vec!["build.rules".to_string()], None, false, false, None).unwrap().nodes[0].rule_ticket.clone();
+        let mut factory = TicketFactory::new();
+        factory.input_ticket(TicketFactory::from_str("Nothing to see.\n").result());
+        let elements = directory::open_readonly(&system, ".ruler", None, None).unwrap();
+        let rule_history = elements.history.read_rule_history(&rule_ticket).unwrap();
+        assert_eq!(rule_history.get_file_state_vec(&factory.result()).unwrap().get_tickets(),
+            vec![TicketFactory::new().result()]);
+
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
+        assert_eq!(read_file_to_string(&system, "empty.txt").unwrap(), "");
+        assert_eq!(printer.printed.last().unwrap(), "Nothing to do: 1 targets up to date");
+    }
 
-        build(
-            system.clone(),
-            &mut EmptyPrinter::new(),
-            make_default_build_params()
-        ).unwrap();
+    /*  warncat writes the poem, then exits 1.  Without success criteria, that fails the build.  With
+        1 among the success exit codes, the build succeeds and the poem is there. */
+    #[test]
+    fn build_success_exit_codes_accept_warnings()
+    {
+        let rules = "poem.txt\n:\nverse1.txt\n:\nwarncat\nverse1.txt\npoem.txt\n:\n";
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
 
-        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(), "Roses are red.\nViolets are blue.\n");
+        match build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test())
+        {
+            Err(BuildError::WorkErrors(errors)) =>
+            {
+                assert_eq!(errors.len(), 1);
+                match &errors[0].1
+                {
+                    WorkError::CommandExecutedButErrored(output) => assert_eq!(output.code, Some(1)),
+                    error => panic!("Unexpected error: {}", error),
+                }
+            },
+            _ => panic!("Expected the exit code to fail the build"),
+        }
+
+        write_str_to_file(&mut system, "build.rules", &format!("@success-exit-codes 0 1\n{}", rules)).unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\n");
     }
 
-    /*  Set up filesystem to build a poem with incorrect rules, which say they generate a target, but actually do not. */
+    /*  The command writes the poem and exits 0, then myecho prints "BUILD FAILED".  With that as a
+        failure pattern, the build fails with an error naming the pattern and the line. */
     #[test]
-    fn build_command_fails_to_generate_target()
+    fn build_failure_pattern_fails_despite_exit_code()
     {
-        let rules = "\
-poem.txt
-:
-verse1.txt
-verse2.txt
-:
-mycat
-verse1.txt
-verse2.txt
-someotherpoem.txt
-:
-";
+        let rules = "poem.txt\n:\nverse1.txt\n:\nmycat\nverse1.txt\npoem.txt\n;\nmyecho\n** BUILD FAILED **\n:\n";
         let mut system = FakeSystem::new(10);
-
         write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
-        write_str_to_file(&mut system, "verse2.txt", "Violets are blue.\n").unwrap();
         write_str_to_file(&mut system, "build.rules", rules).unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
 
-        match build(
-            system.clone(),
-            &mut EmptyPrinter::new(),
-            make_default_build_params()
-        )
+        write_str_to_file(&mut system, "build.rules",
+            &format!("@failure-pattern nothing like this\n@failure-pattern BUILD FAILED\n{}", rules)).unwrap();
+        match build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test())
         {
-            Ok(_) => panic!("unexpected success"),
             Err(BuildError::WorkErrors(errors)) =>
             {
                 assert_eq!(errors.len(), 1);
-                match &errors[0]
+                match &errors[0].1
                 {
-                    WorkError::TargetFileNotGenerated(path_str) => assert_eq!(path_str, "poem.txt"),
-                    _ => panic!("Got work error but not the correct error: {}", errors[0]),
+                    WorkError::FailurePatternMatched(provenance, pattern, line) =>
+                    {
+                        assert_eq!(*provenance, Provenance::new("build.rules", 3));
+                        assert_eq!(pattern, "BUILD FAILED");
+                        assert_eq!(line, "** BUILD FAILED **");
+                    },
+                    error => panic!("Unexpected error: {}", error),
                 }
             },
-            Err(error) => panic!("Got error but not the correct error: {}", error),
+            _ => panic!("Expected the failure pattern to fail the build"),
         }
     }
 
-    /*  In a file system, create source files and rules file for a poem.
-        Access the .ruler direcotry, and use the take() function to get the state of the poem.
-        Verify that it is uninitialized.  Then run the build.  Verify that the build imparted
-        the new FileState on the poem. */
+    fn make_force_params(goal_target : &str, force : Force) -> BuildParams
+    {
+        let mut params = BuildParams::for_test();
+        params.goal_target_opt = Some(goal_target.to_string());
+        params.force = force;
+        params
+    }
+
+    /*  Everything is up-to-date, but forcing the first stanza runs its command again, and only that
+        one.  The command makes the same stanza, so the history agrees, and a build of everything
+        afterwards finds nothing to do: the poem doesn't rebuild, since its sources came out the same. */
     #[test]
-    fn build_check_file_state()
+    fn build_force_reruns_up_to_date_rule()
     {
-        let rules = "\
-poem.txt
-:
-verse1.txt
-verse2.txt
-:
-mycat
-verse1.txt
-verse2.txt
-poem.txt
-:
-";
-        let mut system = FakeSystem::new(17);
+        let mut system = make_stanza_system();
+        system.time_passes(1);
+        let log_length = system.get_command_log().len();
 
-        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
-        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
-        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, make_force_params("stanza1.txt", Force::Goal)).unwrap();
+        let command_log = system.get_command_log();
+        assert_eq!(command_log.len(), log_length + 1);
+        assert_eq!(command_log[log_length], vec!["mycat", "verse1.txt", "stanza1.txt"]);
+        assert_eq!(read_file_to_string(&system, "stanza1.txt").unwrap(), "Roses are red.\n");
 
-        let expected_poem_blob_before = Blob::from_paths(vec!["poem.txt".to_string()], |_path|{FileState::empty()});
-        let expected_poem_blob_after = Blob::from_paths(vec!["poem.txt".to_string()], |_path|
-            {FileState::new(
-                TicketFactory::from_str("Roses are red.\nViolets are violet.\n").result(), 17)
-            });
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(system.get_command_log().len(), log_length + 1);
+    }
+
+    /*  Forcing the poem deep runs the stanzas' commands as well as the poem's. */
+    #[test]
+    fn build_force_deep_reruns_dependences()
+    {
+        let mut system = make_stanza_system();
+        system.time_passes(1);
+        let log_length = system.get_command_log().len();
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, make_force_params("poem.txt", Force::Goal)).unwrap();
+        assert_eq!(system.get_command_log().len(), log_length + 1);
 
+        system.time_passes(1);
+        build(system.clone(), &mut EmptyPrinter::new(), None, make_force_params("poem.txt", Force::Deep)).unwrap();
+        assert_eq!(system.get_command_log().len(), log_length + 4);
+    }
+
+    /*  A command that writes the time into its target makes something different every run.  Forcing
+        it contradicts the history, and the build fails saying so. */
+    #[test]
+    fn build_force_nondeterministic_command_contradicts()
+    {
+        let mut system = FakeSystem::new(10);
+        system.register_command("stamp", Box::new(
+            |system : &mut FakeSystem, args : &[String]|
+            {
+                let stamp = format!("{}\n", get_timestamp(system.now()).unwrap());
+                write_str_to_file(system, &args[0], &stamp).unwrap();
+                CommandLineOutput::new()
+            }));
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "stamp.txt\n:\nverse1.txt\n:\nstamp\nstamp.txt\n:\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(read_file_to_string(&system, "stamp.txt").unwrap(), "10\n");
+
+        system.time_passes(1);
+        match build(system.clone(), &mut EmptyPrinter::new(), None, make_force_params("stamp.txt", Force::Goal))
         {
-            let mut elements = directory::init(&mut system, ".ruler").unwrap();
-            assert_eq!(elements.current_file_states.take_blob(vec!["poem.txt".to_string()]), expected_poem_blob_before);
+            Err(BuildError::WorkErrors(work_errors)) =>
+            {
+                assert_eq!(work_errors.len(), 1);
+                match &work_errors[0].1
+                {
+                    WorkError::Contradiction(paths, provenance) =>
+                    {
+                        assert_eq!(paths, &vec!["stamp.txt".to_string()]);
+                        assert_eq!(*provenance, Provenance::new("build.rules", 1));
+                    },
+                    error => panic!("Unexpected error: {}", error),
+                }
+            },
+            _ => panic!("Expected the forced rebuild to contradict the history"),
         }
+        assert_eq!(system.get_command_log().len(), 2);
+    }
 
-        build(
-            system.clone(),
-            &mut EmptyPrinter::new(),
-            make_default_build_params()
-            ).unwrap();
-
-        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(),
-            "Roses are red.\nViolets are violet.\n");
+    /*  A resolver that has the content of whatever it's been told, and writes it out when asked for
+        its ticket, or writes nonsense regardless if it's a liar. */
+    struct ContentResolver
+    {
+        name : String,
+        contents : Vec<String>,
+        liar : bool,
+    }
 
+    impl Resolver<FakeSystem> for ContentResolver
+    {
+        fn try_resolve(&self, ticket : &Ticket, path : &str, system : &mut FakeSystem)
+            -> Result<Option<FileResolution>, ResolutionError>
         {
-            let mut elements = directory::init(&mut system, ".ruler").unwrap();
-            assert_eq!(elements.current_file_states.take_blob(vec!["poem.txt".to_string()]), expected_poem_blob_after);
+            if self.liar
+            {
+                write_str_to_file(system, path, "Roses are plaid.\n").unwrap();
+                return Ok(Some(FileResolution::Recovered(self.name.clone())));
+            }
+
+            for content in self.contents.iter()
+            {
+                if TicketFactory::from_str(content).result() == *ticket
+                {
+                    write_str_to_file(system, path, content).unwrap();
+                    return Ok(Some(FileResolution::Recovered(self.name.clone())));
+                }
+            }
+            Ok(None)
         }
     }
 
+    /*  Build the poem, clean it, and lose the cache.  The resolvers get asked in order after the
+        cache: the liar's poem doesn't match the ticket, so it's warned about and passed over, and the
+        database's does, so the poem comes back recovered by name, without the command running. */
     #[test]
-    fn build_first_does_not_cache()
+    fn build_with_resolvers_synthesizes_target()
     {
-        let rules = "\
-poem.txt
-:
-verse1.txt
-verse2.txt
-:
-mycat
-verse1.txt
-verse2.txt
-poem.txt
-:
-";
-        let mut system = FakeSystem::new(19);
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "poem.txt\n:\nverse1.txt\n:\nmycat\nverse1.txt\npoem.txt\n:\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        clean(system.clone(), &mut EmptyPrinter::new(), BuildParams::for_test()).unwrap();
+        for path in system.list_dir(".ruler/cache").unwrap()
+        {
+            if system.is_file(&path)
+            {
+                system.remove_file(&path).unwrap();
+            }
+        }
+        assert!(!system.is_file("poem.txt"));
+
+        let mut printer = RecordingPrinter::new();
+        build_with_downloader(system.clone(), &FakeDownloader::new(), &mut printer, &mut EmptyObserver::new(), BuildParams::for_test(), vec![
+            Arc::new(ContentResolver{name : "liar".to_string(), contents : vec![], liar : true}),
+            Arc::new(ContentResolver{name : "database".to_string(), contents : vec!["Roses are red.\n".to_string()], liar : false}),
+        ]).unwrap();
+
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\n");
+        assert_eq!(system.get_command_log().len(), 1);
+        assert!(printer.printed.contains(&"   Warning resolved content does not match the ticket: poem.txt".to_string()));
+        assert!(printer.printed.contains(&" Recovered poem.txt (from database cache)".to_string()));
+    }
 
+    /*  Build the two stanzas and the poem, with golden copies of all three, one of which has a word
+        changed.  Comparing reports that one, with the tickets of both, and nothing else. */
+    #[test]
+    fn compare_to_golden_reports_only_differing_target()
+    {
+        let mut system = FakeSystem::new(10);
         write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
         write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
-        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+        write_str_to_file(&mut system, "build.rules", "\
+stanza1.txt\n:\nverse1.txt\n:\nmycat\nverse1.txt\nstanza1.txt\n:\n
+stanza2.txt\n:\nverse2.txt\n:\nmycat\nverse2.txt\nstanza2.txt\n:\n
+poem.txt\n:\nstanza1.txt\nstanza2.txt\n:\nmycat\nstanza1.txt\nstanza2.txt\npoem.txt\n:\n").unwrap();
+
+        system.create_dir("golden").unwrap();
+        write_str_to_file(&mut system, "golden/stanza1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "golden/stanza2.txt", "Violets are blue.\n").unwrap();
+        write_str_to_file(&mut system, "golden/poem.txt", "Roses are red.\nViolets are violet.\n").unwrap();
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(compare_to_golden(&system, BuildParams::for_test(), "golden/").unwrap(),
+            vec![
+                TargetMismatch
+                {
+                    path : "stanza2.txt".to_string(),
+                    built_ticket : TicketFactory::from_str("Violets are violet.\n").result(),
+                    golden_ticket_opt : Some(TicketFactory::from_str("Violets are blue.\n").result()),
+                }
+            ]);
+    }
 
-        build(
-            system.clone(),
-            &mut EmptyPrinter::new(),
-            make_default_build_params()
-        ).unwrap();
+    /*  Stage a copy of the tool with a command that embeds wherever this machine installs to, read
+        from a file no rule declares, build, then change that, and force the staging rule again. */
+    fn build_staged_tool_twice(marker : &str) -> (FakeSystem, Result<(), BuildError>)
+    {
+        let mut system = FakeSystem::new(10);
+        system.register_command("install", Box::new(
+            |system : &mut FakeSystem, args : &[String]|
+            {
+                let content = format!("{}installed under {}",
+                    read_file_to_string(system, &args[0]).unwrap(),
+                    read_file_to_string(system, "prefix.txt").unwrap());
+                write_str_to_file(system, &args[1], &content).unwrap();
+                CommandLineOutput::new()
+            }));
+        write_str_to_file(&mut system, "tool", "tool\n").unwrap();
+        write_str_to_file(&mut system, "prefix.txt", "/home/alice\n").unwrap();
+        write_str_to_file(&mut system, "build.rules",
+            &format!("{}stage/bin/tool\n:\ntool\n:\ninstall\ntool\nstage/bin/tool\n:\n", marker)).unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
 
-        assert_eq!(
-            read_file_to_string(&mut system, "poem.txt").unwrap(),
-            "Roses are red.\nViolets are violet.\n");
+        system.time_passes(1);
+        write_str_to_file(&mut system, "prefix.txt", "/home/bob\n").unwrap();
+        let result = build(system.clone(), &mut EmptyPrinter::new(), None, make_force_params("stage/bin/tool", Force::Goal));
+        (system, result)
+    }
 
-        let elements = directory::init(&mut system, "ruler-directory").unwrap();
-        match elements.cache.open(&TicketFactory::from_str("Roses are red.\nViolets are violet.\n").result())
+    /*  An install rule whose output embeds a different path the second time takes the new output into
+        its history without a contradiction, so building again afterwards leaves it be.  The same rule
+        unmarked contradicts its history. */
+    #[test]
+    fn build_install_rule_overwrites_history_instead_of_contradicting()
+    {
+        let (system, result) = build_staged_tool_twice("@install\n");
+        result.unwrap();
+        assert_eq!(read_file_to_string(&system, "stage/bin/tool").unwrap(), "tool\ninstalled under /home/bob\n");
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(system.get_command_log().len(), 2);
+
+        let (_system, result) = build_staged_tool_twice("");
+        match result
         {
-            Ok(_file) => panic!("Unexpected cache presence after first build"),
-            Err(OpenError::NotThere) => {},
-            Err(_) => panic!("Unexpected error trying to access cache after first build"),
+            Err(BuildError::WorkErrors(work_errors)) =>
+            {
+                assert_eq!(work_errors.len(), 1);
+                match &work_errors[0].1
+                {
+                    WorkError::Contradiction(paths, _provenance) => assert_eq!(paths, &vec!["stage/bin/tool".to_string()]),
+                    error => panic!("Unexpected error: {}", error),
+                }
+            },
+            _ => panic!("Expected the unmarked rule to contradict its history"),
         }
     }
 
+    /*  Building the poem, the observer hears the build start, the poem's rule start and finish by
+        building, and the build finish, in that order, and nothing about the verses.  Building again,
+        the rule finishes up-to-date. */
+    #[test]
+    fn build_observer_poem_events()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules",
+            "poem.txt\n:\nverse1.txt\nverse2.txt\n:\nmycat\nverse1.txt\nverse2.txt\npoem.txt\n:\n").unwrap();
+
+        let mut observer = RecordingObserver::new();
+        build(system.clone(), &mut EmptyPrinter::new(), Some(&mut observer), BuildParams::for_test()).unwrap();
+        assert_eq!(observer.events, vec![
+            "build started 1",
+            "started poem.txt",
+            "finished poem.txt built",
+            "build finished ok",
+        ]);
+
+        let mut observer = RecordingObserver::new();
+        build(system.clone(), &mut EmptyPrinter::new(), Some(&mut observer), BuildParams::for_test()).unwrap();
+        assert_eq!(observer.events, vec![
+            "build started 1",
+            "started poem.txt",
+            "finished poem.txt up_to_date",
+            "build finished ok",
+        ]);
+    }
 }
@@ -0,0 +1,313 @@
+use std::fmt;
+use std::io;
+use std::collections::HashMap;
+use crate::blob::FileState;
+use crate::system::
+{
+    CommandLineOutput,
+    CommandScript,
+    ReadWriteError,
+    System,
+    SystemError,
+};
+
+/*  Commands ruler carries out itself, through the System, instead of spawning a process:
+
+    @copy source target
+    @cat source1 source2 ... target
+
+    @copy copies source to target, executable bit and all.  @cat concatenates the sources in the order
+    given into target.  Since they go through the System like everything else, they behave the same
+    under any System, and they save a process per file on rules that only shuffle files around. */
+const COPY : &str = "@copy";
+const CAT : &str = "@cat";
+
+#[derive(Debug, PartialEq)]
+pub enum BuiltInError
+{
+    WrongNumberOfArguments(String),
+    NotBuiltIn(String),
+    SourceNotFound(String),
+    ReadWriteError(String, ReadWriteError),
+}
+
+impl fmt::Display for BuiltInError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            BuiltInError::WrongNumberOfArguments(name) =>
+                write!(formatter, "Wrong number of arguments to {}", name),
+
+            BuiltInError::NotBuiltIn(word) =>
+                write!(formatter, "A command starting with a built-in can only run built-ins, but got: {}", word),
+
+            BuiltInError::SourceNotFound(path) =>
+                write!(formatter, "File not found: {}", path),
+
+            BuiltInError::ReadWriteError(path, error) =>
+                write!(formatter, "Error copying file: {}: {}", path, error),
+        }
+    }
+}
+
+/*  The words of one command, however the rule split them across lines. */
+fn to_words(argv : &Vec<String>) -> Vec<String>
+{
+    argv.iter().flat_map(|line| line.split_whitespace()).map(|word| word.to_string()).collect()
+}
+
+/*  True if the command script starts with a built-in, in which case execute_built_in runs it instead
+    of the System. */
+pub fn is_built_in(command_script : &CommandScript) -> bool
+{
+    match command_script.argvs.first()
+    {
+        Some(argv) =>
+        {
+            match to_words(argv).first()
+            {
+                Some(word) => word == COPY || word == CAT,
+                None => false,
+            }
+        },
+        None => false,
+    }
+}
+
+fn open_source<SystemType : System>
+(
+    system : &SystemType,
+    path : &str
+)
+-> Result<SystemType::File, BuiltInError>
+{
+    match system.open(path)
+    {
+        Ok(file) => Ok(file),
+        Err(SystemError::NotFound) => Err(BuiltInError::SourceNotFound(path.to_string())),
+        Err(error) => Err(BuiltInError::ReadWriteError(path.to_string(), ReadWriteError::SystemError(error))),
+    }
+}
+
+/*  Opens every source before creating the target, so a missing source leaves the target alone, then
+    streams each source into the target in turn. */
+fn concatenate<SystemType : System>
+(
+    system : &mut SystemType,
+    sources : &[String],
+    target : &str
+)
+-> Result<(), BuiltInError>
+{
+    let mut source_files = vec![];
+    for source in sources
+    {
+        source_files.push(open_source(system, source)?);
+    }
+
+    let mut target_file =
+    match system.create_file(target)
+    {
+        Ok(file) => file,
+        Err(error) => return Err(BuiltInError::ReadWriteError(target.to_string(), ReadWriteError::SystemError(error))),
+    };
+
+    for (source, mut source_file) in sources.iter().zip(source_files.into_iter())
+    {
+        match io::copy(&mut source_file, &mut target_file)
+        {
+            Ok(_size) => {},
+            Err(error) => return Err(BuiltInError::ReadWriteError(source.clone(), ReadWriteError::IOError(format!("{}", error)))),
+        }
+    }
+
+    Ok(())
+}
+
+fn copy<SystemType : System>
+(
+    system : &mut SystemType,
+    args : &[String],
+    known_states : &HashMap<String, FileState>
+)
+-> Result<(), BuiltInError>
+{
+    if args.len() != 2
+    {
+        return Err(BuiltInError::WrongNumberOfArguments(COPY.to_string()));
+    }
+
+    concatenate(system, &args[..1], &args[1])?;
+
+    let executable =
+    match known_states.get(&args[0])
+    {
+        Some(file_state) => file_state.executable,
+        None =>
+        {
+            match system.is_executable(&args[0])
+            {
+                Ok(executable) => executable,
+                Err(error) => return Err(BuiltInError::ReadWriteError(args[0].clone(), ReadWriteError::SystemError(error))),
+            }
+        },
+    };
+
+    match system.set_is_executable(&args[1], executable)
+    {
+        Ok(()) => Ok(()),
+        Err(error) => Err(BuiltInError::ReadWriteError(args[1].clone(), ReadWriteError::SystemError(error))),
+    }
+}
+
+fn cat<SystemType : System>
+(
+    system : &mut SystemType,
+    args : &[String]
+)
+-> Result<(), BuiltInError>
+{
+    match args.split_last()
+    {
+        Some((target, sources)) => concatenate(system, sources, target),
+        None => Err(BuiltInError::WrongNumberOfArguments(CAT.to_string())),
+    }
+}
+
+/*  Runs each command of the script as a built-in, and on success, returns the output of a command that
+    printed nothing and exited with 0, so callers can treat it like any other.  known_states holds file
+    states already known for some of the files, such as the rule's sources, to use instead of asking
+    the System again. */
+pub fn execute_built_in<SystemType : System>
+(
+    system : &mut SystemType,
+    command_script : &CommandScript,
+    known_states : &HashMap<String, FileState>
+)
+-> Result<CommandLineOutput, BuiltInError>
+{
+    for argv in command_script.argvs.iter()
+    {
+        let words = to_words(argv);
+        match words.split_first()
+        {
+            Some((name, args)) if name == COPY => copy(system, args, known_states)?,
+            Some((name, args)) if name == CAT => cat(system, args)?,
+            Some((name, _args)) => return Err(BuiltInError::NotBuiltIn(name.clone())),
+            None => {},
+        }
+    }
+
+    Ok(CommandLineOutput::from_bytes(vec![], vec![], Some(0), true))
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::builtin::
+    {
+        BuiltInError,
+        execute_built_in,
+        is_built_in,
+    };
+    use crate::system::
+    {
+        System,
+        to_command_script,
+    };
+    use crate::system::fake::FakeSystem;
+    use std::collections::HashMap;
+    use crate::system::util::
+    {
+        read_file,
+        read_file_to_string,
+        write_str_to_file,
+    };
+
+    fn to_lines(text : &str) -> Vec<String>
+    {
+        text.split('\n').map(|line| line.to_string()).collect()
+    }
+
+    /*  Only a command whose first word is a built-in counts, whichever line that word is on. */
+    #[test]
+    fn recognize_built_in()
+    {
+        assert!(is_built_in(&to_command_script(to_lines("@copy\na.txt\nb.txt"))));
+        assert!(is_built_in(&to_command_script(to_lines("@cat a.txt b.txt c.txt"))));
+        assert!(!is_built_in(&to_command_script(to_lines("mycat a.txt b.txt"))));
+        assert!(!is_built_in(&to_command_script(to_lines("echo @copy"))));
+        assert!(!is_built_in(&to_command_script(vec![])));
+    }
+
+    /*  @copy gives the target the source's content and executable bit. */
+    #[test]
+    fn copy_preserves_content_and_executable_bit()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "run.sh", "echo hello\n").unwrap();
+        system.set_is_executable("run.sh", true).unwrap();
+        system.create_dir("bin").unwrap();
+
+        let output = execute_built_in(&mut system, &to_command_script(to_lines("@copy run.sh bin/run.sh")), &HashMap::new()).unwrap();
+        assert_eq!(output.code, Some(0));
+        assert_eq!(output.out, "");
+        assert_eq!(read_file_to_string(&system, "bin/run.sh").unwrap(), "echo hello\n");
+        assert!(system.is_executable("bin/run.sh").unwrap());
+
+        write_str_to_file(&mut system, "notes.txt", "plain\n").unwrap();
+        execute_built_in(&mut system, &to_command_script(to_lines("@copy notes.txt bin/run.sh")), &HashMap::new()).unwrap();
+        assert_eq!(read_file_to_string(&system, "bin/run.sh").unwrap(), "plain\n");
+        assert!(!system.is_executable("bin/run.sh").unwrap());
+    }
+
+    /*  @cat puts the sources into the target in the order given. */
+    #[test]
+    fn cat_concatenates_in_order()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+
+        execute_built_in(&mut system, &to_command_script(to_lines("@cat\nverse2.txt\nverse1.txt\npoem.txt")), &HashMap::new()).unwrap();
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Violets are violet.\nRoses are red.\n");
+    }
+
+    /*  A missing source is an error naming it, and the target doesn't get created. */
+    #[test]
+    fn missing_source_names_file()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+
+        assert_eq!(
+            execute_built_in(&mut system, &to_command_script(to_lines("@cat verse1.txt verse2.txt poem.txt")), &HashMap::new()),
+            Err(BuiltInError::SourceNotFound("verse2.txt".to_string())));
+        assert!(!system.is_file("poem.txt"));
+
+        assert_eq!(
+            execute_built_in(&mut system, &to_command_script(to_lines("@copy poem.txt")), &HashMap::new()),
+            Err(BuiltInError::WrongNumberOfArguments("@copy".to_string())));
+
+        assert_eq!(
+            execute_built_in(&mut system, &to_command_script(to_lines("@copy verse1.txt poem.txt\n;\nmycat verse1.txt poem.txt")), &HashMap::new()),
+            Err(BuiltInError::NotBuiltIn("mycat".to_string())));
+    }
+
+    /*  A file much bigger than any one buffer comes through intact. */
+    #[test]
+    fn copy_large_file()
+    {
+        let mut system = FakeSystem::new(10);
+        let content : Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        {
+            let mut file = system.create_file("big.bin").unwrap();
+            std::io::Write::write_all(&mut file, &content).unwrap();
+        }
+
+        execute_built_in(&mut system, &to_command_script(to_lines("@copy big.bin copy.bin")), &HashMap::new()).unwrap();
+        assert_eq!(read_file(&system, "copy.bin").unwrap(), content);
+    }
+}
@@ -1,9 +1,28 @@
 use std::boxed::Box;
 use std::fmt;
+use std::io;
+
+use std::io::
+{
+    Read,
+    Write,
+};
 
-#[cfg(test)]
 use rand::prelude::*;
+use serde::
+{
+    Serialize,
+    Deserialize
+};
+use termcolor::Color;
 
+use crate::directory;
+use crate::ignore::
+{
+    IgnorePatterns,
+    move_directory,
+};
+use crate::printer::Printer;
 use crate::ticket::Ticket;
 use crate::ticket::TicketFactory;
 use crate::system::
@@ -15,6 +34,7 @@ use crate::system::
 use crate::downloader::
 {
     download_file,
+    Downloader,
 };
 
 #[derive(Debug, PartialEq)]
@@ -58,6 +78,36 @@ impl fmt::Display for OpenError
     }
 }
 
+#[derive(Debug)]
+pub enum StoreError
+{
+    FailedToOpenInbox(OpenError),
+    FailedToCopy(String),
+    ReadWriteError(ReadWriteError),
+    TicketMismatch(Ticket),
+}
+
+impl fmt::Display for StoreError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            StoreError::FailedToOpenInbox(error) =>
+                write!(formatter, "Failed to open inbox file: {}", error),
+
+            StoreError::FailedToCopy(error) =>
+                write!(formatter, "Failed to copy into inbox file: {}", error),
+
+            StoreError::ReadWriteError(error) =>
+                write!(formatter, "Failed to move inbox file into cache: {}", error),
+
+            StoreError::TicketMismatch(ticket) =>
+                write!(formatter, "Content did not match expected ticket, got: {}", ticket),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct DownloaderCache
 {
@@ -95,6 +145,34 @@ impl DownloaderCache
 
         DownloadResult::NotThere
     }
+
+    /*  Tries each base url in turn for the file with the given ticket, and stores the first
+        download whose content matches the ticket in the given cache. */
+    pub fn download_to_cache<DownloaderType : Downloader, SystemType : System>(
+        &self,
+        downloader : &DownloaderType,
+        ticket : &Ticket,
+        cache : &mut SysCache<SystemType>
+    ) -> DownloadResult
+    {
+        for base_url in &self.base_urls
+        {
+            match downloader.download_bytes(&format!("{}/{}", base_url, ticket.human_readable()))
+            {
+                Ok(content) =>
+                {
+                    match cache.store_from_reader(ticket, &mut &content[..])
+                    {
+                        Ok(()) => return DownloadResult::Done,
+                        Err(_error) => {},
+                    }
+                },
+                Err(_error) => {},
+            }
+        }
+
+        DownloadResult::NotThere
+    }
 }
 
 pub struct InboxFile<SystemType : System>
@@ -121,24 +199,31 @@ impl<SystemType : System> std::io::Write for InboxFile<SystemType>
 
 impl<SystemType : System> InboxFile<SystemType>
 {
-    #[cfg(test)]
-    fn finish(mut self) -> Result<(), ReadWriteError>
+    /*  Closes the inbox file and moves it into the cache under the ticket of the content
+        written.  Returns that ticket. */
+    fn finish(mut self) -> Result<Ticket, ReadWriteError>
     {
         drop(self.file);
-        self.cache.back_up_file_with_ticket(
-            &self.ticket_factory.result(),
-            &self.inbox_file_path)
+        let ticket = self.ticket_factory.result();
+        self.cache.back_up_file_with_ticket(&ticket, &self.inbox_file_path)?;
+        Ok(ticket)
     }
 }
 
+/*  The tier of the cache in the ruler directory (or wherever --cache-dir puts it). */
+pub const LOCAL_CACHE_TIER : &str = "local";
+
+/*  A cache of files named by ticket, in a directory.  The tier labels which cache this is, so that
+    with more than one (say a local one and a shared one), a recovered file can say where it came
+    from. */
 #[derive(Clone)]
 pub struct SysCache<SystemType : System>
 {
     system_box : Box<SystemType>,
     path : String,
+    tier : String,
 }
 
-#[cfg(test)]
 fn random_filename() -> String
 {
     const ALPHABET : [u8; 62] = [
@@ -156,24 +241,53 @@ impl<SystemType : System> SysCache<SystemType>
 {
     pub fn new(system : SystemType, path : &str)
     -> SysCache<SystemType>
+    {
+        SysCache::with_tier(system, path, LOCAL_CACHE_TIER)
+    }
+
+    pub fn with_tier(system : SystemType, path : &str, tier : &str)
+    -> SysCache<SystemType>
     {
         SysCache
         {
             system_box : Box::new(system),
             path : path.to_string(),
+            tier : tier.to_string(),
         }
     }
 
+    pub fn get_tier(&self) -> &str
+    {
+        &self.tier
+    }
+
+    /*  The path of the file in the cache for the given ticket.  The name is the full human-readable
+        ticket, never a truncated form, so two tickets that agree on a prefix (as they do for the
+        purposes of Hash) still get distinct files. */
+    fn get_cache_path(&self, ticket : &Ticket) -> String
+    {
+        format!("{}/{}", self.path, ticket.human_readable())
+    }
+
+    /*  Whether the cache has a file or directory for the given ticket.  Looks without touching. */
+    pub fn contains(&self, ticket : &Ticket) -> bool
+    {
+        let cache_path = self.get_cache_path(ticket);
+        self.system_box.is_file(&cache_path) || self.system_box.is_dir(&cache_path)
+    }
+
+    /*  Moves the file with the given ticket out of the cache to target_path.  A directory that finds
+        one already at target_path moves in around whatever ignored things are there. */
     pub fn restore_file(
         &mut self,
         ticket : &Ticket,
         target_path : &str
     ) -> RestoreResult
     {
+        let cache_path = self.get_cache_path(ticket);
         let system = &mut (*self.system_box);
         if system.is_dir(&self.path)
         {
-            let cache_path = format!("{}/{}", self.path, ticket.human_readable());
             if system.is_file(&cache_path)
             {
                 match system.rename(&cache_path, &target_path)
@@ -182,6 +296,21 @@ impl<SystemType : System> SysCache<SystemType>
                     Ok(()) => RestoreResult::Done
                 }
             }
+            else if system.is_dir(&cache_path)
+            {
+                let ignore_patterns =
+                match IgnorePatterns::from_system(system)
+                {
+                    Ok(ignore_patterns) => ignore_patterns,
+                    Err(_error) => return RestoreResult::SystemError(SystemError::Weird),
+                };
+
+                match move_directory(system, &cache_path, &target_path, &ignore_patterns)
+                {
+                    Err(error) => RestoreResult::SystemError(error),
+                    Ok(()) => RestoreResult::Done
+                }
+            }
             else
             {
                 RestoreResult::NotThere
@@ -204,7 +333,7 @@ impl<SystemType : System> SysCache<SystemType>
             return Err(OpenError::CacheDirectoryMissing);
         }
 
-        let cache_path = format!("{}/{}", self.path, ticket.human_readable());
+        let cache_path = self.get_cache_path(ticket);
         if ! system.is_file(&cache_path)
         {
             return Err(OpenError::NotThere);
@@ -217,7 +346,6 @@ impl<SystemType : System> SysCache<SystemType>
         }
     }
 
-    #[cfg(test)]
     pub fn open_inbox_file(&mut self) -> Result<InboxFile<SystemType>, OpenError>
     {
         let system = &mut (*self.system_box);
@@ -226,10 +354,14 @@ impl<SystemType : System> SysCache<SystemType>
             return Err(OpenError::CacheDirectoryMissing);
         }
 
-        match system.create_dir(&format!("{}/inbox", self.path))
+        let inbox_path = format!("{}/inbox", self.path);
+        if ! system.is_dir(&inbox_path)
         {
-            Ok(()) => {},
-            Err(system_error) => return Err(OpenError::SystemError(system_error)),
+            match system.create_dir(&inbox_path)
+            {
+                Ok(()) => {},
+                Err(system_error) => return Err(OpenError::SystemError(system_error)),
+            }
         }
 
         let inbox_file_path = loop
@@ -256,8 +388,50 @@ impl<SystemType : System> SysCache<SystemType>
         })
     }
 
+    /*  Streams the reader into the cache as the file for the given ticket.  The content is hashed
+        on the way in, and if it does not match the ticket, the error says so.  The mismatched content
+        still lands in the cache, but under its own ticket, where it can do no harm. */
+    pub fn store_from_reader<ReaderType : io::Read>
+    (
+        &mut self,
+        ticket : &Ticket,
+        reader : &mut ReaderType
+    )
+    ->
+    Result<(), StoreError>
+    {
+        let mut inbox_file = match self.open_inbox_file()
+        {
+            Ok(inbox_file) => inbox_file,
+            Err(error) => return Err(StoreError::FailedToOpenInbox(error)),
+        };
+
+        match io::copy(reader, &mut inbox_file)
+        {
+            Ok(_size) => {},
+            Err(error) => return Err(StoreError::FailedToCopy(error.to_string())),
+        }
+
+        match inbox_file.finish()
+        {
+            Ok(actual_ticket) =>
+            {
+                if actual_ticket == *ticket
+                {
+                    Ok(())
+                }
+                else
+                {
+                    Err(StoreError::TicketMismatch(actual_ticket))
+                }
+            },
+            Err(error) => Err(StoreError::ReadWriteError(error)),
+        }
+    }
+
     /*  Creates a file with the given ticket (convertd to human_readable) as a name, and
-        moves the file into that place. */
+        moves the file into that place.  A directory goes in without anything IGNORE_FILE_PATH says
+        to ignore, since that's not part of its ticket. */
     pub fn back_up_file_with_ticket
     (
         &mut self,
@@ -267,8 +441,18 @@ impl<SystemType : System> SysCache<SystemType>
     ->
     Result<(), ReadWriteError>
     {
+        let cache_path = self.get_cache_path(ticket);
         let system = &mut (*self.system_box);
-        let cache_path = format!("{}/{}", self.path, ticket.human_readable());
+        if system.is_dir(target_path)
+        {
+            let ignore_patterns = IgnorePatterns::from_system(system)?;
+            return match move_directory(system, target_path, &cache_path, &ignore_patterns)
+            {
+                Ok(()) => Ok(()),
+                Err(error) => Err(ReadWriteError::SystemError(error)),
+            };
+        }
+
         match system.rename(&target_path, &cache_path)
         {
             Ok(_) => Ok(()),
@@ -276,6 +460,141 @@ impl<SystemType : System> SysCache<SystemType>
         }
     }
 
+    /*  Like back_up_file_with_ticket, but copies the file into the cache, leaving it where it is.
+        Should the content turn out not to match the ticket, the copy goes in under its own ticket. */
+    pub fn copy_file_with_ticket
+    (
+        &mut self,
+        ticket : &Ticket,
+        target_path : &str
+    )
+    ->
+    Result<(), ReadWriteError>
+    {
+        let mut reader = match self.system_box.open(target_path)
+        {
+            Ok(reader) => reader,
+            Err(error) => return Err(ReadWriteError::SystemError(error)),
+        };
+
+        match self.store_from_reader(ticket, &mut reader)
+        {
+            Ok(()) => Ok(()),
+            Err(StoreError::TicketMismatch(_actual_ticket)) => Ok(()),
+            Err(StoreError::ReadWriteError(error)) => Err(error),
+            Err(error) => Err(ReadWriteError::IOError(error.to_string())),
+        }
+    }
+
+    /*  The tickets of the files in the cache, sorted.  Anything not named like a ticket (the inbox,
+        say) is left out.  A missing cache directory has nothing in it. */
+    pub fn list_entries(&self) -> Result<Vec<Ticket>, ReadWriteError>
+    {
+        let system = &(*self.system_box);
+        if ! system.is_dir(&self.path)
+        {
+            return Ok(vec![]);
+        }
+
+        let mut paths =
+        match system.list_dir(&self.path)
+        {
+            Ok(paths) => paths,
+            Err(error) => return Err(ReadWriteError::SystemError(error)),
+        };
+        paths.sort();
+
+        Ok(paths.iter().filter(|path| system.is_file(path)).filter_map(
+            |path|
+            {
+                let name = match path.rsplit_once('/')
+                {
+                    Some((_directory, name)) => name,
+                    None => path,
+                };
+
+                match Ticket::from_human_readable(name)
+                {
+                    Ok(ticket) => Some(ticket),
+                    Err(_) => None,
+                }
+            }).collect())
+    }
+
+    /*  Rehashes the entry with the given ticket, and says whether it still matches. */
+    pub fn entry_matches_ticket(&self, ticket : &Ticket) -> Result<bool, ReadWriteError>
+    {
+        let system = &(*self.system_box);
+        Ok(TicketFactory::from_file(system, &self.get_cache_path(ticket))?.result() == *ticket)
+    }
+
+    /*  Picks sample_size entries at random (the same ones for the same seed) and rehashes each to
+        check it still matches its ticket.  Entries that don't get removed, so whatever needs them gets
+        rebuilt or downloaded again instead of restored wrong. */
+    pub fn verify_sample(&mut self, sample_size : usize, seed : u64) -> Result<SampleReport, ReadWriteError>
+    {
+        let entries = self.list_entries()?;
+        let mut rng = StdRng::seed_from_u64(seed);
+        let sample : Vec<Ticket> = entries.choose_multiple(&mut rng, sample_size).cloned().collect();
+
+        let mut corrupt = vec![];
+        for ticket in sample.iter()
+        {
+            if ! self.entry_matches_ticket(ticket)?
+            {
+                let cache_path = self.get_cache_path(ticket);
+                let system = &mut (*self.system_box);
+                match system.remove_file(&cache_path)
+                {
+                    Ok(()) => {},
+                    Err(error) => return Err(ReadWriteError::SystemError(error)),
+                }
+                corrupt.push(ticket.clone());
+            }
+        }
+
+        Ok(SampleReport
+        {
+            entry_count : entries.len(),
+            verified : sample,
+            corrupt : corrupt,
+        })
+    }
+
+    /*  Rehashes every entry, one file at a time and each streamed through the hasher, so the cache
+        never has to fit in memory.  With repair, entries that don't match their tickets get removed,
+        as sampling would.  Returns a report with every entry verified. */
+    pub fn verify_all(&mut self, repair : bool) -> Result<SampleReport, ReadWriteError>
+    {
+        let entries = self.list_entries()?;
+
+        let mut corrupt = vec![];
+        for ticket in entries.iter()
+        {
+            if ! self.entry_matches_ticket(ticket)?
+            {
+                if repair
+                {
+                    let cache_path = self.get_cache_path(ticket);
+                    let system = &mut (*self.system_box);
+                    match system.remove_file(&cache_path)
+                    {
+                        Ok(()) => {},
+                        Err(error) => return Err(ReadWriteError::SystemError(error)),
+                    }
+                }
+                corrupt.push(ticket.clone());
+            }
+        }
+
+        Ok(SampleReport
+        {
+            entry_count : entries.len(),
+            verified : entries,
+            corrupt : corrupt,
+        })
+    }
+
     pub fn back_up_file
     (
         &mut self,
@@ -296,6 +615,295 @@ impl<SystemType : System> SysCache<SystemType>
     }
 }
 
+/*  How many cache entries each build checks against their tickets, unless told otherwise. */
+pub const DEFAULT_VERIFY_SAMPLE_SIZE : usize = 5;
+
+/*  What one round of sampling found: how many entries the cache had, which got checked, and which of
+    those didn't match their tickets (and so got removed). */
+#[derive(Debug, PartialEq)]
+pub struct SampleReport
+{
+    pub entry_count : usize,
+    pub verified : Vec<Ticket>,
+    pub corrupt : Vec<Ticket>,
+}
+
+/*  Running totals of cache sampling, kept in the ruler directory across builds.  Sampling picks entries
+    at random, so coverage is only ever an estimate: the last full coverage is when the entries checked
+    since the one before added up to the size of the cache. */
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct VerifyStats
+{
+    pub entries_verified : u64,
+    pub corrupt_found : u64,
+    pub verified_since_coverage : u64,
+
+    /*  Microseconds since the epoch. */
+    pub last_full_coverage_opt : Option<u64>,
+}
+
+impl VerifyStats
+{
+    pub fn new() -> VerifyStats
+    {
+        VerifyStats
+        {
+            entries_verified : 0,
+            corrupt_found : 0,
+            verified_since_coverage : 0,
+            last_full_coverage_opt : None,
+        }
+    }
+
+    /*  Adds a round of sampling, done at the given time, to the totals. */
+    pub fn add_report(&mut self, report : &SampleReport, timestamp : u64)
+    {
+        let verified = report.verified.len() as u64;
+        self.entries_verified += verified;
+        self.corrupt_found += report.corrupt.len() as u64;
+        self.verified_since_coverage += verified;
+        if report.entry_count > 0 && self.verified_since_coverage >= report.entry_count as u64
+        {
+            self.last_full_coverage_opt = Some(timestamp);
+            self.verified_since_coverage = 0;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum VerifyStatsError
+{
+    CannotReadStatsFile(String),
+    CannotInterpretStatsFile(String),
+    CannotWriteStatsFile(String),
+    CannotListCache(ReadWriteError),
+}
+
+impl fmt::Display for VerifyStatsError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            VerifyStatsError::CannotReadStatsFile(path) =>
+                write!(formatter, "Cannot read cache sampling stats file: {}", path),
+
+            VerifyStatsError::CannotInterpretStatsFile(path) =>
+                write!(formatter, "Cannot interpret cache sampling stats file: {}", path),
+
+            VerifyStatsError::CannotWriteStatsFile(path) =>
+                write!(formatter, "Cannot write cache sampling stats file: {}", path),
+
+            VerifyStatsError::CannotListCache(error) =>
+                write!(formatter, "Cannot list cache: {}", error),
+        }
+    }
+}
+
+pub fn get_verify_stats_path(directory_path : &str) -> String
+{
+    format!("{}/verify-stats", directory_path)
+}
+
+/*  Reads the sampling totals.  No file yet means no sampling yet. */
+pub fn read_verify_stats<SystemType : System>
+(
+    system : &SystemType,
+    path : &str
+)
+-> Result<VerifyStats, VerifyStatsError>
+{
+    if ! system.is_file(path)
+    {
+        return Ok(VerifyStats::new());
+    }
+
+    let mut file =
+    match system.open(path)
+    {
+        Ok(file) => file,
+        Err(_) => return Err(VerifyStatsError::CannotReadStatsFile(path.to_string())),
+    };
+
+    let mut content = Vec::new();
+    match file.read_to_end(&mut content)
+    {
+        Ok(_size) => {},
+        Err(_) => return Err(VerifyStatsError::CannotReadStatsFile(path.to_string())),
+    }
+
+    match bincode::deserialize(&content)
+    {
+        Ok(stats) => Ok(stats),
+        Err(_) => Err(VerifyStatsError::CannotInterpretStatsFile(path.to_string())),
+    }
+}
+
+pub fn write_verify_stats<SystemType : System>
+(
+    system : &mut SystemType,
+    path : &str,
+    stats : &VerifyStats
+)
+-> Result<(), VerifyStatsError>
+{
+    let content =
+    match bincode::serialize(stats)
+    {
+        Ok(content) => content,
+        Err(_) => return Err(VerifyStatsError::CannotWriteStatsFile(path.to_string())),
+    };
+
+    let mut file =
+    match system.create_file(path)
+    {
+        Ok(file) => file,
+        Err(_) => return Err(VerifyStatsError::CannotWriteStatsFile(path.to_string())),
+    };
+
+    match file.write_all(&content)
+    {
+        Ok(_) => Ok(()),
+        Err(_) => Err(VerifyStatsError::CannotWriteStatsFile(path.to_string())),
+    }
+}
+
+/*  Checks a sample of the cache, warns about and removes entries that don't match their tickets, and
+    adds the round to the totals.  Called at the end of a build.  Nothing here fails the build: a problem
+    with the sampling itself is a warning too. */
+pub fn verify_cache_sample
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &mut SystemType,
+    printer : &mut PrinterType,
+    cache : &mut SysCache<SystemType>,
+    directory_path : &str,
+    sample_size : usize,
+    build_fingerprint : &Ticket,
+    timestamp : u64
+)
+{
+    let stats_path = get_verify_stats_path(directory_path);
+    let mut stats =
+    match read_verify_stats(system, &stats_path)
+    {
+        Ok(stats) => stats,
+        Err(error) =>
+        {
+            printer.print_single_banner_line("   Warning", Color::Yellow, &format!("{}", error));
+            VerifyStats::new()
+        },
+    };
+
+    /*  The count of entries verified so far goes into the seed, so building the same thing over and
+        over still works through different entries. */
+    let mut factory = TicketFactory::new();
+    factory.input_ticket(build_fingerprint.clone());
+    factory.input_str(&stats.entries_verified.to_string());
+
+    let report =
+    match cache.verify_sample(sample_size, factory.result().to_seed())
+    {
+        Ok(report) => report,
+        Err(error) =>
+        {
+            printer.print_single_banner_line("   Warning", Color::Yellow, &format!("Cache sampling failed: {}", error));
+            return;
+        },
+    };
+
+    for ticket in report.corrupt.iter()
+    {
+        printer.print_single_banner_line("   Warning", Color::Yellow,
+            &format!("cache entry {} did not match its ticket and was removed", ticket.human_readable()));
+    }
+
+    stats.add_report(&report, timestamp);
+    match write_verify_stats(system, &stats_path, &stats)
+    {
+        Ok(()) => {},
+        Err(error) => printer.print_single_banner_line("   Warning", Color::Yellow, &format!("{}", error)),
+    }
+}
+
+/*  This is the function that runs when you type "ruler cache-stats" at the command-line.  Prints how
+    many entries the cache has and what sampling at the end of builds has checked so far. */
+pub fn cache_stats
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &SystemType,
+    printer : &mut PrinterType,
+    directory_path : &str,
+    cache_directory_opt : Option<&str>
+)
+-> Result<VerifyStats, VerifyStatsError>
+{
+    let cache = SysCache::new(system.clone(), &directory::get_cache_path(directory_path, cache_directory_opt));
+    let entry_count =
+    match cache.list_entries()
+    {
+        Ok(entries) => entries.len(),
+        Err(error) => return Err(VerifyStatsError::CannotListCache(error)),
+    };
+
+    let stats = read_verify_stats(system, &get_verify_stats_path(directory_path))?;
+    printer.print(&format!("entries in cache: {}", entry_count));
+    printer.print(&format!("entries verified by sampling: {}", stats.entries_verified));
+    printer.print(&format!("corrupt entries found: {}", stats.corrupt_found));
+    printer.print(&format!("verified since last full coverage: {}", stats.verified_since_coverage));
+    printer.print(&format!("last full coverage (estimated): {}",
+        match stats.last_full_coverage_opt
+        {
+            Some(timestamp) => format!("{} microseconds since the epoch", timestamp),
+            None => "never".to_string(),
+        }));
+    Ok(stats)
+}
+
+/*  This is the function that runs when you type "ruler cache verify" at the command-line.  Rehashes
+    every entry in the cache and lists each one that doesn't match its ticket, removing them too with
+    repair.  Ends with a one-line summary. */
+pub fn cache_verify
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &SystemType,
+    printer : &mut PrinterType,
+    directory_path : &str,
+    cache_directory_opt : Option<&str>,
+    repair : bool
+)
+-> Result<SampleReport, ReadWriteError>
+{
+    let mut cache = SysCache::new(system.clone(), &directory::get_cache_path(directory_path, cache_directory_opt));
+    let report = cache.verify_all(repair)?;
+
+    for ticket in report.corrupt.iter()
+    {
+        if repair
+        {
+            printer.print_single_banner_line("   Corrupt", Color::Red,
+                &format!("{} did not match its ticket and was removed", ticket.human_readable()));
+        }
+        else
+        {
+            printer.print_single_banner_line("   Corrupt", Color::Red,
+                &format!("{} does not match its ticket", ticket.human_readable()));
+        }
+    }
+
+    printer.print(&format!("{} entries verified, {} corrupt", report.entry_count, report.corrupt.len()));
+    Ok(report)
+}
+
 #[cfg(test)]
 mod test
 {
@@ -304,19 +912,36 @@ mod test
         SysCache,
         RestoreResult,
         OpenError,
+        StoreError,
+        VerifyStats,
+        get_verify_stats_path,
+        read_verify_stats,
+        verify_cache_sample,
+        cache_verify,
     };
+    use crate::printer::RecordingPrinter;
     use crate::system::
     {
         System,
         fake::FakeSystem
     };
-    use crate::ticket::TicketFactory;
+    use crate::ticket::
+    {
+        Ticket,
+        TicketFactory,
+    };
     use crate::system::util::
     {
         write_str_to_file,
         read_file_to_string,
         file_to_string,
     };
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::
+    {
+        Hash,
+        Hasher,
+    };
     use std::io::Write;
 
     fn make_fake_system_and_cache() -> (FakeSystem, SysCache<FakeSystem>)
@@ -350,6 +975,26 @@ mod test
         assert_eq!(read_file_to_string(&mut system, "apples.txt").unwrap(), "apples\n");
     }
 
+    /*  Copy a file into the cache, check it's still where it was, and that the cache has it too. */
+    #[test]
+    fn copy_and_open()
+    {
+        let (mut system, mut cache) = make_fake_system_and_cache();
+        write_str_to_file(&mut system, "apples.txt", "apples\n").unwrap();
+
+        let ticket = TicketFactory::from_str("apples\n").result();
+        match cache.copy_file_with_ticket(&ticket, "apples.txt")
+        {
+            Ok(()) => {},
+            Err(error) => panic!("Copy failed unexpectedly: {}", error),
+        }
+
+        assert_eq!(read_file_to_string(&mut system, "apples.txt").unwrap(), "apples\n");
+
+        let mut file = cache.open(&ticket).unwrap();
+        assert_eq!(file_to_string(&mut file).unwrap(), "apples\n");
+    }
+
     #[test]
     fn back_up_nonexistent_file()
     {
@@ -571,4 +1216,219 @@ mod test
         let mut reading_file = cache.open(&TicketFactory::from_str("abc").result()).unwrap();
         assert_eq!(file_to_string(&mut reading_file).unwrap(), "abc".to_string());
     }
+
+    #[test]
+    fn store_from_reader_matching_ticket()
+    {
+        let mut system = FakeSystem::new(15);
+        system.create_dir("cache-dir").unwrap();
+        let mut cache = SysCache::new(system, "cache-dir");
+        let ticket = TicketFactory::from_str("abc").result();
+        cache.store_from_reader(&ticket, &mut "abc".as_bytes()).unwrap();
+        cache.store_from_reader(&ticket, &mut "abc".as_bytes()).unwrap();
+
+        let mut reading_file = cache.open(&ticket).unwrap();
+        assert_eq!(file_to_string(&mut reading_file).unwrap(), "abc".to_string());
+    }
+
+    #[test]
+    fn store_from_reader_mismatched_ticket()
+    {
+        let mut system = FakeSystem::new(16);
+        system.create_dir("cache-dir").unwrap();
+        let mut cache = SysCache::new(system, "cache-dir");
+        let ticket = TicketFactory::from_str("abc").result();
+        match cache.store_from_reader(&ticket, &mut "xyz".as_bytes())
+        {
+            Err(StoreError::TicketMismatch(actual_ticket)) =>
+                assert_eq!(actual_ticket, TicketFactory::from_str("xyz").result()),
+            _ => panic!("unexpected result"),
+        }
+
+        match cache.open(&ticket)
+        {
+            Err(OpenError::NotThere) => {},
+            _ => panic!("unexpected result"),
+        }
+    }
+
+    fn get_hash(ticket : &Ticket) -> u64
+    {
+        let mut hasher = DefaultHasher::new();
+        ticket.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /*  Back up a directory target with an ignored swap file in it.  The swap file doesn't go into the
+        cache.  Restore into a directory that has a swap file of its own: everything else comes back,
+        the local swap file stays as it was, and the directory hashes to the ticket it went in with. */
+    #[test]
+    fn back_up_and_restore_directory_leaves_ignored_alone()
+    {
+        let (mut system, mut cache) = make_fake_system_and_cache();
+        write_str_to_file(&mut system, ".rulerignore", "*.swp\n").unwrap();
+        system.create_dir("out").unwrap();
+        system.create_dir("out/stanzas").unwrap();
+        write_str_to_file(&mut system, "out/poem.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "out/stanzas/one.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "out/.poem.txt.swp", "editor state").unwrap();
+        let ticket = TicketFactory::from_directory(&system, "out").unwrap().result();
+
+        cache.back_up_file_with_ticket(&ticket, "out").unwrap();
+        let cache_path = format!("files/{}", ticket.human_readable());
+        assert!(!system.is_dir("out"));
+        assert!(system.is_file(&format!("{}/poem.txt", cache_path)));
+        assert!(!system.is_file(&format!("{}/.poem.txt.swp", cache_path)));
+
+        system.create_dir("out").unwrap();
+        write_str_to_file(&mut system, "out/.poem.txt.swp", "local editor state").unwrap();
+        assert_eq!(cache.restore_file(&ticket, "out"), RestoreResult::Done);
+
+        assert_eq!(read_file_to_string(&system, "out/poem.txt").unwrap(), "Roses are red.\n");
+        assert_eq!(read_file_to_string(&system, "out/stanzas/one.txt").unwrap(), "Violets are violet.\n");
+        assert_eq!(read_file_to_string(&system, "out/.poem.txt.swp").unwrap(), "local editor state");
+        assert!(!system.is_dir(&cache_path));
+        assert_eq!(TicketFactory::from_directory(&system, "out").unwrap().result(), ticket);
+    }
+
+    /*  Make two tickets that agree on their first 8 bytes, so they Hash the same.  Back up a
+        different file with each, and check that they land in distinct cache files named by the
+        full ticket and restore without clobbering each other. */
+    #[test]
+    fn back_up_tickets_sharing_prefix_do_not_collide()
+    {
+        let (mut system, mut cache) = make_fake_system_and_cache();
+
+        let mut apples_bytes = [7u8; 32];
+        let mut bananas_bytes = [7u8; 32];
+        apples_bytes[31] = 1;
+        bananas_bytes[31] = 2;
+        let apples_ticket = Ticket::from_bytes(apples_bytes);
+        let bananas_ticket = Ticket::from_bytes(bananas_bytes);
+
+        assert_eq!(get_hash(&apples_ticket), get_hash(&bananas_ticket));
+        assert_ne!(apples_ticket.human_readable(), bananas_ticket.human_readable());
+
+        write_str_to_file(&mut system, "apples.txt", "apples\n").unwrap();
+        write_str_to_file(&mut system, "bananas.txt", "bananas\n").unwrap();
+        cache.back_up_file_with_ticket(&apples_ticket, "apples.txt").unwrap();
+        cache.back_up_file_with_ticket(&bananas_ticket, "bananas.txt").unwrap();
+
+        assert_eq!(system.list_dir("files").unwrap().len(), 2);
+        assert!(system.is_file(&format!("files/{}", apples_ticket.human_readable())));
+        assert!(system.is_file(&format!("files/{}", bananas_ticket.human_readable())));
+
+        assert_eq!(cache.restore_file(&bananas_ticket, "bananas.txt"), RestoreResult::Done);
+        assert_eq!(cache.restore_file(&apples_ticket, "apples.txt"), RestoreResult::Done);
+        assert_eq!(read_file_to_string(&system, "apples.txt").unwrap(), "apples\n");
+        assert_eq!(read_file_to_string(&system, "bananas.txt").unwrap(), "bananas\n");
+    }
+
+    /*  Back up three files, then overwrite the cache entry for one of them with different content, the
+        way a bad disk might. */
+    fn make_cache_with_corrupt_entry() -> (FakeSystem, SysCache<FakeSystem>, Vec<Ticket>, Ticket)
+    {
+        let (mut system, mut cache) = make_fake_system_and_cache();
+        let mut tickets = vec![];
+        for content in ["apples\n", "bananas\n", "cherries\n"].iter()
+        {
+            write_str_to_file(&mut system, "fruit.txt", content).unwrap();
+            cache.back_up_file("fruit.txt").unwrap();
+            tickets.push(TicketFactory::from_str(content).result());
+        }
+
+        let corrupt_ticket = tickets[1].clone();
+        write_str_to_file(&mut system, &format!("files/{}", corrupt_ticket.human_readable()), "bananaz\n").unwrap();
+        tickets.sort_by_key(|ticket| ticket.human_readable());
+        (system, cache, tickets, corrupt_ticket)
+    }
+
+    /*  The same seed picks the same entries.  Sampling everything finds the corrupt entry and removes
+        it, leaving the good ones. */
+    #[test]
+    fn verify_sample_finds_and_removes_corrupt_entry()
+    {
+        let (system, mut cache, tickets, corrupt_ticket) = make_cache_with_corrupt_entry();
+        assert_eq!(cache.list_entries().unwrap(), tickets);
+
+        let (_other_system, mut other_cache, _tickets, _corrupt_ticket) = make_cache_with_corrupt_entry();
+        let first = other_cache.verify_sample(1, 42).unwrap();
+        let (_other_system, mut other_cache, _tickets, _corrupt_ticket) = make_cache_with_corrupt_entry();
+        assert_eq!(other_cache.verify_sample(1, 42).unwrap(), first);
+
+        let report = cache.verify_sample(5, 42).unwrap();
+        assert_eq!(report.entry_count, 3);
+        assert_eq!(report.verified.len(), 3);
+        assert_eq!(report.corrupt, vec![corrupt_ticket.clone()]);
+        assert!(!system.is_file(&format!("files/{}", corrupt_ticket.human_readable())));
+        assert_eq!(cache.list_entries().unwrap().len(), 2);
+    }
+
+    /*  Sampling at the end of a build warns about the corrupt entry and keeps the totals in the stats
+        file, where a second round adds to them. */
+    #[test]
+    fn verify_cache_sample_warns_and_records_stats()
+    {
+        let (mut system, mut cache, _tickets, corrupt_ticket) = make_cache_with_corrupt_entry();
+        system.create_dir(".ruler").unwrap();
+        let fingerprint = TicketFactory::from_str("build").result();
+
+        let mut printer = RecordingPrinter::new();
+        verify_cache_sample(&mut system, &mut printer, &mut cache, ".ruler", 3, &fingerprint, 1000);
+        assert_eq!(printer.printed, vec![
+            format!("   Warning cache entry {} did not match its ticket and was removed", corrupt_ticket.human_readable())]);
+
+        assert_eq!(read_verify_stats(&system, &get_verify_stats_path(".ruler")).unwrap(), VerifyStats
+        {
+            entries_verified : 3,
+            corrupt_found : 1,
+            verified_since_coverage : 0,
+            last_full_coverage_opt : Some(1000),
+        });
+
+        let mut printer = RecordingPrinter::new();
+        verify_cache_sample(&mut system, &mut printer, &mut cache, ".ruler", 1, &fingerprint, 2000);
+        assert_eq!(printer.printed, Vec::<String>::new());
+        assert_eq!(read_verify_stats(&system, &get_verify_stats_path(".ruler")).unwrap(), VerifyStats
+        {
+            entries_verified : 4,
+            corrupt_found : 1,
+            verified_since_coverage : 1,
+            last_full_coverage_opt : Some(1000),
+        });
+    }
+
+    /*  Verify lists only the corrupt entry and leaves it in place.  With repair, it goes, and the
+        healthy entries stay. */
+    #[test]
+    fn cache_verify_flags_and_repairs_corrupt_entry()
+    {
+        let (system, _cache, tickets, corrupt_ticket) = make_cache_with_corrupt_entry();
+        let corrupt_path = format!("files/{}", corrupt_ticket.human_readable());
+
+        let mut printer = RecordingPrinter::new();
+        let report = cache_verify(&system, &mut printer, ".ruler", Some("files"), false).unwrap();
+        assert_eq!(report.verified, tickets);
+        assert_eq!(report.corrupt, vec![corrupt_ticket.clone()]);
+        assert_eq!(printer.printed, vec![
+            format!("   Corrupt {} does not match its ticket", corrupt_ticket.human_readable()),
+            "3 entries verified, 1 corrupt".to_string(),
+        ]);
+        assert!(system.is_file(&corrupt_path));
+
+        let mut printer = RecordingPrinter::new();
+        let report = cache_verify(&system, &mut printer, ".ruler", Some("files"), true).unwrap();
+        assert_eq!(report.corrupt, vec![corrupt_ticket.clone()]);
+        assert_eq!(printer.printed[0],
+            format!("   Corrupt {} did not match its ticket and was removed", corrupt_ticket.human_readable()));
+        assert!(!system.is_file(&corrupt_path));
+        for ticket in tickets.iter().filter(|ticket| **ticket != corrupt_ticket)
+        {
+            assert!(system.is_file(&format!("files/{}", ticket.human_readable())));
+        }
+
+        let report = cache_verify(&system, &mut RecordingPrinter::new(), ".ruler", Some("files"), false).unwrap();
+        assert_eq!(report.entry_count, 2);
+        assert_eq!(report.corrupt, vec![]);
+    }
 }
@@ -8,7 +8,12 @@ use crate::blob::
     Blob,
     FileState,
 };
-use std::collections::HashMap;
+use crate::ticket::Ticket;
+use std::collections::
+{
+    BTreeSet,
+    HashMap,
+};
 use serde::
 {
     Serialize,
@@ -53,8 +58,27 @@ pub struct CurrentFileStatesInside
 {
     /*  Map target path to target-history */
     file_states : HashMap<String, FileState>,
+
+    /*  The paths some rule produced, as opposed to ones only ever read as sources.  Once no rule
+        mentions one, it's an orphan. */
+    targets : BTreeSet<String>,
+
+    /*  When the last build started, in microseconds since the epoch, or 0 if no build has recorded it.
+        A later build starting earlier than this means the clock went back. */
+    last_build_time : u64,
+}
+
+/*  The layout before builds recorded their start time. */
+#[derive(Serialize, Deserialize)]
+struct CurrentFileStatesWithoutBuildTime
+{
+    file_states : HashMap<String, FileState>,
+    targets : BTreeSet<String>,
 }
 
+/*  A timestamp no file can have, so a FileState with it never matches on timestamp alone. */
+pub const UNTRUSTED_TIMESTAMP : u64 = u64::MAX;
+
 /*  file_states: For a given target (file path) stores the most recently observed hash of that target along
     with the modified timestamp for the file at that time, and whether it is exectuable. */
 pub struct CurrentFileStates<SystemType : System>
@@ -122,7 +146,31 @@ impl<SystemType : System> CurrentFileStates<SystemType>
         match bincode::deserialize(&content)
         {
             Ok(inside) => Ok(CurrentFileStates::from_inside(system, current_file_statesfile_path, inside)),
-            Err(_) => Err(CurrentFileStatesError::CannotInterpretFile(current_file_statesfile_path)),
+
+            /*  Files written before build times got recorded read as having no build time.  Files
+                written before targets got recorded hold only the map.  Read those as having no targets
+                yet; the next build fills them in. */
+            Err(_) => match bincode::deserialize::<CurrentFileStatesWithoutBuildTime>(&content)
+            {
+                Ok(older) => Ok(CurrentFileStates::from_inside(system, current_file_statesfile_path,
+                    CurrentFileStatesInside
+                    {
+                        file_states : older.file_states,
+                        targets : older.targets,
+                        last_build_time : 0,
+                    })),
+                Err(_) => match bincode::deserialize(&content)
+                {
+                    Ok(file_states) => Ok(CurrentFileStates::from_inside(system, current_file_statesfile_path,
+                        CurrentFileStatesInside
+                        {
+                            file_states : file_states,
+                            targets : BTreeSet::new(),
+                            last_build_time : 0,
+                        })),
+                    Err(_) => Err(CurrentFileStatesError::CannotInterpretFile(current_file_statesfile_path)),
+                },
+            },
         }
     }
 
@@ -145,6 +193,23 @@ impl<SystemType : System> CurrentFileStates<SystemType>
         }
     }
 
+    /*  Like from_file, but when the file doesn't exist, returns an empty CurrentFileStates without
+        creating the file. */
+    pub fn from_file_readonly(
+        system: SystemType,
+        path : String)
+        -> Result<CurrentFileStates<SystemType>, CurrentFileStatesError>
+    {
+        if system.is_file(&path)
+        {
+            Self::read_all_current_file_states_from_file(system, path)
+        }
+        else
+        {
+            Ok(CurrentFileStates::new(system, path))
+        }
+    }
+
     pub fn from_inside(
         system : SystemType,
         path : String,
@@ -179,6 +244,8 @@ impl<SystemType : System> CurrentFileStates<SystemType>
             inside : CurrentFileStatesInside
             {
                 file_states : HashMap::new(),
+                targets : BTreeSet::new(),
+                last_build_time : 0,
             },
         }
     }
@@ -189,6 +256,12 @@ impl<SystemType : System> CurrentFileStates<SystemType>
         self.inside.file_states.insert(target_path, file_state);
     }
 
+    /*  The FileState recorded for the given path, if there is one. */
+    pub fn get_file_state(&self, path : &str) -> Option<&FileState>
+    {
+        self.inside.file_states.get(path)
+    }
+
     /*  Takes a vector of paths and returns a blob with current FileStates for those paths.
 
         If a FileState is not present in the map, this function returns a new, empty FileState instead. */
@@ -213,6 +286,70 @@ impl<SystemType : System> CurrentFileStates<SystemType>
             self.insert_file_state(info.path, info.file_state)
         }
     }
+
+    /*  Like insert_blob, but for the targets of a rule, remembering that a rule produced them. */
+    pub fn insert_target_blob(self : &mut Self, blob : Blob)
+    {
+        for info in blob.get_file_infos().into_iter()
+        {
+            self.inside.targets.insert(info.path.clone());
+            self.insert_file_state(info.path, info.file_state)
+        }
+    }
+
+    /*  The ticket of every file recorded, sources and targets alike. */
+    pub fn get_tickets(&self) -> Vec<Ticket>
+    {
+        self.inside.file_states.values().map(|file_state| file_state.ticket.clone()).collect()
+    }
+
+    /*  Every path some rule produced, in sorted order. */
+    pub fn get_target_paths(&self) -> Vec<String>
+    {
+        self.inside.targets.iter().cloned().collect()
+    }
+
+    /*  When the last build started, if one recorded it. */
+    pub fn get_last_build_time(&self) -> Option<u64>
+    {
+        match self.inside.last_build_time
+        {
+            0 => None,
+            last_build_time => Some(last_build_time),
+        }
+    }
+
+    pub fn set_last_build_time(&mut self, last_build_time : u64)
+    {
+        self.inside.last_build_time = last_build_time;
+    }
+
+    /*  Makes every recorded timestamp unmatchable, so each file gets hashed instead of assumed
+        unchanged because its modified time is the same.  The tickets stay as they were. */
+    pub fn distrust_timestamps(&mut self)
+    {
+        for file_state in self.inside.file_states.values_mut()
+        {
+            file_state.timestamp = UNTRUSTED_TIMESTAMP;
+        }
+    }
+
+    /*  Like distrust_timestamps, but only for the file at path. */
+    pub fn distrust_timestamp(&mut self, path : &str)
+    {
+        match self.inside.file_states.get_mut(path)
+        {
+            Some(file_state) => file_state.timestamp = UNTRUSTED_TIMESTAMP,
+            None => {},
+        }
+    }
+
+        /*  Drops everything recorded about the path. */
+    pub fn forget(&mut self, path : &str)
+    {
+        self.inside.file_states.remove(path);
+        self.inside.targets.remove(path);
+    }
 }
 
 #[cfg(test)]
@@ -222,12 +359,14 @@ mod test
     use crate::current::
     {
         CurrentFileStates,
+        CurrentFileStatesWithoutBuildTime,
         FileState,
         Blob,
         write_file,
     };
     use crate::ticket::{TicketFactory};
     use crate::system::util::read_file;
+    use std::collections::HashMap;
 
     /*  Create a CurrentFileStates, populate with a FileState, then serialize it to binary, and deserialize
         to create a new CurrentFileStates.  Check that the contents of the new CurrentFileStates are the same
@@ -350,4 +489,57 @@ mod test
             current_file_states.take_blob(vec!["src/math.cpp".to_string()]),
             Blob::from_paths(vec!["src/math.cpp".to_string()], |_path|{FileState::empty()}));
     }
+
+    /*  A file written before targets got recorded, holding only the map, still reads, with no targets.
+        Targets inserted as such come back sorted, and forgetting a path drops it entirely. */
+    #[test]
+    fn read_file_without_targets()
+    {
+        let mut system = FakeSystem::new(10);
+        let mut file_states = HashMap::new();
+        file_states.insert("poem.txt".to_string(), FileState::new(TicketFactory::from_str("Roses").result(), 123));
+        write_file(&mut system, "current_file_states.file", &bincode::serialize(&file_states).unwrap()).unwrap();
+
+        let mut current_file_states = CurrentFileStates::from_file(system, "current_file_states.file".to_string()).unwrap();
+        assert!(current_file_states.get_file_state("poem.txt").is_some());
+        assert_eq!(current_file_states.get_target_paths(), Vec::<String>::new());
+
+        current_file_states.insert_target_blob(Blob::from_paths(
+            vec!["stanza2.txt".to_string(), "stanza1.txt".to_string()], |_path|{FileState::empty()}));
+        assert_eq!(current_file_states.get_target_paths(), vec!["stanza1.txt", "stanza2.txt"]);
+
+        current_file_states.forget("stanza1.txt");
+        assert_eq!(current_file_states.get_target_paths(), vec!["stanza2.txt"]);
+        assert!(current_file_states.get_file_state("stanza1.txt").is_none());
+    }
+
+    /*  A file written before build times got recorded still reads, targets and all, with no build time.
+        Distrusting timestamps keeps the tickets but changes the timestamps. */
+    #[test]
+    fn read_file_without_build_time()
+    {
+        let mut system = FakeSystem::new(10);
+        let mut older = CurrentFileStatesWithoutBuildTime
+        {
+            file_states : HashMap::new(),
+            targets : std::collections::BTreeSet::new(),
+        };
+        older.file_states.insert("poem.txt".to_string(), FileState::new(TicketFactory::from_str("Roses").result(), 123));
+        older.targets.insert("poem.txt".to_string());
+        write_file(&mut system, "current_file_states.file", &bincode::serialize(&older).unwrap()).unwrap();
+
+        let mut current_file_states = CurrentFileStates::from_file(system.clone(), "current_file_states.file".to_string()).unwrap();
+        assert_eq!(current_file_states.get_target_paths(), vec!["poem.txt"]);
+        assert_eq!(current_file_states.get_last_build_time(), None);
+
+        current_file_states.set_last_build_time(456);
+        current_file_states.distrust_timestamps();
+        current_file_states.to_file().unwrap();
+
+        let current_file_states = CurrentFileStates::from_file(system, "current_file_states.file".to_string()).unwrap();
+        assert_eq!(current_file_states.get_last_build_time(), Some(456));
+        let file_state = current_file_states.get_file_state("poem.txt").unwrap();
+        assert_eq!(file_state.ticket, TicketFactory::from_str("Roses").result());
+        assert_ne!(file_state.timestamp, 123);
+    }
 }
@@ -0,0 +1,491 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::
+{
+    self,
+    BufRead,
+    BufReader,
+    Write,
+};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use serde::
+{
+    Deserialize,
+    Serialize,
+};
+use termcolor::Color;
+use crate::build::
+{
+    build_with_memo,
+    BuildMemo,
+    BuildParams,
+    BuildSummary,
+};
+use crate::downloader::HttpDownloader;
+use crate::observer::BuildObserver;
+use crate::printer::Printer;
+use crate::progress::
+{
+    get_error_code,
+    get_status,
+    ProgressEvent,
+};
+use crate::system::System;
+use crate::timing;
+use crate::work::
+{
+    WorkError,
+    WorkOption,
+};
+
+/*  A request to the daemon, one per line, as JSON with the kind in the "cmd" field:
+
+        {"cmd":"build"}
+        {"cmd":"build","target":"poem.txt"}
+        {"cmd":"status"}
+        {"cmd":"shutdown"}
+*/
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum DaemonRequest
+{
+    Build
+    {
+        #[serde(default)]
+        target : Option<String>,
+    },
+    Status,
+    Shutdown,
+}
+
+/*  What the daemon sends back besides progress events, one line of JSON each, with the kind in the
+    "event" field, like progress events.  Every request gets exactly one result, last. */
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DaemonReply
+{
+    /*  The answer to a status request: how many builds the daemon has done, how many times it had to
+        load the rules, and whether it has them in memory now. */
+    Status
+    {
+        builds : usize,
+        rule_loads : usize,
+        rules_in_memory : bool,
+    },
+
+    /*  The summary is there when a build got as far as building. */
+    Result
+    {
+        success : bool,
+        message : Option<String>,
+        summary : Option<BuildSummary>,
+    },
+}
+
+#[derive(Debug)]
+pub enum DaemonError
+{
+    CannotListen(String, io::Error),
+    CannotAccept(io::Error),
+}
+
+impl fmt::Display for DaemonError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            DaemonError::CannotListen(address, error) =>
+                write!(formatter, "Cannot listen on {}: {}", address, error),
+
+            DaemonError::CannotAccept(error) =>
+                write!(formatter, "Cannot accept connection: {}", error),
+        }
+    }
+}
+
+/*  Where the daemon listens: a port on localhost, or a unix socket at a path. */
+pub enum DaemonAddress
+{
+    Port(u16),
+    #[cfg(unix)]
+    Socket(String),
+}
+
+/*  Writes what the build tells it to the connection, as progress events.  A client that's gone away
+    just stops getting them: the build goes on regardless. */
+struct StreamingObserver<'a, WriterType : Write>
+{
+    writer : &'a mut WriterType,
+    start_times : HashMap<Vec<String>, u64>,
+    failed : usize,
+    summary_opt : Option<BuildSummary>,
+}
+
+impl<'a, WriterType : Write> StreamingObserver<'a, WriterType>
+{
+    fn new(writer : &'a mut WriterType) -> StreamingObserver<'a, WriterType>
+    {
+        StreamingObserver
+        {
+            writer : writer,
+            start_times : HashMap::new(),
+            failed : 0,
+            summary_opt : None,
+        }
+    }
+
+    fn send(&mut self, event : ProgressEvent)
+    {
+        write_line(self.writer, &event);
+    }
+}
+
+impl<'a, WriterType : Write> BuildObserver for StreamingObserver<'a, WriterType>
+{
+    fn build_started(&mut self, total_nodes : usize)
+    {
+        self.send(ProgressEvent::BuildStarted{total_nodes : total_nodes});
+    }
+
+    fn node_started(&mut self, targets : &[String])
+    {
+        self.start_times.insert(targets.to_vec(), timing::now());
+        self.send(ProgressEvent::NodeStarted{targets : targets.to_vec()});
+    }
+
+    fn command_output(&mut self, targets : &[String], line : &str)
+    {
+        self.send(ProgressEvent::CommandOutput{targets : targets.to_vec(), line : line.to_string()});
+    }
+
+    /*  The duration is from when this thread heard the rule start, which is close enough for
+        watching a build go. */
+    fn node_finished(&mut self, targets : &[String], work_option : &WorkOption)
+    {
+        let duration =
+        match self.start_times.remove(targets)
+        {
+            Some(start_time) => timing::now().saturating_sub(start_time) as f64 / 1_000_000.0,
+            None => 0.0,
+        };
+
+        self.send(
+            ProgressEvent::NodeFinished
+            {
+                targets : targets.to_vec(),
+                status : get_status(work_option).to_string(),
+                duration : duration,
+            });
+    }
+
+    fn node_failed(&mut self, targets : &[String], error : &WorkError)
+    {
+        self.failed += 1;
+        self.send(
+            ProgressEvent::NodeFailed
+            {
+                targets : targets.to_vec(),
+                error_code : get_error_code(error),
+                message : error.to_string(),
+            });
+    }
+
+    fn build_finished(&mut self, success : bool, summary : &BuildSummary)
+    {
+        self.summary_opt = Some(summary.clone());
+        self.send(
+            ProgressEvent::BuildFinished
+            {
+                success : success,
+                failed : self.failed,
+                summary : summary.clone(),
+            });
+    }
+}
+
+fn write_line<WriterType : Write, EventType : Serialize>(writer : &mut WriterType, event : &EventType)
+{
+    match serde_json::to_string(event)
+    {
+        Ok(line) => match writeln!(writer, "{}", line).and_then(|()| writer.flush())
+        {
+            Ok(()) => {},
+            Err(_error) => {},
+        },
+        Err(_error) => {},
+    }
+}
+
+/*  A ruler that stays running between builds, so a build asked of it skips what "ruler build" does
+    every time before building: reading the ruler directory, and parsing and sorting the rules, which
+    it only does again when they change.  Everything a build writes to the ruler directory still gets
+    written, so stopping the daemon, however it happens, loses nothing.  It does count on nothing
+    else changing the ruler directory while it runs. */
+pub struct Daemon<SystemType : System>
+{
+    system : SystemType,
+    params : BuildParams,
+    memo : BuildMemo<SystemType>,
+    build_count : usize,
+}
+
+impl<SystemType : System + 'static> Daemon<SystemType>
+{
+    /*  Builds go by the given params, but for the target, which each request says for itself. */
+    pub fn new(system : SystemType, params : BuildParams) -> Daemon<SystemType>
+    {
+        Daemon
+        {
+            system : system,
+            params : params,
+            memo : BuildMemo::new(),
+            build_count : 0,
+        }
+    }
+
+    /*  Carries out one request, given as a line of JSON, and writes the replies to the writer, a line
+        each, the result last.  A build's progress events come before its result, as it goes.  Returns
+        false if the request was to shut down, true otherwise, even if the request made no sense. */
+    pub fn handle_request<PrinterType : Printer, WriterType : Write>
+    (
+        &mut self,
+        printer : &mut PrinterType,
+        line : &str,
+        writer : &mut WriterType
+    )
+    -> bool
+    {
+        match serde_json::from_str::<DaemonRequest>(line)
+        {
+            Ok(DaemonRequest::Build{target}) =>
+            {
+                let mut observer = StreamingObserver::new(writer);
+                let result = build_with_memo(
+                    self.system.clone(),
+                    &HttpDownloader::new(),
+                    printer,
+                    &mut observer,
+                    self.params.with_goal_target(target),
+                    vec![],
+                    &mut self.memo);
+                self.build_count += 1;
+
+                let summary_opt = observer.summary_opt.take();
+                write_line(writer,
+                    &match result
+                    {
+                        Ok(()) => DaemonReply::Result{success : true, message : None, summary : summary_opt},
+                        Err(error) => DaemonReply::Result{success : false, message : Some(error.to_string()), summary : summary_opt},
+                    });
+                true
+            },
+            Ok(DaemonRequest::Status) =>
+            {
+                write_line(writer,
+                    &DaemonReply::Status
+                    {
+                        builds : self.build_count,
+                        rule_loads : self.memo.load_count,
+                        rules_in_memory : self.memo.has_rules(),
+                    });
+                write_line(writer, &DaemonReply::Result{success : true, message : None, summary : None});
+                true
+            },
+            Ok(DaemonRequest::Shutdown) =>
+            {
+                write_line(writer, &DaemonReply::Result{success : true, message : None, summary : None});
+                false
+            },
+            Err(error) =>
+            {
+                write_line(writer,
+                    &DaemonReply::Result
+                    {
+                        success : false,
+                        message : Some(format!("Cannot understand request: {}", error)),
+                        summary : None,
+                    });
+                true
+            },
+        }
+    }
+
+    /*  Handles requests from one connection, in order, until it closes or asks for a shutdown.
+        Returns false for a shutdown. */
+    fn serve_connection<PrinterType : Printer, ReaderType : BufRead, WriterType : Write>
+    (
+        &mut self,
+        printer : &mut PrinterType,
+        reader : ReaderType,
+        writer : &mut WriterType
+    )
+    -> bool
+    {
+        for line_result in reader.lines()
+        {
+            match line_result
+            {
+                Ok(line) =>
+                {
+                    if line.trim() == ""
+                    {
+                        continue;
+                    }
+
+                    if ! self.handle_request(printer, &line, writer)
+                    {
+                        return false;
+                    }
+                },
+                Err(_error) => return true,
+            }
+        }
+
+        true
+    }
+}
+
+/*  This is the function that runs when you type "ruler daemon" at the command-line.  Listens at the
+    address and serves one connection at a time, so requests run one at a time, in the order they
+    come, and the rest wait their turn.  Returns once a request says to shut down. */
+pub fn run_daemon
+<
+    SystemType : System + 'static,
+    PrinterType : Printer,
+>
+(
+    system : SystemType,
+    printer : &mut PrinterType,
+    params : BuildParams,
+    address : DaemonAddress
+)
+-> Result<(), DaemonError>
+{
+    let mut daemon = Daemon::new(system, params);
+    match address
+    {
+        DaemonAddress::Port(port) =>
+        {
+            let address = format!("127.0.0.1:{}", port);
+            let listener =
+            match TcpListener::bind(&address)
+            {
+                Ok(listener) => listener,
+                Err(error) => return Err(DaemonError::CannotListen(address, error)),
+            };
+
+            printer.print_single_banner_line("    Daemon", Color::Green, &format!("listening on {}", address));
+            for stream_result in listener.incoming()
+            {
+                match stream_result
+                {
+                    Ok(stream) =>
+                    {
+                        if ! daemon.serve_connection(printer, BufReader::new(&stream), &mut &stream)
+                        {
+                            return Ok(());
+                        }
+                    },
+                    Err(error) => return Err(DaemonError::CannotAccept(error)),
+                }
+            }
+        },
+
+        #[cfg(unix)]
+        DaemonAddress::Socket(path) =>
+        {
+            let listener =
+            match UnixListener::bind(&path)
+            {
+                Ok(listener) => listener,
+                Err(error) => return Err(DaemonError::CannotListen(path, error)),
+            };
+
+            printer.print_single_banner_line("    Daemon", Color::Green, &format!("listening on {}", path));
+            for stream_result in listener.incoming()
+            {
+                match stream_result
+                {
+                    Ok(stream) =>
+                    {
+                        if ! daemon.serve_connection(printer, BufReader::new(&stream), &mut &stream)
+                        {
+                            /*  The socket file stays behind otherwise, and the next daemon couldn't bind. */
+                            match std::fs::remove_file(&path)
+                            {
+                                Ok(()) => {},
+                                Err(_error) => {},
+                            }
+                            return Ok(());
+                        }
+                    },
+                    Err(error) => return Err(DaemonError::CannotAccept(error)),
+                }
+            }
+        },
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::build::
+    {
+        BuildParams,
+    };
+    use crate::daemon::Daemon;
+    use crate::printer::EmptyPrinter;
+    use crate::system::fake::FakeSystem;
+    use crate::system::util::write_str_to_file;
+
+    /*  Send the daemon a request, and get back what it replies, a JSON value a line. */
+    fn send(daemon : &mut Daemon<FakeSystem>, request : &str) -> (bool, Vec<serde_json::Value>)
+    {
+        let mut reply = vec![];
+        let keep_going = daemon.handle_request(&mut EmptyPrinter::new(), request, &mut reply);
+        (keep_going, String::from_utf8(reply).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect())
+    }
+
+    /*  Two builds of the poem in a row.  The first loads the rules and builds, the second does
+        nothing, without loading the rules again, and its result says so.  Each reply ends in its
+        result. */
+    #[test]
+    fn daemon_second_build_reuses_loaded_rules()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules",
+            "poem.txt\n:\nverse1.txt\nverse2.txt\n:\nmycat\nverse1.txt\nverse2.txt\npoem.txt\n:\n").unwrap();
+        let mut daemon = Daemon::new(system.clone(), BuildParams::for_test());
+
+        let (keep_going, replies) = send(&mut daemon, r#"{"cmd":"build","target":"poem.txt"}"#);
+        assert!(keep_going);
+        let events : Vec<&str> = replies.iter().map(|reply| reply["event"].as_str().unwrap()).collect();
+        assert_eq!(events, vec!["build_started", "node_started", "node_finished", "build_finished", "result"]);
+        assert_eq!(replies[2]["status"], "built");
+        assert_eq!(replies[4]["success"], true);
+        assert_eq!(replies[4]["summary"]["built"], 1);
+        assert_eq!(daemon.memo.load_count, 1);
+
+        let (_keep_going, replies) = send(&mut daemon, r#"{"cmd":"build","target":"poem.txt"}"#);
+        let result = replies.last().unwrap();
+        assert_eq!(result["event"], "result");
+        assert_eq!(result["success"], true);
+        assert_eq!(result["summary"], serde_json::json!({"built" : 0, "recovered" : 0, "downloaded" : 0, "up_to_date" : 1}));
+        assert_eq!(daemon.memo.load_count, 1);
+        assert_eq!(system.get_command_log().len(), 1);
+
+        let (_keep_going, replies) = send(&mut daemon, r#"{"cmd":"status"}"#);
+        assert_eq!(replies[0], serde_json::json!({"event" : "status", "builds" : 2, "rule_loads" : 1, "rules_in_memory" : true}));
+
+        let (keep_going, replies) = send(&mut daemon, r#"{"cmd":"shutdown"}"#);
+        assert!(!keep_going);
+        assert_eq!(replies.len(), 1);
+    }
+}
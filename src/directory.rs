@@ -13,6 +13,12 @@ use crate::cache::
 {
     SysCache,
 };
+use crate::pin::
+{
+    Pins,
+    PinsError,
+    get_pins_path,
+};
 
 use crate::system::
 {
@@ -23,10 +29,11 @@ use crate::system::
 #[derive(Debug)]
 pub enum InitDirectoryError
 {
-    FailedToCreateDirectory(SystemError),
-    FailedToCreateCacheDirectory(SystemError),
-    FailedToCreateHistoryDirectory(SystemError),
+    FailedToCreateDirectory(String, SystemError),
+    FailedToCreateCacheDirectory(String, SystemError),
+    FailedToCreateHistoryDirectory(String, SystemError),
     FailedToReadCurrentFileStates(CurrentFileStatesError),
+    FailedToReadPins(PinsError),
 }
 
 impl fmt::Display for InitDirectoryError
@@ -35,25 +42,59 @@ impl fmt::Display for InitDirectoryError
     {
         match self
         {
-            InitDirectoryError::FailedToCreateDirectory(error) =>
-                write!(formatter, "Failed to create directory: {}", error),
+            InitDirectoryError::FailedToCreateDirectory(path, error) =>
+                write!(formatter, "Failed to create directory {}: {}", path, error),
 
-            InitDirectoryError::FailedToCreateCacheDirectory(error) =>
-                write!(formatter, "Failed to create cache directory: {}", error),
+            InitDirectoryError::FailedToCreateCacheDirectory(path, error) =>
+                write!(formatter, "Failed to create cache directory {}: {}", path, error),
 
-            InitDirectoryError::FailedToCreateHistoryDirectory(error) =>
-                write!(formatter, "Failed to create history directory: {}", error),
+            InitDirectoryError::FailedToCreateHistoryDirectory(path, error) =>
+                write!(formatter, "Failed to create history directory {}: {}", path, error),
 
             InitDirectoryError::FailedToReadCurrentFileStates(error) =>
                 write!(formatter, "Failed to read current_file_states file: {}", error),
+
+            InitDirectoryError::FailedToReadPins(error) =>
+                write!(formatter, "{}", error),
         }
     }
 }
 
+/*  Where the cache lives: the given cache directory if there is one, so several checkouts can share
+    a cache while each keeps its own history and current file states, otherwise inside the ruler
+    directory. */
+pub fn get_cache_path(directory : &str, cache_directory_opt : Option<&str>) -> String
+{
+    match cache_directory_opt
+    {
+        Some(cache_directory) => cache_directory.to_string(),
+        None => format!("{}/cache", directory),
+    }
+}
+
+/*  Where the current file states live.  Each variant keeps its own, so switching variants doesn't
+    make either one forget what it built.  Histories and the cache go by ticket, so the variants
+    share those. */
+pub fn get_current_file_states_path(directory : &str, variant_opt : Option<&str>) -> String
+{
+    match variant_opt
+    {
+        Some(variant) => format!("{}/current_file_states.{}", directory, variant),
+        None => format!("{}/current_file_states", directory),
+    }
+}
+
+/*  Opens the ruler directory for a subcommand that changes things (build, clean and so on), creating
+    the directory and its subdirectories if they aren't there yet.  A cache directory given separately
+    gets created along with any missing parents.  With compress_history, rule histories get written
+    gzipped. */
 pub fn init<SystemType : System>
 (
     system : &mut SystemType,
-    directory : &str
+    directory : &str,
+    cache_directory_opt : Option<&str>,
+    variant_opt : Option<&str>,
+    compress_history : bool
 )
 -> Result<Elements<SystemType>, InitDirectoryError>
 {
@@ -62,18 +103,18 @@ pub fn init<SystemType : System>
         match system.create_dir(directory)
         {
             Ok(_) => {},
-            Err(error) => return Err(InitDirectoryError::FailedToCreateDirectory(error)),
+            Err(error) => return Err(InitDirectoryError::FailedToCreateDirectory(directory.to_string(), error)),
         }
     }
 
-    let cache_path = format!("{}/cache", directory);
+    let cache_path = get_cache_path(directory, cache_directory_opt);
 
     if ! system.is_dir(&cache_path)
     {
-        match system.create_dir(&cache_path)
+        match system.create_dir_all(&cache_path)
         {
             Ok(_) => {},
-            Err(error) => return Err(InitDirectoryError::FailedToCreateCacheDirectory(error)),
+            Err(error) => return Err(InitDirectoryError::FailedToCreateCacheDirectory(cache_path, error)),
         }
     }
 
@@ -84,11 +125,11 @@ pub fn init<SystemType : System>
         match system.create_dir(&history_path)
         {
             Ok(_) => {},
-            Err(error) => return Err(InitDirectoryError::FailedToCreateHistoryDirectory(error)),
+            Err(error) => return Err(InitDirectoryError::FailedToCreateHistoryDirectory(history_path, error)),
         }
     }
 
-    let current_file_statesfile = format!("{}/current_file_states", directory);
+    let current_file_statesfile = get_current_file_states_path(directory, variant_opt);
 
     Ok(Elements
     {
@@ -98,7 +139,45 @@ pub fn init<SystemType : System>
             Err(error) => return Err(InitDirectoryError::FailedToReadCurrentFileStates(error)),
         },
         cache : SysCache::new(system.clone(), &cache_path),
+        history : History::new_with_compression(system.clone(), &history_path, compress_history),
+        pins : match Pins::from_file(system, &get_pins_path(directory))
+        {
+            Ok(pins) => pins,
+            Err(error) => return Err(InitDirectoryError::FailedToReadPins(error)),
+        },
+    })
+}
+
+/*  Opens the ruler directory for a subcommand that only looks.  Creates nothing: if the directory or
+    anything in it is missing, the elements come back empty, as if nothing had been built yet.  That
+    way analysis works on a read-only checkout, and doesn't leave an empty ruler directory behind. */
+pub fn open_readonly<SystemType : System>
+(
+    system : &SystemType,
+    directory : &str,
+    cache_directory_opt : Option<&str>,
+    variant_opt : Option<&str>
+)
+-> Result<Elements<SystemType>, InitDirectoryError>
+{
+    let cache_path = get_cache_path(directory, cache_directory_opt);
+    let history_path = format!("{}/history", directory);
+    let current_file_statesfile = get_current_file_states_path(directory, variant_opt);
+
+    Ok(Elements
+    {
+        current_file_states : match CurrentFileStates::from_file_readonly(system.clone(), current_file_statesfile)
+        {
+            Ok(current_file_states) => current_file_states,
+            Err(error) => return Err(InitDirectoryError::FailedToReadCurrentFileStates(error)),
+        },
+        cache : SysCache::new(system.clone(), &cache_path),
         history : History::new(system.clone(), &history_path),
+        pins : match Pins::from_file(system, &get_pins_path(directory))
+        {
+            Ok(pins) => pins,
+            Err(error) => return Err(InitDirectoryError::FailedToReadPins(error)),
+        },
     })
 }
 
@@ -107,6 +186,7 @@ pub struct Elements<SystemType : System>
     pub current_file_states : CurrentFileStates<SystemType>,
     pub cache : SysCache<SystemType>,
     pub history : History<SystemType>,
+    pub pins : Pins,
 }
 
 #[cfg(test)]
@@ -124,7 +204,7 @@ mod test
         let mut system = FakeSystem::new(180);
 
         let _elements =
-            match directory::init(&mut system, "ruler-directory")
+            match directory::init(&mut system, "ruler-directory", None, None, false)
             {
                 Ok(elements) => elements,
                 Err(error) => panic!("Failed to init directory error: {}", error)
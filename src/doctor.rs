@@ -0,0 +1,390 @@
+use std::fmt;
+use termcolor::Color;
+use crate::cache::SysCache;
+use crate::current::CurrentFileStates;
+use crate::directory::get_cache_path;
+use crate::history::History;
+use crate::printer::Printer;
+use crate::system::System;
+use crate::ticket::Ticket;
+
+/*  Something wrong in the ruler directory that doctor can't fix on its own. */
+#[derive(Debug, PartialEq)]
+pub enum Problem
+{
+    NoRulerDirectory(String),
+    CannotCreateDirectory(String, String),
+    CannotListDirectory(String, String),
+    BrokenRuleHistory(String),
+    CorruptCacheEntry(Ticket),
+    UnreadableCacheEntry(Ticket, String),
+    BrokenCurrentFileStates(String),
+}
+
+impl fmt::Display for Problem
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Problem::NoRulerDirectory(path) =>
+                write!(formatter, "No ruler directory at {} (nothing has been built here yet)", path),
+
+            Problem::CannotCreateDirectory(path, error) =>
+                write!(formatter, "Missing directory {} could not be created: {}", path, error),
+
+            Problem::CannotListDirectory(path, error) =>
+                write!(formatter, "Cannot list {}: {}", path, error),
+
+            Problem::BrokenRuleHistory(message) =>
+                write!(formatter, "{}", message),
+
+            Problem::CorruptCacheEntry(ticket) =>
+                write!(formatter, "Cache entry {} does not match its ticket", ticket),
+
+            Problem::UnreadableCacheEntry(ticket, error) =>
+                write!(formatter, "Cannot read cache entry {}: {}", ticket, error),
+
+            Problem::BrokenCurrentFileStates(message) =>
+                write!(formatter, "{}", message),
+        }
+    }
+}
+
+/*  What doctor found: what it fixed, what it couldn't, and how much it looked at. */
+#[derive(Debug, PartialEq)]
+pub struct DoctorReport
+{
+    pub fixed : Vec<String>,
+    pub problems : Vec<Problem>,
+    pub cache_entry_count : usize,
+    pub rule_history_count : usize,
+    pub current_file_states_count : usize,
+}
+
+impl DoctorReport
+{
+    fn new() -> DoctorReport
+    {
+        DoctorReport
+        {
+            fixed : vec![],
+            problems : vec![],
+            cache_entry_count : 0,
+            rule_history_count : 0,
+            current_file_states_count : 0,
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool
+    {
+        self.problems.len() == 0
+    }
+
+    fn get_summary(&self) -> String
+    {
+        format!("{}: checked {} cache entries, {} rule histories and {} current file states",
+            if self.is_healthy()
+            {
+                "Healthy".to_string()
+            }
+            else
+            {
+                format!("{} problems", self.problems.len())
+            },
+            self.cache_entry_count,
+            self.rule_history_count,
+            self.current_file_states_count)
+    }
+}
+
+/*  The last component of a path from list_dir. */
+fn get_name(path : &str) -> &str
+{
+    match path.rsplit_once('/')
+    {
+        Some((_directory, name)) => name,
+        None => path,
+    }
+}
+
+/*  Creates a subdirectory of the ruler directory if it's missing, which is all it takes to fix that. */
+fn ensure_directory<SystemType : System>
+(
+    system : &mut SystemType,
+    report : &mut DoctorReport,
+    path : &str
+)
+{
+    if system.is_dir(path)
+    {
+        return;
+    }
+
+    match system.create_dir_all(path)
+    {
+        Ok(()) => report.fixed.push(format!("Created missing directory {}", path)),
+        Err(error) => report.problems.push(Problem::CannotCreateDirectory(path.to_string(), error.to_string())),
+    }
+}
+
+/*  Reads every rule history, counting them, and notes the ones that don't parse.  Files not named
+    like a ticket aren't rule histories, so they're left alone. */
+fn check_history<SystemType : System>
+(
+    system : &SystemType,
+    report : &mut DoctorReport,
+    history_path : &str
+)
+{
+    let mut paths =
+    match system.list_dir(history_path)
+    {
+        Ok(paths) => paths,
+        Err(error) =>
+        {
+            report.problems.push(Problem::CannotListDirectory(history_path.to_string(), error.to_string()));
+            return;
+        },
+    };
+    paths.sort();
+
+    let history = History::new(system.clone(), history_path);
+    for path in paths.iter().filter(|path| system.is_file(path))
+    {
+        let rule_ticket =
+        match Ticket::from_human_readable(get_name(path))
+        {
+            Ok(rule_ticket) => rule_ticket,
+            Err(_) => continue,
+        };
+
+        report.rule_history_count += 1;
+        match history.read_rule_history(&rule_ticket)
+        {
+            Ok(_rule_history) => {},
+            Err(error) => report.problems.push(Problem::BrokenRuleHistory(error.to_string())),
+        }
+    }
+}
+
+/*  Rehashes every cache entry, and notes the ones that don't match their tickets.  Unlike the sampling
+    at the end of a build, this leaves them where they are. */
+fn check_cache<SystemType : System>
+(
+    system : &SystemType,
+    report : &mut DoctorReport,
+    cache_path : &str
+)
+{
+    let cache = SysCache::new(system.clone(), cache_path);
+    let entries =
+    match cache.list_entries()
+    {
+        Ok(entries) => entries,
+        Err(error) =>
+        {
+            report.problems.push(Problem::CannotListDirectory(cache_path.to_string(), error.to_string()));
+            return;
+        },
+    };
+
+    report.cache_entry_count = entries.len();
+    for ticket in entries
+    {
+        match cache.entry_matches_ticket(&ticket)
+        {
+            Ok(true) => {},
+            Ok(false) => report.problems.push(Problem::CorruptCacheEntry(ticket)),
+            Err(error) => report.problems.push(Problem::UnreadableCacheEntry(ticket, error.to_string())),
+        }
+    }
+}
+
+/*  Reads the current file states, those of every variant included, and notes the ones that don't
+    parse. */
+fn check_current_file_states<SystemType : System>
+(
+    system : &SystemType,
+    report : &mut DoctorReport,
+    directory_path : &str
+)
+{
+    let mut paths =
+    match system.list_dir(directory_path)
+    {
+        Ok(paths) => paths,
+        Err(error) =>
+        {
+            report.problems.push(Problem::CannotListDirectory(directory_path.to_string(), error.to_string()));
+            return;
+        },
+    };
+    paths.sort();
+
+    for path in paths.iter().filter(|path| system.is_file(path))
+    {
+        let name = get_name(path);
+        if name != "current_file_states" && ! name.starts_with("current_file_states.")
+        {
+            continue;
+        }
+
+        report.current_file_states_count += 1;
+        match CurrentFileStates::from_file_readonly(system.clone(), path.clone())
+        {
+            Ok(_current_file_states) => {},
+            Err(error) => report.problems.push(Problem::BrokenCurrentFileStates(error.to_string())),
+        }
+    }
+}
+
+/*  This is the function that runs when you type "ruler doctor" at the command-line.  Checks that the
+    ruler directory has its subdirectories, that every rule history parses, that every cache entry
+    still matches its ticket, and that the current file states can be read.  Missing subdirectories
+    get created.  Everything else only gets reported, ending with a one-line summary. */
+pub fn doctor
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &mut SystemType,
+    printer : &mut PrinterType,
+    directory_path : &str,
+    cache_directory_opt : Option<&str>
+)
+-> DoctorReport
+{
+    let mut report = DoctorReport::new();
+
+    if system.is_dir(directory_path)
+    {
+        let cache_path = get_cache_path(directory_path, cache_directory_opt);
+        let history_path = format!("{}/history", directory_path);
+        ensure_directory(system, &mut report, &cache_path);
+        ensure_directory(system, &mut report, &history_path);
+
+        check_history(system, &mut report, &history_path);
+        check_cache(system, &mut report, &cache_path);
+        check_current_file_states(system, &mut report, directory_path);
+    }
+    else
+    {
+        report.problems.push(Problem::NoRulerDirectory(directory_path.to_string()));
+    }
+
+    for fix in report.fixed.iter()
+    {
+        printer.print_single_banner_line("     Fixed", Color::Green, fix);
+    }
+
+    for problem in report.problems.iter()
+    {
+        printer.print_single_banner_line("   Problem", Color::Red, &format!("{}", problem));
+    }
+
+    printer.print(&report.get_summary());
+    report
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::build::
+    {
+        build,
+        BuildParams,
+    };
+    use crate::doctor::
+    {
+        doctor,
+        Problem,
+    };
+    use crate::printer::
+    {
+        EmptyPrinter,
+        RecordingPrinter,
+    };
+    use crate::ticket::TicketFactory;
+    use crate::system::
+    {
+        System,
+        fake::FakeSystem,
+    };
+    use crate::system::util::write_str_to_file;
+
+    /*  Build a poem twice, changing a verse in between so the first poem lands in the cache. */
+    fn make_built_system() -> FakeSystem
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "poem.txt\n:\nverse1.txt\n:\nmycat\nverse1.txt\npoem.txt\n:\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+        system.time_passes(1);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are blue.\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        system
+    }
+
+    /*  A directory fresh from a couple of builds is healthy, and doctor says what it checked. */
+    #[test]
+    fn doctor_healthy_directory()
+    {
+        let mut system = make_built_system();
+        let mut printer = RecordingPrinter::new();
+        let report = doctor(&mut system, &mut printer, ".ruler", None);
+
+        assert!(report.is_healthy());
+        assert_eq!(report.fixed, Vec::<String>::new());
+        assert_eq!(report.cache_entry_count, 1);
+        assert_eq!(report.rule_history_count, 1);
+        assert_eq!(report.current_file_states_count, 1);
+        assert_eq!(printer.printed, vec!["Healthy: checked 1 cache entries, 1 rule histories and 1 current file states"]);
+    }
+
+    /*  Overwrite the one cache entry with something else, and doctor reports exactly that entry,
+        leaving it in place. */
+    #[test]
+    fn doctor_reports_corrupt_cache_entry()
+    {
+        let mut system = make_built_system();
+        let ticket = TicketFactory::from_str("Roses are red.\n").result();
+        let cache_path = format!(".ruler/cache/{}", ticket.human_readable());
+        assert!(system.is_file(&cache_path));
+        write_str_to_file(&mut system, &cache_path, "Roses are plaid.\n").unwrap();
+
+        let mut printer = RecordingPrinter::new();
+        let report = doctor(&mut system, &mut printer, ".ruler", None);
+
+        assert!(!report.is_healthy());
+        assert_eq!(report.problems, vec![Problem::CorruptCacheEntry(ticket.clone())]);
+        assert_eq!(printer.printed, vec![
+            format!("   Problem Cache entry {} does not match its ticket", ticket),
+            "1 problems: checked 1 cache entries, 1 rule histories and 1 current file states".to_string(),
+        ]);
+        assert!(system.is_file(&cache_path));
+    }
+
+    /*  Missing subdirectories get created and count as fixed, not as problems.  A missing ruler
+        directory is a problem, and doctor doesn't create it. */
+    #[test]
+    fn doctor_creates_missing_subdirectories()
+    {
+        let mut system = FakeSystem::new(10);
+        let report = doctor(&mut system, &mut EmptyPrinter::new(), ".ruler", None);
+        assert_eq!(report.problems, vec![Problem::NoRulerDirectory(".ruler".to_string())]);
+        assert!(!system.is_dir(".ruler"));
+
+        system.create_dir(".ruler").unwrap();
+        let report = doctor(&mut system, &mut EmptyPrinter::new(), ".ruler", None);
+        assert!(report.is_healthy());
+        assert_eq!(report.fixed, vec![
+            "Created missing directory .ruler/cache",
+            "Created missing directory .ruler/history",
+        ]);
+        assert!(system.is_dir(".ruler/cache"));
+        assert!(system.is_dir(".ruler/history"));
+    }
+}
@@ -10,7 +10,12 @@ use reqwest::
 use std::fmt;
 use futures::StreamExt;
 use std::io::Write;
+#[cfg(test)]
+use std::collections::HashMap;
+#[cfg(test)]
+use std::sync::Mutex;
 
+#[derive(Debug)]
 pub enum DownloadError
 {
     UrlInaccessible(String),
@@ -117,3 +122,113 @@ pub async fn download_string(url : &str) -> Result<String, DownloadError>
         Err(_error) => return Err(DownloadError::UrlInaccessible(url.to_string())),
     }
 }
+
+/*  Appeal to the url and return the bytes that download, or an appropriate error. */
+#[tokio::main]
+pub async fn download_bytes(url : &str) -> Result<Vec<u8>, DownloadError>
+{
+    match get(url).await
+    {
+        Ok(response) =>
+        {
+            if response.status() != StatusCode::OK
+            {
+                return Err(DownloadError::UrlInaccessible(url.to_string()));
+            }
+            match response.bytes().await
+            {
+                Ok(bytes) => Ok(bytes.to_vec()),
+                Err(_) => return Err(DownloadError::FailedMidDownload(url.to_string())),
+            }
+        },
+        Err(_error) => return Err(DownloadError::UrlInaccessible(url.to_string())),
+    }
+}
+
+/*  Fetches the content at a url.  The real implementation goes over HTTP, the fake one
+    (for testing) serves content from a map, the way FakeSystem stands in for the filesystem. */
+pub trait Downloader
+{
+    fn download_string(&self, url : &str) -> Result<String, DownloadError>;
+    fn download_bytes(&self, url : &str) -> Result<Vec<u8>, DownloadError>;
+}
+
+pub struct HttpDownloader
+{
+}
+
+impl HttpDownloader
+{
+    pub fn new() -> HttpDownloader
+    {
+        HttpDownloader{}
+    }
+}
+
+impl Downloader for HttpDownloader
+{
+    fn download_string(&self, url : &str) -> Result<String, DownloadError>
+    {
+        download_string(url)
+    }
+
+    fn download_bytes(&self, url : &str) -> Result<Vec<u8>, DownloadError>
+    {
+        download_bytes(url)
+    }
+}
+
+#[cfg(test)]
+pub struct FakeDownloader
+{
+    resources : HashMap<String, Vec<u8>>,
+    requests : Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+impl FakeDownloader
+{
+    pub fn new() -> FakeDownloader
+    {
+        FakeDownloader
+        {
+            resources : HashMap::new(),
+            requests : Mutex::new(vec![]),
+        }
+    }
+
+    /*  Every url asked for so far, in order, whether or not it was there. */
+    pub fn requests(&self) -> Vec<String>
+    {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /*  Makes the given content available at the given url. */
+    pub fn serve(&mut self, url : &str, content : &[u8])
+    {
+        self.resources.insert(url.to_string(), content.to_vec());
+    }
+}
+
+#[cfg(test)]
+impl Downloader for FakeDownloader
+{
+    fn download_string(&self, url : &str) -> Result<String, DownloadError>
+    {
+        match String::from_utf8(self.download_bytes(url)?)
+        {
+            Ok(s) => Ok(s),
+            Err(_) => Err(DownloadError::FailedMidDownload(url.to_string())),
+        }
+    }
+
+    fn download_bytes(&self, url : &str) -> Result<Vec<u8>, DownloadError>
+    {
+        self.requests.lock().unwrap().push(url.to_string());
+        match self.resources.get(url)
+        {
+            Some(content) => Ok(content.clone()),
+            None => Err(DownloadError::UrlInaccessible(url.to_string())),
+        }
+    }
+}
@@ -0,0 +1,451 @@
+use std::fmt;
+use crate::directory::get_cache_path;
+use crate::printer::Printer;
+use crate::system::System;
+
+/*  A number of files and the bytes they take up together. */
+#[derive(Debug, PartialEq, Clone)]
+pub struct Usage
+{
+    pub count : u64,
+    pub bytes : u64,
+}
+
+impl Usage
+{
+    fn new() -> Usage
+    {
+        Usage
+        {
+            count : 0,
+            bytes : 0,
+        }
+    }
+
+    fn add(&mut self, bytes : u64)
+    {
+        self.count += 1;
+        self.bytes += bytes;
+    }
+}
+
+/*  One file in the cache.  Cache files are named by the human-readable ticket of their content. */
+#[derive(Debug, PartialEq, Clone)]
+pub struct BlobUsage
+{
+    pub ticket : String,
+    pub bytes : u64,
+}
+
+/*  The breakdown of what's in the ruler directory.  Anything that doesn't belong to a known
+    component goes under other, and entries which couldn't be read are listed in skipped along with
+    the reason. */
+#[derive(Debug, PartialEq)]
+pub struct DiskUsage
+{
+    pub cache : Usage,
+    pub largest_blobs : Vec<BlobUsage>,
+    pub history : Usage,
+    pub current_file_states : Usage,
+    pub logs : Usage,
+    pub other : Usage,
+    pub skipped : Vec<(String, String)>,
+}
+
+impl DiskUsage
+{
+    pub fn total_bytes(&self) -> u64
+    {
+        self.cache.bytes
+        + self.history.bytes
+        + self.current_file_states.bytes
+        + self.logs.bytes
+        + self.other.bytes
+    }
+}
+
+pub enum DuError
+{
+    DirectoryNotFound(String),
+}
+
+impl fmt::Display for DuError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            DuError::DirectoryNotFound(path) =>
+                write!(formatter, "Ruler directory not found: {}", path),
+        }
+    }
+}
+
+/*  Recursively lists the files under path along with their sizes.  An entry that can't be listed or
+    measured doesn't stop the walk, it goes in skipped with the error. */
+fn walk_files<SystemType : System>
+(
+    system : &SystemType,
+    path : &str,
+    files : &mut Vec<(String, u64)>,
+    skipped : &mut Vec<(String, String)>
+)
+{
+    let entries =
+    match system.list_dir(path)
+    {
+        Ok(entries) => entries,
+        Err(error) =>
+        {
+            skipped.push((path.to_string(), format!("{}", error)));
+            return;
+        },
+    };
+
+    for entry in entries
+    {
+        if system.is_dir(&entry)
+        {
+            walk_files(system, &entry, files, skipped);
+        }
+        else
+        {
+            match system.get_file_len(&entry)
+            {
+                Ok(len) => files.push((entry, len)),
+                Err(error) => skipped.push((entry, format!("{}", error))),
+            }
+        }
+    }
+}
+
+/*  Walks the ruler directory, and the cache if it's kept elsewhere, and sorts each file into a
+    component by where it sits:
+        cache/<ticket>          a cache blob (or <ticket> right in cache_directory_opt)
+        history/<ticket>        a rule history
+        current_file_states     the current file states
+        timings, logs/...       records of past builds
+    Everything else (including partial downloads in the cache's inbox) counts as other.
+    Keeps the largest_count largest blobs, biggest first, ties broken by ticket. */
+pub fn measure<SystemType : System>
+(
+    system : &SystemType,
+    directory_path : &str,
+    cache_directory_opt : Option<&str>,
+    largest_count : usize
+)
+-> Result<DiskUsage, DuError>
+{
+    if ! system.is_dir(directory_path)
+    {
+        return Err(DuError::DirectoryNotFound(directory_path.to_string()));
+    }
+
+    let mut files = vec![];
+    let mut skipped = vec![];
+    walk_files(system, directory_path, &mut files, &mut skipped);
+
+    let prefix = format!("{}/", directory_path);
+    let cache_path = get_cache_path(directory_path, cache_directory_opt);
+    let cache_prefix = format!("{}/", cache_path);
+    if ! cache_path.starts_with(&prefix) && system.is_dir(&cache_path)
+    {
+        walk_files(system, &cache_path, &mut files, &mut skipped);
+    }
+
+    let mut usage = DiskUsage
+    {
+        cache : Usage::new(),
+        largest_blobs : vec![],
+        history : Usage::new(),
+        current_file_states : Usage::new(),
+        logs : Usage::new(),
+        other : Usage::new(),
+        skipped : vec![],
+    };
+
+    let mut blobs = vec![];
+    for (path, bytes) in files
+    {
+        match path.strip_prefix(&cache_prefix)
+        {
+            Some(ticket) if ! ticket.contains('/') =>
+            {
+                usage.cache.add(bytes);
+                blobs.push(BlobUsage{ticket : ticket.to_string(), bytes : bytes});
+                continue;
+            },
+            _ => {},
+        }
+
+        let relative = match path.strip_prefix(&prefix)
+        {
+            Some(relative) => relative,
+            None => &path,
+        };
+
+        let components : Vec<&str> = relative.split('/').collect();
+        match components.as_slice()
+        {
+            ["history", _rule_ticket] => usage.history.add(bytes),
+            ["current_file_states"] => usage.current_file_states.add(bytes),
+            [name] if name.starts_with("current_file_states.") => usage.current_file_states.add(bytes),
+            ["timings"] => usage.logs.add(bytes),
+            ["logs", ..] => usage.logs.add(bytes),
+            _ => usage.other.add(bytes),
+        }
+    }
+
+    blobs.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.ticket.cmp(&b.ticket)));
+    blobs.truncate(largest_count);
+    usage.largest_blobs = blobs;
+    usage.skipped = skipped;
+    Ok(usage)
+}
+
+/*  Formats a number of bytes in the largest binary unit that leaves at least 1 in front of the
+    decimal point, e.g. 512 B, 1.5 KiB, 20.0 MiB. */
+pub fn format_size(bytes : u64) -> String
+{
+    let units = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024
+    {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit_index = 0;
+    while value >= 1024.0 && unit_index + 1 < units.len()
+    {
+        value /= 1024.0;
+        unit_index += 1;
+    }
+    format!("{:.1} {}", value, units[unit_index])
+}
+
+pub fn json_string(text : &str) -> String
+{
+    let mut out = String::from("\"");
+    for c in text.chars()
+    {
+        match c
+        {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn usage_json(usage : &Usage) -> String
+{
+    format!("{{\"count\":{},\"bytes\":{}}}", usage.count, usage.bytes)
+}
+
+/*  Renders the usage as a single line of JSON:
+        {"total_bytes":N,
+         "cache":{"count":N,"bytes":N,"largest":[{"ticket":"...","bytes":N},...]},
+         "history":{"count":N,"bytes":N},
+         "current_file_states":{"count":N,"bytes":N},
+         "logs":{"count":N,"bytes":N},
+         "other":{"count":N,"bytes":N},
+         "skipped":[{"path":"...","error":"..."},...]} */
+pub fn to_json(usage : &DiskUsage) -> String
+{
+    let largest : Vec<String> = usage.largest_blobs.iter().map(
+        |blob| format!("{{\"ticket\":{},\"bytes\":{}}}", json_string(&blob.ticket), blob.bytes)).collect();
+
+    let skipped : Vec<String> = usage.skipped.iter().map(
+        |(path, error)| format!("{{\"path\":{},\"error\":{}}}", json_string(path), json_string(error))).collect();
+
+    format!("{{\"total_bytes\":{},\"cache\":{{\"count\":{},\"bytes\":{},\"largest\":[{}]}},\
+\"history\":{},\"current_file_states\":{},\"logs\":{},\"other\":{},\"skipped\":[{}]}}",
+        usage.total_bytes(),
+        usage.cache.count,
+        usage.cache.bytes,
+        largest.join(","),
+        usage_json(&usage.history),
+        usage_json(&usage.current_file_states),
+        usage_json(&usage.logs),
+        usage_json(&usage.other),
+        skipped.join(","))
+}
+
+/*  This is the function that runs when you type "ruler du" at the command-line.  Measures the ruler
+    directory and prints the total and each component, either as a table or as JSON. */
+pub fn du
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &SystemType,
+    printer : &mut PrinterType,
+    directory_path : &str,
+    cache_directory_opt : Option<&str>,
+    largest_count : usize,
+    json : bool
+)
+-> Result<DiskUsage, DuError>
+{
+    let usage = measure(system, directory_path, cache_directory_opt, largest_count)?;
+
+    if json
+    {
+        printer.print(&to_json(&usage));
+        return Ok(usage);
+    }
+
+    for (path, error) in usage.skipped.iter()
+    {
+        printer.error(&format!("Note: skipped {}: {}", path, error));
+    }
+
+    printer.print(&format!("{:<20} {:>10}", "Total", format_size(usage.total_bytes())));
+    for (name, component) in [
+        ("Cache blobs", &usage.cache),
+        ("Rule histories", &usage.history),
+        ("Current states", &usage.current_file_states),
+        ("Logs", &usage.logs),
+        ("Other", &usage.other),
+    ]
+    {
+        printer.print(&format!("{:<20} {:>10}  ({} files)", name, format_size(component.bytes), component.count));
+    }
+
+    if usage.largest_blobs.len() > 0
+    {
+        printer.print("Largest cache blobs:");
+        for blob in usage.largest_blobs.iter()
+        {
+            printer.print(&format!("{:>10}  {}", format_size(blob.bytes), blob.ticket));
+        }
+    }
+
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::du::
+    {
+        measure,
+        format_size,
+        to_json,
+        BlobUsage,
+        Usage,
+    };
+    use crate::system::
+    {
+        System,
+        fake::FakeSystem,
+    };
+    use crate::system::util::write_str_to_file;
+
+    fn make_ruler_directory() -> FakeSystem
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir(".ruler").unwrap();
+        system.create_dir(".ruler/cache").unwrap();
+        system.create_dir(".ruler/cache/inbox").unwrap();
+        system.create_dir(".ruler/history").unwrap();
+        write_str_to_file(&mut system, ".ruler/cache/AAAA", "12345").unwrap();
+        write_str_to_file(&mut system, ".ruler/cache/BBBB", "1234567890").unwrap();
+        write_str_to_file(&mut system, ".ruler/cache/CCCC", "12").unwrap();
+        write_str_to_file(&mut system, ".ruler/cache/DDDD", "1234567890").unwrap();
+        write_str_to_file(&mut system, ".ruler/cache/inbox/partial", "123").unwrap();
+        write_str_to_file(&mut system, ".ruler/history/RULE1", "1234").unwrap();
+        write_str_to_file(&mut system, ".ruler/history/RULE2", "123456").unwrap();
+        write_str_to_file(&mut system, ".ruler/current_file_states", "1234567").unwrap();
+        write_str_to_file(&mut system, ".ruler/timings", "12345678").unwrap();
+        write_str_to_file(&mut system, ".ruler/notes.txt", "1").unwrap();
+        system
+    }
+
+    /*  Each file lands in its component with exact counts and bytes, and the largest blobs come
+        biggest first, with ties in ticket order. */
+    #[test]
+    fn du_measure_components()
+    {
+        let system = make_ruler_directory();
+        let usage = measure(&system, ".ruler", None, 3).ok().unwrap();
+
+        assert_eq!(usage.cache, Usage{count : 4, bytes : 27});
+        assert_eq!(usage.history, Usage{count : 2, bytes : 10});
+        assert_eq!(usage.current_file_states, Usage{count : 1, bytes : 7});
+        assert_eq!(usage.logs, Usage{count : 1, bytes : 8});
+        assert_eq!(usage.other, Usage{count : 2, bytes : 4});
+        assert_eq!(usage.total_bytes(), 56);
+        assert_eq!(usage.skipped, vec![]);
+        assert_eq!(usage.largest_blobs, vec![
+            BlobUsage{ticket : "BBBB".to_string(), bytes : 10},
+            BlobUsage{ticket : "DDDD".to_string(), bytes : 10},
+            BlobUsage{ticket : "AAAA".to_string(), bytes : 5},
+        ]);
+    }
+
+    /*  With the cache kept outside the ruler directory, its blobs still count as the cache, and an
+        old cache left in the ruler directory counts as other. */
+    #[test]
+    fn du_measure_separate_cache_directory()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir(".ruler").unwrap();
+        system.create_dir(".ruler/cache").unwrap();
+        system.create_dir(".ruler/history").unwrap();
+        write_str_to_file(&mut system, ".ruler/cache/OLD", "123").unwrap();
+        write_str_to_file(&mut system, ".ruler/history/RULE1", "1234").unwrap();
+        system.create_dir("machine").unwrap();
+        system.create_dir("machine/cache").unwrap();
+        system.create_dir("machine/cache/inbox").unwrap();
+        write_str_to_file(&mut system, "machine/cache/AAAA", "12345").unwrap();
+        write_str_to_file(&mut system, "machine/cache/BBBB", "1234567890").unwrap();
+        write_str_to_file(&mut system, "machine/cache/inbox/partial", "12").unwrap();
+
+        let usage = measure(&system, ".ruler", Some("machine/cache"), 3).ok().unwrap();
+        assert_eq!(usage.cache, Usage{count : 2, bytes : 15});
+        assert_eq!(usage.history, Usage{count : 1, bytes : 4});
+        assert_eq!(usage.other, Usage{count : 2, bytes : 5});
+        assert_eq!(usage.largest_blobs, vec![
+            BlobUsage{ticket : "BBBB".to_string(), bytes : 10},
+            BlobUsage{ticket : "AAAA".to_string(), bytes : 5},
+        ]);
+    }
+
+    #[test]
+    fn du_json_shape()
+    {
+        let system = make_ruler_directory();
+        let usage = measure(&system, ".ruler", None, 1).ok().unwrap();
+        assert_eq!(to_json(&usage),
+            "{\"total_bytes\":56,\
+\"cache\":{\"count\":4,\"bytes\":27,\"largest\":[{\"ticket\":\"BBBB\",\"bytes\":10}]},\
+\"history\":{\"count\":2,\"bytes\":10},\
+\"current_file_states\":{\"count\":1,\"bytes\":7},\
+\"logs\":{\"count\":1,\"bytes\":8},\
+\"other\":{\"count\":2,\"bytes\":4},\
+\"skipped\":[]}");
+    }
+
+    #[test]
+    fn du_missing_directory()
+    {
+        let system = FakeSystem::new(10);
+        assert!(measure(&system, ".ruler", None, 3).is_err());
+    }
+
+    #[test]
+    fn du_format_size()
+    {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(20 * 1024 * 1024), "20.0 MiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
@@ -0,0 +1,253 @@
+use crate::build::
+{
+    get_nodes,
+    BuildError,
+};
+use crate::sort::NodePack;
+use crate::printer::Printer;
+use crate::system::System;
+
+/*  One line per node, in the order build would start them, naming the node's targets, the rule it
+    came from, and the paths it waits on before its command can run.  Those are leaves (files nothing
+    builds) or targets of earlier nodes.  Global sources, which every node waits on, come first, each
+    on a line of its own rather than repeated on every node's. */
+pub fn order_readout(node_pack : &NodePack) -> Vec<String>
+{
+    let mut lines = Vec::new();
+    for path in node_pack.get_global_source_paths()
+    {
+        lines.push(format!("global source: {}", path));
+    }
+
+    for (node_index, node) in node_pack.nodes.iter().enumerate()
+    {
+        let source_paths = node_pack.get_source_paths(node_index);
+        lines.push(
+            format!("{} ({})  waits on: {}",
+                node.targets.join(" "),
+                node.provenance,
+                if source_paths.len() == 0
+                {
+                    "nothing".to_string()
+                }
+                else
+                {
+                    source_paths.join(", ")
+                }));
+    }
+    lines
+}
+
+/*  One line per node, naming its targets and the start of its rule's ticket.  For a node pack sorted
+    toward one goal, that's every rule the goal depends on, the goal's own rule last. */
+pub fn rules_readout(node_pack : &NodePack) -> Vec<String>
+{
+    node_pack.nodes.iter().map(
+        |node| format!("{}  {}", node.targets.join(" "), node.rule_ticket.short_human_readable())).collect()
+}
+
+/*  This is the function that runs when you type "ruler rules-for" at the command-line.  Sorts the
+    rules toward the given target and prints every rule the target depends on, directly or not,
+    including its own.  Runs nothing. */
+pub fn rules_for
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &SystemType,
+    printer : &mut PrinterType,
+    rulefile_paths : Vec<String>,
+    target : String,
+    dedupe_rules : bool,
+    case_insensitive_paths : bool
+)
+-> Result<(), BuildError>
+{
+    let node_pack = get_nodes(system, rulefile_paths, Some(target), dedupe_rules, case_insensitive_paths, None)?;
+    for line in rules_readout(&node_pack)
+    {
+        printer.print(&line);
+    }
+    Ok(())
+}
+
+/*  This is the function that runs when you type "ruler explain" at the command-line.  Reads the
+    rules the way build would, and prints the order the rules go in, each with what it waits on.
+    Runs nothing and reads no history, so it's purely about the shape of the graph. */
+pub fn explain
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &SystemType,
+    printer : &mut PrinterType,
+    rulefile_paths : Vec<String>,
+    goal_target_opt : Option<String>,
+    dedupe_rules : bool,
+    case_insensitive_paths : bool,
+    variant_opt : Option<&str>
+)
+-> Result<(), BuildError>
+{
+    let node_pack = get_nodes(system, rulefile_paths, goal_target_opt, dedupe_rules, case_insensitive_paths, variant_opt)?;
+    for line in order_readout(&node_pack)
+    {
+        printer.print(&line);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::build::get_nodes;
+    use crate::explain::
+    {
+        order_readout,
+        rules_readout,
+    };
+    use crate::system::fake::FakeSystem;
+    use crate::system::util::write_str_to_file;
+
+    /*  In the poem graph, each stanza waits on its two verses, and the poem, which comes last,
+        waits on the stanzas. */
+    #[test]
+    fn order_readout_poem()
+    {
+        let rules = "\
+poem.txt
+:
+stanza1.txt
+stanza2.txt
+:
+mycat
+stanza1.txt
+stanza2.txt
+poem.txt
+:
+
+stanza1.txt
+:
+verse1.txt
+verse2.txt
+:
+mycat
+verse1.txt
+verse2.txt
+stanza1.txt
+:
+
+stanza2.txt
+:
+verse3.txt
+verse4.txt
+:
+mycat
+verse3.txt
+verse4.txt
+stanza2.txt
+:
+";
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        let node_pack = get_nodes(&system, vec!["build.rules".to_string()], None, false, false, None).unwrap();
+        assert_eq!(order_readout(&node_pack), vec![
+            "stanza1.txt (build.rules:12)  waits on: verse1.txt, verse2.txt",
+            "stanza2.txt (build.rules:23)  waits on: verse3.txt, verse4.txt",
+            "poem.txt (build.rules:1)  waits on: stanza1.txt, stanza2.txt",
+        ]);
+    }
+
+    /*  Global sources get listed before the rules, in the order declared. */
+    #[test]
+    fn order_readout_global_sources()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "build.rules", "\
+@global-source config.toml
+@global-source flags.txt
+
+poem.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+poem.txt
+:
+").unwrap();
+
+        let node_pack = get_nodes(&system, vec!["build.rules".to_string()], None, false, false, None).unwrap();
+        assert_eq!(order_readout(&node_pack), vec![
+            "global source: config.toml",
+            "global source: flags.txt",
+            "poem.txt (build.rules:4)  waits on: verse1.txt",
+        ]);
+    }
+
+    /*  Sorting toward the poem, the rules listed are the two stanzas' and the poem's, each with the
+        start of its ticket, and not the rule for the unrelated cookies. */
+    #[test]
+    fn rules_readout_poem_closure()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "build.rules", "\
+poem.txt
+:
+stanza1.txt
+stanza2.txt
+:
+mycat
+stanza1.txt
+stanza2.txt
+poem.txt
+:
+
+stanza1.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+stanza1.txt
+:
+
+stanza2.txt
+:
+verse2.txt
+:
+mycat
+verse2.txt
+stanza2.txt
+:
+
+cookies.txt
+:
+recipe.txt
+:
+mycat
+recipe.txt
+cookies.txt
+:
+").unwrap();
+
+        let all_pack = get_nodes(&system, vec!["build.rules".to_string()], None, false, false, None).unwrap();
+        let short_ticket = |target : &str|
+        {
+            let node = all_pack.nodes.iter().find(|node| node.targets == vec![target.to_string()]).unwrap();
+            node.rule_ticket.human_readable()[..10].to_string()
+        };
+
+        let node_pack = get_nodes(&system, vec!["build.rules".to_string()], Some("poem.txt".to_string()), false, false, None).unwrap();
+        let readout = rules_readout(&node_pack);
+        assert_eq!(readout, vec![
+            format!("stanza1.txt  {}", short_ticket("stanza1.txt")),
+            format!("stanza2.txt  {}", short_ticket("stanza2.txt")),
+            format!("poem.txt  {}", short_ticket("poem.txt")),
+        ]);
+        assert!(readout.iter().all(|line| !line.contains("cookies.txt")));
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Duration;
+use termcolor::Color;
+use crate::current::
+{
+    CurrentFileStates,
+    CurrentFileStatesError,
+};
+use crate::directory::get_cache_path;
+use crate::du::format_size;
+use crate::history::
+{
+    History,
+    HistoryError,
+    RuleHistory,
+};
+use crate::pin::
+{
+    Pins,
+    PinsError,
+    get_pins_path,
+};
+use crate::printer::Printer;
+use crate::system::
+{
+    System,
+    SystemError,
+};
+use crate::system::util::get_timestamp;
+use crate::ticket::Ticket;
+
+/*  What gc removed: history entries older than the cutoff along with the bytes the rule history files
+    shrank by (a file left with no entries gets removed, and counts in full), then cache blobs nothing
+    refers to anymore. */
+#[derive(Debug, PartialEq)]
+pub struct GcReport
+{
+    pub history_entries_removed : usize,
+    pub history_bytes_removed : u64,
+    pub cache_blobs_removed : usize,
+    pub cache_bytes_removed : u64,
+}
+
+#[derive(Debug)]
+pub enum GcError
+{
+    DirectoryNotFound(String),
+    CannotListDirectory(String, SystemError),
+    HistoryError(HistoryError),
+    CurrentFileStatesError(CurrentFileStatesError),
+    PinsError(PinsError),
+    CannotRemove(String, SystemError),
+}
+
+impl fmt::Display for GcError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            GcError::DirectoryNotFound(path) =>
+                write!(formatter, "Ruler directory not found: {}", path),
+
+            GcError::CannotListDirectory(path, error) =>
+                write!(formatter, "Cannot list directory: {}: {}", path, error),
+
+            GcError::HistoryError(error) =>
+                write!(formatter, "{} (nothing removed, since what it refers to can't be known)", error),
+
+            GcError::CurrentFileStatesError(error) =>
+                write!(formatter, "{} (nothing removed, since what it refers to can't be known)", error),
+
+            GcError::PinsError(error) =>
+                write!(formatter, "{} (nothing removed, since what it refers to can't be known)", error),
+
+            GcError::CannotRemove(path, error) =>
+                write!(formatter, "Cannot remove: {}: {}", path, error),
+        }
+    }
+}
+
+/*  Reads a duration like 90d: a whole number followed by a unit, s, m, h, d or w. */
+pub fn parse_duration(text : &str) -> Result<Duration, String>
+{
+    let (number, unit) = text.split_at(text.trim_end_matches(char::is_alphabetic).len());
+    let seconds_per_unit =
+    match unit
+    {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("'{}' needs a unit: s, m, h, d or w, as in 90d", text)),
+    };
+
+    match number.parse::<u64>()
+    {
+        Ok(count) => Ok(Duration::from_secs(count * seconds_per_unit)),
+        Err(_) => Err(format!("'{}' isn't a whole number followed by a unit, as in 90d", text)),
+    }
+}
+
+/*  The last component of a path from list_dir. */
+fn get_name(path : &str) -> &str
+{
+    match path.rsplit_once('/')
+    {
+        Some((_directory, name)) => name,
+        None => path,
+    }
+}
+
+fn list_files<SystemType : System>
+(
+    system : &SystemType,
+    path : &str
+)
+-> Result<Vec<String>, GcError>
+{
+    match system.list_dir(path)
+    {
+        Ok(paths) => Ok(paths.into_iter().filter(|path| system.is_file(path)).collect()),
+        Err(error) => Err(GcError::CannotListDirectory(path.to_string(), error)),
+    }
+}
+
+/*  This is the function that runs when you type "ruler gc" at the command-line.  Removes every rule
+    history entry made longer ago than older_than, then every cache blob that no remaining entry, no
+    current file state (of any variant) and no pin refers to.  Entries from before creation times got
+    recorded count as made at the epoch, so the first gc removes them.
+
+    Everything gets read before anything gets removed, so a history or state file that won't parse
+    stops gc without touching anything, since there'd be no telling what it refers to.  A cache shared
+    with other checkouts only knows this checkout's references, so gc removes blobs only the others
+    need. */
+pub fn gc
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &mut SystemType,
+    printer : &mut PrinterType,
+    directory_path : &str,
+    cache_directory_opt : Option<&str>,
+    compress_history : bool,
+    older_than : Duration
+)
+-> Result<GcReport, GcError>
+{
+    if ! system.is_dir(directory_path)
+    {
+        return Err(GcError::DirectoryNotFound(directory_path.to_string()));
+    }
+
+    let now = get_timestamp(system.now()).unwrap_or(0);
+    let cutoff = now.saturating_sub(older_than.as_micros() as u64);
+
+    let history_path = format!("{}/history", directory_path);
+    let mut history = History::new_with_compression(system.clone(), &history_path, compress_history);
+    let mut rule_histories : Vec<(String, Ticket, RuleHistory)> = vec![];
+    if system.is_dir(&history_path)
+    {
+        for path in list_files(system, &history_path)?
+        {
+            let rule_ticket =
+            match Ticket::from_human_readable(get_name(&path))
+            {
+                Ok(rule_ticket) => rule_ticket,
+                Err(_) => continue,
+            };
+
+            match history.read_rule_history(&rule_ticket)
+            {
+                Ok(rule_history) => rule_histories.push((path, rule_ticket, rule_history)),
+                Err(error) => return Err(GcError::HistoryError(error)),
+            }
+        }
+    }
+
+    let mut referenced : HashSet<Ticket> = HashSet::new();
+    for path in list_files(system, directory_path)?
+    {
+        let name = get_name(&path);
+        if name != "current_file_states" && ! name.starts_with("current_file_states.")
+        {
+            continue;
+        }
+
+        match CurrentFileStates::from_file_readonly(system.clone(), path.clone())
+        {
+            Ok(current_file_states) => referenced.extend(current_file_states.get_tickets()),
+            Err(error) => return Err(GcError::CurrentFileStatesError(error)),
+        }
+    }
+
+    match Pins::from_file(system, &get_pins_path(directory_path))
+    {
+        Ok(pins) => referenced.extend(pins.get_tickets()),
+        Err(error) => return Err(GcError::PinsError(error)),
+    }
+
+    let mut report = GcReport
+    {
+        history_entries_removed : 0,
+        history_bytes_removed : 0,
+        cache_blobs_removed : 0,
+        cache_bytes_removed : 0,
+    };
+
+    for (path, rule_ticket, mut rule_history) in rule_histories
+    {
+        let removed = rule_history.remove_older_than(cutoff);
+        for file_state_vec in rule_history.get_all_file_state_vecs()
+        {
+            referenced.extend(file_state_vec.get_tickets());
+        }
+
+        if removed == 0
+        {
+            continue;
+        }
+
+        let bytes_before = system.get_file_len(&path).unwrap_or(0);
+        if rule_history.is_empty()
+        {
+            match system.remove_file(&path)
+            {
+                Ok(()) => {},
+                Err(error) => return Err(GcError::CannotRemove(path, error)),
+            }
+            report.history_bytes_removed += bytes_before;
+        }
+        else
+        {
+            match history.write_rule_history(rule_ticket, rule_history)
+            {
+                Ok(()) => {},
+                Err(error) => return Err(GcError::HistoryError(error)),
+            }
+            report.history_bytes_removed += bytes_before.saturating_sub(system.get_file_len(&path).unwrap_or(0));
+        }
+        report.history_entries_removed += removed;
+    }
+
+    let cache_path = get_cache_path(directory_path, cache_directory_opt);
+    if system.is_dir(&cache_path)
+    {
+        for path in list_files(system, &cache_path)?
+        {
+            match Ticket::from_human_readable(get_name(&path))
+            {
+                Ok(ticket) if ! referenced.contains(&ticket) =>
+                {
+                    let bytes = system.get_file_len(&path).unwrap_or(0);
+                    match system.remove_file(&path)
+                    {
+                        Ok(()) => {},
+                        Err(error) => return Err(GcError::CannotRemove(path, error)),
+                    }
+                    report.cache_blobs_removed += 1;
+                    report.cache_bytes_removed += bytes;
+                },
+                _ => {},
+            }
+        }
+    }
+
+    printer.print_single_banner_line("   Removed", Color::Green,
+        &format!("{} history entries ({})", report.history_entries_removed, format_size(report.history_bytes_removed)));
+    printer.print_single_banner_line("   Removed", Color::Green,
+        &format!("{} cache blobs ({})", report.cache_blobs_removed, format_size(report.cache_bytes_removed)));
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test
+{
+    use std::time::Duration;
+    use crate::blob::FileStateVec;
+    use crate::current::CurrentFileStates;
+    use crate::directory;
+    use crate::gc::
+    {
+        gc,
+        parse_duration,
+        GcReport,
+    };
+    use crate::history::
+    {
+        History,
+        RuleHistory,
+    };
+    use crate::blob::FileState;
+    use crate::printer::RecordingPrinter;
+    use crate::system::
+    {
+        System,
+        fake::FakeSystem,
+    };
+    use crate::system::util::write_str_to_file;
+    use crate::ticket::
+    {
+        Ticket,
+        TicketFactory,
+    };
+
+    const DAY : u64 = 24 * 60 * 60 * 1_000_000;
+
+    /*  Puts content in the cache under its ticket, and returns the ticket. */
+    fn put_blob(system : &mut FakeSystem, content : &str) -> Ticket
+    {
+        let ticket = TicketFactory::from_str(content).result();
+        write_str_to_file(system, &format!(".ruler/cache/{}", ticket.human_readable()), content).unwrap();
+        ticket
+    }
+
+    #[test]
+    fn parse_duration_units()
+    {
+        assert_eq!(parse_duration("90d"), Ok(Duration::from_secs(90 * 24 * 60 * 60)));
+        assert_eq!(parse_duration("2w"), Ok(Duration::from_secs(14 * 24 * 60 * 60)));
+        assert_eq!(parse_duration("36h"), Ok(Duration::from_secs(36 * 60 * 60)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("45s"), Ok(Duration::from_secs(45)));
+        assert!(parse_duration("90").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("ninety days").is_err());
+    }
+
+    /*  It's day 100.  The poem rule has an entry from day 1 and one from day 95, the stanza rule only
+        one from day 2.  Gc with 90 days removes the two old entries, and the stanza rule's file with
+        its last entry.  Of the blobs, the old poem goes, and so does the stanza only the old entry
+        knew.  The shared blob, in both an old entry and the young one, stays, as do the young poem
+        and the blob the current file states refer to. */
+    #[test]
+    fn gc_removes_old_entries_and_unreferenced_blobs()
+    {
+        let mut system = FakeSystem::new(100 * DAY);
+        directory::init(&mut system, ".ruler", None, None, false).unwrap();
+
+        let old_poem = put_blob(&mut system, "Roses are red.\n");
+        let young_poem = put_blob(&mut system, "Roses are blue.\n");
+        let shared = put_blob(&mut system, "Violets are violet.\n");
+        let old_stanza = put_blob(&mut system, "Sugar is sweet.\n");
+        let current = put_blob(&mut system, "And so are you.\n");
+
+        let mut poem_history = RuleHistory::new();
+        poem_history.insert(TicketFactory::from_str("old sources").result(),
+            FileStateVec::from_ticket_vec(vec![old_poem.clone(), shared.clone()]), DAY).unwrap();
+        poem_history.insert(TicketFactory::from_str("young sources").result(),
+            FileStateVec::from_ticket_vec(vec![young_poem.clone(), shared.clone()]), 95 * DAY).unwrap();
+
+        let mut stanza_history = RuleHistory::new();
+        stanza_history.insert(TicketFactory::from_str("stanza sources").result(),
+            FileStateVec::from_ticket_vec(vec![old_stanza.clone()]), 2 * DAY).unwrap();
+
+        let poem_rule = TicketFactory::from_str("poem rule").result();
+        let stanza_rule = TicketFactory::from_str("stanza rule").result();
+        let mut history = History::new(system.clone(), ".ruler/history");
+        history.write_rule_history(poem_rule.clone(), poem_history).unwrap();
+        history.write_rule_history(stanza_rule.clone(), stanza_history).unwrap();
+
+        let mut current_file_states = CurrentFileStates::from_file(
+            system.clone(), ".ruler/current_file_states".to_string()).unwrap();
+        current_file_states.insert_file_state("couplet.txt".to_string(), FileState::new(current.clone(), 10));
+        current_file_states.to_file().unwrap();
+
+        let poem_history_path = format!(".ruler/history/{}", poem_rule);
+        let stanza_history_path = format!(".ruler/history/{}", stanza_rule);
+        let history_bytes_before = system.get_file_len(&poem_history_path).unwrap()
+            + system.get_file_len(&stanza_history_path).unwrap();
+        let cache_bytes_removed = ("Roses are red.\n".len() + "Sugar is sweet.\n".len()) as u64;
+
+        let mut printer = RecordingPrinter::new();
+        let report = gc(&mut system, &mut printer, ".ruler", None, false, parse_duration("90d").unwrap()).unwrap();
+
+        assert_eq!(report, GcReport
+        {
+            history_entries_removed : 2,
+            history_bytes_removed : history_bytes_before - system.get_file_len(&poem_history_path).unwrap(),
+            cache_blobs_removed : 2,
+            cache_bytes_removed : cache_bytes_removed,
+        });
+
+        let poem_history = history.read_rule_history(&poem_rule).unwrap();
+        assert_eq!(poem_history.get_file_state_vec(&TicketFactory::from_str("old sources").result()), None);
+        assert_eq!(poem_history.get_created_at(&TicketFactory::from_str("young sources").result()), Some(95 * DAY));
+        assert!(!system.is_file(&stanza_history_path));
+
+        let mut expected = vec![young_poem, shared, current];
+        expected.sort_by_key(|ticket| ticket.human_readable());
+        assert_eq!(directory::open_readonly(&system, ".ruler", None, None).unwrap().cache.list_entries().unwrap(), expected);
+
+        assert_eq!(printer.printed, vec![
+            format!("   Removed 2 history entries ({} B)", report.history_bytes_removed),
+            format!("   Removed 2 cache blobs ({} B)", cache_bytes_removed),
+        ]);
+
+        /*  Running again finds nothing more to remove. */
+        let report = gc(&mut system, &mut RecordingPrinter::new(), ".ruler", None, false, parse_duration("90d").unwrap()).unwrap();
+        assert_eq!(report.history_entries_removed, 0);
+        assert_eq!(report.cache_blobs_removed, 0);
+    }
+}
@@ -0,0 +1,361 @@
+use crate::ignore::IgnorePatterns;
+use crate::system::
+{
+    System,
+    ReadWriteError,
+};
+use crate::ticket::
+{
+    Ticket,
+    TicketFactory,
+};
+use crate::work::WorkError;
+
+/*  How many differing entries "ruler hash --compare --verbose" lists before it stops looking. */
+pub const DEFAULT_DIFFERENCE_LIMIT : usize = 10;
+
+/*  The ticket of the file or directory at path, or of everything on standard input if path is "-". */
+pub fn hash_path<SystemType : System>
+(
+    system : &mut SystemType,
+    path : &str
+)
+-> Result<Ticket, ReadWriteError>
+{
+    if path == "-"
+    {
+        return match system.read_stdin()
+        {
+            Ok(content) =>
+            {
+                let mut factory = TicketFactory::new();
+                factory.input_bytes(&content);
+                Ok(factory.result())
+            },
+            Err(error) => Err(ReadWriteError::SystemError(error)),
+        };
+    }
+
+    Ok(TicketFactory::from_path(system, path)?.result())
+}
+
+/*  The tickets of two paths, and, if they were asked for, the paths of entries underneath that
+    differ, relative to the two paths, up to a limit. */
+#[derive(Debug, PartialEq)]
+pub struct Comparison
+{
+    pub first_ticket : Ticket,
+    pub second_ticket : Ticket,
+    pub differences : Vec<String>,
+}
+
+impl Comparison
+{
+    pub fn is_equal(&self) -> bool
+    {
+        self.first_ticket == self.second_ticket
+    }
+}
+
+/*  Hashes both paths.  When they differ and both are directories, walks the two side by side and
+    collects the entries that differ: a file or directory on only one side, a file on one side where
+    the other has a directory, or two files with different tickets.  Directories in both get
+    descended into rather than listed themselves.  Stops after difference_limit entries, so zero
+    skips the walk. */
+pub fn compare_paths<SystemType : System>
+(
+    system : &SystemType,
+    first_path : &str,
+    second_path : &str,
+    difference_limit : usize
+)
+-> Result<Comparison, ReadWriteError>
+{
+    let first_ticket = TicketFactory::from_path(system, first_path)?.result();
+    let second_ticket = TicketFactory::from_path(system, second_path)?.result();
+
+    let mut differences = vec![];
+    if first_ticket != second_ticket && difference_limit > 0
+        && system.is_dir(first_path) && system.is_dir(second_path)
+    {
+        let ignore_patterns = IgnorePatterns::from_system(system)?;
+        let mut stack = vec!["".to_string()];
+        while let Some(relative) = stack.pop()
+        {
+            if differences.len() >= difference_limit
+            {
+                break;
+            }
+
+            for (name, first_child_opt, second_child_opt) in pair_children(
+                system, &ignore_patterns, &join(first_path, &relative), &join(second_path, &relative))?
+            {
+                let child_relative = join(&relative, &name);
+                match (first_child_opt, second_child_opt)
+                {
+                    (Some(first_child), Some(second_child)) =>
+                    {
+                        if system.is_dir(&first_child) && system.is_dir(&second_child)
+                        {
+                            if TicketFactory::from_path(system, &first_child)?.result()
+                                != TicketFactory::from_path(system, &second_child)?.result()
+                            {
+                                stack.push(child_relative);
+                            }
+                            continue;
+                        }
+
+                        if system.is_file(&first_child) && system.is_file(&second_child)
+                            && TicketFactory::from_file(system, &first_child)?.result()
+                                == TicketFactory::from_file(system, &second_child)?.result()
+                        {
+                            continue;
+                        }
+
+                        differences.push(child_relative);
+                    },
+                    _ => differences.push(child_relative),
+                }
+
+                if differences.len() >= difference_limit
+                {
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(
+        Comparison
+        {
+            first_ticket : first_ticket,
+            second_ticket : second_ticket,
+            differences : differences,
+        })
+}
+
+/*  A target whose content differs from its golden copy: the ticket of each, or None for a golden copy
+    that isn't there. */
+#[derive(Debug, PartialEq)]
+pub struct TargetMismatch
+{
+    pub path : String,
+    pub built_ticket : Ticket,
+    pub golden_ticket_opt : Option<Ticket>,
+}
+
+/*  Hashes each target and the file at the same path under golden_directory, and returns the targets
+    whose tickets differ, in the order given.  A target that isn't a file, like one named by an
+    aggregation that never makes it, has nothing to compare and gets skipped. */
+pub fn compare_to_golden<SystemType : System>
+(
+    system : &SystemType,
+    targets : &Vec<String>,
+    golden_directory : &str
+)
+-> Result<Vec<TargetMismatch>, WorkError>
+{
+    let mut mismatches = vec![];
+    for target in targets.iter()
+    {
+        if ! system.is_file(target)
+        {
+            continue;
+        }
+
+        let built_ticket =
+        match TicketFactory::from_file(system, target)
+        {
+            Ok(mut factory) => factory.result(),
+            Err(error) => return Err(WorkError::ReadWriteError(target.clone(), error)),
+        };
+
+        let golden_path = join(golden_directory.trim_end_matches('/'), target);
+        let golden_ticket_opt =
+        if system.is_file(&golden_path)
+        {
+            match TicketFactory::from_file(system, &golden_path)
+            {
+                Ok(mut factory) => Some(factory.result()),
+                Err(error) => return Err(WorkError::ReadWriteError(golden_path, error)),
+            }
+        }
+        else
+        {
+            None
+        };
+
+        if golden_ticket_opt.as_ref() != Some(&built_ticket)
+        {
+            mismatches.push(
+                TargetMismatch
+                {
+                    path : target.clone(),
+                    built_ticket : built_ticket,
+                    golden_ticket_opt : golden_ticket_opt,
+                });
+        }
+    }
+
+    Ok(mismatches)
+}
+
+fn join(directory : &str, name : &str) -> String
+{
+    if directory == ""
+    {
+        name.to_string()
+    }
+    else if name == ""
+    {
+        directory.to_string()
+    }
+    else
+    {
+        format!("{}/{}", directory, name)
+    }
+}
+
+/*  The names in either of two directories, sorted, each with its full path in the first and in the
+    second, or None where it's missing.  Ignored entries are left out, as they are from tickets. */
+fn pair_children<SystemType : System>
+(
+    system : &SystemType,
+    ignore_patterns : &IgnorePatterns,
+    first_directory : &str,
+    second_directory : &str
+)
+-> Result<Vec<(String, Option<String>, Option<String>)>, ReadWriteError>
+{
+    let mut pairs : Vec<(String, Option<String>, Option<String>)> = vec![];
+    for (side, directory) in [first_directory, second_directory].iter().enumerate()
+    {
+        let listing =
+        match system.list_dir(directory)
+        {
+            Ok(listing) => listing,
+            Err(error) => return Err(ReadWriteError::SystemError(error)),
+        };
+
+        for path in listing.into_iter().filter(|path| ! ignore_patterns.is_ignored(path))
+        {
+            let name = match path.rfind('/')
+            {
+                Some(index) => path[index + 1..].to_string(),
+                None => path.clone(),
+            };
+
+            let index =
+            match pairs.iter().position(|(other, _first, _second)| *other == name)
+            {
+                Some(index) => index,
+                None =>
+                {
+                    pairs.push((name, None, None));
+                    pairs.len() - 1
+                },
+            };
+
+            if side == 0
+            {
+                pairs[index].1 = Some(path);
+            }
+            else
+            {
+                pairs[index].2 = Some(path);
+            }
+        }
+    }
+
+    pairs.sort();
+    Ok(pairs)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::hash::
+    {
+        compare_paths,
+        compare_to_golden,
+        TargetMismatch,
+        hash_path,
+        DEFAULT_DIFFERENCE_LIMIT,
+    };
+    use crate::system::System;
+    use crate::system::fake::FakeSystem;
+    use crate::system::util::write_str_to_file;
+    use crate::ticket::TicketFactory;
+
+    /*  Hashing "-" hashes what's on standard input, same as hashing those bytes as a string. */
+    #[test]
+    fn hash_stdin_matches_from_str()
+    {
+        let mut system = FakeSystem::new(10);
+        system.set_stdin(b"Roses are red.\n");
+        assert_eq!(hash_path(&mut system, "-").unwrap(), TicketFactory::from_str("Roses are red.\n").result());
+    }
+
+    /*  Two files with the same content compare equal, and with different content unequal, with the
+        tickets of each either way. */
+    #[test]
+    fn compare_files_equal_and_unequal()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "poem.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "copy.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "other.txt", "Violets are blue.\n").unwrap();
+
+        let comparison = compare_paths(&system, "poem.txt", "copy.txt", DEFAULT_DIFFERENCE_LIMIT).unwrap();
+        assert!(comparison.is_equal());
+        assert_eq!(comparison.first_ticket, TicketFactory::from_str("Roses are red.\n").result());
+        assert_eq!(comparison.differences, Vec::<String>::new());
+
+        let comparison = compare_paths(&system, "poem.txt", "other.txt", DEFAULT_DIFFERENCE_LIMIT).unwrap();
+        assert!(!comparison.is_equal());
+        assert_eq!(comparison.second_ticket, TicketFactory::from_str("Violets are blue.\n").result());
+    }
+
+    /*  Two trees the same but for one file two levels down: the walk names that file and nothing else,
+        not even the directories it's in. */
+    #[test]
+    fn compare_directories_lists_differing_child()
+    {
+        let mut system = FakeSystem::new(10);
+        for tree in ["before", "after"].iter()
+        {
+            system.create_dir_all(&format!("{}/poems/red", tree)).unwrap();
+            write_str_to_file(&mut system, &format!("{}/readme.txt", tree), "Poems.\n").unwrap();
+            write_str_to_file(&mut system, &format!("{}/poems/blue.txt", tree), "Violets are blue.\n").unwrap();
+            write_str_to_file(&mut system, &format!("{}/poems/red/roses.txt", tree), "Roses are red.\n").unwrap();
+        }
+        write_str_to_file(&mut system, "after/poems/red/roses.txt", "Roses are crimson.\n").unwrap();
+
+        let comparison = compare_paths(&system, "before", "after", DEFAULT_DIFFERENCE_LIMIT).unwrap();
+        assert!(!comparison.is_equal());
+        assert_eq!(comparison.differences, vec!["poems/red/roses.txt".to_string()]);
+
+        let comparison = compare_paths(&system, "before", "after", 0).unwrap();
+        assert_eq!(comparison.differences, Vec::<String>::new());
+    }
+
+    /*  A target with no golden copy differs from it, with no golden ticket.  A target that isn't there
+        at all gets skipped. */
+    #[test]
+    fn compare_to_golden_missing_copy()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir("golden").unwrap();
+        write_str_to_file(&mut system, "poem.txt", "Roses are red.\n").unwrap();
+
+        assert_eq!(compare_to_golden(&system, &vec!["poem.txt".to_string(), "stanzas".to_string()], "golden").unwrap(),
+            vec![
+                TargetMismatch
+                {
+                    path : "poem.txt".to_string(),
+                    built_ticket : TicketFactory::from_str("Roses are red.\n").result(),
+                    golden_ticket_opt : None,
+                }
+            ]);
+    }
+}
@@ -7,9 +7,12 @@ use crate::blob::
 };
 use crate::downloader::
 {
-    download_string,
+    Downloader,
+    HttpDownloader,
 };
+use crate::printer::PrinterHandle;
 use std::collections::HashMap;
+use termcolor::Color;
 use serde::
 {
     Serialize,
@@ -21,6 +24,14 @@ use std::io::
     Read,
     Write,
 };
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+/*  Compressed rule histories start with this header, then the gzip stream.  A plain history starts
+    with the number of sources it has as eight little-endian bytes, and read that way, these bytes
+    make a number no history gets near. */
+const COMPRESSED_HEADER : [u8; 8] = *b"rulergz1";
 
 pub struct DownloaderRuleHistory
 {
@@ -30,11 +41,25 @@ pub struct DownloaderRuleHistory
 
 impl DownloaderRuleHistory
 {
-    pub fn get_file_state_vec(&self, source_ticket: &Ticket) -> Option<FileStateVec>
+    pub fn get_file_state_vec(&self, source_ticket: &Ticket, printer_handle : &PrinterHandle) -> Option<FileStateVec>
+    {
+        self.get_file_state_vec_from(&HttpDownloader::new(), source_ticket, printer_handle)
+    }
+
+    /*  Asks each base url in turn for the target tickets remembered for the given source ticket,
+        fetching through the given Downloader. */
+    pub fn get_file_state_vec_from<DownloaderType : Downloader>
+    (
+        &self,
+        downloader : &DownloaderType,
+        source_ticket: &Ticket,
+        printer_handle : &PrinterHandle
+    )
+    -> Option<FileStateVec>
     {
         for base_url in &self.base_urls
         {
-            match download_string(&format!("{}/{}/{}",
+            match downloader.download_string(&format!("{}/{}/{}",
                 base_url, self.rule_ticket.human_readable(), source_ticket.human_readable()))
             {
                 Ok(download_string) =>
@@ -44,7 +69,8 @@ impl DownloaderRuleHistory
                         Ok(file_state_vec) => return Some(file_state_vec),
                         Err(_error) =>
                         {
-                            println!("Warning: downloaded target tickets did not parse");
+                            printer_handle.print_single_banner_line("   Warning", Color::Yellow,
+                                &format!("target tickets downloaded from {} did not parse", base_url));
                         },
                     }
                 },
@@ -68,6 +94,17 @@ pub struct RuleHistory
             key = source-ticket
             value = a target ticket for each target */
     source_to_targets : HashMap<Ticket, FileStateVec>,
+
+    /*  When each entry went in, in microseconds since the epoch.  Entries from before this got
+        recorded have none, and count as made at the epoch. */
+    created_at : HashMap<Ticket, u64>,
+}
+
+/*  The layout before entries recorded when they went in. */
+#[derive(Serialize, Deserialize)]
+struct RuleHistoryWithoutCreationTimes
+{
+    source_to_targets : HashMap<Ticket, FileStateVec>,
 }
 
 /*  Inserting target tickets in a RuleHistory can go wrong in a couple ways.
@@ -103,17 +140,20 @@ impl RuleHistory
     {
         RuleHistory
         {
-            source_to_targets : HashMap::new()
+            source_to_targets : HashMap::new(),
+            created_at : HashMap::new(),
         }
     }
 
-    /*  With the given source_ticket, add the given file_state_vec to the history.
-        If there's a contradiction, constructs a RuleHistoryInsertError::Contradiction
-        with a vector of indices. */
+    /*  With the given source_ticket, add the given file_state_vec to the history, made at created_at,
+        in microseconds since the epoch.  If there's a contradiction, constructs a
+        RuleHistoryInsertError::Contradiction with a vector of indices.  Inserting what's already there
+        changes nothing, the time included. */
     pub fn insert(
         &mut self,
         source_ticket: Ticket,
-        file_state_vec: FileStateVec)
+        file_state_vec: FileStateVec,
+        created_at : u64)
     -> Result<(), RuleHistoryInsertError>
     {
         match self.source_to_targets.get(&source_ticket)
@@ -129,16 +169,67 @@ impl RuleHistory
             },
             None =>
             {
+                self.created_at.insert(source_ticket.clone(), created_at);
                 self.source_to_targets.insert(source_ticket, file_state_vec);
                 Ok(())
             }
         }
     }
 
+    /*  With the given source_ticket, record the given file_state_vec, made at created_at, replacing
+        whatever was there, contradiction or not. */
+    pub fn overwrite(
+        &mut self,
+        source_ticket: Ticket,
+        file_state_vec: FileStateVec,
+        created_at : u64)
+    {
+        self.created_at.insert(source_ticket.clone(), created_at);
+        self.source_to_targets.insert(source_ticket, file_state_vec);
+    }
+
+    /*  When the entry for the given sources went in, or 0, the epoch, if it's from before that got
+        recorded.  None if there's no entry. */
+    #[cfg(test)]
+    pub fn get_created_at(&self, source_ticket : &Ticket) -> Option<u64>
+    {
+        match self.source_to_targets.get(source_ticket)
+        {
+            Some(_file_state_vec) => Some(*self.created_at.get(source_ticket).unwrap_or(&0)),
+            None => None,
+        }
+    }
+
+    /*  Removes every entry made before the cutoff, in microseconds since the epoch, and returns how
+        many went. */
+    pub fn remove_older_than(&mut self, cutoff : u64) -> usize
+    {
+        let old_tickets : Vec<Ticket> = self.source_to_targets.keys().filter(
+            |source_ticket| *self.created_at.get(source_ticket).unwrap_or(&0) < cutoff).cloned().collect();
+
+        for source_ticket in old_tickets.iter()
+        {
+            self.source_to_targets.remove(source_ticket);
+            self.created_at.remove(source_ticket);
+        }
+        old_tickets.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.source_to_targets.len() == 0
+    }
+
     pub fn get_file_state_vec(&self, source_ticket: &Ticket) -> Option<&FileStateVec>
     {
         self.source_to_targets.get(source_ticket)
     }
+
+    /*  Every FileStateVec remembered, for any sources. */
+    pub fn get_all_file_state_vecs(&self) -> Vec<&FileStateVec>
+    {
+        self.source_to_targets.values().collect()
+    }
 }
 
 impl fmt::Display for RuleHistory
@@ -168,6 +259,9 @@ pub struct History<SystemType : System>
 {
     system_box : Box<SystemType>,
     path : String,
+
+    /*  Whether to gzip rule histories when writing them.  Reading handles either form regardless. */
+    compress : bool,
 }
 
 /*  When accessing History, a few things can go wrong.  History is stored in a file, so that file could be unreadable or
@@ -210,11 +304,20 @@ impl<SystemType : System> History<SystemType>
     /*  Create a new History from a filepath in the filesystem. */
     pub fn new(system: SystemType, path : &str)
     -> History<SystemType>
+    {
+        History::new_with_compression(system, path, false)
+    }
+
+    /*  Like new, but if compress is true, rule histories get written gzipped.  Only what's on disk
+        differs, a RuleHistory in memory is the same either way. */
+    pub fn new_with_compression(system: SystemType, path : &str, compress : bool)
+    -> History<SystemType>
     {
         History
         {
             system_box : Box::new(system),
             path : path.to_string(),
+            compress : compress,
         }
     }
 
@@ -232,6 +335,27 @@ impl<SystemType : System> History<SystemType>
             Err(_) => return Err(HistoryError::CannotSerializeRuleHistory(rule_history_file_path)),
         };
 
+        let content =
+        if self.compress
+        {
+            let mut encoder = GzEncoder::new(COMPRESSED_HEADER.to_vec(), Compression::default());
+            match encoder.write_all(&content)
+            {
+                Ok(()) => {},
+                Err(_) => return Err(HistoryError::CannotSerializeRuleHistory(rule_history_file_path)),
+            }
+
+            match encoder.finish()
+            {
+                Ok(compressed) => compressed,
+                Err(_) => return Err(HistoryError::CannotSerializeRuleHistory(rule_history_file_path)),
+            }
+        }
+        else
+        {
+            content
+        };
+
         let mut file =
         match system.create_file(&rule_history_file_path)
         {
@@ -266,10 +390,32 @@ impl<SystemType : System> History<SystemType>
             Err(_) => return Err(HistoryError::CannotReadRuleHistoryFile(rule_history_file_path)),
         }
 
+        /*  Recognize compressed histories by their header rather than by the setting, so turning
+            compression on or off doesn't strand what's already written.  If what follows the header
+            doesn't decompress, read the whole file as a plain history after all. */
+        if content.starts_with(&COMPRESSED_HEADER)
+        {
+            let mut decompressed = Vec::new();
+            match GzDecoder::new(&content[COMPRESSED_HEADER.len()..]).read_to_end(&mut decompressed)
+            {
+                Ok(_size) => content = decompressed,
+                Err(_) => {},
+            }
+        }
+
         match bincode::deserialize(&content)
         {
             Ok(rule_history) => Ok(rule_history),
-            Err(_) => Err(HistoryError::CannotInterpretRuleHistoryFile(rule_history_file_path)),
+            Err(_) => match bincode::deserialize::<RuleHistoryWithoutCreationTimes>(&content)
+            {
+                Ok(older) => Ok(
+                    RuleHistory
+                    {
+                        source_to_targets : older.source_to_targets,
+                        created_at : HashMap::new(),
+                    }),
+                Err(_) => Err(HistoryError::CannotInterpretRuleHistoryFile(rule_history_file_path)),
+            },
         }
     }
 }
@@ -308,10 +454,12 @@ mod test
     use crate::history::
     {
         RuleHistory,
+        RuleHistoryWithoutCreationTimes,
         History,
         HistoryError,
         RuleHistoryInsertError
     };
+    use std::collections::HashMap;
     use crate::blob::
     {
         FileStateVec,
@@ -324,6 +472,7 @@ mod test
     };
     use std::io::
     {
+        Read,
         Write,
     };
 
@@ -342,7 +491,7 @@ mod test
             TicketFactory::from_str("target3").result(),
         ]);
 
-        match rule_history.insert(source_ticket.clone(), file_state_vec.clone())
+        match rule_history.insert(source_ticket.clone(), file_state_vec.clone(), 0)
         {
             Ok(_) => {},
             Err(_) => panic!("Rule history failed to insert"),
@@ -381,13 +530,13 @@ mod test
             TicketFactory::from_str("target3").result(),
         ]);
 
-        match rule_history.insert(source_ticket.clone(), file_state_vec1.clone())
+        match rule_history.insert(source_ticket.clone(), file_state_vec1.clone(), 0)
         {
             Ok(_) => {},
             Err(_) => panic!("Rule history failed to insert"),
         }
 
-        match rule_history.insert(source_ticket.clone(), file_state_vec2.clone())
+        match rule_history.insert(source_ticket.clone(), file_state_vec2.clone(), 0)
         {
             Ok(_) => panic!("Rule history allowed insert when not expected"),
             Err(RuleHistoryInsertError::Contradiction(indices)) =>
@@ -416,13 +565,13 @@ mod test
             TicketFactory::from_str("target2").result(),
         ]);
 
-        match rule_history.insert(source_ticket.clone(), file_state_vec1.clone())
+        match rule_history.insert(source_ticket.clone(), file_state_vec1.clone(), 0)
         {
             Ok(_) => {},
             Err(_) => panic!("Rule history failed to insert"),
         }
 
-        match rule_history.insert(source_ticket.clone(), file_state_vec2.clone())
+        match rule_history.insert(source_ticket.clone(), file_state_vec2.clone(), 0)
         {
             Ok(_) => panic!("Rule history allowed insert when not expected"),
             Err(RuleHistoryInsertError::TargetSizesDifferWeird) => {},
@@ -449,13 +598,13 @@ mod test
             TicketFactory::from_str("target3").result(),
         ]);
 
-        match rule_history.insert(source_ticket.clone(), file_state_vec1.clone())
+        match rule_history.insert(source_ticket.clone(), file_state_vec1.clone(), 0)
         {
             Ok(_) => {},
             Err(_) => panic!("Rule history failed to insert"),
         }
 
-        match rule_history.insert(source_ticket.clone(), file_state_vec2.clone())
+        match rule_history.insert(source_ticket.clone(), file_state_vec2.clone(), 0)
         {
             Ok(_) => {},
             Err(_) => panic!("Rule history failed to insert a second time"),
@@ -491,7 +640,7 @@ mod test
         };
 
         assert_eq!(rule_history, RuleHistory::new());
-        match rule_history.insert(source_ticket.clone(), file_state_vec.clone())
+        match rule_history.insert(source_ticket.clone(), file_state_vec.clone(), 0)
         {
             Ok(()) => {},
             Err(error) => panic!("RuleHisotry failed to insert source / target-ticket pair: {}", error),
@@ -521,6 +670,67 @@ mod test
         assert_eq!(file_state_vec, *file_state_vec2);
     }
 
+    /*  Write the same RuleHistory through a compressing History and a plain one.  Check the compressed
+        file really is the header then gzip, and that both read back equal to each other and to what
+        went in. */
+    #[test]
+    fn round_trip_compressed_history()
+    {
+        let rule_ticket = TicketFactory::from_str("rule").result();
+        let mut rule_history = RuleHistory::new();
+        rule_history.insert(
+            TicketFactory::from_str("source").result(),
+            FileStateVec::from_ticket_vec(vec![
+                TicketFactory::from_str("target1").result(),
+                TicketFactory::from_str("target2").result(),
+            ]), 0).unwrap();
+
+        let mut system = FakeSystem::new(10);
+        system.create_dir("compressed").unwrap();
+        system.create_dir("plain").unwrap();
+
+        let mut compressed_history = History::new_with_compression(system.clone(), "compressed", true);
+        let mut plain_history = History::new(system.clone(), "plain");
+        compressed_history.write_rule_history(rule_ticket.clone(), rule_history.clone()).unwrap();
+        plain_history.write_rule_history(rule_ticket.clone(), rule_history.clone()).unwrap();
+
+        let mut content = Vec::new();
+        system.open(&format!("compressed/{}", rule_ticket)).unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content[0..8], *b"rulergz1");
+        assert_eq!(content[8..10], [0x1f, 0x8b]);
+
+        let compressed_read = compressed_history.read_rule_history(&rule_ticket).unwrap();
+        let plain_read = plain_history.read_rule_history(&rule_ticket).unwrap();
+        assert_eq!(compressed_read, plain_read);
+        assert_eq!(compressed_read, rule_history);
+    }
+
+    /*  A plain RuleHistory with 0x8b1f sources starts with the same two bytes as a gzip stream.  Write
+        one uncompressed, and check it reads back as itself. */
+    #[test]
+    fn plain_history_starting_like_gzip_reads_back()
+    {
+        let rule_ticket = TicketFactory::from_str("rule").result();
+        let mut rule_history = RuleHistory::new();
+        for i in 0..0x8b1f
+        {
+            rule_history.insert(
+                TicketFactory::from_str(&format!("source{}", i)).result(),
+                FileStateVec::from_ticket_vec(vec![TicketFactory::from_str("target").result()]), 0).unwrap();
+        }
+
+        let mut system = FakeSystem::new(10);
+        system.create_dir("plain").unwrap();
+        let mut history = History::new(system.clone(), "plain");
+        history.write_rule_history(rule_ticket.clone(), rule_history.clone()).unwrap();
+
+        let mut content = Vec::new();
+        system.open(&format!("plain/{}", rule_ticket)).unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content[0..2], [0x1f, 0x8b]);
+
+        assert_eq!(history.read_rule_history(&rule_ticket).unwrap(), rule_history);
+    }
+
     /*  Plant a RuleHistory file with wrong data in it.  Attempt to load that, and check we get the expected error. */
     #[test]
     fn history_with_file_tampering()
@@ -558,4 +768,40 @@ mod test
             Err(error) => panic!("Reading RuleHistory errored but with the wrong error: {}", error),
         }
     }
+
+    /*  A rule history written before entries recorded when they went in still reads, with its entries
+        made at the epoch.  Inserting what's already there leaves the time as it was, overwriting
+        replaces it. */
+    #[test]
+    fn history_without_creation_times_reads_as_epoch()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir("history").unwrap();
+
+        let source_ticket = TicketFactory::from_str("source").result();
+        let file_state_vec = FileStateVec::from_ticket_vec(vec![TicketFactory::from_str("target").result()]);
+        let mut source_to_targets = HashMap::new();
+        source_to_targets.insert(source_ticket.clone(), file_state_vec.clone());
+        let older = RuleHistoryWithoutCreationTimes
+        {
+            source_to_targets : source_to_targets,
+        };
+
+        let rule_ticket = TicketFactory::from_str("rule").result();
+        system.create_file(&format!("history/{}", rule_ticket)).unwrap()
+            .write_all(&bincode::serialize(&older).unwrap()).unwrap();
+
+        let mut rule_history = History::new(system.clone(), "history").read_rule_history(&rule_ticket).unwrap();
+        assert_eq!(rule_history.get_file_state_vec(&source_ticket), Some(&file_state_vec));
+        assert_eq!(rule_history.get_created_at(&source_ticket), Some(0));
+
+        rule_history.insert(source_ticket.clone(), file_state_vec.clone(), 500).unwrap();
+        assert_eq!(rule_history.get_created_at(&source_ticket), Some(0));
+
+        rule_history.overwrite(source_ticket.clone(), file_state_vec.clone(), 700);
+        assert_eq!(rule_history.get_created_at(&source_ticket), Some(700));
+        assert_eq!(rule_history.remove_older_than(700), 0);
+        assert_eq!(rule_history.remove_older_than(701), 1);
+        assert!(rule_history.is_empty());
+    }
 }
@@ -0,0 +1,196 @@
+use crate::system::
+{
+    System,
+    SystemError,
+    ReadWriteError,
+};
+use crate::system::util::read_file_to_string;
+
+/*  The file listing names of things to leave out of directory targets and sources, one glob per line. */
+pub const IGNORE_FILE_PATH : &str = ".rulerignore";
+
+/*  Globs matched against the name of each thing inside a directory, at any depth.  What matches counts
+    neither toward the directory's ticket nor as part of the directory in the cache.  A matching
+    directory is left out along with everything in it. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct IgnorePatterns
+{
+    patterns : Vec<String>,
+}
+
+/*  Whether name matches pattern, where '*' matches any run of characters and '?' any one. */
+fn glob_matches(pattern : &[char], name : &[char]) -> bool
+{
+    match pattern.split_first()
+    {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| glob_matches(rest, &name[skip..])),
+        Some(('?', rest)) => ! name.is_empty() && glob_matches(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_matches(rest, &name[1..]),
+    }
+}
+
+/*  The last component of a path. */
+fn get_name(path : &str) -> &str
+{
+    match path.rsplit_once('/')
+    {
+        Some((_directory, name)) => name,
+        None => path,
+    }
+}
+
+impl IgnorePatterns
+{
+    pub fn new() -> IgnorePatterns
+    {
+        IgnorePatterns
+        {
+            patterns : vec![],
+        }
+    }
+
+    /*  One pattern per line.  Blank lines and lines starting with '#' don't count, and a trailing '/'
+        is dropped, since the pattern matches a directory by its name either way. */
+    pub fn from_str(content : &str) -> IgnorePatterns
+    {
+        IgnorePatterns
+        {
+            patterns : content.lines().map(|line| line.trim()).filter(
+                |line| *line != "" && ! line.starts_with('#')).map(
+                |line| line.trim_end_matches('/').to_string()).collect(),
+        }
+    }
+
+    /*  Reads IGNORE_FILE_PATH through the system.  No file means nothing is ignored. */
+    pub fn from_system<SystemType : System>(system : &SystemType) -> Result<IgnorePatterns, ReadWriteError>
+    {
+        if ! system.is_file(IGNORE_FILE_PATH)
+        {
+            return Ok(IgnorePatterns::new());
+        }
+
+        match read_file_to_string(system, IGNORE_FILE_PATH)
+        {
+            Ok(content) => Ok(IgnorePatterns::from_str(&content)),
+            Err(error) => Err(ReadWriteError::IOError(error.to_string())),
+        }
+    }
+
+    /*  Whether the thing at path, inside some directory, gets left out.  Only its name counts. */
+    pub fn is_ignored(&self, path : &str) -> bool
+    {
+        let name : Vec<char> = get_name(path).chars().collect();
+        self.patterns.iter().any(
+            |pattern| glob_matches(&pattern.chars().collect::<Vec<char>>(), &name))
+    }
+}
+
+/*  Removes whatever is at path, directory or file. */
+fn remove_all<SystemType : System>
+(
+    system : &mut SystemType,
+    path : &str
+)
+-> Result<(), SystemError>
+{
+    if system.is_dir(path)
+    {
+        for child_path in system.list_dir(path)?
+        {
+            remove_all(system, &child_path)?;
+        }
+        system.remove_dir(path)
+    }
+    else
+    {
+        system.remove_file(path)
+    }
+}
+
+/*  Removes everything ignored from inside the directory at directory_path, at any depth. */
+pub fn remove_ignored<SystemType : System>
+(
+    system : &mut SystemType,
+    directory_path : &str,
+    ignore_patterns : &IgnorePatterns
+)
+-> Result<(), SystemError>
+{
+    for path in system.list_dir(directory_path)?
+    {
+        if ignore_patterns.is_ignored(&path)
+        {
+            remove_all(system, &path)?;
+        }
+        else if system.is_dir(&path)
+        {
+            remove_ignored(system, &path, ignore_patterns)?;
+        }
+    }
+    Ok(())
+}
+
+/*  Moves the directory at from_path to to_path.  If there's a directory at to_path already, moves the
+    contents over instead, one by one, so that ignored things already at to_path stay as they are.
+    Ignored things inside from_path don't get moved, they get removed along with from_path. */
+pub fn move_directory<SystemType : System>
+(
+    system : &mut SystemType,
+    from_path : &str,
+    to_path : &str,
+    ignore_patterns : &IgnorePatterns
+)
+-> Result<(), SystemError>
+{
+    if ! system.is_dir(to_path)
+    {
+        system.rename(from_path, to_path)?;
+        return remove_ignored(system, to_path, ignore_patterns);
+    }
+
+    for path in system.list_dir(from_path)?
+    {
+        if ignore_patterns.is_ignored(&path)
+        {
+            continue;
+        }
+
+        let destination = format!("{}/{}", to_path, get_name(&path));
+        if system.is_dir(&path) && system.is_dir(&destination)
+        {
+            move_directory(system, &path, &destination, ignore_patterns)?;
+        }
+        else
+        {
+            if system.is_dir(&destination) || system.is_file(&destination)
+            {
+                remove_all(system, &destination)?;
+            }
+            system.rename(&path, &destination)?;
+        }
+    }
+
+    remove_all(system, from_path)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::ignore::IgnorePatterns;
+
+    /*  Patterns match names at any depth, with '*' and '?' as wildcards, and comments and blank lines
+        in the file count for nothing. */
+    #[test]
+    fn ignore_patterns_match_names()
+    {
+        let ignore_patterns = IgnorePatterns::from_str("# volatile\n.DS_Store\n\n*.sw?\n__pycache__/\n");
+        assert!(ignore_patterns.is_ignored("out/.DS_Store"));
+        assert!(ignore_patterns.is_ignored("out/deep/.main.c.swp"));
+        assert!(ignore_patterns.is_ignored("out/__pycache__"));
+        assert!(!ignore_patterns.is_ignored("out/main.c"));
+        assert!(!ignore_patterns.is_ignored("out/.main.c.sw"));
+        assert!(!ignore_patterns.is_ignored("out/# volatile"));
+        assert!(!IgnorePatterns::new().is_ignored("out/.DS_Store"));
+    }
+}
@@ -0,0 +1,345 @@
+use std::fmt;
+use std::io::Write;
+use termcolor::Color;
+use crate::directory::
+{
+    self,
+    InitDirectoryError,
+};
+use crate::printer::Printer;
+use crate::system::
+{
+    System,
+    ReadWriteError,
+};
+use crate::system::util::
+{
+    read_file_to_string,
+    write_str_to_file,
+    ReadFileToStringError,
+};
+
+/*  Which starter rules file "ruler init" writes. */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Template
+{
+    C,
+    Script,
+    Empty,
+}
+
+/*  A C program: compile main.c to an object file, then link it. */
+const C_TEMPLATE : &str = "\
+# Starter rules for a C program.
+#
+# Each rule lists its targets, then its sources, then the command that makes the
+# targets from the sources, each section ending with ':' alone on a line.  The
+# command goes one word per line.  Rules are separated by an empty line.  Lines
+# starting with '#' are comments, except in a command, where they go to the shell.
+#
+# Run 'ruler build' to build everything, or 'ruler build app' for one target.
+
+build/main.o
+:
+main.c
+:
+cc
+-c
+main.c
+-o
+build/main.o
+:
+
+build/app
+:
+build/main.o
+:
+cc
+build/main.o
+-o
+build/app
+:
+";
+
+/*  A script that turns an input file into an output file. */
+const SCRIPT_TEMPLATE : &str = "\
+# Starter rules for a script.
+#
+# Each rule lists its targets, then its sources, then the command that makes the
+# targets from the sources, each section ending with ':' alone on a line.  The
+# command goes one word per line.  Rules are separated by an empty line.  Lines
+# starting with '#' are comments, except in a command, where they go to the shell.
+#
+# The script is a source too, so editing it rebuilds the output.
+
+output.txt
+:
+generate.sh
+input.txt
+:
+sh
+generate.sh
+input.txt
+output.txt
+:
+";
+
+/*  Only the explanation, no rules. */
+const EMPTY_TEMPLATE : &str = "\
+# Rules for ruler.
+#
+# Each rule lists its targets, then its sources, then the command that makes the
+# targets from the sources, each section ending with ':' alone on a line.  The
+# command goes one word per line.  Rules are separated by an empty line.  Lines
+# starting with '#' are comments, except in a command, where they go to the shell.
+#
+# For example:
+#
+# poem.txt
+# :
+# verse1.txt
+# verse2.txt
+# :
+# @cat
+# verse1.txt
+# verse2.txt
+# poem.txt
+# :
+";
+
+impl Template
+{
+    pub fn from_name(name : &str) -> Option<Template>
+    {
+        match name
+        {
+            "c" => Some(Template::C),
+            "script" => Some(Template::Script),
+            "empty" => Some(Template::Empty),
+            _ => None,
+        }
+    }
+
+    fn get_content(&self) -> &'static str
+    {
+        match self
+        {
+            Template::C => C_TEMPLATE,
+            Template::Script => SCRIPT_TEMPLATE,
+            Template::Empty => EMPTY_TEMPLATE,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum InitError
+{
+    RulesFileExists(String),
+    CannotWriteRulesFile(String, ReadWriteError),
+    DirectoryInitFailed(InitDirectoryError),
+    CannotReadGitignore(String, ReadFileToStringError),
+    CannotWriteGitignore(String, String),
+}
+
+impl fmt::Display for InitError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            InitError::RulesFileExists(path) =>
+                write!(formatter, "Rules file already exists: {} (use --force to overwrite it)", path),
+
+            InitError::CannotWriteRulesFile(path, error) =>
+                write!(formatter, "Cannot write rules file: {}: {}", path, error),
+
+            InitError::DirectoryInitFailed(error) =>
+                write!(formatter, "Failed to initialize ruler directory: {}", error),
+
+            InitError::CannotReadGitignore(path, error) =>
+                write!(formatter, "Cannot read {}: {}", path, error),
+
+            InitError::CannotWriteGitignore(path, error) =>
+                write!(formatter, "Cannot write {}: {}", path, error),
+        }
+    }
+}
+
+const GITIGNORE_PATH : &str = ".gitignore";
+
+/*  Adds the ruler directory to an existing .gitignore, unless some line already names it.  Appends
+    rather than rewriting, so nothing else in the file changes.  Returns whether it added the line. */
+fn add_to_gitignore<SystemType : System>
+(
+    system : &mut SystemType,
+    directory_path : &str
+)
+-> Result<bool, InitError>
+{
+    let content =
+    match read_file_to_string(system, GITIGNORE_PATH)
+    {
+        Ok(content) => content,
+        Err(error) => return Err(InitError::CannotReadGitignore(GITIGNORE_PATH.to_string(), error)),
+    };
+
+    let directory_name = directory_path.trim_end_matches('/');
+    if content.lines().map(|line| line.trim().trim_start_matches('/').trim_end_matches('/')).any(|line| line == directory_name)
+    {
+        return Ok(false);
+    }
+
+    let mut addition = String::new();
+    if content.len() > 0 && ! content.ends_with('\n')
+    {
+        addition.push('\n');
+    }
+    addition.push_str(&format!("{}/\n", directory_name));
+
+    let mut file =
+    match system.append_to_file(GITIGNORE_PATH)
+    {
+        Ok(file) => file,
+        Err(error) => return Err(InitError::CannotWriteGitignore(GITIGNORE_PATH.to_string(), error.to_string())),
+    };
+
+    match file.write_all(addition.as_bytes())
+    {
+        Ok(()) => Ok(true),
+        Err(error) => Err(InitError::CannotWriteGitignore(GITIGNORE_PATH.to_string(), error.to_string())),
+    }
+}
+
+/*  This is the function that runs when you type "ruler init" at the command-line.  Writes a starter
+    rules file from the template, creates the ruler directory, and if there's a .gitignore, adds the
+    ruler directory to it.  An existing rules file stays as it is unless force is true. */
+pub fn init
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &mut SystemType,
+    printer : &mut PrinterType,
+    directory_path : &str,
+    rules_path : &str,
+    template : Template,
+    force : bool
+)
+-> Result<(), InitError>
+{
+    if (system.is_file(rules_path) || system.is_dir(rules_path)) && ! force
+    {
+        return Err(InitError::RulesFileExists(rules_path.to_string()));
+    }
+
+    match write_str_to_file(system, rules_path, template.get_content())
+    {
+        Ok(()) => printer.print_single_banner_line("   Created", Color::Green, rules_path),
+        Err(error) => return Err(InitError::CannotWriteRulesFile(rules_path.to_string(), error)),
+    }
+
+    match directory::init(system, directory_path, None, None, false)
+    {
+        Ok(_elements) => printer.print_single_banner_line("   Created", Color::Green, directory_path),
+        Err(error) => return Err(InitError::DirectoryInitFailed(error)),
+    }
+
+    if system.is_file(GITIGNORE_PATH)
+    {
+        if add_to_gitignore(system, directory_path)?
+        {
+            printer.print_single_banner_line("   Updated", Color::Green, GITIGNORE_PATH);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::init::
+    {
+        init,
+        InitError,
+        Template,
+    };
+    use crate::printer::
+    {
+        EmptyPrinter,
+        RecordingPrinter,
+    };
+    use crate::rule::parse_all;
+    use crate::system::
+    {
+        System,
+        fake::FakeSystem,
+    };
+    use crate::system::util::
+    {
+        read_file_to_string,
+        write_str_to_file,
+    };
+
+    /*  Each template makes a rules file that parses, the C and script ones with rules in them. */
+    #[test]
+    fn init_templates_parse()
+    {
+        for (name, rule_count) in [("c", 2), ("script", 1), ("empty", 0)]
+        {
+            let mut system = FakeSystem::new(10);
+            let template = Template::from_name(name).unwrap();
+            init(&mut system, &mut EmptyPrinter::new(), ".ruler", "build.rules", template, false).unwrap();
+
+            let content = read_file_to_string(&system, "build.rules").unwrap();
+            let rules = parse_all(vec![("build.rules".to_string(), content)]).unwrap();
+            assert_eq!(rules.len(), rule_count, "template {}", name);
+            assert!(system.is_dir(".ruler"));
+        }
+
+        assert_eq!(Template::from_name("cobol"), None);
+    }
+
+    /*  With no .gitignore, init doesn't make one.  With one, it adds the ruler directory once,
+        however many times init runs, keeping what was there. */
+    #[test]
+    fn init_appends_to_gitignore_once()
+    {
+        let mut system = FakeSystem::new(10);
+        init(&mut system, &mut EmptyPrinter::new(), ".ruler", "build.rules", Template::Empty, false).unwrap();
+        assert!(!system.is_file(".gitignore"));
+
+        write_str_to_file(&mut system, ".gitignore", "target").unwrap();
+        let mut printer = RecordingPrinter::new();
+        init(&mut system, &mut printer, ".ruler", "build.rules", Template::Empty, true).unwrap();
+        assert_eq!(read_file_to_string(&system, ".gitignore").unwrap(), "target\n.ruler/\n");
+        assert_eq!(printer.printed, vec![
+            "   Created build.rules",
+            "   Created .ruler",
+            "   Updated .gitignore",
+        ]);
+
+        init(&mut system, &mut EmptyPrinter::new(), ".ruler", "build.rules", Template::Empty, true).unwrap();
+        assert_eq!(read_file_to_string(&system, ".gitignore").unwrap(), "target\n.ruler/\n");
+    }
+
+    /*  An existing rules file stays put without force, and nothing else gets created. */
+    #[test]
+    fn init_refuses_to_overwrite_rules()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "build.rules", "mine\n").unwrap();
+
+        match init(&mut system, &mut EmptyPrinter::new(), ".ruler", "build.rules", Template::C, false)
+        {
+            Err(InitError::RulesFileExists(path)) => assert_eq!(path, "build.rules"),
+            _ => panic!("Expected init to refuse to overwrite the rules file"),
+        }
+        assert_eq!(read_file_to_string(&system, "build.rules").unwrap(), "mine\n");
+        assert!(!system.is_dir(".ruler"));
+
+        init(&mut system, &mut EmptyPrinter::new(), ".ruler", "build.rules", Template::C, true).unwrap();
+        assert!(read_file_to_string(&system, "build.rules").unwrap().starts_with("# Starter rules for a C program."));
+    }
+}
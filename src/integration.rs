@@ -0,0 +1,179 @@
+/*  End-to-end tests against the real filesystem and real child processes, each in a sandbox of its
+    own.  They're ignored by default since they touch the disk and need a Unix shell; run them with
+
+        cargo test -- --ignored
+
+    Commands go through logged.sh, which appends each command to commands.log before running it, so
+    counting lines in that file tells how many commands a build actually executed. */
+
+use crate::build::
+{
+    build,
+    clean,
+    run,
+    BuildParams,
+    BuildError,
+};
+use crate::work::WorkError;
+use crate::printer::EmptyPrinter;
+use crate::ticket::TicketFactory;
+use crate::system::System;
+use crate::system::sandbox::Sandbox;
+
+const LOGGED_SH : &str = "echo \"$@\" >> commands.log\nexec \"$@\"\n";
+
+const POEM_RULES : &str = "\
+poem.txt
+:
+verse1.txt
+verse2.txt
+:
+sh logged.sh cat verse1.txt verse2.txt > poem.txt
+:
+";
+
+/*  A sandbox with the logging script, two verses and the given rules. */
+fn make_poem_sandbox(name : &str, rules : &str) -> Sandbox
+{
+    let sandbox = Sandbox::new(name);
+    sandbox.write("logged.sh", LOGGED_SH);
+    sandbox.write("verse1.txt", "Roses are red.\n");
+    sandbox.write("verse2.txt", "Violets are blue.\n");
+    sandbox.write("build.rules", rules);
+    sandbox
+}
+
+/*  How many commands have run through logged.sh. */
+fn count_commands(sandbox : &Sandbox) -> usize
+{
+    if sandbox.system().is_file("commands.log")
+    {
+        sandbox.read("commands.log").lines().count()
+    }
+    else
+    {
+        0
+    }
+}
+
+#[test]
+#[ignore]
+fn real_build_basic()
+{
+    let sandbox = make_poem_sandbox("basic", POEM_RULES);
+    build(sandbox.system(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+    assert_eq!(sandbox.read("poem.txt"), "Roses are red.\nViolets are blue.\n");
+    assert_eq!(count_commands(&sandbox), 1);
+    assert!(sandbox.system().is_dir(".ruler/history"));
+}
+
+/*  An absolute path stays absolute: a file written through one lands at that path, not under the
+    current directory, and reads back the same through the relative path. */
+#[test]
+#[ignore]
+fn real_absolute_path()
+{
+    let sandbox = make_poem_sandbox("absolute", POEM_RULES);
+    let absolute_path = format!("{}/absolute.txt", sandbox.path.to_str().unwrap());
+    assert!(absolute_path.starts_with('/'));
+
+    sandbox.write(&absolute_path, "Roses are red.\n");
+    assert_eq!(sandbox.read("absolute.txt"), "Roses are red.\n");
+    assert!(sandbox.system().is_file(&absolute_path));
+    assert!(!sandbox.path.join(absolute_path.trim_start_matches('/')).exists());
+}
+
+/*  A second build with nothing changed runs nothing, and changing a source runs the command again. */
+#[test]
+#[ignore]
+fn real_build_incremental()
+{
+    let sandbox = make_poem_sandbox("incremental", POEM_RULES);
+    build(sandbox.system(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+    build(sandbox.system(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+    assert_eq!(count_commands(&sandbox), 1);
+
+    sandbox.write("verse2.txt", "Violets are violet.\n");
+    build(sandbox.system(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+    assert_eq!(count_commands(&sandbox), 2);
+    assert_eq!(sandbox.read("poem.txt"), "Roses are red.\nViolets are violet.\n");
+}
+
+/*  Clean moves the poem into the cache under its ticket, and the next build recovers it from there
+    without running the command. */
+#[test]
+#[ignore]
+fn real_clean_and_recover()
+{
+    let sandbox = make_poem_sandbox("clean", POEM_RULES);
+    build(sandbox.system(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+    clean(sandbox.system(), &mut EmptyPrinter::new(), BuildParams::for_test()).unwrap();
+
+    let poem = "Roses are red.\nViolets are blue.\n";
+    assert!(!sandbox.system().is_file("poem.txt"));
+    assert_eq!(
+        sandbox.read(&format!(".ruler/cache/{}", TicketFactory::from_str(poem).result().human_readable())),
+        poem);
+
+    build(sandbox.system(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+    assert_eq!(sandbox.read("poem.txt"), poem);
+    assert_eq!(count_commands(&sandbox), 1);
+}
+
+/*  The command reads verse2.txt but the rule doesn't declare it.  Changing verse2.txt and forcing a
+    rebuild makes the same sources give a different poem, which contradicts the history. */
+#[test]
+#[ignore]
+fn real_build_contradiction()
+{
+    let sandbox = make_poem_sandbox("contradiction", "\
+poem.txt
+:
+verse1.txt
+:
+sh logged.sh cat verse1.txt verse2.txt > poem.txt
+:
+");
+    build(sandbox.system(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+    sandbox.write("verse2.txt", "Violets are violet.\n");
+    sandbox.write("poem.txt", "Wrong content forcing a rebuild");
+
+    match build(sandbox.system(), &mut EmptyPrinter::new(), None, BuildParams::for_test())
+    {
+        Err(BuildError::WorkErrors(work_errors)) =>
+        {
+            assert_eq!(work_errors.len(), 1);
+            match &work_errors[0].1
+            {
+                WorkError::Contradiction(paths, _provenance) => assert_eq!(paths, &vec!["poem.txt".to_string()]),
+                error => panic!("Wrong kind of work error: {}", error),
+            }
+        },
+        Ok(()) => panic!("Unexpected success when a contradiction should arise"),
+        Err(error) => panic!("Wrong kind of error: {}", error),
+    }
+}
+
+/*  Run builds a script from its source, then runs it with the extra arguments. */
+#[test]
+#[ignore]
+fn real_run()
+{
+    let sandbox = make_poem_sandbox("run", "\
+hello.sh
+:
+hello.src
+:
+sh logged.sh cp hello.src hello.sh && chmod +x hello.sh
+:
+");
+    sandbox.write("hello.src", "#!/bin/sh\necho \"$1\" > ran.txt\n");
+
+    run(sandbox.system(), &mut EmptyPrinter::new(), BuildParams::for_test(), "hello.sh".to_string(),
+        vec!["hi".to_string()]).ok().unwrap();
+
+    assert_eq!(sandbox.read("ran.txt"), "hi\n");
+    assert_eq!(count_commands(&sandbox), 1);
+}
@@ -0,0 +1,558 @@
+use std::fmt;
+use std::io::
+{
+    Read,
+    Write,
+};
+use serde::
+{
+    Serialize,
+    Deserialize,
+};
+use termcolor::Color;
+use crate::ledger::format_timestamp;
+use crate::printer::Printer;
+use crate::system::
+{
+    System,
+    SystemError,
+};
+use crate::system::util::get_timestamp;
+
+/*  How many invocations the journal keeps.  Past that, the oldest go, so the file stays small no
+    matter how long the checkout lives. */
+pub const DEFAULT_JOURNAL_MAX_RECORDS : usize = 50;
+
+/*  How an invocation ended.  Interrupted means it never got to say: the next invocation found its
+    record unfinished, say because the process was killed or the machine went down. */
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Outcome
+{
+    Succeeded,
+    Failed(String),
+    Interrupted,
+}
+
+impl fmt::Display for Outcome
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Outcome::Succeeded =>
+                write!(formatter, "succeeded"),
+
+            Outcome::Failed(message) =>
+                write!(formatter, "failed: {}", message),
+
+            Outcome::Interrupted =>
+                write!(formatter, "interrupted"),
+        }
+    }
+}
+
+/*  One ruler command that changed (or set out to change) the state of the ruler directory: the
+    subcommand, its goal target if it had one, the arguments exactly as given on the command-line, and
+    when it started and ended, in microseconds since the epoch.  A record with no outcome is still
+    running, or else it never finished. */
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct JournalRecord
+{
+    pub subcommand : String,
+    pub target_opt : Option<String>,
+    pub arguments : Vec<String>,
+    pub start_timestamp : u64,
+    pub end_timestamp_opt : Option<u64>,
+    pub outcome_opt : Option<Outcome>,
+}
+
+impl JournalRecord
+{
+    pub fn new(subcommand : &str, target_opt : Option<String>, arguments : Vec<String>) -> JournalRecord
+    {
+        JournalRecord
+        {
+            subcommand : subcommand.to_string(),
+            target_opt : target_opt,
+            arguments : arguments,
+            start_timestamp : 0,
+            end_timestamp_opt : None,
+            outcome_opt : None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum JournalError
+{
+    CannotCreateDirectory(String, SystemError),
+    CannotReadJournalFile(String, String),
+    CannotInterpretJournalFile(String),
+    CannotWriteJournalFile(String, String),
+}
+
+impl fmt::Display for JournalError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            JournalError::CannotCreateDirectory(path, error) =>
+                write!(formatter, "Cannot create directory for journal: {}: {}", path, error),
+
+            JournalError::CannotReadJournalFile(path, error) =>
+                write!(formatter, "Cannot read journal file: {}: {}", path, error),
+
+            JournalError::CannotInterpretJournalFile(path) =>
+                write!(formatter, "Cannot interpret journal file: {}", path),
+
+            JournalError::CannotWriteJournalFile(path, error) =>
+                write!(formatter, "Cannot write journal file: {}: {}", path, error),
+        }
+    }
+}
+
+/*  The path of the journal file inside the ruler directory. */
+pub fn get_journal_path(directory_path : &str) -> String
+{
+    format!("{}/journal", directory_path)
+}
+
+/*  Reads every record in the journal, oldest first.  If there's no journal yet, returns no records. */
+pub fn read_journal<SystemType : System>
+(
+    system : &SystemType,
+    path : &str
+)
+-> Result<Vec<JournalRecord>, JournalError>
+{
+    if ! system.is_file(path)
+    {
+        return Ok(vec![]);
+    }
+
+    let mut content = vec![];
+    match system.open(path)
+    {
+        Ok(mut file) => match file.read_to_end(&mut content)
+        {
+            Ok(_size) => {},
+            Err(error) => return Err(JournalError::CannotReadJournalFile(path.to_string(), format!("{}", error))),
+        },
+        Err(error) => return Err(JournalError::CannotReadJournalFile(path.to_string(), format!("{}", error))),
+    }
+
+    match bincode::deserialize(&content)
+    {
+        Ok(records) => Ok(records),
+        Err(_error) => Err(JournalError::CannotInterpretJournalFile(path.to_string())),
+    }
+}
+
+fn write_journal<SystemType : System>
+(
+    system : &mut SystemType,
+    path : &str,
+    records : &Vec<JournalRecord>
+)
+-> Result<(), JournalError>
+{
+    let content =
+    match bincode::serialize(records)
+    {
+        Ok(content) => content,
+        Err(error) => return Err(JournalError::CannotWriteJournalFile(path.to_string(), format!("{}", error))),
+    };
+
+    match system.create_file(path)
+    {
+        Ok(mut file) => match file.write_all(&content)
+        {
+            Ok(_) => Ok(()),
+            Err(error) => Err(JournalError::CannotWriteJournalFile(path.to_string(), format!("{}", error))),
+        },
+        Err(error) => Err(JournalError::CannotWriteJournalFile(path.to_string(), format!("{}", error))),
+    }
+}
+
+/*  Appends the record to the journal, stamped with the current time, creating the ruler directory if
+    need be, then drops the oldest records until there are at most max_records.  Any earlier record
+    still without an outcome belongs to an invocation that never finished (ruler doesn't run two
+    commands in one directory at once), so those get marked interrupted.  Returns the start time, to
+    hand to finish_invocation, and the records newly marked interrupted. */
+pub fn start_invocation<SystemType : System>
+(
+    system : &mut SystemType,
+    directory_path : &str,
+    mut record : JournalRecord,
+    max_records : usize
+)
+-> Result<(u64, Vec<JournalRecord>), JournalError>
+{
+    if ! system.is_dir(directory_path)
+    {
+        match system.create_dir_all(directory_path)
+        {
+            Ok(_) => {},
+            Err(error) => return Err(JournalError::CannotCreateDirectory(directory_path.to_string(), error)),
+        }
+    }
+
+    let path = get_journal_path(directory_path);
+    let mut records = read_journal(system, &path)?;
+
+    let mut interrupted = vec![];
+    for earlier in records.iter_mut()
+    {
+        if earlier.outcome_opt == None
+        {
+            earlier.outcome_opt = Some(Outcome::Interrupted);
+            interrupted.push(earlier.clone());
+        }
+    }
+
+    record.start_timestamp = get_timestamp(system.now()).unwrap_or(0);
+    record.end_timestamp_opt = None;
+    record.outcome_opt = None;
+    let start_timestamp = record.start_timestamp;
+    records.push(record);
+
+    if records.len() > max_records
+    {
+        records.drain(..records.len() - max_records);
+    }
+
+    write_journal(system, &path, &records)?;
+    Ok((start_timestamp, interrupted))
+}
+
+/*  Gives the newest unfinished record started at start_timestamp its end time and outcome.  If the cap
+    already evicted it, there's nothing to do. */
+pub fn finish_invocation<SystemType : System>
+(
+    system : &mut SystemType,
+    directory_path : &str,
+    start_timestamp : u64,
+    outcome : Outcome
+)
+-> Result<(), JournalError>
+{
+    let path = get_journal_path(directory_path);
+    let mut records = read_journal(system, &path)?;
+
+    match records.iter_mut().rev().find(
+        |record| record.start_timestamp == start_timestamp && record.outcome_opt == None)
+    {
+        Some(record) =>
+        {
+            record.end_timestamp_opt = Some(get_timestamp(system.now()).unwrap_or(0));
+            record.outcome_opt = Some(outcome);
+        },
+        None => return Ok(()),
+    }
+
+    write_journal(system, &path, &records)
+}
+
+/*  Starts a record of an invocation in the journal.  A journal that can't be read or written
+    shouldn't stop the command, so problems with it only get printed as warnings, as do earlier
+    invocations found to have been interrupted.  Returns the start time to hand to end, if the record
+    got written. */
+pub fn begin
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &mut SystemType,
+    printer : &mut PrinterType,
+    directory_path : &str,
+    record : JournalRecord,
+    max_records : usize
+)
+-> Option<u64>
+{
+    match start_invocation(system, directory_path, record, max_records)
+    {
+        Ok((start_timestamp, interrupted)) =>
+        {
+            for earlier in interrupted
+            {
+                printer.print_single_banner_line("   Warning", Color::Yellow,
+                    &format!("ruler {} started {} never finished; journaled as interrupted",
+                        earlier.subcommand, format_timestamp(earlier.start_timestamp)));
+            }
+            Some(start_timestamp)
+        },
+        Err(error) =>
+        {
+            printer.print_single_banner_line("   Warning", Color::Yellow, &format!("{}", error));
+            None
+        },
+    }
+}
+
+/*  Finishes the record begin started, with the outcome taken from the command's result. */
+pub fn end
+<
+    SystemType : System,
+    PrinterType : Printer,
+    ResultType,
+    ErrorType : fmt::Display,
+>
+(
+    system : &mut SystemType,
+    printer : &mut PrinterType,
+    directory_path : &str,
+    start_timestamp_opt : Option<u64>,
+    result : &Result<ResultType, ErrorType>
+)
+{
+    let start_timestamp =
+    match start_timestamp_opt
+    {
+        Some(start_timestamp) => start_timestamp,
+        None => return,
+    };
+
+    let outcome =
+    match result
+    {
+        Ok(_) => Outcome::Succeeded,
+        Err(error) => Outcome::Failed(format!("{}", error)),
+    };
+
+    match finish_invocation(system, directory_path, start_timestamp, outcome)
+    {
+        Ok(()) => {},
+        Err(error) => printer.print_single_banner_line("   Warning", Color::Yellow, &format!("{}", error)),
+    }
+}
+
+/*  This is the function that runs when you type "ruler last" at the command-line.  Prints the most
+    recent count records in the journal, newest first: when each started, how long it took, how it
+    ended and the command-line it ran with.  Returns the records printed. */
+pub fn last
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &SystemType,
+    printer : &mut PrinterType,
+    directory_path : &str,
+    count : usize
+)
+-> Result<Vec<JournalRecord>, JournalError>
+{
+    let mut records = read_journal(system, &get_journal_path(directory_path))?;
+    records.reverse();
+    records.truncate(count);
+
+    if records.len() == 0
+    {
+        printer.print_single_banner_line("   Nothing", Color::Yellow, "in the journal yet");
+        return Ok(records);
+    }
+
+    printer.print(&format!("{:<19}  {:<19}  {:<11}  {}", "Started (UTC)", "Ended (UTC)", "Outcome", "Command"));
+    for record in records.iter()
+    {
+        let ended =
+        match record.end_timestamp_opt
+        {
+            Some(end_timestamp) => format_timestamp(end_timestamp),
+            None => "-".to_string(),
+        };
+
+        let outcome =
+        match &record.outcome_opt
+        {
+            Some(Outcome::Failed(_)) => "failed".to_string(),
+            Some(outcome) => format!("{}", outcome),
+            None => "unfinished".to_string(),
+        };
+
+        let mut command = vec!["ruler".to_string()];
+        command.extend(record.arguments.iter().cloned());
+
+        printer.print(&format!("{:<19}  {:<19}  {:<11}  {}",
+            format_timestamp(record.start_timestamp), ended, outcome, command.join(" ")));
+
+        match &record.outcome_opt
+        {
+            Some(Outcome::Failed(message)) => printer.print(&format!("    {}", message)),
+            _ => {},
+        }
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::journal::
+    {
+        JournalRecord,
+        Outcome,
+        DEFAULT_JOURNAL_MAX_RECORDS,
+        get_journal_path,
+        begin,
+        end,
+        last,
+        read_journal,
+        start_invocation,
+    };
+    use crate::build::
+    {
+        build,
+        clean,
+        BuildError,
+        BuildParams,
+    };
+    use crate::printer::RecordingPrinter;
+    use crate::system::System;
+    use crate::system::fake::FakeSystem;
+    use crate::system::util::write_str_to_file;
+
+    fn make_params() -> BuildParams
+    {
+        let mut params = BuildParams::for_test();
+        params.variant_opt = Some("debug".to_string());
+        params
+    }
+
+    fn make_poem_system() -> FakeSystem
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules",
+            "poem.txt\n:\nverse1.txt\nverse2.txt\n:\nmycat\nverse1.txt\nverse2.txt\npoem.txt\n:\n").unwrap();
+        system
+    }
+
+    fn to_arguments(line : &str) -> Vec<String>
+    {
+        line.split(' ').map(|argument| argument.to_string()).collect()
+    }
+
+    /*  Builds the poem between begin and end, the way main does. */
+    fn journaled_build(system : &FakeSystem, printer : &mut RecordingPrinter, record : JournalRecord) -> Result<(), BuildError>
+    {
+        let start_timestamp_opt = begin(&mut system.clone(), printer, ".ruler", record, DEFAULT_JOURNAL_MAX_RECORDS);
+        let result = build(system.clone(), printer, None, make_params());
+        end(&mut system.clone(), printer, ".ruler", start_timestamp_opt, &result);
+        result
+    }
+
+    /*  A build and then a clean each leave a finished record, in the order they ran, with what they
+        were asked to do and when. */
+    #[test]
+    fn journal_build_then_clean()
+    {
+        let mut system = make_poem_system();
+        let mut printer = RecordingPrinter::new();
+
+        journaled_build(&system, &mut printer,
+            JournalRecord::new("build", Some("poem.txt".to_string()), to_arguments("--variant debug build poem.txt"))).unwrap();
+
+        system.time_passes(5);
+
+        let start_timestamp_opt = begin(&mut system, &mut printer, ".ruler",
+            JournalRecord::new("clean", None, to_arguments("--variant debug clean")), DEFAULT_JOURNAL_MAX_RECORDS);
+        let result = clean(system.clone(), &mut printer, make_params());
+        system.time_passes(2);
+        end(&mut system, &mut printer, ".ruler", start_timestamp_opt, &result);
+        result.unwrap();
+
+        let records = read_journal(&system, &get_journal_path(".ruler")).unwrap();
+        assert_eq!(records.len(), 2);
+
+        assert_eq!(records[0].subcommand, "build");
+        assert_eq!(records[0].target_opt, Some("poem.txt".to_string()));
+        assert_eq!(records[0].arguments, to_arguments("--variant debug build poem.txt"));
+        assert_eq!(records[0].start_timestamp, 10);
+        assert_eq!(records[0].end_timestamp_opt, Some(10));
+        assert_eq!(records[0].outcome_opt, Some(Outcome::Succeeded));
+
+        assert_eq!(records[1].subcommand, "clean");
+        assert_eq!(records[1].target_opt, None);
+        assert_eq!(records[1].arguments, to_arguments("--variant debug clean"));
+        assert_eq!(records[1].start_timestamp, 15);
+        assert_eq!(records[1].end_timestamp_opt, Some(17));
+        assert_eq!(records[1].outcome_opt, Some(Outcome::Succeeded));
+
+        assert!(printer.printed.iter().all(|line| ! line.contains("Warning")));
+
+        let mut printer = RecordingPrinter::new();
+        assert_eq!(last(&system, &mut printer, ".ruler", 1).unwrap().len(), 1);
+        assert_eq!(printer.printed.len(), 2);
+        assert!(printer.printed[1].contains("succeeded"));
+        assert!(printer.printed[1].ends_with("ruler --variant debug clean"));
+    }
+
+    /*  A build that fails gets journaled as failed, with the error. */
+    #[test]
+    fn journal_failed_build()
+    {
+        let mut system = make_poem_system();
+        system.remove_file("verse2.txt").unwrap();
+
+        let result = journaled_build(&system, &mut RecordingPrinter::new(),
+            JournalRecord::new("build", None, to_arguments("build")));
+
+        let records = read_journal(&system, &get_journal_path(".ruler")).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].outcome_opt, Some(Outcome::Failed(format!("{}", result.err().unwrap()))));
+    }
+
+    /*  A start with no finish, as when the process is killed mid-build, reads as interrupted once the
+        next invocation starts, and that invocation warns about it. */
+    #[test]
+    fn journal_unfinished_record_reported_interrupted()
+    {
+        let mut system = make_poem_system();
+        start_invocation(&mut system, ".ruler",
+            JournalRecord::new("build", None, to_arguments("build")), DEFAULT_JOURNAL_MAX_RECORDS).unwrap();
+
+        let records = read_journal(&system, &get_journal_path(".ruler")).unwrap();
+        assert_eq!(records[0].outcome_opt, None);
+
+        let mut printer = RecordingPrinter::new();
+        journaled_build(&system, &mut printer,
+            JournalRecord::new("build", None, to_arguments("build"))).unwrap();
+
+        let records = read_journal(&system, &get_journal_path(".ruler")).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].outcome_opt, Some(Outcome::Interrupted));
+        assert_eq!(records[0].end_timestamp_opt, None);
+        assert_eq!(records[1].outcome_opt, Some(Outcome::Succeeded));
+        assert!(printer.printed.contains(
+            &"   Warning ruler build started 1970-01-01 00:00:00 never finished; journaled as interrupted".to_string()));
+    }
+
+    /*  Past the cap, the oldest records go. */
+    #[test]
+    fn journal_cap_evicts_oldest()
+    {
+        let mut system = FakeSystem::new(10);
+        for index in 0..5
+        {
+            let mut printer = RecordingPrinter::new();
+            let start_timestamp_opt = begin(&mut system, &mut printer, ".ruler",
+                JournalRecord::new("pin", Some(format!("file{}", index)), vec![]), 3);
+            let result : Result<(), String> = Ok(());
+            end(&mut system, &mut printer, ".ruler", start_timestamp_opt, &result);
+        }
+
+        let records = read_journal(&system, &get_journal_path(".ruler")).unwrap();
+        let targets : Vec<Option<String>> = records.iter().map(|record| record.target_opt.clone()).collect();
+        assert_eq!(targets, vec![
+            Some("file2".to_string()),
+            Some("file3".to_string()),
+            Some("file4".to_string())]);
+        assert!(records.iter().all(|record| record.outcome_opt == Some(Outcome::Succeeded)));
+    }
+}
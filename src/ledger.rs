@@ -0,0 +1,438 @@
+use std::fmt;
+use termcolor::Color;
+use crate::build::
+{
+    get_nodes,
+    BuildError,
+    BuildParams,
+};
+use crate::du::json_string;
+use crate::sort::to_path_key;
+use crate::ticket::Ticket;
+use crate::printer::Printer;
+use crate::system::
+{
+    System,
+    ReadWriteError,
+};
+use crate::system::util::
+{
+    read_file_to_string,
+    write_str_to_file,
+    ReadFileToStringError,
+};
+
+/*  How many records the ledger keeps unless told otherwise.  Past that, the oldest go. */
+pub const DEFAULT_LEDGER_MAX_RECORDS : usize = 10000;
+
+/*  One command that actually ran during a build (as opposed to targets that were up-to-date,
+    recovered or downloaded).  Times are in microseconds, the timestamp since the epoch. */
+#[derive(Clone, PartialEq, Debug)]
+pub struct LedgerRecord
+{
+    pub timestamp : u64,
+    pub duration_micros : u64,
+    pub rule_ticket : Ticket,
+    pub sources_ticket : Ticket,
+    pub target_tickets : Vec<Ticket>,
+    pub first_target : String,
+}
+
+impl LedgerRecord
+{
+    /*  A record's line in the ledger file: the fields separated by tabs, target tickets separated by
+        commas.  The target path goes last, so whatever characters it contains, splitting the line
+        can't go wrong. */
+    fn to_line(&self) -> String
+    {
+        format!("{}\t{}\t{}\t{}\t{}\t{}",
+            self.timestamp,
+            self.duration_micros,
+            self.rule_ticket,
+            self.sources_ticket,
+            self.target_tickets.iter().map(|ticket| ticket.human_readable()).collect::<Vec<String>>().join(","),
+            self.first_target)
+    }
+
+    fn from_line(line : &str) -> Option<LedgerRecord>
+    {
+        let fields : Vec<&str> = line.splitn(6, '\t').collect();
+        if fields.len() != 6
+        {
+            return None;
+        }
+
+        let mut target_tickets = vec![];
+        for ticket_str in fields[4].split(',').filter(|ticket_str| *ticket_str != "")
+        {
+            target_tickets.push(Ticket::from_human_readable(ticket_str).ok()?);
+        }
+
+        Some(LedgerRecord
+        {
+            timestamp : fields[0].parse().ok()?,
+            duration_micros : fields[1].parse().ok()?,
+            rule_ticket : Ticket::from_human_readable(fields[2]).ok()?,
+            sources_ticket : Ticket::from_human_readable(fields[3]).ok()?,
+            target_tickets : target_tickets,
+            first_target : fields[5].to_string(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum LedgerError
+{
+    CannotReadLedgerFile(String, ReadFileToStringError),
+    CannotInterpretLedgerFile(String, usize),
+    CannotWriteLedgerFile(String, ReadWriteError),
+}
+
+impl fmt::Display for LedgerError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            LedgerError::CannotReadLedgerFile(path, error) =>
+                write!(formatter, "Cannot read ledger file: {}: {}", path, error),
+
+            LedgerError::CannotInterpretLedgerFile(path, line_number) =>
+                write!(formatter, "Cannot interpret ledger file: {}:{}", path, line_number),
+
+            LedgerError::CannotWriteLedgerFile(path, error) =>
+                write!(formatter, "Cannot write ledger file: {}: {}", path, error),
+        }
+    }
+}
+
+/*  The path of the ledger file inside the ruler directory. */
+pub fn get_ledger_path(directory_path : &str) -> String
+{
+    format!("{}/ledger", directory_path)
+}
+
+/*  Reads every record in the ledger, oldest first.  If there's no ledger yet, nothing has run, so
+    returns no records. */
+pub fn read_ledger<SystemType : System>
+(
+    system : &SystemType,
+    path : &str
+)
+-> Result<Vec<LedgerRecord>, LedgerError>
+{
+    if ! system.is_file(path)
+    {
+        return Ok(vec![]);
+    }
+
+    let content =
+    match read_file_to_string(system, path)
+    {
+        Ok(content) => content,
+        Err(error) => return Err(LedgerError::CannotReadLedgerFile(path.to_string(), error)),
+    };
+
+    let mut records = vec![];
+    for (index, line) in content.lines().enumerate()
+    {
+        match LedgerRecord::from_line(line)
+        {
+            Some(record) => records.push(record),
+            None => return Err(LedgerError::CannotInterpretLedgerFile(path.to_string(), index + 1)),
+        }
+    }
+
+    Ok(records)
+}
+
+/*  Adds the given records to the end of the ledger, then drops the oldest records until there are at
+    most max_records.  Writes nothing when there's nothing to add. */
+pub fn append_ledger<SystemType : System>
+(
+    system : &mut SystemType,
+    path : &str,
+    new_records : Vec<LedgerRecord>,
+    max_records : usize
+)
+-> Result<(), LedgerError>
+{
+    if new_records.len() == 0
+    {
+        return Ok(());
+    }
+
+    let mut records = read_ledger(system, path)?;
+    records.extend(new_records);
+    if records.len() > max_records
+    {
+        records.drain(..records.len() - max_records);
+    }
+
+    let mut content = String::new();
+    for record in records.iter()
+    {
+        content.push_str(&record.to_line());
+        content.push('\n');
+    }
+
+    match write_str_to_file(system, path, &content)
+    {
+        Ok(()) => Ok(()),
+        Err(error) => Err(LedgerError::CannotWriteLedgerFile(path.to_string(), error)),
+    }
+}
+
+/*  A timestamp in microseconds since the epoch as a UTC date and time, YYYY-MM-DD HH:MM:SS.  The
+    date arithmetic is the usual days-to-civil conversion. */
+pub fn format_timestamp(micros : u64) -> String
+{
+    let seconds = micros / 1_000_000;
+    let days = (seconds / 86400) as i64;
+    let time_of_day = seconds % 86400;
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year, month, day, time_of_day / 3600, (time_of_day / 60) % 60, time_of_day % 60)
+}
+
+fn format_millis(micros : u64) -> String
+{
+    format!("{}.{:03}ms", micros / 1000, micros % 1000)
+}
+
+/*  Renders a record as a single line of JSON. */
+fn record_json(record : &LedgerRecord) -> String
+{
+    let target_tickets : Vec<String> = record.target_tickets.iter().map(
+        |ticket| json_string(&ticket.human_readable())).collect();
+
+    format!("{{\"timestamp\":{},\"duration_micros\":{},\"rule_ticket\":{},\"sources_ticket\":{},\
+\"target_tickets\":[{}],\"first_target\":{}}}",
+        record.timestamp,
+        record.duration_micros,
+        json_string(&record.rule_ticket.human_readable()),
+        json_string(&record.sources_ticket.human_readable()),
+        target_tickets.join(","),
+        json_string(&record.first_target))
+}
+
+pub enum QueryError
+{
+    BuildError(BuildError),
+    LedgerError(LedgerError),
+    TargetNotFound(String),
+}
+
+impl fmt::Display for QueryError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            QueryError::BuildError(error) =>
+                write!(formatter, "{}", error),
+
+            QueryError::LedgerError(error) =>
+                write!(formatter, "{}", error),
+
+            QueryError::TargetNotFound(target) =>
+                write!(formatter, "No rule has {} as a target", target),
+        }
+    }
+}
+
+/*  This is the function that runs when you type "ruler query" at the command-line.  Finds the rule
+    for the target in the current rules, and prints the ledger records of that rule's command
+    running, newest first, at most last_opt of them if given.  Returns the records printed. */
+pub fn query
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &SystemType,
+    printer : &mut PrinterType,
+    params : &BuildParams,
+    target : &str,
+    last_opt : Option<usize>,
+    json : bool
+)
+-> Result<Vec<LedgerRecord>, QueryError>
+{
+    let case_insensitive_paths = params.case_insensitive_paths;
+    let node_pack =
+    match get_nodes(system, params.rulefile_paths.clone(), Some(target.to_string()), params.dedupe_rules, case_insensitive_paths, None)
+    {
+        Ok(node_pack) => node_pack,
+        Err(error) => return Err(QueryError::BuildError(error)),
+    };
+
+    let rule_ticket =
+    match node_pack.nodes.iter().find(|node| node.targets.iter().any(|node_target| to_path_key(node_target, case_insensitive_paths) == to_path_key(target, case_insensitive_paths)))
+    {
+        Some(node) => node.rule_ticket.clone(),
+        None => return Err(QueryError::TargetNotFound(target.to_string())),
+    };
+
+    let mut records : Vec<LedgerRecord> =
+    match read_ledger(system, &get_ledger_path(&params.directory_path))
+    {
+        Ok(records) => records.into_iter().filter(|record| record.rule_ticket == rule_ticket).collect(),
+        Err(error) => return Err(QueryError::LedgerError(error)),
+    };
+
+    records.reverse();
+    match last_opt
+    {
+        Some(last) => records.truncate(last),
+        None => {},
+    }
+
+    if json
+    {
+        let lines : Vec<String> = records.iter().map(record_json).collect();
+        printer.print(&format!("[{}]", lines.join(",")));
+        return Ok(records);
+    }
+
+    if records.len() == 0
+    {
+        printer.print_single_banner_line("   Nothing", Color::Yellow,
+            &format!("in the ledger for {} (rule ticket {})", target, rule_ticket));
+        return Ok(records);
+    }
+
+    printer.print(&format!("{:<19}  {:>12}  {:<44}  {}", "Built (UTC)", "Duration", "Sources ticket", "Target tickets"));
+    for record in records.iter()
+    {
+        printer.print(&format!("{:<19}  {:>12}  {:<44}  {}",
+            format_timestamp(record.timestamp),
+            format_millis(record.duration_micros),
+            record.sources_ticket.human_readable(),
+            record.target_tickets.iter().map(|ticket| ticket.human_readable()).collect::<Vec<String>>().join(" ")));
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::ledger::
+    {
+        LedgerRecord,
+        append_ledger,
+        read_ledger,
+        get_ledger_path,
+        format_timestamp,
+        query,
+    };
+    use crate::build::
+    {
+        build,
+        get_nodes,
+        BuildParams,
+    };
+    use crate::directory;
+    use crate::printer::EmptyPrinter;
+    use crate::ticket::TicketFactory;
+    use crate::system::fake::FakeSystem;
+    use crate::system::util::write_str_to_file;
+
+    fn make_record(n : u64) -> LedgerRecord
+    {
+        LedgerRecord
+        {
+            timestamp : n,
+            duration_micros : 10 * n,
+            rule_ticket : TicketFactory::from_str("rule").result(),
+            sources_ticket : TicketFactory::from_str(&format!("sources{}", n)).result(),
+            target_tickets : vec![
+                TicketFactory::from_str(&format!("target{}", n)).result(),
+                TicketFactory::from_str("other").result(),
+            ],
+            first_target : "out/my\tpoem.txt".to_string(),
+        }
+    }
+
+    /*  Records read back as written, and appending past the cap drops the oldest. */
+    #[test]
+    fn ledger_round_trip_and_rotation()
+    {
+        let mut system = FakeSystem::new(10);
+        assert_eq!(read_ledger(&system, "ledger").unwrap(), vec![]);
+
+        append_ledger(&mut system, "ledger", vec![make_record(1), make_record(2)], 3).unwrap();
+        assert_eq!(read_ledger(&system, "ledger").unwrap(), vec![make_record(1), make_record(2)]);
+
+        append_ledger(&mut system, "ledger", vec![make_record(3), make_record(4)], 3).unwrap();
+        assert_eq!(read_ledger(&system, "ledger").unwrap(), vec![make_record(2), make_record(3), make_record(4)]);
+
+        append_ledger(&mut system, "ledger", vec![], 1).unwrap();
+        assert_eq!(read_ledger(&system, "ledger").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn format_timestamp_general()
+    {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951_782_400_000_000), "2000-02-29 00:00:00");
+        assert_eq!(format_timestamp(1_700_000_000_123_456), "2023-11-14 22:13:20");
+    }
+
+    fn build_poem(system : &FakeSystem)
+    {
+        build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            BuildParams::for_test()).unwrap();
+    }
+
+    /*  After a first build, build three more times, changing a verse before the second of those.  Only
+        the second runs the command, so the three add exactly one record.  Query finds it first, with the
+        rule's ticket, the poem's new ticket, and a sources ticket the history agrees on. */
+    #[test]
+    fn ledger_records_only_executed_rules()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", "poem.txt\n:\nverse1.txt\n:\nmycat\nverse1.txt\npoem.txt\n:\n").unwrap();
+
+        build_poem(&system);
+        assert_eq!(read_ledger(&system, &get_ledger_path(".ruler")).unwrap().len(), 1);
+
+        system.time_passes(1);
+        build_poem(&system);
+        system.time_passes(1);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are blue.\n").unwrap();
+        build_poem(&system);
+        system.time_passes(1);
+        build_poem(&system);
+        assert_eq!(read_ledger(&system, &get_ledger_path(".ruler")).unwrap().len(), 2);
+
+        let records = query(&system, &mut EmptyPrinter::new(),
+            &BuildParams::for_test(), "poem.txt", Some(1), false).ok().unwrap();
+        assert_eq!(records.len(), 1);
+
+        let rule_ticket = get_nodes(&system, vec!["build.rules".to_string()], None, false, false, None).unwrap().nodes[0].rule_ticket.clone();
+        let poem_ticket = TicketFactory::from_str("Roses are blue.\n").result();
+        assert_eq!(records[0].rule_ticket, rule_ticket);
+        assert_eq!(records[0].first_target, "poem.txt");
+        assert_eq!(records[0].target_tickets, vec![poem_ticket.clone()]);
+
+        let elements = directory::open_readonly(&system, ".ruler", None, None).unwrap();
+        let rule_history = elements.history.read_rule_history(&rule_ticket).unwrap();
+        assert_eq!(rule_history.get_file_state_vec(&records[0].sources_ticket).unwrap().get_tickets(), vec![poem_ticket]);
+    }
+}
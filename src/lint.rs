@@ -0,0 +1,352 @@
+use std::fmt;
+use std::collections::
+{
+    HashMap,
+    HashSet,
+};
+use termcolor::Color;
+use crate::build::
+{
+    print_normalized_rules_warnings,
+    read_all_rules_files_to_strings,
+    BuildError,
+};
+use crate::rule::
+{
+    parse_all,
+    escapes_project_directory,
+    Provenance,
+    Rule,
+};
+use crate::printer::Printer;
+use crate::downloader::HttpDownloader;
+use crate::system::System;
+
+/*  Things in the rules that are allowed, but worth a second look. */
+#[derive(Debug, PartialEq)]
+pub enum LintWarning
+{
+    /*  A source outside the project directory.  Reading from outside is legitimate, but the build
+        then depends on files other checkouts and other people can change. */
+    ExternalSource(String, Provenance),
+
+    /*  Two rules with the same command whose targets overlap, with the overlapping targets.  The
+        command writes those targets twice over, and each rule's history claims them, so this one is
+        an error. */
+    SameCommandOverlappingTargets(Provenance, Provenance, Vec<String>),
+
+    /*  Two rules with the same command, different targets, but some sources in common, with the
+        common sources.  Often one sloppy rule split in two. */
+    SameCommandOverlappingSources(Provenance, Provenance, Vec<String>),
+
+    /*  A rule identical to an earlier one except for the order its targets or sources are listed in,
+        with the later rule first.  The later one can go. */
+    DuplicateRule(Provenance, Provenance),
+}
+
+impl LintWarning
+{
+    /*  Whether this is bad enough to fail lint whatever the options. */
+    pub fn is_error(&self) -> bool
+    {
+        match self
+        {
+            LintWarning::SameCommandOverlappingTargets(_, _, _) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for LintWarning
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            LintWarning::ExternalSource(source, provenance) =>
+                write!(formatter, "Source {} of rule at {} is outside the project directory", source, provenance),
+
+            LintWarning::SameCommandOverlappingTargets(first, second, targets) =>
+                write!(formatter, "Rules at {} and {} run the same command and both list targets: {}",
+                    first, second, targets.join(" ")),
+
+            LintWarning::SameCommandOverlappingSources(first, second, sources) =>
+                write!(formatter, "Rules at {} and {} run the same command and share sources: {}",
+                    first, second, sources.join(" ")),
+
+            LintWarning::DuplicateRule(duplicate, original) =>
+                write!(formatter, "Rule at {} is the same as the rule at {} but for order, consider deleting it",
+                    duplicate, original),
+        }
+    }
+}
+
+/*  The paths in both lists, in the order they come in the first. */
+fn get_common_paths(first : &Vec<String>, second : &Vec<String>) -> Vec<String>
+{
+    let second_set : HashSet<&String> = second.iter().collect();
+    first.iter().filter(|path| second_set.contains(path)).cloned().collect()
+}
+
+fn to_sorted(paths : &Vec<String>) -> Vec<String>
+{
+    let mut sorted = paths.clone();
+    sorted.sort();
+    sorted
+}
+
+/*  Groups the rules by command text, in the order rules are declared, and checks each pair in a group:
+    identical but for order, overlapping targets, or disjoint targets with sources in common. */
+fn check_same_command_rules(rules : &Vec<Rule>) -> Vec<LintWarning>
+{
+    let mut groups : Vec<Vec<&Rule>> = vec![];
+    let mut group_indices : HashMap<&Vec<String>, usize> = HashMap::new();
+    for rule in rules.iter()
+    {
+        match group_indices.get(&rule.command)
+        {
+            Some(index) => groups[*index].push(rule),
+            None =>
+            {
+                group_indices.insert(&rule.command, groups.len());
+                groups.push(vec![rule]);
+            },
+        }
+    }
+
+    let mut warnings = vec![];
+    for group in groups.iter()
+    {
+        for (i, first) in group.iter().enumerate()
+        {
+            for second in group[(i+1)..].iter()
+            {
+                if to_sorted(&first.targets) == to_sorted(&second.targets)
+                    && to_sorted(&first.sources) == to_sorted(&second.sources)
+                {
+                    warnings.push(LintWarning::DuplicateRule(second.provenance.clone(), first.provenance.clone()));
+                    continue;
+                }
+
+                let common_targets = get_common_paths(&first.targets, &second.targets);
+                if common_targets.len() > 0
+                {
+                    warnings.push(LintWarning::SameCommandOverlappingTargets(
+                        first.provenance.clone(), second.provenance.clone(), common_targets));
+                    continue;
+                }
+
+                let common_sources = get_common_paths(&first.sources, &second.sources);
+                if common_sources.len() > 0
+                {
+                    warnings.push(LintWarning::SameCommandOverlappingSources(
+                        first.provenance.clone(), second.provenance.clone(), common_sources));
+                }
+            }
+        }
+    }
+
+    warnings
+}
+
+/*  This is the function that runs when you type "ruler lint" at the command-line.  Parses the rules
+    files (so anything that's an outright error comes back as one) and prints a warning for each
+    questionable thing found.  Returns the warnings. */
+pub fn lint
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &SystemType,
+    printer : &mut PrinterType,
+    rulefile_paths : Vec<String>
+)
+-> Result<Vec<LintWarning>, BuildError>
+{
+    let (all_rule_text, normalized_paths) = read_all_rules_files_to_strings(system, &HttpDownloader::new(), rulefile_paths)?;
+    print_normalized_rules_warnings(printer, &normalized_paths);
+    let rules =
+    match parse_all(all_rule_text)
+    {
+        Ok(rules) => rules,
+        Err(error) => return Err(BuildError::RuleFileFailedToParse(error)),
+    };
+
+    let mut warnings = vec![];
+    for rule in rules.iter()
+    {
+        for source in rule.sources.iter()
+        {
+            if escapes_project_directory(source)
+            {
+                warnings.push(LintWarning::ExternalSource(source.clone(), rule.provenance.clone()));
+            }
+        }
+    }
+
+    warnings.extend(check_same_command_rules(&rules));
+
+    for warning in warnings.iter()
+    {
+        if warning.is_error()
+        {
+            printer.print_single_banner_line("     Error", Color::Red, &format!("{}", warning));
+        }
+        else
+        {
+            printer.print_single_banner_line("   Warning", Color::Yellow, &format!("{}", warning));
+        }
+    }
+
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::lint::
+    {
+        lint,
+        LintWarning,
+    };
+    use crate::rule::Provenance;
+    use crate::printer::EmptyPrinter;
+    use crate::system::fake::FakeSystem;
+    use crate::system::util::write_str_to_file;
+
+    /*  Sources outside the project get flagged, with the rule they're in, and sources inside don't. */
+    #[test]
+    fn lint_flags_external_sources()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "build.rules", "\
+poem.txt
+:
+verse1.txt
+../shared/verse2.txt
+:
+mycat
+verse1.txt
+../shared/verse2.txt
+poem.txt
+:
+").unwrap();
+
+        assert_eq!(
+            lint(&system, &mut EmptyPrinter::new(), vec!["build.rules".to_string()]).unwrap(),
+            vec![LintWarning::ExternalSource(
+                "../shared/verse2.txt".to_string(),
+                Provenance::new("build.rules", 1))]);
+    }
+
+    /*  Three pairs of rules, each pair sharing a command: one pair with a target in common, one with
+        disjoint targets but a source in common, and one the same rule twice with its sources listed in
+        a different order.  Each pair gives exactly one finding, naming both rules. */
+    #[test]
+    fn lint_flags_rules_with_the_same_command()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "gen.rules", "\
+api.h
+api.c
+:
+api.idl
+:
+gen-api
+:
+
+api.c
+:
+api.idl
+types.idl
+:
+gen-api
+:
+
+left.h
+:
+shapes.idl
+left.idl
+:
+gen-shapes
+:
+
+right.h
+:
+shapes.idl
+right.idl
+:
+gen-shapes
+:
+
+table.c
+:
+a.csv
+b.csv
+:
+gen-table
+:
+
+table.c
+:
+b.csv
+a.csv
+:
+gen-table
+:
+").unwrap();
+
+        let warnings = lint(&system, &mut EmptyPrinter::new(), vec!["gen.rules".to_string()]).unwrap();
+        assert_eq!(warnings, vec![
+            LintWarning::SameCommandOverlappingTargets(
+                Provenance::new("gen.rules", 1), Provenance::new("gen.rules", 9), vec!["api.c".to_string()]),
+            LintWarning::SameCommandOverlappingSources(
+                Provenance::new("gen.rules", 17), Provenance::new("gen.rules", 25), vec!["shapes.idl".to_string()]),
+            LintWarning::DuplicateRule(
+                Provenance::new("gen.rules", 41), Provenance::new("gen.rules", 33)),
+        ]);
+        assert!(warnings[0].is_error());
+        assert!(!warnings[1].is_error());
+        assert!(!warnings[2].is_error());
+    }
+
+    /*  Rules with different commands, or the same command on unrelated files, give no findings. */
+    #[test]
+    fn lint_clean_rules()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "build.rules", "\
+stanza1.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+stanza1.txt
+:
+
+stanza2.txt
+:
+verse2.txt
+:
+mycat
+verse2.txt
+stanza2.txt
+:
+
+poem.txt
+:
+stanza1.txt
+stanza2.txt
+:
+mycat
+stanza1.txt
+stanza2.txt
+poem.txt
+:
+").unwrap();
+
+        assert_eq!(lint(&system, &mut EmptyPrinter::new(), vec!["build.rules".to_string()]).unwrap(), vec![]);
+    }
+}
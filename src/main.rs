@@ -11,23 +11,56 @@ use clap_derive::
     Subcommand,
 };
 use crate::system::real::RealSystem;
+use crate::system::util::read_file_to_string;
 use crate::printer::StandardPrinter;
-use crate::ticket::TicketFactory;
+use std::time::SystemTime;
+use crate::ticket::
+{
+    Ticket,
+    TicketFactory,
+};
+use crate::downloader::HttpDownloader;
+use crate::work::ContradictionPolicy;
 
+mod bench;
 mod blob;
+mod builtin;
 mod bundle;
 mod build;
 mod cache;
+mod daemon;
 mod directory;
+mod doctor;
+mod du;
+mod explain;
+mod gc;
+mod hash;
 mod current;
 mod history;
+mod ignore;
+mod init;
+mod journal;
+#[cfg(all(test, unix))]
+mod integration;
+mod ledger;
+mod lint;
+mod ninja;
+mod observer;
+mod orphans;
 mod packet;
+mod pin;
+mod plan;
+mod pool;
 mod printer;
+mod private;
+mod progress;
 mod rule;
 mod server;
 mod sort;
+mod status;
 mod system;
 mod ticket;
+mod timing;
 mod work;
 mod downloader;
 
@@ -41,6 +74,57 @@ ancestors.")]
     target : Option<String>,
 }
 
+#[derive(Parser)]
+struct BuildCommandConfig
+{
+    #[arg(index=1, value_name = "TARGET_PATH", help =
+"When specified, Ruler searches for a dependnece rule in which TARGET_PATH is
+listed as a target, and limit the build to that rule and its ancestors.")]
+    target : Option<String>,
+
+    #[arg(long, help =
+"Say what the build would do with each rule, in order: leave it, recover its
+targets, or rebuild it and why.  Runs no commands and changes nothing.")]
+    dry_run : bool,
+
+    #[arg(long, default_value="text", value_name = "text|json", value_parser = ["text", "json"], requires = "dry_run", help =
+"How --dry-run prints the plan: a line per rule, or JSON listing each rule's
+targets, tickets, predicted action and sources.")]
+    format : String,
+
+    #[arg(long, value_name = "PATH[=TICKET]", value_parser = parse_what_if, requires = "dry_run", help =
+"With --dry-run, plan as if the source at PATH had changed, without touching it,
+to see what would rebuild.  TICKET is the human-readable ticket to pretend it
+has.  Without one, it gets a made-up ticket no real file has.  Give it once per
+source.")]
+    what_if : Vec<(String, Ticket)>,
+
+    #[arg(long, conflicts_with_all = ["dry_run", "force_deep", "force_all"], help =
+"Run the command of TARGET_PATH's rule whatever the history says, instead of
+leaving the targets be or recovering them.  What the command makes still goes
+into the history, so if it differs from what the history remembers for the same
+sources, that's a contradiction (see --fail-on-contradiction): the sign of a
+command that doesn't always make the same thing.  Rules that depend on TARGET_PATH
+aren't forced: they rebuild only if it comes out different.  Needs a TARGET_PATH.")]
+    force : bool,
+
+    #[arg(long, conflicts_with_all = ["dry_run", "force_all"], help =
+"Like --force, but forces the rules TARGET_PATH depends on too, all the way
+down.  Needs a TARGET_PATH.")]
+    force_deep : bool,
+
+    #[arg(long, conflicts_with = "dry_run", help =
+"Like --force, but forces every rule.  Takes no TARGET_PATH.")]
+    force_all : bool,
+
+    #[arg(long, value_name = "DIR", conflicts_with = "dry_run", help =
+"After a successful build, compare each target against the file at the same path
+under DIR, a golden copy from a build known to be good, and list the targets
+whose tickets differ, or whose golden copy is missing.  Exits with an error if
+any do.  For checking a build is reproducible.")]
+    compare_to : Option<String>,
+}
+
 #[derive(Parser)]
 struct RunConfig
 {
@@ -53,6 +137,140 @@ struct RunConfig
     extra_args: Vec<String>,
 }
 
+#[derive(Parser)]
+struct ExportNinjaConfig
+{
+    #[arg(index=1, value_name = "TARGET_PATH", help =
+"When specified, exports only the rule for TARGET_PATH and its ancestors.")]
+    target : Option<String>,
+
+    #[arg(short, long, default_value="build.ninja", value_name = "NINJA_FILE", help =
+"The path of the ninja file to write")]
+    output : String,
+}
+
+#[derive(Parser)]
+struct PrintCommandConfig
+{
+    #[arg(index=1, required=true, value_name = "TARGET_PATH", help =
+"The target whose rule's command to print")]
+    target : String,
+}
+
+#[derive(Parser)]
+struct RulesForConfig
+{
+    #[arg(index=1, required=true, value_name = "TARGET_PATH", help =
+"The target whose rules to list")]
+    target : String,
+}
+
+#[derive(Parser)]
+struct InitConfig
+{
+    #[arg(long, default_value="script", value_name = "c|script|empty", value_parser = ["c", "script", "empty"], help =
+"Which starter rules to write: a C program compiled and linked, a script that
+turns an input file into an output file, or only comments explaining the
+format.")]
+    template : String,
+
+    #[arg(long, help =
+"Overwrite the rules file if there already is one.")]
+    force : bool,
+}
+
+#[derive(Parser)]
+struct LintConfig
+{
+    #[arg(long, help =
+"Exit with a failure code on any warning, not only on errors.")]
+    deny_warnings : bool,
+}
+
+#[derive(Parser)]
+struct OrphansConfig
+{
+    #[arg(long, help =
+"Move each orphan into the cache, the way clean does, and forget it was ever
+built.")]
+    clean : bool,
+}
+
+#[derive(Parser)]
+struct QueryConfig
+{
+    #[arg(index=1, required=true, value_name = "TARGET_PATH", help =
+"The target whose rule to look up in the ledger")]
+    target : String,
+
+    #[arg(long, value_name = "N", help =
+"Print only the N most recent records")]
+    last : Option<usize>,
+
+    #[arg(long, help = "Print the records as JSON")]
+    json : bool,
+}
+
+#[derive(Parser)]
+struct LastConfig
+{
+    #[arg(index=1, value_name = "N", default_value_t = 10, help =
+"How many of the most recent invocations to print")]
+    count : usize,
+}
+
+#[derive(Parser)]
+struct PinConfig
+{
+    #[arg(index=1, required=true, value_name = "TARGET_PATH", help =
+"The target to pin")]
+    target : String,
+
+    #[arg(index=2, required=true, value_name = "TICKET", help =
+"The ticket of the file in the cache to put in place as the target")]
+    ticket : String,
+}
+
+#[derive(Parser)]
+struct UnpinConfig
+{
+    #[arg(index=1, required=true, value_name = "TARGET_PATH", help =
+"The target to unpin")]
+    target : String,
+}
+
+#[derive(Parser)]
+struct BenchConfig
+{
+    #[arg(index=1, value_name = "TARGET_PATH", help =
+"When specified, builds only the rule for TARGET_PATH and its ancestors.")]
+    target : Option<String>,
+
+    #[arg(short, long, default_value="10", value_name = "N", help =
+"How many up-to-date builds to time after the first build")]
+    runs : usize,
+}
+
+#[derive(Parser)]
+struct DuConfig
+{
+    #[arg(long, help = "Print the report as JSON")]
+    json : bool,
+
+    #[arg(short, long, default_value="10", value_name = "N", help =
+"How many of the largest cache blobs to list")]
+    largest : usize,
+}
+
+#[derive(Parser)]
+struct GcConfig
+{
+    #[arg(long, value_name = "DURATION", value_parser = gc::parse_duration, help =
+"Remove history entries made longer ago than this: a whole number and a unit,
+s, m, h, d or w, as in 90d")]
+    older_than : std::time::Duration,
+}
+
 #[derive(Parser)]
 struct ServeConfig
 {
@@ -60,6 +278,19 @@ struct ServeConfig
     port : u16,
 }
 
+#[derive(Parser)]
+struct DaemonConfig
+{
+    #[arg(long, default_value = "7879", help =
+"The port on localhost to listen on.")]
+    port : u16,
+
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH", help =
+"Listen on a unix socket at PATH instead of a port.")]
+    socket : Option<String>,
+}
+
 #[derive(Parser)]
 struct ListConfig
 {
@@ -70,18 +301,69 @@ struct ListConfig
 #[derive(Parser)]
 struct HashConfig
 {
-    #[arg(index=1, value_name = "PATH", help = "A path")]
-    path : String,
+    #[arg(index=1, value_name = "PATH", required_unless_present = "compare", help =
+"A path, or \"-\" to hash standard input")]
+    path_opt : Option<String>,
+
+    #[arg(long, num_args = 2, value_names = ["PATH1", "PATH2"], conflicts_with = "path_opt", help =
+"Hash two paths and say whether they're equal, exiting non-zero if they aren't")]
+    compare : Option<Vec<String>>,
+
+    #[arg(long, requires = "compare", help =
+"With --compare on two directories, list the entries that differ")]
+    verbose : bool,
+}
+
+#[derive(Parser)]
+struct CacheVerifyConfig
+{
+    #[arg(long, help =
+"Remove the entries that don't match their tickets")]
+    repair : bool,
+}
+
+#[derive(Subcommand)]
+enum CacheSubcommand
+{
+    #[command(about="Rehashes every cache entry and reports the corrupt ones", long_about=
+"Rehashes every entry in the cache, one file at a time, and lists each whose
+content doesn't match the ticket it's named by.  With --repair, removes them too,
+so whatever needs them gets rebuilt or downloaded again instead of restored
+wrong.  Exits with a failure code if any corrupt entry is left in place.")]
+    Verify(CacheVerifyConfig),
+}
+
+#[derive(Parser)]
+struct CacheConfig
+{
+    #[command(subcommand)]
+    command : CacheSubcommand,
 }
 
 #[derive(Subcommand)]
 enum RulerSubcommand
 {
+    #[command(about="Sets up a new project", long_about=
+"Writes a starter rules file (see --template), creates the ruler directory, and
+if there's a .gitignore, adds the ruler directory to it.  An existing rules
+file is left alone unless given --force.")]
+    Init(InitConfig),
+
     #[command(about="Builds the given target or all targets", long_about=
 "Builds the given target.  If no build-target is specified, builds all targets.
 The target must be a file listed in the target section of the current rules
-file.")]
-    Build(BuildConfig),
+file.
+
+A rule marked '@install' in the rules file is for copying built things somewhere
+they get used from, like a staging directory, where what gets made may differ
+from one machine to the next, say by embedding a local path.  Such a rule is
+private: its targets and history never come from or go to download urls, and
+'ruler serve' doesn't share them.  Otherwise it builds like any rule, in order
+with the rest, and its history gets recorded, except that when its command makes
+something other than what the history remembers for the same sources, the new
+result replaces the old instead of being a contradiction.  Clean treats it like
+any other rule.")]
+    Build(BuildCommandConfig),
 
     #[command(about="Builds and runs an executable", long_about=
 "The run subcommand builds EXECUTABLE as it would any target.  Provided the
@@ -99,6 +381,178 @@ recovered later if needed.
 If a target is specified, cleans only the ancestors of that target.")]
     Clean(BuildConfig),
 
+    #[command(about="Downloads what a build would need", long_about=
+"Works out which rules a build of the given target (or all targets) would run,
+and asks the download urls for their targets.  Target files are downloaded into
+the cache and remembered in the history, so a later build can recover them
+without a network connection.  The working tree is left as it is.
+
+Reports each rule as prefetched, already local, missing from the remote, or
+blocked because its sources differ from anything that can be looked up.")]
+    Prefetch(BuildConfig),
+
+    #[command(about="Exports the rules as a ninja file", long_about =
+"Reads the rules file(s) and writes the dependence graph as a ninja build file,
+one build statement per rule.  Sources which are not targets of any rule appear
+only as inputs.")]
+    ExportNinja(ExportNinjaConfig),
+
+    #[command(about="Lists the rules on the critical path of the last build", long_about =
+"Reads the timings recorded by the last build, and finds the chain of rules
+that determined how long the build took: each rule on the path is the slowest
+dependence of the next.  Prints each rule's own time followed by the cumulative
+time, so the first rule worth optimizing stands out.")]
+    CriticalPath(BuildConfig),
+
+    #[command(about="Reports on the cache and how much of it has been checked", long_about =
+"Prints how many entries the cache has, and what the checks at the end of each
+build (see --verify-sample) have covered: how many entries they checked in all,
+how many turned out corrupt, and roughly when they last got through as many
+entries as the cache holds.")]
+    CacheStats,
+
+    #[command(about="Checks and repairs the cache")]
+    Cache(CacheConfig),
+
+    #[command(about="Prints the order rules run in and what each waits on", long_about =
+"Reads the rules, sorts them the way build would, and prints them in that order.
+Each line names a rule's targets and where the rule is, followed by the paths it
+waits on: source files and targets of rules earlier in the list.  Files declared
+with '@global-source', which every rule waits on, are listed first.  Runs
+nothing.")]
+    Explain(BuildConfig),
+
+    #[command(about="Lists every rule a target depends on", long_about =
+"Sorts the rules toward TARGET_PATH and prints every rule it depends on, directly
+or through other rules, its own rule included, in the order they would run.
+Each line names the rule's targets and the start of its ticket.  Runs nothing.")]
+    RulesFor(RulesForConfig),
+
+    #[command(about="Reports which targets changed since the last build", long_about =
+"For each target (or each ancestor of TARGET_PATH), reports whether the file is
+unchanged since the last build, modified, missing, unknown because nothing was
+recorded for it, or pinned with 'ruler pin'.  Only reads: the ruler directory is not created if it
+isn't there, so status works on a read-only checkout.")]
+    Status(BuildConfig),
+
+    #[command(about="Lists files no rule makes anymore", long_about =
+"Lists the files some rule produced in an earlier build that no rule in the
+rules file(s) mentions anymore, say because the rule got deleted.  Files only
+ever read as sources are never listed.  With --clean, moves each one into the
+cache and forgets it.  A build of all targets warns about these too.")]
+    Orphans(OrphansConfig),
+
+    #[command(about="Prints the command that builds a target", long_about =
+"Finds the rule with TARGET_PATH as a target and prints its command, one line
+per command in the order they would execute, without building anything.")]
+    PrintCommand(PrintCommandConfig),
+
+    #[command(about="Prints every source no rule produces", long_about=
+"Reads the rules and prints, sorted and one per line, every path that appears as
+a source but isn't the target of any rule.  Handy for generating a .gitignore or
+a list of inputs.")]
+    PrintLeaves,
+
+    #[command(about="Lists when a target's rule actually ran", long_about=
+"Every build adds a record to the ledger in the ruler directory for each command
+it ran (not for targets that were up-to-date, recovered or downloaded): when,
+how long it took, the rule's ticket, the sources ticket and the resulting target
+tickets.  This finds the rule for TARGET_PATH in the current rules and prints its
+records, newest first.")]
+    Query(QueryConfig),
+
+    #[command(about="Lists the last few ruler invocations in this directory", long_about=
+"Build, run, clean, prefetch, pin, unpin and gc each add a record to the journal
+in the ruler directory when they start, and finish it when they end.  This prints
+the N most recent records, newest first: when each started and ended, how it
+turned out, and the command-line it ran with.  An invocation that never got to
+finish, say because it was killed, shows as interrupted.  The journal keeps only
+the most recent invocations.")]
+    Last(LastConfig),
+
+    #[command(about="Holds a target at a version from the cache", long_about=
+"Puts the file with the given TICKET from the cache in place as TARGET_PATH, and
+pins it there: until unpinned, builds don't run or resolve the target's rule,
+and rules that depend on the target build against the pinned file.  Whatever
+was at TARGET_PATH before goes into the cache.  Pins last across builds, and
+status lists them.  Handy for bisecting with an old version of an intermediate
+file.")]
+    Pin(PinConfig),
+
+    #[command(about="Removes the pin from a target", long_about=
+"Removes the pin that 'ruler pin' put on TARGET_PATH.  The file stays as it is
+until the next build treats it like any other target.")]
+    Unpin(UnpinConfig),
+
+    #[command(about="Checks the rules for questionable things", long_about =
+"Parses the rules file(s) and warns about things which are allowed but worth a
+second look, such as sources outside the project directory.  (Targets outside
+the project directory are an error unless the rules file permits them with an
+'@allow-external-targets PREFIX' directive.)
+
+Rules that run the same command get compared: overlapping targets are an error,
+shared sources a warning, and a rule identical to another but for the order of
+its paths gets flagged for deletion.  Exits with a failure code on errors, or on
+any warning with --deny-warnings.")]
+    Lint(LintConfig),
+
+    #[command(about="Checks the ruler directory for damage", long_about =
+"Checks that the ruler directory has its cache and history subdirectories, that
+every rule history parses, that every cache entry still matches its ticket, and
+that the current file states (of every variant) can be read.  Missing
+subdirectories get created.  Anything else gets reported and left as it is.
+Ends with a one-line summary, and exits with a failure code if anything was
+wrong.")]
+    Doctor,
+
+    #[command(about="Reports disk usage of the ruler directory", long_about =
+"Walks the ruler directory and reports the total bytes used, broken down into
+cache blobs, rule histories, current file states and logs of past builds, with
+the largest cache blobs listed by ticket.  With --cache-dir, the cache blobs are
+counted there instead.  Files that belong to none of those count as other.
+Entries that can't be read are skipped with a note.")]
+    Du(DuConfig),
+
+    #[command(about="Removes old history and the cache blobs only it needed", long_about =
+"Removes every rule history entry made longer ago than --older-than, then every
+cache blob that no remaining history entry, current file state (of any variant)
+or pin refers to.  Entries from before ruler recorded when they were made count
+as made at the epoch.  Reports how many entries and blobs went and the bytes
+they took.  If any history or current file states can't be read, removes
+nothing.  A cache shared through --cache-dir loses blobs only other checkouts
+refer to.")]
+    Gc(GcConfig),
+
+    #[command(about="Times up-to-date builds", long_about =
+"Builds once, then builds again N times and reports the min, median and max wall
+time of those later builds.  Since the first build leaves everything up-to-date,
+this measures the overhead of a build with nothing to do.")]
+    Bench(BenchConfig),
+
+    #[command(about="Stays running to do builds on request", long_about =
+"Starts a ruler that stays running and builds when asked, for a file-watcher or
+an editor that builds often.  It reads the ruler directory, and parses and sorts
+the rules, only once, and again only when the rules files change, so builds it
+does skip what 'ruler build' does every time before building.  Everything else
+about a build is as 'ruler build' with the same options would do it, and the
+ruler directory gets written after each build as usual, so stopping the daemon
+loses nothing.  Nothing else should change the ruler directory while it runs.
+
+Requests are lines of JSON sent to a port on localhost (see --port) or a unix
+socket (see --socket):
+
+    {\"cmd\":\"build\"}
+    {\"cmd\":\"build\",\"target\":\"poem.txt\"}
+    {\"cmd\":\"status\"}
+    {\"cmd\":\"shutdown\"}
+
+Each gets replies as lines of JSON, with the kind in the \"event\" field: a build's
+progress events as it goes, the same as --progress-file gives plus
+\"command_output\" lines, and last, for every request, a \"result\" saying whether
+it succeeded, why not if it didn't, and for a build, its summary.  Requests run
+one at a time: one connection gets served until it closes, and others wait.")]
+    Daemon(DaemonConfig),
+
     #[command(about="Run a server", long_about =
 "Starts a server which provides cached files to other computers on the network")]
     Serve(ServeConfig),
@@ -108,13 +562,18 @@ If a target is specified, cleans only the ancestors of that target.")]
     List(ListConfig),
 
     #[command(about="Hash a file or directory", long_about =
-"Takes a filesystem path and returns the hash of the file or directory at that path.")]
+"Takes a filesystem path and returns the hash of the file or directory at that path, or, given \"-\",
+the hash of standard input.  With --compare, hashes two paths and reports whether they're equal.")]
     Hash(HashConfig),
 }
 
 
+/*  This build of ruler's version, which "ruler --version" prints and "@ruler-version" in a rules
+    file gets checked against. */
+pub const RULER_VERSION : &str = "1.1.6";
+
 #[derive(Parser)]
-#[command(version = "1.1.6",
+#[command(version = RULER_VERSION,
     about = "https://rulerbuild.com",
     long_about = "A straight-forward, general-purpose build tool.\nhttps://rulerbuild.com")]
 struct CommandLineParser
@@ -123,99 +582,1257 @@ struct CommandLineParser
     command: RulerSubcommand,
 
     #[arg(short, long, default_value="build.rules", value_name = "RULES_FILE", help =
-"A .rules file defining the dependence graph for build, run and clean operations")]
+"A .rules file defining the dependence graph for build, run and clean operations.
+May be an http(s) url, in which case the file is downloaded.  Lines starting with
+'#' are comments, ignored everywhere except in a command, where they go to the
+shell as written.")]
     rules : Vec<String>,
 
-    #[arg(short, long, default_value=".ruler", help =
+    #[arg(short, long, default_value=".ruler", env = "RULER_DIRECTORY", help =
 "Ruler uses this directory to store cached files, rule history and information
 about the current filesystem state.")]
     directory : String,
-}
 
-use crate::system::System;
+    #[arg(long, value_name = "CACHE_DIR", env = "RULER_CACHE_DIR", help =
+"Keep cached files here instead of in the ruler directory.  History and current
+file states stay in the ruler directory, so checkouts can each keep their own
+while sharing one cache.")]
+    cache_dir : Option<String>,
 
+    #[arg(long, value_name = "NAME", value_parser = parse_variant, help =
+"Build a variant of the project, such as debug or release.  Each variant keeps
+its own current file states in the ruler directory, and rules get the name
+wherever they say $(VARIANT), so each variant's targets can go in their own
+directory.  Histories and the cache are shared, so switching back to a variant
+recovers what it built before instead of building it again.  Status, explain and
+clean take it too.")]
+    variant : Option<String>,
 
-fn main()
+    #[arg(long, help =
+"Print what a command writes to stdout only when the command fails.  Output of
+failing commands always gets printed in full.")]
+    output_on_failure : bool,
+
+    #[arg(long, value_name = "N", default_value_t = ledger::DEFAULT_LEDGER_MAX_RECORDS, help =
+"The most records the build ledger keeps.  When a build would take it past this
+many, the oldest records go.")]
+    ledger_max_records : usize,
+
+    #[arg(long, value_name = "N", default_value_t = cache::DEFAULT_VERIFY_SAMPLE_SIZE, help =
+"After each build, check this many randomly chosen cache entries against their
+tickets.  Entries that don't match get removed with a warning, so the next
+build recreates or downloads them.  'ruler cache-stats' shows the totals.")]
+    verify_sample : usize,
+
+    #[arg(long, help =
+"Skip checking cache entries after the build.")]
+    no_verify_sample : bool,
+
+    #[arg(short, long, help =
+"Leave out the line for each target.  A successful build still ends with its
+one-line summary, along with any command output and warnings.")]
+    quiet : bool,
+
+    #[arg(long, help =
+"Start each line of output with the time it was printed, in ISO 8601 and UTC,
+to line up with other logs, say in CI.")]
+    timestamps : bool,
+
+    #[arg(long, help =
+"Print every packet passed between rules while building: which target it came
+from, which target it went to, and the short ticket it carried (or cancel).
+For working out why a build hangs.")]
+    trace_packets : bool,
+
+    #[arg(long, help =
+"When a rule has waited more than ten seconds for a target it depends on, print
+once which target it's waiting on.  Tells a stuck build from a slow one.")]
+    show_waiting : bool,
+
+    #[arg(long, help =
+"Keep trusting modified times even when the clock reads earlier than it did at
+the start of the last build.  Otherwise such a build warns and hashes every
+file, since after the clock goes back an edited file can end up with the very
+modified time recorded for it before the edit.")]
+    trust_clock : bool,
+
+    #[arg(long, value_name = "PATH", help =
+"A source file that might have changed since the last build, say from an editor
+or a version control hook.  Give it once per file.  Sources not listed are taken
+to be as the last build saw them, without so much as checking their modified
+times, and listed ones get hashed.  A wrong list builds from stale sources, so
+see --changed-verify-sample.")]
+    changed : Vec<String>,
+
+    #[arg(long, value_name = "FILE", help =
+"Like --changed, for each line in this file, or on stdin with \"-\".")]
+    changed_from : Option<String>,
+
+    #[arg(long, value_name = "N", default_value_t = 0, help =
+"With --changed or --changed-from, hash this many randomly chosen sources not
+listed before building, and fail without building anything if any have changed.")]
+    changed_verify_sample : usize,
+
+    #[arg(long, help =
+"Have a target count as a source by its content together with the rule that
+built it, so changing a rule's command reruns the rules downstream even when
+the target comes out byte-for-byte the same.")]
+    command_provenance : bool,
+
+    #[arg(short, long, value_name = "N", help =
+"Run at most N commands at once.  Without it, every rule whose sources are ready
+runs its command straight away.")]
+    jobs : Option<usize>,
+
+    #[arg(long, value_name = "NAME=N", help =
+"Let up to N commands of rules in the group NAME (see @group) run at once,
+instead of one at a time.  Give it once per group.  Counts toward --jobs.")]
+    group_jobs : Vec<String>,
+
+    #[arg(long, value_name = "PATH", help =
+"Write progress to PATH as the build goes, one JSON object per line: when the
+build starts, when each rule starts, finishes or fails, and when the build
+finishes.  A named pipe works, for an editor to read from.  Trouble writing
+there gets a warning, never a failed build.")]
+    progress_file : Option<String>,
+
+    #[cfg(unix)]
+    #[arg(long, value_name = "N", conflicts_with = "progress_file", help =
+"Like --progress-file, but writing to file descriptor N, already open.")]
+    progress_fd : Option<u32>,
+
+    #[arg(long, help =
+"Fail the build when the rules files contain no rules, naming the files checked.
+This holds even with --allow-empty, and when building a particular target.")]
+    require_rules : bool,
+
+    #[arg(short, long, value_name = "URLS_FILE", env = "RULER_URLS", help =
+"A toml file listing urls of Ruler servers to download cached targets from")]
+    urls : Option<String>,
+
+    #[arg(long, help =
+"Drop rules that are exact duplicates of an earlier rule instead of reporting
+their shared targets as an error.  Rules that share a target but differ in any
+other way are still an error.")]
+    dedupe_identical_rules : bool,
+
+    #[arg(long, help =
+"Match sources to targets ignoring the case of their paths, so a rule reading
+Poem.txt depends on the rule that writes poem.txt.  Use this on filesystems
+that ignore case, as they usually do on macOS and Windows.")]
+    case_insensitive_paths : bool,
+
+    #[arg(long, default_value="error", value_name = "error|warn", value_parser = ["error", "warn"], help =
+"What to do when a rule's command produces targets that contradict the rule's
+history, which usually means the rule is missing a source.  With 'warn', the
+build prints a warning, records the new result over the old one, and carries
+on.  This hides real dependence bugs, so use it only to keep building while
+the rules get fixed.")]
+    fail_on_contradiction : String,
+
+    #[arg(long, help =
+"After each command runs, report any source its rule declares but the command
+never opened.  This is advisory: an unused source makes the rule rebuild more
+often than it needs to.  Detection goes by file access times, so on
+filesystems mounted noatime or relatime the report can be incomplete or wrong.")]
+    report_unused_sources : bool,
+
+    #[arg(long, help =
+"Run rule commands with an empty environment except for PATH and the variables
+named with --env-allow or with an '@env-allow NAME...' line before a rule in
+the rules file.  Keeps variables like CFLAGS or PYTHONPATH in your shell from
+changing what commands do.  Allowlists aren't part of rule tickets, so changing
+one doesn't rebuild anything; clean first if a rebuild is what you want.")]
+    hermetic_env : bool,
+
+    #[arg(long, value_name = "NAME", help =
+"With --hermetic-env, pass this environment variable along to every command.
+Can be given more than once.")]
+    env_allow : Vec<String>,
+
+    #[arg(long, help =
+"Fail a rule when its command writes output that isn't valid UTF-8.  Otherwise
+such output gets printed with replacement characters and a note saying so.")]
+    strict_utf8_output : bool,
+
+    #[arg(long, help =
+"Write rule histories to the ruler directory gzipped.  Histories already written
+either way still read fine, so this can be turned on or off at any time.")]
+    compress_history : bool,
+
+    #[arg(long, help =
+"Neither read nor write rule histories.  For a ruler directory that gets thrown
+away after a single build, as on a CI runner, where writing them is wasted.
+Targets still come back from the cache when the current file states say what
+they should be, but otherwise every rule runs its command.")]
+    no_history : bool,
+
+    #[arg(long, help =
+"Print the settings a build would use, after flags, environment variables and
+defaults are all taken into account, one per line.  Then exit without building.")]
+    dump_config : bool,
+
+    #[arg(long, help =
+"Let a build with no target succeed even when the rules files contain no rules.
+Without this, that's an error with its own exit code, since an empty rules file
+is more often a mistake than not.")]
+    allow_empty : bool,
+}
+
+/*  The global environment allowlist if commands are to run hermetic, otherwise None. */
+fn to_hermetic_env_opt(hermetic_env : bool, env_allow : Vec<String>) -> Option<Vec<String>>
 {
-    let command_line = CommandLineParser::parse();
+    if hermetic_env
+    {
+        Some(env_allow)
+    }
+    else
+    {
+        None
+    }
+}
 
-    match command_line.command
+/*  A variant name ends up in a file name in the ruler directory, so it has to be one. */
+fn parse_variant(variant : &str) -> Result<String, String>
+{
+    if variant == "" || variant.starts_with('.') || variant.contains('/') || variant.contains('\\')
     {
-        RulerSubcommand::Build(build_config) =>
+        return Err(format!("not a usable variant name: '{}'", variant));
+    }
+    Ok(variant.to_string())
+}
+
+/*  A --what-if value: the path, and the ticket it gave or a made-up one. */
+fn parse_what_if(what_if : &str) -> Result<(String, Ticket), String>
+{
+    match what_if.split_once('=')
+    {
+        Some((path, ticket_text)) =>
         {
-            match build::build(
-                RealSystem::new(),
-                &mut StandardPrinter::new(),
-                build::BuildParams::from_all(
-                    command_line.directory,
-                    command_line.rules,
-                    None,
-                    build_config.target
-                ))
+            match Ticket::from_human_readable(ticket_text)
             {
-                Ok(()) => {},
-                Err(error) => eprintln!("{}", error),
+                Ok(ticket) => Ok((path.to_string(), ticket)),
+                Err(error) => Err(format!("not a ticket: '{}': {}", ticket_text, error)),
             }
         },
-        RulerSubcommand::Run(run_config) =>
+        None => Ok((what_if.to_string(), TicketFactory::from_str(&format!("what-if {}", what_if)).result())),
+    }
+}
+
+/*  How many cache entries to check at the end of a build, none with --no-verify-sample. */
+fn to_verify_sample_size(verify_sample : usize, no_verify_sample : bool) -> usize
+{
+    if no_verify_sample
+    {
+        0
+    }
+    else
+    {
+        verify_sample
+    }
+}
+
+/*  The sources named with --changed plus those listed in the --changed-from file ("-" for stdin), or
+    None when neither is given, since then every source might have changed. */
+fn to_changed_paths_opt(changed : &Vec<String>, changed_from_opt : &Option<String>) -> Result<Option<Vec<String>>, String>
+{
+    let mut changed_paths = changed.clone();
+    match changed_from_opt
+    {
+        Some(changed_from) =>
         {
-            match build::run(
-                RealSystem::new(),
-                &command_line.directory,
-                command_line.rules,
-                None,
-                run_config.executable,
-                run_config.extra_args,
-                &mut StandardPrinter::new())
+            let content =
+            match changed_from.as_str()
             {
-                Ok(()) => {},
-                Err(error) => eprintln!("{}", error),
-            }
-        },
-        RulerSubcommand::Clean(build_config) =>
-        {
-            match build::clean(
-                RealSystem::new(),
-                &command_line.directory,
-                command_line.rules,
-                build_config.target)
+                "-" =>
+                {
+                    match RealSystem::new().read_stdin()
+                    {
+                        Ok(content) => String::from_utf8_lossy(&content).to_string(),
+                        Err(error) => return Err(format!("Failed to read changed files from stdin: {}", error)),
+                    }
+                },
+                path =>
+                {
+                    match read_file_to_string(&RealSystem::new(), path)
+                    {
+                        Ok(content) => content,
+                        Err(error) => return Err(format!("Failed to read changed files from {}: {}", path, error)),
+                    }
+                },
+            };
+
+            for line in content.lines()
             {
-                Ok(()) => {},
-                Err(error) => eprintln!("{}", error),
+                let path = line.trim();
+                if path != ""
+                {
+                    changed_paths.push(path.to_string());
+                }
             }
         },
-        RulerSubcommand::Serve(serve_config) =>
+        None =>
         {
-            match server::serve(
-                RealSystem::new(),
-                &command_line.directory,
-                serve_config.port)
+            if changed_paths.is_empty()
             {
-                Ok(()) => {},
-                Err(error) => eprintln!("{}", error),
+                return Ok(None);
             }
         },
-        RulerSubcommand::List(list_config) =>
+    }
+
+    Ok(Some(changed_paths))
+}
+
+/*  Which rules a build forces, going by --force, --force-deep and --force-all.  Forcing every rule
+    takes asking for it by name, so --force without a target is an error rather than a way to get it. */
+fn to_force(command : &RulerSubcommand) -> Result<build::Force, String>
+{
+    match command
+    {
+        RulerSubcommand::Build(build_config) =>
         {
-            match RealSystem::new().list_dir(&list_config.path)
+            match (build_config.force, build_config.force_deep, build_config.force_all, &build_config.target)
             {
-                Ok(list) =>
-                {
-                    for l in list
-                    {
-                        println!("{}", l);
-                    }
-                },
-                Err(error) => eprintln!("{}", error),
+                (true, _, _, None) | (_, true, _, None) =>
+                    Err("--force and --force-deep need a target.  To force every rule, use --force-all".to_string()),
+                (_, _, true, Some(_)) =>
+                    Err("--force-all forces every rule, so takes no target.  Use --force or --force-deep".to_string()),
+                (true, _, _, Some(_)) => Ok(build::Force::Goal),
+                (_, true, _, Some(_)) => Ok(build::Force::Deep),
+                (_, _, true, None) => Ok(build::Force::All),
+                _ => Ok(build::Force::Off),
             }
         },
-        RulerSubcommand::Hash(config) =>
+        _ => Ok(build::Force::Off),
+    }
+}
+
+/*  The limits given with --group-jobs, each NAME=N with N at least one. */
+fn to_group_jobs(group_jobs : &Vec<String>) -> Result<Vec<(String, usize)>, String>
+{
+    let mut result = vec![];
+    for group_job in group_jobs.iter()
+    {
+        match group_job.split_once('=')
         {
-            match TicketFactory::from_path(&RealSystem::new(), &config.path)
+            Some((name, jobs)) if name != "" =>
             {
-                Ok(mut factory) => println!("{}", factory.result().human_readable()),
-                Err(error) => eprintln!("{}", error),
-            }
+                match jobs.parse::<usize>()
+                {
+                    Ok(jobs) if jobs > 0 => result.push((name.to_string(), jobs)),
+                    _ => return Err(format!("--group-jobs needs a number of jobs of at least 1: {}", group_job)),
+                }
+            },
+            _ => return Err(format!("--group-jobs takes NAME=N: {}", group_job)),
         }
     }
+    Ok(result)
+}
+
+/*  The printer for all output, starting each line with the time with --timestamps. */
+fn to_printer(timestamps : bool) -> StandardPrinter
+{
+    if timestamps
+    {
+        StandardPrinter::with_timestamps(SystemTime::now)
+    }
+    else
+    {
+        StandardPrinter::new()
+    }
+}
+
+/*  The exit code when a build finds no rules, distinct so scripts can tell an empty rules file
+    from other trouble. */
+const NO_RULES_EXIT_CODE : i32 = 3;
+
+/*  The exit code when lint finds an error, or any warning with --deny-warnings. */
+const LINT_FAILED_EXIT_CODE : i32 = 1;
+
+/*  The exit code when doctor finds a problem it can't fix. */
+const DOCTOR_FAILED_EXIT_CODE : i32 = 1;
+
+/*  The exit code when cache verify finds corrupt entries and isn't told to repair them. */
+const CACHE_VERIFY_FAILED_EXIT_CODE : i32 = 1;
+
+/*  The exit codes when hash --compare finds the two paths differ, and when it can't hash one, kept
+    apart so scripts can tell a change from trouble. */
+const HASH_COMPARE_UNEQUAL_EXIT_CODE : i32 = 1;
+const HASH_COMPARE_ERROR_EXIT_CODE : i32 = 2;
+
+/*  The exit code when build --compare-to finds a target that differs from its golden copy. */
+const COMPARE_TO_MISMATCH_EXIT_CODE : i32 = 1;
+
+fn to_contradiction_policy(fail_on_contradiction : &str) -> ContradictionPolicy
+{
+    match fail_on_contradiction
+    {
+        "warn" => ContradictionPolicy::Warn,
+        _ => ContradictionPolicy::Error,
+    }
+}
+
+/*  The target a subcommand was given, if it takes one. */
+fn get_target_opt(command : &RulerSubcommand) -> Option<String>
+{
+    match command
+    {
+        RulerSubcommand::Build(build_config) => build_config.target.clone(),
+        RulerSubcommand::Clean(build_config) |
+        RulerSubcommand::Prefetch(build_config) |
+        RulerSubcommand::CriticalPath(build_config) |
+        RulerSubcommand::Explain(build_config) |
+        RulerSubcommand::Status(build_config) => build_config.target.clone(),
+        RulerSubcommand::Bench(bench_config) => bench_config.target.clone(),
+        RulerSubcommand::Run(run_config) => Some(run_config.executable.clone()),
+        _ => None,
+    }
+}
+
+/*  A journal record for the given subcommand, with the arguments as given on the command-line. */
+fn to_journal_record(subcommand : &str, target_opt : Option<String>) -> journal::JournalRecord
+{
+    journal::JournalRecord::new(subcommand, target_opt, std::env::args().skip(1).collect())
+}
+
+/*  Where build progress goes: the file given, or the file descriptor given, by way of /dev/fd. */
+fn to_progress_path_opt(command_line : &CommandLineParser) -> Option<String>
+{
+    #[cfg(unix)]
+    match command_line.progress_fd
+    {
+        Some(fd) => return Some(format!("/dev/fd/{}", fd)),
+        None => {},
+    }
+
+    command_line.progress_file.clone()
+}
+
+/*  The BuildParams the command line comes to, flags and environment variables and defaults all
+    resolved. */
+fn to_build_params(command_line : &CommandLineParser) -> build::BuildParams
+{
+    let changed_paths_opt =
+    match to_changed_paths_opt(&command_line.changed, &command_line.changed_from)
+    {
+        Ok(changed_paths_opt) => changed_paths_opt,
+        Err(message) =>
+        {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        },
+    };
+
+    let force =
+    match to_force(&command_line.command)
+    {
+        Ok(force) => force,
+        Err(message) =>
+        {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        },
+    };
+
+    let progress_path_opt = to_progress_path_opt(command_line);
+
+    let group_jobs =
+    match to_group_jobs(&command_line.group_jobs)
+    {
+        Ok(group_jobs) => group_jobs,
+        Err(message) =>
+        {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        },
+    };
+
+    let mut params = build::BuildParams::new(command_line.directory.clone(), command_line.rules.clone());
+    params.urlfile_path_opt = command_line.urls.clone();
+    params.goal_target_opt = get_target_opt(&command_line.command);
+    params.dedupe_rules = command_line.dedupe_identical_rules;
+    params.contradiction_policy = to_contradiction_policy(&command_line.fail_on_contradiction);
+    params.report_unused_sources = command_line.report_unused_sources;
+    params.hermetic_env_opt = to_hermetic_env_opt(command_line.hermetic_env, command_line.env_allow.clone());
+    params.strict_utf8_output = command_line.strict_utf8_output;
+    params.compress_history = command_line.compress_history;
+    params.allow_empty = command_line.allow_empty;
+    params.cache_directory_opt = command_line.cache_dir.clone();
+    params.output_on_failure = command_line.output_on_failure;
+    params.ledger_max_records = command_line.ledger_max_records;
+    params.require_rules = command_line.require_rules;
+    params.case_insensitive_paths = command_line.case_insensitive_paths;
+    params.verify_sample_size = to_verify_sample_size(command_line.verify_sample, command_line.no_verify_sample);
+    params.quiet = command_line.quiet;
+    params.trace_packets = command_line.trace_packets;
+    params.show_waiting = command_line.show_waiting;
+    params.variant_opt = command_line.variant.clone();
+    params.trust_clock = command_line.trust_clock;
+    params.changed_paths_opt = changed_paths_opt;
+    params.changed_verify_sample = command_line.changed_verify_sample;
+    params.force = force;
+    params.command_provenance = command_line.command_provenance;
+    params.jobs_opt = command_line.jobs;
+    params.group_jobs = group_jobs;
+    params.progress_path_opt = progress_path_opt;
+    params.no_history = command_line.no_history;
+    params
+}
+
+use crate::system::System;
+
+
+/*  The path with a leading "~/", or a path that's just "~", standing for the home directory given.  A
+    "~" anywhere else is left be, as is "~name/", someone else's home, and so is everything without a
+    home directory to expand to. */
+fn expand_home(path : &str, home_opt : Option<&str>) -> String
+{
+    match home_opt
+    {
+        Some(home) if path == "~" => home.to_string(),
+        Some(home) if path.starts_with("~/") =>
+            format!("{}/{}", home.trim_end_matches('/'), &path[2..]),
+        _ => path.to_string(),
+    }
+}
+
+/*  The home directory, from HOME, or USERPROFILE on Windows. */
+fn get_home_opt() -> Option<String>
+{
+    match std::env::var("HOME")
+    {
+        Ok(home) if home != "" => return Some(home),
+        _ => {},
+    }
+
+    #[cfg(windows)]
+    match std::env::var("USERPROFILE")
+    {
+        Ok(home) if home != "" => return Some(home),
+        _ => {},
+    }
+
+    None
+}
+
+/*  Expands a leading "~/" in the ruler directory, cache directory and rules files, however they were
+    given, flag or environment variable, since no shell expands them in the one case, and not always
+    in the other. */
+fn expand_home_in_paths(command_line : &mut CommandLineParser, home_opt : Option<&str>)
+{
+    command_line.directory = expand_home(&command_line.directory, home_opt);
+    command_line.cache_dir = command_line.cache_dir.as_ref().map(|path| expand_home(path, home_opt));
+    command_line.rules = command_line.rules.iter().map(|path| expand_home(path, home_opt)).collect();
+}
+
+fn main()
+{
+    let mut command_line = CommandLineParser::parse();
+    expand_home_in_paths(&mut command_line, get_home_opt().as_deref());
+    let build_params = to_build_params(&command_line);
+    let mut printer = to_printer(command_line.timestamps);
+
+    if command_line.dump_config
+    {
+        for line in build_params.to_config_lines()
+        {
+            println!("{}", line);
+        }
+        return;
+    }
+
+    let target_opt = get_target_opt(&command_line.command);
+    match command_line.command
+    {
+        RulerSubcommand::Build(build_config) if build_config.dry_run =>
+        {
+            let plan_result =
+            if build_config.what_if.is_empty()
+            {
+                build::plan_build(&RealSystem::new(), build_params)
+            }
+            else
+            {
+                build::plan_build_what_if(&RealSystem::new(), build_params, &build_config.what_if.iter().cloned().collect())
+            };
+
+            match plan_result
+            {
+                Ok(plan) => plan::print_plan(&mut printer, &plan, build_config.format == "json"),
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Build(build_config) =>
+        {
+            let start_timestamp_opt = journal::begin(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                to_journal_record("build", target_opt),
+                journal::DEFAULT_JOURNAL_MAX_RECORDS);
+
+            let result = build::build(
+                RealSystem::new(),
+                &mut printer,
+                None,
+                build_params.clone());
+
+            journal::end(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                start_timestamp_opt,
+                &result);
+
+            match result
+            {
+                Ok(()) =>
+                {
+                    match &build_config.compare_to
+                    {
+                        Some(golden_directory) =>
+                        {
+                            match build::compare_to_golden(&RealSystem::new(), build_params, golden_directory)
+                            {
+                                Ok(mismatches) =>
+                                {
+                                    for mismatch in mismatches.iter()
+                                    {
+                                        println!("   Differs {} built {} golden {}", mismatch.path,
+                                            mismatch.built_ticket.human_readable(),
+                                            match &mismatch.golden_ticket_opt
+                                            {
+                                                Some(ticket) => ticket.human_readable(),
+                                                None => "missing".to_string(),
+                                            });
+                                    }
+
+                                    if ! mismatches.is_empty()
+                                    {
+                                        std::process::exit(COMPARE_TO_MISMATCH_EXIT_CODE);
+                                    }
+                                },
+                                Err(error) =>
+                                {
+                                    eprintln!("{}", error);
+                                    std::process::exit(COMPARE_TO_MISMATCH_EXIT_CODE);
+                                },
+                            }
+                        },
+                        None => {},
+                    }
+                },
+                Err(error) =>
+                {
+                    eprintln!("{}", error);
+                    match error
+                    {
+                        build::BuildError::NoRules(_) |
+                        build::BuildError::RulesRequired(_) => std::process::exit(NO_RULES_EXIT_CODE),
+                        _ => {},
+                    }
+                },
+            }
+        },
+        RulerSubcommand::Init(init_config) =>
+        {
+            match init::init(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                &command_line.rules[0],
+                init::Template::from_name(&init_config.template).unwrap_or(init::Template::Script),
+                init_config.force)
+            {
+                Ok(()) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Run(run_config) =>
+        {
+            let start_timestamp_opt = journal::begin(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                to_journal_record("run", target_opt),
+                journal::DEFAULT_JOURNAL_MAX_RECORDS);
+
+            let result = build::run(
+                RealSystem::new(),
+                &mut printer,
+                build_params,
+                run_config.executable,
+                run_config.extra_args);
+
+            journal::end(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                start_timestamp_opt,
+                &result);
+
+            match result
+            {
+                Ok(()) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Clean(_build_config) =>
+        {
+            let start_timestamp_opt = journal::begin(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                to_journal_record("clean", target_opt),
+                journal::DEFAULT_JOURNAL_MAX_RECORDS);
+
+            let result = build::clean(
+                RealSystem::new(),
+                &mut printer,
+                build_params);
+
+            journal::end(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                start_timestamp_opt,
+                &result);
+
+            match result
+            {
+                Ok(()) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Prefetch(_build_config) =>
+        {
+            let start_timestamp_opt = journal::begin(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                to_journal_record("prefetch", target_opt),
+                journal::DEFAULT_JOURNAL_MAX_RECORDS);
+
+            let result = build::prefetch(
+                RealSystem::new(),
+                &HttpDownloader::new(),
+                &mut printer,
+                build_params);
+
+            journal::end(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                start_timestamp_opt,
+                &result);
+
+            match result
+            {
+                Ok(_report) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::ExportNinja(export_ninja_config) =>
+        {
+            match ninja::export_ninja(
+                RealSystem::new(),
+                command_line.rules,
+                export_ninja_config.target,
+                command_line.dedupe_identical_rules,
+                command_line.case_insensitive_paths,
+                &export_ninja_config.output)
+            {
+                Ok(()) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::CacheStats =>
+        {
+            match cache::cache_stats(
+                &RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                command_line.cache_dir.as_deref())
+            {
+                Ok(_stats) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Cache(cache_config) =>
+        {
+            match cache_config.command
+            {
+                CacheSubcommand::Verify(verify_config) =>
+                {
+                    /*  Only a repair changes anything, so only a repair goes in the journal. */
+                    let start_timestamp_opt =
+                    if verify_config.repair
+                    {
+                        journal::begin(
+                            &mut RealSystem::new(),
+                            &mut printer,
+                            &command_line.directory,
+                            to_journal_record("cache verify", None),
+                            journal::DEFAULT_JOURNAL_MAX_RECORDS)
+                    }
+                    else
+                    {
+                        None
+                    };
+
+                    let result = cache::cache_verify(
+                        &RealSystem::new(),
+                        &mut printer,
+                        &command_line.directory,
+                        command_line.cache_dir.as_deref(),
+                        verify_config.repair);
+
+                    journal::end(
+                        &mut RealSystem::new(),
+                        &mut printer,
+                        &command_line.directory,
+                        start_timestamp_opt,
+                        &result);
+
+                    match result
+                    {
+                        Ok(report) =>
+                        {
+                            if report.corrupt.len() > 0 && ! verify_config.repair
+                            {
+                                std::process::exit(CACHE_VERIFY_FAILED_EXIT_CODE);
+                            }
+                        },
+                        Err(error) => eprintln!("{}", error),
+                    }
+                },
+            }
+        },
+        RulerSubcommand::CriticalPath(build_config) =>
+        {
+            match timing::report_critical_path(
+                RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                command_line.rules,
+                build_config.target,
+                command_line.dedupe_identical_rules,
+                command_line.case_insensitive_paths)
+            {
+                Ok(()) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::RulesFor(rules_for_config) =>
+        {
+            match explain::rules_for(
+                &RealSystem::new(),
+                &mut printer,
+                command_line.rules,
+                rules_for_config.target,
+                command_line.dedupe_identical_rules,
+                command_line.case_insensitive_paths)
+            {
+                Ok(()) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Explain(build_config) =>
+        {
+            match explain::explain(
+                &RealSystem::new(),
+                &mut printer,
+                command_line.rules,
+                build_config.target,
+                command_line.dedupe_identical_rules,
+                command_line.case_insensitive_paths,
+                command_line.variant.as_deref())
+            {
+                Ok(()) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Status(_build_config) =>
+        {
+            match status::status(
+                &RealSystem::new(),
+                &mut printer,
+                &build_params)
+            {
+                Ok(_report) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Orphans(orphans_config) =>
+        {
+            match orphans::orphans(
+                RealSystem::new(),
+                &mut printer,
+                &build_params,
+                orphans_config.clean)
+            {
+                Ok(_orphans) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::PrintCommand(print_command_config) =>
+        {
+            match build::get_command_lines(
+                &RealSystem::new(),
+                command_line.rules,
+                print_command_config.target,
+                command_line.dedupe_identical_rules,
+                command_line.case_insensitive_paths)
+            {
+                Ok(lines) =>
+                {
+                    for line in lines
+                    {
+                        println!("{}", line);
+                    }
+                },
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::PrintLeaves =>
+        {
+            match build::get_leaves(
+                &RealSystem::new(),
+                command_line.rules,
+                command_line.dedupe_identical_rules,
+                command_line.case_insensitive_paths)
+            {
+                Ok(leaves) =>
+                {
+                    for leaf in leaves
+                    {
+                        println!("{}", leaf);
+                    }
+                },
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Query(query_config) =>
+        {
+            match ledger::query(
+                &RealSystem::new(),
+                &mut printer,
+                &build_params,
+                &query_config.target,
+                query_config.last,
+                query_config.json)
+            {
+                Ok(_records) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Last(last_config) =>
+        {
+            match journal::last(
+                &RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                last_config.count)
+            {
+                Ok(_records) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Pin(pin_config) =>
+        {
+            let start_timestamp_opt = journal::begin(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                to_journal_record("pin", Some(pin_config.target.clone())),
+                journal::DEFAULT_JOURNAL_MAX_RECORDS);
+
+            let result = pin::pin(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                command_line.cache_dir.as_deref(),
+                command_line.variant.as_deref(),
+                &pin_config.target,
+                &pin_config.ticket);
+
+            journal::end(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                start_timestamp_opt,
+                &result);
+
+            match result
+            {
+                Ok(()) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Unpin(unpin_config) =>
+        {
+            let start_timestamp_opt = journal::begin(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                to_journal_record("unpin", Some(unpin_config.target.clone())),
+                journal::DEFAULT_JOURNAL_MAX_RECORDS);
+
+            let result = pin::unpin(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                &unpin_config.target);
+
+            journal::end(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                start_timestamp_opt,
+                &result);
+
+            match result
+            {
+                Ok(()) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Lint(lint_config) =>
+        {
+            match lint::lint(
+                &RealSystem::new(),
+                &mut printer,
+                command_line.rules)
+            {
+                Ok(warnings) =>
+                {
+                    if warnings.iter().any(|warning| lint_config.deny_warnings || warning.is_error())
+                    {
+                        std::process::exit(LINT_FAILED_EXIT_CODE);
+                    }
+                },
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Doctor =>
+        {
+            let report = doctor::doctor(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                command_line.cache_dir.as_deref());
+
+            if ! report.is_healthy()
+            {
+                std::process::exit(DOCTOR_FAILED_EXIT_CODE);
+            }
+        },
+        RulerSubcommand::Bench(bench_config) =>
+        {
+            match bench::bench(
+                RealSystem::new(),
+                &mut printer,
+                build_params,
+                bench_config.runs)
+            {
+                Ok(_report) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Du(du_config) =>
+        {
+            match du::du(
+                &RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                command_line.cache_dir.as_deref(),
+                du_config.largest,
+                du_config.json)
+            {
+                Ok(_usage) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Gc(gc_config) =>
+        {
+            let start_timestamp_opt = journal::begin(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                to_journal_record("gc", None),
+                journal::DEFAULT_JOURNAL_MAX_RECORDS);
+
+            let result = gc::gc(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                command_line.cache_dir.as_deref(),
+                command_line.compress_history,
+                gc_config.older_than);
+
+            journal::end(
+                &mut RealSystem::new(),
+                &mut printer,
+                &command_line.directory,
+                start_timestamp_opt,
+                &result);
+
+            match result
+            {
+                Ok(_report) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Daemon(daemon_config) =>
+        {
+            #[cfg(unix)]
+            let address =
+            match daemon_config.socket
+            {
+                Some(path) => daemon::DaemonAddress::Socket(path),
+                None => daemon::DaemonAddress::Port(daemon_config.port),
+            };
+
+            #[cfg(not(unix))]
+            let address = daemon::DaemonAddress::Port(daemon_config.port);
+
+            match daemon::run_daemon(RealSystem::new(), &mut printer, build_params, address)
+            {
+                Ok(()) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Serve(serve_config) =>
+        {
+            match server::serve(
+                RealSystem::new(),
+                &command_line.directory,
+                command_line.cache_dir.as_deref(),
+                serve_config.port)
+            {
+                Ok(()) => {},
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::List(list_config) =>
+        {
+            match RealSystem::new().list_dir(&list_config.path)
+            {
+                Ok(list) =>
+                {
+                    for l in list
+                    {
+                        println!("{}", l);
+                    }
+                },
+                Err(error) => eprintln!("{}", error),
+            }
+        },
+        RulerSubcommand::Hash(config) =>
+        {
+            match (&config.compare, &config.path_opt)
+            {
+                (Some(paths), _) =>
+                {
+                    let difference_limit = if config.verbose { hash::DEFAULT_DIFFERENCE_LIMIT } else { 0 };
+                    match hash::compare_paths(&RealSystem::new(), &paths[0], &paths[1], difference_limit)
+                    {
+                        Ok(comparison) =>
+                        {
+                            println!("{} {}", comparison.first_ticket.human_readable(), paths[0]);
+                            println!("{} {}", comparison.second_ticket.human_readable(), paths[1]);
+                            for path in comparison.differences.iter()
+                            {
+                                println!("   Differs {}", path);
+                            }
+
+                            if comparison.is_equal()
+                            {
+                                println!("Equal");
+                            }
+                            else
+                            {
+                                println!("Unequal");
+                                std::process::exit(HASH_COMPARE_UNEQUAL_EXIT_CODE);
+                            }
+                        },
+                        Err(error) =>
+                        {
+                            eprintln!("{}", error);
+                            std::process::exit(HASH_COMPARE_ERROR_EXIT_CODE);
+                        },
+                    }
+                },
+                (None, Some(path)) =>
+                {
+                    match hash::hash_path(&mut RealSystem::new(), path)
+                    {
+                        Ok(ticket) => println!("{}", ticket.human_readable()),
+                        Err(error) => eprintln!("{}", error),
+                    }
+                },
+                (None, None) => {},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use clap::Parser;
+    use crate::
+    {
+        CommandLineParser,
+        expand_home,
+        expand_home_in_paths,
+        to_build_params,
+    };
+
+    /*  A setting given both as an environment variable and as a flag dumps as the flag's value, and as
+        the variable's when there's no flag. */
+    #[test]
+    fn dump_config_flag_overrides_environment()
+    {
+        std::env::set_var("RULER_CACHE_DIR", "env-cache");
+
+        let command_line = CommandLineParser::try_parse_from(vec!["ruler", "--dump-config", "build"]).unwrap();
+        let lines = to_build_params(&command_line).to_config_lines();
+        assert!(lines.contains(&"cache_dir = env-cache".to_string()));
+        assert!(lines.contains(&"target = none".to_string()));
+
+        let command_line = CommandLineParser::try_parse_from(
+            vec!["ruler", "--cache-dir", "flag-cache", "--dump-config", "build", "poem.txt"]).unwrap();
+        let lines = to_build_params(&command_line).to_config_lines();
+        assert!(lines.contains(&"cache_dir = flag-cache".to_string()));
+        assert!(lines.contains(&"target = poem.txt".to_string()));
+        assert!(lines.contains(&"directory = .ruler".to_string()));
+
+        std::env::remove_var("RULER_CACHE_DIR");
+    }
+
+    /*  A leading "~/" expands to the home directory, but a "~" further in, or with a name after it,
+        stays as it is, and with no home directory, so does everything. */
+    #[test]
+    fn expand_home_only_at_start()
+    {
+        assert_eq!(expand_home("~/cache", Some("/home/alice")), "/home/alice/cache");
+        assert_eq!(expand_home("~/cache", Some("/home/alice/")), "/home/alice/cache");
+        assert_eq!(expand_home("~", Some("/home/alice")), "/home/alice");
+        assert_eq!(expand_home("project/~/cache", Some("/home/alice")), "project/~/cache");
+        assert_eq!(expand_home("~bob/cache", Some("/home/alice")), "~bob/cache");
+        assert_eq!(expand_home("~/cache", None), "~/cache");
+    }
+
+    /*  The ruler directory, cache directory and rules files all get expanded, each on its own. */
+    #[test]
+    fn expand_home_in_command_line_paths()
+    {
+        let mut command_line = CommandLineParser::try_parse_from(vec!["ruler",
+            "-d", "~/.ruler", "--cache-dir", "~/cache", "-r", "~/poem.rules", "-r", "rules/~/stanza.rules", "build"]).unwrap();
+        expand_home_in_paths(&mut command_line, Some("/home/alice"));
+        assert_eq!(command_line.directory, "/home/alice/.ruler");
+        assert_eq!(command_line.cache_dir, Some("/home/alice/cache".to_string()));
+        assert_eq!(command_line.rules, vec!["/home/alice/poem.rules".to_string(), "rules/~/stanza.rules".to_string()]);
+    }
 }
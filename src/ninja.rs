@@ -0,0 +1,237 @@
+use std::fmt;
+use crate::build::
+{
+    get_nodes,
+    BuildError,
+};
+use crate::sort::
+{
+    NodePack,
+    SourceIndex,
+};
+use crate::system::
+{
+    System,
+    ReadWriteError,
+    to_command_script,
+};
+use crate::system::util::write_str_to_file;
+
+pub enum ExportNinjaError
+{
+    BuildError(BuildError),
+    FailedToWrite(String, ReadWriteError),
+}
+
+impl fmt::Display for ExportNinjaError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            ExportNinjaError::BuildError(error) =>
+                write!(formatter, "{}", error),
+
+            ExportNinjaError::FailedToWrite(path, error) =>
+                write!(formatter, "Failed to write ninja file {}: {}", path, error),
+        }
+    }
+}
+
+/*  In a ninja file, dollar-sign is the escape character.  Paths additionally need spaces and colons
+    escaped, since those separate paths in a build statement. */
+fn escape_path(path : &str) -> String
+{
+    path.replace("$", "$$").replace(" ", "$ ").replace(":", "$:")
+}
+
+fn escape_command(command : &str) -> String
+{
+    command.replace("$", "$$")
+}
+
+/*  Translates a NodePack into the text of a build.ninja file.  Each node gets a ninja rule wrapping
+    its command, and a build statement listing its targets as outputs and its sources as inputs.
+    Leaves don't get statements of their own, they just appear as inputs.  A node with no command
+    becomes a phony build statement. */
+pub fn to_ninja(node_pack : &NodePack) -> String
+{
+    let mut out = String::new();
+    for (node_index, node) in node_pack.nodes.iter().enumerate()
+    {
+        let inputs : Vec<String> = node.source_indices.iter().map(
+            |source_index|
+            {
+                escape_path(
+                    match source_index
+                    {
+                        SourceIndex::Leaf(i) => &node_pack.leaves[*i],
+                        SourceIndex::Pair(i, sub_index) => &node_pack.nodes[*i].targets[*sub_index],
+                    }
+                )
+            }
+        ).collect();
+
+        let outputs : Vec<String> = node.targets.iter().map(|target| escape_path(target)).collect();
+
+        let command_script = to_command_script(node.command.clone());
+        let rule_name = if command_script.lines.len() == 0
+        {
+            "phony".to_string()
+        }
+        else
+        {
+            let rule_name = format!("rule{}", node_index);
+            out.push_str(&format!("rule {}\n", rule_name));
+            out.push_str(&format!("  command = {}\n\n",
+                escape_command(&command_script.lines.join(" && "))));
+            rule_name
+        };
+
+        out.push_str(&format!("build {}: {}", outputs.join(" "), rule_name));
+        for input in inputs
+        {
+            out.push_str(" ");
+            out.push_str(&input);
+        }
+        out.push_str("\n\n");
+    }
+    out
+}
+
+/*  This is the function that runs when you type "ruler export-ninja" at the command-line.
+    Reads the rules the way build would, then writes the graph out in ninja format. */
+pub fn export_ninja<SystemType : System>
+(
+    mut system : SystemType,
+    rulefile_paths : Vec<String>,
+    goal_target_opt : Option<String>,
+    dedupe_rules : bool,
+    case_insensitive_paths : bool,
+    ninja_path : &str
+)
+-> Result<(), ExportNinjaError>
+{
+    let node_pack = match get_nodes(&system, rulefile_paths, goal_target_opt, dedupe_rules, case_insensitive_paths, None)
+    {
+        Ok(node_pack) => node_pack,
+        Err(error) => return Err(ExportNinjaError::BuildError(error)),
+    };
+
+    match write_str_to_file(&mut system, ninja_path, &to_ninja(&node_pack))
+    {
+        Ok(()) => Ok(()),
+        Err(error) => Err(ExportNinjaError::FailedToWrite(ninja_path.to_string(), error)),
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::ninja::export_ninja;
+    use crate::system::fake::FakeSystem;
+    use crate::system::util::
+    {
+        write_str_to_file,
+        read_file_to_string,
+    };
+
+    /*  Export the poem graph, where two stanzas each come from two verses and the poem comes from
+        the stanzas.  Check the statements come out in dependence order with the right inputs. */
+    #[test]
+    fn export_ninja_poem()
+    {
+        let rules = "\
+poem.txt
+:
+stanza1.txt
+stanza2.txt
+:
+mycat
+stanza1.txt
+stanza2.txt
+poem.txt
+:
+
+stanza1.txt
+:
+verse1.txt
+verse2.txt
+:
+mycat
+verse1.txt
+verse2.txt
+stanza1.txt
+:
+
+stanza2.txt
+:
+verse3.txt
+verse4.txt
+:
+mycat
+verse3.txt
+verse4.txt
+stanza2.txt
+:
+";
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        export_ninja(system.clone(), vec!["build.rules".to_string()], None, false, false, "build.ninja").ok().unwrap();
+
+        assert_eq!(read_file_to_string(&mut system, "build.ninja").unwrap(), "\
+rule rule0
+  command = mycat verse1.txt verse2.txt stanza1.txt
+
+build stanza1.txt: rule0 verse1.txt verse2.txt
+
+rule rule1
+  command = mycat verse3.txt verse4.txt stanza2.txt
+
+build stanza2.txt: rule1 verse3.txt verse4.txt
+
+rule rule2
+  command = mycat stanza1.txt stanza2.txt poem.txt
+
+build poem.txt: rule2 stanza1.txt stanza2.txt
+
+");
+    }
+
+    /*  Paths with spaces, colons and dollar-signs get escaped, and a rule with no command
+        becomes phony. */
+    #[test]
+    fn export_ninja_escapes_and_phony()
+    {
+        let rules = "\
+all
+:
+my poem.txt
+:
+:
+
+my poem.txt
+:
+c:verse$.txt
+:
+mycat
+$HOME
+:
+";
+        let mut system = FakeSystem::new(11);
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        export_ninja(system.clone(), vec!["build.rules".to_string()], None, false, false, "build.ninja").ok().unwrap();
+
+        assert_eq!(read_file_to_string(&mut system, "build.ninja").unwrap(), "\
+rule rule0
+  command = mycat $$HOME
+
+build my$ poem.txt: rule0 c$:verse$$.txt
+
+build all: phony my$ poem.txt
+
+");
+    }
+}
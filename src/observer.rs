@@ -0,0 +1,119 @@
+use crate::build::BuildSummary;
+#[cfg(test)]
+use crate::progress::get_status;
+use crate::work::
+{
+    WorkError,
+    WorkOption,
+};
+
+/*  What a program embedding ruler gets told as a build goes, instead of having to read it out of
+    what the printer prints: an IDE, say.  Every method gets called from the thread that called build,
+    in the order things happened, as far as that thread can tell.  A rule's node_started always comes
+    before its node_finished or node_failed, and its command_output lines in between.  Sources don't
+    get events of their own.  Every method does nothing unless overridden, so an observer need only
+    implement the ones it cares about. */
+pub trait BuildObserver
+{
+    /*  The rules files are read and sorted, and the build is about to start on the given number of
+        rules. */
+    fn build_started(&mut self, _total_nodes : usize)
+    {
+    }
+
+    /*  A rule's sources are all ready, and it's getting its targets up-to-date. */
+    fn node_started(&mut self, _targets : &[String])
+    {
+    }
+
+    /*  A line of what a rule's command wrote to stdout or stderr, without the newline. */
+    fn command_output(&mut self, _targets : &[String], _line : &str)
+    {
+    }
+
+    /*  A rule's targets are up-to-date, and the work option says how they got that way: built,
+        recovered, downloaded and so on. */
+    fn node_finished(&mut self, _targets : &[String], _work_option : &WorkOption)
+    {
+    }
+
+    fn node_failed(&mut self, _targets : &[String], _error : &WorkError)
+    {
+    }
+
+    fn build_finished(&mut self, _success : bool, _summary : &BuildSummary)
+    {
+    }
+}
+
+/*  Ignores everything, for builds nobody's observing. */
+pub struct EmptyObserver
+{
+}
+
+impl EmptyObserver
+{
+    pub fn new() -> EmptyObserver
+    {
+        EmptyObserver
+        {
+        }
+    }
+}
+
+impl BuildObserver for EmptyObserver
+{
+}
+
+/*  Keeps every event as a line of text, so tests can check what an observer would have been told. */
+#[cfg(test)]
+pub struct RecordingObserver
+{
+    pub events : Vec<String>,
+}
+
+#[cfg(test)]
+impl RecordingObserver
+{
+    pub fn new() -> RecordingObserver
+    {
+        RecordingObserver
+        {
+            events : vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+impl BuildObserver for RecordingObserver
+{
+    fn build_started(&mut self, total_nodes : usize)
+    {
+        self.events.push(format!("build started {}", total_nodes));
+    }
+
+    fn node_started(&mut self, targets : &[String])
+    {
+        self.events.push(format!("started {}", targets.join(" ")));
+    }
+
+    fn command_output(&mut self, targets : &[String], line : &str)
+    {
+        self.events.push(format!("output {}: {}", targets.join(" "), line));
+    }
+
+    fn node_finished(&mut self, targets : &[String], work_option : &WorkOption)
+    {
+        self.events.push(format!("finished {} {}", targets.join(" "), get_status(work_option)));
+    }
+
+    fn node_failed(&mut self, targets : &[String], error : &WorkError)
+    {
+        self.events.push(format!("failed {}: {}", targets.join(" "), error));
+    }
+
+    fn build_finished(&mut self, success : bool, _summary : &BuildSummary)
+    {
+        self.events.push(format!("build finished {}", if success { "ok" } else { "failed" }));
+    }
+}
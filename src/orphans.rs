@@ -0,0 +1,290 @@
+use std::collections::HashSet;
+use termcolor::Color;
+use crate::build::
+{
+    BuildError,
+    BuildParams,
+    get_nodes,
+};
+use crate::current::CurrentFileStates;
+use crate::directory::
+{
+    self,
+    InitDirectoryError,
+};
+use crate::printer::Printer;
+use crate::sort::NodePack;
+use crate::system::System;
+use crate::work::clean_targets;
+
+/*  Every path the rules mention, as a target or as a source. */
+pub fn get_rule_paths(node_pack : &NodePack) -> HashSet<String>
+{
+    let mut paths : HashSet<String> = node_pack.leaves.iter().cloned().collect();
+    for node in node_pack.nodes.iter()
+    {
+        paths.extend(node.targets.iter().cloned());
+    }
+    paths
+}
+
+/*  The paths some rule once produced that no rule mentions anymore, in sorted order.  Paths only ever
+    read as sources never count, and neither does anything the rules still read, even if it used to be
+    a target. */
+pub fn find_orphans<SystemType : System>
+(
+    current_file_states : &CurrentFileStates<SystemType>,
+    rule_paths : &HashSet<String>
+)
+-> Vec<String>
+{
+    current_file_states.get_target_paths().into_iter().filter(|path| ! rule_paths.contains(path)).collect()
+}
+
+/*  Prints a warning for each orphan still in the filesystem. */
+pub fn warn_about_orphans<SystemType : System, PrinterType : Printer>
+(
+    system : &SystemType,
+    printer : &mut PrinterType,
+    orphans : &Vec<String>
+)
+{
+    for path in orphans.iter().filter(|path| system.is_file(path))
+    {
+        printer.print_single_banner_line("    Orphan", Color::Yellow,
+            &format!("{} (no rule makes it anymore; 'ruler orphans --clean' moves it to the cache)", path));
+    }
+}
+
+/*  This is the function that runs when you type "ruler orphans" at the command-line.  Lists the files
+    some rule produced that no rule in the current rules files mentions anymore, and returns them.  With
+    clean, moves each into the cache the way "ruler clean" would, and forgets it, so it's as if it had
+    never been built.  Orphans already gone from the filesystem get forgotten without a mention. */
+pub fn orphans
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    mut system : SystemType,
+    printer : &mut PrinterType,
+    params : &BuildParams,
+    clean : bool
+)
+-> Result<Vec<String>, BuildError>
+{
+    let variant_opt = params.variant_opt.as_deref();
+    let node_pack = get_nodes(&system, params.rulefile_paths.clone(), None, params.dedupe_rules, params.case_insensitive_paths,
+        variant_opt)?;
+
+    /*  Only cleaning changes anything, so only cleaning creates the ruler directory. */
+    let elements_result =
+    if clean
+    {
+        directory::init(&mut system, &params.directory_path, params.cache_directory_opt.as_deref(), variant_opt, false)
+    }
+    else
+    {
+        directory::open_readonly(&system, &params.directory_path, params.cache_directory_opt.as_deref(), variant_opt)
+    };
+
+    let mut elements =
+    match elements_result
+    {
+        Ok(elements) => elements,
+        Err(InitDirectoryError::FailedToReadCurrentFileStates(error)) =>
+            return Err(BuildError::FailedToReadCurrentFileStates(error)),
+        Err(error) => return Err(BuildError::DirectoryInitFailed(error)),
+    };
+
+    let candidates = find_orphans(&elements.current_file_states, &get_rule_paths(&node_pack));
+    let orphans : Vec<String> = candidates.iter().filter(|path| system.is_file(path)).cloned().collect();
+
+    if ! clean
+    {
+        if orphans.len() == 0
+        {
+            printer.print("No orphans");
+        }
+        for path in orphans.iter()
+        {
+            printer.print_single_banner_line("    Orphan", Color::Yellow, path);
+        }
+        return Ok(orphans);
+    }
+
+    let blob = elements.current_file_states.take_blob(orphans.clone());
+    match clean_targets(blob, &mut system, &mut elements.cache)
+    {
+        Ok(_count) => {},
+        Err(error) => return Err(BuildError::WorkErrors(vec![(None, error)])),
+    }
+
+    for path in candidates.iter()
+    {
+        elements.current_file_states.forget(path);
+    }
+
+    match elements.current_file_states.to_file()
+    {
+        Ok(()) => {},
+        Err(_) => printer.error("Error writing history"),
+    }
+
+    for path in orphans.iter()
+    {
+        printer.print_single_banner_line("    Cached", Color::Cyan, path);
+    }
+    if orphans.len() == 0
+    {
+        printer.print("No orphans");
+    }
+
+    Ok(orphans)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::build::
+    {
+        build,
+        BuildParams,
+    };
+    use crate::directory;
+    use crate::orphans::orphans;
+    use crate::printer::
+    {
+        EmptyPrinter,
+        RecordingPrinter,
+    };
+    use crate::ticket::TicketFactory;
+    use crate::system::
+    {
+        System,
+        fake::FakeSystem,
+    };
+    use crate::system::util::
+    {
+        read_file_to_string,
+        write_str_to_file,
+    };
+
+    const STANZA_RULES : &str = "\
+stanza1.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+stanza1.txt
+:
+
+stanza2.txt
+:
+verse2.txt
+:
+mycat
+verse2.txt
+stanza2.txt
+:
+";
+
+    const POEM_RULE : &str = "
+poem.txt
+:
+stanza1.txt
+stanza2.txt
+:
+mycat
+stanza1.txt
+stanza2.txt
+poem.txt
+:
+";
+
+    /*  Build the poem, then take away its rule, keeping the stanzas.  The next build warns about the
+        poem, and so does orphans, which with clean moves exactly the poem into the cache and forgets
+        it.  The stanzas and verses stay put. */
+    #[test]
+    fn orphan_after_rule_removed()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", &format!("{}{}", STANZA_RULES, POEM_RULE)).unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(orphans(system.clone(), &mut EmptyPrinter::new(), &BuildParams::for_test(), false).unwrap(), Vec::<String>::new());
+
+        write_str_to_file(&mut system, "build.rules", STANZA_RULES).unwrap();
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
+        assert!(printer.printed.contains(&"    Orphan poem.txt (no rule makes it anymore; 'ruler orphans --clean' moves it to the cache)".to_string()));
+
+        assert_eq!(orphans(system.clone(), &mut EmptyPrinter::new(), &BuildParams::for_test(), false).unwrap(), vec!["poem.txt"]);
+        assert!(system.is_file("poem.txt"));
+
+        let mut printer = RecordingPrinter::new();
+        assert_eq!(orphans(system.clone(), &mut printer, &BuildParams::for_test(), true).unwrap(), vec!["poem.txt"]);
+        assert_eq!(printer.printed, vec!["    Cached poem.txt"]);
+
+        assert!(!system.is_file("poem.txt"));
+        for path in ["verse1.txt", "verse2.txt", "stanza1.txt", "stanza2.txt"]
+        {
+            assert!(system.is_file(path));
+        }
+
+        let elements = directory::open_readonly(&system, ".ruler", None, None).unwrap();
+        assert!(elements.current_file_states.get_file_state("poem.txt").is_none());
+        assert!(elements.current_file_states.get_file_state("stanza1.txt").is_some());
+        let poem_ticket = TicketFactory::from_str("Roses are red.\nViolets are violet.\n").result();
+        let mut reader = elements.cache.open(&poem_ticket).unwrap();
+        let mut content = String::new();
+        std::io::Read::read_to_string(&mut reader, &mut content).unwrap();
+        assert_eq!(content, "Roses are red.\nViolets are violet.\n");
+        assert_eq!(read_file_to_string(&system, "stanza2.txt").unwrap(), "Violets are violet.\n");
+
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
+        assert!(!printer.printed.iter().any(|line| line.starts_with("    Orphan")));
+    }
+
+    /*  Listing orphans on a read-only checkout that's never been built finds none and creates no
+        ruler directory. */
+    #[test]
+    fn orphans_listing_creates_nothing()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "build.rules", STANZA_RULES).unwrap();
+        system.set_read_only(true);
+
+        let mut printer = RecordingPrinter::new();
+        assert_eq!(orphans(system.clone(), &mut printer, &BuildParams::for_test(), false).unwrap(), Vec::<String>::new());
+        assert_eq!(printer.printed, vec!["No orphans"]);
+        assert!(!system.is_dir(".ruler"));
+    }
+
+    /*  Build the poem under a variant, then take away its rule.  Orphans under that variant finds the
+        poem, from the variant's current file states.  Without the variant, there's nothing on record,
+        so nothing's an orphan. */
+    #[test]
+    fn orphans_with_variant()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", &format!("{}{}", STANZA_RULES, POEM_RULE)).unwrap();
+        let mut params = BuildParams::for_test();
+        params.variant_opt = Some("debug".to_string());
+        build(system.clone(), &mut EmptyPrinter::new(), None, params.clone()).unwrap();
+
+        write_str_to_file(&mut system, "build.rules", STANZA_RULES).unwrap();
+        assert_eq!(orphans(system.clone(), &mut EmptyPrinter::new(), &BuildParams::for_test(), false).unwrap(), Vec::<String>::new());
+        assert_eq!(orphans(system.clone(), &mut EmptyPrinter::new(), &params, false).unwrap(), vec!["poem.txt"]);
+
+        assert_eq!(orphans(system.clone(), &mut EmptyPrinter::new(), &params, true).unwrap(), vec!["poem.txt"]);
+        assert!(!system.is_file("poem.txt"));
+        let elements = directory::open_readonly(&system, ".ruler", None, Some("debug")).unwrap();
+        assert!(elements.current_file_states.get_file_state("poem.txt").is_none());
+    }
+}
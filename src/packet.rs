@@ -1,4 +1,4 @@
-use crate::ticket::Ticket;
+use crate::blob::FileState;
 
 #[derive(Debug)]
 pub enum PacketError
@@ -6,18 +6,21 @@ pub enum PacketError
     Cancel,
 }
 
+/*  What a node sends each node that depends on it: the state of one of its targets, or a cancel if
+    the node failed.  Carrying the whole FileState rather than just the ticket means dependents learn
+    the timestamp and executable bit too, without going back to the filesystem. */
 pub struct Packet
 {
-    ticket_result: Result<Ticket, PacketError>,
+    file_state_result: Result<FileState, PacketError>,
 }
 
 impl Packet
 {
-    pub fn from_ticket(ticket: Ticket) -> Packet
+    pub fn from_file_state(file_state: FileState) -> Packet
     {
         Packet
         {
-            ticket_result: Ok(ticket),
+            file_state_result: Ok(file_state),
         }
     }
 
@@ -25,12 +28,22 @@ impl Packet
     {
         Packet
         {
-            ticket_result: Err(PacketError::Cancel)
+            file_state_result: Err(PacketError::Cancel)
         }
     }
 
-    pub fn get_ticket(self) -> Result<Ticket, PacketError>
+    pub fn get_file_state(self) -> Result<FileState, PacketError>
     {
-        self.ticket_result
+        self.file_state_result
+    }
+
+    /*  What the packet carries, briefly, for tracing: the short ticket, or cancel. */
+    pub fn describe(&self) -> String
+    {
+        match &self.file_state_result
+        {
+            Ok(file_state) => file_state.ticket.short_human_readable(),
+            Err(PacketError::Cancel) => "cancel".to_string(),
+        }
     }
 }
@@ -0,0 +1,547 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use termcolor::Color;
+use crate::directory::
+{
+    self,
+    InitDirectoryError,
+};
+use crate::cache::OpenError;
+use crate::printer::Printer;
+use crate::ticket::
+{
+    Ticket,
+    TicketFactory,
+    FromHumanReadableError,
+};
+use crate::system::
+{
+    System,
+    SystemError,
+    ReadWriteError,
+};
+use crate::system::util::
+{
+    read_file_to_string,
+    write_str_to_file,
+    ReadFileToStringError,
+};
+
+/*  The targets pinned to particular tickets.  While a target is pinned, builds leave it alone: its
+    rule doesn't run, isn't resolved against the cache, and gets no history written, and dependents
+    see the pinned ticket. */
+#[derive(Debug, PartialEq)]
+pub struct Pins
+{
+    pins : BTreeMap<String, Ticket>,
+}
+
+#[derive(Debug)]
+pub enum PinsError
+{
+    CannotReadPinsFile(String, ReadFileToStringError),
+    CannotInterpretPinsFile(String, usize),
+    CannotWritePinsFile(String, ReadWriteError),
+}
+
+impl fmt::Display for PinsError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            PinsError::CannotReadPinsFile(path, error) =>
+                write!(formatter, "Cannot read pins file: {}: {}", path, error),
+
+            PinsError::CannotInterpretPinsFile(path, line_number) =>
+                write!(formatter, "Cannot interpret pins file: {}:{}", path, line_number),
+
+            PinsError::CannotWritePinsFile(path, error) =>
+                write!(formatter, "Cannot write pins file: {}: {}", path, error),
+        }
+    }
+}
+
+/*  The path of the pins file inside the ruler directory. */
+pub fn get_pins_path(directory_path : &str) -> String
+{
+    format!("{}/pins", directory_path)
+}
+
+impl Pins
+{
+    pub fn new() -> Pins
+    {
+        Pins
+        {
+            pins : BTreeMap::new(),
+        }
+    }
+
+    /*  Reads the pins file, one pin per line: the ticket, a tab, then the target path.  No file means
+        no pins. */
+    pub fn from_file<SystemType : System>
+    (
+        system : &SystemType,
+        path : &str
+    )
+    -> Result<Pins, PinsError>
+    {
+        let mut pins = Pins::new();
+        if ! system.is_file(path)
+        {
+            return Ok(pins);
+        }
+
+        let content =
+        match read_file_to_string(system, path)
+        {
+            Ok(content) => content,
+            Err(error) => return Err(PinsError::CannotReadPinsFile(path.to_string(), error)),
+        };
+
+        for (index, line) in content.lines().enumerate()
+        {
+            match line.split_once('\t')
+            {
+                Some((ticket_str, target)) =>
+                {
+                    match Ticket::from_human_readable(ticket_str)
+                    {
+                        Ok(ticket) => pins.insert(target.to_string(), ticket),
+                        Err(_) => return Err(PinsError::CannotInterpretPinsFile(path.to_string(), index + 1)),
+                    }
+                },
+                None => return Err(PinsError::CannotInterpretPinsFile(path.to_string(), index + 1)),
+            }
+        }
+
+        Ok(pins)
+    }
+
+    pub fn to_file<SystemType : System>
+    (
+        &self,
+        system : &mut SystemType,
+        path : &str
+    )
+    -> Result<(), PinsError>
+    {
+        let mut content = String::new();
+        for (target, ticket) in self.pins.iter()
+        {
+            content.push_str(&format!("{}\t{}\n", ticket, target));
+        }
+
+        match write_str_to_file(system, path, &content)
+        {
+            Ok(()) => Ok(()),
+            Err(error) => Err(PinsError::CannotWritePinsFile(path.to_string(), error)),
+        }
+    }
+
+    pub fn get(&self, target : &str) -> Option<&Ticket>
+    {
+        self.pins.get(target)
+    }
+
+    pub fn insert(&mut self, target : String, ticket : Ticket)
+    {
+        self.pins.insert(target, ticket);
+    }
+
+    /*  The ticket of every pin. */
+    pub fn get_tickets(&self) -> Vec<Ticket>
+    {
+        self.pins.values().cloned().collect()
+    }
+
+    /*  Removes the pin on the given target, returning whether there was one. */
+    pub fn remove(&mut self, target : &str) -> bool
+    {
+        self.pins.remove(target).is_some()
+    }
+}
+
+pub enum PinError
+{
+    InvalidTicket(String, FromHumanReadableError),
+    DirectoryInitFailed(InitDirectoryError),
+    TicketNotInCache(String, Ticket),
+    CacheMalfunction(SystemError),
+    CannotReadTarget(String, ReadWriteError),
+    CannotWriteTarget(String, String),
+    NotPinned(String),
+    PinsError(PinsError),
+}
+
+impl fmt::Display for PinError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            PinError::InvalidTicket(ticket_str, error) =>
+                write!(formatter, "Invalid ticket: {}: {}", ticket_str, error),
+
+            PinError::DirectoryInitFailed(error) =>
+                write!(formatter, "Failed to initialize ruler directory: {}", error),
+
+            PinError::TicketNotInCache(target, ticket) =>
+                write!(formatter, "Cannot pin {}: no file with ticket {} in the cache", target, ticket),
+
+            PinError::CacheMalfunction(error) =>
+                write!(formatter, "Cache malfunction: {}", error),
+
+            PinError::CannotReadTarget(target, error) =>
+                write!(formatter, "Cannot read target: {}: {}", target, error),
+
+            PinError::CannotWriteTarget(target, error) =>
+                write!(formatter, "Cannot write target: {}: {}", target, error),
+
+            PinError::NotPinned(target) =>
+                write!(formatter, "Target not pinned: {}", target),
+
+            PinError::PinsError(error) =>
+                write!(formatter, "{}", error),
+        }
+    }
+}
+
+/*  This is the function that runs when you type "ruler pin" at the command-line.  Puts the file with
+    the given ticket from the cache in place as the target and records the pin, so builds leave it be
+    until "ruler unpin".  Whatever was at the target before goes into the cache.  The pinned file
+    gets copied out of the cache rather than moved, so the cache can serve it again. */
+pub fn pin
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &mut SystemType,
+    printer : &mut PrinterType,
+    directory_path : &str,
+    cache_directory_opt : Option<&str>,
+    variant_opt : Option<&str>,
+    target : &str,
+    ticket_str : &str
+)
+-> Result<(), PinError>
+{
+    let ticket =
+    match Ticket::from_human_readable(ticket_str)
+    {
+        Ok(ticket) => ticket,
+        Err(error) => return Err(PinError::InvalidTicket(ticket_str.to_string(), error)),
+    };
+
+    let mut elements =
+    match directory::init(system, directory_path, cache_directory_opt, variant_opt, false)
+    {
+        Ok(elements) => elements,
+        Err(error) => return Err(PinError::DirectoryInitFailed(error)),
+    };
+
+    /*  Check the cache has the file before touching the target, so a bad ticket changes nothing. */
+    match elements.cache.open(&ticket)
+    {
+        Ok(_reader) => {},
+        Err(OpenError::NotThere) => return Err(PinError::TicketNotInCache(target.to_string(), ticket)),
+        Err(OpenError::CacheDirectoryMissing) => return Err(PinError::TicketNotInCache(target.to_string(), ticket)),
+        Err(OpenError::SystemError(error)) => return Err(PinError::CacheMalfunction(error)),
+    };
+
+    let current_ticket_opt =
+    if system.is_file(target)
+    {
+        match TicketFactory::from_file(system, target)
+        {
+            Ok(mut factory) => Some(factory.result()),
+            Err(error) => return Err(PinError::CannotReadTarget(target.to_string(), error)),
+        }
+    }
+    else
+    {
+        None
+    };
+
+    match &current_ticket_opt
+    {
+        Some(current_ticket) =>
+        {
+            if *current_ticket != ticket
+            {
+                match elements.cache.back_up_file_with_ticket(current_ticket, target)
+                {
+                    Ok(()) => {},
+                    Err(error) => return Err(PinError::CannotReadTarget(target.to_string(), error)),
+                }
+            }
+        },
+        None => {},
+    }
+
+    if current_ticket_opt != Some(ticket.clone())
+    {
+        let mut reader =
+        match elements.cache.open(&ticket)
+        {
+            Ok(reader) => reader,
+            Err(error) => return Err(PinError::CannotWriteTarget(target.to_string(), error.to_string())),
+        };
+
+        let mut file =
+        match system.create_file(target)
+        {
+            Ok(file) => file,
+            Err(error) => return Err(PinError::CannotWriteTarget(target.to_string(), error.to_string())),
+        };
+
+        match io::copy(&mut reader, &mut file)
+        {
+            Ok(_size) => {},
+            Err(error) => return Err(PinError::CannotWriteTarget(target.to_string(), error.to_string())),
+        }
+    }
+
+    elements.pins.insert(target.to_string(), ticket.clone());
+    match elements.pins.to_file(system, &get_pins_path(directory_path))
+    {
+        Ok(()) => {},
+        Err(error) => return Err(PinError::PinsError(error)),
+    }
+
+    printer.print_single_banner_line("    Pinned", Color::Cyan, &format!("{} to {}", target, ticket));
+    Ok(())
+}
+
+/*  This is the function that runs when you type "ruler unpin" at the command-line.  Removes the pin,
+    leaving the file where it is.  The next build treats it like any other target, and puts back
+    whatever the rules say it should be. */
+pub fn unpin
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &mut SystemType,
+    printer : &mut PrinterType,
+    directory_path : &str,
+    target : &str
+)
+-> Result<(), PinError>
+{
+    let pins_path = get_pins_path(directory_path);
+    let mut pins =
+    match Pins::from_file(system, &pins_path)
+    {
+        Ok(pins) => pins,
+        Err(error) => return Err(PinError::PinsError(error)),
+    };
+
+    if ! pins.remove(target)
+    {
+        return Err(PinError::NotPinned(target.to_string()));
+    }
+
+    match pins.to_file(system, &pins_path)
+    {
+        Ok(()) => {},
+        Err(error) => return Err(PinError::PinsError(error)),
+    }
+
+    printer.print_single_banner_line("  Unpinned", Color::Cyan, target);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::build::
+    {
+        build,
+        BuildParams,
+    };
+    use crate::pin::
+    {
+        pin,
+        unpin,
+        Pins,
+        PinError,
+        get_pins_path,
+    };
+    use crate::status::
+    {
+        status,
+        TargetStatus,
+    };
+    use crate::printer::
+    {
+        EmptyPrinter,
+        RecordingPrinter,
+    };
+    use crate::ticket::TicketFactory;
+    use crate::system::
+    {
+        System,
+        fake::FakeSystem,
+    };
+    use crate::system::util::
+    {
+        read_file_to_string,
+        write_str_to_file,
+    };
+
+    const RULES : &str = "\
+poem.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+poem.txt
+:
+
+book.txt
+:
+poem.txt
+:
+mycat
+poem.txt
+book.txt
+:
+";
+
+    /*  Builds the poem from the first verse, then from the second, so both poems end up in the cache
+        or in place. */
+    fn build_two_versions() -> FakeSystem
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "build.rules", RULES).unwrap();
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+        system.time_passes(1);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are blue.\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        system.time_passes(1);
+        system
+    }
+
+    /*  Pin the poem to its old version, and check a build keeps it, doesn't run the poem's command,
+        says the poem is pinned, and brings the book in line with the old poem.  Build again and check the
+        pin holds.  Status lists the pin.  Then unpin, build, and check everything's back to current. */
+    #[test]
+    fn pin_build_unpin()
+    {
+        let mut system = build_two_versions();
+        let old_ticket = TicketFactory::from_str("Roses are red.\n").result();
+
+        pin(&mut system, &mut EmptyPrinter::new(), ".ruler", None, None, "poem.txt", &old_ticket.human_readable()).ok().unwrap();
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\n");
+
+        system.time_passes(1);
+        let commands_before = system.get_command_log_joined().len();
+        let mut printer = RecordingPrinter::new();
+        build(system.clone(), &mut printer, None, BuildParams::for_test()).unwrap();
+        assert!(printer.printed.contains(&"    Pinned poem.txt".to_string()));
+        assert!(!system.get_command_log_joined()[commands_before..].contains(&"mycat verse1.txt poem.txt".to_string()));
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\n");
+        assert_eq!(read_file_to_string(&system, "book.txt").unwrap(), "Roses are red.\n");
+
+        system.time_passes(1);
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\n");
+
+        let report = status(&system, &mut EmptyPrinter::new(), &BuildParams::for_test()).unwrap();
+        assert!(report.contains(&("poem.txt".to_string(), TargetStatus::Pinned(old_ticket.clone()))));
+
+        unpin(&mut system, &mut EmptyPrinter::new(), ".ruler", "poem.txt").ok().unwrap();
+        assert_eq!(Pins::from_file(&system, &get_pins_path(".ruler")).unwrap(), Pins::new());
+
+        system.time_passes(1);
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are blue.\n");
+        assert_eq!(read_file_to_string(&system, "book.txt").unwrap(), "Roses are blue.\n");
+
+        match unpin(&mut system, &mut EmptyPrinter::new(), ".ruler", "poem.txt")
+        {
+            Err(PinError::NotPinned(target)) => assert_eq!(target, "poem.txt"),
+            _ => panic!("Expected an error unpinning a target that isn't pinned"),
+        }
+    }
+
+    /*  Pin to a ticket the cache doesn't have, and check the error names the target and ticket, and
+        that neither the target nor the pins changed. */
+    #[test]
+    fn pin_ticket_missing_from_cache()
+    {
+        let mut system = build_two_versions();
+        let missing_ticket = TicketFactory::from_str("Roses are green.\n").result();
+
+        match pin(&mut system, &mut EmptyPrinter::new(), ".ruler", None, None, "poem.txt", &missing_ticket.human_readable())
+        {
+            Err(error) =>
+            {
+                assert_eq!(format!("{}", error),
+                    format!("Cannot pin poem.txt: no file with ticket {} in the cache", missing_ticket));
+                match error
+                {
+                    PinError::TicketNotInCache(target, ticket) =>
+                    {
+                        assert_eq!(target, "poem.txt");
+                        assert_eq!(ticket, missing_ticket);
+                    },
+                    _ => panic!("Wrong error"),
+                }
+            },
+            Ok(()) => panic!("Unexpected success pinning to a ticket not in the cache"),
+        }
+
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are blue.\n");
+        assert!(!system.is_file(&get_pins_path(".ruler")));
+    }
+
+    /*  Pin the poem to one version and build, then pin it to the other and build again.  The book
+        follows the pin each time. */
+    #[test]
+    fn pin_change_rebuilds_downstream()
+    {
+        let mut system = build_two_versions();
+        let old_ticket = TicketFactory::from_str("Roses are red.\n").result();
+        let new_ticket = TicketFactory::from_str("Roses are blue.\n").result();
+
+        pin(&mut system, &mut EmptyPrinter::new(), ".ruler", None, None, "poem.txt", &old_ticket.human_readable()).ok().unwrap();
+        system.time_passes(1);
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(read_file_to_string(&system, "book.txt").unwrap(), "Roses are red.\n");
+
+        pin(&mut system, &mut EmptyPrinter::new(), ".ruler", None, None, "poem.txt", &new_ticket.human_readable()).ok().unwrap();
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are blue.\n");
+        system.time_passes(1);
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        assert_eq!(read_file_to_string(&system, "book.txt").unwrap(), "Roses are blue.\n");
+    }
+
+    /*  Pinning under a variant reads that variant's current file states, so a default one that won't
+        parse gets in the way of pinning without the variant, but not with it. */
+    #[test]
+    fn pin_with_variant()
+    {
+        let mut system = build_two_versions();
+        let old_ticket = TicketFactory::from_str("Roses are red.\n").result();
+        write_str_to_file(&mut system, ".ruler/current_file_states", "not current file states").unwrap();
+
+        match pin(&mut system, &mut EmptyPrinter::new(), ".ruler", None, None, "poem.txt", &old_ticket.human_readable())
+        {
+            Err(PinError::DirectoryInitFailed(_)) => {},
+            Err(error) => panic!("Wrong error: {}", error),
+            Ok(()) => panic!("Unexpected success pinning with unreadable current file states"),
+        }
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are blue.\n");
+
+        pin(&mut system, &mut EmptyPrinter::new(), ".ruler", None, Some("debug"), "poem.txt", &old_ticket.human_readable()).ok().unwrap();
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\n");
+    }
+}
@@ -0,0 +1,344 @@
+use std::fmt;
+use serde::
+{
+    Serialize,
+    Deserialize,
+};
+use termcolor::Color;
+use crate::printer::Printer;
+
+/*  Where targets a build would recover come from. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RecoverFrom
+{
+    Cache,
+    Download,
+}
+
+/*  Why a build would run a rule's command. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RebuildReason
+{
+    /*  The rule has no history, so it has never been built. */
+    NeverBuilt,
+
+    /*  The history remembers nothing for the sources as they are now. */
+    SourcesChanged,
+
+    /*  These sources get rebuilt first, so what they'll be isn't known until then. */
+    SourcesRebuilt(Vec<String>),
+
+    /*  These sources aren't there.  The build would stop with an error. */
+    SourcesMissing(Vec<String>),
+
+    /*  The history remembers these targets, but neither the files in place nor the cache have them. */
+    TargetsUnavailable(Vec<String>),
+
+    /*  The rule is marked @no-cache, so its command always runs. */
+    NoCache,
+}
+
+impl fmt::Display for RebuildReason
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            RebuildReason::NeverBuilt =>
+                write!(formatter, "never built"),
+
+            RebuildReason::SourcesChanged =>
+                write!(formatter, "sources changed"),
+
+            RebuildReason::SourcesRebuilt(paths) =>
+                write!(formatter, "rebuilt first: {}", paths.join(", ")),
+
+            RebuildReason::SourcesMissing(paths) =>
+                write!(formatter, "missing: {}", paths.join(", ")),
+
+            RebuildReason::TargetsUnavailable(paths) =>
+                write!(formatter, "not in the cache: {}", paths.join(", ")),
+
+            RebuildReason::NoCache =>
+                write!(formatter, "@no-cache"),
+        }
+    }
+}
+
+/*  What a build would do with a rule's targets. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PredictedAction
+{
+    UpToDate,
+    Recover{from : RecoverFrom},
+    Rebuild{reason : RebuildReason},
+}
+
+/*  One source of a planned node: its path, and the index in the plan of the node that makes it, or
+    None if nothing does. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlanEdge
+{
+    pub path : String,
+    pub from_node : Option<usize>,
+}
+
+/*  One rule in the plan.  Tickets are human-readable.  The sources ticket is None when some source's
+    ticket can't be known without building. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlannedNode
+{
+    pub targets : Vec<String>,
+    pub rule_ticket : String,
+    pub action : PredictedAction,
+    pub sources_ticket : Option<String>,
+    pub sources : Vec<PlanEdge>,
+}
+
+/*  What a build would do, node by node, in the order build would start them. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildPlan
+{
+    pub nodes : Vec<PlannedNode>,
+}
+
+impl BuildPlan
+{
+    pub fn to_json(&self) -> String
+    {
+        match serde_json::to_string_pretty(self)
+        {
+            Ok(json) => json,
+            Err(error) => panic!("Build plan failed to serialize: {}", error),
+        }
+    }
+}
+
+/*  Prints one line per node, saying what a build would do with its targets, or with json, the whole
+    plan as JSON. */
+pub fn print_plan<PrinterType : Printer>
+(
+    printer : &mut PrinterType,
+    plan : &BuildPlan,
+    json : bool
+)
+{
+    if json
+    {
+        printer.print(&plan.to_json());
+        return;
+    }
+
+    for node in plan.nodes.iter()
+    {
+        let targets = node.targets.join(" ");
+        match &node.action
+        {
+            PredictedAction::UpToDate =>
+                printer.print_single_banner_line("Up-to-date", Color::Cyan, &targets),
+
+            PredictedAction::Recover{from : RecoverFrom::Cache} =>
+                printer.print_single_banner_line("   Recover", Color::Yellow, &format!("{} (from cache)", targets)),
+
+            PredictedAction::Recover{from : RecoverFrom::Download} =>
+                printer.print_single_banner_line("  Download", Color::Yellow, &targets),
+
+            PredictedAction::Rebuild{reason} =>
+                printer.print_single_banner_line("   Rebuild", Color::Green, &format!("{} ({})", targets, reason)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::build::
+    {
+        build,
+        plan_build,
+        plan_build_what_if,
+        BuildParams,
+    };
+    use std::collections::HashMap;
+    use crate::plan::
+    {
+        print_plan,
+        BuildPlan,
+        PlanEdge,
+        PredictedAction,
+        RebuildReason,
+        RecoverFrom,
+    };
+    use crate::printer::
+    {
+        EmptyPrinter,
+        RecordingPrinter,
+    };
+    use crate::ticket::TicketFactory;
+    use crate::system::
+    {
+        System,
+        fake::FakeSystem,
+    };
+    use crate::system::util::
+    {
+        read_file,
+        read_file_to_string,
+        write_str_to_file,
+    };
+
+    const RULES : &str = "\
+poem.txt
+:
+stanza1.txt
+stanza2.txt
+:
+mycat
+stanza1.txt
+stanza2.txt
+poem.txt
+:
+
+stanza1.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+stanza1.txt
+:
+
+stanza2.txt
+:
+verse2.txt
+:
+mycat
+verse2.txt
+stanza2.txt
+:
+";
+
+    /*  Every file under the path, with its bytes, sorted. */
+    fn snapshot(system : &FakeSystem, path : &str) -> Vec<(String, Vec<u8>)>
+    {
+        system.list_dir_recursive(path).unwrap().into_iter().map(
+            |file_path| (file_path.clone(), read_file(system, &file_path).unwrap())).collect()
+    }
+
+    /*  Build the poem, change the first verse, and plan.  The first stanza rebuilds because its
+        sources changed, and the poem because the first stanza rebuilds first.  The second stanza is
+        up-to-date.  Planning changes nothing in the ruler directory or the tree, and the plan
+        survives a round trip through JSON. */
+    #[test]
+    fn plan_after_source_changed()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", RULES).unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+        system.time_passes(1);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are blue.\n").unwrap();
+        let before = snapshot(&system, ".ruler");
+        let plan = plan_build(&system, BuildParams::for_test()).unwrap();
+        assert_eq!(snapshot(&system, ".ruler"), before);
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Roses are red.\nViolets are violet.\n");
+
+        let targets : Vec<Vec<String>> = plan.nodes.iter().map(|node| node.targets.clone()).collect();
+        assert_eq!(targets, vec![vec!["stanza1.txt"], vec!["stanza2.txt"], vec!["poem.txt"]]);
+        assert_eq!(plan.nodes[0].action, PredictedAction::Rebuild{reason : RebuildReason::SourcesChanged});
+        assert_eq!(plan.nodes[1].action, PredictedAction::UpToDate);
+        assert_eq!(plan.nodes[2].action,
+            PredictedAction::Rebuild{reason : RebuildReason::SourcesRebuilt(vec!["stanza1.txt".to_string()])});
+        assert!(plan.nodes[0].sources_ticket.is_some());
+        assert!(plan.nodes[2].sources_ticket.is_none());
+        assert_eq!(plan.nodes[2].sources, vec![
+            PlanEdge{path : "stanza1.txt".to_string(), from_node : Some(0)},
+            PlanEdge{path : "stanza2.txt".to_string(), from_node : Some(1)},
+        ]);
+        assert_eq!(plan.nodes[0].sources, vec![PlanEdge{path : "verse1.txt".to_string(), from_node : None}]);
+
+        let round_trip : BuildPlan = serde_json::from_str(&plan.to_json()).unwrap();
+        assert_eq!(round_trip, plan);
+
+        let mut printer = RecordingPrinter::new();
+        print_plan(&mut printer, &plan, false);
+        assert_eq!(printer.printed, vec![
+            "   Rebuild stanza1.txt (sources changed)",
+            "Up-to-date stanza2.txt",
+            "   Rebuild poem.txt (rebuilt first: stanza1.txt)",
+        ]);
+    }
+
+    /*  Change the verse back after building the blue poem, and the red one is in the cache, so the
+        plan recovers the first stanza and the poem, with their tickets known all the way down.  On
+        a fresh tree with no ruler directory, everything is never built, and nothing gets created. */
+    #[test]
+    fn plan_recover_and_never_built()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", RULES).unwrap();
+
+        let plan = plan_build(&system, BuildParams::for_test()).unwrap();
+        let actions : Vec<PredictedAction> = plan.nodes.iter().map(|node| node.action.clone()).collect();
+        assert_eq!(actions, vec![
+            PredictedAction::Rebuild{reason : RebuildReason::NeverBuilt},
+            PredictedAction::Rebuild{reason : RebuildReason::NeverBuilt},
+            PredictedAction::Rebuild{reason : RebuildReason::SourcesRebuilt(
+                vec!["stanza1.txt".to_string(), "stanza2.txt".to_string()])},
+        ]);
+        assert!(!system.is_dir(".ruler"));
+
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        system.time_passes(1);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are blue.\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+        system.time_passes(1);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+
+        let plan = plan_build(&system, BuildParams::for_test()).unwrap();
+        let actions : Vec<PredictedAction> = plan.nodes.iter().map(|node| node.action.clone()).collect();
+        assert_eq!(actions, vec![
+            PredictedAction::Recover{from : RecoverFrom::Cache},
+            PredictedAction::UpToDate,
+            PredictedAction::Recover{from : RecoverFrom::Cache},
+        ]);
+        assert!(plan.nodes[2].sources_ticket.is_some());
+    }
+
+    /*  Build the poem, then plan as if the first verse had some other ticket.  The first stanza
+        rebuilds because its sources changed, the poem because the first stanza rebuilds first, and the
+        second stanza is up-to-date.  The verse is left alone, nothing runs, and nothing in the ruler
+        directory changes. */
+    #[test]
+    fn plan_what_if_source_ticket_overridden()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", RULES).unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+        system.time_passes(1);
+        let before = snapshot(&system, ".ruler");
+        let mut ticket_overrides = HashMap::new();
+        ticket_overrides.insert("verse1.txt".to_string(), TicketFactory::from_str("Roses are blue.\n").result());
+        let plan = plan_build_what_if(&system, BuildParams::for_test(), &ticket_overrides).unwrap();
+
+        let actions : Vec<PredictedAction> = plan.nodes.iter().map(|node| node.action.clone()).collect();
+        assert_eq!(actions, vec![
+            PredictedAction::Rebuild{reason : RebuildReason::SourcesChanged},
+            PredictedAction::UpToDate,
+            PredictedAction::Rebuild{reason : RebuildReason::SourcesRebuilt(vec!["stanza1.txt".to_string()])},
+        ]);
+        assert_eq!(snapshot(&system, ".ruler"), before);
+        assert_eq!(read_file_to_string(&system, "verse1.txt").unwrap(), "Roses are red.\n");
+        assert_eq!(system.get_command_log().len(), 3);
+
+        let plan = plan_build(&system, BuildParams::for_test()).unwrap();
+        assert!(plan.nodes.iter().all(|node| node.action == PredictedAction::UpToDate));
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::VecDeque;
+use std::panic::
+{
+    self,
+    AssertUnwindSafe,
+};
+use std::sync::
+{
+    Arc,
+    Condvar,
+    Mutex,
+};
+use std::thread::
+{
+    self,
+    JoinHandle,
+};
+
+/*  Above this many leaves and nodes together, build runs them on a WorkerPool instead of giving each
+    its own thread. */
+pub const WORKER_POOL_THRESHOLD : usize = 2048;
+
+/*  The most threads a WorkerPool starts.  Rules mostly spend their time waiting on commands, so this
+    is about how many commands can be going at once, not about how many cores there are. */
+pub const WORKER_POOL_MAX_WORKERS : usize = 64;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/*  A task submitted to the pool: the job (until a worker takes it), how many of its dependencies have
+    yet to finish, and the tasks waiting on it. */
+struct Task
+{
+    job_opt : Option<Job>,
+    remaining : usize,
+    finished : bool,
+    dependents : Vec<usize>,
+}
+
+struct PoolState
+{
+    tasks : Vec<Task>,
+    ready : VecDeque<usize>,
+    unfinished : usize,
+    idle_workers : usize,
+    live_workers : usize,
+    peak_workers : usize,
+    shutting_down : bool,
+    workers : Vec<JoinHandle<()>>,
+}
+
+struct PoolShared
+{
+    state : Mutex<PoolState>,
+    condvar : Condvar,
+    max_workers : usize,
+}
+
+/*  Runs tasks on a bounded set of threads.  Each task names the tasks it depends on, and a worker
+    only picks it up once all of those finished, so no worker ever sits waiting on another task.
+    Workers start as they're needed, up to max_workers. */
+pub struct WorkerPool
+{
+    shared : Arc<PoolShared>,
+}
+
+impl WorkerPool
+{
+    pub fn new(max_workers : usize) -> WorkerPool
+    {
+        WorkerPool
+        {
+            shared : Arc::new(
+                PoolShared
+                {
+                    state : Mutex::new(
+                        PoolState
+                        {
+                            tasks : vec![],
+                            ready : VecDeque::new(),
+                            unfinished : 0,
+                            idle_workers : 0,
+                            live_workers : 0,
+                            peak_workers : 0,
+                            shutting_down : false,
+                            workers : vec![],
+                        }),
+                    condvar : Condvar::new(),
+                    max_workers : max_workers.max(1),
+                }),
+        }
+    }
+
+    /*  Adds a task that runs once every task in dependencies has finished, and returns a handle to
+        its result.  Tasks get numbered from zero in the order they're submitted, and a task can only
+        depend on ones submitted before it. */
+    pub fn submit
+    <
+        T : Send + 'static,
+        F : FnOnce() -> T + Send + 'static,
+    >
+    (
+        &mut self,
+        dependencies : &[usize],
+        f : F
+    )
+    -> TaskHandle<T>
+    {
+        let slot = Arc::new((Mutex::new(None), Condvar::new()));
+        let slot_clone = slot.clone();
+        let job : Job = Box::new(
+            move ||
+            {
+                let result = panic::catch_unwind(AssertUnwindSafe(f));
+                let (lock, condvar) = &*slot_clone;
+                *lock.lock().unwrap() = Some(result);
+                condvar.notify_all();
+            });
+
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            let index = state.tasks.len();
+            let mut remaining = 0;
+            for dependency in dependencies
+            {
+                if *dependency < index && !state.tasks[*dependency].finished
+                {
+                    state.tasks[*dependency].dependents.push(index);
+                    remaining += 1;
+                }
+            }
+
+            state.tasks.push(
+                Task
+                {
+                    job_opt : Some(job),
+                    remaining : remaining,
+                    finished : false,
+                    dependents : vec![],
+                });
+            state.unfinished += 1;
+
+            if remaining == 0
+            {
+                state.ready.push_back(index);
+            }
+
+            start_workers(&self.shared, &mut state);
+        }
+
+        self.shared.condvar.notify_all();
+        TaskHandle::Pooled(slot)
+    }
+
+    /*  The most workers that were ever alive at once. */
+    #[cfg(test)]
+    pub fn get_peak_workers(&self) -> usize
+    {
+        self.shared.state.lock().unwrap().peak_workers
+    }
+}
+
+/*  Lets the workers finish whatever is left, then waits for them to exit. */
+impl Drop for WorkerPool
+{
+    fn drop(&mut self)
+    {
+        self.shared.state.lock().unwrap().shutting_down = true;
+        self.shared.condvar.notify_all();
+
+        /*  A worker can start another on its way out, so keep going until none are left. */
+        loop
+        {
+            let workers = std::mem::replace(&mut self.shared.state.lock().unwrap().workers, vec![]);
+            if workers.is_empty()
+            {
+                break;
+            }
+
+            for worker in workers
+            {
+                match worker.join()
+                {
+                    Ok(()) => {},
+                    Err(_error) => {},
+                }
+            }
+        }
+    }
+}
+
+/*  Starts enough workers for the ready tasks that the idle ones can't take, without going over
+    max_workers.  A worker counts as idle from the moment it's started, so it gets counted before it
+    gets going. */
+fn start_workers(shared : &Arc<PoolShared>, state : &mut PoolState)
+{
+    let wanted = state.ready.len().saturating_sub(state.idle_workers);
+    let count = wanted.min(shared.max_workers - state.live_workers);
+    state.live_workers += count;
+    state.idle_workers += count;
+    state.peak_workers = state.peak_workers.max(state.live_workers);
+    for _ in 0..count
+    {
+        let shared_clone = shared.clone();
+        state.workers.push(thread::spawn(move || work(shared_clone)));
+    }
+}
+
+/*  A worker's loop: take a ready task, run it, mark its dependents one closer to ready.  Counting
+    itself idle before starting anyone else means a chain of tasks stays on one worker. */
+fn work(shared : Arc<PoolShared>)
+{
+    let mut state = shared.state.lock().unwrap();
+    loop
+    {
+        match state.ready.pop_front()
+        {
+            Some(index) =>
+            {
+                let job = state.tasks[index].job_opt.take().unwrap();
+                state.idle_workers -= 1;
+                drop(state);
+                job();
+                state = shared.state.lock().unwrap();
+
+                state.tasks[index].finished = true;
+                state.unfinished -= 1;
+                state.idle_workers += 1;
+                let dependents = std::mem::replace(&mut state.tasks[index].dependents, vec![]);
+                for dependent in dependents
+                {
+                    state.tasks[dependent].remaining -= 1;
+                    if state.tasks[dependent].remaining == 0
+                    {
+                        state.ready.push_back(dependent);
+                    }
+                }
+
+                start_workers(&shared, &mut state);
+                if state.ready.len() > 1 || state.unfinished == 0
+                {
+                    shared.condvar.notify_all();
+                }
+            },
+            None =>
+            {
+                if state.shutting_down && state.unfinished == 0
+                {
+                    state.live_workers -= 1;
+                    state.idle_workers -= 1;
+                    return;
+                }
+
+                state = shared.condvar.wait(state).unwrap();
+            },
+        }
+    }
+}
+
+/*  How build runs its leaves and nodes: each on its own thread, or as tasks on a WorkerPool.  Either
+    way, every one gets a TaskHandle to join. */
+pub enum Executor
+{
+    ThreadPerTask,
+    Pool(WorkerPool),
+}
+
+impl Executor
+{
+    /*  Picks the pool when there are more tasks than WORKER_POOL_THRESHOLD. */
+    pub fn for_task_count(task_count : usize) -> Executor
+    {
+        if task_count > WORKER_POOL_THRESHOLD
+        {
+            Executor::Pool(WorkerPool::new(WORKER_POOL_MAX_WORKERS))
+        }
+        else
+        {
+            Executor::ThreadPerTask
+        }
+    }
+
+    /*  Starts f, or submits it to run once its dependencies are done.  Dependencies only matter to the
+        pool: a thread of its own waits on its sources by itself. */
+    pub fn spawn
+    <
+        T : Send + 'static,
+        F : FnOnce() -> T + Send + 'static,
+    >
+    (
+        &mut self,
+        dependencies : &[usize],
+        f : F
+    )
+    -> TaskHandle<T>
+    {
+        match self
+        {
+            Executor::ThreadPerTask => TaskHandle::Thread(thread::spawn(f)),
+            Executor::Pool(pool) => pool.submit(dependencies, f),
+        }
+    }
+
+    /*  The most threads that were running tasks at once, if this is a pool. */
+    #[cfg(test)]
+    pub fn get_peak_workers_opt(&self) -> Option<usize>
+    {
+        match self
+        {
+            Executor::ThreadPerTask => None,
+            Executor::Pool(pool) => Some(pool.get_peak_workers()),
+        }
+    }
+}
+
+/*  The result of a task, however it was run.  Joining gives what JoinHandle::join would: the task's
+    return value, or the panic if it panicked. */
+pub enum TaskHandle<T>
+{
+    Thread(JoinHandle<T>),
+    Pooled(Arc<(Mutex<Option<thread::Result<T>>>, Condvar)>),
+}
+
+impl<T> TaskHandle<T>
+{
+    pub fn join(self) -> thread::Result<T>
+    {
+        match self
+        {
+            TaskHandle::Thread(handle) => handle.join(),
+            TaskHandle::Pooled(slot) =>
+            {
+                let (lock, condvar) = &*slot;
+                let mut result_opt = lock.lock().unwrap();
+                loop
+                {
+                    match result_opt.take()
+                    {
+                        Some(result) => return result,
+                        None => result_opt = condvar.wait(result_opt).unwrap(),
+                    }
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::pool::WorkerPool;
+    use std::sync::
+    {
+        Arc,
+        Mutex,
+    };
+
+    /*  A long chain runs every task, in order, without ever needing more than one worker at a time
+        beyond the first. */
+    #[test]
+    fn pool_runs_long_chain_on_few_workers()
+    {
+        let mut pool = WorkerPool::new(16);
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut handles = vec![];
+        for i in 0..10000
+        {
+            let log_clone = log.clone();
+            let dependencies = if i == 0 { vec![] } else { vec![i - 1] };
+            handles.push(pool.submit(&dependencies, move || { log_clone.lock().unwrap().push(i); i * 2 }));
+        }
+
+        for (i, handle) in handles.into_iter().enumerate()
+        {
+            assert_eq!(handle.join().unwrap(), i * 2);
+        }
+
+        assert_eq!(*log.lock().unwrap(), (0..10000).collect::<Vec<usize>>());
+        assert!(pool.get_peak_workers() <= 2, "peak workers: {}", pool.get_peak_workers());
+    }
+
+    /*  A task that depends on two others runs after both, and the pool never goes over its cap even
+        with lots of independent tasks. */
+    #[test]
+    fn pool_respects_dependencies_and_cap()
+    {
+        let mut pool = WorkerPool::new(4);
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut handles = vec![];
+        for i in 0..100
+        {
+            let log_clone = log.clone();
+            handles.push(pool.submit(&[], move || log_clone.lock().unwrap().push(i)));
+        }
+
+        let log_clone = log.clone();
+        let last = pool.submit(&[3, 97], move || log_clone.lock().unwrap().len());
+
+        for handle in handles
+        {
+            handle.join().unwrap();
+        }
+
+        assert!(last.join().unwrap() >= 2);
+        assert!(pool.get_peak_workers() <= 4);
+    }
+
+    /*  A panicking task comes back as an Err from join, same as a thread would, and the tasks that
+        depend on it still run. */
+    #[test]
+    fn pool_reports_panic_and_continues()
+    {
+        let mut pool = WorkerPool::new(2);
+        let panicker = pool.submit(&[], || -> usize { panic!("on purpose") });
+        let after = pool.submit(&[0], || 5);
+
+        assert!(panicker.join().is_err());
+        assert_eq!(after.join().unwrap(), 5);
+    }
+}
@@ -1,5 +1,20 @@
 
-use std::io::Write;
+use std::io::
+{
+    IsTerminal,
+    Write,
+};
+use std::sync::mpsc::
+{
+    self,
+    Sender,
+    Receiver,
+};
+use std::time::
+{
+    SystemTime,
+    UNIX_EPOCH,
+};
 use termcolor::
 {
     Color,
@@ -19,18 +34,89 @@ pub trait Printer
 
     fn error(
         &mut self, text: &str);
+
+    /*  Shows that a long, quiet phase (like hashing every source on a cold start) is still going,
+        in place of the last heartbeat.  Gets called over and over until clear_heartbeat. */
+    fn heartbeat(
+        &mut self, text : &str);
+
+    fn clear_heartbeat(
+        &mut self);
+}
+
+/*  The frames of the spinner that goes with a heartbeat. */
+const SPINNER_FRAMES : [char; 4] = ['|', '/', '-', '\\'];
+
+/*  Where the time for a timestamp comes from.  Normally SystemTime::now, but tests put in a fixed time. */
+pub type Clock = fn() -> SystemTime;
+
+/*  The time in ISO 8601 form, in UTC, to the millisecond, like 2023-11-14T22:13:20.000Z.  Times before
+    1970 come out as 1970. */
+pub fn format_timestamp(time : SystemTime) -> String
+{
+    let millis =
+    match time.duration_since(UNIX_EPOCH)
+    {
+        Ok(duration) => duration.as_millis() as i64,
+        Err(_) => 0,
+    };
+
+    let days = millis / 86_400_000;
+    let millis_of_day = millis % 86_400_000;
+
+    /*  Days since 1970-01-01 to a year, month and day in the proleptic Gregorian calendar, counting
+        from 0000-03-01 so that leap days fall at the end of each year. */
+    let shifted = days + 719_468;
+    let era = shifted / 146_097;
+    let day_of_era = shifted - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day,
+        millis_of_day / 3_600_000,
+        millis_of_day / 60_000 % 60,
+        millis_of_day / 1000 % 60,
+        millis_of_day % 1000)
 }
 
 #[derive(Clone)]
 pub struct StandardPrinter
 {
+    spinner_index : usize,
+
+    /*  The length of the heartbeat line showing, so clearing it knows how much to blank out. */
+    heartbeat_length : usize,
+
+    /*  When Some, each line printed starts with the time from this clock. */
+    clock_opt : Option<Clock>,
 }
 
 impl StandardPrinter
 {
     pub fn new() -> StandardPrinter
     {
-        return StandardPrinter{};
+        return StandardPrinter{spinner_index : 0, heartbeat_length : 0, clock_opt : None};
+    }
+
+    /*  A printer that starts each line with the time according to the given clock. */
+    pub fn with_timestamps(clock : Clock) -> StandardPrinter
+    {
+        return StandardPrinter{spinner_index : 0, heartbeat_length : 0, clock_opt : Some(clock)};
+    }
+
+    /*  What goes at the start of each line: the time and a space with timestamps, otherwise nothing. */
+    fn get_line_prefix(&self) -> String
+    {
+        match self.clock_opt
+        {
+            Some(clock) => format!("{} ", format_timestamp(clock())),
+            None => String::new(),
+        }
     }
 }
 
@@ -40,6 +126,11 @@ impl Printer for StandardPrinter
         &mut self, banner_text : &str, banner_color : Color, path : &str)
     {
         let mut stdout = StandardStream::stdout(ColorChoice::Always);
+        match write!(&mut stdout, "{}", self.get_line_prefix())
+        {
+            Ok(_) => {},
+            Err(_error) => return,
+        }
         match stdout.set_color(ColorSpec::new().set_fg(Some(banner_color)))
         {
             Ok(_) => {},
@@ -78,22 +169,188 @@ impl Printer for StandardPrinter
     fn print(
         &mut self, text : &str)
     {
-        println!("{}", text);
+        println!("{}{}", self.get_line_prefix(), text);
     }
 
     fn error(
         &mut self, text : &str)
     {
-        println!("{}", text);
+        println!("{}{}", self.get_line_prefix(), text);
+    }
+
+    /*  The heartbeat goes to stderr, and only when that's a terminal, since it's made to be overwritten
+        and would only clutter a log. */
+    fn heartbeat(
+        &mut self, text : &str)
+    {
+        let mut stderr = std::io::stderr();
+        if ! stderr.is_terminal()
+        {
+            return;
+        }
+
+        let line = format!("{} {}", SPINNER_FRAMES[self.spinner_index % SPINNER_FRAMES.len()], text);
+        self.spinner_index += 1;
+        let padding = " ".repeat(self.heartbeat_length.saturating_sub(line.chars().count()));
+        self.heartbeat_length = line.chars().count();
+        match write!(&mut stderr, "\r{}{}", line, padding)
+        {
+            Ok(_) => {},
+            Err(_error) => {},
+        }
+        match stderr.flush()
+        {
+            Ok(_) => {},
+            Err(_error) => {},
+        }
+    }
+
+    fn clear_heartbeat(
+        &mut self)
+    {
+        if self.heartbeat_length == 0
+        {
+            return;
+        }
+
+        let mut stderr = std::io::stderr();
+        match write!(&mut stderr, "\r{}\r", " ".repeat(self.heartbeat_length))
+        {
+            Ok(_) => {},
+            Err(_error) => {},
+        }
+        match stderr.flush()
+        {
+            Ok(_) => {},
+            Err(_error) => {},
+        }
+        self.heartbeat_length = 0;
     }
 }
 
+/*  Keeps everything printed, banners included, so tests can check what a user would have seen. */
 #[cfg(test)]
-pub struct EmptyPrinter
+pub struct RecordingPrinter
 {
+    pub printed : Vec<String>,
+    pub errors : Vec<String>,
+    pub heartbeats : Vec<String>,
+    pub heartbeat_showing : bool,
 }
 
 #[cfg(test)]
+impl RecordingPrinter
+{
+    pub fn new() -> RecordingPrinter
+    {
+        return RecordingPrinter{printed : vec![], errors : vec![], heartbeats : vec![], heartbeat_showing : false}
+    }
+}
+
+#[cfg(test)]
+impl Printer for RecordingPrinter
+{
+    fn print_single_banner_line(
+        &mut self, banner_text : &str, _banner_color : Color, path : &str)
+    {
+        self.printed.push(format!("{} {}", banner_text, path));
+    }
+
+    fn print(
+        &mut self, text : &str)
+    {
+        self.printed.push(text.to_string());
+    }
+
+    fn error(
+        &mut self, text: &str)
+    {
+        self.errors.push(text.to_string());
+    }
+
+    fn heartbeat(
+        &mut self, text : &str)
+    {
+        self.heartbeats.push(text.to_string());
+        self.heartbeat_showing = true;
+    }
+
+    fn clear_heartbeat(
+        &mut self)
+    {
+        self.heartbeat_showing = false;
+    }
+}
+
+/*  Something a thread without the printer wants printed. */
+pub enum PrinterMessage
+{
+    Banner(String, Color, String),
+}
+
+/*  How threads other than the one with the printer print, so that only one thread ever writes to the
+    terminal and no line gets split by another.  Messages wait on a channel, in the order they were
+    sent, until the thread with the printer calls print_messages.  Clones send to the same channel.  A
+    handle from discard has no channel, and drops what it's given. */
+#[derive(Clone)]
+pub struct PrinterHandle
+{
+    sender_opt : Option<Sender<PrinterMessage>>,
+}
+
+impl PrinterHandle
+{
+    /*  A handle, and the receiver to give print_messages. */
+    pub fn new() -> (PrinterHandle, Receiver<PrinterMessage>)
+    {
+        let (sender, receiver) = mpsc::channel();
+        (PrinterHandle{sender_opt : Some(sender)}, receiver)
+    }
+
+    pub fn discard() -> PrinterHandle
+    {
+        PrinterHandle{sender_opt : None}
+    }
+
+    pub fn print_single_banner_line(&self, banner_text : &str, banner_color : Color, path : &str)
+    {
+        match &self.sender_opt
+        {
+            Some(sender) =>
+            {
+                match sender.send(PrinterMessage::Banner(banner_text.to_string(), banner_color, path.to_string()))
+                {
+                    Ok(_) => {},
+                    Err(_error) => {},
+                }
+            },
+            None => {},
+        }
+    }
+}
+
+/*  Prints every message waiting on the receiver, in the order they were sent. */
+pub fn print_messages<PrinterType : Printer>
+(
+    printer : &mut PrinterType,
+    receiver : &Receiver<PrinterMessage>
+)
+{
+    for message in receiver.try_iter()
+    {
+        match message
+        {
+            PrinterMessage::Banner(banner_text, banner_color, path) =>
+                printer.print_single_banner_line(&banner_text, banner_color, &path),
+        }
+    }
+}
+
+/*  Prints nothing, for output nobody needs to see. */
+pub struct EmptyPrinter
+{
+}
+
 impl EmptyPrinter
 {
     pub fn new() -> EmptyPrinter
@@ -102,7 +359,6 @@ impl EmptyPrinter
     }
 }
 
-#[cfg(test)]
 impl Printer for EmptyPrinter
 {
     fn print_single_banner_line(
@@ -119,4 +375,108 @@ impl Printer for EmptyPrinter
         &mut self, _text: &str)
     {
     }
+
+    fn heartbeat(
+        &mut self, _text : &str)
+    {
+    }
+
+    fn clear_heartbeat(
+        &mut self)
+    {
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::printer::
+    {
+        format_timestamp,
+        print_messages,
+        PrinterHandle,
+        RecordingPrinter,
+        StandardPrinter,
+    };
+    use std::thread;
+    use std::time::
+    {
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    };
+    use termcolor::Color;
+
+    fn fixed_clock() -> SystemTime
+    {
+        UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)
+    }
+
+    /*  With timestamps, each line starts with the clock's time in ISO 8601, UTC, then a space.  Without,
+        nothing. */
+    #[test]
+    fn standard_printer_timestamp_prefix()
+    {
+        assert_eq!(StandardPrinter::with_timestamps(fixed_clock).get_line_prefix(), "2023-11-14T22:13:20.123Z ");
+        assert_eq!(StandardPrinter::new().get_line_prefix(), "");
+    }
+
+    /*  Dates come out right across month ends, leap days and the turn of a century. */
+    #[test]
+    fn format_timestamp_dates()
+    {
+        let at = |seconds : u64| format_timestamp(UNIX_EPOCH + Duration::from_secs(seconds));
+        assert_eq!(at(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(951_782_400), "2000-02-29T00:00:00.000Z");
+        assert_eq!(at(951_868_799), "2000-02-29T23:59:59.000Z");
+        assert_eq!(at(1_709_251_199), "2024-02-29T23:59:59.000Z");
+        assert_eq!(at(4_102_444_800), "2100-01-01T00:00:00.000Z");
+    }
+
+    /*  Two threads warn at once, a hundred times each, through clones of one handle, while the thread
+        with the printer keeps printing what's arrived.  Every line comes out whole, and each thread's
+        lines come out in the order it sent them. */
+    #[test]
+    fn printer_handle_keeps_lines_whole()
+    {
+        let (handle, receiver) = PrinterHandle::new();
+        let mut printer = RecordingPrinter::new();
+
+        let threads : Vec<thread::JoinHandle<()>> = ["first", "second"].iter().map(
+            |name|
+            {
+                let handle_clone = handle.clone();
+                let name = name.to_string();
+                thread::spawn(move ||
+                {
+                    for i in 0..100
+                    {
+                        handle_clone.print_single_banner_line("   Warning", Color::Yellow,
+                            &format!("failed to set executable: {}-{}.txt", name, i));
+                    }
+                })
+            }).collect();
+
+        while threads.iter().any(|thread| ! thread.is_finished())
+        {
+            print_messages(&mut printer, &receiver);
+        }
+        for thread in threads
+        {
+            thread.join().unwrap();
+        }
+        print_messages(&mut printer, &receiver);
+
+        assert_eq!(printer.printed.len(), 200);
+        for name in ["first", "second"]
+        {
+            let lines : Vec<&String> = printer.printed.iter().filter(
+                |line| line.starts_with(&format!("   Warning failed to set executable: {}-", name))).collect();
+            let expected : Vec<String> = (0..100).map(
+                |i| format!("   Warning failed to set executable: {}-{}.txt", name, i)).collect();
+            assert_eq!(lines, expected.iter().collect::<Vec<&String>>());
+        }
+
+        PrinterHandle::discard().print_single_banner_line("   Warning", Color::Yellow, "nowhere");
+    }
 }
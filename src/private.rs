@@ -0,0 +1,333 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io::
+{
+    Read,
+    Write,
+};
+use serde::
+{
+    Serialize,
+    Deserialize
+};
+use crate::history::
+{
+    History,
+    HistoryError,
+};
+use crate::ticket::Ticket;
+use crate::system::System;
+
+/*  The rule-tickets of every rule marked private that has ever been built in this ruler directory.
+    Build adds to it, so the server knows what not to share even for rules outside the current
+    rules file. */
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct PrivateRules
+{
+    rule_tickets : HashSet<Ticket>,
+}
+
+impl PrivateRules
+{
+    pub fn new() -> PrivateRules
+    {
+        PrivateRules
+        {
+            rule_tickets : HashSet::new(),
+        }
+    }
+
+    pub fn insert(&mut self, rule_ticket : Ticket)
+    {
+        self.rule_tickets.insert(rule_ticket);
+    }
+
+    pub fn contains(&self, rule_ticket : &Ticket) -> bool
+    {
+        self.rule_tickets.contains(rule_ticket)
+    }
+}
+
+#[derive(Debug)]
+pub enum PrivateRulesError
+{
+    CannotReadPrivateRulesFile(String),
+    CannotInterpretPrivateRulesFile(String),
+    CannotWritePrivateRulesFile(String),
+    HistoryError(HistoryError),
+}
+
+impl fmt::Display for PrivateRulesError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            PrivateRulesError::CannotReadPrivateRulesFile(path) =>
+                write!(formatter, "Cannot read private rules file: {}", path),
+
+            PrivateRulesError::CannotInterpretPrivateRulesFile(path) =>
+                write!(formatter, "Cannot interpret private rules file: {}", path),
+
+            PrivateRulesError::CannotWritePrivateRulesFile(path) =>
+                write!(formatter, "Cannot write private rules file: {}", path),
+
+            PrivateRulesError::HistoryError(error) =>
+                write!(formatter, "{}", error),
+        }
+    }
+}
+
+/*  The path of the private rules file inside the ruler directory. */
+pub fn get_private_rules_path(directory_path : &str) -> String
+{
+    format!("{}/private_rules", directory_path)
+}
+
+/*  Reads the private rules file.  If there isn't one, no rule has been private, so returns an empty set. */
+pub fn read_private_rules<SystemType : System>
+(
+    system : &SystemType,
+    path : &str
+)
+-> Result<PrivateRules, PrivateRulesError>
+{
+    if ! system.is_file(path)
+    {
+        return Ok(PrivateRules::new());
+    }
+
+    let mut file =
+    match system.open(path)
+    {
+        Ok(file) => file,
+        Err(_) => return Err(PrivateRulesError::CannotReadPrivateRulesFile(path.to_string())),
+    };
+
+    let mut content = Vec::new();
+    match file.read_to_end(&mut content)
+    {
+        Ok(_size) => {},
+        Err(_) => return Err(PrivateRulesError::CannotReadPrivateRulesFile(path.to_string())),
+    }
+
+    match bincode::deserialize(&content)
+    {
+        Ok(private_rules) => Ok(private_rules),
+        Err(_) => Err(PrivateRulesError::CannotInterpretPrivateRulesFile(path.to_string())),
+    }
+}
+
+pub fn write_private_rules<SystemType : System>
+(
+    system : &mut SystemType,
+    path : &str,
+    private_rules : &PrivateRules
+)
+-> Result<(), PrivateRulesError>
+{
+    let content =
+    match bincode::serialize(private_rules)
+    {
+        Ok(content) => content,
+        Err(_) => return Err(PrivateRulesError::CannotWritePrivateRulesFile(path.to_string())),
+    };
+
+    let mut file =
+    match system.create_file(path)
+    {
+        Ok(file) => file,
+        Err(_) => return Err(PrivateRulesError::CannotWritePrivateRulesFile(path.to_string())),
+    };
+
+    match file.write_all(&content)
+    {
+        Ok(_) => Ok(()),
+        Err(_) => Err(PrivateRulesError::CannotWritePrivateRulesFile(path.to_string())),
+    }
+}
+
+/*  Adds the given rule-tickets to the private rules file, creating it if need be.  Doesn't write
+    anything when there's nothing to add, so directories without private rules never get the file. */
+pub fn record_private_rules<SystemType : System>
+(
+    system : &mut SystemType,
+    directory_path : &str,
+    rule_tickets : Vec<Ticket>
+)
+-> Result<(), PrivateRulesError>
+{
+    let path = get_private_rules_path(directory_path);
+    let mut private_rules = read_private_rules(system, &path)?;
+    let mut changed = false;
+    for rule_ticket in rule_tickets
+    {
+        if ! private_rules.contains(&rule_ticket)
+        {
+            private_rules.insert(rule_ticket);
+            changed = true;
+        }
+    }
+
+    if changed
+    {
+        write_private_rules(system, &path, &private_rules)
+    }
+    else
+    {
+        Ok(())
+    }
+}
+
+/*  What the server may hand out.  A private rule's history is never shared, and neither is any file
+    whose ticket appears as a target in a private rule's history, even if some public rule happens to
+    produce the same content. */
+pub struct SharePolicy
+{
+    private_rules : PrivateRules,
+    private_files : HashSet<Ticket>,
+}
+
+impl SharePolicy
+{
+    pub fn allows_rule(&self, rule_ticket : &Ticket) -> bool
+    {
+        ! self.private_rules.contains(rule_ticket)
+    }
+
+    pub fn allows_file(&self, file_ticket : &Ticket) -> bool
+    {
+        ! self.private_files.contains(file_ticket)
+    }
+}
+
+/*  Reads the private rules file and each private rule's history to work out what not to share. */
+pub fn load_share_policy<SystemType : System>
+(
+    system : &SystemType,
+    directory_path : &str,
+    history : &History<SystemType>
+)
+-> Result<SharePolicy, PrivateRulesError>
+{
+    let private_rules = read_private_rules(system, &get_private_rules_path(directory_path))?;
+    let mut private_files = HashSet::new();
+    for rule_ticket in private_rules.rule_tickets.iter()
+    {
+        let rule_history =
+        match history.read_rule_history(rule_ticket)
+        {
+            Ok(rule_history) => rule_history,
+            Err(error) => return Err(PrivateRulesError::HistoryError(error)),
+        };
+
+        for file_state_vec in rule_history.get_all_file_state_vecs()
+        {
+            private_files.extend(file_state_vec.get_tickets());
+        }
+    }
+
+    Ok(SharePolicy
+    {
+        private_rules : private_rules,
+        private_files : private_files,
+    })
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::private::
+    {
+        PrivateRules,
+        read_private_rules,
+        record_private_rules,
+        get_private_rules_path,
+        load_share_policy,
+    };
+    use crate::build::
+    {
+        build,
+        get_nodes,
+        BuildParams,
+    };
+    use crate::directory;
+    use crate::printer::EmptyPrinter;
+    use crate::ticket::TicketFactory;
+    use crate::system::
+    {
+        System,
+        fake::FakeSystem,
+    };
+    use crate::system::util::write_str_to_file;
+
+    /*  Recording accumulates across calls, and recording nothing writes no file. */
+    #[test]
+    fn record_private_rules_accumulates()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir(".ruler").unwrap();
+
+        record_private_rules(&mut system, ".ruler", vec![]).unwrap();
+        assert!(!system.is_file(&get_private_rules_path(".ruler")));
+
+        let a = TicketFactory::from_str("a").result();
+        let b = TicketFactory::from_str("b").result();
+        record_private_rules(&mut system, ".ruler", vec![a.clone()]).unwrap();
+        record_private_rules(&mut system, ".ruler", vec![b.clone()]).unwrap();
+
+        let mut expected = PrivateRules::new();
+        expected.insert(a);
+        expected.insert(b);
+        assert_eq!(read_private_rules(&system, &get_private_rules_path(".ruler")).unwrap(), expected);
+    }
+
+    /*  Build a private rule and a public one, then check what the server would share: neither the
+        private rule's history nor its target's blob, but everything of the public rule. */
+    #[test]
+    fn share_policy_omits_private_rule()
+    {
+        let rules = "\
+@private
+licensed.bin
+:
+sdk.txt
+:
+mycat
+sdk.txt
+licensed.bin
+:
+
+poem.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+poem.txt
+:
+";
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "sdk.txt", "Licensed bits\n").unwrap();
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "build.rules", rules).unwrap();
+
+        build(
+            system.clone(),
+            &mut EmptyPrinter::new(),
+            None,
+            BuildParams::for_test()).unwrap();
+
+        let node_pack = get_nodes(&system, vec!["build.rules".to_string()], None, false, false, None).unwrap();
+        let private_node = node_pack.nodes.iter().find(|node| node.private).unwrap();
+        let public_node = node_pack.nodes.iter().find(|node| !node.private).unwrap();
+
+        let elements = directory::open_readonly(&system, ".ruler", None, None).unwrap();
+        let share_policy = load_share_policy(&system, ".ruler", &elements.history).unwrap();
+
+        assert!(!share_policy.allows_rule(&private_node.rule_ticket));
+        assert!(share_policy.allows_rule(&public_node.rule_ticket));
+        assert!(!share_policy.allows_file(&TicketFactory::from_str("Licensed bits\n").result()));
+        assert!(share_policy.allows_file(&TicketFactory::from_str("Roses are red.\n").result()));
+    }
+}
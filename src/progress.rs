@@ -0,0 +1,254 @@
+use serde::Serialize;
+use std::io::Write;
+use std::sync::mpsc::
+{
+    self,
+    Receiver,
+    Sender,
+};
+use std::thread::
+{
+    self,
+    JoinHandle,
+};
+use termcolor::Color;
+use crate::blob::FileResolution;
+use crate::build::BuildSummary;
+use crate::printer::PrinterHandle;
+use crate::system::System;
+use crate::work::
+{
+    WorkError,
+    WorkOption,
+};
+
+/*  What a build says about itself as it goes, for a program to read rather than a person: an editor
+    showing progress, say.  Each event becomes one line of JSON, with its kind in the "event" field. */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent
+{
+    BuildStarted
+    {
+        total_nodes : usize,
+    },
+
+    /*  A rule's sources are all ready, and it's about to get its targets up-to-date. */
+    NodeStarted
+    {
+        targets : Vec<String>,
+    },
+
+    /*  The status is how the targets got up-to-date (see get_status), the duration how many seconds
+        that took. */
+    NodeFinished
+    {
+        targets : Vec<String>,
+        status : String,
+        duration : f64,
+    },
+
+    /*  The error code is the command's exit code, when the rule failed by its command exiting with one. */
+    NodeFailed
+    {
+        targets : Vec<String>,
+        error_code : Option<i32>,
+        message : String,
+    },
+
+    /*  A line of what a rule's command printed.  Only "ruler daemon" sends these: a progress file leaves
+        output to the terminal. */
+    CommandOutput
+    {
+        targets : Vec<String>,
+        line : String,
+    },
+
+    BuildFinished
+    {
+        success : bool,
+        failed : usize,
+        summary : BuildSummary,
+    },
+}
+
+/*  How a rule's targets got up-to-date, in a word: "built", "recovered", "downloaded", "pinned", or
+    "up_to_date" when there was nothing to do (an aggregation included). */
+pub fn get_status(work_option : &WorkOption) -> &'static str
+{
+    match work_option
+    {
+        WorkOption::SourceOnly => "source",
+        WorkOption::CommandExecuted(_output) => "built",
+        WorkOption::Pinned => "pinned",
+        WorkOption::Aggregated => "up_to_date",
+        WorkOption::Resolutions(resolutions) =>
+        {
+            let mut status = "up_to_date";
+            for resolution in resolutions.iter()
+            {
+                match resolution
+                {
+                    FileResolution::Downloaded => return "downloaded",
+                    FileResolution::Recovered(_) => status = "recovered",
+                    FileResolution::AlreadyCorrect | FileResolution::NeedsRebuild => {},
+                }
+            }
+            status
+        },
+    }
+}
+
+/*  The exit code of the command, if that's why the rule failed. */
+pub fn get_error_code(error : &WorkError) -> Option<i32>
+{
+    match error
+    {
+        WorkError::CommandExecutedButErrored(output) => output.code,
+        _ => None,
+    }
+}
+
+/*  How rules send progress events, from whatever thread they're on.  Clones send to the same stream.
+    One with no channel drops what it's given, for builds nobody's watching. */
+#[derive(Clone)]
+pub struct ProgressSender
+{
+    sender_opt : Option<Sender<ProgressEvent>>,
+}
+
+impl ProgressSender
+{
+    pub fn send(&self, event : ProgressEvent)
+    {
+        match &self.sender_opt
+        {
+            Some(sender) =>
+            {
+                match sender.send(event)
+                {
+                    Ok(_) => {},
+                    Err(_error) => {},
+                }
+            },
+            None => {},
+        }
+    }
+}
+
+/*  Progress events on their way to a file (or a named pipe, or a file descriptor by way of
+    /dev/fd/N).  A thread of its own writes each one as it comes, a line at a time, flushing each, so
+    whatever's reading sees it straight away. */
+pub struct ProgressStream
+{
+    sender : ProgressSender,
+    handle_opt : Option<JoinHandle<()>>,
+}
+
+impl ProgressStream
+{
+    /*  Opens the path and starts writing to it.  Without a path, events go nowhere.  Progress is never
+        worth failing a build over, so if the path won't open or a write fails, that gets said once,
+        through the printer handle, and the events after go nowhere. */
+    pub fn start<SystemType : System + 'static>
+    (
+        system : &mut SystemType,
+        path_opt : &Option<String>,
+        printer_handle : PrinterHandle
+    )
+    -> ProgressStream
+    {
+        let nowhere = ProgressStream
+        {
+            sender : ProgressSender{sender_opt : None},
+            handle_opt : None,
+        };
+
+        let path =
+        match path_opt
+        {
+            Some(path) => path.clone(),
+            None => return nowhere,
+        };
+
+        let file =
+        match system.create_file(&path)
+        {
+            Ok(file) => file,
+            Err(error) =>
+            {
+                warn_progress_stopped(&printer_handle, &path, &error.to_string());
+                return nowhere;
+            },
+        };
+
+        let (sender, receiver) : (Sender<ProgressEvent>, Receiver<ProgressEvent>) = mpsc::channel();
+        ProgressStream
+        {
+            sender : ProgressSender{sender_opt : Some(sender)},
+            handle_opt : Some(thread::spawn(move || write_events(file, receiver, &path, &printer_handle))),
+        }
+    }
+
+    pub fn get_sender(&self) -> ProgressSender
+    {
+        self.sender.clone()
+    }
+
+    /*  Sends the last event, and waits for everything sent to get written.  Every clone of the sender
+        has to be gone by then, or this waits on them. */
+    pub fn finish(self, event : ProgressEvent)
+    {
+        let ProgressStream{sender, handle_opt} = self;
+        sender.send(event);
+        drop(sender);
+        match handle_opt
+        {
+            Some(handle) => match handle.join()
+            {
+                Ok(()) => {},
+                Err(_error) => {},
+            },
+            None => {},
+        }
+    }
+}
+
+fn warn_progress_stopped(printer_handle : &PrinterHandle, path : &str, message : &str)
+{
+    printer_handle.print_single_banner_line("   Warning", Color::Yellow,
+        &format!("could not write progress to {}: {} (no more progress events this build)", path, message));
+}
+
+fn write_events<FileType : Write>
+(
+    mut file : FileType,
+    receiver : Receiver<ProgressEvent>,
+    path : &str,
+    printer_handle : &PrinterHandle
+)
+{
+    for event in receiver.iter()
+    {
+        let line =
+        match serde_json::to_string(&event)
+        {
+            Ok(line) => line,
+            Err(error) =>
+            {
+                warn_progress_stopped(printer_handle, path, &error.to_string());
+                return;
+            },
+        };
+
+        match writeln!(file, "{}", line).and_then(|()| file.flush())
+        {
+            Ok(()) => {},
+            Err(error) =>
+            {
+                warn_progress_stopped(printer_handle, path, &error.to_string());
+                return;
+            },
+        }
+    }
+}
@@ -1,18 +1,230 @@
 use std::fmt;
+use std::collections::HashSet;
 
-use crate::ticket::Ticket;
+use crate::ticket::
+{
+    Ticket,
+    TicketFactory,
+};
 use crate::bundle::
 {
     self,
     PathBundle
 };
+use serde::
+{
+    Serialize,
+    Deserialize,
+};
+
+/*  Where a rule came from: the path of the rules file and the line on which the rule starts.
+    Displays as path:line so error messages can point right at the responsible rule. */
+#[derive(Debug, PartialOrd, Ord, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Provenance
+{
+    pub path : String,
+    pub line : usize,
+}
+
+impl Provenance
+{
+    pub fn new(path : &str, line : usize) -> Provenance
+    {
+        Provenance
+        {
+            path : path.to_string(),
+            line : line,
+        }
+    }
+}
+
+impl fmt::Display for Provenance
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(formatter, "{}:{}", self.path, self.line)
+    }
+}
+
+#[derive(Debug, PartialOrd, Ord, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub enum SizeComparison
+{
+    AtLeast,
+    AtMost,
+}
+
+/*  A bound on the size of one of a rule's targets, from a line like '@expect libfoo.a size >= 1024'
+    before the rule.  A cheap sanity check, so a command that writes a target far too small (or too
+    big) fails its rule, instead of the rules downstream failing in some baffling way. */
+#[derive(Debug, PartialOrd, Ord, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct OutputConstraint
+{
+    pub target : String,
+    pub comparison : SizeComparison,
+    pub size : u64,
+}
+
+impl OutputConstraint
+{
+    pub fn is_met_by(&self, size : u64) -> bool
+    {
+        match self.comparison
+        {
+            SizeComparison::AtLeast => size >= self.size,
+            SizeComparison::AtMost => size <= self.size,
+        }
+    }
+}
+
+impl fmt::Display for OutputConstraint
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self.comparison
+        {
+            SizeComparison::AtLeast => write!(formatter, "{} size >= {}", self.target, self.size),
+            SizeComparison::AtMost => write!(formatter, "{} size <= {}", self.target, self.size),
+        }
+    }
+}
+
+/*  The constraint on a line '@expect TARGET size >= N' (or <=), given the words after "@expect".  None
+    if they don't say one. */
+fn parse_output_constraint(words : Vec<&str>) -> Option<OutputConstraint>
+{
+    match words.as_slice()
+    {
+        [target, "size", comparison, size] =>
+        {
+            let comparison = match *comparison
+            {
+                ">=" => SizeComparison::AtLeast,
+                "<=" => SizeComparison::AtMost,
+                _ => return None,
+            };
+
+            match size.parse::<u64>()
+            {
+                Ok(size) => Some(
+                    OutputConstraint
+                    {
+                        target : target.to_string(),
+                        comparison : comparison,
+                        size : size,
+                    }),
+                Err(_) => None,
+            }
+        },
+        _ => None,
+    }
+}
 
 #[derive(Debug, PartialOrd, Ord, Eq, PartialEq, Clone)]
 pub struct Rule
 {
     pub targets : Vec<String>,
+
+    /*  Bounds on the sizes of the targets, checked once the command has run.  Part of the ticket. */
+    pub output_constraints : Vec<OutputConstraint>,
     pub sources : Vec<String>,
     pub command : Vec<String>,
+    pub provenance : Provenance,
+
+    /*  A private rule's targets and history never go to or come from download urls, and the server
+        doesn't share them. */
+    pub private : bool,
+
+    /*  A precious rule's targets stay where they are while the rule rebuilds: the old ones get copied
+        into the cache rather than moved, so a failed command leaves them untouched.  Not part of the
+        ticket, since it's about how files get handled, not what the rule means. */
+    pub precious : bool,
+
+    /*  A no-cache rule always rebuilds, and its targets never go into or come out of the cache or the
+        history.  For rules whose output changes every run anyway, like one stamping in the time, where
+        remembering it only leads to contradictions.  Not part of the ticket, since nothing about the
+        rule gets remembered. */
+    pub no_cache : bool,
+
+    /*  An install rule copies built things somewhere they get used from, like a staging directory,
+        and what it makes may legitimately differ from machine to machine, say by embedding a local
+        path.  It's private too, and when its command makes something other than what the history
+        remembers for the same sources, that replaces the history's entry instead of being a
+        contradiction.  Part of the ticket, so an install rule doesn't share a history with the same
+        rule unmarked. */
+    pub install : bool,
+
+    /*  A serial rule's command never runs alongside any other command, for tools that can't share the
+        machine.  Not part of the ticket: it's about scheduling, not what the rule means. */
+    pub serial : bool,
+
+    /*  At most one rule of a group runs its command at a time, for rules that contend for something
+        like a GPU or a license server.  None means no group.  Not part of the ticket, like serial. */
+    pub group_opt : Option<String>,
+
+    /*  Environment variables the rule's command may see under --hermetic-env, on top of PATH and the
+        global allowlist.  Not part of the ticket: it's about reproducing the build, not about what
+        the rule means. */
+    pub env_allow : Vec<String>,
+
+    /*  When the rule's command counts as having succeeded.  Part of the ticket, since it changes what
+        the rule means. */
+    pub success_criteria : SuccessCriteria,
+}
+
+/*  When a rule's command counts as having succeeded: every command exits with one of the exit codes,
+    just 0 when there are none, and no line of any command's output contains any of the failure
+    patterns.  For tools that exit 1 on success with warnings, or that report failure only in what
+    they print. */
+#[derive(Debug, PartialOrd, Ord, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SuccessCriteria
+{
+    pub exit_codes : Vec<i32>,
+    pub failure_patterns : Vec<String>,
+}
+
+impl SuccessCriteria
+{
+    pub fn new() -> SuccessCriteria
+    {
+        SuccessCriteria
+        {
+            exit_codes : vec![],
+            failure_patterns : vec![],
+        }
+    }
+
+    /*  True when the criteria are the usual ones: exit code 0, whatever gets printed. */
+    pub fn is_default(&self) -> bool
+    {
+        self.exit_codes.len() == 0 && self.failure_patterns.len() == 0
+    }
+
+    /*  A command killed by a signal has no exit code, and never succeeds. */
+    pub fn accepts_exit_code(&self, code_opt : Option<i32>) -> bool
+    {
+        match code_opt
+        {
+            Some(code) if self.exit_codes.len() == 0 => code == 0,
+            Some(code) => self.exit_codes.contains(&code),
+            None => false,
+        }
+    }
+
+    /*  The first line of the text containing a failure pattern, and the pattern it contains. */
+    pub fn find_failure_pattern(&self, text : &str) -> Option<(String, String)>
+    {
+        for line in text.lines()
+        {
+            for pattern in self.failure_patterns.iter()
+            {
+                if line.contains(pattern.as_str())
+                {
+                    return Some((pattern.clone(), line.to_string()));
+                }
+            }
+        }
+        None
+    }
 }
 
 fn is_sorted(data: &Vec<String>) -> bool
@@ -23,24 +235,48 @@ fn is_sorted(data: &Vec<String>) -> bool
 /*  When a rule is first parsed, it goes into this struct, the targets,
     sources and command are simply parsed into vecs.  This is before the
     topological-sort step which puts the data into a list of Nodes and
-    creates Nodes for sources that are not listed as targest of rules. */
+    creates Nodes for sources that are not listed as targest of rules.
+
+    The provenance records where the rule was written.  It is not part of the ticket,
+    so moving a rule around in the rules file does not invalidate its history. */
 impl Rule
 {
+    #[cfg(test)]
     pub fn new(
         targets : Vec<String>,
         sources : Vec<String>,
         command : Vec<String>) -> Rule
+    {
+        Rule::with_provenance(targets, sources, command, Provenance::new("", 0))
+    }
+
+    pub fn with_provenance(
+        targets : Vec<String>,
+        sources : Vec<String>,
+        command : Vec<String>,
+        provenance : Provenance) -> Rule
     {
         Rule
         {
             targets: targets,
+            output_constraints: vec![],
             sources: sources,
-            command: command
+            command: command,
+            provenance: provenance,
+            private: false,
+            precious: false,
+            no_cache: false,
+            install: false,
+            serial: false,
+            group_opt: None,
+            env_allow: vec![],
+            success_criteria: SuccessCriteria::new(),
         }
     }
 
     pub fn get_ticket(self: &Self) -> Ticket
     {
+        let ticket =
         if is_sorted(&self.targets) && is_sorted(&self.sources)
         {
             Ticket::from_strings(&self.targets, &self.sources, &self.command)
@@ -52,6 +288,73 @@ impl Rule
             t.sort();
             s.sort();
             Ticket::from_strings(&t, &s, &self.command)
+        };
+
+        /*  A rule that expects something of its targets' sizes isn't the same rule without, since
+            without, a target failing the expectation would count as built.  Rules expecting nothing
+            keep the tickets they always had. */
+        let ticket =
+        if self.output_constraints.len() > 0
+        {
+            let mut factory = TicketFactory::new();
+            factory.input_ticket(ticket);
+            for constraint in self.output_constraints.iter()
+            {
+                factory.input_str(&format!("@expect {}", constraint));
+            }
+            factory.result()
+        }
+        else
+        {
+            ticket
+        };
+
+        /*  Marking a rule private folds the marker into the ticket, so the private rule doesn't share
+            a history with the same rule unmarked.  Unmarked rules keep the tickets they always had. */
+        let ticket =
+        if self.private
+        {
+            let mut factory = TicketFactory::new();
+            factory.input_ticket(ticket);
+            factory.input_str("@private");
+            factory.result()
+        }
+        else
+        {
+            ticket
+        };
+
+        let ticket =
+        if self.install
+        {
+            let mut factory = TicketFactory::new();
+            factory.input_ticket(ticket);
+            factory.input_str("@install");
+            factory.result()
+        }
+        else
+        {
+            ticket
+        };
+
+        /*  Success criteria fold in the same way, so rules with the usual criteria keep their tickets. */
+        if self.success_criteria.is_default()
+        {
+            ticket
+        }
+        else
+        {
+            let mut factory = TicketFactory::new();
+            factory.input_ticket(ticket);
+            for code in self.success_criteria.exit_codes.iter()
+            {
+                factory.input_str(&format!("@success-exit-codes {}\n", code));
+            }
+            for pattern in self.success_criteria.failure_patterns.iter()
+            {
+                factory.input_str(&format!("@failure-pattern {}\n", pattern));
+            }
+            factory.result()
         }
     }
 }
@@ -86,7 +389,13 @@ pub enum ParseError
     UnexpectedEndOfFileMidTargets(String, usize),
     UnexpectedEndOfFileMidSources(String, usize),
     UnexpectedEndOfFileMidCommand(String, usize),
-    BundleError(String, bundle::ParseError),
+    BundleError(String, usize, bundle::ParseError),
+    UnknownDirective(String, usize, String),
+    MalformedDirective(String, usize, String),
+    ExternalTarget(String, usize, String),
+    ContinuationAtEndOfFile(String, usize),
+    RulerVersionTooOld(String, usize, String, String),
+    InvisibleCharacters(String, usize, String),
 }
 
 impl fmt::Display for ParseError
@@ -110,8 +419,28 @@ impl fmt::Display for ParseError
             ParseError::UnexpectedEndOfFileMidCommand(filename, line_number) =>
                 write!(formatter, "Unexpected end of file mid-command line {}:{}", filename, line_number),
 
-            ParseError::BundleError(filename, bundle_error) =>
-                write!(formatter, "Bundle parse error {}:{}", filename, bundle_error),
+            ParseError::BundleError(filename, line_number, bundle_error) =>
+                write!(formatter, "Bundle parse error {}:{}: {}", filename, line_number, bundle_error),
+
+            ParseError::UnknownDirective(filename, line_number, line) =>
+                write!(formatter, "Unknown directive '{}' on line {}:{}", line, filename, line_number),
+
+            ParseError::MalformedDirective(filename, line_number, line) =>
+                write!(formatter, "Malformed directive '{}' on line {}:{}", line, filename, line_number),
+
+            ParseError::ExternalTarget(filename, line_number, target) =>
+                write!(formatter, "Target {} of rule at {}:{} is outside the project directory \
+(add '@allow-external-targets PREFIX' to the rules file to permit it)", target, filename, line_number),
+
+            ParseError::ContinuationAtEndOfFile(filename, line_number) =>
+                write!(formatter, "Line {}:{} ends in '\\' but there's no line after it to continue onto", filename, line_number),
+
+            ParseError::RulerVersionTooOld(filename, line_number, required, have) =>
+                write!(formatter, "This rules file requires ruler {}, you have {} (line {}:{})", required, have, filename, line_number),
+
+            ParseError::InvisibleCharacters(filename, line_number, escaped_path) =>
+                write!(formatter, "Path {} at {}:{} has invisible characters or whitespace at an end, \
+shown as <U+XXXX>, so it won't match the path it looks like", escaped_path, filename, line_number),
         }
     }
 }
@@ -122,251 +451,1286 @@ impl fmt::Display for ParseError
     If the parsing of any one file presents an error, this function returns the
     ParseError object for the first error, and does not bother parsing the
     rest. */
-pub fn parse_all(mut contents : Vec<(String, String)>)
+pub fn parse_all(contents : Vec<(String, String)>)
 -> Result<Vec<Rule>, ParseError>
 {
-    let mut result : Vec<Rule> = vec![];
+    let (rules, _global_sources) = parse_all_with_global_sources(contents)?;
+    Ok(rules)
+}
+
+/*  Like parse_all, but also returns the paths declared with @global-source, in the order the files
+    and the lines within them declare them. */
+pub fn parse_all_with_global_sources(mut contents : Vec<(String, String)>)
+-> Result<(Vec<Rule>, Vec<String>), ParseError>
+{
+    let mut rules : Vec<Rule> = vec![];
+    let mut global_sources : Vec<String> = vec![];
     for (filename, content) in contents.drain(..)
     {
-        result.extend(parse(filename, content)?);
+        let (file_rules, file_global_sources) = parse_with_global_sources(filename, content)?;
+        rules.extend(file_rules);
+        global_sources.extend(file_global_sources);
     }
 
-    Ok(result)
+    Ok((rules, global_sources))
 }
 
-/*  Reads in a .rules file content as a String, and creates a vector of Rule
-    objects. */
-pub fn parse(filename : String, content : String)
--> Result<Vec<Rule>, ParseError>
+/*  Generated rules files sometimes emit the same rule twice.  Removes each rule whose ticket matches
+    an earlier rule's, keeping the first (and its provenance).  Rules that share a target but differ
+    in any other way stay in, so the topological sort still reports them. */
+pub fn dedupe_identical_rules(mut rules : Vec<Rule>) -> Vec<Rule>
 {
-    enum Mode
+    let mut seen = HashSet::new();
+    let mut result = vec![];
+    for rule in rules.drain(..)
     {
-        Pending,
-        Targets,
-        Sources,
-        Command,
+        if seen.insert(rule.get_ticket())
+        {
+            result.push(rule);
+        }
     }
+    result
+}
 
-    let mut rules = Vec::new();
-    let mut target_lines = vec![];
-    let mut source_lines = vec![];
-    let mut command = vec![];
-    let mut mode = Mode::Pending;
-    let mut line_number = 1;
+/*  Files authored on Windows may start with a byte-order-mark and end lines with \r\n, or even lone \r.
+    Returns the text with the mark removed and every line ending as \n, so that target names and tickets
+    come out the same whatever editor wrote the file.  The bool says whether anything changed. */
+pub fn normalize_line_endings(text : &str) -> (String, bool)
+{
+    let without_bom = match text.strip_prefix('\u{feff}')
+    {
+        Some(rest) => rest,
+        None => text,
+    };
 
-    let lines = content.split('\n').collect::<Vec<&str>>();
+    let normalized = without_bom.replace("\r\n", "\n").replace("\r", "\n");
+    let changed = normalized != text;
+    (normalized, changed)
+}
 
-    for line in lines
+/*  Collapses "." components, repeated slashes and ".." where it follows a named component.  A ".."
+    that would climb above the start stays in place, so the result starts with ".." exactly when the
+    path escapes the directory it's relative to. */
+pub fn collapse_path(path : &str) -> String
+{
+    let mut components : Vec<&str> = vec![];
+    for component in path.split('/')
     {
-        match mode
+        match component
         {
-            Mode::Pending =>
-            {
-                match line
-                {
-                    "" => {},
-                    ":" => return Err(ParseError::UnexpectedExtraColon(filename, line_number)),
-                    _ =>
-                    {
-                        mode = Mode::Targets;
-                        target_lines.push(line);
-                    },
-                }
-            },
-            Mode::Targets =>
-            {
-                match line
-                {
-                    "" => return Err(ParseError::UnexpectedEmptyLine(filename, line_number)),
-                    ":" => mode = Mode::Sources,
-                    _ => target_lines.push(line),
-                }
-            },
-            Mode::Sources =>
+            "" | "." => {},
+            ".." =>
             {
-                match line
+                match components.last()
                 {
-                    "" => return Err(ParseError::UnexpectedEmptyLine(filename, line_number)),
-                    ":" => mode = Mode::Command,
-                    _ => source_lines.push(line),
+                    Some(&"..") | None => components.push(".."),
+                    Some(_) => {components.pop();},
                 }
             },
-            Mode::Command =>
-            {
-                match line
-                {
-                    "" => return Err(ParseError::UnexpectedEmptyLine(filename, line_number)),
-                    ":" =>
-                    {
-                        mode = Mode::Pending;
+            _ => components.push(component),
+        }
+    }
 
-                        let target_bundle = match PathBundle::parse_lines(target_lines)
-                        {
-                            Ok(bundle) => bundle,
-                            Err(error) => return Err(ParseError::BundleError(filename, error)),
-                        };
+    if path.starts_with('/')
+    {
+        format!("/{}", components.join("/"))
+    }
+    else
+    {
+        components.join("/")
+    }
+}
 
-                        let source_bundle = match PathBundle::parse_lines(source_lines)
-                        {
-                            Ok(bundle) => bundle,
-                            Err(error) => return Err(ParseError::BundleError(filename, error)),
-                        };
+/*  Paths in rules are relative to the project directory, the directory ruler runs in.  A path
+    escapes it if it's absolute or climbs out with "..". */
+pub fn escapes_project_directory(path : &str) -> bool
+{
+    let collapsed = collapse_path(path);
+    collapsed.starts_with('/') || collapsed == ".." || collapsed.starts_with("../")
+}
 
-                        let rule = Rule::new(
-                            target_bundle.get_path_strings('/'),
-                            source_bundle.get_path_strings('/'),
-                            command);
+/*  Characters that don't show, or show as if they were an ordinary space, so a path with one in it
+    prints just like the path without: control characters (tab included), the no-break spaces, the
+    other Unicode spaces, zero-width spaces and joiners, direction marks and embeddings, the soft
+    hyphen, the Hangul fillers, and the byte-order mark. */
+pub fn is_invisible(c : char) -> bool
+{
+    c.is_control() || match c
+    {
+        '\u{00A0}' | '\u{00AD}' | '\u{034F}' | '\u{061C}' | '\u{115F}' | '\u{1160}' | '\u{1680}' | '\u{180E}' |
+        '\u{2000}'..='\u{200F}' | '\u{2028}'..='\u{202F}' | '\u{205F}'..='\u{206F}' |
+        '\u{3000}' | '\u{3164}' | '\u{FEFF}' | '\u{FFA0}' => true,
+        _ => false,
+    }
+}
 
-                        rules.push(rule);
+/*  Whether the path has invisible characters in it, or whitespace at either end.  Spaces in the middle
+    are fine: they show. */
+pub fn has_invisible_characters(path : &str) -> bool
+{
+    path.trim() != path || path.chars().any(is_invisible)
+}
 
-                        target_lines = vec![];
-                        source_lines = vec![];
-                        command = vec![];
-                    }
-                    _ => command.push(line.to_string()),
-                }
-            },
+/*  The number of the first of the lines that has invisible characters in the part of it that went
+    into path, leaving out the tabs that indent it.  None if no one line does. */
+fn find_invisible_line
+<
+    'a,
+    LinesType : Iterator<Item = &'a (usize, String)>,
+>
+(
+    lines : LinesType,
+    path : &str
+)
+-> Option<usize>
+{
+    for (line_number, line) in lines
+    {
+        let name = line.trim_start_matches('\t');
+        if has_invisible_characters(name) && path.contains(name)
+        {
+            return Some(*line_number);
         }
+    }
+    None
+}
 
-        line_number += 1;
+/*  The path with each invisible character, and each whitespace character at either end, written out
+    as <U+XXXX>, so two paths that print the same can be told apart. */
+pub fn escape_invisible(path : &str) -> String
+{
+    let body_start = path.len() - path.trim_start().len();
+    let body_end = path.trim_end().len();
+    let mut escaped = String::new();
+    for (index, c) in path.char_indices()
+    {
+        if is_invisible(c) || index < body_start || index >= body_end
+        {
+            escaped.push_str(&format!("<U+{:04X}>", c as u32));
+        }
+        else
+        {
+            escaped.push(c);
+        }
     }
+    escaped
+}
 
-    match mode
+/*  The path as it looks: invisible characters taken out, and whitespace at the ends trimmed off. */
+pub fn strip_invisible(path : &str) -> String
+{
+    path.chars().filter(|c| ! is_invisible(*c)).collect::<String>().trim().to_string()
+}
+
+/*  Whether the path is the prefix, or lies in the directory named by the prefix. */
+pub fn path_has_prefix(path : &str, prefix : &str) -> bool
+{
+    let path = collapse_path(path);
+    let prefix = collapse_path(prefix);
+    path == prefix
+        || prefix == "/" && path.starts_with('/')
+        || path.starts_with(&format!("{}/", prefix))
+}
+
+/*  What marks a source as a whole directory rather than a file. */
+pub const DIRECTORY_SOURCE_PREFIX : &str = "dir:";
+
+/*  If the source is a directory source, dir:PATH, the directory's path, without any trailing '/'. */
+pub fn get_directory_source(source : &str) -> Option<String>
+{
+    if source.starts_with(DIRECTORY_SOURCE_PREFIX)
     {
-        Mode::Pending => return Ok(rules),
-        Mode::Targets => return Err(ParseError::UnexpectedEndOfFileMidTargets(filename, line_number)),
-        Mode::Sources => return Err(ParseError::UnexpectedEndOfFileMidSources(filename, line_number)),
-        Mode::Command => return Err(ParseError::UnexpectedEndOfFileMidCommand(filename, line_number)),
+        let path = source[DIRECTORY_SOURCE_PREFIX.len()..].trim_end_matches('/');
+        if path == ""
+        {
+            Some(".".to_string())
+        }
+        else
+        {
+            Some(path.to_string())
+        }
+    }
+    else
+    {
+        None
     }
 }
 
-#[cfg(test)]
-mod tests
+/*  A version as major.minor.patch, compared numerically, part by part. */
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Version
 {
-    use crate::rule::
+    major : u64,
+    minor : u64,
+    patch : u64,
+}
+
+impl Version
+{
+    fn parse(text : &str) -> Option<Version>
     {
-        Rule,
-        parse,
-        parse_all,
-        ParseError,
-    };
+        let parts : Vec<&str> = text.split('.').collect();
+        match parts.as_slice()
+        {
+            [major, minor, patch] =>
+            {
+                match (major.parse::<u64>(), minor.parse::<u64>(), patch.parse::<u64>())
+                {
+                    (Ok(major), Ok(minor), Ok(patch)) =>
+                        Some(Version{major : major, minor : minor, patch : patch}),
+                    _ => None,
+                }
+            },
+            _ => None,
+        }
+    }
+}
+
+/*  Checks a directive line "@ruler-version >= X.Y.Z", given the words after "@ruler-version", against
+    this ruler's own version.  The directive usually opens the file, so a file using syntax this ruler
+    doesn't know fails with the version it needs instead of some confusing parse error. */
+fn check_ruler_version(filename : &str, line_number : usize, line : &str, words : Vec<&str>)
+-> Result<(), ParseError>
+{
+    let required =
+    match (words.get(0), words.get(1).and_then(|word| Version::parse(word)), words.len())
+    {
+        (Some(&">="), Some(required), 2) => required,
+        _ => return Err(ParseError::MalformedDirective(filename.to_string(), line_number, line.to_string())),
+    };
+
+    match Version::parse(crate::RULER_VERSION)
+    {
+        Some(have) if have >= required => Ok(()),
+        _ => Err(ParseError::RulerVersionTooOld(filename.to_string(), line_number,
+            format!("{}.{}.{}", required.major, required.minor, required.patch), crate::RULER_VERSION.to_string())),
+    }
+}
+
+/*  How many words may follow a directive's name. */
+#[derive(Clone, Copy)]
+enum Arity
+{
+    Zero,
+    One,
+    OneOrMore,
+
+    /*  The directive checks its own words. */
+    Checked,
+}
+
+impl Arity
+{
+    fn accepts(self, count : usize) -> bool
+    {
+        match self
+        {
+            Arity::Zero => count == 0,
+            Arity::One => count == 1,
+            Arity::OneOrMore => count > 0,
+            Arity::Checked => true,
+        }
+    }
+}
+
+/*  Every directive ruler knows: its name, the words it takes, and whether it applies to the rule
+    that follows, which makes it an error at the end of the file. */
+const DIRECTIVES : [(&str, Arity, bool); 13] =
+[
+    ("allow-external-targets", Arity::OneOrMore, false),
+    ("global-source", Arity::OneOrMore, false),
+    ("ruler-version", Arity::Checked, false),
+    ("private", Arity::Zero, true),
+    ("precious", Arity::Zero, true),
+    ("no-cache", Arity::Zero, true),
+    ("install", Arity::Zero, true),
+    ("serial", Arity::Zero, true),
+    ("group", Arity::One, true),
+    ("env-allow", Arity::OneOrMore, true),
+    ("expect", Arity::Checked, true),
+    ("success-exit-codes", Arity::OneOrMore, true),
+    ("failure-pattern", Arity::OneOrMore, true),
+];
+
+fn is_comment(line : &str) -> bool
+{
+    line.trim_start().starts_with('#')
+}
+
+/*  Groups the physical lines of a rules file into logical lines: a line ending in '\' continues
+    onto the next.  Each logical line comes back as its pieces, with the backslashes removed and
+    the leading whitespace of every continuation removed, along with the number of the line it
+    starts on.  How the pieces join depends on the section, see join_pieces. */
+fn get_logical_lines(filename : &str, content : &str)
+-> Result<Vec<(usize, Vec<String>)>, ParseError>
+{
+    let physical_lines = content.split('\n').collect::<Vec<&str>>();
+    let mut logical_lines = vec![];
+    let mut current_opt : Option<(usize, Vec<String>)> = None;
+
+    for (index, physical_line) in physical_lines.iter().enumerate()
+    {
+        let line_number = index + 1;
+        let (start_line_number, mut pieces) =
+        match current_opt.take()
+        {
+            Some((start_line_number, pieces)) => (start_line_number, pieces),
+            None => (line_number, vec![]),
+        };
+
+        let piece = if pieces.len() == 0 { physical_line } else { physical_line.trim_start() };
+        match piece.strip_suffix('\\')
+        {
+            Some(before) =>
+            {
+                /*  The split leaves an empty string after the file's final newline.  That's not a
+                    line to continue onto. */
+                let remaining = &physical_lines[index + 1..];
+                if remaining.len() == 0 || (remaining.len() == 1 && remaining[0] == "")
+                {
+                    return Err(ParseError::ContinuationAtEndOfFile(filename.to_string(), line_number));
+                }
+                pieces.push(before.to_string());
+                current_opt = Some((start_line_number, pieces));
+            },
+            None =>
+            {
+                pieces.push(piece.to_string());
+                logical_lines.push((start_line_number, pieces));
+            },
+        }
+    }
+
+    Ok(logical_lines)
+}
+
+/*  The canonical form of a continued line, which is what the rule and its ticket see.  In a command
+    the pieces are words, so each loses its trailing whitespace and they join with a single space.
+    Elsewhere they're parts of a path and join with nothing in between. */
+fn join_pieces(pieces : &Vec<String>, in_command : bool) -> String
+{
+    if in_command && pieces.len() > 1
+    {
+        pieces.iter().map(|piece| piece.trim_end()).filter(|piece| piece.len() > 0)
+            .collect::<Vec<&str>>().join(" ")
+    }
+    else
+    {
+        pieces.concat()
+    }
+}
+
+/*  Reads in a .rules file content as a String, and creates a vector of Rule
+    objects. */
+#[cfg(test)]
+pub fn parse(filename : String, content : String)
+-> Result<Vec<Rule>, ParseError>
+{
+    let (rules, _global_sources) = parse_with_global_sources(filename, content)?;
+    Ok(rules)
+}
+
+/*  Like parse, but also returns the paths declared with @global-source, in order.
+
+    Between rules, a line starting with '@' is a directive.  Most apply to the rule that follows:
+    @private, @precious, @no-cache, @install, @serial, @group NAME, @env-allow NAME...,
+    @expect TARGET size >= N (or <=), @success-exit-codes CODE... and @failure-pattern TEXT, each
+    setting a field of Rule, where it's described.  The rest apply to the file: @allow-external-targets PREFIX..., @global-source PATH... and
+    @ruler-version >= X.Y.Z.  An unknown directive is an error, so one from a newer ruler can't get
+    taken for something it isn't.
+
+    A '#' line is a comment, dropped everywhere but the command section, where shells may want it.
+    A line ending in '\' continues onto the next; see join_pieces for how the pieces join. */
+pub fn parse_with_global_sources(filename : String, content : String)
+-> Result<(Vec<Rule>, Vec<String>), ParseError>
+{
+    enum Mode
+    {
+        Pending,
+        Targets,
+        Sources,
+        Command,
+    }
+
+    let mut rules = Vec::new();
+    let mut target_lines : Vec<(usize, String)> = vec![];
+    let mut source_lines : Vec<(usize, String)> = vec![];
+    let mut command = vec![];
+    let mut mode = Mode::Pending;
+    let mut rule_line_number = 1;
+    let mut output_constraints : Vec<(usize, String, OutputConstraint)> = vec![];
+    let mut allowed_external_prefixes : Vec<String> = vec![];
+    let mut pending_directives : Vec<(usize, String)> = vec![];
+    let mut private = false;
+    let mut precious = false;
+    let mut no_cache = false;
+    let mut install = false;
+    let mut serial = false;
+    let mut group_opt : Option<String> = None;
+    let mut env_allow : Vec<String> = vec![];
+    let mut success_criteria = SuccessCriteria::new();
+    let mut global_sources : Vec<String> = vec![];
+
+    let physical_line_count = content.split('\n').count();
+    let logical_lines = get_logical_lines(&filename, &content)?;
+
+    for (line_number, pieces) in logical_lines
+    {
+        let in_command = match mode { Mode::Command => true, _ => false };
+        let joined = join_pieces(&pieces, in_command);
+        let line = joined.as_str();
+
+        if ! in_command && is_comment(line)
+        {
+            continue;
+        }
+
+        /*  A separator may have trailing whitespace. */
+        let line = if line.trim_end() == ":" { ":" } else { line };
+
+        match mode
+        {
+            Mode::Pending =>
+            {
+                match line
+                {
+                    "" => {},
+                    ":" => return Err(ParseError::UnexpectedExtraColon(filename, line_number)),
+                    _ if line.starts_with('@') =>
+                    {
+                        let mut words = line[1..].split_whitespace();
+                        let name = words.next().unwrap_or("");
+                        let words : Vec<&str> = words.collect();
+                        match DIRECTIVES.iter().find(|(directive, _arity, _for_next_rule)| *directive == name)
+                        {
+                            Some((_directive, arity, for_next_rule)) =>
+                            {
+                                if ! arity.accepts(words.len())
+                                {
+                                    return Err(ParseError::MalformedDirective(filename, line_number, line.to_string()));
+                                }
+                                if *for_next_rule
+                                {
+                                    pending_directives.push((line_number, line.to_string()));
+                                }
+                            },
+                            None => return Err(ParseError::UnknownDirective(filename, line_number, line.to_string())),
+                        }
+
+                        match name
+                        {
+                            "allow-external-targets" => allowed_external_prefixes.extend(words.iter().map(|word| word.to_string())),
+                            "global-source" => global_sources.extend(words.iter().map(|word| word.to_string())),
+                            "ruler-version" => check_ruler_version(&filename, line_number, line, words)?,
+                            "private" => private = true,
+                            "precious" => precious = true,
+                            "no-cache" => no_cache = true,
+                            "install" => install = true,
+                            "serial" => serial = true,
+                            "group" => group_opt = Some(words[0].to_string()),
+                            "env-allow" => env_allow.extend(words.iter().map(|word| word.to_string())),
+                            "expect" =>
+                            {
+                                match parse_output_constraint(words)
+                                {
+                                    Some(constraint) => output_constraints.push((line_number, line.to_string(), constraint)),
+                                    None => return Err(ParseError::MalformedDirective(filename, line_number, line.to_string())),
+                                }
+                            },
+                            "success-exit-codes" =>
+                            {
+                                for word in words
+                                {
+                                    match word.parse::<i32>()
+                                    {
+                                        Ok(code) => success_criteria.exit_codes.push(code),
+                                        Err(_) => return Err(ParseError::MalformedDirective(filename, line_number, line.to_string())),
+                                    }
+                                }
+                            },
+                            "failure-pattern" =>
+                            {
+                                let pattern = line[1..].trim_start()["failure-pattern".len()..].trim();
+                                success_criteria.failure_patterns.push(pattern.to_string());
+                            },
+                            _ => {},
+                        }
+                    },
+                    _ =>
+                    {
+                        mode = Mode::Targets;
+                        rule_line_number = line_number;
+                        target_lines.push((line_number, line.to_string()));
+                    },
+                }
+            },
+            Mode::Targets =>
+            {
+                match line
+                {
+                    "" => return Err(ParseError::UnexpectedEmptyLine(filename, line_number)),
+                    ":" => mode = Mode::Sources,
+                    _ => target_lines.push((line_number, line.to_string())),
+                }
+            },
+            Mode::Sources =>
+            {
+                match line
+                {
+                    "" => return Err(ParseError::UnexpectedEmptyLine(filename, line_number)),
+                    ":" => mode = Mode::Command,
+                    _ => source_lines.push((line_number, line.to_string())),
+                }
+            },
+            Mode::Command =>
+            {
+                match line
+                {
+                    "" => return Err(ParseError::UnexpectedEmptyLine(filename, line_number)),
+                    ":" =>
+                    {
+                        mode = Mode::Pending;
+
+                        let target_bundle = match PathBundle::parse_lines(target_lines.iter().map(|(_line_number, line)| line.as_str()).collect())
+                        {
+                            Ok(bundle) => bundle,
+                            Err(error) => return Err(ParseError::BundleError(filename, rule_line_number, error)),
+                        };
+
+                        let source_bundle = match PathBundle::parse_lines(source_lines.iter().map(|(_line_number, line)| line.as_str()).collect())
+                        {
+                            Ok(bundle) => bundle,
+                            Err(error) => return Err(ParseError::BundleError(filename, rule_line_number, error)),
+                        };
+
+                        let targets = target_bundle.get_path_strings('/');
+                        for (constraint_line, constraint_text, constraint) in output_constraints.iter()
+                        {
+                            if ! targets.contains(&constraint.target)
+                            {
+                                return Err(ParseError::MalformedDirective(filename, *constraint_line, constraint_text.clone()));
+                            }
+                        }
+
+                        let mut rule = Rule::with_provenance(
+                            targets,
+                            source_bundle.get_path_strings('/'),
+                            command,
+                            Provenance::new(&filename, rule_line_number));
+                        rule.output_constraints = output_constraints.drain(..).map(|(_line, _text, constraint)| constraint).collect();
+
+                        /*  A path that prints like another but isn't it never connects to it, and
+                            nothing says so, so a stray no-break space can take hours to find. */
+                        for path in rule.targets.iter().chain(rule.sources.iter())
+                        {
+                            if has_invisible_characters(path)
+                            {
+                                let bad_line_number = find_invisible_line(
+                                    target_lines.iter().chain(source_lines.iter()), path).unwrap_or(rule_line_number);
+                                return Err(ParseError::InvisibleCharacters(filename, bad_line_number, escape_invisible(path)));
+                            }
+                        }
+                        rule.install = install;
+                        rule.private = private || install;
+                        rule.precious = precious;
+                        rule.no_cache = no_cache;
+                        rule.serial = serial;
+                        rule.group_opt = group_opt.take();
+                        rule.env_allow = env_allow;
+                        rule.success_criteria = success_criteria;
+
+                        pending_directives = vec![];
+                        private = false;
+                        precious = false;
+                        no_cache = false;
+                        install = false;
+                        serial = false;
+                        env_allow = vec![];
+                        success_criteria = SuccessCriteria::new();
+
+                        rules.push(rule);
+
+                        target_lines = vec![];
+                        source_lines = vec![];
+                        command = vec![];
+                    }
+                    _ => command.push(line.to_string()),
+                }
+            },
+        }
+    }
+
+    let line_number = physical_line_count + 1;
+
+    match mode
+    {
+        Mode::Pending =>
+        {
+            /*  A directive for the rule that follows, with no rule following. */
+            match pending_directives.first()
+            {
+                Some((directive_line, directive_text)) =>
+                    return Err(ParseError::MalformedDirective(filename, *directive_line, directive_text.clone())),
+                None => {},
+            }
+
+            for rule in rules.iter()
+            {
+                for target in rule.targets.iter()
+                {
+                    if escapes_project_directory(target)
+                        && ! allowed_external_prefixes.iter().any(|prefix| path_has_prefix(target, prefix))
+                    {
+                        return Err(ParseError::ExternalTarget(filename, rule.provenance.line, target.clone()));
+                    }
+                }
+            }
+            return Ok((rules, global_sources))
+        },
+        Mode::Targets => return Err(ParseError::UnexpectedEndOfFileMidTargets(filename, line_number)),
+        Mode::Sources => return Err(ParseError::UnexpectedEndOfFileMidSources(filename, line_number)),
+        Mode::Command => return Err(ParseError::UnexpectedEndOfFileMidCommand(filename, line_number)),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::rule::
+    {
+        Rule,
+        OutputConstraint,
+        SizeComparison,
+        Provenance,
+        SuccessCriteria,
+        parse,
+        parse_all,
+        parse_all_with_global_sources,
+        normalize_line_endings,
+        dedupe_identical_rules,
+        collapse_path,
+        escapes_project_directory,
+        escape_invisible,
+        strip_invisible,
+        has_invisible_characters,
+        ParseError,
+    };
+
+    #[test]
+    fn rule_tickets_differ()
+    {
+        let z = Rule::new(vec!["".to_string()], vec!["".to_string()], vec!["".to_string()]);
+        let a = Rule::new(vec!["a".to_string()], vec!["".to_string()], vec!["".to_string()]);
+        let b = Rule::new(vec!["".to_string()], vec!["b".to_string()], vec!["".to_string()]);
+        let c = Rule::new(vec!["".to_string()], vec!["".to_string()], vec!["c".to_string()]);
+
+        assert_ne!(z.get_ticket(), a.get_ticket());
+        assert_ne!(z.get_ticket(), b.get_ticket());
+        assert_ne!(z.get_ticket(), c.get_ticket());
+
+        assert_ne!(a.get_ticket(), b.get_ticket());
+        assert_ne!(a.get_ticket(), c.get_ticket());
+
+        assert_ne!(b.get_ticket(), c.get_ticket());
+    }
+
+    #[test]
+    fn rule_target_orders_do_not_affect_ticket()
+    {
+        assert_eq!(
+            Rule::new(
+                vec!["".to_string()],
+                vec!["apples".to_string(), "bananas".to_string()],
+                vec!["".to_string()]).get_ticket(),
+            Rule::new(
+                vec!["".to_string()],
+                vec!["bananas".to_string(), "apples".to_string()],
+                vec!["".to_string()]).get_ticket()
+        );
+
+    }
+
+    /*  Call parse on an empty string, check that the rule list is empty. */
+    #[test]
+    fn parse_empty()
+    {
+        assert_eq!(parse("empty.rules".to_string(), "".to_string()).unwrap(), vec![]);
+    }
+
+    /*  Call parse on a properly formatted rule, check that the targets,
+        sources and command are what was in the text. */
+    #[test]
+    fn parse_one_rule()
+    {
+        let result = parse(
+            "one.rules".to_string(),
+            "a\n:\nb\n:\nc\n:\n".to_string());
+
+        match result
+        {
+            Ok(v) =>
+            {
+                assert_eq!(v.len(), 1);
+                assert_eq!(v[0].targets, vec!["a".to_string()]);
+                assert_eq!(v[0].sources, vec!["b".to_string()]);
+                assert_eq!(v[0].command, vec!["c".to_string()]);
+            },
+            Err(why) => panic!("Expected success, got: {}", why),
+        };
+    }
+
+    /*  Call parse on twp properly formatted rules, check that the targets,
+        sources and command are what was in the text. */
+    #[test]
+    fn parse_two()
+    {
+        match parse(
+            "paper.rules".to_string(),
+            "a\n:\nb\n:\nc\n:\n\nd\n:\ne\n:\nf\n:\n".to_string())
+        {
+            Ok(v) =>
+            {
+                assert_eq!(v.len(), 2);
+                assert_eq!(v[0].targets, vec!["a".to_string()]);
+                assert_eq!(v[0].sources, vec!["b".to_string()]);
+                assert_eq!(v[0].command, vec!["c".to_string()]);
+                assert_eq!(v[1].targets, vec!["d".to_string()]);
+                assert_eq!(v[1].sources, vec!["e".to_string()]);
+                assert_eq!(v[1].command, vec!["f".to_string()]);
+            },
+            Err(why) => panic!("Expected success, got: {}", why),
+        };
+    }
+
+    #[test]
+    fn parse_bundles()
+    {
+        let content = "\
+build
+\tmath.o
+:
+cpp
+\tmath.cpp
+\tmath.h
+:
+c++ -c math.cpp -o build/math.o
+:
+".to_string();
+        assert_eq!(
+            parse("parsnip.rules".to_string(), content),
+            Ok(vec![
+                Rule
+                {
+                    targets: vec!["build/math.o".to_string()],
+                    output_constraints: vec![],
+                    sources: vec![
+                        "cpp/math.cpp".to_string(),
+                        "cpp/math.h".to_string(),
+                    ],
+                    command: vec![
+                        "c++ -c math.cpp -o build/math.o".to_string()
+                    ],
+                    provenance: Provenance::new("parsnip.rules", 1),
+                    private: false,
+                    precious: false,
+                    no_cache: false,
+                    install: false,
+                    serial: false,
+                    group_opt: None,
+                    env_allow: vec![],
+                    success_criteria: SuccessCriteria::new(),
+                }
+            ])
+        );
+    }
+
+    /*  Parse two files with a few rules each, check that each rule remembers the file it
+        came from and the line on which its first target appears. */
+    #[test]
+    fn parse_all_records_provenance()
+    {
+        let rules = parse_all(
+            vec![
+                ("fruit.rules".to_string(), "\na\n:\nb\n:\nc\n:\n\n\nd\ne\n:\nf\n:\ng\n:\n".to_string()),
+                ("veggie.rules".to_string(), "h\n:\ni\n:\nj\n:\n".to_string()),
+            ]).unwrap();
+
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0].provenance, Provenance::new("fruit.rules", 2));
+        assert_eq!(rules[1].provenance, Provenance::new("fruit.rules", 10));
+        assert_eq!(rules[2].provenance, Provenance::new("veggie.rules", 1));
+        assert_eq!(format!("{}", rules[1].provenance), "fruit.rules:10");
+    }
+
+    #[test]
+    fn normalize_line_endings_general()
+    {
+        assert_eq!(normalize_line_endings("a\nb\n"), ("a\nb\n".to_string(), false));
+        assert_eq!(normalize_line_endings("a\r\nb\r\n"), ("a\nb\n".to_string(), true));
+        assert_eq!(normalize_line_endings("a\rb\r"), ("a\nb\n".to_string(), true));
+        assert_eq!(normalize_line_endings("\u{feff}a\nb\n"), ("a\nb\n".to_string(), true));
+        assert_eq!(normalize_line_endings("\u{feff}a\r\nb\r\n"), ("a\nb\n".to_string(), true));
+        assert_eq!(normalize_line_endings("a\u{feff}\n"), ("a\u{feff}\n".to_string(), false));
+    }
+
+    /*  Parse the same rules with LF, CRLF and BOM+CRLF line endings after normalizing, and check the
+        rules and their tickets come out identical. */
+    #[test]
+    fn parse_normalized_line_endings_identical()
+    {
+        let lf = "poem.txt\n:\nverse1.txt\nverse2.txt\n:\nmycat\nverse1.txt\nverse2.txt\npoem.txt\n:\n";
+        let crlf = lf.replace("\n", "\r\n");
+        let bom_crlf = format!("\u{feff}{}", crlf);
+
+        let lf_rules = parse("build.rules".to_string(), normalize_line_endings(lf).0).unwrap();
+        let crlf_rules = parse("build.rules".to_string(), normalize_line_endings(&crlf).0).unwrap();
+        let bom_crlf_rules = parse("build.rules".to_string(), normalize_line_endings(&bom_crlf).0).unwrap();
+
+        assert_eq!(lf_rules, crlf_rules);
+        assert_eq!(lf_rules, bom_crlf_rules);
+        assert_eq!(lf_rules[0].targets, vec!["poem.txt".to_string()]);
+        assert_eq!(lf_rules[0].get_ticket(), crlf_rules[0].get_ticket());
+        assert_eq!(lf_rules[0].get_ticket(), bom_crlf_rules[0].get_ticket());
+    }
+
+    /*  Dedupe a list with an exact duplicate and a rule that shares its target but has a different
+        command.  Only the exact duplicate goes, and the first copy is the one kept. */
+    #[test]
+    fn dedupe_identical_rules_keeps_first_and_conflicts()
+    {
+        let first = Rule::with_provenance(
+            vec!["a".to_string()], vec!["b".to_string()], vec!["c".to_string()],
+            Provenance::new("gen.rules", 1));
+        let duplicate = Rule::with_provenance(
+            vec!["a".to_string()], vec!["b".to_string()], vec!["c".to_string()],
+            Provenance::new("gen.rules", 7));
+        let conflicting = Rule::with_provenance(
+            vec!["a".to_string()], vec!["b".to_string()], vec!["d".to_string()],
+            Provenance::new("gen.rules", 13));
+
+        assert_eq!(
+            dedupe_identical_rules(vec![first.clone(), duplicate, conflicting.clone()]),
+            vec![first, conflicting]);
+    }
+
+    #[test]
+    fn collapse_path_general()
+    {
+        assert_eq!(collapse_path("a/b/c"), "a/b/c");
+        assert_eq!(collapse_path("./a//b/"), "a/b");
+        assert_eq!(collapse_path("a/../b"), "b");
+        assert_eq!(collapse_path("a/../../b"), "../b");
+        assert_eq!(collapse_path("../../b"), "../../b");
+        assert_eq!(collapse_path("/a/./b/.."), "/a");
+        assert_eq!(collapse_path(""), "");
+    }
+
+    #[test]
+    fn escapes_project_directory_general()
+    {
+        assert!(!escapes_project_directory("poem.txt"));
+        assert!(!escapes_project_directory("build/../poem.txt"));
+        assert!(escapes_project_directory("build/../../poem.txt"));
+        assert!(escapes_project_directory("../shared/out.bin"));
+        assert!(escapes_project_directory(".."));
+        assert!(escapes_project_directory("/tmp/out.bin"));
+    }
+
+    /*  A target climbing out of the project is an error naming the target and the rule's line. */
+    #[test]
+    fn parse_external_target_errors()
+    {
+        let error = parse(
+            "gen.rules".to_string(),
+            "poem.txt\n:\nverse.txt\n:\nmycat\n:\n\nsub/../../shared/out.bin\n:\npoem.txt\n:\nmycat\n:\n".to_string()).unwrap_err();
+
+        assert_eq!(error, ParseError::ExternalTarget(
+            "gen.rules".to_string(), 8, "sub/../../shared/out.bin".to_string()));
+        assert!(format!("{}", error).contains("sub/../../shared/out.bin"));
+        assert!(format!("{}", error).contains("gen.rules:8"));
+    }
+
+    /*  The directive permits targets under its prefixes, and only those. */
+    #[test]
+    fn parse_allow_external_targets_directive()
+    {
+        let rules = parse(
+            "gen.rules".to_string(),
+            "@allow-external-targets ../shared /opt/out/\n\n../shared/out.bin\n/opt/out/a.bin\n:\npoem.txt\n:\nmycat\n:\n".to_string()).unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].targets, vec!["../shared/out.bin".to_string(), "/opt/out/a.bin".to_string()]);
+
+        assert_eq!(
+            parse(
+                "gen.rules".to_string(),
+                "@allow-external-targets ../shared\n../sharedness/out.bin\n:\npoem.txt\n:\nmycat\n:\n".to_string()),
+            Err(ParseError::ExternalTarget("gen.rules".to_string(), 2, "../sharedness/out.bin".to_string())));
+    }
+
+    #[test]
+    fn parse_bad_directives()
+    {
+        assert_eq!(
+            parse("gen.rules".to_string(), "@allow-external-targets\n".to_string()),
+            Err(ParseError::MalformedDirective("gen.rules".to_string(), 1, "@allow-external-targets".to_string())));
+
+        assert_eq!(
+            parse("gen.rules".to_string(), "\n@frobnicate\n".to_string()),
+            Err(ParseError::UnknownDirective("gen.rules".to_string(), 2, "@frobnicate".to_string())));
+    }
+
+    /*  A version requirement this ruler meets parses like any other file.  One it doesn't meet is an
+        error naming both versions, ahead of any syntax the file goes on to use that this ruler doesn't
+        know.  A requirement that isn't ">= X.Y.Z" is malformed, and a command line that happens to
+        look like one isn't a directive at all. */
+    #[test]
+    fn parse_ruler_version_directive()
+    {
+        let poem = "poem.txt\n:\nverse.txt\n:\nmycat\nverse.txt\npoem.txt\n:\n";
+        let rules = parse("build.rules".to_string(), format!("@ruler-version >= 1.0.0\n\n{}", poem)).unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].targets, vec!["poem.txt".to_string()]);
+
+        let error = parse_all(vec![
+            ("build.rules".to_string(), poem.to_string()),
+            ("new.rules".to_string(), format!("# needs pools\n@ruler-version >= 99.0.0\n@pool gpu 2\n{}", poem)),
+        ]).unwrap_err();
+        assert_eq!(error, ParseError::RulerVersionTooOld("new.rules".to_string(), 2,
+            "99.0.0".to_string(), crate::RULER_VERSION.to_string()));
+        assert_eq!(format!("{}", error), format!(
+            "This rules file requires ruler 99.0.0, you have {} (line new.rules:2)", crate::RULER_VERSION));
+
+        for line in ["@ruler-version 1.0.0", "@ruler-version >= 1.0", "@ruler-version >= 1.x.0", "@ruler-version >= 1.0.0 2.0.0"].iter()
+        {
+            assert_eq!(
+                parse("build.rules".to_string(), format!("\n{}\n{}", line, poem)),
+                Err(ParseError::MalformedDirective("build.rules".to_string(), 2, line.to_string())));
+        }
+
+        let rules = parse("build.rules".to_string(),
+            "poem.txt\n:\nverse.txt\n:\n@ruler-version >= 99.0.0\n:\n".to_string()).unwrap();
+        assert_eq!(rules[0].command, vec!["@ruler-version >= 99.0.0".to_string()]);
+    }
+
+    /*  A directive this ruler doesn't know, as from a newer one, is an error rather than getting taken
+        for the targets of the rule after it. */
+    #[test]
+    fn parse_unknown_directive_before_rule()
+    {
+        assert_eq!(
+            parse("build.rules".to_string(), "@pool gpu 2\npoem.txt\n:\nverse.txt\n:\nmycat\nverse.txt\npoem.txt\n:\n".to_string()),
+            Err(ParseError::UnknownDirective("build.rules".to_string(), 1, "@pool gpu 2".to_string())));
+    }
+
+    /*  The private directive marks only the rule right after it, changes its ticket, and is an error
+        with nothing after it or with words after it. */
+    #[test]
+    fn parse_private_directive()
+    {
+        let rules = parse(
+            "sdk.rules".to_string(),
+            "@private
+licensed.bin
+:
+sdk.zip
+:
+unzip
+:
+
+poem.txt
+:
+verse.txt
+:
+mycat
+:
+".to_string()).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert!(rules[0].private);
+        assert!(!rules[1].private);
+        assert_eq!(rules[0].provenance, Provenance::new("sdk.rules", 2));
+
+        let mut unmarked = rules[0].clone();
+        unmarked.private = false;
+        assert!(unmarked.get_ticket() != rules[0].get_ticket());
+
+        assert_eq!(
+            parse("sdk.rules".to_string(), "poem.txt
+:
+verse.txt
+:
+mycat
+:
+@private
+".to_string()),
+            Err(ParseError::MalformedDirective("sdk.rules".to_string(), 7, "@private".to_string())));
+
+        assert_eq!(
+            parse("sdk.rules".to_string(), "@private please
+".to_string()),
+            Err(ParseError::MalformedDirective("sdk.rules".to_string(), 1, "@private please".to_string())));
+    }
+
+    /*  The install directive marks only the rule right after it, as install and private both, and
+        changes its ticket from that of the same rule marked only private. */
+    #[test]
+    fn parse_install_directive()
+    {
+        let rules = parse(
+            "stage.rules".to_string(),
+            "@install
+stage/bin/tool
+:
+tool
+:
+install
+:
+
+tool
+:
+tool.c
+:
+compile
+:
+".to_string()).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert!(rules[0].install);
+        assert!(rules[0].private);
+        assert!(!rules[1].install);
+        assert!(!rules[1].private);
+
+        let mut private_only = rules[0].clone();
+        private_only.install = false;
+        assert!(private_only.get_ticket() != rules[0].get_ticket());
+
+        assert_eq!(
+            parse("stage.rules".to_string(), "@install
+".to_string()),
+            Err(ParseError::MalformedDirective("stage.rules".to_string(), 1, "@install".to_string())));
+    }
+
+    /*  A target with a no-break space on the end, or a source with a tab, is an error that shows
+        where the invisible character is. */
+    #[test]
+    fn parse_invisible_characters_is_error()
+    {
+        assert_eq!(
+            parse("lib.rules".to_string(), "libfoo.a\u{a0}
+:
+foo.o
+:
+ar rcs libfoo.a foo.o
+:
+".to_string()),
+            Err(ParseError::InvisibleCharacters("lib.rules".to_string(), 1, "libfoo.a<U+00A0>".to_string())));
+
+        assert_eq!(
+            parse("lib.rules".to_string(), "libfoo.a
+:
+foo.o\t
+:
+ar rcs libfoo.a foo.o
+:
+".to_string()),
+            Err(ParseError::InvisibleCharacters("lib.rules".to_string(), 3, "foo.o<U+0009>".to_string())));
+
+        assert_eq!(escape_invisible("a\u{200b}b"), "a<U+200B>b");
+        assert_eq!(strip_invisible(" a\u{feff}b "), "ab");
+        assert!(!has_invisible_characters("src/main.c"));
+    }
+
+    /*  The precious directive marks only the rule right after it, leaves its ticket alone, and like
+        private, is an error with no rule after it. */
+    #[test]
+    fn parse_precious_directive()
+    {
+        let rules = parse(
+            "app.rules".to_string(),
+            "@precious
+app
+:
+main.c
+:
+cc
+:
+
+poem.txt
+:
+verse.txt
+:
+mycat
+:
+".to_string()).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert!(rules[0].precious);
+        assert!(!rules[1].precious);
+
+        let mut unmarked = rules[0].clone();
+        unmarked.precious = false;
+        assert_eq!(unmarked.get_ticket(), rules[0].get_ticket());
+
+        assert_eq!(
+            parse("app.rules".to_string(), "app
+:
+main.c
+:
+cc
+:
+@precious
+".to_string()),
+            Err(ParseError::MalformedDirective("app.rules".to_string(), 7, "@precious".to_string())));
+    }
 
+    /*  The no-cache directive marks only the rule right after it and leaves its ticket alone. */
     #[test]
-    fn rule_tickets_differ()
+    fn parse_no_cache_directive()
     {
-        let z = Rule::new(vec!["".to_string()], vec!["".to_string()], vec!["".to_string()]);
-        let a = Rule::new(vec!["a".to_string()], vec!["".to_string()], vec!["".to_string()]);
-        let b = Rule::new(vec!["".to_string()], vec!["b".to_string()], vec!["".to_string()]);
-        let c = Rule::new(vec!["".to_string()], vec!["".to_string()], vec!["c".to_string()]);
+        let rules = parse(
+            "app.rules".to_string(),
+            "@no-cache
+version.txt
+:
+version.in
+:
+stamp
+:
 
-        assert_ne!(z.get_ticket(), a.get_ticket());
-        assert_ne!(z.get_ticket(), b.get_ticket());
-        assert_ne!(z.get_ticket(), c.get_ticket());
+poem.txt
+:
+verse.txt
+:
+mycat
+:
+".to_string()).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert!(rules[0].no_cache);
+        assert!(!rules[1].no_cache);
 
-        assert_ne!(a.get_ticket(), b.get_ticket());
-        assert_ne!(a.get_ticket(), c.get_ticket());
+        let mut unmarked = rules[0].clone();
+        unmarked.no_cache = false;
+        assert_eq!(unmarked.get_ticket(), rules[0].get_ticket());
 
-        assert_ne!(b.get_ticket(), c.get_ticket());
+        assert_eq!(
+            parse("app.rules".to_string(), "@no-cache now
+".to_string()),
+            Err(ParseError::MalformedDirective("app.rules".to_string(), 1, "@no-cache now".to_string())));
     }
 
+    /*  The serial directive marks only the rule right after it, leaves its ticket alone, and is an
+        error with no rule after it. */
     #[test]
-    fn rule_target_orders_do_not_affect_ticket()
+    fn parse_serial_directive()
     {
-        assert_eq!(
-            Rule::new(
-                vec!["".to_string()],
-                vec!["apples".to_string(), "bananas".to_string()],
-                vec!["".to_string()]).get_ticket(),
-            Rule::new(
-                vec!["".to_string()],
-                vec!["bananas".to_string(), "apples".to_string()],
-                vec!["".to_string()]).get_ticket()
-        );
+        let rules = parse(
+            "app.rules".to_string(),
+            "@serial
+app
+:
+main.c
+:
+licensed-cc
+:
+
+poem.txt
+:
+verse.txt
+:
+mycat
+:
+".to_string()).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert!(rules[0].serial);
+        assert!(!rules[1].serial);
+
+        let mut unmarked = rules[0].clone();
+        unmarked.serial = false;
+        assert_eq!(unmarked.get_ticket(), rules[0].get_ticket());
 
+        assert_eq!(
+            parse("app.rules".to_string(), "poem.txt
+:
+verse.txt
+:
+mycat
+:
+@serial
+".to_string()),
+            Err(ParseError::MalformedDirective("app.rules".to_string(), 7, "@serial".to_string())));
     }
 
-    /*  Call parse on an empty string, check that the rule list is empty. */
+    /*  The group directive names one group for the rule right after it, leaves its ticket alone, and
+        needs exactly one name. */
     #[test]
-    fn parse_empty()
+    fn parse_group_directive()
     {
-        assert_eq!(parse("empty.rules".to_string(), "".to_string()).unwrap(), vec![]);
+        let rules = parse(
+            "app.rules".to_string(),
+            "@group gpu
+model.bin
+:
+data.csv
+:
+train
+:
+
+poem.txt
+:
+verse.txt
+:
+mycat
+:
+".to_string()).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].group_opt, Some("gpu".to_string()));
+        assert_eq!(rules[1].group_opt, None);
+
+        let mut unmarked = rules[0].clone();
+        unmarked.group_opt = None;
+        assert_eq!(unmarked.get_ticket(), rules[0].get_ticket());
+
+        assert_eq!(
+            parse("app.rules".to_string(), "@group gpu license\nmodel.bin\n:\ndata.csv\n:\ntrain\n:\n".to_string()),
+            Err(ParseError::MalformedDirective("app.rules".to_string(), 1, "@group gpu license".to_string())));
+
+        assert_eq!(
+            parse("app.rules".to_string(), "@group\nmodel.bin\n:\ndata.csv\n:\ntrain\n:\n".to_string()),
+            Err(ParseError::MalformedDirective("app.rules".to_string(), 1, "@group".to_string())));
+
+        assert_eq!(
+            parse("app.rules".to_string(), "model.bin\n:\ndata.csv\n:\ntrain\n:\n@group gpu\n".to_string()),
+            Err(ParseError::MalformedDirective("app.rules".to_string(), 7, "@group gpu".to_string())));
     }
 
-    /*  Call parse on a properly formatted rule, check that the targets,
-        sources and command are what was in the text. */
+    /*  Provenance is left out of the ticket, so the same rule in a different place has the same ticket. */
     #[test]
-    fn parse_one_rule()
+    fn rule_provenance_does_not_affect_ticket()
     {
-        let result = parse(
-            "one.rules".to_string(),
-            "a\n:\nb\n:\nc\n:\n".to_string());
-
-        match result
-        {
-            Ok(v) =>
-            {
-                assert_eq!(v.len(), 1);
-                assert_eq!(v[0].targets, vec!["a".to_string()]);
-                assert_eq!(v[0].sources, vec!["b".to_string()]);
-                assert_eq!(v[0].command, vec!["c".to_string()]);
-            },
-            Err(why) => panic!("Expected success, got: {}", why),
-        };
+        let a = Rule::with_provenance(
+            vec!["a".to_string()], vec!["b".to_string()], vec!["c".to_string()],
+            Provenance::new("one.rules", 1));
+        let b = Rule::with_provenance(
+            vec!["a".to_string()], vec!["b".to_string()], vec!["c".to_string()],
+            Provenance::new("two.rules", 42));
+        assert_eq!(a.get_ticket(), b.get_ticket());
     }
 
-    /*  Call parse on twp properly formatted rules, check that the targets,
-        sources and command are what was in the text. */
+    /*  Call parse on a rule whose targets are an invalid bundle, check the error names the rule's line. */
     #[test]
-    fn parse_two()
+    fn parse_bundle_error_names_file_and_line()
     {
-        match parse(
-            "paper.rules".to_string(),
-            "a\n:\nb\n:\nc\n:\n\nd\n:\ne\n:\nf\n:\n".to_string())
+        let error = parse(
+            "its-file.rules".to_string(),
+            format!("{}a\n\tb\n\t\tc\n\td\n\t\t\te\n:\nf\n:\ng\n:\n", "\n".repeat(41))).unwrap_err();
+
+        match &error
         {
-            Ok(v) =>
+            ParseError::BundleError(filename, line_number, _) =>
             {
-                assert_eq!(v.len(), 2);
-                assert_eq!(v[0].targets, vec!["a".to_string()]);
-                assert_eq!(v[0].sources, vec!["b".to_string()]);
-                assert_eq!(v[0].command, vec!["c".to_string()]);
-                assert_eq!(v[1].targets, vec!["d".to_string()]);
-                assert_eq!(v[1].sources, vec!["e".to_string()]);
-                assert_eq!(v[1].command, vec!["f".to_string()]);
+                assert_eq!(filename, "its-file.rules");
+                assert_eq!(*line_number, 42);
             },
-            Err(why) => panic!("Expected success, got: {}", why),
-        };
-    }
-
-    #[test]
-    fn parse_bundles()
-    {
-        let content = "\
-build
-\tmath.o
-:
-cpp
-\tmath.cpp
-\tmath.h
-:
-c++ -c math.cpp -o build/math.o
-:
-".to_string();
-        assert_eq!(
-            parse("parsnip.rules".to_string(), content),
-            Ok(vec![
-                Rule
-                {
-                    targets: vec!["build/math.o".to_string()],
-                    sources: vec![
-                        "cpp/math.cpp".to_string(),
-                        "cpp/math.h".to_string(),
-                    ],
-                    command: vec![
-                        "c++ -c math.cpp -o build/math.o".to_string()
-                    ]
-                }
-            ])
-        );
+            _ => panic!("Wrong kind of error: {}", error),
+        }
+        assert!(format!("{}", error).contains("its-file.rules:42"));
     }
 
     #[test]
@@ -774,4 +2138,332 @@ e
             }
         };
     }
+
+
+    /*  Expect lines add up for the rule right after them and change its ticket.  Each needs a target
+        of that rule, "size", ">=" or "<=" and a number, and a rule to apply to. */
+    #[test]
+    fn parse_expect_directive()
+    {
+        let rules = parse(
+            "build.rules".to_string(),
+            "@expect poem.txt size >= 10
+@expect poem.txt size <= 1000
+poem.txt
+:
+verse1.txt
+:
+mycat
+verse1.txt
+poem.txt
+:
+".to_string()).unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].output_constraints, vec![
+            OutputConstraint
+            {
+                target : "poem.txt".to_string(),
+                comparison : SizeComparison::AtLeast,
+                size : 10,
+            },
+            OutputConstraint
+            {
+                target : "poem.txt".to_string(),
+                comparison : SizeComparison::AtMost,
+                size : 1000,
+            },
+        ]);
+        assert_eq!(format!("{}", rules[0].output_constraints[0]), "poem.txt size >= 10");
+
+        let mut unconstrained = rules[0].clone();
+        unconstrained.output_constraints = vec![];
+        assert!(unconstrained.get_ticket() != rules[0].get_ticket());
+
+        assert_eq!(
+            parse("build.rules".to_string(), "@expect poem.txt size > 10
+poem.txt
+:
+:
+:
+".to_string()),
+            Err(ParseError::MalformedDirective("build.rules".to_string(), 1, "@expect poem.txt size > 10".to_string())));
+
+        assert_eq!(
+            parse("build.rules".to_string(), "@expect stanza.txt size >= 10
+poem.txt
+:
+verse1.txt
+:
+mycat
+:
+".to_string()),
+            Err(ParseError::MalformedDirective("build.rules".to_string(), 1, "@expect stanza.txt size >= 10".to_string())));
+
+        assert_eq!(
+            parse("build.rules".to_string(), "@expect poem.txt size >= 10
+".to_string()),
+            Err(ParseError::MalformedDirective("build.rules".to_string(), 1, "@expect poem.txt size >= 10".to_string())));
+    }
+
+    /*  Env-allow lines add up for the rule right after them, leave the next rule alone and the ticket
+        unchanged, and need at least one name and a rule to apply to. */
+    #[test]
+    fn parse_env_allow_directive()
+    {
+        let rules = parse("build.rules".to_string(),
+            "@env-allow CFLAGS
+@env-allow PYTHONPATH HOME
+out.o
+:
+in.c
+:
+cc
+:
+
+other.o
+:
+in.c
+:
+cc
+:
+".to_string()).unwrap();
+
+        assert_eq!(rules[0].env_allow, vec!["CFLAGS".to_string(), "PYTHONPATH".to_string(), "HOME".to_string()]);
+        assert_eq!(rules[1].env_allow, Vec::<String>::new());
+
+        let mut unmarked = rules[0].clone();
+        unmarked.env_allow = vec![];
+        assert_eq!(rules[0].get_ticket(), unmarked.get_ticket());
+
+        assert_eq!(
+            parse("build.rules".to_string(), "@env-allow\n".to_string()),
+            Err(ParseError::MalformedDirective("build.rules".to_string(), 1, "@env-allow".to_string())));
+
+        assert_eq!(
+            parse("build.rules".to_string(), "out.o\n:\nin.c\n:\ncc\n:\n@env-allow CFLAGS\n".to_string()),
+            Err(ParseError::MalformedDirective("build.rules".to_string(), 7, "@env-allow CFLAGS".to_string())));
+    }
+
+    /*  Success criteria add up for the rule right after them and leave the next rule alone.  A failure
+        pattern is the rest of its line, spaces included.  Exit codes have to be numbers, and both need
+        a rule to apply to. */
+    #[test]
+    fn parse_success_criteria_directives()
+    {
+        let rules = parse("build.rules".to_string(),
+            "@success-exit-codes 0 1
+@failure-pattern BUILD FAILED
+@failure-pattern error:
+out.o
+:
+in.c
+:
+cc
+:
+
+other.o
+:
+in.c
+:
+cc
+:
+".to_string()).unwrap();
+
+        assert_eq!(rules[0].success_criteria, SuccessCriteria
+        {
+            exit_codes : vec![0, 1],
+            failure_patterns : vec!["BUILD FAILED".to_string(), "error:".to_string()],
+        });
+        assert_eq!(rules[1].success_criteria, SuccessCriteria::new());
+
+        assert_eq!(
+            parse("build.rules".to_string(), "@success-exit-codes\n".to_string()),
+            Err(ParseError::MalformedDirective("build.rules".to_string(), 1, "@success-exit-codes".to_string())));
+
+        assert_eq!(
+            parse("build.rules".to_string(), "@success-exit-codes 0 warnings\n".to_string()),
+            Err(ParseError::MalformedDirective("build.rules".to_string(), 1, "@success-exit-codes 0 warnings".to_string())));
+
+        assert_eq!(
+            parse("build.rules".to_string(), "@failure-pattern   \n".to_string()),
+            Err(ParseError::MalformedDirective("build.rules".to_string(), 1, "@failure-pattern   ".to_string())));
+
+        assert_eq!(
+            parse("build.rules".to_string(), "out.o\n:\nin.c\n:\ncc\n:\n@failure-pattern oops\n".to_string()),
+            Err(ParseError::MalformedDirective("build.rules".to_string(), 7, "@failure-pattern oops".to_string())));
+    }
+
+    /*  Success criteria are part of the ticket: adding them, or editing either kind, changes it. */
+    #[test]
+    fn success_criteria_change_ticket()
+    {
+        let plain = parse("build.rules".to_string(), "out.o\n:\nin.c\n:\ncc\n:\n".to_string()).unwrap().remove(0);
+        let with_codes = parse("build.rules".to_string(),
+            "@success-exit-codes 0 1\nout.o\n:\nin.c\n:\ncc\n:\n".to_string()).unwrap().remove(0);
+        let with_other_codes = parse("build.rules".to_string(),
+            "@success-exit-codes 0 2\nout.o\n:\nin.c\n:\ncc\n:\n".to_string()).unwrap().remove(0);
+        let with_pattern = parse("build.rules".to_string(),
+            "@failure-pattern BUILD FAILED\nout.o\n:\nin.c\n:\ncc\n:\n".to_string()).unwrap().remove(0);
+        let with_other_pattern = parse("build.rules".to_string(),
+            "@failure-pattern BUILD BROKE\nout.o\n:\nin.c\n:\ncc\n:\n".to_string()).unwrap().remove(0);
+
+        let tickets = vec![
+            plain.get_ticket(),
+            with_codes.get_ticket(),
+            with_other_codes.get_ticket(),
+            with_pattern.get_ticket(),
+            with_other_pattern.get_ticket(),
+        ];
+        for i in 0..tickets.len()
+        {
+            for j in (i+1)..tickets.len()
+            {
+                assert_ne!(tickets[i], tickets[j]);
+            }
+        }
+
+        assert_eq!(plain.get_ticket(), Rule::new(
+            vec!["out.o".to_string()], vec!["in.c".to_string()], vec!["cc".to_string()]).get_ticket());
+    }
+
+    /*  Global sources collect across lines and files in order, and need at least one path. */
+    #[test]
+    fn parse_global_source_directive()
+    {
+        let (rules, global_sources) = parse_all_with_global_sources(vec![
+            ("a.rules".to_string(), "@global-source config.toml\nout.o\n:\nin.c\n:\ncc\n:\n@global-source flags.txt env.txt\n".to_string()),
+            ("b.rules".to_string(), "@global-source more.txt\n".to_string()),
+        ]).unwrap();
+
+        assert_eq!(rules.len(), 1);
+        assert_eq!(global_sources, vec!["config.toml", "flags.txt", "env.txt", "more.txt"]);
+
+        assert_eq!(
+            parse("build.rules".to_string(), "@global-source\n".to_string()),
+            Err(ParseError::MalformedDirective("build.rules".to_string(), 1, "@global-source".to_string())));
+    }
+
+    /*  Comments between rules, before directives and inside target and source sections parse away,
+        leaving the same rules with the same tickets as the file without them.  Only provenance moves,
+        since the rules start on later lines.  A comment in a command stays put. */
+    #[test]
+    fn parse_comments()
+    {
+        let plain = "\
+@private
+stanza.txt
+:
+verse1.txt
+verse2.txt
+:
+mycat
+verse1.txt
+verse2.txt
+stanza.txt
+:
+
+poem.txt
+:
+stanza.txt
+:
+mycat
+stanza.txt
+poem.txt
+:
+";
+        let commented = "\
+# The poem, built up from verses.
+  # indented, still a comment
+
+# private, because the verses are.
+@private
+stanza.txt
+# the stanza is the only target
+:
+verse1.txt
+    # verse2 comes second
+verse2.txt
+:
+mycat
+verse1.txt
+verse2.txt
+stanza.txt
+:
+# between rules
+poem.txt
+:
+# stanza.txt
+stanza.txt
+:
+mycat
+stanza.txt
+poem.txt
+:
+# the end
+";
+        let plain_rules = parse("build.rules".to_string(), plain.to_string()).unwrap();
+        let commented_rules = parse("build.rules".to_string(), commented.to_string()).unwrap();
+
+        assert_eq!(plain_rules.len(), commented_rules.len());
+        for (plain_rule, commented_rule) in plain_rules.iter().zip(commented_rules.iter())
+        {
+            let mut moved = commented_rule.clone();
+            moved.provenance = plain_rule.provenance.clone();
+            assert_eq!(*plain_rule, moved);
+            assert_eq!(plain_rule.get_ticket(), commented_rule.get_ticket());
+        }
+
+        assert_eq!(commented_rules[0].provenance, Provenance::new("build.rules", 6));
+        assert_eq!(commented_rules[1].provenance, Provenance::new("build.rules", 19));
+
+        let rules = parse("build.rules".to_string(), "out.txt\n:\nin.txt\n:\nrecord\n# kept\n:\n".to_string()).unwrap();
+        assert_eq!(rules[0].command, vec!["record".to_string(), "# kept".to_string()]);
+    }
+
+    /*  The same command on one line and continued across three parse to equal rules with equal
+        tickets, however the continuations are indented.  A path can be continued too, and a
+        separator can have trailing whitespace. */
+    #[test]
+    fn parse_continuation_lines()
+    {
+        let one_line = "\
+out/program
+:
+src/main.c
+:
+cc -O2 -Wall -o out/program src/main.c
+:
+";
+        let continued = "\
+out/\\
+program
+:\t
+src/\\
+    main.c
+:  
+cc -O2 \\
+    -Wall\\
+        -o out/program   \\
+  src/main.c
+:
+";
+        let one_line_rules = parse("build.rules".to_string(), one_line.to_string()).unwrap();
+        let continued_rules = parse("build.rules".to_string(), continued.to_string()).unwrap();
+        assert_eq!(one_line_rules, continued_rules);
+        assert_eq!(one_line_rules[0].get_ticket(), continued_rules[0].get_ticket());
+        assert_eq!(continued_rules[0].command, vec!["cc -O2 -Wall -o out/program src/main.c".to_string()]);
+    }
+
+    /*  A backslash on the last line has nothing to continue onto, with or without a final newline. */
+    #[test]
+    fn parse_continuation_at_end_of_file()
+    {
+        assert_eq!(
+            parse("build.rules".to_string(), "out.o\n:\nin.c\n:\ncc \\\n".to_string()),
+            Err(ParseError::ContinuationAtEndOfFile("build.rules".to_string(), 5)));
+
+        assert_eq!(
+            parse("build.rules".to_string(), "out.o\n:\nin.c\n:\ncc \\".to_string()),
+            Err(ParseError::ContinuationAtEndOfFile("build.rules".to_string(), 5)));
+    }
 }
@@ -11,6 +11,8 @@ use warp::http::
 };
 
 use crate::directory;
+use crate::private;
+use std::sync::Arc;
 
 use crate::ticket::
 {
@@ -47,20 +49,29 @@ pub async fn serve
     SystemType : System + Clone + Send + 'static,
 >
 (
-    mut system : SystemType,
+    system : SystemType,
     directory_path : &str,
+    cache_directory_opt : Option<&str>,
     port : u16
 )
 -> Result<(), ServerError>
 {
     let elements =
-    match directory::init(&mut system, directory_path)
+    match directory::open_readonly(&system, directory_path, cache_directory_opt, None)
     {
         Ok(elements) => elements,
         Err(error) => panic!("Failed to init directory error: {}", error)
     };
 
+    let share_policy =
+    match private::load_share_policy(&system, directory_path, &elements.history)
+    {
+        Ok(share_policy) => Arc::new(share_policy),
+        Err(error) => panic!("Failed to read private rules: {}", error)
+    };
+
     let cache = elements.cache;
+    let files_share_policy = share_policy.clone();
 
     let files_endpoint = warp::get()
         .and(warp::path!("files" / String))
@@ -68,6 +79,13 @@ pub async fn serve
             {
                 match Ticket::from_human_readable(&hash_str)
                 {
+                    Ok(ticket) if ! files_share_policy.allows_file(&ticket) =>
+                    {
+                        println!("Not serving private file: {}", hash_str);
+                        Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(format!("Not found: {}", hash_str).into_bytes())
+                    },
                     Ok(ticket) =>
                     {
                         match cache.open(&ticket)
@@ -136,6 +154,13 @@ pub async fn serve
                     }
                 };
 
+                if ! share_policy.allows_rule(&rule_ticket)
+                {
+                    return Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(format!("Not found: {}", rule_hash_str).into_bytes())
+                }
+
                 let source_ticket =
                 match Ticket::from_human_readable(&source_hash_str)
                 {
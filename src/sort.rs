@@ -1,15 +1,30 @@
-use std::collections::BTreeSet;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use crate::ticket::Ticket;
-use crate::rule::Rule;
+use crate::rule::
+{
+    escape_invisible,
+    get_directory_source,
+    path_has_prefix,
+    strip_invisible,
+    Rule,
+    OutputConstraint,
+    Provenance,
+    SuccessCriteria,
+};
+use serde::
+{
+    Serialize,
+    Deserialize,
+};
 
 use std::fmt;
 
 
 /*  When rules are converted into leaves and nodes as part of the topological sort step,
     This enum gets used to allow each Node to reference its sources either in the vec of nodes.  */
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum SourceIndex
 {
     /*  If the source referenced is a leaf, attach the index of that leaf in 'leaves' */
@@ -26,14 +41,30 @@ pub enum SourceIndex
     indices indicating which other node has the source as a target.
 
     Node also carries an optional Ticket.  If the Node came from a rule,
-    that's the hash of the rule itself (not file content). */
-#[derive(Debug, PartialEq)]
+    that's the hash of the rule itself (not file content).  The provenance
+    says where in the rules files that rule was written.
+
+    Directory sources (dir:PATH) aren't leaves: their content only gets
+    hashed once the rules with targets in them are done, so they're kept
+    as paths in directory_sources, and those rules are in source_indices. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Node
 {
     pub targets: Vec<String>,
+    pub output_constraints : Vec<OutputConstraint>,
     pub source_indices: Vec<SourceIndex>,
+    pub directory_sources: Vec<String>,
     pub command : Vec<String>,
     pub rule_ticket : Ticket,
+    pub provenance : Provenance,
+    pub private : bool,
+    pub precious : bool,
+    pub no_cache : bool,
+    pub install : bool,
+    pub serial : bool,
+    pub group_opt : Option<String>,
+    pub env_allow : Vec<String>,
+    pub success_criteria : SuccessCriteria,
 }
 
 impl fmt::Display for Node
@@ -45,6 +76,7 @@ impl fmt::Display for Node
         {
             write!(f, "{}\n", t).unwrap();
         }
+        write!(f, "{}\n", self.provenance).unwrap();
         write!(f, "{}\n\n", self.rule_ticket).unwrap();
         write!(f, "")
     }
@@ -55,9 +87,19 @@ impl fmt::Display for Node
 struct Frame
 {
     targets: Vec<String>,
+    output_constraints: Vec<OutputConstraint>,
     sources: Vec<String>,
     command: Vec<String>,
     rule_ticket: Ticket,
+    provenance: Provenance,
+    private: bool,
+    precious: bool,
+    no_cache: bool,
+    install: bool,
+    serial: bool,
+    group_opt: Option<String>,
+    env_allow: Vec<String>,
+    success_criteria: SuccessCriteria,
     index: usize,
     sub_index: usize,
     visited: bool,
@@ -71,9 +113,19 @@ impl Frame
         Frame
         {
             targets: rule.targets,
+            output_constraints: rule.output_constraints,
             sources: rule.sources,
             command: rule.command,
             rule_ticket: ticket,
+            provenance: rule.provenance,
+            private: rule.private,
+            precious: rule.precious,
+            no_cache: rule.no_cache,
+            install: rule.install,
+            serial: rule.serial,
+            group_opt: rule.group_opt,
+            env_allow: rule.env_allow,
+            success_criteria: rule.success_criteria,
             index: index,
             sub_index: 0,
             visited: false,
@@ -85,9 +137,19 @@ impl Frame
         return Frame
         {
             targets: self.targets,
+            output_constraints: self.output_constraints,
             sources: self.sources,
             command: self.command,
             rule_ticket: self.rule_ticket,
+            provenance: self.provenance,
+            private: self.private,
+            precious: self.precious,
+            no_cache: self.no_cache,
+            install: self.install,
+            serial: self.serial,
+            group_opt: self.group_opt,
+            env_allow: self.env_allow,
+            success_criteria: self.success_criteria,
             index: self.index,
             sub_index: self.sub_index,
             visited: true
@@ -99,9 +161,13 @@ impl Frame
 pub enum TopologicalSortError
 {
     TargetMissing(String),
-    SelfDependentRule(String),
-    CircularDependence(Vec<String>),
-    TargetInMultipleRules(String),
+    SelfDependentRule(String, Provenance),
+    CircularDependence(Vec<(String, Provenance)>),
+    TargetInMultipleRules(String, Provenance, Provenance),
+    GlobalSourceIsTarget(String, Provenance),
+
+    /*  The goal target isn't a target, but the second path is, and looks just like it. */
+    ConfusableTarget(String, String),
 }
 
 impl fmt::Display for TopologicalSortError
@@ -113,22 +179,29 @@ impl fmt::Display for TopologicalSortError
             TopologicalSortError::TargetMissing(target) =>
                 write!(formatter, "Target missing from rules: {}", target),
 
-            TopologicalSortError::SelfDependentRule(target)  =>
-                write!(formatter, "Self-dependent target: {}", target),
+            TopologicalSortError::SelfDependentRule(target, provenance)  =>
+                write!(formatter, "Path listed as both a target and a source of the rule at {}: {}", provenance, target),
 
             TopologicalSortError::CircularDependence(cycle) =>
             {
                 write!(formatter, "Circular dependence:\n")?;
-                for t in cycle.iter()
+                for (t, provenance) in cycle.iter()
                 {
-                    write!(formatter, "{}\n", t)?;
+                    write!(formatter, "{} ({})\n", t, provenance)?;
                 }
 
                 Ok(())
             },
 
-            TopologicalSortError::TargetInMultipleRules(target) =>
-                write!(formatter, "Target found in more than one rule: {}", target),
+            TopologicalSortError::TargetInMultipleRules(target, first, second) =>
+                write!(formatter, "Target found in more than one rule: {} in rules at {} and {}", target, first, second),
+
+            TopologicalSortError::GlobalSourceIsTarget(path, provenance) =>
+                write!(formatter, "Global source is a target of the rule at {}: {}", provenance, path),
+
+            TopologicalSortError::ConfusableTarget(target, confusable) =>
+                write!(formatter, "Target missing from rules: {} (but there is a target {}, which differs from it \
+only by invisible characters or whitespace, shown as <U+XXXX>)", escape_invisible(target), escape_invisible(confusable)),
         }
     }
 }
@@ -140,6 +213,21 @@ struct FrameBufferValue
     opt_frame: Option<Frame>,
 }
 
+/*  The key a path is looked up by when connecting sources to targets.  With case_insensitive_paths,
+    paths differing only in case get the same key, the way they name the same file on a filesystem
+    that ignores case (the usual one on macOS and Windows). */
+pub fn to_path_key(path : &str, case_insensitive_paths : bool) -> String
+{
+    if case_insensitive_paths
+    {
+        path.to_lowercase()
+    }
+    else
+    {
+        path.to_string()
+    }
+}
+
 /*  Consume Rules, and in their place, make Nodes.
     In each Node, leave 'source_indices' empty.
 
@@ -149,8 +237,9 @@ struct FrameBufferValue
         to_buffer_index:
             A map that tells us the index in frame_buffer of the
             ndoe that has the given string as a target, and also subindex, the index in that
-            node's target list of the target in question  */
-fn rules_to_frame_buffer(mut rules : Vec<Rule>)
+            node's target list of the target in question.  The strings are path keys (see
+            to_path_key). */
+fn rules_to_frame_buffer(mut rules : Vec<Rule>, case_insensitive_paths : bool)
 -> Result<
     (Vec<FrameBufferValue>, HashMap<String, (usize, usize)>),
     TopologicalSortError>
@@ -164,12 +253,34 @@ fn rules_to_frame_buffer(mut rules : Vec<Rule>)
     {
         rule.targets.sort();
         rule.sources.sort();
+
+        /*  A path that's both a target and a source of one rule means the command overwrites its own
+            input, usually a copy-paste slip.  Check every rule up front, not just the ones a goal
+            happens to reach. */
+        for source in rule.sources.iter()
+        {
+            if rule.targets.contains(source)
+            {
+                return Err(TopologicalSortError::SelfDependentRule(source.clone(), rule.provenance.clone()));
+            }
+        }
+
         for (sub_index, target) in rule.targets.iter().enumerate()
         {
-            let t_string = target.to_string();
+            let t_string = to_path_key(target, case_insensitive_paths);
             match to_buffer_index.get(&t_string)
             {
-                Some(_) => return Err(TopologicalSortError::TargetInMultipleRules(t_string)),
+                Some((buffer_index, _sub_index)) =>
+                {
+                    let first_provenance =
+                    match &frame_buffer[*buffer_index].opt_frame
+                    {
+                        Some(frame) => frame.provenance.clone(),
+                        None => rule.provenance.clone(),
+                    };
+                    return Err(TopologicalSortError::TargetInMultipleRules(
+                        target.to_string(), first_provenance, rule.provenance));
+                },
                 None => to_buffer_index.insert(t_string, (current_buffer_index, sub_index)),
             };
         }
@@ -185,10 +296,59 @@ fn rules_to_frame_buffer(mut rules : Vec<Rule>)
     Ok((frame_buffer, to_buffer_index))
 }
 
+/*  The key of the path as it looks, for finding paths that print the same but aren't. */
+fn to_visible_key(path : &str, case_insensitive_paths : bool) -> String
+{
+    to_path_key(&strip_invisible(path), case_insensitive_paths)
+}
+
+/*  The first target in the frame buffer that looks just like path, if any. */
+fn find_confusable_target(
+    frame_buffer : &Vec<FrameBufferValue>,
+    path : &str,
+    case_insensitive_paths : bool) -> Option<String>
+{
+    let path_key = to_visible_key(path, case_insensitive_paths);
+    for value in frame_buffer.iter()
+    {
+        match &value.opt_frame
+        {
+            Some(frame) =>
+            {
+                for target in frame.targets.iter()
+                {
+                    if to_visible_key(target, case_insensitive_paths) == path_key
+                    {
+                        return Some(target.clone());
+                    }
+                }
+            },
+            None => {},
+        }
+    }
+
+    None
+}
+
+/*  Where to find each target that lies in the given directory, ordered by path key, so the edges a
+    directory source makes come out the same every time. */
+fn get_targets_in_directory(
+    to_buffer_index : &HashMap<String, (usize, usize)>,
+    directory : &str,
+    case_insensitive_paths : bool) -> Vec<(usize, usize)>
+{
+    let directory_key = to_path_key(directory, case_insensitive_paths);
+    let mut found : Vec<(&String, &(usize, usize))> = to_buffer_index.iter().filter(
+        |(key, _pair)| path_has_prefix(key, &directory_key)).collect();
+    found.sort();
+    found.into_iter().map(|(_key, pair)| *pair).collect()
+}
+
 struct TopologicalSortMachine
 {
-    /*  Source paths found in one rule that aren't the targets of another rule */
-    source_leaves : BTreeSet<String>,
+    /*  Source paths found in one rule that aren't the targets of another rule, by path key.  When
+        paths differing only in case share a key, the first one found stands for them all. */
+    source_leaves : BTreeMap<String, String>,
 
     /*  The "buffer" referred to by variable-names here is
         the buffer of frames (frame_buffer) */
@@ -199,6 +359,8 @@ struct TopologicalSortMachine
         - index of the target in the rule's target list */
     to_buffer_index : HashMap<String, (usize, usize)>,
 
+    case_insensitive_paths : bool,
+
     /*  Recall frame_buffer is a vector of options.  That's so that
         the frames can be taken from frame_buffer and added to frames_in_order */
     frames_in_order : Vec<Frame>,
@@ -210,15 +372,17 @@ impl TopologicalSortMachine
 {
     pub fn new(
         frame_buffer : Vec<FrameBufferValue>,
-        to_buffer_index : HashMap<String, (usize, usize)>
+        to_buffer_index : HashMap<String, (usize, usize)>,
+        case_insensitive_paths : bool
     )
     -> Self
     {
         TopologicalSortMachine
         {
-            source_leaves : BTreeSet::new(),
+            source_leaves : BTreeMap::new(),
             frame_buffer : frame_buffer,
             to_buffer_index : to_buffer_index,
+            case_insensitive_paths : case_insensitive_paths,
             frames_in_order : vec![],
         }
     }
@@ -263,42 +427,52 @@ impl TopologicalSortMachine
                 let mut reverser = vec![];
                 for source in frame.sources.iter()
                 {
-                    match self.to_buffer_index.get(source)
+                    let source_key = to_path_key(source, self.case_insensitive_paths);
+                    let connections =
+                    match get_directory_source(source)
                     {
-                        Some((buffer_index, sub_index)) =>
+                        Some(directory) => get_targets_in_directory(
+                            &self.to_buffer_index, &directory, self.case_insensitive_paths),
+                        None => match self.to_buffer_index.get(&source_key)
                         {
-                            if let Some(mut frame) = self.frame_buffer[*buffer_index].opt_frame.take()
+                            Some(pair) => vec![*pair],
+                            None =>
                             {
-                                frame.sub_index = *sub_index;
-                                reverser.push(frame);
+                                self.source_leaves.entry(source_key).or_insert(source.to_owned());
+                                vec![]
+                            },
+                        },
+                    };
+
+                    for (buffer_index, sub_index) in connections.iter()
+                    {
+                        if let Some(mut frame) = self.frame_buffer[*buffer_index].opt_frame.take()
+                        {
+                            frame.sub_index = *sub_index;
+                            reverser.push(frame);
+                        }
+                        else
+                        {
+                            if frame.index == *buffer_index
+                            {
+                                return Err(TopologicalSortError::SelfDependentRule(
+                                    frame.targets[*sub_index].clone(), frame.provenance.clone()));
                             }
-                            else
+
+                            /*  Look for a cycle by checking the stack for another instance of the node we're
+                                currently on */
+                            if indices_in_stack.contains(buffer_index)
                             {
-                                if frame.index == *buffer_index
+                                let mut target_cycle = vec![];
+                                for f in stack.iter()
                                 {
-                                    return Err(TopologicalSortError::SelfDependentRule(
-                                        frame.targets[*sub_index].clone()));
+                                    target_cycle.push((f.targets[f.sub_index].clone(), f.provenance.clone()));
                                 }
+                                target_cycle.push((frame.targets[frame.sub_index].clone(), frame.provenance.clone()));
 
-                                /*  Look for a cycle by checking the stack for another instance of the node we're
-                                    currently on */
-                                if indices_in_stack.contains(buffer_index)
-                                {
-                                    let mut target_cycle = vec![];
-                                    for f in stack.iter()
-                                    {
-                                        target_cycle.push(f.targets[f.sub_index].clone());
-                                    }
-                                    target_cycle.push(frame.targets[frame.sub_index].clone());
-
-                                    return Err(TopologicalSortError::CircularDependence(target_cycle));
-                                }
+                                return Err(TopologicalSortError::CircularDependence(target_cycle));
                             }
-                        },
-                        None =>
-                        {
-                            self.source_leaves.insert(source.to_owned());
-                        },
+                        }
                     }
                 }
 
@@ -325,19 +499,33 @@ impl TopologicalSortMachine
         let mut leaves = Vec::new();
         let mut leaf_to_index = HashMap::new();
 
-        for leaf in self.source_leaves
+        for (leaf_key, leaf) in self.source_leaves
         {
-            leaves.push(leaf.clone());
-            leaf_to_index.insert(leaf, num_leaves);
+            leaves.push(leaf);
+            leaf_to_index.insert(leaf_key, num_leaves);
             num_leaves += 1;
         }
 
         for mut frame in self.frames_in_order.drain(..)
         {
             let mut source_indices = vec![];
+            let mut directory_sources = vec![];
             for source in frame.sources.drain(..)
             {
-                match leaf_to_index.get(&source)
+                if let Some(directory) = get_directory_source(&source)
+                {
+                    for (buffer_index, sub_index) in get_targets_in_directory(
+                        &self.to_buffer_index, &directory, self.case_insensitive_paths)
+                    {
+                        source_indices.push(SourceIndex::Pair(
+                            self.frame_buffer[buffer_index].final_index, sub_index));
+                    }
+                    directory_sources.push(directory);
+                    continue;
+                }
+
+                let source_key = to_path_key(&source, self.case_insensitive_paths);
+                match leaf_to_index.get(&source_key)
                 {
                     Some(index) =>
                     {
@@ -345,7 +533,7 @@ impl TopologicalSortMachine
                     },
                     None =>
                     {
-                        let (buffer_index, sub_index) = self.to_buffer_index.get(&source).unwrap();
+                        let (buffer_index, sub_index) = self.to_buffer_index.get(&source_key).unwrap();
                         source_indices.push(SourceIndex::Pair(
                             self.frame_buffer[*buffer_index].final_index, *sub_index));
                     }
@@ -356,9 +544,20 @@ impl TopologicalSortMachine
                 Node
                 {
                     targets: frame.targets,
+                    output_constraints: frame.output_constraints,
                     source_indices: source_indices,
+                    directory_sources: directory_sources,
                     command: frame.command,
                     rule_ticket: frame.rule_ticket,
+                    provenance: frame.provenance,
+                    private: frame.private,
+                    precious: frame.precious,
+                    no_cache: frame.no_cache,
+                    install: frame.install,
+                    serial: frame.serial,
+                    group_opt: frame.group_opt,
+                    env_allow: frame.env_allow,
+                    success_criteria: frame.success_criteria,
                 }
             );
         }
@@ -367,11 +566,14 @@ impl TopologicalSortMachine
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NodePack
 {
     pub leaves: Vec<String>,
     pub nodes: Vec<Node>,
+
+    /*  Indices into leaves of the files every node depends on, after its own sources. */
+    pub global_source_indices: Vec<usize>,
 }
 
 impl NodePack
@@ -383,6 +585,7 @@ impl NodePack
         {
             leaves: Vec::new(),
             nodes: Vec::new(),
+            global_source_indices: Vec::new(),
         }
     }
 
@@ -392,8 +595,65 @@ impl NodePack
         {
             leaves: leaves,
             nodes: nodes,
+            global_source_indices: Vec::new(),
         }
     }
+
+    /*  Makes every node depend on each of the given paths, in order.  A path that's already a leaf
+        stays where it is, otherwise it becomes a new leaf.  Global sources have to be files nothing
+        builds, since the rule building one would have to depend on it. */
+    pub fn add_global_sources(&mut self, paths : Vec<String>) -> Result<(), TopologicalSortError>
+    {
+        for path in paths
+        {
+            for node in self.nodes.iter()
+            {
+                if node.targets.contains(&path)
+                {
+                    return Err(TopologicalSortError::GlobalSourceIsTarget(path, node.provenance.clone()));
+                }
+            }
+
+            let leaf_index =
+            match self.leaves.iter().position(|leaf| *leaf == path)
+            {
+                Some(leaf_index) => leaf_index,
+                None =>
+                {
+                    self.leaves.push(path);
+                    self.leaves.len() - 1
+                },
+            };
+
+            if ! self.global_source_indices.contains(&leaf_index)
+            {
+                self.global_source_indices.push(leaf_index);
+            }
+        }
+
+        Ok(())
+    }
+
+    /*  The paths of the global sources, in order. */
+    pub fn get_global_source_paths(&self) -> Vec<String>
+    {
+        self.global_source_indices.iter().map(|i| self.leaves[*i].clone()).collect()
+    }
+
+    /*  The paths of the sources of the node at node_index, in the order the rule lists them. */
+    pub fn get_source_paths(&self, node_index : usize) -> Vec<String>
+    {
+        self.nodes[node_index].source_indices.iter().map(
+            |source_index|
+            {
+                match source_index
+                {
+                    SourceIndex::Leaf(i) => self.leaves[*i].clone(),
+                    SourceIndex::Pair(i, sub_index) => self.nodes[*i].targets[*sub_index].clone(),
+                }
+            }
+        ).collect()
+    }
 }
 
 /*  Takes a vector of Rules and goal_target, goal target is the target in whose rule the
@@ -404,31 +664,58 @@ impl NodePack
 
     Leaves (sources which are not also listed as targets) become Nodes with a non-existant
     RuleInfo and an empty list of sources. */
+#[cfg(test)]
 pub fn topological_sort(
     rules : Vec<Rule>,
     goal_target : &str) -> Result<NodePack, TopologicalSortError>
 {
-    let (frame_buffer, to_buffer_index) = rules_to_frame_buffer(rules)?;
+    topological_sort_with_path_case(rules, goal_target, false)
+}
+
+/*  Like topological_sort, but with case_insensitive_paths, a source connects to a target that differs
+    from it only in case (the goal target too). */
+pub fn topological_sort_with_path_case(
+    rules : Vec<Rule>,
+    goal_target : &str,
+    case_insensitive_paths : bool) -> Result<NodePack, TopologicalSortError>
+{
+    let (frame_buffer, to_buffer_index) = rules_to_frame_buffer(rules, case_insensitive_paths)?;
+    let goal_pair_opt = to_buffer_index.get(&to_path_key(goal_target, case_insensitive_paths)).cloned();
     let (index, sub_index) =
-    match to_buffer_index.get(goal_target)
+    match goal_pair_opt
     {
-        Some((index, sub_index)) => (*index, *sub_index),
-        None => return Err(TopologicalSortError::TargetMissing(goal_target.to_string())),
+        Some((index, sub_index)) => (index, sub_index),
+        None => return Err(
+            match find_confusable_target(&frame_buffer, goal_target, case_insensitive_paths)
+            {
+                Some(target) => TopologicalSortError::ConfusableTarget(goal_target.to_string(), target),
+                None => TopologicalSortError::TargetMissing(goal_target.to_string()),
+            }),
     };
 
-    let mut machine = TopologicalSortMachine::new(frame_buffer, to_buffer_index);
+    let mut machine = TopologicalSortMachine::new(frame_buffer, to_buffer_index, case_insensitive_paths);
+
     machine.sort_once(index, sub_index)?;
     machine.get_result()
 }
 
 /*  For building all targets.  This function calls rules_to_frame_buffer to generate frames for the rules,
     then iterates through all the frames */
+#[cfg(test)]
 pub fn topological_sort_all(
     rules : Vec<Rule>) -> Result<NodePack, TopologicalSortError>
 {
-    let (frame_buffer, to_buffer_index) = rules_to_frame_buffer(rules)?;
+    topological_sort_all_with_path_case(rules, false)
+}
+
+/*  Like topological_sort_all, with case_insensitive_paths as in topological_sort_with_path_case. */
+pub fn topological_sort_all_with_path_case(
+    rules : Vec<Rule>,
+    case_insensitive_paths : bool) -> Result<NodePack, TopologicalSortError>
+{
+    let (frame_buffer, to_buffer_index) = rules_to_frame_buffer(rules, case_insensitive_paths)?;
     let frame_buffer_len = frame_buffer.len();
-    let mut machine = TopologicalSortMachine::new(frame_buffer, to_buffer_index);
+    let mut machine = TopologicalSortMachine::new(frame_buffer, to_buffer_index, case_insensitive_paths);
     for index in 0..frame_buffer_len
     {
         machine.sort_once(index, 0)?;
@@ -440,7 +727,12 @@ pub fn topological_sort_all(
 #[cfg(test)]
 mod tests
 {
-    use crate::rule::Rule;
+    use crate::rule::
+    {
+        Rule,
+        Provenance,
+        SuccessCriteria,
+    };
     use crate::sort::
     {
         Node,
@@ -449,6 +741,8 @@ mod tests
         rules_to_frame_buffer,
         topological_sort,
         topological_sort_all,
+        topological_sort_all_with_path_case,
+        topological_sort_with_path_case,
         TopologicalSortError,
     };
 
@@ -458,7 +752,7 @@ mod tests
     #[test]
     fn rules_to_frame_buffer_empty_to_empty()
     {
-        match rules_to_frame_buffer(vec![])
+        match rules_to_frame_buffer(vec![], false)
         {
             Ok((frame_buffer, to_frame_buffer_index)) =>
             {
@@ -479,11 +773,21 @@ mod tests
                     Rule
                     {
                         targets: vec!["plant".to_string(), "tangerine".to_string()],
+                        output_constraints: vec![],
                         sources: vec!["seed".to_string(), "soil".to_string()],
                         command: vec!["water every day".to_string()],
+                        provenance: Provenance::new("", 0),
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
-                ]
-            )
+                ],
+                false)
         {
             Ok((frame_buffer, to_frame_buffer_index)) =>
             {
@@ -555,17 +859,37 @@ mod tests
                 Rule
                 {
                     targets: vec!["fruit".to_string()],
+                    output_constraints: vec![],
                     sources: vec!["plant".to_string()],
                     command: vec!["pick occasionally".to_string()],
+                    provenance: Provenance::new("", 0),
+                    private: false,
+                    precious: false,
+                    no_cache: false,
+                    install: false,
+                    serial: false,
+                    group_opt: None,
+                    env_allow: vec![],
+                    success_criteria: SuccessCriteria::new(),
                 },
                 Rule
                 {
                     targets: vec!["plant".to_string()],
+                    output_constraints: vec![],
                     sources: vec!["soil".to_string(), "seed".to_string()],
                     command: vec!["water every day".to_string()],
+                    provenance: Provenance::new("", 0),
+                    private: false,
+                    precious: false,
+                    no_cache: false,
+                    install: false,
+                    serial: false,
+                    group_opt: None,
+                    env_allow: vec![],
+                    success_criteria: SuccessCriteria::new(),
                 },
-            ]
-        )
+            ],
+            false)
         {
             Ok((frame_buffer, to_frame_buffer_index)) =>
             {
@@ -589,17 +913,40 @@ mod tests
                 Rule
                 {
                     targets: vec!["fruit".to_string()],
+                    output_constraints: vec![],
                     sources: vec!["plant".to_string()],
                     command: vec!["pick occasionally".to_string()],
+                    provenance: Provenance::new("fruit.rules", 1),
+                    private: false,
+                    precious: false,
+                    no_cache: false,
+                    install: false,
+                    serial: false,
+                    group_opt: None,
+                    env_allow: vec![],
+                    success_criteria: SuccessCriteria::new(),
                 },
                 Rule
                 {
                     targets: vec!["plant".to_string(), "fruit".to_string()],
+                    output_constraints: vec![],
                     sources: vec!["soil".to_string(), "seed".to_string()],
                     command: vec!["water every day".to_string()],
+                    provenance: Provenance::new("fruit.rules", 7),
+                    private: false,
+                    precious: false,
+                    no_cache: false,
+                    install: false,
+                    serial: false,
+                    group_opt: None,
+                    env_allow: vec![],
+                    success_criteria: SuccessCriteria::new(),
                 },
-            ]
-        ), Err(TopologicalSortError::TargetInMultipleRules("fruit".to_string())));
+            ],
+            false), Err(TopologicalSortError::TargetInMultipleRules(
+            "fruit".to_string(),
+            Provenance::new("fruit.rules", 1),
+            Provenance::new("fruit.rules", 7))));
     }
 
     /*  Topological sort the empty set of rules, but with a goal-target.  That should error. */
@@ -609,6 +956,22 @@ mod tests
         assert_eq!(topological_sort(vec![], "prune"), Err(TopologicalSortError::TargetMissing("prune".to_string())));
     }
 
+    /*  A goal target that misses a target only by an invisible character gets an error naming the
+        target it looks like. */
+    #[test]
+    fn topological_sort_confusable_names_the_pair()
+    {
+        let library_rule = Rule::new(
+            vec!["libfoo.a\u{a0}".to_string()],
+            vec!["foo.o".to_string()],
+            vec![],
+        );
+
+        let error = topological_sort(vec![library_rule], "libfoo.a").unwrap_err();
+        assert_eq!(error, TopologicalSortError::ConfusableTarget("libfoo.a".to_string(), "libfoo.a\u{a0}".to_string()));
+        assert!(format!("{}", error).contains("libfoo.a<U+00A0>"));
+    }
+
     /*  Topological sort all of an empty set of rules, check that the result is empty. */
     #[test]
     fn topological_sort_all_empty_is_empty()
@@ -635,9 +998,20 @@ mod tests
                     Node
                     {
                         targets: vec!["plant".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![],
+                        directory_sources: vec![],
                         command : vec![],
                         rule_ticket : rule.get_ticket(),
+                        provenance: rule.provenance.clone(),
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     }
                 ]
             ))
@@ -662,9 +1036,20 @@ mod tests
                     Node
                     {
                         targets: vec!["plant".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![],
+                        directory_sources: vec![],
                         command: vec![],
                         rule_ticket : rule.get_ticket(),
+                        provenance: rule.provenance.clone(),
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     }
                 ]
             ))
@@ -698,15 +1083,37 @@ mod tests
             vec![
                 Node{
                     targets: vec!["plant".to_string()],
+                    output_constraints: vec![],
                     source_indices: vec![],
+                    directory_sources: vec![],
                     command: vec![],
                     rule_ticket : plant_rule.get_ticket(),
+                    provenance: plant_rule.provenance.clone(),
+                    private: false,
+                    precious: false,
+                    no_cache: false,
+                    install: false,
+                    serial: false,
+                    group_opt: None,
+                    env_allow: vec![],
+                    success_criteria: SuccessCriteria::new(),
                 },
                 Node{
                     targets: vec!["fruit".to_string()],
+                    output_constraints: vec![],
                     source_indices: vec![SourceIndex::Pair(0, 0)],
+                    directory_sources: vec![],
                     command: vec!["pick occasionally".to_string()],
                     rule_ticket : fruit_rule.get_ticket(),
+                    provenance: fruit_rule.provenance.clone(),
+                    private: false,
+                    precious: false,
+                    no_cache: false,
+                    install: false,
+                    serial: false,
+                    group_opt: None,
+                    env_allow: vec![],
+                    success_criteria: SuccessCriteria::new(),
                 },
             ])
         ));
@@ -720,15 +1127,35 @@ mod tests
         let fruit_rule = Rule
         {
             targets: vec!["fruit".to_string()],
+            output_constraints: vec![],
             sources: vec!["plant".to_string()],
             command: vec!["pick occasionally".to_string()],
+            provenance: Provenance::new("", 0),
+            private: false,
+            precious: false,
+            no_cache: false,
+            install: false,
+            serial: false,
+            group_opt: None,
+            env_allow: vec![],
+            success_criteria: SuccessCriteria::new(),
         };
 
         let plant_rule = Rule
         {
             targets: vec!["plant".to_string()],
+            output_constraints: vec![],
             sources: vec![],
             command: vec!["take care of plant".to_string()],
+            provenance: Provenance::new("", 0),
+            private: false,
+            precious: false,
+            no_cache: false,
+            install: false,
+            serial: false,
+            group_opt: None,
+            env_allow: vec![],
+            success_criteria: SuccessCriteria::new(),
         };
 
         assert_eq!(topological_sort_all(
@@ -742,16 +1169,38 @@ mod tests
                     Node
                     {
                         targets: vec!["plant".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![],
+                        directory_sources: vec![],
                         rule_ticket: plant_rule.get_ticket(),
+                        provenance: plant_rule.provenance.clone(),
                         command: vec!["take care of plant".to_string()],
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                     Node
                     {
                         targets: vec!["fruit".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Pair(0,0)],
+                        directory_sources: vec![],
                         rule_ticket: fruit_rule.get_ticket(),
+                        provenance: fruit_rule.provenance.clone(),
                         command: vec!["pick occasionally".to_string()],
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                 ]
             ))
@@ -766,26 +1215,66 @@ mod tests
         let math_rule = Rule
         {
             targets: vec!["math".to_string()],
+            output_constraints: vec![],
             sources: vec![],
             command: vec!["build math".to_string()],
+            provenance: Provenance::new("", 0),
+            private: false,
+            precious: false,
+            no_cache: false,
+            install: false,
+            serial: false,
+            group_opt: None,
+            env_allow: vec![],
+            success_criteria: SuccessCriteria::new(),
         };
         let graphics_rule = Rule
         {
             targets: vec!["graphics".to_string()],
+            output_constraints: vec![],
             sources: vec!["math".to_string()],
             command: vec!["build graphics".to_string()],
+            provenance: Provenance::new("", 0),
+            private: false,
+            precious: false,
+            no_cache: false,
+            install: false,
+            serial: false,
+            group_opt: None,
+            env_allow: vec![],
+            success_criteria: SuccessCriteria::new(),
         };
         let physics_rule = Rule
         {
             targets: vec!["physics".to_string()],
+            output_constraints: vec![],
             sources: vec!["math".to_string()],
             command: vec!["build physics".to_string()],
+            provenance: Provenance::new("", 0),
+            private: false,
+            precious: false,
+            no_cache: false,
+            install: false,
+            serial: false,
+            group_opt: None,
+            env_allow: vec![],
+            success_criteria: SuccessCriteria::new(),
         };
         let game_rule = Rule
         {
             targets: vec!["game".to_string()],
+            output_constraints: vec![],
             sources: vec!["graphics".to_string(), "physics".to_string()],
             command: vec!["build game".to_string()],
+            provenance: Provenance::new("", 0),
+            private: false,
+            precious: false,
+            no_cache: false,
+            install: false,
+            serial: false,
+            group_opt: None,
+            env_allow: vec![],
+            success_criteria: SuccessCriteria::new(),
         };
 
         assert_eq!(topological_sort(
@@ -802,30 +1291,74 @@ mod tests
                     Node
                     {
                         targets: vec!["math".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![],
+                        directory_sources: vec![],
                         rule_ticket: math_rule.get_ticket(),
+                        provenance: math_rule.provenance.clone(),
                         command: vec!["build math".to_string()],
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                     Node
                     {
                         targets: vec!["graphics".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Pair(0, 0)],
+                        directory_sources: vec![],
                         rule_ticket: graphics_rule.get_ticket(),
+                        provenance: graphics_rule.provenance.clone(),
                         command: vec!["build graphics".to_string()],
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                     Node
                     {
                         targets: vec!["physics".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Pair(0, 0)],
+                        directory_sources: vec![],
                         rule_ticket: physics_rule.get_ticket(),
+                        provenance: physics_rule.provenance.clone(),
                         command: vec!["build physics".to_string()],
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                     Node
                     {
                         targets: vec!["game".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Pair(1, 0), SourceIndex::Pair(2, 0),],
+                        directory_sources: vec![],
                         rule_ticket: game_rule.get_ticket(),
+                        provenance: game_rule.provenance.clone(),
                         command: vec!["build game".to_string()],
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                 ]
             )
@@ -875,30 +1408,74 @@ mod tests
                     Node
                     {
                         targets: vec!["math".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![],
+                        directory_sources: vec![],
                         rule_ticket: math_rule.get_ticket(),
+                        provenance: math_rule.provenance.clone(),
                         command: vec!["build math".to_string()],
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                     Node
                     {
                         targets: vec!["graphics".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Pair(0, 0)],
+                        directory_sources: vec![],
                         rule_ticket: graphics_rule.get_ticket(),
+                        provenance: graphics_rule.provenance.clone(),
                         command: vec!["build graphics".to_string()],
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                     Node
                     {
                         targets: vec!["physics".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Pair(0, 0)],
+                        directory_sources: vec![],
                         rule_ticket: physics_rule.get_ticket(),
+                        provenance: physics_rule.provenance.clone(),
                         command: vec!["build physics".to_string()],
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                     Node
                     {
                         targets: vec!["game".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Pair(1, 0), SourceIndex::Pair(2, 0),],
+                        directory_sources: vec![],
                         rule_ticket: game_rule.get_ticket(),
+                        provenance: game_rule.provenance.clone(),
                         command: vec!["build game".to_string()],
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                 ]
             )
@@ -948,23 +1525,56 @@ mod tests
                     Node
                     {
                         targets: vec!["stanza1".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Leaf(0), SourceIndex::Leaf(1)],
+                        directory_sources: vec![],
                         command: vec!["poemcat verse1 chorus".to_string()],
                         rule_ticket: stanza1_rule.get_ticket(),
+                        provenance: stanza1_rule.provenance.clone(),
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                     Node
                     {
                         targets: vec!["stanza2".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Leaf(0), SourceIndex::Leaf(2)],
+                        directory_sources: vec![],
                         command: vec!["poemcat verse2 chorus".to_string()],
                         rule_ticket: stanza2_rule.get_ticket(),
+                        provenance: stanza2_rule.provenance.clone(),
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                     Node
                     {
                         targets: vec!["poem".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Pair(0, 0), SourceIndex::Pair(1, 0)],
+                        directory_sources: vec![],
                         command: vec!["poemcat stanza1 stanza2".to_string()],
                         rule_ticket: poem_rule.get_ticket(),
+                        provenance: poem_rule.provenance.clone(),
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     }
                 ]
             ))
@@ -1010,23 +1620,56 @@ mod tests
                     Node
                     {
                         targets: vec!["stanza1".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Leaf(0), SourceIndex::Leaf(1)],
+                        directory_sources: vec![],
                         command: vec!["poemcat verse1 chorus".to_string()],
                         rule_ticket: stanza1_rule.get_ticket(),
+                        provenance: stanza1_rule.provenance.clone(),
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                     Node
                     {
                         targets: vec!["stanza2".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Leaf(0), SourceIndex::Leaf(2)],
+                        directory_sources: vec![],
                         command: vec!["poemcat verse2 chorus".to_string()],
                         rule_ticket: stanza2_rule.get_ticket(),
+                        provenance: stanza2_rule.provenance.clone(),
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                     Node
                     {
                         targets: vec!["poem".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Pair(0, 0), SourceIndex::Pair(1, 0)],
+                        directory_sources: vec![],
                         command: vec!["poemcat stanza1 stanza2".to_string()],
                         rule_ticket: poem_rule.get_ticket(),
+                        provenance: poem_rule.provenance.clone(),
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     }
                 ]
             ))
@@ -1072,23 +1715,56 @@ mod tests
                     Node
                     {
                         targets: vec!["stanza1".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Leaf(0), SourceIndex::Leaf(1)],
+                        directory_sources: vec![],
                         command: vec!["poemcat verse1 chorus".to_string()],
                         rule_ticket: stanza1_rule.get_ticket(),
+                        provenance: stanza1_rule.provenance.clone(),
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                     Node
                     {
                         targets: vec!["stanza2".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Leaf(0), SourceIndex::Leaf(2)],
+                        directory_sources: vec![],
                         command: vec!["poemcat verse2 chorus".to_string()],
                         rule_ticket: stanza2_rule.get_ticket(),
+                        provenance: stanza2_rule.provenance.clone(),
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                     Node
                     {
                         targets: vec!["poem".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Pair(0, 0), SourceIndex::Pair(1, 0)],
+                        directory_sources: vec![],
                         command: vec!["poemcat stanza1 stanza2".to_string()],
                         rule_ticket: poem_rule.get_ticket(),
+                        provenance: poem_rule.provenance.clone(),
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     }
                 ]
             ))
@@ -1125,16 +1801,38 @@ mod tests
                     Node
                     {
                         targets: vec!["cookies".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Leaf(0)],
+                        directory_sources: vec![],
                         command: vec!["bake cookies".to_string()],
                         rule_ticket: cookie_rule.get_ticket(),
+                        provenance: cookie_rule.provenance.clone(),
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                     Node
                     {
                         targets: vec!["poem".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Leaf(1)],
+                        directory_sources: vec![],
                         command: vec!["poemcat stanza1".to_string()],
                         rule_ticket: poem_rule.get_ticket(),
+                        provenance: poem_rule.provenance.clone(),
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     }
                 ]
             ))
@@ -1170,9 +1868,20 @@ mod tests
                     Node
                     {
                         targets: vec!["poem".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Leaf(0)],
+                        directory_sources: vec![],
                         command: vec!["poemcat stanza1".to_string()],
                         rule_ticket: poem_rule.get_ticket(),
+                        provenance: poem_rule.provenance.clone(),
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     }
                 ]
             ))
@@ -1189,14 +1898,34 @@ mod tests
                 Rule
                 {
                     targets: vec!["Quine".to_string(), "SomethingElse".to_string()],
+                    output_constraints: vec![],
                     sources: vec!["Hofstadter".to_string()],
                     command: vec!["poemcat Hofstadter".to_string()],
+                    provenance: Provenance::new("quine.rules", 1),
+                    private: false,
+                    precious: false,
+                    no_cache: false,
+                    install: false,
+                    serial: false,
+                    group_opt: None,
+                    env_allow: vec![],
+                    success_criteria: SuccessCriteria::new(),
                 },
                 Rule
                 {
                     targets: vec!["AnotherThing".to_string(), "Hofstadter".to_string()],
+                    output_constraints: vec![],
                     sources: vec!["Quine".to_string()],
                     command: vec!["poemcat Quine".to_string()],
+                    provenance: Provenance::new("quine.rules", 8),
+                    private: false,
+                    precious: false,
+                    no_cache: false,
+                    install: false,
+                    serial: false,
+                    group_opt: None,
+                    env_allow: vec![],
+                    success_criteria: SuccessCriteria::new(),
                 },
             ],
             "Quine")
@@ -1208,8 +1937,8 @@ mod tests
                 {
                     TopologicalSortError::CircularDependence(cycle) =>
                     {
-                        assert_eq!(cycle[0], "Quine");
-                        assert_eq!(cycle[1], "Hofstadter");
+                        assert_eq!(cycle[0], ("Quine".to_string(), Provenance::new("quine.rules", 1)));
+                        assert_eq!(cycle[1], ("Hofstadter".to_string(), Provenance::new("quine.rules", 8)));
                     },
                     _ => panic!("Expected circular dependence, got another type of error")
                 }
@@ -1227,8 +1956,18 @@ mod tests
                 Rule
                 {
                     targets: vec!["Hofstadter".to_string()],
+                    output_constraints: vec![],
                     sources: vec!["Hofstadter".to_string()],
                     command: vec!["poemcat Hofstadter".to_string()],
+                    provenance: Provenance::new("hofstadter.rules", 3),
+                    private: false,
+                    precious: false,
+                    no_cache: false,
+                    install: false,
+                    serial: false,
+                    group_opt: None,
+                    env_allow: vec![],
+                    success_criteria: SuccessCriteria::new(),
                 },
             ],
             "Hofstadter")
@@ -1238,7 +1977,11 @@ mod tests
             {
                 match error
                 {
-                    TopologicalSortError::SelfDependentRule(target) => assert_eq!(target, "Hofstadter"),
+                    TopologicalSortError::SelfDependentRule(target, provenance) =>
+                    {
+                        assert_eq!(target, "Hofstadter");
+                        assert_eq!(provenance, Provenance::new("hofstadter.rules", 3));
+                    },
                     _ => panic!("Expected self-dependent rule, got another type of error")
                 }
             },
@@ -1253,13 +1996,24 @@ mod tests
         let fruit_rule = Rule
         {
             targets: vec!["fruit".to_string()],
+            output_constraints: vec![],
             sources: vec!["plant".to_string()],
             command: vec!["pick occasionally".to_string()],
+            provenance: Provenance::new("", 0),
+            private: false,
+            precious: false,
+            no_cache: false,
+            install: false,
+            serial: false,
+            group_opt: None,
+            env_allow: vec![],
+            success_criteria: SuccessCriteria::new(),
         };
 
         let plant_rule = Rule
         {
             targets: vec!["plant".to_string()],
+            output_constraints: vec![],
             sources: vec![
                 "seed".to_string(),
                 "soil".to_string(),
@@ -1267,6 +2021,15 @@ mod tests
                 "water".to_string(),
             ],
             command: vec!["take care of plant".to_string()],
+            provenance: Provenance::new("", 0),
+            private: false,
+            precious: false,
+            no_cache: false,
+            install: false,
+            serial: false,
+            group_opt: None,
+            env_allow: vec![],
+            success_criteria: SuccessCriteria::new(),
         };
 
         assert_eq!(topological_sort(
@@ -1286,24 +2049,132 @@ mod tests
                     Node
                     {
                         targets: vec!["plant".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![
                             SourceIndex::Leaf(0),
                             SourceIndex::Leaf(1),
                             SourceIndex::Leaf(2),
                             SourceIndex::Leaf(3)
                         ],
+                        directory_sources: vec![],
                         rule_ticket: plant_rule.get_ticket(),
+                        provenance: plant_rule.provenance.clone(),
                         command: vec!["take care of plant".to_string()],
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                     Node
                     {
                         targets: vec!["fruit".to_string()],
+                        output_constraints: vec![],
                         source_indices: vec![SourceIndex::Pair(0, 0)],
+                        directory_sources: vec![],
                         rule_ticket: fruit_rule.get_ticket(),
+                        provenance: fruit_rule.provenance.clone(),
                         command: vec!["pick occasionally".to_string()],
+                        private: false,
+                        precious: false,
+                        no_cache: false,
+                        install: false,
+                        serial: false,
+                        group_opt: None,
+                        env_allow: vec![],
+                        success_criteria: SuccessCriteria::new(),
                     },
                 ]
             ))
         );
     }
-}
\ No newline at end of file
+
+    /*  A rule listing poem.txt as both target and source fails the sort with a message saying so,
+        even when the goal is a different rule that never reaches it. */
+    #[test]
+    fn topological_sort_target_also_source()
+    {
+        let rules = vec![
+            Rule
+            {
+                targets: vec!["stanza.txt".to_string()],
+                output_constraints: vec![],
+                sources: vec!["verse.txt".to_string()],
+                command: vec!["mycat".to_string()],
+                provenance: Provenance::new("build.rules", 1),
+                private: false,
+                precious: false,
+                no_cache: false,
+                install: false,
+                serial: false,
+                group_opt: None,
+                env_allow: vec![],
+                success_criteria: SuccessCriteria::new(),
+            },
+            Rule
+            {
+                targets: vec!["poem.txt".to_string(), "title.txt".to_string()],
+                output_constraints: vec![],
+                sources: vec!["stanza.txt".to_string(), "poem.txt".to_string()],
+                command: vec!["mycat".to_string()],
+                provenance: Provenance::new("build.rules", 8),
+                private: false,
+                precious: false,
+                no_cache: false,
+                install: false,
+                serial: false,
+                group_opt: None,
+                env_allow: vec![],
+                success_criteria: SuccessCriteria::new(),
+            },
+        ];
+
+        let expected = TopologicalSortError::SelfDependentRule(
+            "poem.txt".to_string(), Provenance::new("build.rules", 8));
+
+        assert_eq!(topological_sort_all(rules.clone()), Err(expected));
+        match topological_sort(rules, "stanza.txt")
+        {
+            Err(error) => assert_eq!(format!("{}", error),
+                "Path listed as both a target and a source of the rule at build.rules:8: poem.txt"),
+            Ok(_) => panic!("Unexpected success sorting with a rule that lists its target as a source"),
+        }
+    }
+
+    /*  A rule reading Stanza.txt and a rule writing stanza.txt.  Ordinarily those are different paths,
+        so Stanza.txt is a leaf.  Ignoring case, the first rule depends on the second, and the leaf goes. */
+    #[test]
+    fn topological_sort_all_case_insensitive_paths()
+    {
+        let stanza_rule = Rule::new(
+            vec!["stanza.txt".to_string()],
+            vec!["verse.txt".to_string()],
+            vec!["mycat verse.txt stanza.txt".to_string()],
+        );
+
+        let poem_rule = Rule::new(
+            vec!["poem.txt".to_string()],
+            vec!["Stanza.txt".to_string()],
+            vec!["mycat Stanza.txt poem.txt".to_string()],
+        );
+
+        let node_pack = topological_sort_all_with_path_case(vec![poem_rule.clone(), stanza_rule.clone()], false).unwrap();
+        assert_eq!(node_pack.leaves, vec!["Stanza.txt".to_string(), "verse.txt".to_string()]);
+
+        let node_pack = topological_sort_all_with_path_case(vec![poem_rule.clone(), stanza_rule.clone()], true).unwrap();
+        assert_eq!(node_pack.leaves, vec!["verse.txt".to_string()]);
+        assert_eq!(node_pack.nodes.len(), 2);
+        assert_eq!(node_pack.nodes[0].targets, vec!["stanza.txt".to_string()]);
+        assert_eq!(node_pack.nodes[1].targets, vec!["poem.txt".to_string()]);
+        assert_eq!(node_pack.nodes[1].source_indices, vec![SourceIndex::Pair(0, 0)]);
+
+        match topological_sort_with_path_case(vec![poem_rule, stanza_rule], "POEM.TXT", true)
+        {
+            Ok(node_pack) => assert_eq!(node_pack.nodes.len(), 2),
+            Err(error) => panic!("Unexpected error sorting for POEM.TXT ignoring case: {}", error),
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use termcolor::Color;
+use crate::build::
+{
+    get_nodes,
+    BuildError,
+    BuildParams,
+};
+use crate::blob::get_actual_file_state;
+use crate::directory::
+{
+    self,
+    Elements,
+};
+use crate::printer::Printer;
+use crate::system::System;
+use crate::ticket::Ticket;
+
+/*  How a target on disk compares with what ruler recorded about it at the end of the last build. */
+#[derive(Debug, PartialEq)]
+pub enum TargetStatus
+{
+    /*  The file is as the last build left it. */
+    Unchanged,
+
+    /*  The file is there, but its contents or executable bit differ from what was recorded. */
+    Modified,
+
+    /*  The file isn't there. */
+    Missing,
+
+    /*  Nothing is recorded for the file, so it hasn't been built (or ruler directory is gone). */
+    Unknown,
+
+    /*  The target is pinned to the given ticket with "ruler pin", so builds leave it alone. */
+    Pinned(Ticket),
+}
+
+/*  How the target on disk compares with what the last build recorded for it. */
+fn get_target_status<SystemType : System>
+(
+    system : &SystemType,
+    elements : &Elements<SystemType>,
+    target : &str
+)
+-> TargetStatus
+{
+    if ! system.is_file(target) && ! system.is_dir(target)
+    {
+        return TargetStatus::Missing;
+    }
+
+    match elements.current_file_states.get_file_state(target)
+    {
+        None => TargetStatus::Unknown,
+        Some(recorded) =>
+        {
+            match get_actual_file_state(system, target, recorded)
+            {
+                Ok(actual) =>
+                {
+                    if actual.ticket == recorded.ticket && actual.executable == recorded.executable
+                    {
+                        TargetStatus::Unchanged
+                    }
+                    else
+                    {
+                        TargetStatus::Modified
+                    }
+                },
+                Err(_) => TargetStatus::Modified,
+            }
+        },
+    }
+}
+
+/*  This is the function that runs when you type "ruler status" at the command-line.  Reads the
+    rules and the current file states without changing anything, not even creating the ruler
+    directory, and reports on each target (or each ancestor of goal_target_opt). */
+pub fn status
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : &SystemType,
+    printer : &mut PrinterType,
+    params : &BuildParams
+)
+-> Result<Vec<(String, TargetStatus)>, BuildError>
+{
+    let variant_opt = params.variant_opt.as_deref();
+    let elements =
+    match directory::open_readonly(system, &params.directory_path, None, variant_opt)
+    {
+        Ok(elements) => elements,
+        Err(error) => return Err(BuildError::DirectoryInitFailed(error)),
+    };
+
+    let node_pack = get_nodes(system, params.rulefile_paths.clone(), params.goal_target_opt.clone(), params.dedupe_rules,
+        params.case_insensitive_paths, variant_opt)?;
+
+    let mut report = vec![];
+    for node in node_pack.nodes.iter()
+    {
+        for target in node.targets.iter()
+        {
+            let target_status =
+            match elements.pins.get(target)
+            {
+                Some(ticket) => TargetStatus::Pinned(ticket.clone()),
+                None => get_target_status(system, &elements, target),
+            };
+
+            let (banner_text, banner_color, text) =
+            match &target_status
+            {
+                TargetStatus::Unchanged => (" Unchanged", Color::Cyan, target.clone()),
+                TargetStatus::Modified => ("  Modified", Color::Yellow, target.clone()),
+                TargetStatus::Missing => ("   Missing", Color::Red, target.clone()),
+                TargetStatus::Unknown => ("   Unknown", Color::White, target.clone()),
+                TargetStatus::Pinned(ticket) => ("    Pinned", Color::Cyan, format!("{} (to {})", target, ticket)),
+            };
+            printer.print_single_banner_line(banner_text, banner_color, &text);
+            report.push((target.clone(), target_status));
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::build::
+    {
+        build,
+        BuildParams,
+        BuildError,
+    };
+    use crate::directory::InitDirectoryError;
+    use crate::printer::EmptyPrinter;
+    use crate::status::
+    {
+        status,
+        TargetStatus,
+    };
+    use crate::system::
+    {
+        System,
+        SystemError,
+        fake::FakeSystem,
+    };
+    use crate::system::util::write_str_to_file;
+
+    const RULES : &str = "\
+poem.txt
+:
+verse1.txt
+verse2.txt
+:
+mycat
+verse1.txt
+verse2.txt
+poem.txt
+:
+";
+
+    /*  On a read-only filesystem with no ruler directory, status reports from empty state and
+        creates nothing, while build fails naming the directory it couldn't create and why. */
+    #[test]
+    fn status_read_only_without_ruler_directory()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "build.rules", RULES).unwrap();
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        system.set_read_only(true);
+
+        assert_eq!(
+            status(&system, &mut EmptyPrinter::new(), &BuildParams::for_test()).unwrap(),
+            vec![("poem.txt".to_string(), TargetStatus::Missing)]);
+        assert!(!system.is_dir(".ruler"));
+
+        match build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test())
+        {
+            Err(BuildError::DirectoryInitFailed(InitDirectoryError::FailedToCreateDirectory(path, SystemError::PermissionDenied))) =>
+                assert_eq!(path, ".ruler"),
+            Err(error) => panic!("Wrong error: {}", error),
+            Ok(()) => panic!("Build succeeded on a read-only filesystem"),
+        }
+    }
+
+    /*  After a build, the poem is unchanged.  Scribble on it and it's modified, remove it and it's
+        missing. */
+    #[test]
+    fn status_after_build()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "build.rules", RULES).unwrap();
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+        build(system.clone(), &mut EmptyPrinter::new(), None, BuildParams::for_test()).unwrap();
+
+        assert_eq!(
+            status(&system, &mut EmptyPrinter::new(), &BuildParams::for_test()).unwrap(),
+            vec![("poem.txt".to_string(), TargetStatus::Unchanged)]);
+
+        system.time_passes(1);
+        write_str_to_file(&mut system, "poem.txt", "Scribbles").unwrap();
+        assert_eq!(
+            status(&system, &mut EmptyPrinter::new(), &BuildParams::for_test()).unwrap(),
+            vec![("poem.txt".to_string(), TargetStatus::Modified)]);
+
+        system.remove_file("poem.txt").unwrap();
+        assert_eq!(
+            status(&system, &mut EmptyPrinter::new(), &BuildParams::for_test()).unwrap(),
+            vec![("poem.txt".to_string(), TargetStatus::Missing)]);
+    }
+}
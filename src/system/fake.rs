@@ -2,14 +2,17 @@ use crate::system::
 {
     System,
     SystemError,
+    FileType,
     CommandLineOutput,
-    CommandScript
+    CommandScript,
+    CommandEnvironment,
 };
 use crate::system::util::
 {
     read_file,
     write_str_to_file,
     timestamp_to_system_time,
+    normalize_path,
 };
 use std::collections::HashMap;
 use std::sync::
@@ -66,6 +69,10 @@ struct Metadata
 {
     modified : SystemTime,
     executable : bool,
+
+    /*  Stands in for a named pipe, socket or device: there, but neither a regular file nor a
+        directory. */
+    special : bool,
 }
 
 impl Metadata
@@ -76,6 +83,7 @@ impl Metadata
         {
             modified : timestamp_to_system_time(timestamp),
             executable : false,
+            special : false,
         }
     }
 }
@@ -131,6 +139,8 @@ enum NodeError
     CreateFileOverExistingDirectory,
     CreateDirectoryOverExistingFile,
     GetModifiedOnDirectory,
+    GetFileLenOnDirectory,
+    TouchOnDirectory,
     IsExecutableOnDirectory,
     Weird,
 }
@@ -181,6 +191,12 @@ impl fmt::Display for NodeError
             NodeError::GetModifiedOnDirectory
                 => write!(formatter, "Attempt to get modified time for a directory (that is not implemented)"),
 
+            NodeError::GetFileLenOnDirectory
+                => write!(formatter, "Attempt to get file length for a directory"),
+
+            NodeError::TouchOnDirectory
+                => write!(formatter, "Attempt to touch a directory (that is not implemented)"),
+
             NodeError::IsExecutableOnDirectory
                 => write!(formatter, "Attempt to ask whether a directory is an executable"),
 
@@ -234,7 +250,7 @@ impl Node
                 match node
                 {
                     Node::Dir(_) => false,
-                    Node::File(_) => true,
+                    Node::File(info) => !info.metadata.special,
                 }
             },
             Err(_) =>
@@ -339,6 +355,31 @@ impl Node
         Ok(content)
     }
 
+    /*  The content of the file at path, to write more onto, creating the file empty if it isn't there.
+        Either way, the file counts as modified at the given time. */
+    pub fn append_file(&mut self, path: &str, timestamp : u64) -> Result<Content, NodeError>
+    {
+        let (dir_components, name) = get_dir_path_and_name(path)?;
+        let dir_map_mut = self.get_dir_map_mut(&dir_components)?;
+
+        match dir_map_mut.get_mut(name)
+        {
+            Some(Node::Dir(_)) => Err(NodeError::CreateFileOverExistingDirectory),
+            Some(Node::File(info)) =>
+            {
+                info.metadata.modified = timestamp_to_system_time(timestamp);
+                Ok(info.content.clone())
+            },
+            None =>
+            {
+                let content = Content::empty();
+                dir_map_mut.insert(name.to_string(), Node::File(
+                    FileInfo::new(Metadata::new(timestamp), content.clone())));
+                Ok(content)
+            },
+        }
+    }
+
     pub fn create_dir(&mut self, path: &str) -> Result<(), NodeError>
     {
         let (dir_components, name) = get_dir_path_and_name(path)?;
@@ -354,6 +395,33 @@ impl Node
         Ok(())
     }
 
+    /*  Walks down the components of path, making a directory wherever one is missing. */
+    pub fn create_dir_all(&mut self, path: &str) -> Result<(), NodeError>
+    {
+        let components = get_components(path);
+        if components.len() == 0
+        {
+            return Err(NodeError::PathEmpty);
+        }
+
+        let mut node = self;
+        for component in components.iter()
+        {
+            node = match node
+            {
+                Node::File(_) => return Err(NodeError::FileInPlaceOfDirectory(component.to_string())),
+                Node::Dir(name_to_node) =>
+                    name_to_node.entry(component.to_string()).or_insert_with(Node::empty_dir),
+            }
+        }
+
+        match node
+        {
+            Node::File(_) => Err(NodeError::CreateDirectoryOverExistingFile),
+            Node::Dir(_) => Ok(()),
+        }
+    }
+
     pub fn remove_file(&mut self, path: &str) -> Result<(), NodeError>
     {
         let (dir_components, name) = get_dir_path_and_name(path)?;
@@ -471,6 +539,30 @@ impl Node
         }
     }
 
+    pub fn get_file_len(&self, path: &str) -> Result<u64, NodeError>
+    {
+        let components = get_components(path);
+        match self.get_node(&components)?
+        {
+            Node::File(info) => Ok(info.content.borrow().len() as u64),
+            Node::Dir(_) => Err(NodeError::GetFileLenOnDirectory),
+        }
+    }
+
+    pub fn touch(&mut self, path: &str, timestamp : u64) -> Result<(), NodeError>
+    {
+        let components = get_components(path);
+        match self.get_node_mut(&components)?
+        {
+            Node::File(info) =>
+            {
+                info.metadata.modified = timestamp_to_system_time(timestamp);
+                Ok(())
+            },
+            Node::Dir(_) => Err(NodeError::TouchOnDirectory),
+        }
+    }
+
     pub fn is_executable(&self, path: &str) -> Result<bool, NodeError>
     {
         let components = get_components(path);
@@ -494,6 +586,31 @@ impl Node
             Node::Dir(_) => Err(NodeError::IsExecutableOnDirectory),
         }
     }
+
+    pub fn get_file_type(&self, path: &str) -> Result<FileType, NodeError>
+    {
+        let components = get_components(path);
+        match self.get_node(&components)?
+        {
+            Node::File(info) if info.metadata.special => Ok(FileType::Other),
+            Node::File(_) => Ok(FileType::Regular),
+            Node::Dir(_) => Ok(FileType::Directory),
+        }
+    }
+
+    pub fn set_special(&mut self, path: &str) -> Result<(), NodeError>
+    {
+        let components = get_components(path);
+        match self.get_node_mut(&components)?
+        {
+            Node::File(info) =>
+            {
+                info.metadata.special = true;
+                Ok(())
+            },
+            Node::Dir(_) => Err(NodeError::DirectoryInPlaceOfFile(path.to_string())),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -501,6 +618,9 @@ enum AccessMode
 {
     Read,
     Write,
+
+    /*  Writing, starting from the end of what's there. */
+    Append,
 }
 
 #[derive(Debug)]
@@ -515,17 +635,29 @@ impl FakeOpenFile
 {
     fn new(content: &Content, access_mode: AccessMode) -> Self
     {
+        let pos = match access_mode
+        {
+            AccessMode::Append => content.borrow().len(),
+            _ => 0,
+        };
+
         FakeOpenFile
         {
             content: content.clone(),
-            pos: 0,
+            pos: pos,
             access_mode,
         }
     }
 
     fn verify_access(&self, access_mode: AccessMode) -> std::io::Result<()>
     {
-        if access_mode != self.access_mode
+        let allowed = match (&self.access_mode, &access_mode)
+        {
+            (AccessMode::Append, AccessMode::Write) => true,
+            (own_mode, mode) => own_mode == mode,
+        };
+
+        if ! allowed
         {
             Err(Error::new(ErrorKind::Other, "Attempt to read/write the wrong way"))
         }
@@ -591,12 +723,204 @@ impl Write for FakeOpenFile
     }
 }
 
+/*  What a fake command does.  Gets the system it runs in and the arguments after the command name,
+    and returns what the command would have output. */
+pub type CommandHandler = Arc<dyn Fn(&mut FakeSystem, &[String]) -> CommandLineOutput + Send + Sync>;
+
+/*  The commands a FakeSystem knows, by name.  Clones share it, so a command registered on one clone
+    is available to the build threads' clones as well. */
+#[derive(Clone)]
+struct CommandRegistry(Arc<Mutex<HashMap<String, CommandHandler>>>);
+
+impl fmt::Debug for CommandRegistry
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        let mut names : Vec<String> = self.0.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        write!(formatter, "CommandRegistry({})", names.join(", "))
+    }
+}
+
+/*  Reads each file named in sources and concatenates the contents, or returns an error output
+    naming the command and the file that was the problem. */
+fn concatenate_files(system : &FakeSystem, sources : &[String], name : &str) -> Result<String, CommandLineOutput>
+{
+    let mut output = String::new();
+    for file in sources.iter()
+    {
+        match read_file(system, file)
+        {
+            Ok(content) =>
+            {
+                match from_utf8(&content)
+                {
+                    Ok(content_string) => output.push_str(content_string),
+                    Err(_) => return Err(CommandLineOutput::error(
+                        format!("{}: file contained non utf8 bytes: {}", name, file))),
+                }
+            },
+            Err(_) => return Err(CommandLineOutput::error(
+                format!("{}: file failed to open: {}", name, file))),
+        }
+    }
+    Ok(output)
+}
+
+/*  Fails without doing anything. */
+fn command_error(_system : &mut FakeSystem, _args : &[String]) -> CommandLineOutput
+{
+    CommandLineOutput::error("Failed".to_string())
+}
+
+/*  Takes source files followed by a target, concatenates the sources and puts the result in the target.
+    Needs at least one source, so a rule that lost its sources fails instead of writing an empty file. */
+fn command_mycat(system : &mut FakeSystem, args : &[String]) -> CommandLineOutput
+{
+    let n = args.len();
+    if n < 2
+    {
+        return CommandLineOutput::error("mycat requires at least one input and an output".to_string());
+    }
+
+    let output = match concatenate_files(system, &args[..(n-1)], "mycat")
+    {
+        Ok(output) => output,
+        Err(error_output) => return error_output,
+    };
+
+    match write_str_to_file(system, &args[n-1], &output)
+    {
+        Ok(_) => CommandLineOutput::new(),
+        Err(why) => CommandLineOutput::error(format!("Failed to cat into file: {} : {}", args[n-1], why)),
+    }
+}
+
+/*  Takes source files followed by two targets, concatenates the sources and puts the result in both the
+    targets.  For instance:
+
+    mycat2 in1.txt in2.txt out1.txt out2.txt
+
+    concatenates in1.txt in2.txt  puts a copy in out1.txt and out2.txt.  Like mycat, needs at least one
+    source. */
+fn command_mycat2(system : &mut FakeSystem, args : &[String]) -> CommandLineOutput
+{
+    let n = args.len();
+    if n < 3
+    {
+        return CommandLineOutput::error("mycat2 requires at least one input and two outputs".to_string());
+    }
+
+    let output = match concatenate_files(system, &args[..(n-2)], "mycat2")
+    {
+        Ok(output) => output,
+        Err(error_output) => return error_output,
+    };
+
+    for target in args[(n-2)..].iter()
+    {
+        match write_str_to_file(system, target, &output)
+        {
+            Ok(_) => {},
+            Err(why) => return CommandLineOutput::error(
+                format!("mycat2: failed to cat into file: {}: {}", target, why)),
+        }
+    }
+
+    CommandLineOutput::new()
+}
+
+/*  Like mycat, but then warns on stderr and exits 1, the way some tools report success with warnings. */
+fn command_warncat(system : &mut FakeSystem, args : &[String]) -> CommandLineOutput
+{
+    let output = command_mycat(system, args);
+    if output.code != Some(0)
+    {
+        return output;
+    }
+
+    CommandLineOutput::from_bytes(vec![], b"warncat: warning: nothing to warn about\n".to_vec(), Some(1), false)
+}
+
+/*  Prints its arguments to stdout, separated by spaces, and exits 0. */
+fn command_myecho(_system : &mut FakeSystem, args : &[String]) -> CommandLineOutput
+{
+    CommandLineOutput::from_bytes(format!("{}\n", args.join(" ")).into_bytes(), vec![], Some(0), true)
+}
+
+/*  Removes each file given.  Needs at least one. */
+fn command_rm(system : &mut FakeSystem, args : &[String]) -> CommandLineOutput
+{
+    if args.len() == 0
+    {
+        return CommandLineOutput::error("rm requires at least one file".to_string());
+    }
+
+    for file in args.iter()
+    {
+        match system.remove_file(file)
+        {
+            Ok(()) => {},
+            Err(_) => return CommandLineOutput::error(format!("File failed to delete: {}", file)),
+        }
+    }
+
+    CommandLineOutput::new()
+}
+
 #[derive(Debug, Clone)]
 pub struct FakeSystem
 {
     root: Arc<Mutex<Node>>,
-    current_timestamp: u64,
-    command_log: Arc<Mutex<Vec<String>>>
+    current_dir: Arc<Mutex<Vec<String>>>,
+    current_timestamp: Arc<Mutex<u64>>,
+    command_log: Arc<Mutex<Vec<Vec<String>>>>,
+    commands: CommandRegistry,
+    environment: Arc<Mutex<HashMap<String, String>>>,
+    environment_log: Arc<Mutex<Vec<Vec<(String, String)>>>>,
+    read_only: Arc<Mutex<bool>>,
+    full_directories: Arc<Mutex<Vec<String>>>,
+    read_counts: Arc<Mutex<HashMap<String, u64>>>,
+    modified_counts: Arc<Mutex<HashMap<String, u64>>>,
+    executable_counts: Arc<Mutex<HashMap<String, u64>>>,
+    stdin: Arc<Mutex<Vec<u8>>>,
+}
+
+/*  Takes the current directory as a list of components and a path, and returns the path relative to the root
+    of the fake filesystem.  A path that begins with '/' is absolute, otherwise it is relative to the current
+    directory.  Components that are "." get dropped, ".." removes the previous component.  The empty path stays
+    empty so that the usual PathEmpty errors still come out of the Node functions. */
+fn resolve_path(current_dir : &Vec<String>, path : &str) -> String
+{
+    if path == ""
+    {
+        return "".to_string();
+    }
+
+    let mut components : Vec<String> =
+    if path.starts_with('/')
+    {
+        vec![]
+    }
+    else
+    {
+        current_dir.clone()
+    };
+
+    for component in path.split('/')
+    {
+        match component
+        {
+            "" | "." => {},
+            ".." =>
+            {
+                components.pop();
+            },
+            _ => components.push(component.to_string()),
+        }
+    }
+
+    components.join("/")
 }
 
 fn convert_node_error_to_system_error(error : NodeError) -> SystemError
@@ -642,6 +966,12 @@ fn convert_node_error_to_system_error(error : NodeError) -> SystemError
         NodeError::GetModifiedOnDirectory
             => SystemError::NotImplemented,
 
+        NodeError::GetFileLenOnDirectory
+            => SystemError::NotImplemented,
+
+        NodeError::TouchOnDirectory
+            => SystemError::NotImplemented,
+
         NodeError::IsExecutableOnDirectory
             => SystemError::NotImplemented,
 
@@ -654,21 +984,129 @@ impl FakeSystem
 {
     pub fn new(start : u64) -> Self
     {
-        FakeSystem
+        let mut system = FakeSystem
         {
             root : Arc::new(Mutex::new(Node::empty_dir())),
 
+            current_dir : Arc::new(Mutex::new(vec![])),
+
             /*  When too many timestamps are 0 by default it triggers the
                 timestamp optimization at the wrong time */
-            current_timestamp : start,
+            current_timestamp : Arc::new(Mutex::new(start)),
 
             command_log : Arc::new(Mutex::new(vec![])),
+
+            read_only : Arc::new(Mutex::new(false)),
+
+            full_directories : Arc::new(Mutex::new(vec![])),
+
+            read_counts : Arc::new(Mutex::new(HashMap::new())),
+
+            modified_counts : Arc::new(Mutex::new(HashMap::new())),
+            executable_counts : Arc::new(Mutex::new(HashMap::new())),
+
+            commands : CommandRegistry(Arc::new(Mutex::new(HashMap::new()))),
+
+            environment : Arc::new(Mutex::new(HashMap::new())),
+
+            environment_log : Arc::new(Mutex::new(vec![])),
+
+            stdin : Arc::new(Mutex::new(vec![])),
+        };
+
+        system.register_command("error", Box::new(command_error));
+        system.register_command("mycat", Box::new(command_mycat));
+        system.register_command("mycat2", Box::new(command_mycat2));
+        system.register_command("myecho", Box::new(command_myecho));
+        system.register_command("warncat", Box::new(command_warncat));
+        system.register_command("rm", Box::new(command_rm));
+        system
+    }
+
+    /*  Makes the command called name run the given handler from now on, replacing any command already
+        called that.  Clones share commands. */
+    pub fn register_command(
+        &mut self,
+        name : &str,
+        handler : Box<dyn Fn(&mut FakeSystem, &[String]) -> CommandLineOutput + Send + Sync>)
+    {
+        self.commands.0.lock().unwrap().insert(name.to_string(), Arc::from(handler));
+    }
+
+    /*  Makes every function that would change the filesystem fail with PermissionDenied, the way
+        they would on a read-only mount.  Clones share the setting. */
+    pub fn set_read_only(&mut self, read_only : bool)
+    {
+        *self.read_only.lock().unwrap() = read_only;
+    }
+
+    fn check_writable(&self) -> Result<(), SystemError>
+    {
+        if *self.read_only.lock().unwrap()
+        {
+            Err(SystemError::PermissionDenied)
+        }
+        else
+        {
+            Ok(())
         }
     }
 
+    /*  Makes the directory at path act like it's on a full disk: creating a file or directory in it,
+        appending to one, or renaming something into it fails with OutOfSpace.  Clones share the
+        setting. */
+    pub fn set_out_of_space(&mut self, path : &str)
+    {
+        let resolved = self.resolve(path);
+        self.full_directories.lock().unwrap().push(resolved);
+    }
+
+    fn check_space(&self, path : &str) -> Result<(), SystemError>
+    {
+        if self.is_dir(path)
+        {
+            return Ok(());
+        }
+
+        let resolved = self.resolve(&normalize_path(path)?);
+        if self.full_directories.lock().unwrap().iter().any(
+            |directory| resolved == *directory || resolved.starts_with(&format!("{}/", directory)))
+        {
+            Err(SystemError::OutOfSpace)
+        }
+        else
+        {
+            Ok(())
+        }
+    }
+
+    /*  Puts something at path that's neither a regular file nor a directory, the way a named pipe
+        would be: is_file and is_dir both say no, and file_type says Other. */
+    pub fn create_special_file(&mut self, path : &str) -> Result<(), SystemError>
+    {
+        self.create_file(path)?;
+        match self.get_root_node_mut().set_special(&self.resolve(path))
+        {
+            Ok(()) => Ok(()),
+            Err(error) => Err(convert_node_error_to_system_error(error)),
+        }
+    }
+
+    /*  The clock is shared among clones, so a fake command moving time on moves it for the whole build. */
     pub fn time_passes(&mut self, increment : u64)
     {
-        self.current_timestamp += increment;
+        *self.current_timestamp.lock().unwrap() += increment;
+    }
+
+    /*  Sets the clock back, as a clock correction would. */
+    pub fn time_goes_back(&mut self, decrement : u64)
+    {
+        *self.current_timestamp.lock().unwrap() -= decrement;
+    }
+
+    fn get_timestamp(&self) -> u64
+    {
+        *self.current_timestamp.lock().unwrap()
     }
 
     fn get_root_node(&self) -> impl Deref<Target=Node> + '_
@@ -681,133 +1119,122 @@ impl FakeSystem
         self.root.lock().unwrap()
     }
 
-    fn get_command_log_mut(&self) -> impl DerefMut<Target=Vec<String>> + '_
+    /*  Resolves the given path against the current directory to get a path from the root. */
+    fn resolve(&self, path : &str) -> String
+    {
+        resolve_path(&self.current_dir.lock().unwrap(), path)
+    }
+
+    fn get_command_log_mut(&self) -> impl DerefMut<Target=Vec<Vec<String>>> + '_
     {
         self.command_log.lock().unwrap()
     }
 
-    pub fn get_command_log(&self) -> Vec<String>
+    /*  Every command executed so far, in order, each as the list of words it was given. */
+    pub fn get_command_log(&self) -> Vec<Vec<String>>
     {
         self.command_log.lock().unwrap().clone()
     }
 
-    fn execute_script_line(&mut self, line : String) -> Result<CommandLineOutput, SystemError>
+    /*  How many times the modified time of the file at path has been asked for. */
+    pub fn get_modified_count(&self, path : &str) -> u64
     {
-        let command_list:Vec<&str> = line.split_whitespace().collect();
+        match self.modified_counts.lock().unwrap().get(&self.resolve(path))
+        {
+            Some(count) => *count,
+            None => 0,
+        }
+    }
 
-        let n = command_list.len();
-        if n <= 0
+    /*  How many times the executable bit of the file at path has been asked for. */
+    pub fn get_executable_count(&self, path : &str) -> u64
+    {
+        match self.executable_counts.lock().unwrap().get(&self.resolve(path))
         {
-            return Ok(CommandLineOutput::error(format!("Wrong number of arguments")));
+            Some(count) => *count,
+            None => 0,
         }
+    }
+
+    /*  Sets what the next read_stdin gets, standing in for whatever was piped into ruler. */
+    pub fn set_stdin(&self, content : &[u8])
+    {
+        *self.stdin.lock().unwrap() = content.to_vec();
+    }
 
-        let mut output = String::new();
-        match command_list[0]
+    /*  Every file under the directory at path, at any depth, sorted.  Directories themselves aren't
+        listed, so an empty one leaves no trace. */
+    pub fn list_dir_recursive(&self, path: &str) -> Result<Vec<String>, SystemError>
+    {
+        let mut result = vec![];
+        for child_path in self.list_dir(path)?
         {
-            "error" =>
+            if self.is_dir(&child_path)
             {
-                Ok(CommandLineOutput::error("Failed".to_string()))
-            },
-
-            "mycat" =>
+                result.extend(self.list_dir_recursive(&child_path)?);
+            }
+            else
             {
-                for file in command_list[1..(n-1)].iter()
-                {
-                    match read_file(self, file)
-                    {
-                        Ok(content) =>
-                        {
-                            match from_utf8(&content)
-                            {
-                                Ok(content_string) =>
-                                {
-                                    output.push_str(content_string);
-                                }
-                                Err(_) => return Ok(CommandLineOutput::error(format!("File contained non utf8 bytes: {}", file))),
-                            }
-                        }
-                        Err(_) =>
-                        {
-                            return Ok(CommandLineOutput::error(format!("File failed to open: {}", file)));
-                        }
-                    }
-                }
+                result.push(child_path);
+            }
+        }
+        result.sort();
+        Ok(result)
+    }
 
-                match write_str_to_file(self, &command_list[n-1], &output)
-                {
-                    Ok(_) => Ok(CommandLineOutput::new()),
-                    Err(why) =>
-                    {
-                        Ok(CommandLineOutput::error(format!("Failed to cat into file: {} : {}", command_list[n-1], why)))
-                    }
-                }
-            },
+    /*  The command log with each command's words joined by spaces, the way they'd read on a command-line. */
+    pub fn get_command_log_joined(&self) -> Vec<String>
+    {
+        self.command_log.lock().unwrap().iter().map(|argv| argv.join(" ")).collect()
+    }
 
-            /*  Takes source files followed by two targets, concats the sources and puts the result in both the
-                targets.  For instance:
+    /*  Sets a variable in the environment that commands would inherit, standing in for the
+        environment of ruler's own process. */
+    pub fn set_env_var(&mut self, name : &str, value : &str)
+    {
+        self.environment.lock().unwrap().insert(name.to_string(), value.to_string());
+    }
 
-                mycat2 in1.txt in2.txt out1.txt out2.txt
+    /*  For every command executed so far, in order, the environment it ran with, sorted by name. */
+    pub fn get_environment_log(&self) -> Vec<Vec<(String, String)>>
+    {
+        self.environment_log.lock().unwrap().clone()
+    }
 
-                concatinates in1.txt in2.txt  puts a copy in out1.txt and out2.txt.*/
-            "mycat2" =>
-            {
-                for file in command_list[1..(n-2)].iter()
-                {
-                    match read_file(self, file)
-                    {
-                        Ok(content) =>
-                        {
-                            match from_utf8(&content)
-                            {
-                                Ok(content_string) =>
-                                {
-                                    output.push_str(content_string);
-                                }
-                                Err(_) => return Ok(CommandLineOutput::error(
-                                    format!("mycat2: file contained non utf8 bytes: {}", file))),
-                            }
-                        }
-                        Err(_) =>
-                        {
-                            return Ok(CommandLineOutput::error(
-                                format!("mycat2: file failed to open: {}", file)));
-                        }
-                    }
-                }
+    /*  The variables a command gets under the given policy, sorted by name. */
+    fn get_effective_environment(&self, command_environment : &CommandEnvironment) -> Vec<(String, String)>
+    {
+        let environment = self.environment.lock().unwrap();
+        let mut result : Vec<(String, String)> =
+        match command_environment
+        {
+            CommandEnvironment::Inherit =>
+                environment.iter().map(|(name, value)| (name.clone(), value.clone())).collect(),
 
-                match write_str_to_file(self, &command_list[n-2], &output)
-                {
-                    Ok(_) => {},
-                    Err(why) => return Ok(CommandLineOutput::error(
-                        format!("mycat2: failed to cat into file: {}: {}", command_list[n-2], why)))
-                }
+            CommandEnvironment::Only(names) =>
+                environment.iter().filter(|(name, _value)| names.contains(name))
+                    .map(|(name, value)| (name.clone(), value.clone())).collect(),
+        };
+        result.sort();
+        result
+    }
 
-                match write_str_to_file(self, &command_list[n-1], &output)
-                {
-                    Ok(_) => Ok(CommandLineOutput::new()),
-                    Err(why) => return Ok(CommandLineOutput::error(
-                        format!("mycat2: failed to cat into file: {}: {}", command_list[n-1], why)))
-                }
-            },
+    /*  Runs one command of a script by looking up the handler registered under its first word. */
+    fn execute_argv(&mut self, argv : Vec<String>) -> Result<CommandLineOutput, SystemError>
+    {
+        if argv.len() == 0
+        {
+            return Ok(CommandLineOutput::error("Empty command: no program to run".to_string()));
+        }
 
-            "rm" =>
-            {
-                for file in command_list[1..n].iter()
-                {
-                    match self.remove_file(file)
-                    {
-                        Ok(()) => {}
-                        Err(_) =>
-                        {
-                            return Ok(CommandLineOutput::error(format!("File failed to delete: {}", file)));
-                        }
-                    }
-                }
+        let handler =
+        match self.commands.0.lock().unwrap().get(&argv[0])
+        {
+            Some(handler) => handler.clone(),
+            None => return Ok(CommandLineOutput::error(format!("Invalid command given: {}", argv[0]))),
+        };
 
-                Ok(CommandLineOutput::new())
-            },
-            _=> Ok(CommandLineOutput::error(format!("Invalid command given: {}", command_list[0])))
-        }
+        Ok(handler(self, &argv[1..]))
     }
 }
 
@@ -817,10 +1244,14 @@ impl System for FakeSystem
 
     fn open(&self, path: &str) -> Result<Self::File, SystemError>
     {
-        match self.get_root_node().open_file(path)
+        let resolved = self.resolve(&normalize_path(path)?);
+        match self.get_root_node().open_file(&resolved)
         {
             Ok(content) =>
-                Ok(FakeOpenFile::new(content, AccessMode::Read)),
+            {
+                *self.read_counts.lock().unwrap().entry(resolved).or_insert(0) += 1;
+                Ok(FakeOpenFile::new(content, AccessMode::Read))
+            },
 
             Err(error) => Err(convert_node_error_to_system_error(error)),
         }
@@ -828,16 +1259,42 @@ impl System for FakeSystem
 
     fn create_file(&mut self, path: &str) -> Result<Self::File, SystemError>
     {
-        match self.get_root_node_mut().create_file(path, Content::empty(), self.current_timestamp)
+        self.check_writable()?;
+        self.check_space(path)?;
+        match self.get_root_node_mut().create_file(&self.resolve(&normalize_path(path)?), Content::empty(), self.get_timestamp())
         {
             Ok(content) => Ok(FakeOpenFile::new(&content, AccessMode::Write)),
             Err(error) => Err(convert_node_error_to_system_error(error)),
         }
     }
 
+    fn append_to_file(&mut self, path: &str) -> Result<Self::File, SystemError>
+    {
+        self.check_writable()?;
+        self.check_space(path)?;
+        match self.get_root_node_mut().append_file(&self.resolve(&normalize_path(path)?), self.get_timestamp())
+        {
+            Ok(content) => Ok(FakeOpenFile::new(&content, AccessMode::Append)),
+            Err(error) => Err(convert_node_error_to_system_error(error)),
+        }
+    }
+
     fn create_dir(&mut self, path: &str) -> Result<(), SystemError>
     {
-        match self.get_root_node_mut().create_dir(path)
+        self.check_writable()?;
+        self.check_space(path)?;
+        match self.get_root_node_mut().create_dir(&self.resolve(&normalize_path(path)?))
+        {
+            Ok(_) => Ok(()),
+            Err(error) => Err(convert_node_error_to_system_error(error)),
+        }
+    }
+
+    fn create_dir_all(&mut self, path: &str) -> Result<(), SystemError>
+    {
+        self.check_writable()?;
+        self.check_space(path)?;
+        match self.get_root_node_mut().create_dir_all(&self.resolve(&normalize_path(path)?))
         {
             Ok(_) => Ok(()),
             Err(error) => Err(convert_node_error_to_system_error(error)),
@@ -846,17 +1303,26 @@ impl System for FakeSystem
 
     fn is_file(&self, path: &str) -> bool
     {
-        self.get_root_node().is_file(path)
+        match normalize_path(path)
+        {
+            Ok(path) => self.get_root_node().is_file(&self.resolve(&path)),
+            Err(_) => false,
+        }
     }
 
     fn is_dir(&self, path: &str) -> bool
     {
-        self.get_root_node().is_dir(path)
+        match normalize_path(path)
+        {
+            Ok(path) => self.get_root_node().is_dir(&self.resolve(&path)),
+            Err(_) => false,
+        }
     }
 
     fn remove_file(&mut self, path: &str) -> Result<(), SystemError>
     {
-        match self.get_root_node_mut().remove_file(path)
+        self.check_writable()?;
+        match self.get_root_node_mut().remove_file(&self.resolve(path))
         {
             Ok(_) => Ok(()),
             Err(error) => Err(convert_node_error_to_system_error(error)),
@@ -865,7 +1331,8 @@ impl System for FakeSystem
 
     fn remove_dir(&mut self, path: &str) -> Result<(), SystemError>
     {
-        match self.get_root_node_mut().remove_dir(path)
+        self.check_writable()?;
+        match self.get_root_node_mut().remove_dir(&self.resolve(path))
         {
             Ok(_) => Ok(()),
             Err(error) => Err(convert_node_error_to_system_error(error)),
@@ -874,16 +1341,22 @@ impl System for FakeSystem
 
     fn list_dir(&self, path: &str) -> Result<Vec<String>, SystemError>
     {
-        match self.get_root_node_mut().list_dir(path)
+        /*  The listing comes back in terms of the resolved path, so swap that prefix for the path as given. */
+        let path = normalize_path(path)?;
+        let resolved = self.resolve(&path);
+        match self.get_root_node().list_dir(&resolved)
         {
-            Ok(result) => Ok(result),
+            Ok(result) => Ok(result.into_iter().map(
+                |p|{format!("{}{}", path, &p[resolved.len()..])}).collect()),
             Err(error) => Err(convert_node_error_to_system_error(error)),
         }
     }
 
     fn rename(&mut self, from: &str, to: &str) -> Result<(), SystemError>
     {
-        match self.get_root_node_mut().rename(from, to)
+        self.check_writable()?;
+        self.check_space(to)?;
+        match self.get_root_node_mut().rename(&self.resolve(&normalize_path(from)?), &self.resolve(&normalize_path(to)?))
         {
             Ok(_) => Ok(()),
             Err(error) => Err(convert_node_error_to_system_error(error)),
@@ -892,16 +1365,71 @@ impl System for FakeSystem
 
     fn get_modified(&self, path: &str) -> Result<SystemTime, SystemError>
     {
-        match self.get_root_node().get_modified(path)
+        let resolved = self.resolve(&normalize_path(path)?);
+        *self.modified_counts.lock().unwrap().entry(resolved.clone()).or_insert(0) += 1;
+        match self.get_root_node().get_modified(&resolved)
         {
             Ok(system_time) => Ok(system_time),
             Err(error) => Err(convert_node_error_to_system_error(error)),
         }
     }
 
+    fn get_file_len(&self, path: &str) -> Result<u64, SystemError>
+    {
+        match self.get_root_node().get_file_len(&self.resolve(path))
+        {
+            Ok(len) => Ok(len),
+            Err(error) => Err(convert_node_error_to_system_error(error)),
+        }
+    }
+
+    fn file_type(&self, path: &str) -> Result<FileType, SystemError>
+    {
+        match self.get_root_node().get_file_type(&self.resolve(path))
+        {
+            Ok(file_type) => Ok(file_type),
+            Err(error) => Err(convert_node_error_to_system_error(error)),
+        }
+    }
+
+    /*  Time stands still in a FakeSystem until time_passes moves it on. */
+    fn now(&self) -> SystemTime
+    {
+        timestamp_to_system_time(self.get_timestamp())
+    }
+
+    /*  Hands over whatever set_stdin put there, leaving nothing for the next read, the way a real
+        standard input is empty once read to its end. */
+    fn read_stdin(&mut self) -> Result<Vec<u8>, SystemError>
+    {
+        Ok(std::mem::replace(&mut *self.stdin.lock().unwrap(), vec![]))
+    }
+
+    /*  Counts every successful open, so the marker is exact. */
+    fn get_read_marker(&self, path: &str) -> Option<u64>
+    {
+        match self.read_counts.lock().unwrap().get(&self.resolve(path))
+        {
+            Some(count) => Some(*count),
+            None => Some(0),
+        }
+    }
+
+    fn touch(&mut self, path: &str) -> Result<(), SystemError>
+    {
+        self.check_writable()?;
+        let timestamp = self.get_timestamp();
+        match self.get_root_node_mut().touch(&self.resolve(path), timestamp)
+        {
+            Ok(()) => Ok(()),
+            Err(error) => Err(convert_node_error_to_system_error(error)),
+        }
+    }
+
     fn is_executable(&self, path: &str) -> Result<bool, SystemError>
     {
-        match self.get_root_node().is_executable(path)
+        *self.executable_counts.lock().unwrap().entry(self.resolve(path)).or_insert(0) += 1;
+        match self.get_root_node().is_executable(&self.resolve(path))
         {
             Ok(executable) => Ok(executable),
             Err(error) => Err(convert_node_error_to_system_error(error)),
@@ -910,20 +1438,47 @@ impl System for FakeSystem
 
     fn set_is_executable(&mut self, path: &str, executable : bool) -> Result<(), SystemError>
     {
-        match self.get_root_node_mut().set_is_executable(path, executable)
+        self.check_writable()?;
+        match self.get_root_node_mut().set_is_executable(&self.resolve(path), executable)
         {
             Ok(()) => Ok(()),
             Err(error) => Err(convert_node_error_to_system_error(error)),
         }
     }
 
+    fn current_dir(&self) -> Result<String, SystemError>
+    {
+        Ok(format!("/{}", self.current_dir.lock().unwrap().join("/")))
+    }
+
+    fn set_current_dir(&mut self, path: &str) -> Result<(), SystemError>
+    {
+        let resolved = self.resolve(path);
+        let root = self.get_root_node();
+        if root.is_dir(&resolved)
+        {
+            *self.current_dir.lock().unwrap() = get_components(&resolved).iter().map(|c|{c.to_string()}).collect();
+            Ok(())
+        }
+        else if root.is_file(&resolved)
+        {
+            Err(SystemError::ExpectedDirFoundFile)
+        }
+        else
+        {
+            Err(SystemError::NotFound)
+        }
+    }
+
     fn execute_command(&mut self, command_script: CommandScript) -> Vec<Result<CommandLineOutput, SystemError>>
     {
         let mut result = Vec::new();
-        self.get_command_log_mut().push(format!("{}", command_script));
-        for line in command_script.lines
+        let effective_environment = self.get_effective_environment(&command_script.environment);
+        for argv in command_script.argvs
         {
-            result.push(self.execute_script_line(line));
+            self.get_command_log_mut().push(argv.clone());
+            self.environment_log.lock().unwrap().push(effective_environment.clone());
+            result.push(self.execute_argv(argv));
         }
         result
     }
@@ -932,9 +1487,15 @@ impl System for FakeSystem
 #[cfg(test)]
 mod test
 {
+    use std::sync::
+    {
+        Arc,
+        Mutex
+    };
     use crate::system::
     {
         System,
+        SystemError,
         CommandLineOutput,
         to_command_script
     };
@@ -948,6 +1509,7 @@ mod test
         NodeError,
         get_components,
         get_dir_path_and_name,
+        resolve_path,
         FakeSystem,
     };
 
@@ -956,7 +1518,9 @@ mod test
         write_str_to_file,
         read_file,
         get_timestamp,
+        normalize_path,
     };
+    use std::io::Write;
 
     #[test]
     fn content_borrows_with_star()
@@ -1284,6 +1848,86 @@ mod test
         assert!(!node.is_file("images/kitten.jpg"));
     }
 
+    #[test]
+    fn resolve_path_general()
+    {
+        let root : Vec<String> = vec![];
+        let fruit = vec!["fruit".to_string()];
+        assert_eq!(resolve_path(&root, "apples.txt"), "apples.txt");
+        assert_eq!(resolve_path(&fruit, "apples.txt"), "fruit/apples.txt");
+        assert_eq!(resolve_path(&fruit, "./apples.txt"), "fruit/apples.txt");
+        assert_eq!(resolve_path(&fruit, "../apples.txt"), "apples.txt");
+        assert_eq!(resolve_path(&fruit, "/apples.txt"), "apples.txt");
+        assert_eq!(resolve_path(&fruit, ""), "");
+    }
+
+    /*  Make a subdirectory, set it as the current directory and check that relative paths
+        land in the subdirectory while absolute paths still land at the root. */
+    #[test]
+    fn system_set_current_dir_resolves_relative_paths()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir("fruit").unwrap();
+        assert_eq!(system.current_dir().unwrap(), "/");
+
+        system.set_current_dir("fruit").unwrap();
+        assert_eq!(system.current_dir().unwrap(), "/fruit");
+
+        write_str_to_file(&mut system, "apples.txt", "apples\n").unwrap();
+        write_str_to_file(&mut system, "/bananas.txt", "bananas\n").unwrap();
+
+        assert!(system.is_file("apples.txt"));
+        assert!(system.is_file("/fruit/apples.txt"));
+        assert!(system.is_file("../bananas.txt"));
+        assert!(!system.is_file("bananas.txt"));
+        assert_eq!(system.list_dir(".").unwrap(), vec!["./apples.txt".to_string()]);
+
+        system.set_current_dir("..").unwrap();
+        assert_eq!(system.current_dir().unwrap(), "/");
+        assert_eq!(read_file(&system, "fruit/apples.txt").unwrap(), b"apples\n");
+        assert!(system.is_file("bananas.txt"));
+    }
+
+    /*  Check that a clone shares the current directory, like threads in one process would. */
+    #[test]
+    fn system_current_dir_shared_by_clones()
+    {
+        let mut system = FakeSystem::new(10);
+        let system_clone = system.clone();
+        system.create_dir("fruit").unwrap();
+        system.set_current_dir("fruit").unwrap();
+        assert_eq!(system_clone.current_dir().unwrap(), "/fruit");
+    }
+
+    #[test]
+    fn system_set_current_dir_errors()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "apples.txt", "apples\n").unwrap();
+        assert_eq!(system.set_current_dir("fruit"), Err(SystemError::NotFound));
+        assert_eq!(system.set_current_dir("apples.txt"), Err(SystemError::ExpectedDirFoundFile));
+        assert_eq!(system.current_dir().unwrap(), "/");
+    }
+
+    /*  Commands run relative to the current directory too. */
+    #[test]
+    fn executing_mycat_in_current_dir()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir("poems").unwrap();
+        system.set_current_dir("poems").unwrap();
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red.\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet.\n").unwrap();
+
+        system.execute_command(to_command_script(vec![
+            "mycat".to_string(),
+            "verse1.txt".to_string(),
+            "verse2.txt".to_string(),
+            "poem.txt".to_string()]));
+
+        assert_eq!(read_file(&system, "/poems/poem.txt").unwrap(), b"Roses are red.\nViolets are violet.\n");
+    }
+
     #[test]
     fn system_add_remove_file()
     {
@@ -1340,6 +1984,30 @@ mod test
         assert_eq!(read_file(&system, "fruit_file.txt").unwrap(), b"cantaloupe");
     }
 
+    /*  Appending to a file keeps what was there and adds the new data after it.  Appending to a file
+        that isn't there creates it. */
+    #[test]
+    fn system_append_to_file()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "log.txt", "first line\n").unwrap();
+        {
+            let mut file = system.append_to_file("log.txt").unwrap();
+            file.write_all(b"second line\n").unwrap();
+            file.write_all(b"third line\n").unwrap();
+        }
+        assert_eq!(read_file(&system, "log.txt").unwrap(), b"first line\nsecond line\nthird line\n");
+
+        {
+            let mut file = system.append_to_file("new.txt").unwrap();
+            file.write_all(b"hello\n").unwrap();
+        }
+        assert_eq!(read_file(&system, "new.txt").unwrap(), b"hello\n");
+
+        system.create_dir("logs").unwrap();
+        assert_eq!(system.append_to_file("logs").err(), Some(SystemError::CreateFileOverExistingDirectory));
+    }
+
     #[test]
     fn system_rename_file()
     {
@@ -1401,6 +2069,19 @@ mod test
         }
     }
 
+    /*  Touching a file moves its modified time up to the current time, and leaves the content alone. */
+    #[test]
+    fn touch_advances_modified_timestamp()
+    {
+        let mut system = FakeSystem::new(3);
+        write_str_to_file(&mut system, "cars.txt", "cantaloupe").unwrap();
+        system.time_passes(8);
+        system.touch("cars.txt").unwrap();
+
+        assert_eq!(get_timestamp(system.get_modified("cars.txt").unwrap()).unwrap(), 11);
+        assert_eq!(read_file(&system, "cars.txt").unwrap(), b"cantaloupe");
+    }
+
     #[test]
     fn writing_updates_modified_timestamp()
     {
@@ -1432,6 +2113,8 @@ mod test
                 {
                     out : "".to_string(),
                     err : "Failed".to_string(),
+                    out_bytes : vec![],
+                    err_bytes : b"Failed".to_vec(),
                     code : Some(1),
                     success : false,
                 })
@@ -1459,6 +2142,8 @@ mod test
                 {
                     out : "".to_string(),
                     err : "".to_string(),
+                    out_bytes : vec![],
+                    err_bytes : vec![],
                     code : Some(0),
                     success : true,
                 })
@@ -1468,6 +2153,45 @@ mod test
         assert_eq!(read_file(&system, "poem.txt"), Ok(b"Ants\nLove to dance\n".to_vec()));
     }
 
+    /*  mycat with no arguments, or with only an output, fails saying what it needs and writes nothing. */
+    #[test]
+    fn executing_mycat_with_too_few_arguments()
+    {
+        let mut system = FakeSystem::new(10);
+
+        assert_eq!(
+            system.execute_command(to_command_script(vec!["mycat".to_string()])),
+            vec![Ok(CommandLineOutput::error("mycat requires at least one input and an output".to_string()))]);
+
+        assert_eq!(
+            system.execute_command(to_command_script(vec!["mycat".to_string(), "poem.txt".to_string()])),
+            vec![Ok(CommandLineOutput::error("mycat requires at least one input and an output".to_string()))]);
+
+        assert!(!system.is_file("poem.txt"));
+    }
+
+    /*  mycat2 needs an input and two outputs, and rm needs a file. */
+    #[test]
+    fn executing_mycat2_and_rm_with_too_few_arguments()
+    {
+        let mut system = FakeSystem::new(10);
+        write_str_to_file(&mut system, "line1.txt", "Ants\n").unwrap();
+
+        assert_eq!(
+            system.execute_command(to_command_script(vec![
+                "mycat2".to_string(),
+                "out1.txt".to_string(),
+                "out2.txt".to_string()])),
+            vec![Ok(CommandLineOutput::error("mycat2 requires at least one input and two outputs".to_string()))]);
+
+        assert_eq!(
+            system.execute_command(to_command_script(vec!["rm".to_string()])),
+            vec![Ok(CommandLineOutput::error("rm requires at least one file".to_string()))]);
+
+        assert!(!system.is_file("out1.txt"));
+        assert!(system.is_file("line1.txt"));
+    }
+
 
     #[test]
     fn executing_mycat2_concatinates_and_dupes()
@@ -1509,6 +2233,8 @@ mod test
                 {
                     out : "".to_string(),
                     err : "".to_string(),
+                    out_bytes : vec![],
+                    err_bytes : vec![],
                     code : Some(0),
                     success : true,
                 })
@@ -1552,6 +2278,8 @@ mod test
                 {
                     out : "".to_string(),
                     err : "".to_string(),
+                    out_bytes : vec![],
+                    err_bytes : vec![],
                     code : Some(0),
                     success : true,
                 })
@@ -1561,20 +2289,157 @@ mod test
         assert!(!system.is_file("terrible-file.txt"));
 
     }
-}
 
 
+    /*  A filename with a space in it reaches the handler as one argument, and the log records the
+        command word for word. */
+    #[test]
+    fn command_argument_with_space_reaches_handler()
+    {
+        let mut system = FakeSystem::new(10);
+        let received = Arc::new(Mutex::new(vec![]));
+        let received_clone = received.clone();
+        system.register_command("record", Box::new(
+            move |_system : &mut FakeSystem, args : &[String]|
+            {
+                received_clone.lock().unwrap().extend(args.iter().cloned());
+                CommandLineOutput::new()
+            }));
 
+        system.execute_command(to_command_script(vec![
+            "record".to_string(),
+            "my poem.txt".to_string()]));
 
+        assert_eq!(*received.lock().unwrap(), vec!["my poem.txt".to_string()]);
+        assert_eq!(system.get_command_log(), vec![vec!["record".to_string(), "my poem.txt".to_string()]]);
+        assert_eq!(system.get_command_log_joined(), vec!["record my poem.txt".to_string()]);
+    }
 
+    /*  A command registered by a test can change the fake filesystem, and clones made before it was
+        registered can run it too. */
+    #[test]
+    fn registered_command_writes_file()
+    {
+        let mut system = FakeSystem::new(10);
+        let mut clone = system.clone();
+        system.register_command("shout", Box::new(
+            |system : &mut FakeSystem, args : &[String]|
+            {
+                match write_str_to_file(system, &args[1], &args[0].to_uppercase())
+                {
+                    Ok(()) => CommandLineOutput::new(),
+                    Err(_) => CommandLineOutput::error("shout: write failed".to_string()),
+                }
+            }));
+
+        assert_eq!(
+            clone.execute_command(to_command_script(vec![
+                "shout".to_string(),
+                "hello".to_string(),
+                "loud file.txt".to_string()])),
+            vec![Ok(CommandLineOutput::new())]);
 
+        assert_eq!(read_file(&system, "loud file.txt").unwrap(), b"HELLO");
+    }
 
+    /*  A nested tree lists every file at every depth, sorted, with empty directories leaving no trace.
+        Listing nothing errors the way list_dir does. */
+    #[test]
+    fn list_dir_recursive_nested_tree()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir_all("garden/beds/roses").unwrap();
+        system.create_dir_all("garden/shed/empty").unwrap();
+        write_str_to_file(&mut system, "garden/beds/roses/red.txt", "red\n").unwrap();
+        write_str_to_file(&mut system, "garden/beds/tulips.txt", "tulips\n").unwrap();
+        write_str_to_file(&mut system, "garden/zinnias.txt", "zinnias\n").unwrap();
+        write_str_to_file(&mut system, "garden/beds/roses/pink.txt", "pink\n").unwrap();
+        write_str_to_file(&mut system, "garden/shed/rake.txt", "rake\n").unwrap();
+        write_str_to_file(&mut system, "outside.txt", "outside\n").unwrap();
+
+        assert_eq!(system.list_dir_recursive("garden").unwrap(), vec![
+            "garden/beds/roses/pink.txt",
+            "garden/beds/roses/red.txt",
+            "garden/beds/tulips.txt",
+            "garden/shed/rake.txt",
+            "garden/zinnias.txt",
+        ]);
+        assert_eq!(system.list_dir_recursive("garden/shed/empty").unwrap(), Vec::<String>::new());
+        assert_eq!(system.list_dir_recursive("orchard"), Err(SystemError::NotFound));
+    }
+
+    /*  Trailing and doubled slashes don't change what a path names, for any operation. */
+    #[test]
+    fn slash_suffixed_and_doubled_paths()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir("mydir/").unwrap();
+        system.create_dir("mydir//sub//").unwrap();
+        assert!(system.is_dir("mydir"));
+        assert!(system.is_dir("mydir/"));
+        assert!(system.is_dir("mydir//sub"));
+        assert!(system.is_dir("mydir/sub"));
 
+        write_str_to_file(&mut system, "mydir//poem.txt", "Roses are red.\n").unwrap();
+        assert!(system.is_file("mydir/poem.txt"));
+        assert!(system.is_file("mydir//poem.txt"));
+        assert_eq!(read_file(&system, "mydir//poem.txt").unwrap(), b"Roses are red.\n");
 
+        assert_eq!(system.list_dir("mydir/").unwrap(), vec!["mydir/poem.txt", "mydir/sub"]);
+        assert_eq!(system.list_dir("mydir//").unwrap(), system.list_dir("mydir").unwrap());
 
+        assert_eq!(
+            system.get_modified("mydir//poem.txt").unwrap(),
+            system.get_modified("mydir/poem.txt").unwrap());
 
+        system.rename("mydir//poem.txt", "mydir/sub//poem.txt").unwrap();
+        assert!(system.is_file("mydir/sub/poem.txt"));
+        assert!(!system.is_file("mydir/poem.txt"));
+    }
 
+    /*  A path made of nothing, or of nothing but the bits normalizing removes, is empty. */
+    #[test]
+    fn empty_path_rejected()
+    {
+        let mut system = FakeSystem::new(10);
+        assert_eq!(system.create_dir("").unwrap_err(), SystemError::PathEmpty);
+        assert_eq!(system.list_dir("").unwrap_err(), SystemError::PathEmpty);
+        assert_eq!(system.open("").unwrap_err(), SystemError::PathEmpty);
+        assert!(!system.is_dir(""));
+        assert!(!system.is_file(""));
+        assert_eq!(normalize_path("a//b///").unwrap(), "a/b");
+        assert_eq!(normalize_path("//").unwrap(), "/");
+        assert_eq!(normalize_path("/").unwrap(), "/");
+    }
 
+    /*  Creating directories all the way down leaves what's already there in place, and stops at a file. */
+    #[test]
+    fn create_dir_all_keeps_existing()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir("out").unwrap();
+        write_str_to_file(&mut system, "out/keep.txt", "kept").unwrap();
 
+        system.create_dir_all("out/sub/deeper").unwrap();
+        assert!(system.is_dir("out/sub/deeper"));
+        assert!(system.is_file("out/keep.txt"));
 
+        assert_eq!(system.create_dir_all("out/keep.txt/nope").unwrap_err(),
+            SystemError::FileInPlaceOfDirectory("nope".to_string()));
+    }
 
+    /*  Output with an invalid byte in the middle of a line reads with a replacement character in its
+        place, keeping the text around it on both sides, while the bytes stay as written.  Only the
+        stream with the bad byte counts as not UTF-8. */
+    #[test]
+    fn command_line_output_lossy_keeps_surrounding_text()
+    {
+        let output = CommandLineOutput::from_bytes(
+            b"compiling caf\xe9.c\nlinking\n".to_vec(), b"warning: 1\n".to_vec(), Some(0), true);
+        assert_eq!(output.out, "compiling caf\u{FFFD}.c\nlinking\n");
+        assert_eq!(output.out_bytes, b"compiling caf\xe9.c\nlinking\n".to_vec());
+        assert_eq!(output.err, "warning: 1\n");
+        assert!(!output.out_is_utf8());
+        assert!(output.err_is_utf8());
+    }
+}
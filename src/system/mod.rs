@@ -6,14 +6,23 @@ use std::time::SystemTime;
 
 #[cfg(test)]
 pub mod fake;
+#[cfg(all(test, unix))]
+pub mod sandbox;
 pub mod util;
 pub mod real;
 
 #[derive(Debug, PartialEq)]
 pub struct CommandLineOutput
 {
+    /*  The output as text for display.  Bytes that aren't valid UTF-8 show up as replacement
+        characters, so check out_is_utf8 and err_is_utf8 before trusting these to be exact. */
     pub out : String,
     pub err : String,
+
+    /*  The output exactly as the command wrote it. */
+    pub out_bytes : Vec<u8>,
+    pub err_bytes : Vec<u8>,
+
     pub code : Option<i32>,
     pub success : bool,
 }
@@ -22,7 +31,14 @@ pub struct CommandLineOutput
 pub enum ReadWriteError
 {
     IOError(String),
-    SystemError(SystemError)
+    SystemError(SystemError),
+
+    /*  A directory nested deeper than the limit allows, with its path and the limit. */
+    DirectoryTooDeep(String, usize),
+
+    /*  Something that's neither a regular file nor a directory, like a named pipe, with its path and
+        type.  Reading one to hash it could block forever, so it never gets read. */
+    NotRegularFile(String, FileType),
 }
 
 impl fmt::Display for ReadWriteError
@@ -36,13 +52,60 @@ impl fmt::Display for ReadWriteError
 
             ReadWriteError::SystemError(error)
                 => write!(formatter, "{}", error),
+
+            ReadWriteError::DirectoryTooDeep(path, max_depth)
+                => write!(formatter, "Directory nested more than {} levels deep: {}", max_depth, path),
+
+            ReadWriteError::NotRegularFile(path, file_type)
+                => write!(formatter, "Not a regular file or directory: {} is a {}", path, file_type),
         }
     }
 }
 
+/*  What sort of thing a path names, as far as ruler cares.  Other covers named pipes, sockets and
+    devices: things that exist, but that ruler can't sensibly hash. */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FileType
+{
+    Regular,
+    Directory,
+    Symlink,
+    Other,
+}
+
+impl fmt::Display for FileType
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            FileType::Regular => write!(formatter, "regular file"),
+            FileType::Directory => write!(formatter, "directory"),
+            FileType::Symlink => write!(formatter, "symlink"),
+            FileType::Other => write!(formatter, "special file"),
+        }
+    }
+}
+
+/*  Which environment variables a command gets.  Inherit passes along everything ruler's own process
+    has.  Only starts from an empty environment and passes along just the named variables, those of
+    them that are set. */
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandEnvironment
+{
+    Inherit,
+    Only(Vec<String>),
+}
+
 pub struct CommandScript
 {
-    pub lines : Vec<String>
+    pub lines : Vec<String>,
+
+    /*  The same commands, each as the list of words the rule gave it, for systems that run commands
+        without going through a shell. */
+    pub argvs : Vec<Vec<String>>,
+
+    pub environment : CommandEnvironment,
 }
 
 impl fmt::Display for CommandScript
@@ -55,7 +118,7 @@ impl fmt::Display for CommandScript
 
 pub fn to_command_script(mut all_lines : Vec<String>) -> CommandScript
 {
-    let mut command_script = CommandScript{lines:vec![]};
+    let mut command_script = CommandScript{lines:vec![], argvs:vec![], environment:CommandEnvironment::Inherit};
     let mut command_lines : Vec<String> = vec![];
 
     for line in all_lines.drain(..)
@@ -65,6 +128,7 @@ pub fn to_command_script(mut all_lines : Vec<String>) -> CommandScript
             ";" =>
             {
                 command_script.lines.push(command_lines.join(" "));
+                command_script.argvs.push(command_lines);
                 command_lines = vec![];
             },
             _ =>
@@ -77,6 +141,7 @@ pub fn to_command_script(mut all_lines : Vec<String>) -> CommandScript
     if command_lines.len() != 0
     {
         command_script.lines.push(command_lines.join(" "));
+        command_script.argvs.push(command_lines);
     }
 
     command_script
@@ -88,46 +153,45 @@ impl CommandLineOutput
     #[cfg(test)]
     pub fn new() -> CommandLineOutput
     {
-        CommandLineOutput
-        {
-            out : "".to_string(),
-            err : "".to_string(),
-            code : Some(0),
-            success : true,
-        }
+        CommandLineOutput::from_bytes(vec![], vec![], Some(0), true)
     }
 
     #[cfg(test)]
     pub fn error(message : String) -> CommandLineOutput
+    {
+        CommandLineOutput::from_bytes(vec![], message.into_bytes(), Some(1), false)
+    }
+
+    pub fn from_bytes(out_bytes : Vec<u8>, err_bytes : Vec<u8>, code : Option<i32>, success : bool) -> CommandLineOutput
     {
         CommandLineOutput
         {
-            out : "".to_string(),
-            err : message,
-            code : Some(1),
-            success : false,
+            out : String::from_utf8_lossy(&out_bytes).to_string(),
+            err : String::from_utf8_lossy(&err_bytes).to_string(),
+            out_bytes : out_bytes,
+            err_bytes : err_bytes,
+            code : code,
+            success : success,
         }
     }
 
     pub fn from_output(output : Output) -> CommandLineOutput
     {
-        CommandLineOutput
-        {
-            out : match from_utf8(&output.stdout)
-            {
-                Ok(text) => text,
-                Err(_) => "<non-utf8 data>",
-            }.to_string(),
+        CommandLineOutput::from_bytes(
+            output.stdout,
+            output.stderr,
+            output.status.code(),
+            output.status.success())
+    }
 
-            err : match from_utf8(&output.stderr)
-            {
-                Ok(text) => text,
-                Err(_) => "<non-utf8 data>",
-            }.to_string(),
+    pub fn out_is_utf8(&self) -> bool
+    {
+        from_utf8(&self.out_bytes).is_ok()
+    }
 
-            code : output.status.code(),
-            success : output.status.success(),
-        }
+    pub fn err_is_utf8(&self) -> bool
+    {
+        from_utf8(&self.err_bytes).is_ok()
     }
 }
 
@@ -151,6 +215,8 @@ pub enum SystemError
     CreateFileOverExistingDirectory,
     CreateDirectoryOverExistingFile,
     CommandExecutationFailed(String),
+    PermissionDenied,
+    OutOfSpace,
     NotImplemented,
     Weird,
 }
@@ -209,6 +275,12 @@ impl fmt::Display for SystemError
             SystemError::CommandExecutationFailed(message)
                 => write!(formatter, "{}", message),
 
+            SystemError::PermissionDenied
+                => write!(formatter, "Permission denied"),
+
+            SystemError::OutOfSpace
+                => write!(formatter, "No space left on device"),
+
             SystemError::NotImplemented
                 => write!(formatter, "Attempt to perform an operation not currently implemented by fake system"),
 
@@ -226,21 +298,57 @@ pub trait System: Clone + Send + Sync
 
     fn open(&self, path: &str) -> Result<Self::File, SystemError>;
     fn create_file(&mut self, path: &str) -> Result<Self::File, SystemError>;
+
+    /*  Opens the file for writing at its end, keeping what's there, or creates it if it isn't. */
+    fn append_to_file(&mut self, path: &str) -> Result<Self::File, SystemError>;
+
     fn create_dir(&mut self, path: &str) -> Result<(), SystemError>;
+
+    /*  Creates the directory at path along with any directories above it that are missing.  Directories
+        that already exist are left as they are. */
+    fn create_dir_all(&mut self, path: &str) -> Result<(), SystemError>;
+
     fn is_dir(&self, path: &str) -> bool;
     fn is_file(&self, path: &str) -> bool;
 
-    #[cfg(test)]
     fn remove_file(&mut self, path: &str) -> Result<(), SystemError>;
 
-    #[cfg(test)]
     fn remove_dir(&mut self, path: &str) -> Result<(), SystemError>;
 
     fn list_dir(&self, path: &str) -> Result<Vec<String>, SystemError>;
+
     fn rename(&mut self, from: &str, to: &str) -> Result<(), SystemError>;
 
     fn get_modified(&self, path: &str) -> Result<SystemTime, SystemError>;
+    fn get_file_len(&self, path: &str) -> Result<u64, SystemError>;
+
+    /*  What sort of thing is at path, without following a symlink.  NotFound if nothing is. */
+    fn file_type(&self, path: &str) -> Result<FileType, SystemError>;
+
+    /*  A number that changes whenever the file at path gets opened for reading, so comparing one from
+        before a command with one from after tells whether the command read the file.  None means the
+        system can't tell. */
+    fn get_read_marker(&self, path: &str) -> Option<u64>;
+
+    /*  The time now, as far as this system is concerned. */
+    fn now(&self) -> SystemTime;
+
+    /*  Everything on standard input, read to its end.  Anything that takes "-" to mean standard input
+        reads it through here. */
+    fn read_stdin(&mut self) -> Result<Vec<u8>, SystemError>;
+
+    /*  Sets the modified time of the file at path to now, leaving the contents alone. */
+    #[cfg(test)]
+    fn touch(&mut self, path: &str) -> Result<(), SystemError>;
     fn is_executable(&self, path: &str) -> Result<bool, SystemError>;
     fn set_is_executable(&mut self, path: &str, executable : bool) -> Result<(), SystemError>;
+
+    /*  Relative paths given to the other functions are resolved against the current directory. */
+    #[cfg(test)]
+    fn current_dir(&self) -> Result<String, SystemError>;
+
+    #[cfg(test)]
+    fn set_current_dir(&mut self, path: &str) -> Result<(), SystemError>;
+
     fn execute_command(&mut self, command_script: CommandScript) -> Vec<Result<CommandLineOutput, SystemError>>;
 }
@@ -2,11 +2,24 @@ use crate::system::
 {
     System,
     SystemError,
+    FileType,
     CommandScript,
     CommandLineOutput,
+    CommandEnvironment,
 };
+use crate::system::util::
+{
+    get_timestamp,
+    normalize_path,
+};
+#[cfg(test)]
+use std::env;
 use std::fs;
-use std::io::ErrorKind;
+use std::io::
+{
+    ErrorKind,
+    Read,
+};
 use std::path::Path;
 use std::path::PathBuf;
 use std::time::SystemTime;
@@ -33,6 +46,12 @@ fn convert_io_error_to_system_error(error : std::io::Error) -> SystemError
         ErrorKind::NotFound
             => SystemError::NotFound,
 
+        ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem
+            => SystemError::PermissionDenied,
+
+        ErrorKind::StorageFull | ErrorKind::QuotaExceeded
+            => SystemError::OutOfSpace,
+
         _ => SystemError::Weird,
     }
 }
@@ -72,8 +91,14 @@ pub fn set_is_executable(path: &str, executable : bool) -> Result<(), SystemErro
     }
 }
 
+/*  A path as the filesystem wants it: relative to the current directory, unless it starts with '/'. */
 fn to_path_buf(path: &str) -> PathBuf
 {
+    if path.starts_with('/')
+    {
+        return PathBuf::from(path);
+    }
+
     Path::new(".").join(path.split("/").map(|s|{s.to_string()}).collect::<PathBuf>())
 }
 
@@ -114,7 +139,7 @@ impl System for RealSystem
 
     fn open(&self, path: &str) -> Result<Self::File, SystemError>
     {
-        match fs::File::open(to_path_buf(path))
+        match fs::File::open(to_path_buf(&normalize_path(path)?))
         {
             Ok(file) => Ok(file),
             Err(error) => Err(convert_io_error_to_system_error(error)),
@@ -123,33 +148,58 @@ impl System for RealSystem
 
     fn create_file(&mut self, path: &str) -> Result<Self::File, SystemError>
     {
-        match fs::File::create(to_path_buf(path))
+        match fs::File::create(to_path_buf(&normalize_path(path)?))
         {
             Ok(file) => Ok(file),
             Err(error) => Err(convert_io_error_to_system_error(error)),  
         }
     }
 
+    fn append_to_file(&mut self, path: &str) -> Result<Self::File, SystemError>
+    {
+        match fs::OpenOptions::new().append(true).create(true).open(to_path_buf(&normalize_path(path)?))
+        {
+            Ok(file) => Ok(file),
+            Err(error) => Err(convert_io_error_to_system_error(error)),
+        }
+    }
+
     fn create_dir(&mut self, path: &str) -> Result<(), SystemError>
     {
-        match fs::create_dir(to_path_buf(path))
+        match fs::create_dir(to_path_buf(&normalize_path(path)?))
         {
             Ok(_) => Ok(()),
             Err(error) => Err(convert_io_error_to_system_error(error)),  
         }
     }
 
+    fn create_dir_all(&mut self, path: &str) -> Result<(), SystemError>
+    {
+        match fs::create_dir_all(to_path_buf(&normalize_path(path)?))
+        {
+            Ok(_) => Ok(()),
+            Err(error) => Err(convert_io_error_to_system_error(error)),
+        }
+    }
+
     fn is_file(&self, path: &str) -> bool
     {
-        Path::new(&to_path_buf(path)).is_file()
+        match normalize_path(path)
+        {
+            Ok(path) => Path::new(&to_path_buf(&path)).is_file(),
+            Err(_) => false,
+        }
     }
 
     fn is_dir(&self, path: &str) -> bool
     {
-        Path::new(&to_path_buf(path)).is_dir()
+        match normalize_path(path)
+        {
+            Ok(path) => Path::new(&to_path_buf(&path)).is_dir(),
+            Err(_) => false,
+        }
     }
 
-    #[cfg(test)]
     fn remove_file(&mut self, path: &str) -> Result<(), SystemError>
     {
         match fs::remove_file(to_path_buf(path))
@@ -159,7 +209,6 @@ impl System for RealSystem
         }
     }
 
-    #[cfg(test)]
     fn remove_dir(&mut self, path: &str) -> Result<(), SystemError>
     {
         match fs::remove_dir(to_path_buf(path))
@@ -171,7 +220,7 @@ impl System for RealSystem
 
     fn list_dir(&self, path: &str) -> Result<Vec<String>, SystemError>
     {
-        let path_buf = to_path_buf(path);
+        let path_buf = to_path_buf(&normalize_path(path)?);
         if !Path::new(&path_buf).is_dir()
         {
             if Path::new(&path_buf).is_file()
@@ -209,7 +258,7 @@ impl System for RealSystem
 
     fn rename(&mut self, from: &str, to: &str) -> Result<(), SystemError>
     {
-        match fs::rename(from, to)
+        match fs::rename(normalize_path(from)?, normalize_path(to)?)
         {
             Ok(_) => Ok(()),
             Err(error) => Err(convert_io_error_to_system_error(error)),
@@ -218,7 +267,7 @@ impl System for RealSystem
 
     fn get_modified(&self, path: &str) -> Result<SystemTime, SystemError>
     {
-        match fs::metadata(path)
+        match fs::metadata(normalize_path(path)?)
         {
             Ok(metadata) =>
             {
@@ -232,6 +281,98 @@ impl System for RealSystem
         }
     }
 
+    fn get_file_len(&self, path: &str) -> Result<u64, SystemError>
+    {
+        match fs::metadata(path)
+        {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(_) => Err(SystemError::MetadataNotFound)
+        }
+    }
+
+    /*  Doesn't follow symlinks, so a symlink reports as one, whatever it points to. */
+    fn file_type(&self, path: &str) -> Result<FileType, SystemError>
+    {
+        match fs::symlink_metadata(path)
+        {
+            Ok(metadata) =>
+            {
+                let file_type = metadata.file_type();
+                if file_type.is_symlink()
+                {
+                    Ok(FileType::Symlink)
+                }
+                else if file_type.is_dir()
+                {
+                    Ok(FileType::Directory)
+                }
+                else if file_type.is_file()
+                {
+                    Ok(FileType::Regular)
+                }
+                else
+                {
+                    Ok(FileType::Other)
+                }
+            },
+            Err(_) => Err(SystemError::NotFound)
+        }
+    }
+
+    fn now(&self) -> SystemTime
+    {
+        SystemTime::now()
+    }
+
+    fn read_stdin(&mut self) -> Result<Vec<u8>, SystemError>
+    {
+        let mut content = vec![];
+        match std::io::stdin().read_to_end(&mut content)
+        {
+            Ok(_size) => Ok(content),
+            Err(error) => Err(convert_io_error_to_system_error(error)),
+        }
+    }
+
+    /*  Best effort: uses the access time, which filesystems mounted noatime never update, and relatime
+        mounts only update sometimes, so an unchanged marker doesn't prove the file went unread. */
+    fn get_read_marker(&self, path: &str) -> Option<u64>
+    {
+        match fs::metadata(path)
+        {
+            Ok(metadata) =>
+            {
+                match metadata.accessed()
+                {
+                    Ok(timestamp) => match get_timestamp(timestamp)
+                    {
+                        Ok(timestamp) => Some(timestamp),
+                        Err(_) => None,
+                    },
+                    Err(_) => None,
+                }
+            },
+            Err(_) => None,
+        }
+    }
+
+    #[cfg(test)]
+    fn touch(&mut self, path: &str) -> Result<(), SystemError>
+    {
+        let file =
+        match fs::OpenOptions::new().append(true).open(to_path_buf(path))
+        {
+            Ok(file) => file,
+            Err(error) => return Err(convert_io_error_to_system_error(error)),
+        };
+
+        match file.set_modified(SystemTime::now())
+        {
+            Ok(_) => Ok(()),
+            Err(error) => Err(convert_io_error_to_system_error(error)),
+        }
+    }
+
     fn is_executable(&self, path: &str) -> Result<bool, SystemError>
     {
         is_executable(path)
@@ -242,6 +383,33 @@ impl System for RealSystem
         set_is_executable(path, executable)
     }
 
+    #[cfg(test)]
+    fn current_dir(&self) -> Result<String, SystemError>
+    {
+        match env::current_dir()
+        {
+            Ok(path_buf) =>
+            {
+                match path_buf.to_str()
+                {
+                    Some(path_str) => Ok(path_str.to_string()),
+                    None => Err(SystemError::PathNotUnicode),
+                }
+            },
+            Err(error) => Err(convert_io_error_to_system_error(error)),
+        }
+    }
+
+    #[cfg(test)]
+    fn set_current_dir(&mut self, path: &str) -> Result<(), SystemError>
+    {
+        match env::set_current_dir(path)
+        {
+            Ok(_) => Ok(()),
+            Err(error) => Err(convert_io_error_to_system_error(error)),
+        }
+    }
+
     fn execute_command(&mut self, command_script : CommandScript) ->
         Vec<Result<CommandLineOutput, SystemError>>
     {
@@ -249,6 +417,22 @@ impl System for RealSystem
         for element in command_script.lines.into_iter()
         {
             let mut cmd = execute::shell(element);
+            match &command_script.environment
+            {
+                CommandEnvironment::Inherit => {},
+                CommandEnvironment::Only(names) =>
+                {
+                    cmd.env_clear();
+                    for name in names.iter()
+                    {
+                        match std::env::var_os(name)
+                        {
+                            Some(value) => { cmd.env(name, value); },
+                            None => {},
+                        }
+                    }
+                },
+            }
             match cmd.execute_output()
             {
                 Ok(output) => result.push(Ok(CommandLineOutput::from_output(output))),
@@ -0,0 +1,96 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::
+{
+    Mutex,
+    MutexGuard,
+};
+use std::sync::atomic::
+{
+    AtomicUsize,
+    Ordering,
+};
+use crate::system::real::RealSystem;
+use crate::system::util::
+{
+    write_str_to_file,
+    read_file_to_string,
+};
+
+/*  RealSystem resolves relative paths, and runs commands, in the process's current directory, which
+    all threads share.  Sandboxes take turns holding this while they're current. */
+static CURRENT_DIR_LOCK : Mutex<()> = Mutex::new(());
+
+static SANDBOX_COUNT : AtomicUsize = AtomicUsize::new(0);
+
+/*  A fresh directory under the system temp directory, made the current directory for as long as the
+    Sandbox lives, so a RealSystem sees only what the test puts there.  Each one gets a name of its own
+    (the test's name, the process id and a counter), so tests never share files, though they do take
+    turns because the current directory belongs to the whole process.  Dropping it restores the old
+    current directory and removes the sandbox, whether the test passed or panicked. */
+pub struct Sandbox
+{
+    pub path : PathBuf,
+    previous_dir : PathBuf,
+    _guard : MutexGuard<'static, ()>,
+}
+
+impl Sandbox
+{
+    pub fn new(name : &str) -> Sandbox
+    {
+        /*  A test that panicked while holding the lock poisons it, but its Sandbox still cleaned up. */
+        let guard = match CURRENT_DIR_LOCK.lock()
+        {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let path = env::temp_dir().join(
+            format!("ruler-sandbox-{}-{}-{}", name, process::id(), SANDBOX_COUNT.fetch_add(1, Ordering::SeqCst)));
+        if path.exists()
+        {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        fs::create_dir_all(&path).unwrap();
+
+        let previous_dir = env::current_dir().unwrap();
+        env::set_current_dir(&path).unwrap();
+
+        Sandbox
+        {
+            path : path,
+            previous_dir : previous_dir,
+            _guard : guard,
+        }
+    }
+
+    /*  A RealSystem, which works relative to the sandbox while the sandbox is current. */
+    pub fn system(&self) -> RealSystem
+    {
+        RealSystem::new()
+    }
+
+    /*  Writes a file in the sandbox through the RealSystem, the way write_str_to_file would. */
+    pub fn write(&self, path : &str, content : &str)
+    {
+        write_str_to_file(&mut self.system(), path, content).unwrap();
+    }
+
+    /*  Reads a file in the sandbox through the RealSystem, the way read_file_to_string would. */
+    pub fn read(&self, path : &str) -> String
+    {
+        read_file_to_string(&self.system(), path).unwrap()
+    }
+}
+
+impl Drop for Sandbox
+{
+    fn drop(&mut self)
+    {
+        let _ = env::set_current_dir(&self.previous_dir);
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
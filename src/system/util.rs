@@ -1,7 +1,6 @@
 use crate::system::SystemError;
 use std::io;
 
-#[cfg(test)]
 use crate::system::ReadWriteError;
 
 use crate::system::System;
@@ -9,7 +8,6 @@ use crate::system::System;
 #[cfg(test)]
 use std::io::Read;
 
-#[cfg(test)]
 use std::io::Write;
 
 #[cfg(test)]
@@ -40,9 +38,47 @@ pub fn get_timestamp(system_time : SystemTime) -> Result<u64, SystemTimeError>
     }
 }
 
+/*  Puts a path given to a System in the one form both implementations work with: runs of slashes
+    collapse to one, and trailing slashes come off, except that "/" on its own stays "/".  That way
+    "mydir/" and "mydir" name the same thing everywhere.  The empty path is an error. */
+pub fn normalize_path(path : &str) -> Result<String, SystemError>
+{
+    let mut result = String::new();
+    let mut previous_slash = false;
+    for c in path.chars()
+    {
+        if c == '/'
+        {
+            if previous_slash
+            {
+                continue;
+            }
+            previous_slash = true;
+        }
+        else
+        {
+            previous_slash = false;
+        }
+        result.push(c);
+    }
+
+    while result.len() > 1 && result.ends_with('/')
+    {
+        result.pop();
+    }
+
+    if result == ""
+    {
+        Err(SystemError::PathEmpty)
+    }
+    else
+    {
+        Ok(result)
+    }
+}
+
 /*  Takes a System, a path as a &str and content, and content as a &str.  Writes content to the file.
     If system fails, forwards the system error.  If file-io fails, forwards the std::io::Error. */
-#[cfg(test)]
 pub fn write_str_to_file
 <
     SystemType : System,
@@ -17,10 +17,36 @@ use crate::system::
     System,
     ReadWriteError,
     SystemError,
+    FileType,
 };
+use crate::ignore::IgnorePatterns;
 use std::fmt;
 use std::io::Read;
 
+/*  The error for a path that's neither a file nor a directory: NotRegularFile if there's something
+    there anyway, like a named pipe, which must not get opened, since reading it could block forever.
+    Otherwise NotFound. */
+pub fn get_neither_file_nor_directory_error<FSType: System>
+(
+    system : &FSType,
+    path : &str
+)
+-> ReadWriteError
+{
+    match system.file_type(path)
+    {
+        Ok(FileType::Other) => ReadWriteError::NotRegularFile(path.to_string(), FileType::Other),
+        _ => ReadWriteError::SystemError(SystemError::NotFound),
+    }
+}
+
+/*  How many characters of a ticket short_human_readable keeps. */
+const SHORT_TICKET_LENGTH : usize = 10;
+
+/*  How deep from_directory goes before deciding the tree is pathological.  Far deeper than any
+    legitimate source tree. */
+pub const DEFAULT_MAX_DIRECTORY_DEPTH : usize = 256;
+
 use num_bigint::
 {
     BigUint
@@ -196,7 +222,7 @@ impl TicketFactory
             return Self::from_directory(file_system, path)
         }
 
-        return Err(ReadWriteError::SystemError(SystemError::NotFound));
+        return Err(get_neither_file_nor_directory_error(file_system, path));
     }
 
     /*  Construct a TicketFactory, initialized with the contents of a file from a System. */
@@ -234,7 +260,9 @@ impl TicketFactory
         }
     }
 
-    /*  Construct a TicketFactory, initialized with the contents of a file from a System. */
+    /*  Construct a TicketFactory from a directory in a System: its listing, then the ticket of each thing
+        in it, recursively, leaving out whatever IGNORE_FILE_PATH says to.  Gives up on directories nested
+        more than DEFAULT_MAX_DIRECTORY_DEPTH deep. */
     pub fn from_directory<FSType: System>
     (
         system: &FSType,
@@ -243,43 +271,114 @@ impl TicketFactory
     ->
     Result<TicketFactory, ReadWriteError>
     {
-        let path_list =
-        match system.list_dir(path)
-        {
-            Ok(path_list) => path_list,
-            Err(_error) => return Err(ReadWriteError::SystemError(SystemError::NotFound)),
-        };
+        TicketFactory::from_directory_with_max_depth(system, path, DEFAULT_MAX_DIRECTORY_DEPTH)
+    }
 
-        let mut factory = TicketFactory::from_str(&path_list.join("\n"));
-        for path in path_list
+    /*  Like from_directory, but with a given limit on nesting.  The directory at path is depth zero, and
+        a directory more than max_depth levels below it is an error: that's either a pathological tree
+        or a symlink loop.  Walks the tree with an explicit stack instead of recursion, so a large limit
+        doesn't risk overflowing the call stack. */
+    pub fn from_directory_with_max_depth<FSType: System>
+    (
+        system: &FSType,
+        path : &str,
+        max_depth : usize
+    )
+    ->
+    Result<TicketFactory, ReadWriteError>
+    {
+        let ignore_patterns = IgnorePatterns::from_system(system)?;
+
+        /*  Each frame is a directory in progress: its factory, its listing, and how far through
+            the listing it's gotten. */
+        let mut stack : Vec<(TicketFactory, Vec<String>, usize)> = vec![];
+        stack.push(TicketFactory::start_directory(system, path, &ignore_patterns)?);
+
+        loop
         {
-            if system.is_dir(&path)
+            let depth = stack.len();
+            let next_path_opt =
+            match stack.last_mut()
             {
-                let mut sub_factory =
-                match TicketFactory::from_directory(system, &path)
+                Some((_factory, path_list, next)) =>
                 {
-                    Ok(fact) => fact,
-                    Err(error) => return Err(error),
-                };
-                factory.input_ticket(sub_factory.result());
-            }
-            else if system.is_file(&path)
+                    if *next < path_list.len()
+                    {
+                        *next += 1;
+                        Some(path_list[*next - 1].clone())
+                    }
+                    else
+                    {
+                        None
+                    }
+                },
+                None => return Err(ReadWriteError::SystemError(SystemError::Weird)),
+            };
+
+            match next_path_opt
             {
-                let mut sub_factory =
-                match TicketFactory::from_file(system, &path)
+                Some(next_path) =>
                 {
-                    Ok(fact) => fact,
-                    Err(error) => return Err(error),
-                };
-                factory.input_ticket(sub_factory.result());
-            }
-            else
-            {
-                return Err(ReadWriteError::SystemError(SystemError::NotFound));
+                    if system.is_dir(&next_path)
+                    {
+                        if depth > max_depth
+                        {
+                            return Err(ReadWriteError::DirectoryTooDeep(next_path, max_depth));
+                        }
+                        stack.push(TicketFactory::start_directory(system, &next_path, &ignore_patterns)?);
+                    }
+                    else if system.is_file(&next_path)
+                    {
+                        let ticket = TicketFactory::from_file(system, &next_path)?.result();
+                        match stack.last_mut()
+                        {
+                            Some((factory, _path_list, _next)) => factory.input_ticket(ticket),
+                            None => return Err(ReadWriteError::SystemError(SystemError::Weird)),
+                        }
+                    }
+                    else
+                    {
+                        return Err(get_neither_file_nor_directory_error(system, &next_path));
+                    }
+                },
+                None =>
+                {
+                    let (mut factory, _path_list, _next) =
+                    match stack.pop()
+                    {
+                        Some(frame) => frame,
+                        None => return Err(ReadWriteError::SystemError(SystemError::Weird)),
+                    };
+
+                    match stack.last_mut()
+                    {
+                        Some((parent_factory, _path_list, _next)) => parent_factory.input_ticket(factory.result()),
+                        None => return Ok(factory),
+                    }
+                },
             }
         }
+    }
+
+    /*  Lists the directory at path and starts a factory with the listing, ready to take the ticket of
+        each thing in it.  Ignored things are left out of the listing, so they count for nothing. */
+    fn start_directory<FSType: System>
+    (
+        system: &FSType,
+        path : &str,
+        ignore_patterns : &IgnorePatterns
+    )
+    ->
+    Result<(TicketFactory, Vec<String>, usize), ReadWriteError>
+    {
+        let path_list : Vec<String> =
+        match system.list_dir(path)
+        {
+            Ok(path_list) => path_list.into_iter().filter(|path| ! ignore_patterns.is_ignored(path)).collect(),
+            Err(_error) => return Err(ReadWriteError::SystemError(SystemError::NotFound)),
+        };
 
-        Ok(factory)
+        Ok((TicketFactory::from_str(&path_list.join("\n")), path_list, 0))
     }
 }
 
@@ -303,12 +402,35 @@ impl Hash for Ticket
 
 impl Ticket
 {
+    /*  Make a ticket straight from the bytes of a hash.  Useful in tests that need tickets with
+        particular bytes, like two tickets which agree on their first few bytes. */
+    #[cfg(test)]
+    pub fn from_bytes(sha : [u8; 32]) -> Ticket
+    {
+        Ticket{sha:sha}
+    }
+
+    /*  The first eight bytes of the hash as a number, for seeding a random number generator so that
+        the same ticket always gives the same sequence. */
+    pub fn to_seed(&self) -> u64
+    {
+        self.sha[..8].iter().fold(0u64, |seed, byte| (seed << 8) | u64::from(*byte))
+    }
+
     /*  Returns a string URL-safe human-readable hash string */
     pub fn human_readable(&self) -> String
     {
         encode62(&self.sha)
     }
 
+    /*  The start of the human-readable string, enough to tell tickets apart at a glance in listings
+        and diagnostics, not enough to look anything up by. */
+    pub fn short_human_readable(&self) -> String
+    {
+        let human_readable = self.human_readable();
+        human_readable[..SHORT_TICKET_LENGTH.min(human_readable.len())].to_string()
+    }
+
     /*  Takes a url-safe human-readable hash string and returns a ticket objcet
         or an error about why the hash string was invalid. */
     pub fn from_human_readable(human_readable_str: &str) ->
@@ -429,7 +551,13 @@ mod test
     {
         FakeSystem
     };
-    use crate::system::System;
+    use crate::system::
+    {
+        System,
+        ReadWriteError,
+        SystemError,
+        FileType,
+    };
     use lipsum::{LOREM_IPSUM};
     use std::collections::HashSet;
     use rand::prelude::*;
@@ -665,6 +793,111 @@ mod test
         assert_ne!(ticket0, ticket1)
     }
 
+    /*  Nest directories five deep with a file at the bottom.  A limit of five hashes the tree, same as
+        the default, and a limit of four fails naming the directory that went over. */
+    #[test]
+    fn ticket_factory_directory_depth_limit()
+    {
+        let mut system = FakeSystem::new(10);
+        let mut path = "deep".to_string();
+        system.create_dir(&path).unwrap();
+        for _ in 0..5
+        {
+            path.push_str("/d");
+            system.create_dir(&path).unwrap();
+        }
+        write_str_to_file(&mut system, &format!("{}/bottom.txt", path), "Way down.\n").unwrap();
+
+        let ticket = TicketFactory::from_directory_with_max_depth(&system, "deep", 5).unwrap().result();
+        assert_eq!(ticket, TicketFactory::from_directory(&system, "deep").unwrap().result());
+
+        match TicketFactory::from_directory_with_max_depth(&system, "deep", 4)
+        {
+            Err(ReadWriteError::DirectoryTooDeep(path, max_depth)) =>
+            {
+                assert_eq!(path, "deep/d/d/d/d/d");
+                assert_eq!(max_depth, 4);
+            },
+            _ => panic!("Expected directory too deep error"),
+        }
+    }
+
+    /*  A volatile file in a directory changes its ticket, unless .rulerignore names it, in which case
+        the ticket is as if the file weren't there.  Take the pattern away and the ticket changes again. */
+    #[test]
+    fn ticket_factory_directory_ignores_patterns()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir("out").unwrap();
+        write_str_to_file(&mut system, "out/poem.txt", "Roses are red.\n").unwrap();
+        let clean_ticket = TicketFactory::from_directory(&system, "out").unwrap().result();
+
+        system.create_dir("out/__pycache__").unwrap();
+        write_str_to_file(&mut system, "out/__pycache__/poem.pyc", "bytecode").unwrap();
+        write_str_to_file(&mut system, "out/.DS_Store", "finder junk").unwrap();
+        let messy_ticket = TicketFactory::from_directory(&system, "out").unwrap().result();
+        assert_ne!(messy_ticket, clean_ticket);
+
+        write_str_to_file(&mut system, ".rulerignore", ".DS_Store\n__pycache__/\n").unwrap();
+        assert_eq!(TicketFactory::from_directory(&system, "out").unwrap().result(), clean_ticket);
+
+        write_str_to_file(&mut system, ".rulerignore", "__pycache__/\n").unwrap();
+        let ticket = TicketFactory::from_directory(&system, "out").unwrap().result();
+        assert_ne!(ticket, clean_ticket);
+        assert_ne!(ticket, messy_ticket);
+
+        system.remove_file(".rulerignore").unwrap();
+        assert_eq!(TicketFactory::from_directory(&system, "out").unwrap().result(), messy_ticket);
+    }
+
+    /*  A special file, alone or inside a directory, is an error naming it rather than something to
+        read. */
+    #[test]
+    fn ticket_factory_refuses_special_file()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir("pipes").unwrap();
+        system.create_special_file("pipes/in.fifo").unwrap();
+
+        assert_eq!(TicketFactory::from_path(&system, "pipes/in.fifo").err(),
+            Some(ReadWriteError::NotRegularFile("pipes/in.fifo".to_string(), FileType::Other)));
+        assert_eq!(TicketFactory::from_path(&system, "pipes").err(),
+            Some(ReadWriteError::NotRegularFile("pipes/in.fifo".to_string(), FileType::Other)));
+        assert_eq!(TicketFactory::from_path(&system, "pipes/out.fifo").err(),
+            Some(ReadWriteError::SystemError(SystemError::NotFound)));
+    }
+
+    /*  Hashing with the explicit stack agrees with hashing by hand: a directory's listing, then the
+        ticket of each thing in it. */
+    #[test]
+    fn ticket_factory_nested_directory_matches_by_hand()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir("top").unwrap();
+        system.create_dir("top/sub").unwrap();
+        write_str_to_file(&mut system, "top/sub/a.txt", "apple\n").unwrap();
+        write_str_to_file(&mut system, "top/b.txt", "banana\n").unwrap();
+
+        let mut sub_factory = TicketFactory::from_str(&system.list_dir("top/sub").unwrap().join("\n"));
+        sub_factory.input_ticket(TicketFactory::from_str("apple\n").result());
+
+        let top_list = system.list_dir("top").unwrap();
+        let mut top_factory = TicketFactory::from_str(&top_list.join("\n"));
+        for path in top_list.iter()
+        {
+            if path == "top/sub"
+            {
+                top_factory.input_ticket(sub_factory.result());
+            }
+            else
+            {
+                top_factory.input_ticket(TicketFactory::from_str("banana\n").result());
+            }
+        }
+
+        assert_eq!(TicketFactory::from_directory(&system, "top").unwrap().result(), top_factory.result());
+    }
+
     /*  Using a fake file-system, create a file, populate it with with known text, then use TicketFactory::from_str
         and input_ticket to simulate making a ticket with that file as a target.  Compare the hash with an exemplar.*/
     #[test]
@@ -752,4 +985,18 @@ mod test
             Err(FromHumanReadableError::InvalidLength)
         );
     }
+
+    /*  "ruler hash mydir/" and "ruler hash mydir" agree. */
+    #[test]
+    fn ticket_factory_directory_trailing_slash()
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir("mydir").unwrap();
+        system.create_dir("mydir/sub").unwrap();
+        write_str_to_file(&mut system, "mydir/sub/poem.txt", "Roses are red.\n").unwrap();
+
+        let ticket = TicketFactory::from_path(&system, "mydir").unwrap().result();
+        assert_eq!(TicketFactory::from_path(&system, "mydir/").unwrap().result(), ticket);
+        assert_eq!(TicketFactory::from_path(&system, "mydir//").unwrap().result(), ticket);
+    }
 }
@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::
+{
+    Read,
+    Write,
+};
+use std::time::SystemTime;
+use serde::
+{
+    Serialize,
+    Deserialize
+};
+use crate::build::
+{
+    get_nodes,
+    BuildError,
+};
+use crate::rule::Provenance;
+use crate::sort::
+{
+    NodePack,
+    SourceIndex,
+};
+use crate::ticket::Ticket;
+use crate::printer::Printer;
+use crate::system::System;
+use crate::system::util::get_timestamp;
+
+/*  When a node's thread started waiting for its sources, when the sources arrived, and when the node
+    finished its work (checking, recovering or running the command).  All in microseconds since the epoch. */
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct NodeTiming
+{
+    pub wait_start : u64,
+    pub wait_end : u64,
+    pub work_end : u64,
+}
+
+impl NodeTiming
+{
+    pub fn work_micros(&self) -> u64
+    {
+        self.work_end.saturating_sub(self.wait_end)
+    }
+}
+
+/*  The current time in microseconds since the epoch, or zero if the clock is somehow before that. */
+pub fn now() -> u64
+{
+    match get_timestamp(SystemTime::now())
+    {
+        Ok(timestamp) => timestamp,
+        Err(_) => 0,
+    }
+}
+
+/*  Timings for each rule (by rule-ticket) from the most recent build.  Build writes one of these to
+    the ruler directory every time, so the critical-path subcommand has something to read. */
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct BuildTimings
+{
+    timings : HashMap<Ticket, NodeTiming>,
+}
+
+impl BuildTimings
+{
+    pub fn new() -> BuildTimings
+    {
+        BuildTimings
+        {
+            timings : HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, rule_ticket : Ticket, timing : NodeTiming)
+    {
+        self.timings.insert(rule_ticket, timing);
+    }
+
+    pub fn get(&self, rule_ticket : &Ticket) -> Option<&NodeTiming>
+    {
+        self.timings.get(rule_ticket)
+    }
+}
+
+#[derive(Debug)]
+pub enum TimingsError
+{
+    CannotReadTimingsFile(String),
+    CannotInterpretTimingsFile(String),
+    CannotWriteTimingsFile(String),
+}
+
+impl fmt::Display for TimingsError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            TimingsError::CannotReadTimingsFile(path) =>
+                write!(formatter, "Cannot read timings file: {}", path),
+
+            TimingsError::CannotInterpretTimingsFile(path) =>
+                write!(formatter, "Cannot interpret timings file: {}", path),
+
+            TimingsError::CannotWriteTimingsFile(path) =>
+                write!(formatter, "Cannot write timings file: {}", path),
+        }
+    }
+}
+
+/*  The path of the timings file inside the ruler directory. */
+pub fn get_timings_path(directory_path : &str) -> String
+{
+    format!("{}/timings", directory_path)
+}
+
+pub fn write_timings<SystemType : System>
+(
+    system : &mut SystemType,
+    path : &str,
+    timings : &BuildTimings
+)
+-> Result<(), TimingsError>
+{
+    let content =
+    match bincode::serialize(timings)
+    {
+        Ok(content) => content,
+        Err(_) => return Err(TimingsError::CannotWriteTimingsFile(path.to_string())),
+    };
+
+    let mut file =
+    match system.create_file(path)
+    {
+        Ok(file) => file,
+        Err(_) => return Err(TimingsError::CannotWriteTimingsFile(path.to_string())),
+    };
+
+    match file.write_all(&content)
+    {
+        Ok(_) => Ok(()),
+        Err(_) => Err(TimingsError::CannotWriteTimingsFile(path.to_string())),
+    }
+}
+
+pub fn read_timings<SystemType : System>
+(
+    system : &SystemType,
+    path : &str
+)
+-> Result<BuildTimings, TimingsError>
+{
+    let mut file =
+    match system.open(path)
+    {
+        Ok(file) => file,
+        Err(_) => return Err(TimingsError::CannotReadTimingsFile(path.to_string())),
+    };
+
+    let mut content = Vec::new();
+    match file.read_to_end(&mut content)
+    {
+        Ok(_size) => {},
+        Err(_) => return Err(TimingsError::CannotReadTimingsFile(path.to_string())),
+    }
+
+    match bincode::deserialize(&content)
+    {
+        Ok(timings) => Ok(timings),
+        Err(_) => Err(TimingsError::CannotInterpretTimingsFile(path.to_string())),
+    }
+}
+
+/*  One rule on the critical path, with the time its own work took, and the time from the start of
+    the path up to and including this rule. */
+#[derive(Debug, PartialEq)]
+pub struct CriticalPathStep
+{
+    pub targets : Vec<String>,
+    pub provenance : Provenance,
+    pub work_micros : u64,
+    pub cumulative_micros : u64,
+}
+
+/*  Finds the longest path through the graph, weighting each node by its recorded work time.
+    A node's cumulative time is its own plus the greatest cumulative time among the nodes it
+    depends on, so the dependence achieving that maximum is the one that determined when the node
+    could start.  Nodes missing from the timings (say, rules added since the last build) count as
+    zero.  Since nodes come sorted, one pass in order computes everything. */
+pub fn critical_path(node_pack : &NodePack, timings : &BuildTimings) -> Vec<CriticalPathStep>
+{
+    let mut cumulative : Vec<u64> = Vec::new();
+    let mut predecessor : Vec<Option<usize>> = Vec::new();
+    let mut own : Vec<u64> = Vec::new();
+
+    for node in node_pack.nodes.iter()
+    {
+        let work_micros = match timings.get(&node.rule_ticket)
+        {
+            Some(timing) => timing.work_micros(),
+            None => 0,
+        };
+
+        let mut best : Option<usize> = None;
+        for source_index in node.source_indices.iter()
+        {
+            match source_index
+            {
+                SourceIndex::Leaf(_) => {},
+                SourceIndex::Pair(i, _sub_index) =>
+                {
+                    match best
+                    {
+                        Some(b) if cumulative[b] >= cumulative[*i] => {},
+                        _ => best = Some(*i),
+                    }
+                },
+            }
+        }
+
+        own.push(work_micros);
+        cumulative.push(work_micros + match best { Some(b) => cumulative[b], None => 0 });
+        predecessor.push(best);
+    }
+
+    let mut end : Option<usize> = None;
+    for i in 0..cumulative.len()
+    {
+        match end
+        {
+            Some(e) if cumulative[e] >= cumulative[i] => {},
+            _ => end = Some(i),
+        }
+    }
+
+    let mut path = Vec::new();
+    while let Some(i) = end
+    {
+        path.push(CriticalPathStep
+        {
+            targets : node_pack.nodes[i].targets.clone(),
+            provenance : node_pack.nodes[i].provenance.clone(),
+            work_micros : own[i],
+            cumulative_micros : cumulative[i],
+        });
+        end = predecessor[i];
+    }
+
+    path.reverse();
+    path
+}
+
+pub enum CriticalPathError
+{
+    BuildError(BuildError),
+    TimingsError(TimingsError),
+}
+
+impl fmt::Display for CriticalPathError
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            CriticalPathError::BuildError(error) =>
+                write!(formatter, "{}", error),
+
+            CriticalPathError::TimingsError(error) =>
+                write!(formatter, "{} (has there been a build yet?)", error),
+        }
+    }
+}
+
+fn format_millis(micros : u64) -> String
+{
+    format!("{}.{:03}ms", micros / 1000, micros % 1000)
+}
+
+/*  This is the function that runs when you type "ruler critical-path" at the command-line.
+    Reads the rules and the timings from the last build, and prints the rules on the critical
+    path in order, each with its own time and the cumulative time. */
+pub fn report_critical_path
+<
+    SystemType : System,
+    PrinterType : Printer,
+>
+(
+    system : SystemType,
+    printer : &mut PrinterType,
+    directory_path : &str,
+    rulefile_paths : Vec<String>,
+    goal_target_opt : Option<String>,
+    dedupe_rules : bool,
+    case_insensitive_paths : bool
+)
+-> Result<(), CriticalPathError>
+{
+    let node_pack = match get_nodes(&system, rulefile_paths, goal_target_opt, dedupe_rules, case_insensitive_paths, None)
+    {
+        Ok(node_pack) => node_pack,
+        Err(error) => return Err(CriticalPathError::BuildError(error)),
+    };
+
+    let timings = match read_timings(&system, &get_timings_path(directory_path))
+    {
+        Ok(timings) => timings,
+        Err(error) => return Err(CriticalPathError::TimingsError(error)),
+    };
+
+    for step in critical_path(&node_pack, &timings)
+    {
+        printer.print(
+            &format!("{:>12} {:>12}  {} ({})",
+                format_millis(step.work_micros),
+                format_millis(step.cumulative_micros),
+                step.targets.join(" "),
+                step.provenance));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test
+{
+    use crate::rule::
+    {
+        Rule,
+        Provenance,
+    };
+    use crate::sort::topological_sort_all;
+    use crate::timing::
+    {
+        BuildTimings,
+        NodeTiming,
+        CriticalPathStep,
+        critical_path,
+        read_timings,
+        write_timings,
+    };
+    use crate::system::fake::FakeSystem;
+
+    fn make_rule(target : &str, sources : Vec<&str>, line : usize) -> Rule
+    {
+        Rule::with_provenance(
+            vec![target.to_string()],
+            sources.iter().map(|source| source.to_string()).collect(),
+            vec!["mycat".to_string()],
+            Provenance::new("build.rules", line))
+    }
+
+    fn make_timing(wait_end : u64, work_micros : u64) -> NodeTiming
+    {
+        NodeTiming
+        {
+            wait_start : 0,
+            wait_end : wait_end,
+            work_end : wait_end + work_micros,
+        }
+    }
+
+    /*  A diamond: base from a leaf, left and right from base, top from left and right.  Left is slower
+        than right, so the path goes base, left, top, with cumulative times adding up along the way. */
+    #[test]
+    fn critical_path_diamond()
+    {
+        let base = make_rule("base.txt", vec!["leaf.txt"], 1);
+        let left = make_rule("left.txt", vec!["base.txt"], 6);
+        let right = make_rule("right.txt", vec!["base.txt"], 11);
+        let top = make_rule("top.txt", vec!["left.txt", "right.txt"], 16);
+
+        let mut timings = BuildTimings::new();
+        timings.insert(base.get_ticket(), make_timing(100, 1000));
+        timings.insert(left.get_ticket(), make_timing(1100, 5000));
+        timings.insert(right.get_ticket(), make_timing(1100, 2000));
+        timings.insert(top.get_ticket(), make_timing(6100, 3000));
+
+        let node_pack = topological_sort_all(vec![top, right, left, base]).unwrap();
+
+        assert_eq!(critical_path(&node_pack, &timings),
+            vec![
+                CriticalPathStep
+                {
+                    targets : vec!["base.txt".to_string()],
+                    provenance : Provenance::new("build.rules", 1),
+                    work_micros : 1000,
+                    cumulative_micros : 1000,
+                },
+                CriticalPathStep
+                {
+                    targets : vec!["left.txt".to_string()],
+                    provenance : Provenance::new("build.rules", 6),
+                    work_micros : 5000,
+                    cumulative_micros : 6000,
+                },
+                CriticalPathStep
+                {
+                    targets : vec!["top.txt".to_string()],
+                    provenance : Provenance::new("build.rules", 16),
+                    work_micros : 3000,
+                    cumulative_micros : 9000,
+                },
+            ]);
+    }
+
+    /*  With no timings at all, every node counts as zero, and the path is still well-formed. */
+    #[test]
+    fn critical_path_no_timings()
+    {
+        let node_pack = topological_sort_all(vec![make_rule("a.txt", vec!["leaf.txt"], 1)]).unwrap();
+        assert_eq!(critical_path(&node_pack, &BuildTimings::new()),
+            vec![
+                CriticalPathStep
+                {
+                    targets : vec!["a.txt".to_string()],
+                    provenance : Provenance::new("build.rules", 1),
+                    work_micros : 0,
+                    cumulative_micros : 0,
+                },
+            ]);
+    }
+
+    #[test]
+    fn timings_round_trip()
+    {
+        let mut system = FakeSystem::new(10);
+        let mut timings = BuildTimings::new();
+        timings.insert(make_rule("a.txt", vec![], 1).get_ticket(), make_timing(5, 7));
+        write_timings(&mut system, "timings", &timings).unwrap();
+        assert_eq!(read_timings(&system, "timings").unwrap(), timings);
+    }
+}
@@ -1,13 +1,21 @@
 
 use crate::ticket::Ticket;
+use crate::rule::
+{
+    OutputConstraint,
+    Provenance,
+    SuccessCriteria,
+};
 use crate::system::
 {
     CommandLineOutput,
     ReadWriteError,
     System,
     SystemError,
+    CommandEnvironment,
     to_command_script
 };
+use crate::system::util::get_timestamp;
 use crate::history::
 {
     RuleHistory,
@@ -17,20 +25,36 @@ use crate::history::
 use crate::blob::
 {
     Blob,
+    FileState,
     GetFileStateError,
-    FileStateVec,
     FileResolution,
     ResolutionError,
+    Resolver,
     GetCurrentFileInfoError,
     get_file_ticket,
 };
-use crate::cache::
+#[cfg(test)]
+use crate::blob::CacheResolver;
+use crate::cache::SysCache;
+use crate::printer::PrinterHandle;
+use crate::builtin::
 {
-    SysCache,
-    DownloaderCache,
+    BuiltInError,
+    execute_built_in,
+    is_built_in,
 };
 
 use std::fmt;
+use std::collections::HashMap;
+use std::sync::
+{
+    Arc,
+    Condvar,
+    Mutex,
+    RwLock,
+    RwLockReadGuard,
+    RwLockWriteGuard,
+};
 
 #[derive(Debug)]
 pub enum WorkOption
@@ -38,15 +62,46 @@ pub enum WorkOption
     SourceOnly,
     Resolutions(Vec<FileResolution>),
     CommandExecuted(CommandLineOutput),
+
+    /*  At least one target is pinned, so the rule was left alone. */
+    Pinned,
+
+    /*  The rule has no command, so it only groups its sources, and it's done once they are. */
+    Aggregated,
+}
+
+/*  What to do when a rule's command produces targets that contradict what the rule history says
+    the same sources produced before.  Error is the default and the right answer: it means the rule
+    is missing a dependence.  Warn records the new result over the old one and carries on, which is
+    for limping along while rule declarations get fixed.  Overwrite does the same without a word, and
+    is only ever for rules marked @install, whose results are expected to vary. */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContradictionPolicy
+{
+    Error,
+    Warn,
+    Overwrite,
 }
 
 #[derive(Debug)]
 pub struct WorkResult
 {
-    pub file_state_vec : FileStateVec,
+    /*  The full current state of each target, timestamp and executable bit included, for the packets
+        sent on to dependents. */
+    pub target_file_states : Vec<FileState>,
     pub blob : Blob,
     pub work_option : WorkOption,
     pub rule_history : Option<RuleHistory>,
+
+    /*  Targets whose new state contradicted the history and got recorded anyway, because the
+        contradiction policy is Warn. */
+    pub contradicted_paths : Vec<String>,
+
+    /*  Declared sources the command never opened, when the rule's sources were being traced. */
+    pub unused_sources : Vec<String>,
+
+    /*  The ticket of the sources the rule's targets were worked out from.  None for a source file. */
+    pub sources_ticket_opt : Option<Ticket>,
 }
 
 #[derive(Debug)]
@@ -55,14 +110,22 @@ pub enum WorkError
     TicketAlignmentError(ReadWriteError),
     FileNotFound(String),
     TargetFileNotGenerated(String),
+    OutputConstraintViolated(OutputConstraint, u64),
+    TargetDirectoryNotCreated(String, SystemError),
+    OutputNotUtf8(Provenance, String),
     FileNotAvailableToCache(String, ReadWriteError),
     ReadWriteError(String, ReadWriteError),
     ResolutionError(ResolutionError),
     GetCurrentFileInfoError(GetCurrentFileInfoError),
-    CommandExecutedButErrored,
+    CommandExecutedButErrored(CommandLineOutput),
     CommandFailedToExecute(SystemError),
+
+    /*  The command printed a line containing one of the rule's failure patterns: the pattern, then
+        the line. */
+    FailurePatternMatched(Provenance, String, String),
     NoCommandExecuted,
-    Contradiction(Vec<String>),
+    Contradiction(Vec<String>, Provenance),
+    BuiltInFailed(Provenance, BuiltInError),
     Weird,
 }
 
@@ -81,6 +144,15 @@ impl fmt::Display for WorkError
             WorkError::TargetFileNotGenerated(path) =>
                 write!(formatter, "Target file missing after running build command: {}", path),
 
+            WorkError::OutputConstraintViolated(constraint, size) =>
+                write!(formatter, "Target {} came out {} bytes, but its rule expects {}", constraint.target, size, constraint),
+
+            WorkError::OutputNotUtf8(provenance, stream) =>
+                write!(formatter, "Command of rule at {} wrote {} that isn't valid UTF-8", provenance, stream),
+
+            WorkError::TargetDirectoryNotCreated(path, error) =>
+                write!(formatter, "Failed to create directory for target: {}: {}", path, error),
+
             WorkError::FileNotAvailableToCache(path, error) =>
                 write!(formatter, "File not available to be cached: {} : {}", path, error),
 
@@ -93,16 +165,19 @@ impl fmt::Display for WorkError
             WorkError::GetCurrentFileInfoError(error) =>
                 write!(formatter, "Error getting ticket and timestamp: {}", error),
 
-            WorkError::CommandExecutedButErrored =>
+            WorkError::CommandExecutedButErrored(_output) =>
                 write!(formatter, "Command executed but errored"),
 
             WorkError::CommandFailedToExecute(error) =>
                 write!(formatter, "Failed to execute command: {}", error),
 
+            WorkError::FailurePatternMatched(provenance, pattern, line) =>
+                write!(formatter, "Command of rule at {} printed a line containing failure pattern '{}': {}", provenance, pattern, line),
+
             WorkError::NoCommandExecuted =>
                 write!(formatter, "No command executed"),
 
-            WorkError::Contradiction(contradicting_target_paths) =>
+            WorkError::Contradiction(contradicting_target_paths, provenance) =>
             {
                 let mut message = "The following targets failed to record into history, because they contradict an existing target history:\n".to_string();
                 for path in contradicting_target_paths
@@ -110,10 +185,13 @@ impl fmt::Display for WorkError
                     message.push_str(path);
                     message.push_str("\n");
                 }
-                message.push_str("This might mean a real dependence is not reflected in the rule.\n");
+                message.push_str(&format!("This might mean a real dependence is not reflected in the rule at {}.\n", provenance));
                 write!(formatter, "{}", message)
             },
 
+            WorkError::BuiltInFailed(provenance, error) =>
+                write!(formatter, "Built-in command of rule at {} failed: {}", provenance, error),
+
             WorkError::Weird =>
                 write!(formatter, "Weird! How did you do that!"),
         }
@@ -123,30 +201,53 @@ impl fmt::Display for WorkError
 pub fn handle_source_only_node<SystemType: System>
 (
     system : SystemType,
-    blob : Blob
+    mut blob : Blob
 )
 ->
 Result<WorkResult, WorkError>
 {
-    let current_file_state_vec =
-    match blob.get_current_file_state_vec(&system)
+    let current_file_states =
+    match blob.get_current_file_states(&system)
     {
-        Ok(tickets) => tickets,
+        Ok(file_states) => file_states,
         Err(GetFileStateError::FileNotFound(path)) => return Err(WorkError::FileNotFound(path)),
         Err(GetFileStateError::ReadWriteError(path, error)) => return Err(WorkError::ReadWriteError(path, error)),
     };
 
+    /*  Remembered in current file states, for a later build given a list of changed sources to take
+        this one as it is, if it's not on the list. */
+    blob.set_file_states(current_file_states.clone());
+
     Ok(
         WorkResult
         {
-            file_state_vec : current_file_state_vec,
+            target_file_states : current_file_states,
             blob : blob,
             work_option : WorkOption::SourceOnly,
-            rule_history : None
+            rule_history : None,
+            contradicted_paths : vec![],
+            unused_sources : vec![],
+            sources_ticket_opt : None,
         }
     )
 }
 
+/*  Like handle_source_only_node, but takes the FileStates already in the blob to be current, without
+    looking at the files at all.  For sources the build has been told haven't changed. */
+pub fn handle_trusted_source_only_node(blob : Blob) -> WorkResult
+{
+    WorkResult
+    {
+        target_file_states : blob.get_file_states(),
+        blob : blob,
+        work_option : WorkOption::SourceOnly,
+        rule_history : None,
+        contradicted_paths : vec![],
+        unused_sources : vec![],
+        sources_ticket_opt : None,
+    }
+}
+
 /*  Takes a vector of resolutions, and returns true if any of them are NeedsRebuild */
 fn needs_rebuild(resolutions : &Vec<FileResolution>) -> bool
 {
@@ -165,7 +266,14 @@ fn needs_rebuild(resolutions : &Vec<FileResolution>) -> bool
     false
 }
 
-fn to_command_line_input(command_result : Vec<Result<CommandLineOutput, SystemError>>) -> Result<CommandLineOutput, WorkError>
+/*  Checks each command's output against the rule's success criteria, and returns the last. */
+fn to_command_line_input
+(
+    command_result : Vec<Result<CommandLineOutput, SystemError>>,
+    success_criteria : &SuccessCriteria,
+    provenance : &Provenance
+)
+-> Result<CommandLineOutput, WorkError>
 {
     let mut result = Err(WorkError::NoCommandExecuted);
     for res in command_result.into_iter()
@@ -174,9 +282,17 @@ fn to_command_line_input(command_result : Vec<Result<CommandLineOutput, SystemEr
         {
             Ok(output) =>
             {
-                if output.code != Some(0)
+                if !success_criteria.accepts_exit_code(output.code)
                 {
-                    return Err(WorkError::CommandExecutedButErrored)
+                    return Err(WorkError::CommandExecutedButErrored(output))
+                }
+
+                match success_criteria.find_failure_pattern(&output.out)
+                    .or_else(|| success_criteria.find_failure_pattern(&output.err))
+                {
+                    Some((pattern, line)) =>
+                        return Err(WorkError::FailurePatternMatched(provenance.clone(), pattern, line)),
+                    None => {},
                 }
                 result = Ok(output);
             },
@@ -193,15 +309,87 @@ fn to_command_line_input(command_result : Vec<Result<CommandLineOutput, SystemEr
 fn rebuild_node<SystemType : System>
 (
     system : &mut SystemType,
-    mut rule_history : RuleHistory,
-    sources_ticket : Ticket,
-    command : Vec<String>,
+    mut rule_ext : RuleExt<SystemType>,
     mut blob : Blob
 )
 ->
 Result<WorkResult, WorkError>
 {
-    let command_result = to_command_line_input(system.execute_command(to_command_script(command)))?;
+    let markers_before : Vec<Option<u64>> = match &rule_ext.traced_sources_opt
+    {
+        Some(traced_sources) => traced_sources.iter().map(|path| system.get_read_marker(path)).collect(),
+        None => vec![],
+    };
+
+    /*  Commands tend to assume the directory a target goes in is there already, so make sure it is. */
+    for path in blob.get_paths()
+    {
+        match path.rsplit_once('/')
+        {
+            Some((parent, _name)) if parent != "" && ! system.is_dir(parent) =>
+            {
+                match system.create_dir_all(parent)
+                {
+                    Ok(()) => {},
+                    Err(error) => return Err(WorkError::TargetDirectoryNotCreated(path, error)),
+                }
+            },
+            _ => {},
+        }
+    }
+
+    let mut command_script = to_command_script(rule_ext.command);
+    command_script.environment = rule_ext.environment;
+    let command_result =
+    {
+        let _guard = rule_ext.command_gate.enter(rule_ext.serial, &rule_ext.group_opt);
+        if is_built_in(&command_script)
+        {
+            match execute_built_in(system, &command_script, &rule_ext.source_file_states)
+            {
+                Ok(output) => output,
+                Err(BuiltInError::SourceNotFound(path)) => return Err(WorkError::FileNotFound(path)),
+                Err(BuiltInError::ReadWriteError(path, error)) => return Err(WorkError::ReadWriteError(path, error)),
+                Err(error) => return Err(WorkError::BuiltInFailed(rule_ext.provenance, error)),
+            }
+        }
+        else
+        {
+            to_command_line_input(system.execute_command(command_script), &rule_ext.success_criteria, &rule_ext.provenance)?
+        }
+    };
+
+    if rule_ext.strict_utf8_output
+    {
+        if !command_result.out_is_utf8()
+        {
+            return Err(WorkError::OutputNotUtf8(rule_ext.provenance, "stdout".to_string()));
+        }
+
+        if !command_result.err_is_utf8()
+        {
+            return Err(WorkError::OutputNotUtf8(rule_ext.provenance, "stderr".to_string()));
+        }
+    }
+
+    /*  A source counts as unused only when the system could tell both before and after, and the
+        marker didn't move. */
+    let mut unused_sources = vec![];
+    match &rule_ext.traced_sources_opt
+    {
+        Some(traced_sources) =>
+        {
+            for (path, marker_before) in traced_sources.iter().zip(markers_before.iter())
+            {
+                match (marker_before, system.get_read_marker(path))
+                {
+                    (Some(before), Some(after)) if *before == after => unused_sources.push(path.clone()),
+                    _ => {},
+                }
+            }
+        },
+        None => {},
+    }
 
     let file_state_vec =
     match blob.update_to_match_system_file_state(system)
@@ -211,7 +399,26 @@ Result<WorkResult, WorkError>
         Err(error) => return Err(WorkError::GetCurrentFileInfoError(error)),
     };
 
-    match rule_history.insert(sources_ticket, file_state_vec.clone())
+    /*  A target that fails an expectation is an error before anything gets recorded, so, as with a
+        command that errors, neither the history nor the current file states take it as built. */
+    for constraint in rule_ext.output_constraints
+    {
+        match system.get_file_len(&constraint.target)
+        {
+            Ok(size) =>
+            {
+                if ! constraint.is_met_by(size)
+                {
+                    return Err(WorkError::OutputConstraintViolated(constraint, size));
+                }
+            },
+            Err(_error) => return Err(WorkError::TargetFileNotGenerated(constraint.target)),
+        }
+    }
+
+    let created_at = get_timestamp(system.now()).unwrap_or(0);
+    let mut contradicted_paths = vec![];
+    match rule_ext.rule_history.insert(rule_ext.sources_ticket.clone(), file_state_vec.clone(), created_at)
     {
         Ok(_) => {},
         Err(error) =>
@@ -226,7 +433,21 @@ Result<WorkResult, WorkError>
                     {
                         contradicting_target_paths.push(paths[index].clone());
                     }
-                    return Err(WorkError::Contradiction(contradicting_target_paths));
+
+                    match rule_ext.contradiction_policy
+                    {
+                        ContradictionPolicy::Error =>
+                            return Err(WorkError::Contradiction(contradicting_target_paths, rule_ext.provenance)),
+
+                        ContradictionPolicy::Warn =>
+                        {
+                            rule_ext.rule_history.overwrite(rule_ext.sources_ticket.clone(), file_state_vec.clone(), created_at);
+                            contradicted_paths = contradicting_target_paths;
+                        },
+
+                        ContradictionPolicy::Overwrite =>
+                            rule_ext.rule_history.overwrite(rule_ext.sources_ticket.clone(), file_state_vec.clone(), created_at),
+                    }
                 }
 
                 RuleHistoryInsertError::TargetSizesDifferWeird =>
@@ -238,10 +459,13 @@ Result<WorkResult, WorkError>
     Ok(
         WorkResult
         {
-            file_state_vec : file_state_vec,
+            target_file_states : blob.get_file_states(),
             blob : blob,
             work_option : WorkOption::CommandExecuted(command_result),
-            rule_history : Some(rule_history),
+            rule_history : Some(rule_ext.rule_history),
+            contradicted_paths : contradicted_paths,
+            unused_sources : unused_sources,
+            sources_ticket_opt : Some(rule_ext.sources_ticket),
         }
     )
 }
@@ -257,22 +481,18 @@ Result<WorkResult, WorkError>
 fn resolve_with_cache<SystemType : System>
 (
     system : &mut SystemType,
-    cache : &mut SysCache<SystemType>,
-    downloader_cache_opt : &Option<DownloaderCache>,
-    rule_history : &RuleHistory,
-    downloader_rule_history_opt : &Option<DownloaderRuleHistory>,
-    sources_ticket : &Ticket,
+    rule_ext : &mut RuleExt<SystemType>,
     blob : &Blob,
 )
 ->
 Result<Vec<FileResolution>, WorkError>
 {
-    match rule_history.get_file_state_vec(sources_ticket)
+    match rule_ext.rule_history.get_file_state_vec(&rule_ext.sources_ticket)
     {
         Some(remembered_file_state_vec) =>
         {
             return match blob.resolve_remembered_file_state_vec(
-                system, cache, downloader_cache_opt, remembered_file_state_vec)
+                system, &mut rule_ext.cache, &rule_ext.resolvers, remembered_file_state_vec, &rule_ext.printer_handle)
             {
                 Ok(file_resolution) => Ok(file_resolution),
                 Err(resolution_error) => Err(WorkError::ResolutionError(resolution_error)),
@@ -282,16 +502,16 @@ Result<Vec<FileResolution>, WorkError>
         None => {},
     }
 
-    match downloader_rule_history_opt
+    match &rule_ext.downloader_rule_history_opt
     {
         Some(downloader_rule_history) =>
         {
-            match downloader_rule_history.get_file_state_vec(sources_ticket)
+            match downloader_rule_history.get_file_state_vec(&rule_ext.sources_ticket, &rule_ext.printer_handle)
             {
                 Some(file_state_vec) =>
                 {
                     return match blob.resolve_remembered_file_state_vec(
-                        system, cache, downloader_cache_opt, &file_state_vec)
+                        system, &mut rule_ext.cache, &rule_ext.resolvers, &file_state_vec, &rule_ext.printer_handle)
                     {
                         Ok(file_resolution) => Ok(file_resolution),
                         Err(resolution_error) => Err(WorkError::ResolutionError(resolution_error)),
@@ -305,36 +525,264 @@ Result<Vec<FileResolution>, WorkError>
         None => {},
     }
 
-    match blob.resolve_with_no_current_file_states(system, cache)
+    match blob.resolve_with_no_current_file_states(system, &mut rule_ext.cache, &rule_ext.printer_handle)
     {
         Ok(resolutions) => Ok(resolutions),
         Err(resolution_error) => Err(WorkError::ResolutionError(resolution_error)),
     }
 }
 
+/*  Keeps the commands of serial rules from overlapping any other command, and limits how many
+    commands of a group run at once: one, unless the group was given more permits.  Every command
+    holds the gate while it runs, ordinary commands all together, a serial rule's command alone.  A
+    command in a group first waits for a permit from its group, then, if there's a limit on jobs, for
+    one of those, and holds both until done.  Clones share the gate, so one per build, cloned into
+    each rule's thread. */
+#[derive(Clone)]
+pub struct CommandGate
+{
+    all : Arc<RwLock<()>>,
+    groups : Arc<Permits>,
+    group_limits : Arc<HashMap<String, usize>>,
+    jobs : Arc<Permits>,
+    jobs_opt : Option<usize>,
+}
+
+/*  How many commands hold a permit under each name, with a condvar to wait on for one to free up. */
+struct Permits
+{
+    held : Mutex<HashMap<String, usize>>,
+    condvar : Condvar,
+}
+
+impl Permits
+{
+    fn new() -> Permits
+    {
+        Permits
+        {
+            held : Mutex::new(HashMap::new()),
+            condvar : Condvar::new(),
+        }
+    }
+
+    /*  Waits until fewer than limit permits under name are held, and takes one. */
+    fn acquire(&self, name : &str, limit : usize) -> PermitGuard<'_>
+    {
+        let mut held =
+        match self.held.lock()
+        {
+            Ok(held) => held,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        while *held.get(name).unwrap_or(&0) >= limit.max(1)
+        {
+            held =
+            match self.condvar.wait(held)
+            {
+                Ok(held) => held,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+        }
+
+        *held.entry(name.to_string()).or_insert(0) += 1;
+        PermitGuard{permits : self, name : name.to_string()}
+    }
+}
+
+/*  Holds a permit until it drops. */
+struct PermitGuard<'a>
+{
+    permits : &'a Permits,
+    name : String,
+}
+
+impl<'a> Drop for PermitGuard<'a>
+{
+    fn drop(&mut self)
+    {
+        let mut held =
+        match self.permits.held.lock()
+        {
+            Ok(held) => held,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        match held.get_mut(&self.name)
+        {
+            Some(count) => *count -= 1,
+            None => {},
+        }
+        self.permits.condvar.notify_all();
+    }
+}
+
+/*  Holds whichever side of the gate a command entered, its job and its group, until it drops.  The
+    group goes last, after the gate and the job. */
+pub struct CommandGateGuard<'a>
+{
+    _shared_opt : Option<RwLockReadGuard<'a, ()>>,
+    _exclusive_opt : Option<RwLockWriteGuard<'a, ()>>,
+    _job_opt : Option<PermitGuard<'a>>,
+    _group_opt : Option<PermitGuard<'a>>,
+}
+
+impl CommandGate
+{
+    /*  A gate with no limit on jobs, where every group runs one command at a time. */
+    #[cfg(test)]
+    pub fn new() -> CommandGate
+    {
+        CommandGate::with_limits(None, &vec![])
+    }
+
+    /*  A gate letting at most jobs_opt commands run at once, if Some, and as many commands of each
+        group in group_limits at once as it says.  Groups not in group_limits run one at a time. */
+    pub fn with_limits(jobs_opt : Option<usize>, group_limits : &Vec<(String, usize)>) -> CommandGate
+    {
+        CommandGate
+        {
+            all : Arc::new(RwLock::new(())),
+            groups : Arc::new(Permits::new()),
+            group_limits : Arc::new(group_limits.iter().cloned().collect()),
+            jobs : Arc::new(Permits::new()),
+            jobs_opt : jobs_opt,
+        }
+    }
+
+    /*  Waits until the gate lets a command through, and keeps it through until the guard drops.  The
+        group comes first, so a command waiting on its group doesn't hold a job or keep a serial rule
+        waiting.  The gate guards nothing but itself, so if a thread panicked holding it, carry on
+        regardless. */
+    pub fn enter(&self, exclusive : bool, group_opt : &Option<String>) -> CommandGateGuard<'_>
+    {
+        let group_guard_opt = group_opt.as_ref().map(
+            |name| self.groups.acquire(name, *self.group_limits.get(name).unwrap_or(&1)));
+        let job_guard_opt = self.jobs_opt.map(|jobs| self.jobs.acquire("", jobs));
+
+        if exclusive
+        {
+            let guard =
+            match self.all.write()
+            {
+                Ok(guard) => guard,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+
+            CommandGateGuard
+            {
+                _shared_opt : None,
+                _exclusive_opt : Some(guard),
+                _job_opt : job_guard_opt,
+                _group_opt : group_guard_opt,
+            }
+        }
+        else
+        {
+            let guard =
+            match self.all.read()
+            {
+                Ok(guard) => guard,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+
+            CommandGateGuard
+            {
+                _shared_opt : Some(guard),
+                _exclusive_opt : None,
+                _job_opt : job_guard_opt,
+                _group_opt : group_guard_opt,
+            }
+        }
+    }
+}
+
 pub struct RuleExt<SystemType: System>
 {
     pub sources_ticket : Ticket,
+
+    /*  The file states the sources arrived with, by path, so the command needn't look at the sources
+        again for what their packets already say. */
+    pub source_file_states : HashMap<String, FileState>,
     pub command : Vec<String>,
+
+    /*  Bounds on the targets' sizes, checked only when the command runs.  Targets from the cache or
+        history passed them when they were built, since the bounds are part of the rule ticket. */
+    pub output_constraints : Vec<OutputConstraint>,
+    pub provenance : Provenance,
+    pub contradiction_policy : ContradictionPolicy,
+
+    /*  When Some, the rule's declared sources, to watch while the command runs and report any it
+        never reads.  This is a diagnostic, off unless asked for. */
+    pub traced_sources_opt : Option<Vec<String>>,
+
+    /*  The environment variables the command gets. */
+    pub environment : CommandEnvironment,
+
+    /*  Whether output that isn't valid UTF-8 is an error rather than something to print lossily. */
+    pub strict_utf8_output : bool,
+
+    /*  The rule's exit codes and failure patterns, for telling whether its command succeeded. */
+    pub success_criteria : SuccessCriteria,
     pub rule_history : RuleHistory,
     pub cache : SysCache<SystemType>,
-    pub downloader_cache_opt : Option<DownloaderCache>,
     pub downloader_rule_history_opt : Option<DownloaderRuleHistory>,
+
+    /*  Ways of bringing back targets from their tickets, asked in order: the cache's, any the build
+        was given, then downloading, unless the rule is private. */
+    pub resolvers : Vec<Arc<dyn Resolver<SystemType>>>,
+
+    /*  For each target, the ticket it's pinned to, if it is.  Empty when nothing is pinned. */
+    pub pinned_tickets : Vec<Option<Ticket>>,
+
+    /*  Whether the rule is marked @no-cache: it always rebuilds, and nothing about it goes into or
+        comes out of the cache or the history. */
+    pub no_cache : bool,
+
+    /*  Whether the build was told to run the rule's command whatever the history says.  The results
+        still go into the history, contradiction check and all. */
+    pub force : bool,
+
+    /*  Whether the rule is marked @serial, so its command goes through the command gate alone. */
+    pub serial : bool,
+
+    /*  The rule's @group, if any, so its command waits for any other command of the group to finish. */
+    pub group_opt : Option<String>,
+    pub command_gate : CommandGate,
+
+    /*  Where the rule's warnings go, to be printed by the thread with the printer. */
+    pub printer_handle : PrinterHandle,
 }
 
-impl<SystemType: System> RuleExt<SystemType>
+impl<SystemType: System + 'static> RuleExt<SystemType>
 {
     #[cfg(test)]
     fn new(cache : SysCache<SystemType>, sources_ticket : Ticket) -> RuleExt<SystemType>
     {
         return RuleExt
         {
+            resolvers : vec![Arc::new(CacheResolver::new(cache.clone()))],
             cache : cache,
             sources_ticket : sources_ticket,
+            source_file_states : HashMap::new(),
             command : Vec::new(),
+            output_constraints : vec![],
+            provenance : Provenance::new("", 0),
+            contradiction_policy : ContradictionPolicy::Error,
+            traced_sources_opt : None,
+            environment : CommandEnvironment::Inherit,
+            strict_utf8_output : false,
+            success_criteria : SuccessCriteria::new(),
             rule_history : RuleHistory::new(),
-            downloader_cache_opt : None,
             downloader_rule_history_opt : None,
+            pinned_tickets : vec![],
+            no_cache : false,
+            force : false,
+            serial : false,
+            group_opt : None,
+            command_gate : CommandGate::new(),
+            printer_handle : PrinterHandle::discard(),
         };
     }
 }
@@ -357,6 +805,129 @@ impl<SystemType: System> HandleNodeInfo<SystemType>
     }
 }
 
+/*  Handles a rule with a pinned target: runs nothing, resolves nothing, and returns no rule history,
+    so there's nothing to contradict and nothing written.  Pinned targets pass on their pinned tickets,
+    any other targets of the rule whatever they are now. */
+fn handle_pinned_node<SystemType: System>
+(
+    info : HandleNodeInfo<SystemType>,
+    rule_ext : RuleExt<SystemType>,
+)
+->
+Result<WorkResult, WorkError>
+{
+    let mut target_file_states =
+    match info.blob.get_current_file_states(&info.system)
+    {
+        Ok(file_states) => file_states,
+        Err(GetFileStateError::FileNotFound(path)) => return Err(WorkError::FileNotFound(path)),
+        Err(GetFileStateError::ReadWriteError(path, error)) => return Err(WorkError::ReadWriteError(path, error)),
+    };
+
+    for (file_state, ticket_opt) in target_file_states.iter_mut().zip(rule_ext.pinned_tickets.into_iter())
+    {
+        match ticket_opt
+        {
+            Some(ticket) => file_state.ticket = ticket,
+            None => {},
+        }
+    }
+
+    Ok(
+        WorkResult
+        {
+            target_file_states : target_file_states,
+            blob : info.blob,
+            work_option : WorkOption::Pinned,
+            rule_history : None,
+            contradicted_paths : vec![],
+            unused_sources : vec![],
+            sources_ticket_opt : Some(rule_ext.sources_ticket),
+        }
+    )
+}
+
+/*  Handles a rule with no command: an aggregation of its sources, satisfied as soon as they resolve.
+    Nothing runs and no target file has to exist.  Each target passes on the sources ticket, so rules
+    depending on the aggregation rebuild exactly when something it groups changes.  There's no history,
+    since there's nothing to remember. */
+fn handle_aggregate_node<SystemType: System>
+(
+    info : HandleNodeInfo<SystemType>,
+    rule_ext : RuleExt<SystemType>,
+)
+->
+Result<WorkResult, WorkError>
+{
+    Ok(
+        WorkResult
+        {
+            target_file_states : info.blob.get_paths().iter().map(
+                |_path| FileState
+                {
+                    ticket : rule_ext.sources_ticket.clone(),
+                    timestamp : 0,
+                    executable : false,
+                }).collect(),
+            blob : info.blob,
+            work_option : WorkOption::Aggregated,
+            rule_history : None,
+            contradicted_paths : vec![],
+            unused_sources : vec![],
+            sources_ticket_opt : Some(rule_ext.sources_ticket),
+        }
+    )
+}
+
+/*  Handles a rule marked @no-cache: removes whatever targets are there, so a command that fails to
+    write one gets caught rather than leaving the old one looking new, then runs the command.  The
+    command runs against an empty history, so nothing it makes can contradict what came before, and
+    that history gets dropped, so nothing gets written. */
+fn handle_no_cache_node<SystemType: System>
+(
+    mut info : HandleNodeInfo<SystemType>,
+    mut rule_ext : RuleExt<SystemType>,
+)
+->
+Result<WorkResult, WorkError>
+{
+    for path in info.blob.get_paths()
+    {
+        if info.system.is_file(&path)
+        {
+            match info.system.remove_file(&path)
+            {
+                Ok(()) => {},
+                Err(error) => return Err(WorkError::ReadWriteError(path, ReadWriteError::SystemError(error))),
+            }
+        }
+    }
+
+    rule_ext.rule_history = RuleHistory::new();
+    let mut work_result = rebuild_node(&mut info.system, rule_ext, info.blob)?;
+
+    work_result.rule_history = None;
+    Ok(work_result)
+}
+
+/*  True when the history remembers targets for these sources and the blob's targets are still exactly
+    those, going by timestamps alone. */
+fn targets_match_history<SystemType: System>
+(
+    system : &SystemType,
+    blob : &Blob,
+    rule_history : &RuleHistory,
+    sources_ticket : &Ticket,
+)
+-> bool
+{
+    match rule_history.get_file_state_vec(sources_ticket)
+    {
+        Some(remembered_file_state_vec) => blob.matches_unmodified(system, remembered_file_state_vec),
+        None => false,
+    }
+}
+
 /*  This is a central, public function for handling a node in the depednece graph.
     It is meant to be called by a dedicated thread.
 
@@ -373,31 +944,68 @@ pub fn handle_rule_node<SystemType: System>
 ->
 Result<WorkResult, WorkError>
 {
-    match resolve_with_cache(
-        &mut info.system,
-        &mut rule_ext.cache,
-        & rule_ext.downloader_cache_opt,
-        & rule_ext.rule_history,
-        & rule_ext.downloader_rule_history_opt,
-        & rule_ext.sources_ticket,
-        & info.blob)
+    if rule_ext.pinned_tickets.iter().any(|ticket_opt| ticket_opt.is_some())
+    {
+        return handle_pinned_node(info, rule_ext);
+    }
+
+    if rule_ext.command.is_empty()
+    {
+        return handle_aggregate_node(info, rule_ext);
+    }
+
+    if rule_ext.no_cache
+    {
+        return handle_no_cache_node(info, rule_ext);
+    }
+
+    /*  Forced: skip the history and the caches, back up whatever targets are there, and run the
+        command.  What it makes goes into the history as usual, so if that differs from what the
+        history remembers for these sources, it's a contradiction, which is the point: a forced
+        rebuild coming out different is how a nondeterministic command shows itself. */
+    if rule_ext.force
+    {
+        match info.blob.resolve_with_no_current_file_states(&mut info.system, &mut rule_ext.cache, &rule_ext.printer_handle)
+        {
+            Ok(_resolutions) => {},
+            Err(resolution_error) => return Err(WorkError::ResolutionError(resolution_error)),
+        }
+
+        return rebuild_node(&mut info.system, rule_ext, info.blob);
+    }
+
+    /*  Fast path: if the targets are still exactly what the history remembers for these sources,
+        skip the caches, and return no rule history, since there's nothing new to write. */
+    if targets_match_history(&info.system, &info.blob, &rule_ext.rule_history, &rule_ext.sources_ticket)
+    {
+        return Ok(
+            WorkResult
+            {
+                target_file_states : info.blob.get_file_states(),
+                work_option : WorkOption::Resolutions(
+                    info.blob.get_paths().iter().map(|_path| FileResolution::AlreadyCorrect).collect()),
+                blob : info.blob,
+                rule_history : None,
+                contradicted_paths : vec![],
+                unused_sources : vec![],
+                sources_ticket_opt : Some(rule_ext.sources_ticket.clone()),
+            }
+        );
+    }
+
+    match resolve_with_cache(&mut info.system, &mut rule_ext, &info.blob)
     {
         Ok(resolutions) =>
         {
             if needs_rebuild(&resolutions)
             {
-                rebuild_node(
-                    &mut info.system,
-                    rule_ext.rule_history,
-                    rule_ext.sources_ticket,
-                    rule_ext.command,
-                    info.blob)
+                rebuild_node(&mut info.system, rule_ext, info.blob)
             }
             else
             {
-                let file_state_vec = match info.blob.get_current_file_state_vec(&info.system)
+                let current_file_states = match info.blob.get_current_file_states(&info.system)
                 {
-                    Ok(file_state_vec) => file_state_vec,
+                    Ok(file_states) => file_states,
                     Err(GetFileStateError::FileNotFound(path)) => return Err(WorkError::FileNotFound(path)),
                     Err(GetFileStateError::ReadWriteError(path, error)) => return Err(WorkError::ReadWriteError(path, error)),
                 };
@@ -405,10 +1013,13 @@ Result<WorkResult, WorkError>
                 Ok(
                     WorkResult
                     {
-                        file_state_vec : file_state_vec,
+                        target_file_states : current_file_states,
                         blob : info.blob,
                         work_option : WorkOption::Resolutions(resolutions),
                         rule_history : Some(rule_ext.rule_history),
+                        contradicted_paths : vec![],
+                        unused_sources : vec![],
+                        sources_ticket_opt : Some(rule_ext.sources_ticket),
                     }
                 )
             }
@@ -418,18 +1029,21 @@ Result<WorkResult, WorkError>
     }
 }
 
+/*  Moves each of the blob's targets that's there into the cache, and returns how many it moved. */
 pub fn clean_targets<SystemType: System>
 (
     blob : Blob,
     system : &mut SystemType,
     cache : &mut SysCache<SystemType>
 )
--> Result<(), WorkError>
+-> Result<usize, WorkError>
 {
+    let mut count = 0;
     for target_info in blob.get_file_infos()
     {
         if system.is_file(&target_info.path)
         {
+            count += 1;
             match get_file_ticket(system, &target_info.path, &target_info.file_state)
             {
                 Ok(Some(current_target_ticket)) =>
@@ -462,13 +1076,14 @@ pub fn clean_targets<SystemType: System>
         }
     }
 
-    Ok(())
+    Ok(count)
 }
 
 
 #[cfg(test)]
 mod test
 {
+    use crate::rule::Provenance;
     use crate::work::
     {
         FileResolution,
@@ -508,6 +1123,7 @@ mod test
     use crate::system::
     {
         System,
+        CommandLineOutput,
         fake::FakeSystem,
     };
 
@@ -555,7 +1171,7 @@ mod test
         // to the target executable.
         rule_history.insert(
             source_factory.result(),
-            FileStateVec::from_ticket_vec(vec![TicketFactory::from_str(target_content).result()])).unwrap();
+            FileStateVec::from_ticket_vec(vec![TicketFactory::from_str(target_content).result()]), 0).unwrap();
 
         // Meanwhile, in the filesystem put some rubbish in game.cpp
         match write_str_to_file(&mut system, "game.cpp", source_content)
@@ -616,7 +1232,8 @@ mod test
         info
     }
 
-    /*  Call handle_rule_node with minimal connections and the empty list as a command. */
+    /*  Call handle_rule_node with minimal connections and the empty list as a command.  That's an
+        aggregation, so nothing runs. */
     #[test]
     fn do_empty_command()
     {
@@ -636,7 +1253,7 @@ mod test
             {
                 match result.work_option
                 {
-                    WorkOption::Resolutions(resolutions) => assert_eq!(resolutions.len(), 0),
+                    WorkOption::Aggregated => {},
                     _ => panic!("Wrong kind of WorkOption in result when command empty"),
                 }
             },
@@ -696,7 +1313,7 @@ mod test
         match handle_rule_node(make_handle_node_info(system.clone(), vec!["poem.txt".to_string()]), rule_ext)
         {
             Ok(_) => panic!("Unexpected command success"),
-            Err(WorkError::CommandExecutedButErrored) => {},
+            Err(WorkError::CommandExecutedButErrored(_output)) => {},
             Err(error) => panic!("Wrong kind of error when command errors: {}", error),
         }
     }
@@ -785,7 +1402,8 @@ mod test
             sources_ticket.clone(),
             FileStateVec::from_ticket_vec(vec![
                 TicketFactory::from_str("Roses are red\nViolets are violet\n").result()
-            ])
+            ]),
+            0
         )
         {
             Ok(_) => {},
@@ -848,7 +1466,7 @@ mod test
             sources_ticket.clone(),
             FileStateVec::from_ticket_vec(
                 vec![TicketFactory::from_str("Roses are red\nViolets are blue\n").result()]
-            ))
+            ), 0)
         {
             Ok(_) => {},
             Err(_) => panic!("Rule history failed to insert"),
@@ -864,6 +1482,7 @@ mod test
         let mut rule_ext = RuleExt::new(SysCache::new(system.clone(), ".ruler-cache"), sources_ticket);
         rule_ext.command = vec!["mycat".to_string(), "verse1.txt".to_string(), "verse2.txt".to_string(), "poem.txt".to_string()];
         rule_ext.rule_history = rule_history;
+        rule_ext.provenance = Provenance::new("poem.rules", 3);
 
         match handle_rule_node(make_handle_node_info(system.clone(), vec!["poem.txt".to_string()]), rule_ext)
         {
@@ -873,11 +1492,13 @@ mod test
             },
             Err(error) =>
             {
+                assert!(format!("{}", error).contains("poem.rules:3"));
                 match error
                 {
-                    WorkError::Contradiction(paths) => 
+                    WorkError::Contradiction(paths, provenance) => 
                     {
                         assert_eq!(paths.len(), 1);
+                        assert_eq!(provenance, Provenance::new("poem.rules", 3));
                     },
                     _ => panic!("Wrong error: {}", error),
                 }
@@ -1063,7 +1684,7 @@ mod test
         assert_eq!(read_file_to_string(&system, "poem_copy.txt").unwrap(),
             "Roses are red\nViolets are blue\n");
 
-        let command_log = system.get_command_log();
+        let command_log = system.get_command_log_joined();
         assert_eq!(command_log.len(), 1);
         assert_eq!(command_log[0], "mycat2 verse1.txt verse2.txt poem.txt poem_copy.txt");
     }
@@ -1146,7 +1767,7 @@ mod test
                     _ => panic!("Wrong type of work option.  Command was supposed to execute."),
                 }
             },
-            Err(WorkError::CommandExecutedButErrored) => {},
+            Err(WorkError::CommandExecutedButErrored(_output)) => {},
             Err(err) => panic!("Error of wrong type: {}", err),
         }
 
@@ -1154,6 +1775,39 @@ mod test
         assert_eq!(system.is_file("poem.txt"), false);
     }
 
+    /*  Like one_dependence_with_error, but the target is precious.  Check the old target is still
+        there after the command fails, and that a copy went into the cache. */
+    #[test]
+    fn precious_target_survives_error()
+    {
+        let mut system = FakeSystem::new(10);
+
+        system.create_dir(".ruler-cache").unwrap();
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red\n").unwrap();
+        write_str_to_file(&mut system, "poem.txt", "Old poem\n").unwrap();
+
+        let mut factory = TicketFactory::new();
+        factory.input_ticket(TicketFactory::from_str("Roses are red\n").result());
+        let sources_ticket = factory.result();
+
+        let cache = SysCache::new(system.clone(), ".ruler-cache");
+        let mut rule_ext = RuleExt::new(cache.clone(), sources_ticket);
+        rule_ext.command = vec!["error".to_string()];
+
+        let mut info = make_handle_node_info(system.clone(), vec!["poem.txt".to_string()]);
+        info.blob.set_precious(true);
+
+        match handle_rule_node(info, rule_ext)
+        {
+            Ok(_result) => panic!("Unexpected success"),
+            Err(WorkError::CommandExecutedButErrored(_output)) => {},
+            Err(err) => panic!("Error of wrong type: {}", err),
+        }
+
+        assert_eq!(read_file_to_string(&system, "poem.txt").unwrap(), "Old poem\n");
+        cache.open(&TicketFactory::from_str("Old poem\n").result()).unwrap();
+    }
+
     /*  Poem with two target files, but there is a mistake in the command, and it produces an error
         instead of building the targets.  Run the build command and check the error.  Also confirm
         that the target already present got moved into the cache. */
@@ -1193,7 +1847,7 @@ mod test
                     _ => panic!("Wrong type of work option.  Command was supposed to execute."),
                 }
             },
-            Err(WorkError::CommandExecutedButErrored) => {},
+            Err(WorkError::CommandExecutedButErrored(_output)) => {},
             Err(err) => panic!("Error of wrong type: {}", err),
         }
 
@@ -1220,7 +1874,8 @@ mod test
             sources_ticket.clone(),
             FileStateVec::from_ticket_vec(vec![
                 TicketFactory::from_str("Roses are red\nViolets are violet\n").result()
-            ])
+            ]),
+            0
         ).unwrap();
 
         let mut system = FakeSystem::new(19);
@@ -1273,4 +1928,108 @@ mod test
     fn one_target_correct_hash_incorrect_timestamp()
     {
     }
+
+
+    /*  A rule declares three verses as sources but its command only reads the first two.  With the
+        sources traced, the third comes back as unused, and the poem still gets built. */
+    #[test]
+    fn poem_with_unused_source_flagged()
+    {
+        let mut system = FakeSystem::new(10);
+
+        system.create_dir(".ruler-cache").unwrap();
+        write_str_to_file(&mut system, "verse1.txt", "Roses are red\n").unwrap();
+        write_str_to_file(&mut system, "verse2.txt", "Violets are violet\n").unwrap();
+        write_str_to_file(&mut system, "verse3.txt", "Sugar is sweet\n").unwrap();
+
+        let sources_ticket = current_sources_ticket(&system, vec!["verse1.txt", "verse2.txt", "verse3.txt"]).unwrap();
+        let mut rule_ext = RuleExt::new(SysCache::new(system.clone(), ".ruler-cache"), sources_ticket);
+        rule_ext.command = vec!["mycat".to_string(), "verse1.txt".to_string(), "verse2.txt".to_string(), "poem.txt".to_string()];
+        rule_ext.traced_sources_opt = Some(vec!["verse1.txt".to_string(), "verse2.txt".to_string(), "verse3.txt".to_string()]);
+
+        match handle_rule_node(make_handle_node_info(system.clone(), vec!["poem.txt".to_string()]), rule_ext)
+        {
+            Ok(result) =>
+            {
+                match result.work_option
+                {
+                    WorkOption::CommandExecuted(_output) => {},
+                    _ => panic!("Expected the command to execute"),
+                }
+                assert_eq!(result.unused_sources, vec!["verse3.txt".to_string()]);
+            },
+            Err(err) => panic!("Command failed: {}", err),
+        }
+
+        assert_eq!(read_file_to_string(&mut system, "poem.txt").unwrap(), "Roses are red\nViolets are violet\n");
+    }
+
+    /*  A fake command that writes its one argument as a target and prints a progress bar with a byte
+        that isn't UTF-8 in it. */
+    fn make_progress_system() -> FakeSystem
+    {
+        let mut system = FakeSystem::new(10);
+        system.create_dir(".ruler-cache").unwrap();
+        system.register_command("progress", Box::new(
+            |system : &mut FakeSystem, args : &[String]|
+            {
+                match write_str_to_file(system, &args[0], "done\n")
+                {
+                    Ok(()) => CommandLineOutput::from_bytes(b"[==\xff==]".to_vec(), vec![], Some(0), true),
+                    Err(_) => CommandLineOutput::error("progress: write failed".to_string()),
+                }
+            }));
+        system
+    }
+
+    /*  By default the output comes back readable, with a replacement character for the bad byte, and
+        the bytes as written alongside. */
+    #[test]
+    fn work_command_output_not_utf8_kept_raw()
+    {
+        let system = make_progress_system();
+        let mut rule_ext = RuleExt::new(SysCache::new(system.clone(), ".ruler-cache"), TicketFactory::new().result());
+        rule_ext.command = vec!["progress".to_string(), "poem.txt".to_string()];
+
+        match handle_rule_node(make_handle_node_info(system.clone(), vec!["poem.txt".to_string()]), rule_ext)
+        {
+            Ok(result) =>
+            {
+                match result.work_option
+                {
+                    WorkOption::CommandExecuted(output) =>
+                    {
+                        assert_eq!(output.out, "[==\u{FFFD}==]");
+                        assert_eq!(output.out_bytes, b"[==\xff==]".to_vec());
+                        assert!(!output.out_is_utf8());
+                        assert!(output.err_is_utf8());
+                    },
+                    _ => panic!("Wrong kind of WorkOption in result when command runs"),
+                }
+            },
+            Err(error) => panic!("Unexpected error: {}", error),
+        }
+    }
+
+    /*  Under strict UTF-8 the same command fails its rule, naming the rule and the stream. */
+    #[test]
+    fn work_command_output_not_utf8_strict()
+    {
+        let system = make_progress_system();
+        let mut rule_ext = RuleExt::new(SysCache::new(system.clone(), ".ruler-cache"), TicketFactory::new().result());
+        rule_ext.command = vec!["progress".to_string(), "poem.txt".to_string()];
+        rule_ext.provenance = Provenance::new("build.rules", 7);
+        rule_ext.strict_utf8_output = true;
+
+        match handle_rule_node(make_handle_node_info(system.clone(), vec!["poem.txt".to_string()]), rule_ext)
+        {
+            Ok(_) => panic!("Unexpected success with output that isn't UTF-8"),
+            Err(WorkError::OutputNotUtf8(provenance, stream)) =>
+            {
+                assert_eq!(provenance, Provenance::new("build.rules", 7));
+                assert_eq!(stream, "stdout");
+            },
+            Err(error) => panic!("Wrong kind of error: {}", error),
+        }
+    }
 }